@@ -1,25 +1,35 @@
 use std::{
     fs,
+    io::Write,
     path::PathBuf,
     process::{exit, Command, ExitStatus, Stdio},
+    str::FromStr,
 };
 
 use cargo_metadata::Message;
 use clap::{Args, Parser, Subcommand};
 use espflash::{
     cli::{
-        self, board_info, config::Config, connect, erase_partitions, flash_elf_image,
-        monitor::monitor, parse_partition_table, partition_table, print_board_info,
-        save_elf_as_image, serial_monitor, ConnectArgs, EspflashProgress, FlashConfigArgs,
-        MonitorArgs, PartitionTableArgs,
+        self, board_info,
+        bootloader::resolve_bootloader,
+        check_partition_table, check_qemu_flash_size,
+        config::Config,
+        connect, erase_partitions, flash_apps, flash_elf_image,
+        monitor::{monitor_until, MonitorExit},
+        parse_partition_table, partition_table, print_board_info, print_flash_summary, prompt,
+        read_app_partitions, read_bootloader, save_elf_as_image, secure_version, security_info,
+        serial_monitor,
+        watch::Watcher,
+        ConnectArgs, EspflashProgress, FlashConfigArgs, MonitorArgs, PartitionTableArgs,
+        SecureVersionArgs, VerbosityArgs,
     },
     image_format::ImageFormatKind,
     logging::initialize_logger,
     targets::Chip,
     update::check_for_update,
 };
-use log::{debug, LevelFilter};
 use miette::{IntoDiagnostic, Result, WrapErr};
+use tracing::{debug, info};
 
 use crate::{
     cargo_config::CargoConfig,
@@ -44,6 +54,12 @@ enum CargoSubcommand {
     Espflash {
         #[clap(subcommand)]
         subcommand: Commands,
+        #[clap(flatten)]
+        verbosity: VerbosityArgs,
+        /// Don't ask for confirmation before erasing or overwriting data on the
+        /// device, eg. when running from a script
+        #[arg(short = 'y', long, global = true)]
+        non_interactive: bool,
     },
 }
 
@@ -52,10 +68,23 @@ enum Commands {
     /// Display information about the connected board and exit without flashing
     BoardInfo(ConnectArgs),
     /// Flash an application to a target device
-    Flash(FlashArgs),
+    Flash(Box<FlashArgs>),
     Monitor(MonitorArgs),
     PartitionTable(PartitionTableArgs),
     SaveImage(SaveImageArgs),
+    /// Display the anti-rollback secure version of the connected board, or
+    /// burn its eFuses to raise it
+    ///
+    /// The second-stage bootloader refuses to boot applications whose secure
+    /// version is lower than the device's. Burning eFuses is irreversible.
+    /// Only supported by the ESP32-C3 and ESP32-S3.
+    SecureVersion(SecureVersionArgs),
+    /// Display the security-related state of the connected board
+    ///
+    /// This includes whether secure boot, flash encryption and secure download
+    /// mode are enabled, and whether JTAG or USB access has been disabled. Not
+    /// supported by the ESP8266 or ESP32.
+    SecurityInfo(ConnectArgs),
 }
 
 #[derive(Debug, Args)]
@@ -109,7 +138,7 @@ struct FlashArgs {
 #[derive(Debug, Args)]
 struct SaveImageArgs {
     /// Image format to flash
-    #[arg(long, value_enum)]
+    #[arg(long, value_name = "FORMAT", value_parser = ImageFormatKind::from_str)]
     pub format: Option<ImageFormatKind>,
 
     #[clap(flatten)]
@@ -120,11 +149,17 @@ struct SaveImageArgs {
 
 fn main() -> Result<()> {
     miette::set_panic_hook();
-    initialize_logger(LevelFilter::Info);
 
     // Attempt to parse any provided comand-line arguments, or print the help
     // message and terminate if the invocation is not correct.
-    let CargoSubcommand::Espflash { subcommand: args } = Cli::parse().subcommand;
+    let CargoSubcommand::Espflash {
+        subcommand: args,
+        verbosity,
+        non_interactive,
+    } = Cli::parse().subcommand;
+    initialize_logger(verbosity.level_filter());
+    prompt::set_non_interactive(non_interactive);
+
     debug!("{:#?}", args);
 
     // Only check for updates once the command-line arguments have been processed,
@@ -139,10 +174,16 @@ fn main() -> Result<()> {
     // associated arguments.
     match args {
         Commands::BoardInfo(args) => board_info(&args, &config),
-        Commands::Flash(args) => flash(args, &config),
-        Commands::Monitor(args) => serial_monitor(args, &config),
+        Commands::Flash(args) => flash(*args, &config),
+        Commands::Monitor(args) => serial_monitor(args, &config).map(|code| {
+            if let Some(code) = code {
+                exit(code);
+            }
+        }),
         Commands::PartitionTable(args) => partition_table(args),
-        Commands::SaveImage(args) => save_image(args),
+        Commands::SaveImage(args) => save_image(args, &config),
+        Commands::SecureVersion(args) => secure_version(&args, &config),
+        Commands::SecurityInfo(args) => security_info(&args, &config),
     }
 }
 
@@ -155,12 +196,69 @@ struct BuildContext {
 
 fn flash(args: FlashArgs, config: &Config) -> Result<()> {
     let metadata = PackageMetadata::load(&args.build_args.package)?;
+    if !args.flash_args.watch {
+        flash_once(&args, &metadata, config, None)?;
+        return Ok(());
+    }
+
+    let paths = std::iter::once(&metadata.package_root)
+        .chain(args.flash_args.bins.iter().map(|app| &app.path))
+        .chain(
+            args.flash_args
+                .bootloader
+                .iter()
+                .chain(&metadata.bootloader),
+        )
+        .chain(
+            args.flash_args
+                .partition_table
+                .iter()
+                .chain(&metadata.partition_table),
+        )
+        .cloned()
+        .collect();
+    let mut watcher = Watcher::new(paths);
+
+    loop {
+        match flash_once(&args, &metadata, config, Some(&mut watcher)) {
+            Ok(false) => return Ok(()),
+            Ok(true) => {}
+            // Errors are reported without exiting, as they may be fixed by the next change
+            Err(err) => {
+                eprintln!("{err:?}");
+                info!("Waiting for changes...");
+                watcher.wait();
+            }
+        }
+        info!("Changes detected, rebuilding...");
+    }
+}
+
+/// Build the package and flash the device, then monitor it if requested
+///
+/// When watching for changes, returns `true` once a change has been detected.
+fn flash_once(
+    args: &FlashArgs,
+    metadata: &PackageMetadata,
+    config: &Config,
+    mut watcher: Option<&mut Watcher>,
+) -> Result<bool> {
     let cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
 
     let mut flasher = connect(&args.connect_args, config)?;
+    flasher.set_ignore_chip_rev(args.flash_args.ignore_chip_rev);
+    if let Some(spi_params) = args.flash_args.external_flash {
+        flasher.attach_external_flash(spi_params)?;
+        flasher.set_verify(true);
+    }
+    flasher.set_skip_unchanged(watcher.is_some());
+    flasher.set_encrypted_partitions(args.flash_args.encrypt_partitions.clone());
+    let flash_size = args
+        .build_args
+        .flash_config_args
+        .apply_flash_size(&mut flasher)?;
     let chip = flasher.chip();
-    let target = chip.into_target();
-    let target_xtal_freq = target.crystal_freq(flasher.connection())?;
+    let target_xtal_freq = flasher.crystal_freq();
     flasher.disable_watchdog()?;
 
     let build_ctx =
@@ -180,6 +278,8 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             .as_deref()
             .or(metadata.bootloader.as_deref())
             .or(build_ctx.bootloader_path.as_deref());
+        let bootloader = resolve_bootloader(bootloader, chip, config)?;
+        let bootloader = bootloader.as_deref();
 
         let partition_table = args
             .flash_args
@@ -195,6 +295,7 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             println!("Partition table:   {}", path.display());
         }
 
+        check_partition_table(&mut flasher, partition_table, args.flash_args.migrate_nvs);
         let partition_table = match partition_table {
             Some(path) => Some(parse_partition_table(path)?),
             None => None,
@@ -204,21 +305,46 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             erase_partitions(
                 &mut flasher,
                 partition_table.clone(),
-                args.flash_args.erase_parts,
-                args.flash_args.erase_data_parts,
+                args.flash_args.erase_parts.clone(),
+                args.flash_args.erase_data_parts.clone(),
+            )?;
+        }
+
+        let flash_data = args
+            .flash_args
+            .flash_data()
+            .bootloader(read_bootloader(bootloader)?)
+            .partition_table(partition_table)
+            .flash_mode(args.build_args.flash_config_args.flash_mode)
+            .flash_size(flash_size)
+            .flash_freq(args.build_args.flash_config_args.flash_freq)
+            .build();
+
+        if args.flash_args.bins.is_empty() {
+            flash_elf_image(
+                &mut flasher,
+                &elf_data,
+                flash_data,
+                args.flash_args.format.or(metadata.format),
+            )?;
+        } else {
+            let bins = read_app_partitions(&args.flash_args.bins)?;
+            let apps = std::iter::once((elf_data.as_slice(), None))
+                .chain(
+                    bins.iter()
+                        .map(|(data, label)| (data.as_slice(), Some(*label))),
+                )
+                .collect::<Vec<_>>();
+
+            flash_apps(
+                &mut flasher,
+                &apps,
+                flash_data,
+                args.flash_args.format.or(metadata.format),
             )?;
         }
 
-        flash_elf_image(
-            &mut flasher,
-            &elf_data,
-            bootloader,
-            partition_table,
-            args.flash_args.format.or(metadata.format),
-            args.build_args.flash_config_args.flash_mode,
-            args.build_args.flash_config_args.flash_size,
-            args.build_args.flash_config_args.flash_freq,
-        )?;
+        print_flash_summary(flasher.flash_summary(), args.flash_args.summary)?;
     }
 
     if args.flash_args.monitor {
@@ -232,16 +358,33 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
                 115_200
             };
 
-        monitor(
+        let reason = monitor_until(
             flasher.into_interface(),
-            Some(&elf_data),
+            chip,
+            &[&elf_data],
             pid,
             args.flash_args.monitor_baud.unwrap_or(default_baud),
+            &args.flash_args.monitor_options,
+            &config.monitor.hooks(),
+            &mut || watcher.as_mut().map_or(false, |watcher| watcher.changed()),
         )
         .into_diagnostic()?;
+
+        if let MonitorExit::ExitCode(code) = reason {
+            exit(code);
+        }
+
+        return Ok(reason == MonitorExit::Stopped);
     }
 
-    Ok(())
+    match watcher {
+        Some(watcher) => {
+            info!("Waiting for changes...");
+            watcher.wait();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
 }
 
 fn build(
@@ -376,8 +519,10 @@ fn build(
                 }
             }
             Message::CompilerMessage(message) => {
+                // Compiler messages are displayed on stderr, as by Cargo, so that they do
+                // not corrupt an image written to stdout
                 if let Some(rendered) = message.message.rendered {
-                    print!("{}", rendered);
+                    eprint!("{}", rendered);
                 }
             }
             // Ignore all other messages.
@@ -405,7 +550,8 @@ fn build(
     Ok(build_ctx)
 }
 
-fn save_image(args: SaveImageArgs) -> Result<()> {
+fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
+    let flash_size = args.build_args.flash_config_args.offline_flash_size()?;
     let metadata = PackageMetadata::load(&args.build_args.package)?;
     let cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
 
@@ -417,8 +563,8 @@ fn save_image(args: SaveImageArgs) -> Result<()> {
         .bootloader
         .as_deref()
         .or(metadata.bootloader.as_deref())
-        .or(build_ctx.bootloader_path.as_deref())
-        .map(|p| p.to_path_buf());
+        .or(build_ctx.bootloader_path.as_deref());
+    let bootloader = resolve_bootloader(bootloader, args.save_image_args.chip, config)?;
 
     let partition_table = args
         .save_image_args
@@ -430,31 +576,75 @@ fn save_image(args: SaveImageArgs) -> Result<()> {
 
     // Since we have no `Flasher` instance and as such cannot print the board
     // information, we will print whatever information we _do_ have.
-    println!("Chip type:         {}", args.save_image_args.chip);
+    let mut out = args.save_image_args.info_output();
+    writeln!(out, "Chip type:         {}", args.save_image_args.chip).into_diagnostic()?;
     if let Some(format) = args.format {
-        println!("Image format:      {:?}", format);
+        writeln!(out, "Image format:      {:?}", format).into_diagnostic()?;
+    }
+    let merge = args.save_image_args.merge();
+    writeln!(out, "Merge:             {}", merge).into_diagnostic()?;
+    if args.save_image_args.split {
+        writeln!(out, "Split:             true").into_diagnostic()?;
     }
-    println!("Merge:             {}", args.save_image_args.merge);
-    println!("Skip padding:      {}", args.save_image_args.skip_padding);
-    if let Some(path) = &args.save_image_args.bootloader {
-        println!("Bootloader:        {}", path.display());
+    writeln!(
+        out,
+        "Skip padding:      {}",
+        args.save_image_args.skip_padding
+    )
+    .into_diagnostic()?;
+    if let Some(size) = args.save_image_args.pad_to_size {
+        writeln!(out, "Pad to size:       {size}").into_diagnostic()?;
+    }
+    writeln!(
+        out,
+        "Reproducible:      {}",
+        args.save_image_args.reproducible
+    )
+    .into_diagnostic()?;
+    if let Some((major, minor)) = args.save_image_args.min_chip_rev {
+        writeln!(out, "Minimum revision:  v{major}.{minor}").into_diagnostic()?;
+    }
+    if let Some(secure_version) = args.save_image_args.secure_version {
+        writeln!(out, "Secure version:    {secure_version}").into_diagnostic()?;
+    }
+    if args.save_image_args.no_hash_appended {
+        writeln!(out, "Hash appended:     false").into_diagnostic()?;
+    }
+    if args.save_image_args.qemu {
+        writeln!(out, "QEMU:              true").into_diagnostic()?;
+        check_qemu_flash_size(args.save_image_args.pad_to_size.or(flash_size))?;
+    }
+    if let Some(path) = &bootloader {
+        writeln!(out, "Bootloader:        {}", path.display()).into_diagnostic()?;
     }
     if let Some(path) = &args.save_image_args.partition_table {
-        println!("Partition table:   {}", path.display());
+        writeln!(out, "Partition table:   {}", path.display()).into_diagnostic()?;
     }
 
+    let flash_data = args
+        .save_image_args
+        .flash_data()
+        .bootloader(read_bootloader(bootloader.as_deref())?)
+        .partition_table(match &partition_table {
+            Some(path) => Some(parse_partition_table(path)?),
+            None => None,
+        })
+        .flash_mode(args.build_args.flash_config_args.flash_mode)
+        .flash_size(flash_size)
+        .flash_freq(args.build_args.flash_config_args.flash_freq)
+        .build();
+
     save_elf_as_image(
         args.save_image_args.chip,
         &elf_data,
         args.save_image_args.file,
         args.format.or(metadata.format),
-        args.build_args.flash_config_args.flash_mode,
-        args.build_args.flash_config_args.flash_size,
-        args.build_args.flash_config_args.flash_freq,
-        args.save_image_args.merge,
-        bootloader,
-        partition_table,
+        flash_data,
+        merge,
+        args.save_image_args.split,
         args.save_image_args.skip_padding,
+        args.save_image_args.pad_to_size,
+        args.save_image_args.fill,
     )?;
 
     Ok(())
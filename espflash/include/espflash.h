@@ -0,0 +1,62 @@
+/*
+ * C API for espflash, available when built with the `ffi` feature.
+ *
+ * All functions returning `int` return `ESPFLASH_OK` on success, or a negative
+ * error code on failure; a description of the error can then be retrieved
+ * using `espflash_last_error`.
+ */
+
+#ifndef ESPFLASH_H
+#define ESPFLASH_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ESPFLASH_OK 0
+#define ESPFLASH_ERR_INVALID_ARGUMENT (-1)
+#define ESPFLASH_ERR_FAILED (-2)
+#define ESPFLASH_ERR_PANIC (-3)
+
+/* Opaque handle to a connected target device */
+typedef struct EspflashFlasher EspflashFlasher;
+
+/* Callbacks used to report the progress of flashing; any may be NULL */
+typedef struct EspflashProgressCallbacks {
+    void *user_data;
+    void (*init)(void *user_data, uint32_t addr, size_t total);
+    void (*update)(void *user_data, size_t current);
+    void (*finish)(void *user_data);
+} EspflashProgressCallbacks;
+
+/*
+ * Connect to the target device attached to the serial port `port`, changing
+ * the baud rate to `baud` if it is non-zero. The handle written to `flasher`
+ * must be released using `espflash_free`.
+ */
+int espflash_connect(const char *port, uint32_t baud, EspflashFlasher **flasher);
+
+/*
+ * Write the ELF image `data` of `len` bytes to flash, along with the default
+ * bootloader and partition table, and reset the device. `progress` may be NULL.
+ */
+int espflash_flash_elf(EspflashFlasher *flasher, const uint8_t *data, size_t len,
+                       const EspflashProgressCallbacks *progress);
+
+/* Disconnect from the device and release the handle */
+void espflash_free(EspflashFlasher *flasher);
+
+/*
+ * Describe the most recent error on the calling thread, or NULL if there was
+ * none. The string remains valid until the next call on the same thread.
+ */
+const char *espflash_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ESPFLASH_H */
@@ -1,62 +1,447 @@
 use std::{
+    borrow::Cow,
     fs::{self, File},
-    io::Read,
+    io::{Read, Write},
     num::ParseIntError,
     path::PathBuf,
+    process::exit,
+    str::FromStr,
+    time::Duration,
 };
 
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+#[cfg(feature = "self-update")]
+use espflash::cli::self_update::{self_update, SelfUpdateArgs};
 use espflash::{
     cli::{
-        self, board_info, config::Config, connect, erase_partitions, flash_elf_image,
-        monitor::monitor, parse_partition_table, partition_table, print_board_info,
-        save_elf_as_image, serial_monitor, ConnectArgs, EspflashProgress, FlashConfigArgs,
-        MonitorArgs, PartitionTableArgs,
+        self, board_info,
+        bootloader::resolve_bootloader,
+        check_build_target, check_partition_table, check_qemu_flash_size,
+        completions::{completions, CompletionsArgs},
+        config::Config,
+        connect, connect_updater, display_image_size,
+        doctor::doctor,
+        dry_run, erase_partitions,
+        esptool::{esptool_compat, EsptoolArgs},
+        filesystem::{find_fs_partition, load_fs_image, FilesystemKind},
+        flash_app_bin, flash_apps, flash_elf_image, flash_jtag,
+        idf::BuildDir,
+        infer_chip,
+        keygen::{keygen, KeygenArgs},
+        monitor::{
+            expect_output, monitor, monitor_until, MonitorExit, MonitorOptions, TestOutcome,
+        },
+        parse_partition_table, partition_table,
+        ports::{list_ports, ListPortsArgs},
+        print_board_info, print_flash_summary, prompt, read_app_partitions, read_bootloader,
+        read_image, reset_device, save_elf_as_image,
+        script::{script, ScriptArgs},
+        secure_version, security_info, serial_monitor,
+        shell::{shell, ShellArgs},
+        snapshot::{snapshot, SnapshotArgs},
+        updater_image,
+        watch::Watcher,
+        write_jtag, ConnectArgs, EspflashProgress, FlashConfigArgs, JtagArgs, MonitorArgs,
+        PartitionTableArgs, SecureVersionArgs, VerbosityArgs,
     },
-    image_format::ImageFormatKind,
+    elf::RomSegment,
+    error::Error,
+    flasher::{FlashData, SpiAttachParams, FLASH_STATUS_PROTECTION},
+    image_format::{image_to_elf, is_app_image, ImageFormatKind, ImageInfo, SizeReport},
     logging::initialize_logger,
+    security::{
+        is_signed, sign_image, verify_signatures, ExternalSigner, Signer, SigningKey, VerifyingKey,
+    },
     targets::Chip,
     update::check_for_update,
 };
-use log::{debug, LevelFilter};
-use miette::{IntoDiagnostic, Result, WrapErr};
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, Parser)]
 #[clap(about, version, propagate_version = true)]
 struct Cli {
     #[clap(subcommand)]
     subcommand: Commands,
+    #[clap(flatten)]
+    verbosity: VerbosityArgs,
+    /// Don't ask for confirmation before erasing or overwriting data on the
+    /// device, eg. when running from a script
+    #[arg(short = 'y', long, global = true)]
+    non_interactive: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Display information about the connected board and exit without flashing
     BoardInfo(ConnectArgs),
+    /// Generate shell completions
+    ///
+    /// For Bash, Zsh and Fish, the `--port` option is completed with the
+    /// currently attached serial ports.
+    Completions(CompletionsArgs),
+    /// Check the host system for common problems which prevent flashing
+    ///
+    /// This includes missing drivers for USB to UART bridges, insufficient
+    /// permissions to open serial ports, and unreliable USB hubs.
+    Doctor,
+    /// Erase the entire flash chip
+    ///
+    /// Requires the flasher stub, so is not supported with `--no-stub`.
+    EraseFlash(ConnectArgs),
+    /// Run an `esptool.py` command, using its command-line syntax
+    ///
+    /// Only `write_flash` is supported, eg. `espflash esptool-compat --chip
+    /// esp32 write_flash 0x1000 boot.bin 0x8000 parts.bin 0x10000 app.bin`.
+    /// This allows espflash to be used as the `upload_command` of PlatformIO
+    /// projects.
+    EsptoolCompat(EsptoolArgs),
     /// Flash an application to a target device
     Flash(FlashArgs),
+    /// Read the flash chip's status registers, or clear its write protection
+    ///
+    /// Some boards ship with the flash chip's write protection enabled, which
+    /// must be cleared before it can be written to.
+    FlashStatus(FlashStatusArgs),
+    /// Calculate the MD5 or SHA-256 digest of a region of flash on the device
+    ///
+    /// MD5 digests are calculated by the device, so the region does not need to
+    /// be read back over the serial connection.
+    HashFlash(HashFlashArgs),
+    /// Reset the target device into its bootloader and leave it there
+    ///
+    /// The device is not connected to, so another tool may then communicate
+    /// with its bootloader. This is the same as `reset --bootloader`.
+    HoldInBootloader(ConnectArgs),
+    /// Display information about an application image and validate it
+    ImageInfo(ImageInfoArgs),
+    /// Reconstruct an ELF file from an application image
+    ///
+    /// Only the loadable segments can be recovered; the resulting ELF does not
+    /// contain any symbols or debug information.
+    ImageToElf(ImageToElfArgs),
+    /// Generate a key for secure boot or flash encryption
+    ///
+    /// Secure boot V2 signing keys are written in PEM format, and the digest
+    /// of the public key, which is burned into the eFuses, is displayed. Flash
+    /// encryption keys are written as raw binary.
+    Keygen(KeygenArgs),
+    /// List the attached serial ports
+    ///
+    /// Displays each port's USB vendor and product IDs, manufacturer, product,
+    /// serial number and USB port path. With `--probe`, each port is also
+    /// connected to in order to detect the attached chip.
+    ListPorts(ListPortsArgs),
+    /// Load an application to RAM and execute it, without touching flash
+    ///
+    /// Only ELF images whose segments are all loaded to RAM are supported.
+    LoadRam(LoadRamArgs),
     Monitor(MonitorArgs),
     PartitionTable(PartitionTableArgs),
+    /// Read words of the device's memory, such as its registers
+    ReadMem(ReadMemArgs),
+    /// Reset the target device, without connecting to it
+    ///
+    /// Only the reset sequence is performed, so the device runs its
+    /// application, or with `--bootloader` is left in its bootloader.
+    Reset(ResetArgs),
     SaveImage(SaveImageArgs),
+    /// Run a script of operations, such as a provisioning flow
+    ///
+    /// The script is a TOML file listing steps such as 'connect',
+    /// 'erase-parts', 'write-bin', 'verify' and 'reset', which are performed
+    /// over a single connection to the device.
+    Script(ScriptArgs),
+    /// Display the anti-rollback secure version of the connected board, or
+    /// burn its eFuses to raise it
+    ///
+    /// The second-stage bootloader refuses to boot applications whose secure
+    /// version is lower than the device's. Burning eFuses is irreversible.
+    /// Only supported by the ESP32-C3 and ESP32-S3.
+    SecureVersion(SecureVersionArgs),
+    /// Display the security-related state of the connected board
+    ///
+    /// This includes whether secure boot, flash encryption and secure download
+    /// mode are enabled, and whether JTAG or USB access has been disabled. Not
+    /// supported by the ESP8266 or ESP32.
+    SecurityInfo(ConnectArgs),
+    /// Update espflash to the latest release published on GitHub
+    ///
+    /// The downloaded release is verified using its published SHA-256
+    /// checksum before the current executable is replaced.
+    #[cfg(feature = "self-update")]
+    SelfUpdate(SelfUpdateArgs),
+    /// Open an interactive shell which runs commands over a single connection
+    ///
+    /// The device is synchronized with once, and then commands such as
+    /// 'board-info', 'read-flash' and 'flash' are read from stdin and run
+    /// without connecting again.
+    Shell(ShellArgs),
+    /// Sign a pre-built application or bootloader image for secure boot V2
+    ///
+    /// The image's appended digest is verified, and the image is padded to a
+    /// sector boundary and followed by a sector containing its signature
+    /// block. With `--detached`, only the signature sector is written.
+    SignImage(SignImageArgs),
+    /// Report how much of the chip's memory an application uses
+    ///
+    /// The usage of each memory region is reported against the chip's limits,
+    /// along with how full the partition the application is written to is.
+    Size(SizeArgs),
+    /// Save or restore a snapshot of the device's flash
+    ///
+    /// Snapshots contain the entire flash, or selected partitions, along with
+    /// information about the board, so that a device can be returned to a known
+    /// state later, eg. between test runs.
+    Snapshot(SnapshotArgs),
+    /// Flash an application, then wait for it to report success or failure
+    ///
+    /// The application's output is displayed until a line matching `--expect`
+    /// or one of the `--fail` patterns is received. Exits with status 0 if the
+    /// test passed, 1 if it failed, and 2 if it timed out.
+    Test(TestArgs),
+    /// Verify an application or bootloader image, and its signatures
+    ///
+    /// Checks the image's checksum, its appended SHA-256 digest and any secure
+    /// boot V2 signature blocks. Exits with status 2 if the checksum is
+    /// invalid, 3 if the digest is invalid, 4 if a signature is invalid, and 5
+    /// if the image is not signed with the key given by `--key`.
+    VerifyImage(VerifyImageArgs),
     WriteBin(WriteBinArgs),
+    WriteFs(WriteFsArgs),
+    /// Write a word to the device's memory, such as one of its registers
+    WriteMem(WriteMemArgs),
 }
 
 #[derive(Debug, Args)]
+#[command(group(ArgGroup::new("offline").args(["dry_run", "jtag"])))]
 struct FlashArgs {
-    /// ELF image to flash
-    image: PathBuf,
+    /// ELF image or pre-built application binary (.bin) to flash, or '-' to
+    /// read it from stdin
+    #[arg(required_unless_present_any = ["bins", "idf_build_dir"])]
+    image: Option<PathBuf>,
+    /// ESP-IDF build directory; flash each binary listed in its
+    /// `flasher_args.json` file at its offset
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["image", "bins", "bootloader", "partition_table", "ram", "dry_run", "jtag"]
+    )]
+    idf_build_dir: Option<PathBuf>,
+    /// Chip to validate the image for when using `--dry-run` or `--jtag`;
+    /// inferred from the image's build target if not provided
+    #[arg(long, value_enum, requires = "offline")]
+    chip: Option<Chip>,
+    /// Validate the image and display what would be written to flash, without
+    /// connecting to a device
+    #[arg(
+        long,
+        conflicts_with_all = ["erase_parts", "erase_data_parts", "external_flash", "monitor", "ram"]
+    )]
+    dry_run: bool,
+    /// Flash the device over JTAG using OpenOCD, rather than over a serial
+    /// port
+    ///
+    /// This is useful for boards on which the UART pins are not broken out,
+    /// but the JTAG pins or the built-in USB-JTAG peripheral are.
+    #[arg(
+        long,
+        conflicts_with_all = ["erase_parts", "erase_data_parts", "external_flash", "monitor", "ram", "watch"]
+    )]
+    jtag: bool,
+    /// Write the application using a protocol provided by the application
+    /// which is running on the device, rather than the ROM bootloader
+    #[arg(
+        long,
+        value_name = "PROTOCOL",
+        value_enum,
+        requires = "image",
+        conflicts_with_all = [
+            "bins", "bootloader_only", "dry_run", "erase_parts", "erase_data_parts",
+            "external_flash", "jtag", "no_app", "partition_table_only", "ram", "watch",
+        ]
+    )]
+    app_protocol: Option<AppProtocol>,
 
     #[clap(flatten)]
     connect_args: ConnectArgs,
     #[clap(flatten)]
+    jtag_args: JtagArgs,
+    #[clap(flatten)]
     pub flash_config_args: FlashConfigArgs,
     #[clap(flatten)]
     flash_args: cli::FlashArgs,
 }
 
+/// Protocol provided by an application for updating it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AppProtocol {
+    /// The serial updater protocol, which writes the application to the next
+    /// OTA partition over the console UART
+    SerialUpdater,
+}
+
+#[derive(Debug, Args)]
+struct LoadRamArgs {
+    /// ELF image to load, or '-' to read it from stdin
+    image: PathBuf,
+    /// Open a serial monitor after loading
+    #[arg(short = 'M', long)]
+    monitor: bool,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    #[clap(flatten)]
+    monitor_options: MonitorOptions,
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("operation").required(true).multiple(true)))]
+struct FlashStatusArgs {
+    /// Read the status registers
+    #[arg(long, group = "operation")]
+    read: bool,
+    /// Clear the write protection bits of the status registers
+    #[arg(long, group = "operation")]
+    clear_protection: bool,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct HashFlashArgs {
+    /// Address of the region to hash
+    #[arg(long, value_parser = parse_uint32)]
+    addr: u32,
+    /// Length of the region to hash, in bytes
+    #[arg(long, value_parser = parse_uint32)]
+    len: u32,
+    /// Calculate the MD5 digest on the device; this is the default
+    #[arg(long, conflicts_with = "sha256")]
+    md5: bool,
+    /// Calculate the SHA-256 digest
+    ///
+    /// The flasher stub can only calculate MD5 digests, so the region is read
+    /// back over the serial connection and hashed on the host.
+    #[arg(long)]
+    sha256: bool,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct ReadMemArgs {
+    /// Address to start reading from
+    #[arg(value_parser = parse_uint32)]
+    addr: u32,
+    /// Number of bytes to read
+    #[arg(value_parser = parse_uint32, default_value = "4")]
+    len: u32,
+    /// Write the memory's contents to a file, instead of displaying them
+    #[arg(short = 'o', long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct ResetArgs {
+    /// Reset the device into its bootloader, and leave it there
+    #[arg(long)]
+    bootloader: bool,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct WriteMemArgs {
+    /// Address to write to
+    #[arg(value_parser = parse_uint32)]
+    addr: u32,
+    /// Value to write
+    #[arg(value_parser = parse_uint32)]
+    value: u32,
+    /// Only modify the bits which are set in the mask
+    #[arg(long, value_parser = parse_uint32)]
+    mask: Option<u32>,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct ImageInfoArgs {
+    /// Chip the image was built for; detected from the image if not provided
+    #[arg(long, value_enum)]
+    chip: Option<Chip>,
+    /// Application image to inspect, or '-' to read it from stdin
+    image: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct SignImageArgs {
+    /// Application or bootloader image to sign
+    image: PathBuf,
+    /// Secure boot V2 signing key, in PEM format, or a PKCS#11 URI
+    /// referencing a key held by a token
+    ///
+    /// When `--signer-cmd` is used this is the public key instead.
+    #[arg(long, value_name = "KEY")]
+    key: String,
+    /// Command which signs the image digest, which it receives on stdin,
+    /// writing the signature to stdout
+    #[arg(long, value_name = "CMD")]
+    signer_cmd: Option<String>,
+    /// Append the signature sector to the image, which is the default
+    #[arg(long, conflicts_with = "detached")]
+    append: bool,
+    /// Write only the signature sector to this file, leaving the image
+    /// unchanged
+    ///
+    /// The signature covers the image padded to a multiple of 4KB, so images
+    /// of any other size must also be given `--output` to write the padded
+    /// image to.
+    #[arg(long, value_name = "FILE")]
+    detached: Option<PathBuf>,
+    /// File to write the signed image to, or with `--detached` the padded
+    /// image; the image is signed in place if not provided
+    #[arg(short = 'o', long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct VerifyImageArgs {
+    /// Chip the image was built for; detected from the image if not provided
+    #[arg(long, value_enum)]
+    chip: Option<Chip>,
+    /// Public key, in PEM format, which the image must be signed with
+    #[arg(long, value_name = "FILE")]
+    key: Option<PathBuf>,
+    /// Application or bootloader image to verify, or '-' to read it from stdin
+    image: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ImageToElfArgs {
+    /// Chip the image was built for; detected from the image if not provided
+    #[arg(long, value_enum)]
+    chip: Option<Chip>,
+    /// Application image to convert, or '-' to read it from stdin
+    image: PathBuf,
+    /// File name to save the ELF file to
+    file: PathBuf,
+}
+
 #[derive(Debug, Args)]
 struct SaveImageArgs {
     /// Image format to flash
-    #[arg(long, value_enum)]
+    #[arg(long, value_name = "FORMAT", value_parser = ImageFormatKind::from_str)]
     format: Option<ImageFormatKind>,
 
     #[clap(flatten)]
@@ -64,10 +449,69 @@ struct SaveImageArgs {
     #[clap(flatten)]
     save_image_args: cli::SaveImageArgs,
 
-    /// ELF image to flash
+    /// ELF image or pre-built application binary (.bin) to convert, or '-' to
+    /// read it from stdin
+    image: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct SizeArgs {
+    /// Chip the application was built for
+    #[arg(long, value_enum)]
+    chip: Chip,
+    /// Path to a CSV file containing the partition table
+    #[arg(short = 'T', long, value_name = "FILE")]
+    partition_table: Option<PathBuf>,
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = SizeFormat::Table)]
+    format: SizeFormat,
+    /// ELF image to report on, or '-' to read it from stdin
     image: PathBuf,
 }
 
+#[derive(Debug, Args)]
+struct TestArgs {
+    /// ELF image to flash, or '-' to read it from stdin
+    image: PathBuf,
+    /// Pattern indicating that the test passed
+    #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+    expect: Regex,
+    /// Pattern indicating that the test failed; may be repeated
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = Regex::new,
+        default_values = ["Guru Meditation Error", "panicked at", r"abort\(\) was called"]
+    )]
+    fail: Vec<Regex>,
+    /// Time to wait for either pattern, eg. '60s', '500ms' or '2m'
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "60s")]
+    test_timeout: Duration,
+    /// Path to a binary (.bin) bootloader file
+    #[arg(long, value_name = "FILE")]
+    bootloader: Option<PathBuf>,
+    /// Path to a CSV file containing partition table
+    #[arg(long, value_name = "FILE")]
+    partition_table: Option<PathBuf>,
+    /// Load the application to RAM instead of Flash
+    #[arg(long)]
+    ram: bool,
+    /// Baud rate at which to read console output
+    #[arg(long, value_name = "BAUD", default_value_t = 115_200)]
+    monitor_baud: u32,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    #[clap(flatten)]
+    flash_config_args: FlashConfigArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SizeFormat {
+    Table,
+    Json,
+}
+
 /// Writes a binary file to a specific address in the chip's flash
 #[derive(Debug, Args)]
 struct WriteBinArgs {
@@ -76,6 +520,41 @@ struct WriteBinArgs {
     pub addr: u32,
     /// File containing the binary data to write
     pub bin_file: String,
+    /// Write to a secondary flash chip connected to the SPI pins 'CLK,Q,D,HD,CS'
+    ///
+    /// The contents of the external flash are verified after writing.
+    #[arg(long, value_name = "PINS", value_parser = SpiAttachParams::from_str)]
+    pub external_flash: Option<SpiAttachParams>,
+    /// Write the binary over JTAG using OpenOCD, rather than over a serial
+    /// port
+    #[arg(long, requires = "chip", conflicts_with = "external_flash")]
+    pub jtag: bool,
+    /// Chip to write the binary to when using `--jtag`
+    #[arg(long, value_enum, requires = "jtag")]
+    pub chip: Option<Chip>,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    #[clap(flatten)]
+    jtag_args: JtagArgs,
+}
+
+/// Writes a filesystem image to a data partition in the chip's flash
+#[derive(Debug, Args)]
+struct WriteFsArgs {
+    /// Filesystem image, or a directory to generate the image from
+    path: PathBuf,
+    /// Filesystem to generate when a directory is provided
+    #[arg(long, value_enum, default_value_t = FilesystemKind::Littlefs)]
+    fs: FilesystemKind,
+    /// Label of the partition to write to
+    #[arg(long, value_name = "LABEL")]
+    partition: Option<String>,
+    /// Path to a CSV or binary file containing the device's partition table
+    ///
+    /// If not provided, the partition table is read from the device.
+    #[arg(long, value_name = "FILE")]
+    partition_table: Option<PathBuf>,
 
     #[clap(flatten)]
     connect_args: ConnectArgs,
@@ -85,13 +564,32 @@ fn parse_uint32(input: &str) -> Result<u32, ParseIntError> {
     parse_int::parse(input)
 }
 
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let (value, unit) = input
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((input, "s"), |i| input.split_at(i));
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration '{input}'"))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        _ => Err(format!("unknown unit '{unit}', expected 'ms', 's' or 'm'")),
+    }
+}
+
 fn main() -> Result<()> {
     miette::set_panic_hook();
-    initialize_logger(LevelFilter::Info);
 
     // Attempt to parse any provided comand-line arguments, or print the help
     // message and terminate if the invocation is not correct.
-    let args = Cli::parse().subcommand;
+    let cli = Cli::parse();
+    initialize_logger(cli.verbosity.level_filter());
+    prompt::set_non_interactive(cli.non_interactive);
+
+    let args = cli.subcommand;
     debug!("{:#?}", args);
 
     // Only check for updates once the command-line arguments have been processed,
@@ -106,29 +604,164 @@ fn main() -> Result<()> {
     // associated arguments.
     match args {
         Commands::BoardInfo(args) => board_info(&args, &config),
+        Commands::Completions(args) => completions(&args, &mut Cli::command(), "espflash"),
+        Commands::Doctor => doctor(),
+        Commands::EraseFlash(args) => erase_flash(&args, &config),
+        Commands::EsptoolCompat(args) => esptool_compat(args, &config),
         Commands::Flash(args) => flash(args, &config),
-        Commands::Monitor(args) => serial_monitor(args, &config),
+        Commands::FlashStatus(args) => flash_status(args, &config),
+        Commands::HashFlash(args) => hash_flash(args, &config),
+        Commands::HoldInBootloader(args) => reset_device(&args, &config, true),
+        Commands::ImageInfo(args) => image_info(args),
+        Commands::ImageToElf(args) => convert_image_to_elf(args),
+        Commands::Keygen(args) => keygen(&args),
+        Commands::ListPorts(args) => list_ports(&args),
+        Commands::LoadRam(args) => load_ram(args, &config),
+        Commands::Monitor(args) => serial_monitor(args, &config).map(exit_on_request),
         Commands::PartitionTable(args) => partition_table(args),
-        Commands::SaveImage(args) => save_image(args),
+        Commands::ReadMem(args) => read_mem(args, &config),
+        Commands::Reset(args) => reset_device(&args.connect_args, &config, args.bootloader),
+        Commands::SaveImage(args) => save_image(args, &config),
+        Commands::Script(args) => script(args, &config),
+        Commands::SecureVersion(args) => secure_version(&args, &config),
+        Commands::SecurityInfo(args) => security_info(&args, &config),
+        #[cfg(feature = "self-update")]
+        Commands::SelfUpdate(args) => {
+            self_update(&args, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        }
+        Commands::Shell(args) => shell(args, &config),
+        Commands::SignImage(args) => sign(args),
+        Commands::Size(args) => size(args),
+        Commands::Snapshot(args) => snapshot(args, &config),
+        Commands::Test(args) => test(args, &config),
+        Commands::VerifyImage(args) => verify_image(args),
         Commands::WriteBin(args) => write_bin(args, &config),
+        Commands::WriteFs(args) => write_fs(args, &config),
+        Commands::WriteMem(args) => write_mem(args, &config),
+    }
+}
+
+/// Exit with the code requested by a monitor hook, if any
+fn exit_on_request(code: Option<i32>) {
+    if let Some(code) = code {
+        exit(code);
     }
 }
 
+fn erase_flash(args: &ConnectArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(args, config)?;
+    print_board_info(&mut flasher)?;
+
+    prompt::confirm("Erase the entire flash?")?;
+    info!("Erasing flash...");
+    flasher.erase_flash()?;
+    info!("Flash has been erased!");
+
+    Ok(())
+}
+
 fn flash(args: FlashArgs, config: &Config) -> Result<()> {
+    if args.dry_run || args.jtag {
+        return flash_offline(args, config);
+    }
+    if let Some(AppProtocol::SerialUpdater) = args.app_protocol {
+        return flash_serial_updater(args, config);
+    }
+    if !args.flash_args.watch {
+        flash_once(&args, config, None)?;
+        return Ok(());
+    }
+
+    let paths = args
+        .image
+        .iter()
+        .chain(args.flash_args.bins.iter().map(|app| &app.path))
+        .chain(&args.flash_args.bootloader)
+        .chain(&args.flash_args.partition_table)
+        .chain(&args.idf_build_dir)
+        .cloned()
+        .collect();
+    let mut watcher = Watcher::new(paths);
+
+    loop {
+        match flash_once(&args, config, Some(&mut watcher)) {
+            Ok(false) => return Ok(()),
+            Ok(true) => {}
+            // Errors are reported without exiting, as they may be fixed by the next change
+            Err(err) => {
+                eprintln!("{err:?}");
+                info!("Waiting for changes...");
+                watcher.wait();
+            }
+        }
+        info!("Changes detected, reflashing...");
+    }
+}
+
+/// Flash the device, then monitor it if requested
+///
+/// When watching for changes, returns `true` once a change has been detected.
+fn flash_once(
+    args: &FlashArgs,
+    config: &Config,
+    mut watcher: Option<&mut Watcher>,
+) -> Result<bool> {
+    let build_dir = match &args.idf_build_dir {
+        Some(dir) => Some(BuildDir::load(dir)?),
+        None => None,
+    };
+
     let mut flasher = connect(&args.connect_args, config)?;
+    if let Some(path) = &args.image {
+        check_build_target(flasher.chip(), path)?;
+    }
+    if let Some(build_dir) = &build_dir {
+        if build_dir.chip != flasher.chip() {
+            return Err(Error::ImageChipMismatch {
+                part: "project",
+                expected: flasher.chip(),
+                found: build_dir.chip,
+            }
+            .into());
+        }
+    }
+    flasher.set_ignore_chip_rev(args.flash_args.ignore_chip_rev);
+    if let Some(spi_params) = args.flash_args.external_flash {
+        flasher.attach_external_flash(spi_params)?;
+        flasher.set_verify(true);
+    }
+    flasher.set_skip_unchanged(watcher.is_some());
+    flasher.set_encrypted_partitions(args.flash_args.encrypt_partitions.clone());
+    let flash_size = args.flash_config_args.apply_flash_size(&mut flasher)?;
     print_board_info(&mut flasher)?;
 
     let chip = flasher.chip();
-    let target = chip.into_target();
-    let target_xtal_freq = target.crystal_freq(flasher.connection())?;
+    let target_xtal_freq = flasher.crystal_freq();
 
     // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(&args.image).into_diagnostic()?;
+    let image_data = match &args.image {
+        Some(path) => Some(read_image(path)?),
+        None => None,
+    };
+    let bins = read_app_partitions(&args.flash_args.bins)?;
 
-    if args.flash_args.ram {
-        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+    // The main application is used for symbolizing the monitor's output, or the
+    // first additional application if there is none.
+    let elf_data = image_data
+        .as_deref()
+        .or_else(|| bins.first().map(|(data, _)| data.as_slice()))
+        .or_else(|| build_dir.as_ref().and_then(|dir| dir.elf.as_deref()))
+        .unwrap_or_default();
+
+    if let Some(build_dir) = &build_dir {
+        flasher.write_bins_to_flash(&build_dir.segments, Some(&mut EspflashProgress::default()))?;
+
+        print_flash_summary(flasher.flash_summary(), args.flash_args.summary)?;
+    } else if args.flash_args.ram {
+        flasher.load_elf_to_ram(elf_data, Some(&mut EspflashProgress::default()))?;
     } else {
-        let bootloader = args.flash_args.bootloader.as_deref();
+        let bootloader = resolve_bootloader(args.flash_args.bootloader.as_deref(), chip, config)?;
+        let bootloader = bootloader.as_deref();
         let partition_table = args.flash_args.partition_table.as_deref();
 
         if let Some(path) = bootloader {
@@ -138,6 +771,7 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             println!("Partition table:   {}", path.display());
         }
 
+        check_partition_table(&mut flasher, partition_table, args.flash_args.migrate_nvs);
         let partition_table = match partition_table {
             Some(path) => Some(parse_partition_table(path)?),
             None => None,
@@ -147,21 +781,43 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             erase_partitions(
                 &mut flasher,
                 partition_table.clone(),
-                args.flash_args.erase_parts,
-                args.flash_args.erase_data_parts,
+                args.flash_args.erase_parts.clone(),
+                args.flash_args.erase_data_parts.clone(),
             )?;
         }
 
-        flash_elf_image(
-            &mut flasher,
-            &elf_data,
-            bootloader,
-            partition_table,
-            args.flash_args.format,
-            args.flash_config_args.flash_mode,
-            args.flash_config_args.flash_size,
-            args.flash_config_args.flash_freq,
-        )?;
+        let flash_data = args
+            .flash_args
+            .flash_data()
+            .bootloader(read_bootloader(bootloader)?)
+            .partition_table(partition_table)
+            .flash_mode(args.flash_config_args.flash_mode)
+            .flash_size(flash_size)
+            .flash_freq(args.flash_config_args.flash_freq)
+            .build();
+
+        if !bins.is_empty() {
+            let apps = image_data
+                .iter()
+                .map(|data| (data.as_slice(), None))
+                .chain(
+                    bins.iter()
+                        .map(|(data, label)| (data.as_slice(), Some(*label))),
+                )
+                .collect::<Vec<_>>();
+
+            flash_apps(&mut flasher, &apps, flash_data, args.flash_args.format)?;
+        } else if is_app_image(elf_data) {
+            if args.flash_args.format.is_some() {
+                warn!("The image format is ignored when flashing a pre-built application binary");
+            }
+
+            flash_app_bin(&mut flasher, elf_data, flash_data)?;
+        } else {
+            flash_elf_image(&mut flasher, elf_data, flash_data, args.flash_args.format)?;
+        }
+
+        print_flash_summary(flasher.flash_summary(), args.flash_args.summary)?;
     }
 
     if args.flash_args.monitor {
@@ -175,65 +831,741 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
                 115_200
             };
 
-        monitor(
+        let reason = monitor_until(
             flasher.into_interface(),
-            Some(&elf_data),
+            chip,
+            &[elf_data],
             pid,
             args.flash_args.monitor_baud.unwrap_or(default_baud),
+            &args.flash_args.monitor_options,
+            &config.monitor.hooks(),
+            &mut || watcher.as_mut().map_or(false, |watcher| watcher.changed()),
+        )
+        .into_diagnostic()?;
+        if let MonitorExit::ExitCode(code) = reason {
+            exit(code);
+        }
+
+        return Ok(reason == MonitorExit::Stopped);
+    }
+
+    match watcher {
+        Some(watcher) => {
+            info!("Waiting for changes...");
+            watcher.wait();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn flash_serial_updater(args: FlashArgs, config: &Config) -> Result<()> {
+    let mut updater = connect_updater(&args.connect_args, config)?;
+    let chip = updater.info().chip;
+    println!("Chip type:         {chip}");
+    println!("Partition size:    {}", updater.info().partition_size);
+
+    // The image is required when using an application protocol
+    let path = args.image.as_deref().unwrap();
+    check_build_target(chip, path)?;
+    let image_data = read_image(path)?;
+
+    let flash_data = FlashData::builder()
+        .flash_mode(args.flash_config_args.flash_mode)
+        .flash_size(args.flash_config_args.offline_flash_size()?)
+        .flash_freq(args.flash_config_args.flash_freq)
+        .min_chip_rev(args.flash_args.min_chip_rev)
+        .secure_version(args.flash_args.secure_version)
+        .no_hash_appended(args.flash_args.no_hash_appended)
+        .image_version(args.flash_args.image_version)
+        .build();
+    let app = updater_image(chip, &image_data, flash_data, args.flash_args.format)?;
+
+    updater.update(&app, Some(&mut EspflashProgress::default()))?;
+    updater.reboot()?;
+    info!("The application has been updated, rebooting into it");
+
+    if args.flash_args.monitor {
+        let pid = updater.get_usb_pid()?;
+        let code = monitor(
+            updater.into_interface(),
+            chip,
+            &[&image_data],
+            pid,
+            args.flash_args
+                .monitor_baud
+                .or(args.connect_args.baud)
+                .unwrap_or(115_200),
+            &args.flash_args.monitor_options,
+            &config.monitor.hooks(),
+        )
+        .into_diagnostic()?;
+        exit_on_request(code);
+    }
+
+    Ok(())
+}
+
+/// Flash over JTAG, or display what would be flashed, without connecting to the
+/// ROM bootloader
+fn flash_offline(args: FlashArgs, config: &Config) -> Result<()> {
+    let chip = match args
+        .chip
+        .or_else(|| args.image.as_deref().and_then(infer_chip))
+    {
+        Some(chip) => chip,
+        None => bail!(
+            "Unable to infer the chip from the image's path, please specify it using `--chip`"
+        ),
+    };
+    let flash_size = args.flash_config_args.offline_flash_size()?;
+
+    let image_data = match &args.image {
+        Some(path) => Some(read_image(path)?),
+        None => None,
+    };
+    let bins = read_app_partitions(&args.flash_args.bins)?;
+    let apps = image_data
+        .iter()
+        .map(|data| (data.as_slice(), None))
+        .chain(
+            bins.iter()
+                .map(|(data, label)| (data.as_slice(), Some(*label))),
+        )
+        .collect::<Vec<_>>();
+
+    let bootloader = resolve_bootloader(args.flash_args.bootloader.as_deref(), chip, config)?;
+    let bootloader = match &bootloader {
+        Some(path) => {
+            println!("Bootloader:        {}", path.display());
+            Some(fs::read(path).into_diagnostic()?)
+        }
+        None => None,
+    };
+    let partition_table = match &args.flash_args.partition_table {
+        Some(path) => {
+            println!("Partition table:   {}", path.display());
+            Some(parse_partition_table(path)?)
+        }
+        None => None,
+    };
+
+    let flash_data = args
+        .flash_args
+        .flash_data()
+        .bootloader(bootloader)
+        .partition_table(partition_table)
+        .flash_mode(args.flash_config_args.flash_mode)
+        .flash_size(flash_size)
+        .flash_freq(args.flash_config_args.flash_freq)
+        .build();
+
+    println!("Chip type:         {chip}");
+    if args.jtag {
+        flash_jtag(
+            chip,
+            &apps,
+            flash_data,
+            args.flash_args.format,
+            &args.jtag_args,
+        )
+    } else {
+        dry_run(chip, &apps, flash_data, args.flash_args.format)
+    }
+}
+
+fn load_ram(args: LoadRamArgs, config: &Config) -> Result<()> {
+    let elf_data = read_image(&args.image)?;
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    check_build_target(flasher.chip(), &args.image)?;
+    print_board_info(&mut flasher)?;
+
+    flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+
+    if args.monitor {
+        let pid = flasher.get_usb_pid()?;
+
+        // The 26MHz ESP32-C2's need to be treated as a special case.
+        let chip = flasher.chip();
+        let default_baud =
+            if chip == Chip::Esp32c2 && args.connect_args.no_stub && flasher.crystal_freq() == 26 {
+                74_880
+            } else {
+                115_200
+            };
+
+        let code = monitor(
+            flasher.into_interface(),
+            chip,
+            &[&elf_data],
+            pid,
+            default_baud,
+            &args.monitor_options,
+            &config.monitor.hooks(),
         )
         .into_diagnostic()?;
+        exit_on_request(code);
+    }
+
+    Ok(())
+}
+
+fn test(args: TestArgs, config: &Config) -> Result<()> {
+    let elf_data = read_image(&args.image)?;
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    check_build_target(flasher.chip(), &args.image)?;
+    let flash_size = args.flash_config_args.apply_flash_size(&mut flasher)?;
+    print_board_info(&mut flasher)?;
+
+    // The device is reset once the application has been loaded.
+    if args.ram {
+        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+    } else {
+        let partition_table = match &args.partition_table {
+            Some(path) => Some(parse_partition_table(path)?),
+            None => None,
+        };
+        let bootloader = resolve_bootloader(args.bootloader.as_deref(), flasher.chip(), config)?;
+
+        let flash_data = FlashData::builder()
+            .bootloader(read_bootloader(bootloader.as_deref())?)
+            .partition_table(partition_table)
+            .flash_mode(args.flash_config_args.flash_mode)
+            .flash_size(flash_size)
+            .flash_freq(args.flash_config_args.flash_freq)
+            .build();
+
+        flash_elf_image(&mut flasher, &elf_data, flash_data, None)?;
+    }
+
+    let mut serial = flasher.into_interface();
+    serial
+        .serial_port_mut()
+        .set_baud_rate(args.monitor_baud)
+        .into_diagnostic()?;
+
+    match expect_output(&mut serial, &args.expect, &args.fail, args.test_timeout)
+        .into_diagnostic()?
+    {
+        TestOutcome::Passed => {
+            info!("Test passed");
+            Ok(())
+        }
+        TestOutcome::Failed(line) => {
+            error!("Test failed: {}", line);
+            exit(1);
+        }
+        TestOutcome::TimedOut => {
+            error!(
+                "Test timed out after {:?} without matching '{}'",
+                args.test_timeout, args.expect
+            );
+            exit(2);
+        }
+    }
+}
+
+fn flash_status(args: FlashStatusArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config)?;
+
+    if args.read {
+        print_flash_status(flasher.read_flash_status()?);
+    }
+
+    if args.clear_protection {
+        flasher.clear_flash_protection()?;
+
+        println!("Write protection cleared");
+        print_flash_status(flasher.read_flash_status()?);
     }
 
     Ok(())
 }
 
-fn save_image(args: SaveImageArgs) -> Result<()> {
-    let elf_data = fs::read(&args.image)
+fn print_flash_status(status: u32) {
+    let protected = status & FLASH_STATUS_PROTECTION != 0;
+
+    println!("Status registers:  {status:#08x}");
+    println!(
+        "Write protected:   {}",
+        if protected { "yes" } else { "no" }
+    );
+}
+
+fn hash_flash(args: HashFlashArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config)?;
+    let digest = if args.sha256 {
+        let data =
+            flasher.read_flash(args.addr, args.len, Some(&mut EspflashProgress::default()))?;
+        Sha256::digest(data).to_vec()
+    } else {
+        flasher.flash_md5(args.addr, args.len)?.to_vec()
+    };
+
+    let digest = digest
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    println!("{digest}");
+
+    Ok(())
+}
+
+fn image_info(args: ImageInfoArgs) -> Result<()> {
+    let data = read_image(&args.image)?;
+
+    let info = ImageInfo::parse(&data, args.chip)?;
+
+    println!("Chip type:         {}", info.chip);
+    println!("Entry point:       {:#010x}", info.entry);
+    println!(
+        "Flash mode:        {}",
+        display_option(info.flash_mode.map(|mode| format!("{mode:?}")))
+    );
+    println!("Flash size:        {}", display_option(info.flash_size));
+    println!("Flash frequency:   {}", display_option(info.flash_freq));
+    if let Some(chip_id) = info.chip_id {
+        println!("Chip ID:           {chip_id}");
+    }
+    if let Some(min_rev) = info.min_rev {
+        println!("Minimum revision:  {min_rev}");
+    }
+    if let Some(rev) = info.min_chip_rev_full {
+        println!("Minimum full rev.: v{}.{}", rev / 100, rev % 100);
+    }
+    if let Some(rev) = info.max_chip_rev_full {
+        println!("Maximum full rev.: v{}.{}", rev / 100, rev % 100);
+    }
+    if let Some(wp_pin) = info.wp_pin {
+        println!("WP pin:            {wp_pin:#04x}");
+    }
+
+    println!();
+    println!("{} segments", info.segments.len());
+    for (i, segment) in info.segments.iter().enumerate() {
+        println!(
+            "  {i:<3} addr={:#010x} len={:#08x} file_offs={:#08x} [{}]",
+            segment.addr,
+            segment.length,
+            segment.file_offset,
+            segment.regions.join(", ")
+        );
+    }
+
+    println!();
+    println!(
+        "Checksum:          {:#04x} ({})",
+        info.checksum,
+        if info.checksum_valid() {
+            "valid".to_string()
+        } else {
+            format!("invalid, calculated {:#04x}", info.calculated_checksum)
+        }
+    );
+    if let (Some(digest), Some(valid)) = (&info.digest, info.digest_valid()) {
+        println!(
+            "Validation hash:   {} ({})",
+            digest
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>(),
+            if valid { "valid" } else { "invalid" }
+        );
+    }
+
+    if !info.checksum_valid() || info.digest_valid() == Some(false) {
+        bail!("The image failed validation");
+    }
+
+    Ok(())
+}
+
+fn convert_image_to_elf(args: ImageToElfArgs) -> Result<()> {
+    let data = read_image(&args.image)?;
+
+    let elf_data = image_to_elf(&data, args.chip)?;
+
+    fs::write(&args.file, elf_data)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write ELF file {}", args.file.display()))?;
+
+    Ok(())
+}
+
+fn sign(args: SignImageArgs) -> Result<()> {
+    let image = fs::read(&args.image)
         .into_diagnostic()
         .wrap_err_with(|| format!("Failed to open image {}", args.image.display()))?;
+    let signer = signer(&args)?;
+
+    let (mut signed, sector) = sign_image(&image, signer.as_ref())?;
+    let mut outputs = Vec::new();
+    match &args.detached {
+        Some(path) => {
+            // The signature only follows the image directly when it's already
+            // padded, otherwise the padded image must be written as well
+            match &args.output {
+                Some(output) => outputs.push((output, signed, "padded image")),
+                None if signed.len() != image.len() => bail!(
+                    "The image is not a multiple of 4KB in size, so it must be padded before the \
+                     detached signature is appended. Use `--output` to write the padded image."
+                ),
+                None => {}
+            }
+            outputs.push((path, sector, "signature"));
+        }
+        None => {
+            signed.extend(sector);
+            let path = args.output.as_ref().unwrap_or(&args.image);
+            outputs.push((path, signed, "signed image"));
+        }
+    }
+
+    for (path, data, written) in outputs {
+        fs::write(path, data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {written} to {}", path.display());
+    }
+
+    let digest = signer
+        .verifying_key()
+        .public_key_digest()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    println!("Public key digest: {digest}");
+
+    Ok(())
+}
+
+/// Load the signer referenced by the `--key` and `--signer-cmd` arguments
+fn signer(args: &SignImageArgs) -> Result<Box<dyn Signer>> {
+    if args.key.starts_with("pkcs11:") {
+        if args.signer_cmd.is_some() {
+            bail!("A PKCS#11 URI can't be used with `--signer-cmd`");
+        }
+
+        #[cfg(feature = "pkcs11")]
+        return Ok(Box::new(espflash::security::Pkcs11Signer::open(&args.key)?));
+        #[cfg(not(feature = "pkcs11"))]
+        bail!("Signing with a PKCS#11 token requires the `pkcs11` feature");
+    }
+
+    let key = fs::read_to_string(&args.key)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open key {}", args.key))?;
+
+    Ok(match &args.signer_cmd {
+        Some(command) => Box::new(ExternalSigner::new(command, VerifyingKey::from_pem(&key)?)),
+        None => Box::new(SigningKey::from_pem(&key)?),
+    })
+}
+
+fn verify_image(args: VerifyImageArgs) -> Result<()> {
+    let data = read_image(&args.image)?;
+    let key = match &args.key {
+        Some(path) => {
+            let key = fs::read_to_string(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open key {}", path.display()))?;
+            Some(VerifyingKey::from_pem(&key)?)
+        }
+        None => None,
+    };
+
+    let info = ImageInfo::parse(&data, args.chip)?;
+    if !info.checksum_valid() {
+        error!(
+            "The checksum {:#04x} does not match the calculated checksum {:#04x}",
+            info.checksum, info.calculated_checksum
+        );
+        exit(2);
+    }
+    println!("Checksum:          valid");
+
+    match info.digest_valid() {
+        Some(true) => println!("Validation hash:   valid"),
+        Some(false) => {
+            error!("The appended SHA-256 digest does not match the image");
+            exit(3);
+        }
+        None => println!("Validation hash:   none"),
+    }
+
+    if !is_signed(&data) {
+        println!("Signatures:        none");
+        if key.is_some() {
+            error!("The image is not signed");
+            exit(5);
+        }
+
+        return Ok(());
+    }
+
+    let keys = match verify_signatures(&data) {
+        Ok(keys) => keys,
+        Err(err) => {
+            error!("{err}");
+            exit(4);
+        }
+    };
+    for signer in &keys {
+        println!(
+            "Signature:         valid, public key digest {}",
+            signer
+                .public_key_digest()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        );
+    }
+
+    if let Some(key) = key {
+        if !keys.contains(&key) {
+            error!("The image is not signed with the given key");
+            exit(5);
+        }
+    }
+
+    Ok(())
+}
+
+fn display_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "unknown".to_string(), |value| value.to_string())
+}
+
+fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
+    let flash_size = args.flash_config_args.offline_flash_size()?;
+    let elf_data = read_image(&args.image)?;
+    let bootloader = resolve_bootloader(
+        args.save_image_args.bootloader.as_deref(),
+        args.save_image_args.chip,
+        config,
+    )?;
 
     // Since we have no `Flasher` instance and as such cannot print the board
     // information, we will print whatever information we _do_ have.
-    println!("Chip type:         {}", args.save_image_args.chip);
+    let mut out = args.save_image_args.info_output();
+    writeln!(out, "Chip type:         {}", args.save_image_args.chip).into_diagnostic()?;
     if let Some(format) = args.format {
-        println!("Image format:      {:?}", format);
+        writeln!(out, "Image format:      {:?}", format).into_diagnostic()?;
+    }
+    let merge = args.save_image_args.merge();
+    writeln!(out, "Merge:             {}", merge).into_diagnostic()?;
+    if args.save_image_args.split {
+        writeln!(out, "Split:             true").into_diagnostic()?;
+    }
+    writeln!(
+        out,
+        "Skip padding:      {}",
+        args.save_image_args.skip_padding
+    )
+    .into_diagnostic()?;
+    if let Some(size) = args.save_image_args.pad_to_size {
+        writeln!(out, "Pad to size:       {size}").into_diagnostic()?;
+    }
+    writeln!(
+        out,
+        "Reproducible:      {}",
+        args.save_image_args.reproducible
+    )
+    .into_diagnostic()?;
+    if let Some((major, minor)) = args.save_image_args.min_chip_rev {
+        writeln!(out, "Minimum revision:  v{major}.{minor}").into_diagnostic()?;
     }
-    println!("Merge:             {}", args.save_image_args.merge);
-    println!("Skip padding:      {}", args.save_image_args.skip_padding);
-    if let Some(path) = &args.save_image_args.bootloader {
-        println!("Bootloader:        {}", path.display());
+    if let Some(secure_version) = args.save_image_args.secure_version {
+        writeln!(out, "Secure version:    {secure_version}").into_diagnostic()?;
+    }
+    if args.save_image_args.no_hash_appended {
+        writeln!(out, "Hash appended:     false").into_diagnostic()?;
+    }
+    if args.save_image_args.qemu {
+        writeln!(out, "QEMU:              true").into_diagnostic()?;
+        check_qemu_flash_size(args.save_image_args.pad_to_size.or(flash_size))?;
+    }
+    if let Some(path) = &bootloader {
+        writeln!(out, "Bootloader:        {}", path.display()).into_diagnostic()?;
     }
     if let Some(path) = &args.save_image_args.partition_table {
-        println!("Partition table:   {}", path.display());
+        writeln!(out, "Partition table:   {}", path.display()).into_diagnostic()?;
     }
 
+    let partition_table = match &args.save_image_args.partition_table {
+        Some(path) => Some(parse_partition_table(path)?),
+        None => None,
+    };
+    let flash_data = args
+        .save_image_args
+        .flash_data()
+        .bootloader(read_bootloader(bootloader.as_deref())?)
+        .partition_table(partition_table)
+        .flash_mode(args.flash_config_args.flash_mode)
+        .flash_size(flash_size)
+        .flash_freq(args.flash_config_args.flash_freq)
+        .build();
+
     save_elf_as_image(
         args.save_image_args.chip,
         &elf_data,
         args.save_image_args.file,
         args.format,
-        args.flash_config_args.flash_mode,
-        args.flash_config_args.flash_size,
-        args.flash_config_args.flash_freq,
-        args.save_image_args.merge,
-        args.save_image_args.bootloader,
-        args.save_image_args.partition_table,
+        flash_data,
+        merge,
+        args.save_image_args.split,
         args.save_image_args.skip_padding,
+        args.save_image_args.pad_to_size,
+        args.save_image_args.fill,
     )?;
 
     Ok(())
 }
 
-fn write_bin(args: WriteBinArgs, config: &Config) -> Result<()> {
+fn size(args: SizeArgs) -> Result<()> {
+    let elf_data = read_image(&args.image)?;
+
+    let partition_table = match &args.partition_table {
+        Some(path) => Some(parse_partition_table(path)?),
+        None => None,
+    };
+    let flash_data = FlashData::builder()
+        .partition_table(partition_table)
+        .build();
+
+    let report = SizeReport::new(&elf_data, args.chip, flash_data)?;
+
+    if args.format == SizeFormat::Json {
+        let json = serde_json::to_string_pretty(&report).into_diagnostic()?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    println!("Chip type:         {}", report.chip);
+    println!();
+    for region in &report.regions {
+        println!(
+            "  {:<12} {:>8} / {:>8} bytes ({:>5.1}%)",
+            region.name,
+            region.used,
+            region.size,
+            percentage(region.used, region.size)
+        );
+    }
+
+    println!();
+    println!("{} sections", report.sections.len());
+    for section in &report.sections {
+        println!(
+            "  {:<24} addr={:#010x} len={:#08x} [{}]",
+            section.name,
+            section.addr,
+            section.size,
+            display_option(section.region.as_ref())
+        );
+    }
+
+    println!();
+    display_image_size(report.app_size, report.part_size);
+
+    Ok(())
+}
+
+fn percentage(used: u32, total: u32) -> f64 {
+    (used as f64 / total as f64) * 100.0
+}
+
+fn read_mem(args: ReadMemArgs, config: &Config) -> Result<()> {
     let mut flasher = connect(&args.connect_args, config)?;
-    print_board_info(&mut flasher)?;
+    let data = flasher.read_memory(args.addr, args.len)?;
 
+    match args.output {
+        Some(path) => fs::write(&path, data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?,
+        None => {
+            for (addr, word) in (args.addr..).step_by(4).zip(data.chunks_exact(4)) {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                println!("{addr:#010x}: {word:#010x}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_mem(args: WriteMemArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config)?;
+    flasher.write_memory(args.addr, args.value, args.mask)?;
+
+    println!("Wrote {:#010x} to {:#010x}", args.value, args.addr);
+
+    Ok(())
+}
+
+fn write_bin(args: WriteBinArgs, config: &Config) -> Result<()> {
     let mut f = File::open(&args.bin_file).into_diagnostic()?;
     let size = f.metadata().into_diagnostic()?.len();
     let mut buffer = Vec::with_capacity(size.try_into().into_diagnostic()?);
     f.read_to_end(&mut buffer).into_diagnostic()?;
 
+    if args.jtag {
+        // The chip is required when using JTAG
+        let chip = args.chip.unwrap();
+        let segment = RomSegment {
+            addr: args.addr,
+            data: Cow::Owned(buffer),
+            encrypted: false,
+        };
+        return write_jtag(chip, &[segment], &args.jtag_args);
+    }
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    print_board_info(&mut flasher)?;
+
+    if let Some(spi_params) = args.external_flash {
+        flasher.attach_external_flash(spi_params)?;
+        flasher.set_verify(true);
+    }
+
+    check_partition_table(&mut flasher, None, false);
     flasher.write_bin_to_flash(args.addr, &buffer, Some(&mut EspflashProgress::default()))?;
 
     Ok(())
 }
+
+fn write_fs(args: WriteFsArgs, config: &Config) -> Result<()> {
+    let partition_table = args
+        .partition_table
+        .as_deref()
+        .map(parse_partition_table)
+        .transpose()?;
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    print_board_info(&mut flasher)?;
+
+    let partition_table = match partition_table {
+        Some(partition_table) => partition_table,
+        None => flasher
+            .read_partition_table()?
+            .ok_or_else(|| miette!("The device does not have a valid partition table"))?,
+    };
+    let partition = find_fs_partition(&partition_table, args.partition.as_deref())?;
+    let data = load_fs_image(&args.path, args.fs, partition.size(), flasher.chip())?;
+
+    println!(
+        "Partition:         {} ({:#x}, {} bytes)",
+        partition.name(),
+        partition.offset(),
+        partition.size()
+    );
+
+    flasher.write_bin_to_flash(
+        partition.offset(),
+        &data,
+        Some(&mut EspflashProgress::default()),
+    )?;
+
+    Ok(())
+}
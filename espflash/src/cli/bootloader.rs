@@ -0,0 +1,150 @@
+//! Prebuilt bootloaders, downloaded from a configurable release source
+//!
+//! Rather than providing the path to a bootloader, `--bootloader idf:VERSION`
+//! selects the bootloader built by that version of ESP-IDF for the target
+//! chip. It is downloaded from the URL configured as `bootloaders.source`, in
+//! which `{version}` and `{chip}` are replaced, and cached so that it is only
+//! downloaded once. Downloading requires the `bootloader-download` feature.
+
+use std::path::{Path, PathBuf};
+
+use miette::{bail, Result};
+
+use super::config::Config;
+use crate::targets::Chip;
+
+/// Prefix which selects a prebuilt bootloader by its ESP-IDF version
+const IDF_PREFIX: &str = "idf:";
+
+/// Resolve the `--bootloader` argument to the path of a bootloader for `chip`
+///
+/// Paths are returned unchanged, while prebuilt bootloaders are downloaded if
+/// they have not already been cached.
+pub fn resolve_bootloader(
+    bootloader: Option<&Path>,
+    chip: Chip,
+    config: &Config,
+) -> Result<Option<PathBuf>> {
+    let path = match bootloader {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let version = match path.to_str().and_then(|s| s.strip_prefix(IDF_PREFIX)) {
+        Some(version) => version,
+        None => return Ok(Some(path.to_path_buf())),
+    };
+
+    // The version is used as the name of a cache directory
+    if version.is_empty()
+        || version.starts_with('.')
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        bail!("Invalid ESP-IDF version '{version}'");
+    }
+
+    download::cached_bootloader(version, chip, config).map(Some)
+}
+
+#[cfg(feature = "bootloader-download")]
+mod download {
+    use std::{fs, io::Read, path::PathBuf};
+
+    use directories_next::ProjectDirs;
+    use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+    use tracing::info;
+
+    use super::Config;
+    use crate::{image_format::ImageInfo, targets::Chip};
+
+    /// The path of the cached bootloader for `chip` from ESP-IDF `version`,
+    /// downloading it first if needed
+    pub(super) fn cached_bootloader(version: &str, chip: Chip, config: &Config) -> Result<PathBuf> {
+        let dirs = ProjectDirs::from("rs", "esp", "espflash")
+            .ok_or_else(|| miette!("Unable to determine the cache directory"))?;
+        let path = dirs
+            .cache_dir()
+            .join("bootloaders")
+            .join(version)
+            .join(format!("{chip}-bootloader.bin"));
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let source = match &config.bootloaders.source {
+            Some(source) => source,
+            None => bail!(
+                "No source of prebuilt bootloaders is configured, please set `bootloaders.source` \
+                 in the configuration file"
+            ),
+        };
+        let url = source
+            .replace("{version}", version)
+            .replace("{chip}", &chip.to_string());
+
+        info!("Downloading {url}");
+        let mut data = Vec::new();
+        ureq::get(&url)
+            .call()
+            .into_diagnostic()?
+            .into_reader()
+            .read_to_end(&mut data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to download {url}"))?;
+
+        let info = ImageInfo::parse(&data, None)
+            .wrap_err_with(|| format!("The download from {url} is not a bootloader"))?;
+        if info.chip != chip {
+            bail!(
+                "The bootloader downloaded from {url} was built for the {}, not the {chip}",
+                info.chip
+            );
+        }
+
+        fs::create_dir_all(path.parent().unwrap()).into_diagnostic()?;
+        fs::write(&path, data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to cache bootloader at {}", path.display()))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(not(feature = "bootloader-download"))]
+mod download {
+    use std::path::PathBuf;
+
+    use miette::{bail, Result};
+
+    use super::Config;
+    use crate::targets::Chip;
+
+    pub(super) fn cached_bootloader(
+        _version: &str,
+        _chip: Chip,
+        _config: &Config,
+    ) -> Result<PathBuf> {
+        bail!("Downloading prebuilt bootloaders requires the `bootloader-download` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_bootloader_path() {
+        let config = Config::default();
+
+        assert_eq!(
+            resolve_bootloader(None, Chip::Esp32, &config).unwrap(),
+            None
+        );
+        assert_eq!(
+            resolve_bootloader(Some(Path::new("boot.bin")), Chip::Esp32, &config).unwrap(),
+            Some(PathBuf::from("boot.bin"))
+        );
+        assert!(resolve_bootloader(Some(Path::new("idf:../v5.2")), Chip::Esp32, &config).is_err());
+    }
+}
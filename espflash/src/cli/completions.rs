@@ -0,0 +1,100 @@
+//! Shell completions
+//!
+//! Completions are generated using `clap_complete`, and for Bash, Zsh and Fish
+//! are extended to complete the `--port` option with the currently attached
+//! serial ports.
+
+use std::io::{stdout, Write};
+
+use clap::{Args, Command};
+use clap_complete::{generate, Shell};
+use miette::{IntoDiagnostic, Result};
+
+use super::serial::port_candidates;
+
+/// Generate shell completions
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// Shell to generate completions for
+    #[arg(value_enum, required_unless_present = "list_ports")]
+    shell: Option<Shell>,
+    /// List the attached serial ports, as used to complete `--port`
+    #[arg(long, hide = true)]
+    list_ports: bool,
+}
+
+/// Write the completions for `cmd`, which is invoked as `name`, to stdout
+pub fn completions(args: &CompletionsArgs, cmd: &mut Command, name: &str) -> Result<()> {
+    let mut out = stdout().lock();
+
+    if args.list_ports {
+        for (port, description) in port_candidates() {
+            writeln!(out, "{port}\t{description}").into_diagnostic()?;
+        }
+
+        return Ok(());
+    }
+
+    // `shell` is required unless listing ports
+    let shell = args.shell.unwrap();
+
+    let mut script = Vec::new();
+    generate(shell, cmd, name, &mut script);
+    let script = String::from_utf8_lossy(&script);
+
+    match shell {
+        Shell::Bash => write!(out, "{script}{}", bash_ports(name)),
+        // Values of `--port` are completed using a function rather than as files,
+        // which is defined after the initial `#compdef` line
+        Shell::Zsh => {
+            let script = script.replace(":PORT: ", &format!(":PORT:_{name}_ports"));
+            let (compdef, rest) = script.split_once('\n').unwrap_or((&script, ""));
+
+            write!(out, "{compdef}\n{}{rest}", zsh_ports(name))
+        }
+        Shell::Fish => write!(out, "{script}{}", fish_ports(name)),
+        _ => write!(out, "{script}"),
+    }
+    .into_diagnostic()
+}
+
+fn bash_ports(name: &str) -> String {
+    format!(
+        r#"
+_{name}_with_ports() {{
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    if [[ "$prev" == "-p" || "$prev" == "--port" ]]; then
+        local ports
+        ports="$({name} completions --list-ports 2>/dev/null | cut -f1)"
+        COMPREPLY=($(compgen -W "$ports" -- "${{COMP_WORDS[COMP_CWORD]}}"))
+        return 0
+    fi
+    _{name} "$@"
+}}
+
+complete -F _{name}_with_ports -o bashdefault -o default {name}
+"#
+    )
+}
+
+fn zsh_ports(name: &str) -> String {
+    format!(
+        r#"
+_{name}_ports() {{
+    local -a ports
+    local port description
+    {name} completions --list-ports 2>/dev/null | while IFS=$'\t' read -r port description; do
+        ports+=("${{port//:/\\:}}:${{description}}")
+    done
+    _describe -t ports 'serial port' ports
+}}
+
+"#
+    )
+}
+
+fn fish_ports(name: &str) -> String {
+    format!(
+        "\ncomplete -c {name} -s p -l port -f -a '({name} completions --list-ports 2>/dev/null)'\n"
+    )
+}
@@ -14,6 +14,7 @@ use std::{
 
 use directories_next::ProjectDirs;
 use miette::{IntoDiagnostic, Result, WrapErr};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_hex::{Compact, SerHex};
 use serialport::UsbPortInfo;
@@ -29,6 +30,18 @@ pub struct Connection {
     /// Ready To Send pin
     #[cfg(feature = "raspberry")]
     pub rts: Option<u8>,
+    /// GPIO character device controlling the strapping pins
+    #[cfg(feature = "gpio")]
+    pub gpio_chip: Option<PathBuf>,
+    /// GPIO line wired to the EN pin
+    #[cfg(feature = "gpio")]
+    pub en_gpio: Option<u32>,
+    /// GPIO line wired to the IO0 (BOOT) pin
+    #[cfg(feature = "gpio")]
+    pub boot_gpio: Option<u32>,
+    /// Sequence used to reset the device into its bootloader, either the name
+    /// of a built-in sequence or a custom sequence
+    pub reset_sequence: Option<String>,
 }
 
 /// A configured, known USB device
@@ -48,6 +61,78 @@ impl UsbDevice {
     }
 }
 
+/// An action triggered when a pattern is matched in the serial monitor's
+/// output
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
+pub struct MonitorHook {
+    /// Regular expression matched against each line of output
+    pub pattern: String,
+    /// Highlight the matching line
+    #[serde(default)]
+    pub highlight: bool,
+    /// Ring the terminal bell
+    #[serde(default)]
+    pub bell: bool,
+    /// Exit the monitor with the given exit code, eg. to fail a CI smoke test
+    pub exit_code: Option<i32>,
+    /// Run a shell command, with the matching line in the
+    /// `ESPFLASH_MONITOR_LINE` environment variable
+    pub command: Option<String>,
+}
+
+impl MonitorHook {
+    fn highlight(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            highlight: true,
+            bell: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// Source of prebuilt bootloaders, selected using `--bootloader idf:VERSION`
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct Bootloaders {
+    /// URL from which bootloaders are downloaded, in which `{version}` and
+    /// `{chip}` are replaced by the ESP-IDF version and the chip's name
+    pub source: Option<String>,
+}
+
+/// Serial monitor configuration
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct Monitor {
+    /// Actions triggered by patterns in the monitor's output
+    #[serde(default)]
+    pub hooks: Vec<MonitorHook>,
+    /// Disable the built-in hooks which highlight panics and brownout resets
+    #[serde(default)]
+    pub disable_default_hooks: bool,
+}
+
+impl Monitor {
+    /// The configured hooks, preceded by the built-in ones unless disabled
+    pub fn hooks(&self) -> Vec<MonitorHook> {
+        let defaults = if self.disable_default_hooks {
+            vec![]
+        } else {
+            vec![
+                // Panics and exceptions reported by ESP-IDF, on Xtensa and RISC-V
+                MonitorHook::highlight(r"Guru Meditation Error|abort\(\) was called at PC"),
+                // Panics reported by `esp-backtrace`
+                MonitorHook::highlight(r"!! A panic occured|panicked at"),
+                // Brownout resets
+                MonitorHook::highlight(r"Brownout detector was triggered|BROWNOUT_RST"),
+            ]
+        };
+
+        defaults
+            .into_iter()
+            .chain(self.hooks.iter().cloned())
+            .collect()
+    }
+}
+
 /// Deserialized contents of a configuration file
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Config {
@@ -57,6 +142,12 @@ pub struct Config {
     /// Preferred USB devices
     #[serde(default)]
     pub usb_device: Vec<UsbDevice>,
+    /// Serial monitor configuration
+    #[serde(default)]
+    pub monitor: Monitor,
+    /// Source of prebuilt bootloaders
+    #[serde(default)]
+    pub bootloaders: Bootloaders,
     #[serde(skip)]
     save_path: PathBuf,
 }
@@ -67,11 +158,18 @@ impl Config {
         let dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
         let file = dirs.config_dir().join("espflash.toml");
 
-        let mut config = if let Ok(data) = read(&file) {
+        let mut config: Self = if let Ok(data) = read(&file) {
             toml::from_slice(&data).into_diagnostic()?
         } else {
             Self::default()
         };
+
+        for hook in &config.monitor.hooks {
+            Regex::new(&hook.pattern)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Invalid monitor hook pattern '{}'", hook.pattern))?;
+        }
+
         config.save_path = file;
         Ok(config)
     }
@@ -0,0 +1,215 @@
+//! Diagnosing problems with the host system which prevent flashing
+//!
+//! The checks performed are for the most common causes of failing to connect
+//! to a device: missing drivers for its USB to UART bridge, insufficient
+//! permissions to open its serial port, and unreliable USB hubs. Most checks
+//! are only supported on Linux.
+
+#[cfg(target_os = "linux")]
+use std::{fs, path::Path};
+
+use crossterm::style::Stylize;
+use miette::{bail, Result};
+use serialport::{SerialPortInfo, SerialPortType};
+
+use super::serial::{bridge, detect_usb_serial_ports, usb_port_path};
+#[cfg(target_os = "linux")]
+use crate::interface::permissions;
+
+/// USB hubs which have been reported to drop devices or corrupt data at high
+/// baud rates
+#[cfg(target_os = "linux")]
+const BAD_HUBS: &[(u16, u16, &str)] = &[
+    (0x1a40, 0x0101, "Terminus FE1.1s"),
+    (0x05e3, 0x0608, "Genesys Logic GL850G"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Check the host system for problems which prevent flashing devices
+pub fn doctor() -> Result<()> {
+    let mut errors = 0;
+    let mut report = |status: Status, message: &str| {
+        let label = match status {
+            Status::Ok => "[ok]  ".green(),
+            Status::Warning => "[warn]".yellow(),
+            Status::Error => "[fail]".red(),
+        };
+        if status == Status::Error {
+            errors += 1;
+        }
+        println!("{label} {message}");
+    };
+
+    let ports = detect_usb_serial_ports().unwrap_or_default();
+    if ports.is_empty() {
+        report(
+            Status::Error,
+            "No serial ports found; make sure the device is connected using a data cable, and \
+             that the driver for its USB to UART bridge is installed",
+        );
+    }
+    for port in &ports {
+        report(Status::Ok, &format!("Found serial port {}", describe(port)));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        check_drivers(&ports, &mut report);
+        check_permissions(&ports, &mut report);
+        check_brltty(&mut report);
+        check_hubs(&mut report);
+    }
+    #[cfg(not(target_os = "linux"))]
+    report(
+        Status::Warning,
+        "Driver, permission and USB hub checks are only supported on Linux",
+    );
+
+    if errors > 0 {
+        bail!("Found {errors} problem(s) with the host system");
+    }
+
+    Ok(())
+}
+
+/// Describe a serial port, including the bridge it belongs to if known
+fn describe(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => {
+            let bridge = bridge(info.vid, info.pid)
+                .map(|(name, _)| name.to_string())
+                .or_else(|| info.product.clone())
+                .unwrap_or_else(|| "unknown device".into());
+
+            let mut details = format!("{bridge}, {:04x}:{:04x}", info.vid, info.pid);
+            if let Some(serial_number) = &info.serial_number {
+                details.push_str(&format!(", serial number {serial_number}"));
+            }
+            if let Some(path) = usb_port_path(&port.port_name) {
+                details.push_str(&format!(", usb:{path}"));
+            }
+
+            format!("{} ({details})", port.port_name)
+        }
+        _ => port.port_name.clone(),
+    }
+}
+
+/// The vendor and product IDs, and class, of each attached USB device
+#[cfg(target_os = "linux")]
+fn usb_devices() -> Vec<(u16, u16, u8)> {
+    let Ok(devices) = fs::read_dir("/sys/bus/usb/devices") else {
+        return Vec::new();
+    };
+
+    devices
+        .flatten()
+        .filter_map(|device| {
+            let read = |attr: &str| fs::read_to_string(device.path().join(attr)).ok();
+            let vid = u16::from_str_radix(read("idVendor")?.trim(), 16).ok()?;
+            let pid = u16::from_str_radix(read("idProduct")?.trim(), 16).ok()?;
+            let class = u8::from_str_radix(read("bDeviceClass")?.trim(), 16).ok()?;
+
+            Some((vid, pid, class))
+        })
+        .collect()
+}
+
+/// Check that each attached bridge has a serial port, and so a driver
+#[cfg(target_os = "linux")]
+fn check_drivers(ports: &[SerialPortInfo], report: &mut impl FnMut(Status, &str)) {
+    for (vid, pid, _) in usb_devices() {
+        let Some((name, driver)) = bridge(vid, pid) else {
+            continue;
+        };
+
+        let has_port = ports.iter().any(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => (info.vid, info.pid) == (vid, pid),
+            _ => false,
+        });
+        if has_port {
+            continue;
+        }
+
+        if Path::new("/sys/bus/usb/drivers").join(driver).exists() {
+            report(
+                Status::Warning,
+                &format!("{name} is connected, but has no serial port; try reconnecting it"),
+            );
+        } else {
+            report(
+                Status::Error,
+                &format!(
+                    "{name} is connected, but the '{driver}' driver is not loaded; try `sudo \
+                     modprobe {driver}`"
+                ),
+            );
+        }
+    }
+}
+
+/// Check that the current user may open each serial port
+#[cfg(target_os = "linux")]
+fn check_permissions(ports: &[SerialPortInfo], report: &mut impl FnMut(Status, &str)) {
+    for port in ports {
+        let usb_ids = match &port.port_type {
+            SerialPortType::UsbPort(info) => Some((info.vid, info.pid)),
+            _ => None,
+        };
+
+        match permissions::diagnose(Path::new(&port.port_name), usb_ids) {
+            Some(problem) => report(
+                Status::Error,
+                &format!("Permission to open {} is denied. {problem}", port.port_name),
+            ),
+            None => report(Status::Ok, &format!("Permitted to open {}", port.port_name)),
+        }
+    }
+}
+
+/// Check for brltty, a braille display daemon which claims CH340 bridges on
+/// some distributions
+#[cfg(target_os = "linux")]
+fn check_brltty(report: &mut impl FnMut(Status, &str)) {
+    let running = fs::read_dir("/proc").map_or(false, |processes| {
+        processes.flatten().any(|process| {
+            fs::read_to_string(process.path().join("comm"))
+                .map_or(false, |name| name.trim() == "brltty")
+        })
+    });
+
+    if running {
+        report(
+            Status::Warning,
+            "brltty is running, and may claim CH340 bridges; uninstall it unless you use a \
+             braille display",
+        );
+    }
+}
+
+/// Check for USB hubs which are known to be unreliable
+#[cfg(target_os = "linux")]
+fn check_hubs(report: &mut impl FnMut(Status, &str)) {
+    const HUB_CLASS: u8 = 0x09;
+
+    for (vid, pid, class) in usb_devices() {
+        if class != HUB_CLASS {
+            continue;
+        }
+        if let Some((.., name)) = BAD_HUBS.iter().find(|hub| (hub.0, hub.1) == (vid, pid)) {
+            report(
+                Status::Warning,
+                &format!(
+                    "{name} USB hub ({vid:04x}:{pid:04x}) is connected, which may drop devices; \
+                     connect the device directly to the host if flashing fails"
+                ),
+            );
+        }
+    }
+}
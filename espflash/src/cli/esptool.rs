@@ -0,0 +1,256 @@
+//! Compatibility with the command-line syntax of `esptool.py`
+//!
+//! Only the `write_flash` command is supported, which is enough for espflash
+//! to be used in place of `esptool.py` by tools which invoke it directly, such
+//! as the `upload_command` of a PlatformIO project:
+//!
+//! ```ini
+//! upload_command = espflash esptool-compat --port $UPLOAD_PORT write_flash 0x10000 $SOURCE
+//! ```
+
+use std::{borrow::Cow, fs};
+
+use clap::{Args, Subcommand, ValueEnum};
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+use tracing::{info, warn};
+
+use super::{
+    check_partition_table, config::Config, connect, print_board_info, print_flash_summary, prompt,
+    ConnectArgs, EspflashProgress, FlashConfigArgs, SummaryFormat,
+};
+use crate::{
+    connection::ResetSequence,
+    elf::RomSegment,
+    image_format::{is_app_image, update_flash_params},
+    targets::Chip,
+};
+
+/// Run an `esptool.py` command
+#[derive(Debug, Args)]
+pub struct EsptoolArgs {
+    /// Chip type, or 'auto' to accept any chip
+    #[arg(short = 'c', long, default_value = "auto")]
+    chip: String,
+    /// What to do before connecting to the chip
+    #[arg(
+        long,
+        default_value = "default_reset",
+        value_parser = ["default_reset", "usb_reset", "no_reset"]
+    )]
+    before: String,
+    /// What to do after the command has completed; the chip is always hard
+    /// reset
+    #[arg(long, default_value = "hard_reset")]
+    after: String,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    #[command(subcommand)]
+    command: EsptoolCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum EsptoolCommand {
+    /// Write binaries to flash, each at its own address
+    #[command(name = "write_flash", alias = "write-flash")]
+    WriteFlash(WriteFlashArgs),
+}
+
+#[derive(Debug, Args)]
+struct WriteFlashArgs {
+    /// Flash mode written to the bootloader's header, or 'keep'
+    #[arg(long = "flash_mode", alias = "flash-mode", default_value = "keep")]
+    flash_mode: String,
+    /// Flash frequency written to the bootloader's header (eg. '40m'), or
+    /// 'keep'
+    #[arg(long = "flash_freq", alias = "flash-freq", default_value = "keep")]
+    flash_freq: String,
+    /// Flash size written to the bootloader's header (eg. '4MB'), 'detect' to
+    /// detect it, or 'keep'
+    #[arg(long = "flash_size", alias = "flash-size", default_value = "keep")]
+    flash_size: String,
+    /// Erase the entire flash chip before writing
+    #[arg(short = 'e', long = "erase-all", alias = "erase_all")]
+    erase_all: bool,
+    /// Compress data in transfer; data is always compressed
+    #[arg(short = 'z', long, hide = true)]
+    compress: bool,
+    /// Disable data compression; data is always compressed
+    #[arg(short = 'u', long = "no-compress", alias = "no_compress", hide = true)]
+    no_compress: bool,
+    /// Pairs of addresses and the binaries to write at them
+    #[arg(required = true, value_name = "ADDRESS FILENAME")]
+    files: Vec<String>,
+}
+
+/// Run an `esptool.py` command, given its arguments
+pub fn esptool_compat(mut args: EsptoolArgs, config: &Config) -> Result<()> {
+    let EsptoolCommand::WriteFlash(write_args) = &args.command;
+
+    let chip = match args.chip.as_str() {
+        "auto" => None,
+        chip => match chip.parse::<Chip>() {
+            Ok(chip) => Some(chip),
+            Err(_) => bail!("Unsupported chip: {chip}"),
+        },
+    };
+    if args.after != "hard_reset" {
+        warn!(
+            "'--after {}' is not supported, the chip is hard reset",
+            args.after
+        );
+    }
+
+    // The files are read before connecting, so that any problems are reported
+    // without needing to wait for the device.
+    let files = read_files(&write_args.files)?;
+    let flash_config_args = FlashConfigArgs {
+        flash_mode: esptool_setting(&write_args.flash_mode, "")?,
+        flash_freq: esptool_setting(&write_args.flash_freq, "hz")?,
+        flash_size: esptool_setting(&write_args.flash_size, "")?,
+    };
+
+    if args.connect_args.reset_sequence.is_none() {
+        args.connect_args.reset_sequence = match args.before.as_str() {
+            "usb_reset" => Some(ResetSequence::UsbJtagSerial),
+            // Waiting without toggling any control lines leaves the chip as it is
+            "no_reset" => Some("W0".parse()?),
+            _ => None,
+        };
+    }
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    if let Some(chip) = chip {
+        if chip != flasher.chip() {
+            bail!("This chip is {}, not {chip}", flasher.chip());
+        }
+    }
+    let flash_size = flash_config_args.apply_flash_size(&mut flasher)?;
+    print_board_info(&mut flasher)?;
+
+    if write_args.erase_all {
+        prompt::confirm("Erase the entire flash?")?;
+        info!("Erasing flash...");
+        flasher.erase_flash()?;
+    }
+
+    // Like `esptool.py`, only the bootloader's header is updated with the flash
+    // settings.
+    let boot_addr = flasher.chip().boot_address();
+    let mut segments = Vec::new();
+    for (addr, mut data) in files {
+        let update = flash_config_args.flash_mode.is_some()
+            || flash_config_args.flash_freq.is_some()
+            || flash_size.is_some();
+
+        if addr == boot_addr && update {
+            if is_app_image(&data) {
+                update_flash_params(
+                    flasher.chip(),
+                    &mut data,
+                    flash_config_args.flash_mode,
+                    flash_size,
+                    flash_config_args.flash_freq,
+                )?;
+            } else {
+                warn!("The image at {addr:#x} is not a bootloader, its flash settings are not updated");
+            }
+        }
+
+        segments.push(RomSegment {
+            addr,
+            data: Cow::Owned(data),
+            encrypted: false,
+        });
+    }
+
+    check_partition_table(&mut flasher, None, false);
+    flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
+    print_flash_summary(flasher.flash_summary(), SummaryFormat::Table)?;
+    info!("Flashing has completed!");
+
+    Ok(())
+}
+
+/// Read each of the binaries from a list of addresses and file names
+fn read_files(args: &[String]) -> Result<Vec<(u32, Vec<u8>)>> {
+    if args.len() % 2 != 0 {
+        bail!("Each binary must be given as an address followed by a file name");
+    }
+
+    args.chunks(2)
+        .map(|pair| {
+            let (addr, path) = (&pair[0], &pair[1]);
+            let addr = parse_address(addr)?;
+            let data = fs::read(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {path}"))?;
+
+            Ok((addr, data))
+        })
+        .collect()
+}
+
+/// Parse an address, which `esptool.py` accepts in either hex or decimal
+fn parse_address(addr: &str) -> Result<u32> {
+    let parsed = match addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => addr.parse(),
+    };
+
+    match parsed {
+        Ok(addr) => Ok(addr),
+        Err(_) => bail!("Invalid address: {addr}"),
+    }
+}
+
+/// Parse a flash setting given as `esptool.py` accepts it, where `keep` leaves
+/// the setting unchanged
+fn esptool_setting<T: ValueEnum>(value: &str, suffix: &str) -> Result<Option<T>> {
+    if value == "keep" {
+        return Ok(None);
+    }
+
+    match T::from_str(&format!("{value}{suffix}"), true) {
+        Ok(setting) => Ok(Some(setting)),
+        Err(_) => bail!("Invalid flash setting: {value}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::FlashSizeArg,
+        flasher::{FlashFrequency, FlashMode, FlashSize},
+    };
+
+    #[test]
+    fn test_esptool_setting() {
+        assert!(esptool_setting::<FlashMode>("keep", "").unwrap().is_none());
+        assert!(matches!(
+            esptool_setting::<FlashMode>("dio", "").unwrap(),
+            Some(FlashMode::Dio)
+        ));
+        assert_eq!(
+            esptool_setting::<FlashFrequency>("40m", "hz").unwrap(),
+            Some(FlashFrequency::_40Mhz)
+        );
+        assert_eq!(
+            esptool_setting::<FlashSizeArg>("4MB", "").unwrap(),
+            Some(FlashSizeArg::Size(FlashSize::_4Mb))
+        );
+        assert_eq!(
+            esptool_setting::<FlashSizeArg>("detect", "").unwrap(),
+            Some(FlashSizeArg::Detect)
+        );
+        assert!(esptool_setting::<FlashMode>("fast", "").is_err());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0x1000").unwrap(), 0x1000);
+        assert_eq!(parse_address("65536").unwrap(), 0x10000);
+        assert!(parse_address("0xzz").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Generation of LittleFS images
+//!
+//! Images use on-disk version 2.0 of LittleFS, which can be mounted by both
+//! 2.0 and later versions. Each directory is stored in a chain of metadata
+//! pairs containing a single commit, and every non-empty file is stored out of
+//! line in a CTZ skip-list, as the size of inline files is limited by the cache
+//! size the device is configured with.
+//!
+//! See the [specification] for details of the on-disk format.
+//!
+//! [specification]: https://github.com/littlefs-project/littlefs/blob/master/SPEC.md
+
+use flate2::Crc;
+
+use super::{Node, FS_BLOCK_SIZE, FS_PAGE_SIZE};
+use crate::error::Error;
+
+const VERSION: u32 = 0x0002_0000;
+const FILE_MAX: u32 = 0x7fff_ffff;
+const ATTR_MAX: u32 = 1022;
+
+/// The size each metadata commit is kept within, which leaves room for the
+/// device to append to the metadata pair before it needs to be split
+const METADATA_MAX: usize = FS_BLOCK_SIZE as usize / 2;
+const ID_MAX: u32 = 0x3fe;
+
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CCRC: u32 = 0x500;
+const TYPE_SOFTTAIL: u32 = 0x600;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+/// Generate a LittleFS image of `size` bytes containing `entries`
+pub(super) fn build(
+    entries: &[(String, Node)],
+    size: u32,
+    name_max: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut builder = Builder {
+        image: vec![0xff; (size - size % FS_BLOCK_SIZE) as usize],
+        block_count: size / FS_BLOCK_SIZE,
+        next_block: 0,
+        name_max,
+        size,
+        pairs: Vec::new(),
+    };
+
+    // The root directory must be stored in the first two blocks, as it holds
+    // the superblock
+    let root = builder.alloc_pair()?;
+    builder.add_dir(root, entries, true)?;
+
+    Ok(builder.finish())
+}
+
+/// A metadata pair, and the attributes of its commit
+struct MetadataPair {
+    blocks: [u32; 2],
+    attrs: Vec<(u32, Vec<u8>)>,
+    ids: u32,
+    /// Whether the directory continues in the following metadata pair
+    split: bool,
+}
+
+impl MetadataPair {
+    fn new(blocks: [u32; 2]) -> Self {
+        Self {
+            blocks,
+            attrs: Vec::new(),
+            ids: 0,
+            split: false,
+        }
+    }
+
+    /// The size of the commit, excluding its tail and CRC tags
+    fn len(&self) -> usize {
+        4 + self
+            .attrs
+            .iter()
+            .map(|(_, data)| 4 + data.len())
+            .sum::<usize>()
+    }
+
+    /// Add an entry with the given name and struct to the metadata pair
+    fn push(&mut self, name: (u32, &[u8]), struct_: (u32, Vec<u8>)) {
+        let id = self.ids;
+        self.ids += 1;
+
+        self.attrs
+            .push((tag(name.0, id, name.1.len()), name.1.to_vec()));
+        self.attrs
+            .push((tag(struct_.0, id, struct_.1.len()), struct_.1));
+    }
+}
+
+struct Builder {
+    image: Vec<u8>,
+    block_count: u32,
+    next_block: u32,
+    name_max: u32,
+    size: u32,
+    /// Every metadata pair in the filesystem, in the order they are linked in
+    pairs: Vec<MetadataPair>,
+}
+
+impl Builder {
+    fn alloc_block(&mut self) -> Result<u32, Error> {
+        if self.next_block == self.block_count {
+            return Err(Error::FsContentsTooBig(self.size));
+        }
+        self.next_block += 1;
+
+        Ok(self.next_block - 1)
+    }
+
+    fn alloc_pair(&mut self) -> Result<[u32; 2], Error> {
+        Ok([self.alloc_block()?, self.alloc_block()?])
+    }
+
+    /// Add a directory, and everything within it, whose first metadata pair
+    /// has already been allocated
+    fn add_dir(
+        &mut self,
+        blocks: [u32; 2],
+        entries: &[(String, Node)],
+        root: bool,
+    ) -> Result<(), Error> {
+        let mut pairs = vec![MetadataPair::new(blocks)];
+        if root {
+            pairs[0].push(
+                (TYPE_SUPERBLOCK, b"littlefs"),
+                (TYPE_INLINESTRUCT, self.superblock()),
+            );
+        }
+
+        let mut dirs = Vec::new();
+        for (name, node) in entries {
+            if name.len() > self.name_max as usize {
+                return Err(Error::FsNameTooLong(name.clone(), self.name_max));
+            }
+
+            let (ty, struct_) = match node {
+                Node::File(data) if data.is_empty() => (TYPE_REG, (TYPE_INLINESTRUCT, Vec::new())),
+                Node::File(data) => {
+                    let head = self.write_ctz(data)?;
+                    (
+                        TYPE_REG,
+                        (TYPE_CTZSTRUCT, le_words(&[head, data.len() as u32])),
+                    )
+                }
+                Node::Dir(entries) => {
+                    let blocks = self.alloc_pair()?;
+                    dirs.push((blocks, entries));
+                    (TYPE_DIR, (TYPE_DIRSTRUCT, le_words(&blocks)))
+                }
+            };
+
+            // Continue the directory in a new metadata pair once this one is full
+            let len = 4 + name.len() + 4 + struct_.1.len();
+            let last = pairs.last().unwrap();
+            if last.len() + len > METADATA_MAX || last.ids == ID_MAX {
+                let blocks = self.alloc_pair()?;
+                pairs.last_mut().unwrap().split = true;
+                pairs.push(MetadataPair::new(blocks));
+            }
+            pairs
+                .last_mut()
+                .unwrap()
+                .push((ty, name.as_bytes()), struct_);
+        }
+
+        // The metadata pairs of subdirectories follow those of their parent
+        self.pairs.extend(pairs);
+        for (blocks, entries) in dirs {
+            self.add_dir(blocks, entries, false)?;
+        }
+
+        Ok(())
+    }
+
+    fn superblock(&self) -> Vec<u8> {
+        le_words(&[
+            VERSION,
+            FS_BLOCK_SIZE,
+            self.block_count,
+            self.name_max,
+            FILE_MAX,
+            ATTR_MAX,
+        ])
+    }
+
+    /// Write the contents of a file to a CTZ skip-list, returning its head
+    ///
+    /// Block `n` of the list begins with pointers to the blocks `n - 2^i`, for
+    /// each `i` up to and including the number of trailing zeros in `n`.
+    fn write_ctz(&mut self, mut data: &[u8]) -> Result<u32, Error> {
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let block = self.alloc_block()?;
+            let index = blocks.len();
+            let skips = match index {
+                0 => 0,
+                index => index.trailing_zeros() as usize + 1,
+            };
+
+            let start = (block * FS_BLOCK_SIZE) as usize;
+            let pointers = (0..skips)
+                .map(|i| blocks[index - (1 << i)])
+                .collect::<Vec<_>>();
+            let pointers = le_words(&pointers);
+            self.image[start..][..pointers.len()].copy_from_slice(&pointers);
+
+            let len = data.len().min(FS_BLOCK_SIZE as usize - pointers.len());
+            self.image[start + pointers.len()..][..len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+
+            blocks.push(block);
+        }
+
+        Ok(*blocks.last().unwrap())
+    }
+
+    /// Write every metadata pair, linking each to the next
+    fn finish(mut self) -> Vec<u8> {
+        for (i, pair) in self.pairs.iter().enumerate() {
+            let tail = match self.pairs.get(i + 1) {
+                Some(next) if pair.split => Some((TYPE_HARDTAIL, next.blocks)),
+                Some(next) => Some((TYPE_SOFTTAIL, next.blocks)),
+                None => None,
+            };
+
+            let commit = commit(pair, tail);
+            let start = (pair.blocks[0] * FS_BLOCK_SIZE) as usize;
+            self.image[start..][..commit.len()].copy_from_slice(&commit);
+        }
+
+        self.image
+    }
+}
+
+/// Encode the commit of a metadata pair, with the given tail
+///
+/// The second block of the pair is left erased.
+fn commit(pair: &MetadataPair, tail: Option<(u32, [u32; 2])>) -> Vec<u8> {
+    let tail = tail.map(|(ty, blocks)| (tag(ty, 0x3ff, 8), le_words(&blocks)));
+
+    // Revision count
+    let mut commit = 1u32.to_le_bytes().to_vec();
+
+    // Tags are stored big-endian, XORed with the previous tag
+    let mut ptag = 0xffff_ffff;
+    for (tag, data) in pair.attrs.iter().chain(&tail) {
+        commit.extend_from_slice(&(tag ^ ptag).to_be_bytes());
+        commit.extend_from_slice(data);
+        ptag = *tag;
+    }
+
+    // The CRC tag's data pads the commit to the next program boundary
+    let end = align_up(commit.len() + 8, FS_PAGE_SIZE as usize);
+    let crc_tag = tag(TYPE_CCRC, 0x3ff, end - commit.len() - 4);
+    commit.extend_from_slice(&(crc_tag ^ ptag).to_be_bytes());
+
+    // LittleFS' CRC-32 is not inverted once complete
+    let mut crc = Crc::new();
+    crc.update(&commit);
+    commit.extend_from_slice(&(!crc.sum()).to_le_bytes());
+
+    commit
+}
+
+fn tag(ty: u32, id: u32, len: usize) -> u32 {
+    (ty << 20) | (id << 10) | len as u32
+}
+
+fn le_words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superblock() {
+        let image = build(&[], 0x10000, 64).unwrap();
+
+        assert_eq!(image.len(), 0x10000);
+        assert_eq!(&image[0..4], &1u32.to_le_bytes());
+        assert_eq!(&image[8..16], b"littlefs");
+        // The second block of the pair is left erased
+        assert!(image[0x1000..0x2000].iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn test_ctz_pointers() {
+        let data = vec![0xaa; FS_BLOCK_SIZE as usize * 4];
+        let image = build(&[("file".into(), Node::File(data))], 0x10000, 64).unwrap();
+
+        // The file's blocks follow the root metadata pair
+        let block = |n: usize| &image[(2 + n) * FS_BLOCK_SIZE as usize..][..8];
+        assert_eq!(block(0), &[0xaa; 8]);
+        assert_eq!(&block(1)[..4], &2u32.to_le_bytes());
+        assert_eq!(block(2), le_words(&[3, 2]));
+        assert_eq!(&block(3)[..4], &4u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_contents_too_big() {
+        let data = vec![0; 0x10000];
+
+        assert!(matches!(
+            build(&[("file".into(), Node::File(data))], 0x10000, 64),
+            Err(Error::FsContentsTooBig(0x10000))
+        ));
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let name = "a".repeat(33);
+
+        assert!(matches!(
+            build(&[(name, Node::File(Vec::new()))], 0x10000, 32),
+            Err(Error::FsNameTooLong(_, 32))
+        ));
+    }
+}
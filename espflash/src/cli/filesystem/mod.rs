@@ -0,0 +1,128 @@
+//! Filesystem images for data partitions
+//!
+//! Images can either be provided pre-built, or generated from a directory on
+//! the host. Generated images can be mounted by the LittleFS and SPIFFS
+//! components of ESP-IDF and Arduino.
+
+use std::{fs, path::Path};
+
+use esp_idf_part::{DataType, Partition, PartitionTable, SubType, Type};
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use strum::{Display, IntoStaticStr};
+
+use crate::{error::Error, targets::Chip};
+
+mod littlefs;
+mod spiffs;
+
+const FS_BLOCK_SIZE: u32 = 4096;
+const FS_PAGE_SIZE: u32 = 256;
+
+/// Labels commonly used for filesystem partitions, in order of preference
+const FS_PARTITION_LABELS: &[&str] = &["storage", "spiffs", "littlefs"];
+
+/// Supported filesystems
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, IntoStaticStr, clap::ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+pub enum FilesystemKind {
+    /// LittleFS
+    Littlefs,
+    /// SPIFFS
+    Spiffs,
+}
+
+/// A file or directory to include in a generated image
+#[derive(Debug)]
+enum Node {
+    File(Vec<u8>),
+    /// The entries of a directory, sorted by name
+    Dir(Vec<(String, Node)>),
+}
+
+/// Find the partition to write a filesystem image to
+///
+/// If no label is provided, the first partition with a well-known filesystem
+/// label is used, falling back to the first `spiffs` data partition.
+pub fn find_fs_partition<'a>(
+    partition_table: &'a PartitionTable,
+    label: Option<&str>,
+) -> Result<&'a Partition, Error> {
+    match label {
+        Some(label) => partition_table
+            .find(label)
+            .ok_or_else(|| Error::PartitionNotFound(label.to_string())),
+        None => FS_PARTITION_LABELS
+            .iter()
+            .find_map(|label| partition_table.find(label))
+            .or_else(|| {
+                partition_table.find_by_subtype(Type::Data, SubType::Data(DataType::Spiffs))
+            })
+            .ok_or(Error::MissingFsPartition),
+    }
+}
+
+/// Load a filesystem image, generating it first if `path` is a directory
+///
+/// Generated images are laid out for the given chip, and fill the partition of
+/// `size` bytes.
+pub fn load_fs_image(path: &Path, kind: FilesystemKind, size: u32, chip: Chip) -> Result<Vec<u8>> {
+    let data = if path.is_dir() {
+        let entries = read_dir(path)?;
+        match kind {
+            FilesystemKind::Littlefs => littlefs::build(&entries, size, littlefs_name_max(chip))?,
+            FilesystemKind::Spiffs => spiffs::build(&entries, size)?,
+        }
+    } else {
+        fs::read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open filesystem image {}", path.display()))?
+    };
+
+    if data.len() as u32 > size {
+        return Err(Error::FsImageTooBig(data.len() as u32, size).into());
+    }
+
+    Ok(data)
+}
+
+/// The maximum length of LittleFS file names supported by the chip's LittleFS
+/// component
+///
+/// This is 32 bytes for the ESP8266 Arduino core, and 64 bytes for the
+/// `esp_littlefs` component used by ESP-IDF and the ESP32 Arduino core.
+fn littlefs_name_max(chip: Chip) -> u32 {
+    match chip {
+        Chip::Esp8266 => 32,
+        _ => 64,
+    }
+}
+
+/// Read the contents of a directory on the host, sorting its entries by name
+fn read_dir(dir: &Path) -> Result<Vec<(String, Node)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read directory {}", dir.display()))?
+    {
+        let path = entry.into_diagnostic()?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| miette!("The name of {} is not valid UTF-8", path.display()))?
+            .to_string();
+
+        let node = if path.is_dir() {
+            Node::Dir(read_dir(&path)?)
+        } else {
+            Node::File(
+                fs::read(&path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read {}", path.display()))?,
+            )
+        };
+        entries.push((name, node));
+    }
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(entries)
+}
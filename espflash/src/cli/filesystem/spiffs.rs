@@ -0,0 +1,244 @@
+//! Generation of SPIFFS images
+//!
+//! Images use the configuration of SPIFFS used by ESP-IDF, and are laid out in
+//! the same way as those generated by its `spiffsgen.py`: each file is written
+//! in turn to the next free pages, starting with its index page.
+//!
+//! SPIFFS has no directories, so files are named after their full path.
+
+use std::mem;
+
+use super::{Node, FS_BLOCK_SIZE, FS_PAGE_SIZE};
+use crate::error::Error;
+
+const PAGE_SIZE: usize = FS_PAGE_SIZE as usize;
+const PAGES_PER_BLOCK: usize = (FS_BLOCK_SIZE / FS_PAGE_SIZE) as usize;
+
+/// Each block begins with a lookup page, containing the object ID of each of
+/// its remaining pages
+const LOOKUP_PAGES: usize = 1;
+const USABLE_PAGES_PER_BLOCK: usize = PAGES_PER_BLOCK - LOOKUP_PAGES;
+/// The position of the magic number within the lookup page, which is followed
+/// by the erase count
+const MAGIC_OFFSET: usize = LOOKUP_PAGES * PAGE_SIZE - 4;
+
+const OBJ_NAME_LEN: usize = 32;
+const META_LEN: usize = 4;
+
+/// Object ID, span index, and flags
+const PAGE_HEADER_LEN: usize = 5;
+/// The page header, padded to a word boundary, followed by the size, type, name
+/// and metadata of the object
+const INDEX_HEADER_LEN: usize = 8 + 4 + 1 + OBJ_NAME_LEN + META_LEN;
+const DATA_PAGE_LEN: usize = PAGE_SIZE - PAGE_HEADER_LEN;
+const HEAD_INDEX_ENTRIES: usize = (PAGE_SIZE - INDEX_HEADER_LEN) / 2;
+const INDEX_ENTRIES: usize = (PAGE_SIZE - 8) / 2;
+
+const OBJ_ID_INDEX: u16 = 0x8000;
+const FLAG_INDEX: u8 = 0xf8;
+const FLAG_DATA: u8 = 0xfc;
+const TYPE_FILE: u8 = 1;
+
+/// Generate a SPIFFS image of `size` bytes containing `entries`
+pub(super) fn build(entries: &[(String, Node)], size: u32) -> Result<Vec<u8>, Error> {
+    let block_count = (size / FS_BLOCK_SIZE) as usize;
+    let mut builder = Builder {
+        image: vec![0xff; block_count * FS_BLOCK_SIZE as usize],
+        pages: block_count * USABLE_PAGES_PER_BLOCK,
+        next_page: 0,
+        next_obj_id: 1,
+        size,
+    };
+
+    for block in 0..block_count {
+        let magic = (0x2014_0529 ^ FS_PAGE_SIZE ^ (block_count - block) as u32) as u16;
+        let offset = block * FS_BLOCK_SIZE as usize + MAGIC_OFFSET;
+        builder.image[offset..][..2].copy_from_slice(&magic.to_le_bytes());
+    }
+
+    builder.add_dir("", entries)?;
+
+    Ok(builder.image)
+}
+
+struct Builder {
+    image: Vec<u8>,
+    pages: usize,
+    next_page: usize,
+    next_obj_id: u16,
+    size: u32,
+}
+
+impl Builder {
+    fn add_dir(&mut self, path: &str, entries: &[(String, Node)]) -> Result<(), Error> {
+        for (name, node) in entries {
+            let path = format!("{path}/{name}");
+            match node {
+                Node::File(data) => self.add_file(&path, data)?,
+                Node::Dir(entries) => self.add_dir(&path, entries)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        // Names are terminated by a NUL byte
+        if name.len() >= OBJ_NAME_LEN {
+            return Err(Error::FsNameTooLong(
+                name.to_string(),
+                OBJ_NAME_LEN as u32 - 1,
+            ));
+        }
+
+        let obj_id = self.next_obj_id;
+        self.next_obj_id += 1;
+
+        // The first index page also holds the object's size and name
+        let mut header = Vec::with_capacity(INDEX_HEADER_LEN - 8);
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.push(TYPE_FILE);
+        header.extend_from_slice(name.as_bytes());
+        header.resize(INDEX_HEADER_LEN - 8, 0);
+
+        let mut index = IndexPage::new(self.alloc_page(obj_id | OBJ_ID_INDEX)?, 0, header);
+        for (span, chunk) in data.chunks(DATA_PAGE_LEN).enumerate() {
+            if index.is_full() {
+                let next = IndexPage::new(
+                    self.alloc_page(obj_id | OBJ_ID_INDEX)?,
+                    index.span + 1,
+                    Vec::new(),
+                );
+                self.write_index(obj_id, mem::replace(&mut index, next));
+            }
+
+            let page = self.alloc_page(obj_id)?;
+            let mut contents = page_header(obj_id, span as u16, FLAG_DATA);
+            contents.extend_from_slice(chunk);
+            self.page_mut(page)[..contents.len()].copy_from_slice(&contents);
+
+            index.entries.push(page as u16);
+        }
+        self.write_index(obj_id, index);
+
+        Ok(())
+    }
+
+    /// Allocate the next free page to the given object ID, returning its index
+    fn alloc_page(&mut self, obj_id: u16) -> Result<usize, Error> {
+        if self.next_page == self.pages {
+            return Err(Error::FsContentsTooBig(self.size));
+        }
+
+        let block = self.next_page / USABLE_PAGES_PER_BLOCK;
+        let entry = self.next_page % USABLE_PAGES_PER_BLOCK;
+        self.next_page += 1;
+
+        let lookup = block * FS_BLOCK_SIZE as usize + entry * 2;
+        self.image[lookup..][..2].copy_from_slice(&obj_id.to_le_bytes());
+
+        Ok(block * PAGES_PER_BLOCK + LOOKUP_PAGES + entry)
+    }
+
+    fn write_index(&mut self, obj_id: u16, index: IndexPage) {
+        let mut contents = page_header(obj_id | OBJ_ID_INDEX, index.span, FLAG_INDEX);
+        contents.resize(8, 0xff);
+        contents.extend_from_slice(&index.header);
+        for entry in index.entries {
+            contents.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        self.page_mut(index.page)[..contents.len()].copy_from_slice(&contents);
+    }
+
+    fn page_mut(&mut self, page: usize) -> &mut [u8] {
+        &mut self.image[page * PAGE_SIZE..][..PAGE_SIZE]
+    }
+}
+
+/// An object index page, listing the data pages of part of an object
+struct IndexPage {
+    page: usize,
+    span: u16,
+    header: Vec<u8>,
+    entries: Vec<u16>,
+}
+
+impl IndexPage {
+    fn new(page: usize, span: u16, header: Vec<u8>) -> Self {
+        Self {
+            page,
+            span,
+            header,
+            entries: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        let capacity = match self.span {
+            0 => HEAD_INDEX_ENTRIES,
+            _ => INDEX_ENTRIES,
+        };
+
+        self.entries.len() == capacity
+    }
+}
+
+fn page_header(obj_id: u16, span: u16, flags: u8) -> Vec<u8> {
+    let mut header = Vec::with_capacity(PAGE_SIZE);
+    header.extend_from_slice(&obj_id.to_le_bytes());
+    header.extend_from_slice(&span.to_le_bytes());
+    header.push(flags);
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic() {
+        let image = build(&[], 0x10000).unwrap();
+
+        assert_eq!(image.len(), 0x10000);
+        assert_eq!(&image[MAGIC_OFFSET..][..2], &0x0439u16.to_le_bytes());
+        assert_eq!(
+            &image[0x1000 + MAGIC_OFFSET..][..2],
+            &0x0426u16.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn test_file_pages() {
+        let data = vec![0xaa; DATA_PAGE_LEN + 1];
+        let entries = [(
+            "dir".into(),
+            Node::Dir(vec![("file".into(), Node::File(data))]),
+        )];
+        let image = build(&entries, 0x10000).unwrap();
+
+        // Lookup entries for the index page and both data pages
+        assert_eq!(&image[0..6], &[0x01, 0x80, 0x01, 0x00, 0x01, 0x00]);
+
+        let index = &image[PAGE_SIZE..][..PAGE_SIZE];
+        assert_eq!(&index[0..5], &[0x01, 0x80, 0x00, 0x00, FLAG_INDEX]);
+        assert_eq!(&index[8..12], &(DATA_PAGE_LEN as u32 + 1).to_le_bytes());
+        assert_eq!(&index[13..22], b"/dir/file");
+        assert_eq!(&index[INDEX_HEADER_LEN..][..4], &[2, 0, 3, 0]);
+
+        let data = &image[3 * PAGE_SIZE..][..PAGE_SIZE];
+        assert_eq!(&data[0..6], &[0x01, 0x00, 0x01, 0x00, FLAG_DATA, 0xaa]);
+        assert_eq!(data[6], 0xff);
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let name = "a".repeat(31);
+
+        assert!(matches!(
+            build(&[(name, Node::File(Vec::new()))], 0x10000),
+            Err(Error::FsNameTooLong(_, 31))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+//! Interoperability with the ESP-IDF build system and `esptool.py`
+//!
+//! Images can be saved as one file per part, alongside the `flash_args` and
+//! `flasher_args.json` files which ESP-IDF generates in its build directory.
+//! These can be passed to `esptool.py write_flash @flash_args`, or consumed
+//! by any tooling which understands ESP-IDF build directories.
+
+use std::{borrow::Cow, collections::BTreeMap, fs, path::Path};
+
+use clap::ValueEnum;
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    elf::RomSegment,
+    flasher::{FlashFrequency, FlashMode, FlashSize},
+    image_format::ImageFormat,
+    targets::Chip,
+};
+
+/// Contents of the `flasher_args.json` file in an ESP-IDF build directory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlasherArgs {
+    /// Arguments to pass to `esptool.py write_flash`
+    pub write_flash_args: Vec<String>,
+    /// Flash settings written to the image headers
+    pub flash_settings: FlashSettings,
+    /// Files to write, keyed by their offset
+    pub flash_files: BTreeMap<String, String>,
+    /// The second-stage bootloader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<FlashFile>,
+    /// The application
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<FlashFile>,
+    /// The partition table
+    #[serde(
+        default,
+        rename = "partition-table",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub partition_table: Option<FlashFile>,
+    /// Connection settings for `esptool.py`
+    pub extra_esptool_args: ExtraEsptoolArgs,
+}
+
+/// Flash settings, in the format accepted by `esptool.py`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlashSettings {
+    pub flash_mode: String,
+    pub flash_size: String,
+    pub flash_freq: String,
+}
+
+/// A single file to write to flash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlashFile {
+    pub offset: String,
+    pub file: String,
+    pub encrypted: String,
+}
+
+/// Connection settings for `esptool.py`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtraEsptoolArgs {
+    pub after: String,
+    pub before: String,
+    pub stub: bool,
+    pub chip: String,
+}
+
+/// Write each part of `image` to its own file in the directory `dir`, named
+/// after its offset, along with `flash_args` and `flasher_args.json` files
+/// describing where each part is written
+///
+/// Flash settings which are not provided are written as `keep`, so that
+/// `esptool.py` uses those in the image headers.
+pub fn save_split_image(
+    dir: &Path,
+    chip: Chip,
+    image: &dyn ImageFormat,
+    flash_mode: Option<FlashMode>,
+    flash_size: Option<FlashSize>,
+    flash_freq: Option<FlashFrequency>,
+) -> Result<()> {
+    fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    let write = |addr: u32, name: &str, data: &[u8]| -> Result<FlashFile> {
+        let file = format!("{addr:#x}_{name}.bin");
+        fs::write(dir.join(&file), data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {file}"))?;
+
+        Ok(FlashFile {
+            offset: format!("{addr:#x}"),
+            file,
+            encrypted: "false".into(),
+        })
+    };
+
+    let bootloader = image
+        .bootloader_segment()
+        .map(|segment| write(segment.addr, "bootloader", &segment.data))
+        .transpose()?;
+    let partition_table = image
+        .partition_table_segment()
+        .map(|segment| write(segment.addr, "partition-table", &segment.data))
+        .transpose()?;
+    let mut segments = image.ota_segments().collect::<Result<Vec<_>, _>>()?;
+    segments.sort_by_key(|segment| segment.addr);
+    let mut apps = segments
+        .iter()
+        .map(|segment| write(segment.addr, "app", &segment.data))
+        .collect::<Result<Vec<_>>>()?;
+
+    let flash_settings = FlashSettings {
+        flash_mode: esptool_value(flash_mode.map(FlashMode::header_mode), |name| {
+            name.to_string()
+        }),
+        flash_size: esptool_value(flash_size, |name| name.to_uppercase()),
+        flash_freq: esptool_value(flash_freq.map(FlashFrequency::header_frequency), |name| {
+            name.trim_end_matches("hz").to_string()
+        }),
+    };
+    let write_flash_args = vec![
+        "--flash_mode".to_string(),
+        flash_settings.flash_mode.clone(),
+        "--flash_size".to_string(),
+        flash_settings.flash_size.clone(),
+        "--flash_freq".to_string(),
+        flash_settings.flash_freq.clone(),
+    ];
+
+    let files = bootloader.iter().chain(&partition_table).chain(&apps);
+    let flash_files = files
+        .clone()
+        .map(|file| (file.offset.clone(), file.file.clone()))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut flash_args = write_flash_args.join(" ");
+    for file in files {
+        flash_args.push_str(&format!("\n{} {}", file.offset, file.file));
+    }
+    flash_args.push('\n');
+    fs::write(dir.join("flash_args"), flash_args).into_diagnostic()?;
+
+    let flasher_args = FlasherArgs {
+        write_flash_args,
+        flash_settings,
+        flash_files,
+        bootloader,
+        // Only images consisting of a single application part can be described
+        app: if apps.len() == 1 { apps.pop() } else { None },
+        partition_table,
+        extra_esptool_args: ExtraEsptoolArgs {
+            after: "hard_reset".into(),
+            before: "default_reset".into(),
+            stub: true,
+            chip: chip.to_string(),
+        },
+    };
+    let json = serde_json::to_string_pretty(&flasher_args).into_diagnostic()?;
+    fs::write(dir.join("flasher_args.json"), json).into_diagnostic()?;
+
+    Ok(())
+}
+
+/// The binaries listed in the `flasher_args.json` file of an ESP-IDF build
+/// directory
+pub struct BuildDir {
+    /// Chip the project was built for
+    pub chip: Chip,
+    /// Each binary, along with the offset it is written to
+    pub segments: Vec<RomSegment<'static>>,
+    /// The application's ELF image, if it is alongside its binary
+    pub elf: Option<Vec<u8>>,
+}
+
+impl BuildDir {
+    /// Load the binaries listed in `dir/flasher_args.json`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("flasher_args.json");
+        let json = fs::read(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let args: FlasherArgs = serde_json::from_slice(&json)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        let chip = match args.extra_esptool_args.chip.parse::<Chip>() {
+            Ok(chip) => chip,
+            Err(_) => bail!(
+                "The project was built for an unsupported chip: {}",
+                args.extra_esptool_args.chip
+            ),
+        };
+
+        let mut segments = Vec::new();
+        for (offset, file) in &args.flash_files {
+            let addr = match u32::from_str_radix(offset.trim_start_matches("0x"), 16) {
+                Ok(addr) => addr,
+                Err(_) => bail!("Invalid offset '{offset}' for {file}"),
+            };
+            let data = fs::read(dir.join(file))
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {file}"))?;
+
+            segments.push(RomSegment {
+                addr,
+                data: Cow::Owned(data),
+                encrypted: false,
+            });
+        }
+        segments.sort_by_key(|segment| segment.addr);
+
+        let elf = args
+            .app
+            .and_then(|app| fs::read(dir.join(app.file).with_extension("elf")).ok());
+
+        Ok(Self {
+            chip,
+            segments,
+            elf,
+        })
+    }
+}
+
+/// Format a flash setting as `esptool.py` expects it, based on the name of
+/// its command-line value, or `keep` if it was not provided
+fn esptool_value<T: ValueEnum>(value: Option<T>, format: impl Fn(&str) -> String) -> String {
+    value
+        .and_then(|value| value.to_possible_value())
+        .map(|value| format(value.get_name()))
+        .unwrap_or_else(|| "keep".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::{elf::ElfFirmwareImage, flasher::FlashData};
+
+    #[test]
+    fn test_save_split_image() {
+        let elf_data = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let elf = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        let flash_data = FlashData::builder()
+            .flash_mode(FlashMode::Dio)
+            .flash_size(FlashSize::_4Mb)
+            .build();
+        let image = Chip::Esp32
+            .into_target()
+            .get_flash_image(&elf, flash_data, None, None)
+            .unwrap();
+
+        let dir = env::temp_dir().join(format!("espflash-split-{}", process::id()));
+        let result = save_split_image(
+            &dir,
+            Chip::Esp32,
+            image.as_ref(),
+            Some(FlashMode::Dio),
+            Some(FlashSize::_4Mb),
+            None,
+        );
+        let read = |file: &str| fs::read(dir.join(file)).unwrap();
+        let flash_args = fs::read_to_string(dir.join("flash_args"));
+        let build_dir = BuildDir::load(&dir);
+        let files = (
+            read("0x1000_bootloader.bin"),
+            read("0x8000_partition-table.bin"),
+            read("0x10000_app.bin"),
+        );
+        fs::remove_dir_all(&dir).ok();
+        result.unwrap();
+
+        // Each part is written to its own file, named after its offset
+        let mut app = Vec::new();
+        image.write_to(&mut app).unwrap();
+        assert_eq!(files.0, image.bootloader_segment().unwrap().data.as_ref());
+        assert_eq!(
+            files.1,
+            image.partition_table_segment().unwrap().data.as_ref()
+        );
+        assert_eq!(files.2, app);
+
+        // Settings which were not provided are kept as they are in the headers
+        assert_eq!(
+            flash_args.unwrap(),
+            "--flash_mode dio --flash_size 4MB --flash_freq keep\n\
+             0x1000 0x1000_bootloader.bin\n\
+             0x8000 0x8000_partition-table.bin\n\
+             0x10000 0x10000_app.bin\n"
+        );
+
+        // The files are listed in flasher_args.json, as in an ESP-IDF build directory
+        let build_dir = build_dir.unwrap();
+        assert_eq!(build_dir.chip, Chip::Esp32);
+        assert_eq!(
+            build_dir
+                .segments
+                .iter()
+                .map(|segment| (segment.addr, segment.data.len()))
+                .collect::<Vec<_>>(),
+            [
+                (0x1000, files.0.len()),
+                (0x8000, files.1.len()),
+                (0x10000, files.2.len())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_build_dir() {
+        let dir = env::temp_dir().join(format!("espflash-idf-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.bin"), [1, 2, 3]).unwrap();
+        fs::write(dir.join("bootloader.bin"), [4, 5]).unwrap();
+        fs::write(
+            dir.join("flasher_args.json"),
+            r#"{
+                "write_flash_args": ["--flash_mode", "dio"],
+                "flash_settings": {"flash_mode": "dio", "flash_size": "4MB", "flash_freq": "40m"},
+                "flash_files": {"0x10000": "app.bin", "0x0": "bootloader.bin"},
+                "app": {"offset": "0x10000", "file": "app.bin", "encrypted": "false"},
+                "extra_esptool_args": {"after": "hard_reset", "before": "default_reset", "stub": true, "chip": "esp32c3"}
+            }"#,
+        )
+        .unwrap();
+
+        let build_dir = BuildDir::load(&dir);
+        fs::remove_dir_all(&dir).ok();
+        let build_dir = build_dir.unwrap();
+
+        assert_eq!(build_dir.chip, Chip::Esp32c3);
+        assert_eq!(
+            build_dir
+                .segments
+                .iter()
+                .map(|segment| (segment.addr, segment.data.to_vec()))
+                .collect::<Vec<_>>(),
+            vec![(0x0, vec![4, 5]), (0x10000, vec![1, 2, 3])]
+        );
+        assert!(build_dir.elf.is_none());
+    }
+}
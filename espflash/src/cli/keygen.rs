@@ -0,0 +1,86 @@
+//! Generating keys for secure boot and flash encryption
+//!
+//! Keys are written to new files, which are only readable by the current user
+//! on Unix-like systems; existing files are never overwritten, since losing a
+//! key which has been burned into a device's eFuses can make it impossible to
+//! update.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgGroup, Args};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::security::{generate_flash_encryption_key, KeyLength, SigningKey, SigningScheme};
+
+/// Generate a key for secure boot or flash encryption
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("kind").required(true).args(["secure_boot_v2", "flash_encryption"])))]
+pub struct KeygenArgs {
+    /// Generate a secure boot V2 signing key, in PEM format, using the given
+    /// signature scheme
+    #[arg(long, value_enum, value_name = "SCHEME")]
+    secure_boot_v2: Option<SigningScheme>,
+    /// Generate a flash encryption key, in binary format
+    #[arg(long)]
+    flash_encryption: bool,
+    /// Length of the flash encryption key, in bits
+    #[arg(long, value_enum, default_value = "256", requires = "flash_encryption")]
+    key_length: KeyLength,
+    /// Also write the digest of the public key, which is burned into the
+    /// eFuses, to this file
+    #[arg(long, value_name = "FILE", requires = "secure_boot_v2")]
+    digest: Option<PathBuf>,
+    /// File to write the key to
+    #[arg(short = 'o', long, value_name = "FILE")]
+    output: PathBuf,
+}
+
+/// Generate a key, writing it to the output file
+pub fn keygen(args: &KeygenArgs) -> Result<()> {
+    if let Some(scheme) = args.secure_boot_v2 {
+        let key = SigningKey::generate(scheme)?;
+        write_new(&args.output, key.to_pem()?.as_bytes())?;
+        println!(
+            "Wrote {scheme} secure boot signing key to {}",
+            args.output.display()
+        );
+
+        let digest = key.public_key_digest();
+        if let Some(path) = &args.digest {
+            write_new(path, &digest)?;
+        }
+        let digest = digest
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        println!("Public key digest: {digest}");
+    } else {
+        let key = generate_flash_encryption_key(args.key_length);
+        write_new(&args.output, &key)?;
+        println!(
+            "Wrote {} bit flash encryption key to {}",
+            key.len() * 8,
+            args.output.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Write to a file which must not already exist, readable only by its owner
+fn write_new(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
@@ -8,44 +8,104 @@
 //! [espflash]: https://crates.io/crates/espflash
 
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fs,
-    io::Write,
+    io::{self, stderr, stdin, stdout, BufWriter, Read, Write},
+    num::ParseIntError,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 
-use clap::Args;
+use clap::{builder::PossibleValue, ArgAction, ArgGroup, Args, ValueEnum};
 use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
-use esp_idf_part::{DataType, Partition, PartitionTable};
-use indicatif::{style::ProgressStyle, HumanCount, ProgressBar};
-use log::{debug, info};
-use miette::{IntoDiagnostic, Result, WrapErr};
+use esp_idf_part::{DataType, Partition, PartitionTable, SubType};
+use indicatif::{style::ProgressStyle, HumanBytes, HumanCount, ProgressBar};
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
 use serialport::{SerialPortType, UsbPortInfo};
-
-use self::{config::Config, monitor::monitor, serial::get_serial_port_info};
+use strum::IntoEnumIterator;
+use tracing::{debug, info, level_filters::LevelFilter, warn};
+
+use self::{
+    config::Config,
+    idf::save_split_image,
+    monitor::{monitor, MonitorOptions},
+    serial::get_serial_port_info,
+};
+#[cfg(feature = "gpio")]
+use crate::interface::StrappingPins;
 use crate::{
-    elf::ElfFirmwareImage,
-    error::{MissingPartition, MissingPartitionTable},
-    flasher::{FlashFrequency, FlashMode, FlashSize, Flasher, ProgressCallbacks},
-    image_format::ImageFormatKind,
-    interface::Interface,
-    targets::Chip,
+    connection::{Connection, ResetSequence, RetryPolicy},
+    elf::{ElfFirmwareImage, RomSegment},
+    error::{Error, MissingPartition, MissingPartitionTable, PartitionTableError},
+    flasher::{
+        app_images, app_segments, check_flash_bounds, moved_data_partitions, parse_apps,
+        ConnectOptions, FlashData, FlashDataBuilder, FlashFrequency, FlashMode, FlashParts,
+        FlashSize, FlashSummary, Flasher, ProgressCallbacks, SpiAttachParams,
+    },
+    image_format::{is_app_image, ImageFormatKind, ImageInfo, ImageVersion},
+    interface::{Interface, TcpProtocol, TcpTransport},
+    jtag::OpenOcd,
+    targets::{Chip, PartitionLayout},
+    updater::Updater,
 };
 
+pub mod bootloader;
+pub mod completions;
 pub mod config;
+pub mod doctor;
+pub mod esptool;
+pub mod filesystem;
+pub mod idf;
+pub mod keygen;
 pub mod monitor;
+pub mod ports;
+pub mod prompt;
+pub mod script;
+#[cfg(feature = "self-update")]
+pub mod self_update;
+pub mod shell;
+pub mod snapshot;
+pub mod watch;
 
 mod serial;
 
+/// Port information used when the port is not a USB device
+const UNKNOWN_PORT_INFO: UsbPortInfo = UsbPortInfo {
+    vid: 0,
+    pid: 0,
+    serial_number: None,
+    manufacturer: None,
+    product: None,
+};
+
+/// GPIO character device used when none is specified
+#[cfg(feature = "gpio")]
+const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+
 /// Establish a connection with a target device
 #[derive(Debug, Args)]
 pub struct ConnectArgs {
     /// Baud rate at which to communicate with target device
     #[arg(short = 'b', long, env = "ESPFLASH_BAUD")]
     pub baud: Option<u32>,
-    /// Serial port connected to target device
+    /// Serial port connected to target device, a remote port of the form
+    /// tcp://HOST:PORT or rfc2217://HOST:PORT, or on Linux the path of the
+    /// physical USB port the device is connected to, eg. usb:1-3.2
     #[arg(short = 'p', long, env = "ESPFLASH_PORT")]
     pub port: Option<String>,
+    /// Serial number of the USB device connected to the target device
+    #[arg(long, env = "ESPFLASH_SERIAL_NUMBER", conflicts_with = "port")]
+    pub serial_number: Option<String>,
+    /// Wait for the device to be connected, for at most the given number of
+    /// seconds if specified
+    ///
+    /// Waits for the selected port or serial number to appear, or if neither
+    /// is selected, for a port matching a configured or known dev board.
+    #[arg(long, value_name = "TIMEOUT", num_args = 0..=1)]
+    pub wait_for_device: Option<Option<u64>>,
     /// DTR pin to use for the internal UART hardware. Uses BCM numbering.
     #[cfg(feature = "raspberry")]
     #[cfg_attr(feature = "raspberry", clap(long))]
@@ -54,9 +114,82 @@ pub struct ConnectArgs {
     #[cfg(feature = "raspberry")]
     #[cfg_attr(feature = "raspberry", clap(long))]
     pub rts: Option<u8>,
+    /// GPIO character device controlling the target device's strapping pins
+    #[cfg(feature = "gpio")]
+    #[cfg_attr(feature = "gpio", clap(long, value_name = "PATH"))]
+    pub gpio_chip: Option<PathBuf>,
+    /// GPIO line wired to the target device's EN pin
+    #[cfg(feature = "gpio")]
+    #[cfg_attr(
+        feature = "gpio",
+        clap(long, value_name = "LINE", requires = "boot_gpio")
+    )]
+    pub en_gpio: Option<u32>,
+    /// GPIO line wired to the target device's IO0 (BOOT) pin
+    #[cfg(feature = "gpio")]
+    #[cfg_attr(
+        feature = "gpio",
+        clap(long, value_name = "LINE", requires = "en_gpio")
+    )]
+    pub boot_gpio: Option<u32>,
     /// Do not use the RAM stub for loading
     #[arg(long)]
     pub no_stub: bool,
+    /// Lock the serial port, so that no other process can open it while it is
+    /// in use
+    #[arg(long)]
+    pub exclusive: bool,
+    /// Sequence used to reset the target device into its bootloader
+    ///
+    /// One of 'auto', 'classic', 'esp32r0', 'usb-jtag-serial' or 'manual', or a
+    /// custom sequence such as 'D0|R1|W0.1|D1|R0|W0.05|D0'.
+    #[arg(long, value_name = "SEQUENCE", value_parser = ResetSequence::from_str)]
+    pub reset_sequence: Option<ResetSequence>,
+    /// Number of times to reset the target device while connecting
+    #[arg(long, value_name = "ATTEMPTS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_attempts: Option<u64>,
+    /// Timeout in seconds for commands sent to the target device
+    ///
+    /// Timeouts for commands whose duration depends on the amount of data
+    /// involved, such as erasing flash, are scaled proportionally.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: Option<u64>,
+    /// Log every frame exchanged with the target device
+    ///
+    /// Frames are written to FILE if one is provided, and to stderr otherwise.
+    #[arg(long, value_name = "FILE", num_args = 0..=1)]
+    pub trace: Option<Option<PathBuf>>,
+    /// SPI pins connected to the flash chip
+    ///
+    /// One of 'SPI' or 'HSPI', or the pin numbers as 'CLK,Q,D,HD,CS'. By
+    /// default the pins are detected automatically.
+    #[arg(long, value_name = "PINS", value_parser = SpiAttachParams::from_str)]
+    pub spi_connection: Option<SpiAttachParams>,
+    /// Record what the ROM prints after each reset, and include it in the
+    /// error if connecting fails
+    ///
+    /// This helps to diagnose strapping pins held at the wrong level, and
+    /// devices stuck in a boot loop.
+    #[arg(long)]
+    pub capture_rom_log: bool,
+}
+
+/// Configure flashing over JTAG
+#[derive(Debug, Args)]
+pub struct JtagArgs {
+    /// OpenOCD executable to run when flashing over JTAG
+    ///
+    /// Espressif's fork of OpenOCD is required; by default `openocd` is run
+    /// from the PATH.
+    #[arg(long, value_name = "PATH", requires = "jtag")]
+    pub openocd: Option<PathBuf>,
+    /// OpenOCD interface configuration selecting the JTAG adapter, eg.
+    /// 'interface/ftdi/esp32_devkitj_v1.cfg'
+    ///
+    /// By default the chip's built-in USB-JTAG peripheral is used, or the
+    /// ESP-Prog for chips which don't have one.
+    #[arg(long, value_name = "CFG", requires = "jtag")]
+    pub jtag_interface: Option<String>,
 }
 
 /// Configure communication with the target device's flash
@@ -68,18 +201,126 @@ pub struct FlashConfigArgs {
     /// Flash mode to use
     #[arg(short = 'm', long, value_name = "MODE", value_enum)]
     pub flash_mode: Option<FlashMode>,
-    /// Flash size of the target
+    /// Flash size of the target, or 'detect' to detect it using the flash
+    /// chip's JEDEC ID
     #[arg(short = 's', long, value_name = "SIZE", value_enum)]
-    pub flash_size: Option<FlashSize>,
+    pub flash_size: Option<FlashSizeArg>,
+}
+
+impl FlashConfigArgs {
+    /// The flash size to use when no device is connected
+    pub fn offline_flash_size(&self) -> Result<Option<FlashSize>, Error> {
+        match self.flash_size {
+            Some(FlashSizeArg::Detect) => Err(Error::FlashSizeDetectionOffline),
+            Some(FlashSizeArg::Size(size)) => Ok(Some(size)),
+            None => Ok(None),
+        }
+    }
+
+    /// Apply the flash size to the connected device, detecting it first if
+    /// requested
+    pub fn apply_flash_size(&self, flasher: &mut Flasher) -> Result<Option<FlashSize>, Error> {
+        match self.flash_size {
+            Some(FlashSizeArg::Detect) => flasher.detect_flash_size().map(Some),
+            Some(FlashSizeArg::Size(size)) => {
+                flasher.set_flash_size(size)?;
+                Ok(Some(size))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Flash size provided on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashSizeArg {
+    /// Detect the size using the flash chip's JEDEC ID
+    Detect,
+    /// Use the provided size
+    Size(FlashSize),
+}
+
+impl ValueEnum for FlashSizeArg {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            FlashSizeArg::Detect,
+            FlashSizeArg::Size(FlashSize::_256Kb),
+            FlashSizeArg::Size(FlashSize::_512Kb),
+            FlashSizeArg::Size(FlashSize::_1Mb),
+            FlashSizeArg::Size(FlashSize::_2Mb),
+            FlashSizeArg::Size(FlashSize::_4Mb),
+            FlashSizeArg::Size(FlashSize::_8Mb),
+            FlashSizeArg::Size(FlashSize::_16Mb),
+            FlashSizeArg::Size(FlashSize::_32Mb),
+            FlashSizeArg::Size(FlashSize::_64Mb),
+            FlashSizeArg::Size(FlashSize::_128Mb),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            FlashSizeArg::Detect => Some(
+                PossibleValue::new("detect")
+                    .help("Detect the size using the flash chip's JEDEC ID"),
+            ),
+            FlashSizeArg::Size(size) => size.to_possible_value(),
+        }
+    }
 }
 
 /// Flash an application to a target device
 #[derive(Debug, Args)]
 #[group(skip)]
+#[command(group(
+    ArgGroup::new("flash_parts")
+        .args(["no_app", "bootloader_only", "partition_table_only"])
+        .conflicts_with_all(["bins", "ram"])
+))]
 pub struct FlashArgs {
+    /// Grow the app partitions of the default partition table up to the
+    /// end of the flash if the application does not fit in it
+    #[arg(long, conflicts_with = "partition_table")]
+    pub auto_grow_partition: bool,
+    /// Additional application to flash, as 'FILE@LABEL'
+    ///
+    /// The application is written to the app partition with the given label,
+    /// and can be repeated to flash several applications at once, eg.) a
+    /// factory recovery application alongside the main one.
+    #[arg(
+        long = "bin",
+        value_name = "FILE@LABEL",
+        value_parser = AppPartition::from_str,
+        conflicts_with = "ram"
+    )]
+    pub bins: Vec<AppPartition>,
     /// Path to a binary (.bin) bootloader file
     #[arg(long, value_name = "FILE")]
     pub bootloader: Option<PathBuf>,
+    /// Only write the bootloader, leaving the rest of the flash untouched
+    #[arg(long)]
+    pub bootloader_only: bool,
+    /// Layout of the partition table generated when a custom one is not
+    /// provided
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        value_enum,
+        conflicts_with = "partition_table"
+    )]
+    pub default_partition_layout: Option<PartitionLayout>,
+    /// Encrypt the given partitions as they are written, for devices with
+    /// flash encryption enabled
+    ///
+    /// Along with partition labels, 'bootloader' and 'partition-table' select
+    /// the bootloader and the partition table. Everything else is written as
+    /// plaintext.
+    #[arg(
+        long,
+        value_name = "LABELS",
+        value_delimiter = ',',
+        conflicts_with = "ram"
+    )]
+    pub encrypt_partitions: Vec<String>,
     /// Erase partitions by label
     #[arg(
         long,
@@ -97,21 +338,126 @@ pub struct FlashArgs {
         value_delimiter = ','
     )]
     pub erase_data_parts: Option<Vec<DataType>>,
+    /// Write to a secondary flash chip connected to the SPI pins 'CLK,Q,D,HD,CS'
+    ///
+    /// The contents of the external flash are verified after writing.
+    #[arg(long, value_name = "PINS", value_parser = SpiAttachParams::from_str, conflicts_with = "ram")]
+    pub external_flash: Option<SpiAttachParams>,
     /// Image format to flash
-    #[arg(long, value_enum)]
+    #[arg(long, value_name = "FORMAT", value_parser = ImageFormatKind::from_str)]
     pub format: Option<ImageFormatKind>,
+    /// Flash the image even if it requires a newer chip revision
+    #[arg(long)]
+    pub ignore_chip_rev: bool,
+    /// Version of the image format to use for the ESP8266
+    #[arg(long, value_name = "VERSION", value_enum, conflicts_with = "ram")]
+    pub image_version: Option<ImageVersion>,
+    /// Copy the contents of NVS partitions which are moved or resized by the
+    /// new partition table to their new offsets. Requires the flasher stub.
+    #[arg(long, conflicts_with = "ram")]
+    pub migrate_nvs: bool,
+    /// Prevent the application from booting on chips older than this
+    /// revision, given as 'MAJOR.MINOR'
+    #[arg(long, value_name = "REV", value_parser = parse_chip_revision, conflicts_with = "ram")]
+    pub min_chip_rev: Option<(u32, u32)>,
+    /// Anti-rollback secure version to write into the application
+    /// description; the application is not booted by devices whose secure
+    /// version eFuse is higher
+    #[arg(long, value_name = "N", conflicts_with = "ram")]
+    pub secure_version: Option<u32>,
     /// Open a serial monitor after flashing
     #[arg(short = 'M', long)]
     pub monitor: bool,
     /// Baud rate at which to read console output
     #[arg(long, requires = "monitor", value_name = "BAUD")]
     pub monitor_baud: Option<u32>,
+    /// Write the bootloader and partition table, but not the application
+    #[arg(long)]
+    pub no_app: bool,
+    /// Don't append a SHA-256 digest to the application image, as required by
+    /// some bootloaders
+    #[arg(long, conflicts_with = "ram")]
+    pub no_hash_appended: bool,
     /// Path to a CSV file containing partition table
     #[arg(long, value_name = "FILE")]
     pub partition_table: Option<PathBuf>,
+    /// Only write the partition table, leaving the rest of the flash untouched
+    #[arg(long)]
+    pub partition_table_only: bool,
     /// Load the application to RAM instead of Flash
     #[arg(long)]
     pub ram: bool,
+    /// Format of the summary of sizes and timings displayed after flashing
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = SummaryFormat::Table)]
+    pub summary: SummaryFormat,
+    /// Reflash the device whenever the application changes, writing only the
+    /// blocks which differ from flash, and restart the monitor if it is open
+    #[arg(long)]
+    pub watch: bool,
+    #[clap(flatten)]
+    pub monitor_options: MonitorOptions,
+}
+
+impl FlashArgs {
+    /// Which parts of the image to write to flash
+    pub fn flash_parts(&self) -> FlashParts {
+        if self.no_app {
+            FlashParts::NoApp
+        } else if self.bootloader_only {
+            FlashParts::BootloaderOnly
+        } else if self.partition_table_only {
+            FlashParts::PartitionTableOnly
+        } else {
+            FlashParts::All
+        }
+    }
+
+    /// Inputs for building the image which are set by these arguments; the
+    /// bootloader, partition table and flash settings are provided separately
+    pub fn flash_data(&self) -> FlashDataBuilder {
+        FlashData::builder()
+            .auto_grow_partition(self.auto_grow_partition)
+            .partition_layout(self.default_partition_layout)
+            .flash_parts(self.flash_parts())
+            .min_chip_rev(self.min_chip_rev)
+            .secure_version(self.secure_version)
+            .no_hash_appended(self.no_hash_appended)
+            .image_version(self.image_version)
+    }
+}
+
+/// Format of the summary displayed after flashing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    /// A table of the sizes and timings of each segment
+    Table,
+    /// The same information as the table, as JSON
+    Json,
+    /// Do not display a summary
+    None,
+}
+
+/// An application to write to a specific app partition
+#[derive(Debug, Clone)]
+pub struct AppPartition {
+    /// ELF image or pre-built application binary (.bin)
+    pub path: PathBuf,
+    /// Label of the app partition to write the application to
+    pub label: String,
+}
+
+impl FromStr for AppPartition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('@') {
+            Some((path, label)) if !path.is_empty() && !label.is_empty() => Ok(Self {
+                path: PathBuf::from(path),
+                label: label.to_string(),
+            }),
+            _ => Err(Error::InvalidAppPartition(s.to_string())),
+        }
+    }
 }
 
 /// Operations for partitions tables
@@ -134,38 +480,319 @@ pub struct PartitionTableArgs {
 /// Save the image to disk instead of flashing to device
 #[derive(Debug, Args)]
 #[group(skip)]
+#[command(group(ArgGroup::new("merged").args(["merge", "qemu"]).multiple(true)))]
+#[command(group(ArgGroup::new("all_parts").args(["merge", "qemu", "split"]).multiple(true)))]
 pub struct SaveImageArgs {
+    /// Grow the app partitions of the default partition table up to the
+    /// end of the flash if the application does not fit in it
+    #[arg(long, conflicts_with = "partition_table")]
+    pub auto_grow_partition: bool,
     /// Custom bootloader for merging
     #[arg(long, value_name = "FILE")]
     pub bootloader: Option<PathBuf>,
     /// Chip to create an image for
     #[arg(long, value_enum)]
     pub chip: Chip,
-    /// File name to save the generated image to
+    /// Layout of the partition table generated when a custom one is not
+    /// provided
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        value_enum,
+        conflicts_with = "partition_table"
+    )]
+    pub default_partition_layout: Option<PartitionLayout>,
+    /// File name to save the generated image to, or '-' to write it to stdout
     pub file: PathBuf,
+    /// Version of the image format to use for the ESP8266
+    #[arg(long, value_name = "VERSION", value_enum)]
+    pub image_version: Option<ImageVersion>,
     /// Boolean flag to merge binaries into single binary
     #[arg(long)]
     pub merge: bool,
+    /// Prevent the application from booting on chips older than this
+    /// revision, given as 'MAJOR.MINOR'
+    #[arg(long, value_name = "REV", value_parser = parse_chip_revision)]
+    pub min_chip_rev: Option<(u32, u32)>,
+    /// Anti-rollback secure version to write into the application
+    /// description
+    #[arg(long, value_name = "N")]
+    pub secure_version: Option<u32>,
+    /// Don't append a SHA-256 digest to the application image, as required by
+    /// some bootloaders
+    #[arg(long)]
+    pub no_hash_appended: bool,
     /// Custom partition table for merging
-    #[arg(long, short = 'T', requires = "merge", value_name = "FILE")]
+    #[arg(long, short = 'T', requires = "all_parts", value_name = "FILE")]
     pub partition_table: Option<PathBuf>,
+    /// Merge binaries into a single image padded to the flash size, suitable
+    /// for use as QEMU's flash drive
+    #[arg(long, conflicts_with = "skip_padding")]
+    pub qemu: bool,
+    /// Save each part of the image to its own file in the directory FILE,
+    /// along with `flash_args` and `flasher_args.json` files for use with
+    /// esptool.py and ESP-IDF tooling
+    #[arg(long, conflicts_with = "merged")]
+    pub split: bool,
     /// Don't pad the image to the flash size
     #[arg(long, short = 'P', requires = "merge")]
     pub skip_padding: bool,
+    /// Pad the merged image to this size, rather than the flash size
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_enum,
+        requires = "merged",
+        conflicts_with = "skip_padding"
+    )]
+    pub pad_to_size: Option<FlashSize>,
+    /// Byte used to fill the gaps between segments and pad the merged image
+    #[arg(long, value_name = "BYTE", value_parser = parse_fill_byte, default_value = "0xFF", requires = "merged")]
+    pub fill: u8,
+    /// Zero the build time and date embedded in the application, so that
+    /// rebuilding the same sources produces an identical image
+    #[arg(long)]
+    pub reproducible: bool,
+}
+
+impl SaveImageArgs {
+    /// Should the binaries be merged into a single image?
+    pub fn merge(&self) -> bool {
+        self.merge || self.qemu
+    }
+
+    /// Where to display information about the image; stderr if the image
+    /// itself is written to stdout, so as not to corrupt it
+    pub fn info_output(&self) -> Box<dyn Write> {
+        info_output(&self.file)
+    }
+
+    /// Inputs for building the image which are set by these arguments; the
+    /// bootloader, partition table and flash settings are provided separately
+    pub fn flash_data(&self) -> FlashDataBuilder {
+        FlashData::builder()
+            .reproducible(self.reproducible)
+            .auto_grow_partition(self.auto_grow_partition)
+            .partition_layout(self.default_partition_layout)
+            .min_chip_rev(self.min_chip_rev)
+            .secure_version(self.secure_version)
+            .no_hash_appended(self.no_hash_appended)
+            .image_version(self.image_version)
+    }
+}
+
+/// Parse a chip revision given as 'MAJOR.MINOR', optionally prefixed with 'v'
+pub fn parse_chip_revision(s: &str) -> Result<(u32, u32), Error> {
+    let invalid = || Error::InvalidChipRevision(s.to_string());
+
+    let (major, minor) = s
+        .trim_start_matches('v')
+        .split_once('.')
+        .ok_or_else(invalid)?;
+    let major = major.parse::<u32>().map_err(|_| invalid())?;
+    let minor = minor.parse::<u32>().map_err(|_| invalid())?;
+
+    // The revision is stored in the image header as `major * 100 + minor`
+    if minor > 99 || major > 255 {
+        return Err(invalid());
+    }
+
+    Ok((major, minor))
+}
+
+/// Parse a byte given in decimal, or in hexadecimal with a '0x' prefix
+fn parse_fill_byte(s: &str) -> Result<u8, ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Ensure the flash size is one which QEMU is able to emulate
+pub fn check_qemu_flash_size(flash_size: Option<FlashSize>) -> Result<(), Error> {
+    let flash_size = flash_size.unwrap_or_default();
+    match flash_size {
+        FlashSize::_2Mb | FlashSize::_4Mb | FlashSize::_8Mb | FlashSize::_16Mb => Ok(()),
+        _ => Err(Error::UnsupportedQemuFlashSize(flash_size)),
+    }
+}
+
+/// Control the verbosity of the log output
+#[derive(Debug, Args)]
+pub struct VerbosityArgs {
+    /// Increase the verbosity of the log output; may be repeated, eg. `-vv`
+    #[arg(short = 'v', long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Only log warnings and errors
+    #[arg(short = 'q', long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+impl VerbosityArgs {
+    /// The most verbose level of log output which should be displayed
+    pub fn level_filter(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::WARN,
+            (false, 0) => LevelFilter::INFO,
+            (false, 1) => LevelFilter::DEBUG,
+            (false, _) => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Read or burn the anti-rollback secure version eFuse
+#[derive(Debug, Args)]
+pub struct SecureVersionArgs {
+    /// Burn the eFuses to raise the secure version to VERSION, which is
+    /// irreversible
+    #[arg(long, value_name = "VERSION")]
+    burn: Option<u32>,
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
 }
 
 /// Open the serial monitor without flashing
 #[derive(Debug, Args)]
 pub struct MonitorArgs {
-    /// Optional file name of the ELF image to load the symbols from
+    /// ELF images to load symbols from, eg. the application and the
+    /// second-stage bootloader; may be given more than once
     #[arg(short = 'e', long, value_name = "FILE")]
-    elf: Option<PathBuf>,
+    elf: Vec<PathBuf>,
     #[clap(flatten)]
     connect_args: ConnectArgs,
+    #[clap(flatten)]
+    monitor_options: MonitorOptions,
 }
 
 /// Select a serial port and establish a connection with a target device
+///
+/// Ports of the form `tcp://host:port` or `rfc2217://host:port` connect to a
+/// remote serial server rather than a local serial port.
 pub fn connect(args: &ConnectArgs, config: &Config) -> Result<Flasher> {
+    let (interface, port_info) = open_reset_interface(args, config)?;
+    let reset_sequence = reset_sequence(args, config)?;
+
+    let mut retry_policy = RetryPolicy::default();
+    if let Some(attempts) = args.connect_attempts {
+        retry_policy = retry_policy.connect_attempts(attempts as usize);
+    }
+    if let Some(timeout) = args.timeout {
+        retry_policy = retry_policy.timeout(Duration::from_secs(timeout));
+    }
+
+    let trace: Option<Box<dyn Write + Send>> = match &args.trace {
+        Some(Some(path)) => Some(Box::new(
+            fs::File::create(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create trace file {}", path.display()))?,
+        )),
+        Some(None) => Some(Box::new(std::io::stderr())),
+        None => None,
+    };
+
+    let options = ConnectOptions::default()
+        .speed(args.baud)
+        .use_stub(!args.no_stub)
+        .reset_sequence(reset_sequence)
+        .retry_policy(retry_policy)
+        .trace(trace)
+        .spi_params(args.spi_connection)
+        .capture_rom_log(args.capture_rom_log);
+
+    Ok(Flasher::connect(interface, port_info, options)?)
+}
+
+/// Reset the target device, optionally into its bootloader where it is left,
+/// without connecting to it
+pub fn reset_device(args: &ConnectArgs, config: &Config, bootloader: bool) -> Result<()> {
+    let (interface, port_info) = open_reset_interface(args, config)?;
+    let mut connection = Connection::new(interface, port_info);
+    connection.set_reset_sequence(reset_sequence(args, config)?);
+
+    if bootloader {
+        connection.reset_to_bootloader()?;
+        info!("Device is held in its bootloader");
+    } else {
+        connection.reset()?;
+        info!("Device has been reset");
+    }
+
+    Ok(())
+}
+
+/// Open the selected port, along with any GPIOs wired to the target device's
+/// strapping pins
+fn open_reset_interface(args: &ConnectArgs, config: &Config) -> Result<(Interface, UsbPortInfo)> {
+    #[allow(unused_mut)]
+    let (mut interface, port_info) = open_interface(args, config)?;
+
+    #[cfg(feature = "gpio")]
+    if let (Some(en), Some(boot)) = (
+        args.en_gpio.or(config.connection.en_gpio),
+        args.boot_gpio.or(config.connection.boot_gpio),
+    ) {
+        let chip = args
+            .gpio_chip
+            .clone()
+            .or_else(|| config.connection.gpio_chip.clone())
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_GPIO_CHIP));
+
+        interface.set_strapping_pins(StrappingPins::new(&chip, en, boot).map_err(Error::from)?);
+    }
+
+    Ok((interface, port_info))
+}
+
+/// The sequence used to reset the target device into its bootloader
+fn reset_sequence(args: &ConnectArgs, config: &Config) -> Result<ResetSequence> {
+    Ok(
+        match (&args.reset_sequence, &config.connection.reset_sequence) {
+            (Some(reset_sequence), _) => reset_sequence.clone(),
+            (None, Some(reset_sequence)) => reset_sequence.parse()?,
+            (None, None) => ResetSequence::default(),
+        },
+    )
+}
+
+/// Connect to the serial updater provided by the application running on a
+/// target device, without resetting it
+pub fn connect_updater(args: &ConnectArgs, config: &Config) -> Result<Updater> {
+    let (mut interface, port_info) = open_interface(args, config)?;
+    if let Some(baud) = args.baud {
+        interface
+            .serial_port_mut()
+            .set_baud_rate(baud)
+            .into_diagnostic()?;
+    }
+
+    Ok(Updater::connect(interface, port_info)?)
+}
+
+/// Open the selected remote or local serial port
+fn open_interface(args: &ConnectArgs, config: &Config) -> Result<(Interface, UsbPortInfo)> {
+    let remote = args
+        .port
+        .as_deref()
+        .or(config.connection.serial.as_deref())
+        .and_then(TcpProtocol::parse_port);
+
+    match remote {
+        Some((protocol, addr)) => {
+            info!("Remote port: '{}'", addr);
+            info!("Connecting...");
+
+            let transport = TcpTransport::connect(addr, protocol)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to connect to remote port {addr}"))?;
+
+            Ok((Interface::from_transport(transport), UNKNOWN_PORT_INFO))
+        }
+        None => open_serial_port(args, config),
+    }
+}
+
+/// Open the selected local serial port
+fn open_serial_port(args: &ConnectArgs, config: &Config) -> Result<(Interface, UsbPortInfo)> {
     let port_info = get_serial_port_info(args, config)?;
 
     // Attempt to open the serial port and set its initial baud rate.
@@ -180,8 +807,7 @@ pub fn connect(args: &ConnectArgs, config: &Config) -> Result<Flasher> {
     #[cfg(not(feature = "raspberry"))]
     let (dtr, rts) = (None, None);
 
-    let interface = Interface::new(&port_info, dtr, rts)
-        .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name))?;
+    let interface = Interface::new(&port_info, dtr, rts, args.exclusive)?;
 
     // NOTE: since `get_serial_port_info` filters out all PCI Port and Bluetooth
     //       serial ports, we can just pretend these types don't exist here.
@@ -189,23 +815,12 @@ pub fn connect(args: &ConnectArgs, config: &Config) -> Result<Flasher> {
         SerialPortType::UsbPort(info) => info,
         SerialPortType::PciPort | SerialPortType::Unknown => {
             debug!("Matched `SerialPortType::PciPort or ::Unknown`");
-            UsbPortInfo {
-                vid: 0,
-                pid: 0,
-                serial_number: None,
-                manufacturer: None,
-                product: None,
-            }
+            UNKNOWN_PORT_INFO
         }
         _ => unreachable!(),
     };
 
-    Ok(Flasher::connect(
-        interface,
-        port_info,
-        args.baud,
-        !args.no_stub,
-    )?)
+    Ok((interface, port_info))
 }
 
 /// Connect to a target device and print information about its chip
@@ -218,6 +833,14 @@ pub fn board_info(args: &ConnectArgs, config: &Config) -> Result<()> {
 
 /// Print information about a chip
 pub fn print_board_info(flasher: &mut Flasher) -> Result<()> {
+    if flasher.secure_download_mode() {
+        println!("Chip type:         {}", flasher.chip());
+        println!("Flash size:        {} (assumed)", flasher.flash_size());
+        println!("Secure download mode is enabled, no further information is available");
+
+        return Ok(());
+    }
+
     let info = flasher.device_info()?;
 
     print!("Chip type:         {}", info.chip);
@@ -229,158 +852,265 @@ pub fn print_board_info(flasher: &mut Flasher) -> Result<()> {
     println!("Crystal frequency: {}MHz", info.crystal_frequency);
     println!("Flash size:        {}", info.flash_size);
     println!("Features:          {}", info.features.join(", "));
+    if !info.embedded_memory.is_empty() {
+        println!("Memory:            {}", info.embedded_memory);
+    }
     println!("MAC address:       {}", info.mac_address);
 
     Ok(())
 }
 
-/// Open a serial monitor
-pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
+/// Connect to a target device and print its security-related state
+pub fn security_info(args: &ConnectArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(args, config)?;
+    let info = flasher.security_info()?;
+
+    let enabled = |enabled: bool| if enabled { "Enabled" } else { "Disabled" };
+
+    println!("Chip type:           {}", flasher.chip());
+    println!("Flags:               {:#010x}", info.flags);
+    println!("Flash crypt count:   {:#04x}", info.flash_crypt_cnt);
+    println!(
+        "Key purposes:        {}",
+        info.key_purposes
+            .iter()
+            .map(|purpose| purpose.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if let Some(chip_id) = info.chip_id {
+        println!("Chip ID:             {chip_id}");
+    }
+    if let Some(api_version) = info.api_version {
+        println!("API version:         {api_version}");
+    }
+    println!();
+    println!(
+        "Secure boot:         {}",
+        enabled(info.secure_boot_enabled())
+    );
+    if info.secure_boot_enabled() {
+        println!(
+            "  Aggressive revoke: {}",
+            enabled(info.secure_boot_aggressive_revoke())
+        );
+        let revoked = info.revoked_secure_boot_keys();
+        if !revoked.is_empty() {
+            println!("  Revoked keys:      {revoked:?}");
+        }
+    }
+    println!(
+        "Flash encryption:    {}",
+        enabled(info.flash_encryption_enabled())
+    );
+    println!(
+        "Secure download:     {}",
+        enabled(info.secure_download_enabled())
+    );
+    println!(
+        "JTAG:                {}",
+        if info.jtag_hard_disabled() {
+            "Permanently disabled"
+        } else if info.jtag_soft_disabled() {
+            "Disabled"
+        } else {
+            "Enabled"
+        }
+    );
+    println!("USB:                 {}", enabled(!info.usb_disabled()));
+    println!(
+        "Download D-cache:    {}",
+        enabled(!info.download_dcache_disabled())
+    );
+    println!(
+        "Download I-cache:    {}",
+        enabled(!info.download_icache_disabled())
+    );
+
+    Ok(())
+}
+
+/// Connect to a target device and print its anti-rollback secure version,
+/// optionally burning the eFuses to raise it
+pub fn secure_version(args: &SecureVersionArgs, config: &Config) -> Result<()> {
     let mut flasher = connect(&args.connect_args, config)?;
-    let pid = flasher.get_usb_pid()?;
+    let current = flasher.secure_version()?;
+    println!("Secure version: {current}");
+
+    if let Some(version) = args.burn.filter(|&version| version != current) {
+        prompt::confirm(&format!(
+            "Burn the eFuses to raise the secure version to {version}? This can not be undone, \
+             and applications with a lower secure version will no longer boot."
+        ))?;
+        flasher.burn_secure_version(version)?;
+        println!("Secure version: {version}");
+    }
 
-    let elf = if let Some(elf_path) = args.elf {
-        let path = fs::canonicalize(elf_path).into_diagnostic()?;
-        let data = fs::read(path).into_diagnostic()?;
+    Ok(())
+}
 
-        Some(data)
-    } else {
-        None
-    };
+/// Open a serial monitor
+///
+/// Returns the exit code requested by a monitor hook, if any.
+pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<Option<i32>> {
+    let flasher = connect(&args.connect_args, config)?;
+    let pid = flasher.get_usb_pid()?;
+
+    let elfs = args
+        .elf
+        .iter()
+        .map(|path| {
+            fs::read(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     let chip = flasher.chip();
-    let target = chip.into_target();
 
     // The 26MHz ESP32-C2's need to be treated as a special case.
-    let default_baud = if chip == Chip::Esp32c2
-        && args.connect_args.no_stub
-        && target.crystal_freq(flasher.connection())? == 26
-    {
-        74_880
-    } else {
-        115_200
-    };
+    let default_baud =
+        if chip == Chip::Esp32c2 && args.connect_args.no_stub && flasher.crystal_freq() == 26 {
+            74_880
+        } else {
+            115_200
+        };
 
     monitor(
         flasher.into_interface(),
-        elf.as_deref(),
+        chip,
+        &elfs.iter().map(Vec::as_slice).collect::<Vec<_>>(),
         pid,
         args.connect_args.baud.unwrap_or(default_baud),
+        &args.monitor_options,
+        &config.monitor.hooks(),
     )
-    .into_diagnostic()?;
+    .into_diagnostic()
+}
 
-    Ok(())
+fn info_output(image_path: &Path) -> Box<dyn Write> {
+    if image_path == Path::new("-") {
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    }
 }
 
 /// Convert the provided firmware image from ELF to binary
+///
+/// Pre-built application binaries are also accepted, in which case they are
+/// used as-is and only merged with the bootloader and partition table. If
+/// `image_path` is `-` the image is written to stdout.
 pub fn save_elf_as_image(
     chip: Chip,
     elf_data: &[u8],
     image_path: PathBuf,
     image_format: Option<ImageFormatKind>,
-    flash_mode: Option<FlashMode>,
-    flash_size: Option<FlashSize>,
-    flash_freq: Option<FlashFrequency>,
+    mut flash_data: FlashData,
     merge: bool,
-    bootloader_path: Option<PathBuf>,
-    partition_table_path: Option<PathBuf>,
+    split: bool,
     skip_padding: bool,
+    pad_to_size: Option<FlashSize>,
+    fill: u8,
 ) -> Result<()> {
-    let image = ElfFirmwareImage::try_from(elf_data)?;
+    let to_stdout = image_path == Path::new("-");
+    let mut info_output = info_output(&image_path);
 
-    if merge {
-        // merge_bin is TRUE
-        // merge bootloader, partition table and app binaries
-        // basic functionality, only merge 3 binaries
+    let elf_image = if is_app_image(elf_data) {
+        None
+    } else {
+        Some(ElfFirmwareImage::try_from(elf_data)?)
+    };
 
-        // If the '-B' option is provided, load the bootloader binary file at the
-        // specified path.
-        let bootloader = if let Some(bootloader_path) = bootloader_path {
-            let path = fs::canonicalize(bootloader_path).into_diagnostic()?;
-            let data = fs::read(path).into_diagnostic()?;
+    if split && to_stdout {
+        bail!("The parts of a split image can not be written to stdout");
+    }
 
-            Some(data)
-        } else {
-            None
-        };
+    // The bootloader and partition table are only included when saving every
+    // part of the image, either merged or split.
+    if !(merge || split) {
+        flash_data.bootloader = None;
+        flash_data.partition_table = None;
+    }
+    let (flash_mode, flash_size, flash_freq) = (
+        flash_data.flash_mode,
+        flash_data.flash_size,
+        flash_data.flash_freq,
+    );
 
-        // If the '-T' option is provided, load the partition table from
-        // the CSV or binary file at the specified path.
-        let partition_table = if let Some(partition_table_path) = partition_table_path {
-            let path = fs::canonicalize(partition_table_path).into_diagnostic()?;
-            let data = fs::read(path)
-                .into_diagnostic()
-                .wrap_err("Failed to open partition table")?;
+    // To get a chip revision, the connection is needed
+    // For simplicity, the revision None is used
+    let image = match &elf_image {
+        Some(elf_image) => {
+            chip.into_target()
+                .get_flash_image(elf_image, flash_data, image_format, None)?
+        }
+        None => chip
+            .into_target()
+            .get_flash_image_from_bin(elf_data, flash_data)?,
+    };
 
-            let table = PartitionTable::try_from(data).into_diagnostic()?;
+    writeln!(
+        info_output,
+        "{}",
+        image_size_summary(image.app_size(), image.part_size())
+    )
+    .into_diagnostic()?;
 
-            Some(table)
-        } else {
+    if merge {
+        // Pad to the flash size, using the default of 4MB if it was not provided
+        let pad_to = if skip_padding {
             None
+        } else {
+            Some(pad_to_size.or(flash_size).unwrap_or_default().size())
         };
 
-        // To get a chip revision, the connection is needed
-        // For simplicity, the revision None is used
-        let image = chip.into_target().get_flash_image(
-            &image,
-            bootloader,
-            partition_table,
-            image_format,
-            None,
-            flash_mode,
-            flash_size,
-            flash_freq,
+        let mut writer = image_writer(&image_path)?;
+        write_merged(
+            &mut writer,
+            image.flash_segments(),
+            flash_size.unwrap_or_default(),
+            fill,
+            pad_to,
+        )?;
+        writer.flush().into_diagnostic()?;
+    } else if split {
+        let segments = image.flash_segments().collect::<Result<Vec<_>, _>>()?;
+        check_flash_bounds(
+            segments
+                .iter()
+                .map(|segment| (segment.addr, segment.data.len() as u32)),
+            flash_size.unwrap_or_default(),
         )?;
 
-        display_image_size(image.app_size(), image.part_size());
-
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(image_path)
-            .into_diagnostic()?;
-
-        for segment in image.flash_segments() {
-            let padding_bytes = vec![
-                0xffu8;
-                segment.addr as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
-            file.write_all(&segment.data).into_diagnostic()?;
-        }
-
-        if !skip_padding {
-            // Take flash_size as input parameter, if None, use default value of 4Mb
-            let padding_bytes = vec![
-                0xffu8;
-                flash_size.unwrap_or_default().size() as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
-        }
-    } else {
-        let image = chip.into_target().get_flash_image(
-            &image,
-            None,
-            None,
-            image_format,
-            None,
+        save_split_image(
+            &image_path,
+            chip,
+            image.as_ref(),
             flash_mode,
             flash_size,
             flash_freq,
         )?;
-
-        display_image_size(image.app_size(), image.part_size());
-
-        let parts = image.ota_segments().collect::<Vec<_>>();
+    } else if image.part_size().is_some() {
+        // Images for an application partition are a single part, which can be
+        // written as it is generated
+        let mut writer = image_writer(&image_path)?;
+        image.write_to(&mut writer)?;
+        writer.flush().into_diagnostic()?;
+    } else {
+        let parts = image.ota_segments().collect::<Result<Vec<_>, _>>()?;
         match parts.as_slice() {
-            [single] => fs::write(&image_path, &single.data).into_diagnostic()?,
+            [single] => write_image(&image_path, &single.data)?,
+            _ if to_stdout => {
+                bail!("The image consists of several parts, which can not be written to stdout")
+            }
             parts => {
+                // Each part is saved alongside the image path, named after its offset
+                let file_name = image_path.file_name().unwrap_or_default().to_string_lossy();
                 for part in parts {
-                    let part_path = format!("{:#x}_{}", part.addr, image_path.display());
-                    fs::write(part_path, &part.data).into_diagnostic()?
+                    let part_path =
+                        image_path.with_file_name(format!("{:#x}_{file_name}", part.addr));
+                    write_image(&part_path, &part.data)?;
                 }
             }
         }
@@ -389,20 +1119,222 @@ pub fn save_elf_as_image(
     Ok(())
 }
 
-pub(crate) fn display_image_size(app_size: u32, part_size: Option<u32>) {
+/// Merge segments into a single image written to `writer`, filling any gaps
+/// between them with `fill`, and padding the image with it up to `pad_to`
+/// bytes if provided
+///
+/// Segments are written as they are generated, so only one is held in memory
+/// at a time. They must be in order of address, and must not overlap.
+fn write_merged<'a>(
+    writer: &mut dyn Write,
+    segments: impl Iterator<Item = Result<RomSegment<'a>, Error>>,
+    flash_size: FlashSize,
+    fill: u8,
+    pad_to: Option<u32>,
+) -> Result<()> {
+    let mut len = 0;
+    for segment in segments {
+        let segment = segment?;
+        check_flash_bounds([(segment.addr, segment.data.len() as u32)], flash_size)?;
+        if (segment.addr as u64) < len {
+            bail!(
+                "The segment at {:#x} begins before the end of the previous one, at {:#x}, so the \
+                 segments can not be merged",
+                segment.addr,
+                len
+            );
+        }
+
+        let end = segment.addr as u64 + segment.data.len() as u64;
+        match pad_to {
+            Some(pad_to) if end > pad_to as u64 => bail!(
+                "The merged image is at least {} bytes, which does not fit when padded to {} bytes",
+                end,
+                pad_to
+            ),
+            _ => {}
+        }
+
+        write_fill(writer, segment.addr as u64 - len, fill)?;
+        writer.write_all(&segment.data).into_diagnostic()?;
+        len = end;
+    }
+
+    if let Some(pad_to) = pad_to {
+        write_fill(writer, pad_to as u64 - len, fill)?;
+    }
+
+    Ok(())
+}
+
+/// Write `len` bytes of `fill` to `writer`
+fn write_fill(writer: &mut dyn Write, len: u64, fill: u8) -> Result<()> {
+    io::copy(&mut io::repeat(fill).take(len), writer).into_diagnostic()?;
+
+    Ok(())
+}
+
+/// Open a buffered writer for an image at `path`, or for stdout if `path` is
+/// `-`
+fn image_writer(path: &Path) -> Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(stdout().lock()))
+    } else {
+        let file = fs::File::create(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write image {}", path.display()))?;
+
+        Ok(Box::new(BufWriter::new(file)))
+    }
+}
+
+/// Write an image to `path`, or to stdout if `path` is `-`
+fn write_image(path: &Path, data: &[u8]) -> Result<()> {
+    let mut writer = image_writer(path)?;
+    writer.write_all(data).into_diagnostic()?;
+    writer.flush().into_diagnostic()
+}
+
+/// Display the size of an application image, and how full its partition is
+pub fn display_image_size(app_size: u32, part_size: Option<u32>) {
+    println!("{}", image_size_summary(app_size, part_size));
+}
+
+fn image_size_summary(app_size: u32, part_size: Option<u32>) -> String {
     if let Some(part_size) = part_size {
         let percent = app_size as f32 / part_size as f32 * 100.0;
-        println!(
+        format!(
             "App/part. size:    {}/{} bytes, {:.2}%",
             HumanCount(app_size as u64),
             HumanCount(part_size as u64),
             percent
-        );
+        )
     } else {
-        println!("App size:          {} bytes", HumanCount(app_size as u64));
+        format!("App size:          {} bytes", HumanCount(app_size as u64))
     }
 }
 
+/// Display the sizes and timings from writing an image to flash, to help with
+/// tuning the baud rate
+pub fn print_flash_summary(summary: &FlashSummary, format: SummaryFormat) -> Result<()> {
+    if summary.segments.is_empty() {
+        return Ok(());
+    }
+
+    match format {
+        SummaryFormat::Table => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+                .set_header(
+                    [
+                        "Address",
+                        "Size",
+                        "Compressed",
+                        "Erase",
+                        "Write",
+                        "Throughput",
+                    ]
+                    .map(|header| Cell::new(header).add_attribute(Attribute::Bold)),
+                );
+
+            let row = |addr: String,
+                       size: usize,
+                       compressed_size: Option<usize>,
+                       erase_time: Duration,
+                       write_time: Duration,
+                       throughput: f64| {
+                vec![
+                    addr,
+                    format!("{} bytes", HumanCount(size as u64)),
+                    compressed_size.map_or_else(
+                        || "-".to_string(),
+                        |compressed_size| {
+                            format!(
+                                "{} bytes ({:.1}x)",
+                                HumanCount(compressed_size as u64),
+                                size as f64 / compressed_size as f64
+                            )
+                        },
+                    ),
+                    format!("{erase_time:.2?}"),
+                    format!("{write_time:.2?}"),
+                    format!("{}/s", HumanBytes(throughput as u64)),
+                ]
+            };
+
+            for segment in &summary.segments {
+                table.add_row(row(
+                    format!("{:#010x}", segment.addr),
+                    segment.size,
+                    segment.compressed_size,
+                    segment.erase_time,
+                    segment.write_time,
+                    segment.throughput(),
+                ));
+            }
+            table.add_row(row(
+                "Total".to_string(),
+                summary.size(),
+                summary.compressed_size(),
+                summary.erase_time(),
+                summary.write_time(),
+                summary.throughput(),
+            ));
+
+            println!("{table}");
+        }
+        SummaryFormat::Json => {
+            let entry = |size: usize,
+                         compressed_size: Option<usize>,
+                         erase_time: Duration,
+                         write_time: Duration,
+                         throughput: f64| {
+                serde_json::json!({
+                    "size": size,
+                    "compressed_size": compressed_size,
+                    "compression_ratio": compressed_size
+                        .map(|compressed_size| size as f64 / compressed_size as f64),
+                    "erase_ms": erase_time.as_secs_f64() * 1000.0,
+                    "write_ms": write_time.as_secs_f64() * 1000.0,
+                    "bytes_per_sec": throughput,
+                })
+            };
+
+            let segments = summary
+                .segments
+                .iter()
+                .map(|segment| {
+                    let mut json = entry(
+                        segment.size,
+                        segment.compressed_size,
+                        segment.erase_time,
+                        segment.write_time,
+                        segment.throughput(),
+                    );
+                    json["addr"] = segment.addr.into();
+                    json
+                })
+                .collect::<Vec<_>>();
+
+            let mut json = entry(
+                summary.size(),
+                summary.compressed_size(),
+                summary.erase_time(),
+                summary.write_time(),
+                summary.throughput(),
+            );
+            json["segments"] = segments.into();
+
+            println!("{}", serde_json::to_string_pretty(&json).into_diagnostic()?);
+        }
+        SummaryFormat::None => {}
+    }
+
+    Ok(())
+}
+
 /// Progress callback implementations for use in `cargo-espflash` and `espflash`
 #[derive(Default)]
 pub struct EspflashProgress {
@@ -440,34 +1372,45 @@ impl ProgressCallbacks for EspflashProgress {
 pub fn flash_elf_image(
     flasher: &mut Flasher,
     elf_data: &[u8],
-    bootloader: Option<&Path>,
-    partition_table: Option<PartitionTable>,
+    flash_data: FlashData,
     image_format: Option<ImageFormatKind>,
-    flash_mode: Option<FlashMode>,
-    flash_size: Option<FlashSize>,
-    flash_freq: Option<FlashFrequency>,
 ) -> Result<()> {
-    // If the '--bootloader' option is provided, load the binary file at the
-    // specified path.
-    let bootloader = if let Some(path) = bootloader {
-        let path = fs::canonicalize(path).into_diagnostic()?;
-        let data = fs::read(path).into_diagnostic()?;
-
-        Some(data)
-    } else {
-        None
-    };
-
     // Load the ELF data, optionally using the provider bootloader/partition
     // table/image format, to the device's flash memory.
     flasher.load_elf_to_flash_with_format(
         elf_data,
-        bootloader,
-        partition_table,
+        flash_data,
+        image_format,
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed!");
+
+    Ok(())
+}
+
+/// Write a pre-built application binary to a target device's flash
+pub fn flash_app_bin(flasher: &mut Flasher, app_data: &[u8], flash_data: FlashData) -> Result<()> {
+    flasher.load_app_bin_to_flash(app_data, flash_data, Some(&mut EspflashProgress::default()))?;
+    info!("Flashing has completed!");
+
+    Ok(())
+}
+
+/// Write several applications to a target device's flash, each to its own
+/// app partition
+///
+/// Applications without a partition label are written to the partition which
+/// a single application would be written to.
+pub fn flash_apps(
+    flasher: &mut Flasher,
+    apps: &[(&[u8], Option<&str>)],
+    flash_data: FlashData,
+    image_format: Option<ImageFormatKind>,
+) -> Result<()> {
+    flasher.load_apps_to_flash(
+        apps,
+        flash_data,
         image_format,
-        flash_mode,
-        flash_size,
-        flash_freq,
         Some(&mut EspflashProgress::default()),
     )?;
     info!("Flashing has completed!");
@@ -475,13 +1418,334 @@ pub fn flash_elf_image(
     Ok(())
 }
 
+/// Find the Rust build target an application was built for, from its path
+/// within Cargo's target directory, eg. `target/xtensa-esp32-none-elf/debug/app`
+///
+/// This allows the chip to be inferred when `espflash` is used as a Cargo
+/// runner.
+pub fn build_target(image: &Path) -> Option<&str> {
+    image
+        .parent()?
+        .ancestors()
+        .filter_map(|dir| dir.file_name()?.to_str())
+        .find(|name| Chip::iter().any(|chip| chip.into_target().supports_build_target(name)))
+}
+
+/// Infer the chip an application was built for from its build target, if only
+/// one chip supports it
+pub fn infer_chip(image: &Path) -> Option<Chip> {
+    build_target(image).and_then(Chip::from_build_target)
+}
+
+/// Check that an application's build target, if it can be found, is supported
+/// by the connected chip
+pub fn check_build_target(chip: Chip, image: &Path) -> Result<()> {
+    match build_target(image) {
+        Some(target) if !chip.into_target().supports_build_target(target) => {
+            Err(Error::UnsupportedBuildTarget {
+                target: target.to_string(),
+                chip,
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read an image from `path`, or from stdin if `path` is `-`
+///
+/// Either the image itself, or the output of `cargo build
+/// --message-format=json`, may be piped to stdin. In the latter case the last
+/// executable built is read.
+pub fn read_image(path: &Path) -> Result<Vec<u8>> {
+    if path != Path::new("-") {
+        return fs::read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open image {}", path.display()));
+    }
+
+    let mut data = Vec::new();
+    stdin()
+        .lock()
+        .read_to_end(&mut data)
+        .into_diagnostic()
+        .wrap_err("Failed to read the image from stdin")?;
+
+    if data.starts_with(b"\x7fELF") || is_app_image(&data) {
+        return Ok(data);
+    }
+
+    match cargo_executable(&data) {
+        Some(path) => {
+            info!("Reading image {}", path.display());
+            read_image(&path)
+        }
+        None => bail!(
+            "stdin does not contain an ELF file, application binary or the output of `cargo build --message-format=json`"
+        ),
+    }
+}
+
+/// Find the last executable built in the JSON messages output by Cargo
+fn cargo_executable(messages: &[u8]) -> Option<PathBuf> {
+    #[derive(Deserialize)]
+    struct Message {
+        reason: String,
+        executable: Option<PathBuf>,
+    }
+
+    String::from_utf8_lossy(messages)
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<Message>(line).ok())
+        .filter(|message| message.reason == "compiler-artifact")
+        .find_map(|message| message.executable)
+}
+
+/// Read the applications given using `--bin`
+pub fn read_app_partitions(bins: &[AppPartition]) -> Result<Vec<(Vec<u8>, &str)>> {
+    bins.iter()
+        .map(|bin| {
+            let data = fs::read(&bin.path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open image {}", bin.path.display()))?;
+
+            Ok((data, bin.label.as_str()))
+        })
+        .collect()
+}
+
+/// Validate the applications and display what would be written to flash,
+/// without connecting to a target device
+///
+/// The image is generated and validated just as when flashing, however the chip
+/// revision can not be checked.
+pub fn dry_run(
+    chip: Chip,
+    apps: &[(&[u8], Option<&str>)],
+    flash_data: FlashData,
+    image_format: Option<ImageFormatKind>,
+) -> Result<()> {
+    let segments = offline_segments(chip, apps, flash_data, image_format)?;
+
+    println!();
+    println!("{} segments would be written", segments.len());
+    for segment in &segments {
+        println!(
+            "  addr={:#010x} len={:#08x} md5={:x}",
+            segment.addr,
+            segment.data.len(),
+            md5::compute(&segment.data)
+        );
+    }
+
+    Ok(())
+}
+
+/// Flash the applications over JTAG, using OpenOCD
+///
+/// As when using `--dry-run`, the chip revision can not be checked, as no
+/// connection is made to the ROM bootloader.
+pub fn flash_jtag(
+    chip: Chip,
+    apps: &[(&[u8], Option<&str>)],
+    flash_data: FlashData,
+    image_format: Option<ImageFormatKind>,
+    args: &JtagArgs,
+) -> Result<()> {
+    let segments = offline_segments(chip, apps, flash_data, image_format)?;
+    write_jtag(chip, &segments, args)
+}
+
+/// Write the segments to flash over JTAG, using OpenOCD
+pub fn write_jtag(chip: Chip, segments: &[RomSegment], args: &JtagArgs) -> Result<()> {
+    let mut openocd = OpenOcd::new(chip)?;
+    if let Some(program) = &args.openocd {
+        openocd = openocd.program(program);
+    }
+    if let Some(interface) = &args.jtag_interface {
+        openocd = openocd.interface(interface);
+    }
+
+    info!("Flashing over JTAG using OpenOCD");
+    openocd.flash(segments)?;
+    info!("Flashing has completed!");
+
+    Ok(())
+}
+
+/// Build and validate the segments to write for the applications, without
+/// connecting to a target device
+fn offline_segments(
+    chip: Chip,
+    apps: &[(&[u8], Option<&str>)],
+    flash_data: FlashData,
+    image_format: Option<ImageFormatKind>,
+) -> Result<Vec<RomSegment<'static>>> {
+    let elfs = parse_apps(apps)?;
+    let images = app_images(chip, apps, &elfs, &flash_data, image_format, None)?;
+
+    for image in &images {
+        display_image_size(image.app_size(), image.part_size());
+
+        if let Some(segment) = image.bootloader_segment() {
+            check_image_chip("bootloader", &segment.data, chip)?;
+        }
+        for segment in image.ota_segments() {
+            check_image_chip("application", &segment?.data, chip)?;
+        }
+    }
+
+    let flash_size = flash_data.flash_size.unwrap_or_default();
+    let segments = app_segments(&images, flash_data.flash_parts, flash_size)?;
+
+    segments
+        .into_iter()
+        .map(|segment| {
+            let segment = segment.load()?;
+            Ok(RomSegment {
+                addr: segment.addr,
+                data: Cow::Owned(segment.data.into_owned()),
+                encrypted: segment.encrypted,
+            })
+        })
+        .collect()
+}
+
+/// Build the application image to write using a device's serial updater
+pub fn updater_image(
+    chip: Chip,
+    app: &[u8],
+    flash_data: FlashData,
+    image_format: Option<ImageFormatKind>,
+) -> Result<Vec<u8>> {
+    let apps = [(app, None)];
+    let elfs = parse_apps(&apps)?;
+    let images = app_images(chip, &apps, &elfs, &flash_data, image_format, None)?;
+    display_image_size(images[0].app_size(), images[0].part_size());
+
+    let segments = images[0].ota_segments().collect::<Result<Vec<_>, _>>()?;
+    match segments.as_slice() {
+        [segment] => {
+            check_image_chip("application", &segment.data, chip)?;
+            Ok(segment.data.to_vec())
+        }
+        _ => bail!("The image consists of several parts, which the serial updater can not write"),
+    }
+}
+
+/// Check that an image built by the ESP-IDF was built for `chip`
+fn check_image_chip(part: &'static str, data: &[u8], chip: Chip) -> Result<(), Error> {
+    // Only images with an extended header identify the chip they were built for
+    if chip == Chip::Esp8266 {
+        return Ok(());
+    }
+    let Ok(info) = ImageInfo::parse(data, None) else {
+        return Ok(());
+    };
+
+    if info.chip != chip {
+        return Err(Error::ImageChipMismatch {
+            part,
+            expected: chip,
+            found: info.chip,
+        });
+    }
+
+    Ok(())
+}
+
+/// Read the bootloader binary at `path`, if one was provided
+pub fn read_bootloader(path: Option<&Path>) -> Result<Option<Vec<u8>>> {
+    match path {
+        Some(path) => {
+            let data = fs::read(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open bootloader {}", path.display()))?;
+
+            Ok(Some(data))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Parse a [PartitionTable] from the provided path
+///
+/// Errors in CSV partition tables point at the lines responsible for them.
 pub fn parse_partition_table(path: &Path) -> Result<PartitionTable> {
     let data = fs::read(path)
         .into_diagnostic()
         .wrap_err("Failed to open partition table")?;
 
-    PartitionTable::try_from(data).into_diagnostic()
+    // Each entry of a binary partition table begins with the same magic bytes
+    if data.starts_with(&[0xAA, 0x50]) {
+        return PartitionTable::try_from_bytes(data).into_diagnostic();
+    }
+
+    let csv = String::from_utf8(data)
+        .into_diagnostic()
+        .wrap_err("The partition table is neither a binary nor a CSV file")?;
+
+    PartitionTable::try_from_str(csv.clone())
+        .map_err(|err| PartitionTableError::new(err, &path.display().to_string(), csv).into())
+}
+
+/// Compare each partition table written to the device against the one which
+/// is already on it, asking for confirmation before replacing it
+///
+/// The changes are shown as a diff of the two tables, along with a warning for
+/// each data partition whose contents would be lost. When `migrate_nvs` is set,
+/// the contents of NVS partitions are instead copied to their new offsets.
+/// When the device has no valid partition table, a table parsed from the CSV
+/// file at `path` is shown in full, so that any offsets which were omitted can
+/// be checked.
+pub fn check_partition_table(flasher: &mut Flasher, path: Option<&Path>, migrate_nvs: bool) {
+    let csv = path
+        .and_then(|path| path.extension())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("csv"));
+
+    flasher.set_migrate_nvs(migrate_nvs);
+    flasher.set_partition_table_check(Box::new(move |old, new| match old {
+        Some(old) if old == new => Ok(()),
+        Some(old) => {
+            println!("The partition table differs from the one on the device:");
+            prompt::print_diff(&partition_table_csv(old), &partition_table_csv(new));
+
+            for partition in moved_data_partitions(old, new) {
+                let nvs = partition.subtype() == SubType::Data(DataType::Nvs);
+                if migrate_nvs && nvs && new.find(&partition.name()).is_some() {
+                    continue;
+                }
+
+                warn!(
+                    "The contents of the '{}' partition at {:#x} will be lost",
+                    partition.name(),
+                    partition.offset()
+                );
+                if nvs && !migrate_nvs {
+                    warn!("Use `--migrate-nvs` to copy them to the partition's new offset");
+                }
+            }
+
+            prompt::confirm("Replace the partition table on the device?")
+        }
+        None if csv && prompt::is_interactive() => {
+            pretty_print(new);
+            prompt::confirm("Flash this partition table?")
+        }
+        None => Ok(()),
+    }));
+}
+
+/// The partitions of a table in CSV format, without any comments
+fn partition_table_csv(table: &PartitionTable) -> String {
+    table
+        .to_csv()
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| format!("{line}\n"))
+        .collect()
 }
 
 /// Erase one or more partitions by label or [DataType]
@@ -539,7 +1803,7 @@ pub fn erase_partitions(
 }
 
 fn erase_partition(flasher: &mut Flasher, part: &Partition) -> Result<()> {
-    log::info!("Erasing {} ({:?})...", part.name(), part.subtype());
+    tracing::info!("Erasing {} ({:?})...", part.name(), part.subtype());
 
     let offset = part.offset();
     let size = part.size();
@@ -577,16 +1841,15 @@ pub fn partition_table(args: PartitionTableArgs) -> Result<()> {
             .write_all(table.to_csv().into_diagnostic()?.as_bytes())
             .into_diagnostic()?;
     } else {
-        let input = fs::read(&args.partition_table).into_diagnostic()?;
-        let table = PartitionTable::try_from(input).into_diagnostic()?;
+        let table = parse_partition_table(&args.partition_table)?;
 
-        pretty_print(table);
+        pretty_print(&table);
     }
 
     Ok(())
 }
 
-fn pretty_print(table: PartitionTable) {
+fn pretty_print(table: &PartitionTable) {
     let mut pretty = Table::new();
 
     pretty
@@ -626,3 +1889,84 @@ fn pretty_print(table: PartitionTable) {
 
     println!("{pretty}");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(addr: u32, data: &'static [u8]) -> Result<RomSegment<'static>, Error> {
+        Ok(RomSegment {
+            addr,
+            data: Cow::Borrowed(data),
+            encrypted: false,
+        })
+    }
+
+    /// Merge the segments into an image for a 1MB flash chip
+    fn merge<const N: usize>(
+        segments: [Result<RomSegment<'static>, Error>; N],
+        fill: u8,
+        pad_to: Option<u32>,
+    ) -> Result<Vec<u8>> {
+        let mut merged = Vec::new();
+        write_merged(
+            &mut merged,
+            segments.into_iter(),
+            FlashSize::_1Mb,
+            fill,
+            pad_to,
+        )?;
+
+        Ok(merged)
+    }
+
+    #[test]
+    fn test_parse_fill_byte() {
+        assert_eq!(parse_fill_byte("0").unwrap(), 0);
+        assert_eq!(parse_fill_byte("255").unwrap(), 0xff);
+        assert_eq!(parse_fill_byte("0xA5").unwrap(), 0xa5);
+        assert_eq!(parse_fill_byte("0Xff").unwrap(), 0xff);
+
+        assert!(parse_fill_byte("256").is_err());
+        assert!(parse_fill_byte("0x100").is_err());
+        assert!(parse_fill_byte("ff").is_err());
+        assert!(parse_fill_byte("").is_err());
+    }
+
+    #[test]
+    fn test_write_merged() {
+        // Gaps between segments, and the image up to the padded size, are filled
+        let merged = merge(
+            [segment(0x2, &[1; 2]), segment(0x6, &[2; 2])],
+            0xa5,
+            Some(10),
+        )
+        .unwrap();
+        assert_eq!(merged, [0xa5, 0xa5, 1, 1, 0xa5, 0xa5, 2, 2, 0xa5, 0xa5]);
+
+        // Without padding the image ends with the last segment
+        let merged = merge([segment(0x2, &[1; 2]), segment(0x6, &[2; 2])], 0xff, None).unwrap();
+        assert_eq!(merged, [0xff, 0xff, 1, 1, 0xff, 0xff, 2, 2]);
+
+        // Adjacent segments are written one after another
+        let merged = merge([segment(0x0, &[1; 4]), segment(0x4, &[2; 4])], 0xff, None).unwrap();
+        assert_eq!(merged, [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        let merged = merge([segment(0x0, &[1; 4])], 0xff, Some(4)).unwrap();
+        assert_eq!(merged, [1; 4]);
+    }
+
+    #[test]
+    fn test_write_merged_errors() {
+        // The image must fit in the padded size, and in the flash
+        assert!(merge([segment(0x2, &[1; 4])], 0xff, Some(5)).is_err());
+        assert!(merge([segment(0xf_fffe, &[1; 4])], 0xff, None).is_err());
+
+        // Overlapping segments, and segments out of order, can't be merged
+        assert!(merge([segment(0x0, &[1; 8]), segment(0x4, &[2; 4])], 0xff, None).is_err());
+        assert!(merge([segment(0x8, &[1; 4]), segment(0x0, &[2; 4])], 0xff, None).is_err());
+
+        // Failing to generate a segment is returned
+        assert!(merge([Err(Error::InvalidAppImage)], 0xff, None).is_err());
+    }
+}
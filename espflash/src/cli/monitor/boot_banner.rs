@@ -0,0 +1,191 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::targets::Chip;
+
+lazy_static! {
+    // eg. `rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+    static ref RE_BANNER: Regex =
+        Regex::new(r"rst:(0x[[:xdigit:]]+)(?: \(\w+\))?,boot:(0x[[:xdigit:]]+)(?: \(([^)]*\)?)\))?")
+            .unwrap();
+    // eg. `rst cause:2, boot mode:(3,6)`
+    static ref RE_BANNER_ESP8266: Regex =
+        Regex::new(r"rst cause:(\d+), boot mode:\((\d+),\d+\)").unwrap();
+}
+
+/// The reset cause and boot mode printed by the ROM when the chip starts
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BootBanner {
+    reset: u32,
+    boot: u32,
+    boot_name: Option<String>,
+}
+
+impl BootBanner {
+    /// Parse the ROM's boot banner, if `line` contains one
+    pub fn parse(line: &str) -> Option<Self> {
+        if let Some(captures) = RE_BANNER.captures(line) {
+            return Some(Self {
+                reset: parse_int::parse(&captures[1]).ok()?,
+                boot: parse_int::parse(&captures[2]).ok()?,
+                boot_name: captures.get(3).map(|name| name.as_str().to_string()),
+            });
+        }
+
+        let captures = RE_BANNER_ESP8266.captures(line)?;
+        Some(Self {
+            reset: captures[1].parse().ok()?,
+            boot: captures[2].parse().ok()?,
+            boot_name: None,
+        })
+    }
+
+    /// A human-readable description of why the chip was reset
+    pub fn reset_cause(&self, chip: Chip) -> String {
+        let cause = match chip {
+            Chip::Esp32 => esp32_reset_cause(self.reset),
+            Chip::Esp8266 => esp8266_reset_cause(self.reset),
+            _ => reset_cause(self.reset),
+        };
+
+        match cause {
+            Some(cause) => cause.to_string(),
+            None => format!("unknown ({:#x})", self.reset),
+        }
+    }
+
+    /// A human-readable description of the boot mode selected by the
+    /// strapping pins
+    pub fn boot_mode(&self, chip: Chip) -> String {
+        let pin = match chip {
+            Chip::Esp32 | Chip::Esp32s2 | Chip::Esp32s3 | Chip::Esp8266 => "GPIO0",
+            _ => "GPIO9",
+        };
+        let download = match chip {
+            // GPIO15, GPIO0 and GPIO2, from most to least significant bit
+            Chip::Esp8266 => match self.boot {
+                1 => Some(true),
+                3 => Some(false),
+                4..=7 => return "SDIO boot: GPIO15 was held high at reset".into(),
+                _ => None,
+            },
+            Chip::Esp32 => Some(self.boot & 0x10 == 0),
+            _ => self
+                .boot_name
+                .as_deref()
+                .map(|name| name.starts_with("DOWNLOAD")),
+        };
+
+        let mut mode = match download {
+            Some(true) => format!(
+                "download boot: {pin} was held low at reset, so the chip is waiting to be flashed"
+            ),
+            Some(false) => "SPI flash boot".to_string(),
+            None => format!("unknown ({:#x})", self.boot),
+        };
+        if chip == Chip::Esp32 && self.boot & 0x20 != 0 {
+            mode.push_str(
+                "; GPIO12 (MTDI) was high at reset, selecting 1.8V flash, which fails to boot from \
+                 3.3V flash",
+            );
+        }
+
+        mode
+    }
+}
+
+fn esp32_reset_cause(code: u32) -> Option<&'static str> {
+    let cause = match code {
+        0x1 => "power on",
+        0x3 => "software reset (digital core)",
+        0x4 => "legacy watchdog (digital core)",
+        0x5 => "deep sleep wakeup",
+        0x6 => "SDIO reset",
+        0x7 => "timer group 0 watchdog (digital core)",
+        0x8 => "timer group 1 watchdog (digital core)",
+        0x9 => "RTC watchdog (digital core)",
+        0xa => "intrusion test (CPU)",
+        0xb => "timer group watchdog (CPU)",
+        0xc => "software reset (CPU)",
+        0xd => "RTC watchdog (CPU)",
+        0xe => "reset by the PRO CPU (APP CPU)",
+        0xf => "brownout",
+        0x10 => "RTC watchdog (digital core and RTC)",
+        _ => return None,
+    };
+
+    Some(cause)
+}
+
+fn esp8266_reset_cause(code: u32) -> Option<&'static str> {
+    let cause = match code {
+        1 => "power on",
+        2 => "external reset or deep sleep wakeup",
+        4 => "hardware watchdog",
+        _ => return None,
+    };
+
+    Some(cause)
+}
+
+// The reset causes of the chips which followed the ESP32
+fn reset_cause(code: u32) -> Option<&'static str> {
+    let cause = match code {
+        0x1 => "power on",
+        0x3 => "software reset (digital core)",
+        0x5 => "deep sleep wakeup",
+        0x7 => "timer group 0 watchdog (digital core)",
+        0x8 => "timer group 1 watchdog (digital core)",
+        0x9 => "RTC watchdog (digital core)",
+        0xb => "timer group 0 watchdog (CPU)",
+        0xc => "software reset (CPU)",
+        0xd => "RTC watchdog (CPU)",
+        0xf => "brownout",
+        0x10 => "RTC watchdog (digital core and RTC)",
+        0x11 => "timer group 1 watchdog (CPU)",
+        0x12 => "super watchdog",
+        0x13 => "clock glitch",
+        0x14 => "eFuse CRC error",
+        0x15 => "USB serial/JTAG (UART)",
+        0x16 => "USB serial/JTAG (JTAG)",
+        0x17 => "power glitch",
+        _ => return None,
+    };
+
+    Some(cause)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_banner() {
+        let banner =
+            BootBanner::parse("rst:0xf (RTCWDT_BROWN_OUT_RESET),boot:0x33 (SPI_FAST_FLASH_BOOT)")
+                .unwrap();
+        assert_eq!(banner.reset_cause(Chip::Esp32), "brownout");
+        assert!(banner
+            .boot_mode(Chip::Esp32)
+            .starts_with("SPI flash boot; GPIO12"));
+
+        let banner = BootBanner::parse("rst:0x1 (POWERON),boot:0x0 (DOWNLOAD(USB/UART0))").unwrap();
+        assert_eq!(banner.boot_name.as_deref(), Some("DOWNLOAD(USB/UART0)"));
+        assert!(banner
+            .boot_mode(Chip::Esp32s3)
+            .starts_with("download boot: GPIO0"));
+
+        let banner = BootBanner::parse(" ets Jan  8 2013,rst cause:2, boot mode:(3,6)").unwrap();
+        assert_eq!(
+            banner.reset_cause(Chip::Esp8266),
+            "external reset or deep sleep wakeup"
+        );
+        assert_eq!(banner.boot_mode(Chip::Esp8266), "SPI flash boot");
+
+        let banner = BootBanner::parse("rst:0x42,boot:0x8").unwrap();
+        assert_eq!(banner.reset_cause(Chip::Esp32c3), "unknown (0x42)");
+        assert_eq!(banner.boot_mode(Chip::Esp32c3), "unknown (0x8)");
+
+        assert!(BootBanner::parse("I (31) boot: ESP-IDF v5.0").is_none());
+    }
+}
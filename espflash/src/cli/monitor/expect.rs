@@ -0,0 +1,108 @@
+use std::{
+    io::{stdout, ErrorKind, Write},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::interface::Interface;
+
+/// Result of waiting for the expected output from a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    /// The expected pattern was matched
+    Passed,
+    /// One of the failure patterns was matched by the given line
+    Failed(String),
+    /// Neither was matched before the timeout elapsed
+    TimedOut,
+}
+
+/// Display the device's output until a line matching `expect` or one of the
+/// `fail` patterns is received, or until `timeout` has elapsed
+///
+/// Unlike [monitor](super::monitor), this does not require a terminal, so is
+/// suitable for use in CI.
+pub fn expect_output(
+    serial: &mut Interface,
+    expect: &Regex,
+    fail: &[Regex],
+    timeout: Duration,
+) -> serialport::Result<TestOutcome> {
+    let deadline = Instant::now() + timeout;
+    serial
+        .serial_port_mut()
+        .set_timeout(Duration::from_millis(100))?;
+
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+
+    let mut line = Vec::new();
+    let mut buff = [0; 1024];
+    while Instant::now() < deadline {
+        let read_count = match serial.serial_port_mut().read(&mut buff) {
+            Ok(count) => count,
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        for &byte in &buff[..read_count] {
+            if byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            writeln!(stdout, "{text}").ok();
+            line.clear();
+
+            if let Some(outcome) = check_line(&text, expect, fail) {
+                stdout.flush().ok();
+                return Ok(outcome);
+            }
+        }
+        stdout.flush().ok();
+    }
+
+    // The device may not terminate the expected output with a newline
+    let text = String::from_utf8_lossy(&line).trim_end().to_string();
+    if !text.is_empty() {
+        println!("{text}");
+    }
+
+    Ok(check_line(&text, expect, fail).unwrap_or(TestOutcome::TimedOut))
+}
+
+// Failure patterns take precedence, in case both match the same line
+fn check_line(line: &str, expect: &Regex, fail: &[Regex]) -> Option<TestOutcome> {
+    if fail.iter().any(|pattern| pattern.is_match(line)) {
+        Some(TestOutcome::Failed(line.to_string()))
+    } else if expect.is_match(line) {
+        Some(TestOutcome::Passed)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_line() {
+        let expect = Regex::new("^PASS").unwrap();
+        let fail = [Regex::new("FAIL|panicked at").unwrap()];
+
+        assert_eq!(check_line("booting...", &expect, &fail), None);
+        assert_eq!(
+            check_line("PASS", &expect, &fail),
+            Some(TestOutcome::Passed)
+        );
+        assert_eq!(
+            check_line("PASS, then panicked at src/main.rs:3:5", &expect, &fail),
+            Some(TestOutcome::Failed(
+                "PASS, then panicked at src/main.rs:3:5".into()
+            ))
+        );
+    }
+}
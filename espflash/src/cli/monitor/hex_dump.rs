@@ -0,0 +1,151 @@
+use std::{fmt::Write as _, io::Write};
+
+/// Number of bytes displayed in each row of the dump
+const ROW_LEN: usize = 16;
+
+/// Displays received data as hex, alongside its ASCII representation
+#[derive(Default)]
+pub(crate) struct HexDump {
+    offset: usize,
+    row: Vec<u8>,
+}
+
+impl HexDump {
+    /// Display each complete row of `bytes`, keeping any remainder until more
+    /// data is received or the output is flushed
+    pub fn write(&mut self, bytes: &[u8], out: &mut dyn Write) {
+        for &byte in bytes {
+            self.row.push(byte);
+            if self.row.len() == ROW_LEN {
+                self.write_row(out);
+            }
+        }
+
+        out.flush().ok();
+    }
+
+    /// Display any incomplete row, eg. once the device stops sending data
+    pub fn flush(&mut self, out: &mut dyn Write) {
+        if !self.row.is_empty() {
+            self.write_row(out);
+            out.flush().ok();
+        }
+    }
+
+    fn write_row(&mut self, out: &mut dyn Write) {
+        write!(out, "{}\r\n", format_row(self.offset, &self.row)).ok();
+
+        self.offset += self.row.len();
+        self.row.clear();
+    }
+}
+
+fn format_row(offset: usize, bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        if i == ROW_LEN / 2 {
+            hex.push(' ');
+        }
+        write!(hex, "{byte:02x} ").ok();
+    }
+
+    let ascii = bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect::<String>();
+
+    format!(
+        "{offset:08x}  {hex:<width$} |{ascii}|",
+        width = ROW_LEN * 3 + 1
+    )
+}
+
+/// Decode received data, displaying bytes which are not valid UTF-8 (and
+/// unprintable control characters) as hex rather than replacement characters
+///
+/// Any incomplete UTF-8 sequence at the end of the data is left in `pending`,
+/// to be completed by the next data received.
+pub(crate) fn decode_raw(bytes: &[u8], pending: &mut Vec<u8>) -> String {
+    let mut data = std::mem::take(pending);
+    data.extend_from_slice(bytes);
+
+    let mut text = String::new();
+    let mut rest = data.as_slice();
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => (valid, None),
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                (
+                    std::str::from_utf8(valid).unwrap(),
+                    Some((e.error_len(), after)),
+                )
+            }
+        };
+
+        for ch in valid.chars() {
+            // Escape sequences are preserved, as they are used to colour log output
+            if ch.is_control() && !matches!(ch, '\t' | '\r' | '\n' | '\x1b') {
+                write!(text, "\\x{:02x}", ch as u32).ok();
+            } else {
+                text.push(ch);
+            }
+        }
+
+        match invalid {
+            None => break,
+            Some((Some(len), after)) => {
+                for byte in &after[..len] {
+                    write!(text, "\\x{byte:02x}").ok();
+                }
+                rest = &after[len..];
+            }
+            Some((None, after)) => {
+                *pending = after.to_vec();
+                break;
+            }
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let mut out = Vec::new();
+        let mut dump = HexDump::default();
+
+        dump.write(b"Hello, world!\n\x00\xff\x01", &mut out);
+        dump.write(b"abc", &mut out);
+        dump.flush(&mut out);
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\r\n\
+             00000010  01 61 62 63                                       |.abc|\r\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_raw() {
+        let mut pending = Vec::new();
+
+        assert_eq!(
+            decode_raw(b"ok\xff\x00\x1b[0m\r\n\xe2\x82", &mut pending),
+            "ok\\xff\\x00\x1b[0m\r\n"
+        );
+        assert_eq!(pending, b"\xe2\x82");
+        assert_eq!(decode_raw(b"\xac!", &mut pending), "\u{20ac}!");
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+use std::process::{Command, Stdio};
+
+use regex::Regex;
+use tracing::{debug, error};
+
+use crate::cli::config::MonitorHook;
+
+/// Hooks whose patterns are matched against each line of the monitor's output
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<(Regex, MonitorHook)>,
+}
+
+impl Hooks {
+    /// Compile the patterns of the given hooks
+    ///
+    /// Patterns are validated when the configuration is loaded, so any which
+    /// fail to compile are reported and ignored.
+    pub fn new(hooks: &[MonitorHook]) -> Self {
+        let hooks = hooks
+            .iter()
+            .filter_map(|hook| match Regex::new(&hook.pattern) {
+                Ok(regex) => Some((regex, hook.clone())),
+                Err(e) => {
+                    error!("Ignoring monitor hook '{}': {}", hook.pattern, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { hooks }
+    }
+
+    /// The hooks whose patterns match `line`
+    pub fn matching<'a>(&'a self, line: &'a str) -> impl Iterator<Item = &'a MonitorHook> {
+        self.hooks
+            .iter()
+            .filter(move |(regex, _)| regex.is_match(line))
+            .map(|(_, hook)| hook)
+    }
+}
+
+/// Run `command` using the system's shell, without waiting for it to complete
+///
+/// Its output is discarded, so as not to interfere with the monitor's.
+pub(crate) fn run_command(command: &str, line: &str) {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    debug!("Running monitor hook command: {}", command);
+    if let Err(e) = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("ESPFLASH_MONITOR_LINE", line)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        error!("Failed to run monitor hook command '{}': {}", command, e);
+    }
+}
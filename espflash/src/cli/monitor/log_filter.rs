@@ -0,0 +1,207 @@
+use std::borrow::Cow;
+
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // An ANSI escape sequence, such as those ESP-IDF uses to color log lines
+    static ref RE_ANSI: Regex = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap();
+    // An ANSI escape sequence which is cut off at the end of the text
+    static ref RE_ANSI_PARTIAL: Regex = Regex::new(r"\x1b(\[[0-9;]*)?$").unwrap();
+    // The prefix of an ESP-IDF log line, eg. `I (31) wifi:`, where the time is
+    // either in milliseconds or the time of day
+    static ref RE_PREFIX: Regex = Regex::new(r"^([EWIDV]) \([0-9:.]+\) ([^:]*):").unwrap();
+    // Text which may be the beginning of an ESP-IDF log line's prefix
+    static ref RE_PREFIX_PARTIAL: Regex =
+        Regex::new(r"^([EWIDV]( (\([0-9:.]*(\)( [^:]*)?)?)?)?)?$").unwrap();
+}
+
+/// When to display the colors in the device's output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Display colors if stdout is a terminal
+    #[default]
+    Auto,
+    /// Always display colors
+    Always,
+    /// Never display colors
+    Never,
+}
+
+/// Verbosity of ESP-IDF log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl LogLevel {
+    fn from_letter(letter: &str) -> Option<Self> {
+        let level = match letter {
+            "E" => LogLevel::Error,
+            "W" => LogLevel::Warn,
+            "I" => LogLevel::Info,
+            "D" => LogLevel::Debug,
+            "V" => LogLevel::Verbose,
+            _ => return None,
+        };
+
+        Some(level)
+    }
+}
+
+/// Selects which ESP-IDF log lines are displayed, by their level and tag
+///
+/// Lines which are not ESP-IDF log lines are always displayed.
+#[derive(Debug, Default)]
+pub(crate) struct LogFilter {
+    level: Option<LogLevel>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl LogFilter {
+    /// Create a filter, or `None` if it would display every line
+    ///
+    /// Tags prefixed with `-` are excluded; if any tags are not, only those
+    /// are included. Tags may contain the wildcards `*` and `?`.
+    pub fn new(level: Option<LogLevel>, tags: &[String]) -> Option<Self> {
+        if level.is_none() && tags.is_empty() {
+            return None;
+        }
+
+        let mut filter = Self {
+            level,
+            ..Self::default()
+        };
+        for tag in tags.iter().map(|tag| tag.trim()) {
+            match tag.strip_prefix('-') {
+                Some(tag) => filter.exclude.push(tag.to_string()),
+                None => filter.include.push(tag.to_string()),
+            }
+        }
+
+        Some(filter)
+    }
+
+    /// Whether the line beginning with `text` is displayed, or `None` if more
+    /// of the line is needed to decide
+    pub fn is_visible(&self, text: &str) -> Option<bool> {
+        let text = strip_ansi(text);
+        let text = RE_ANSI_PARTIAL.replace(&text, "");
+
+        if let Some(captures) = RE_PREFIX.captures(&text) {
+            let level = LogLevel::from_letter(&captures[1]).unwrap();
+            let tag = &captures[2];
+
+            let visible = self.level.map_or(true, |max| level <= max)
+                && !self.exclude.iter().any(|glob| glob_match(glob, tag))
+                && (self.include.is_empty()
+                    || self.include.iter().any(|glob| glob_match(glob, tag)));
+
+            Some(visible)
+        } else if RE_PREFIX_PARTIAL.is_match(&text) {
+            None
+        } else {
+            Some(true)
+        }
+    }
+}
+
+/// Remove any ANSI escape sequences from `text`
+pub(crate) fn strip_ansi(text: &str) -> Cow<'_, str> {
+    RE_ANSI.replace_all(text, "")
+}
+
+/// Split off an ANSI escape sequence which is cut off at the end of `text`,
+/// so that it can be completed by the following text
+pub(crate) fn split_partial_ansi(text: &mut String) -> String {
+    match RE_ANSI_PARTIAL.find(text) {
+        Some(partial) => text.split_off(partial.start()),
+        None => String::new(),
+    }
+}
+
+/// Match `text` against a pattern, in which `*` matches any number of
+/// characters and `?` matches any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // The position in the pattern and text to resume from when a `*` needs to
+    // match more characters
+    let mut backtrack = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let tags = ["wifi*".to_string(), "-wifi_init".to_string()];
+        let filter = LogFilter::new(Some(LogLevel::Info), &tags).unwrap();
+
+        assert_eq!(filter.is_visible("I (31) wifi: started"), Some(true));
+        assert_eq!(
+            filter.is_visible("\x1b[0;33mW (12:00:01.123) wifi_sta: lost\x1b[0m"),
+            Some(true)
+        );
+        assert_eq!(filter.is_visible("D (31) wifi: details"), Some(false));
+        assert_eq!(
+            filter.is_visible("I (31) wifi_init: rx ba win"),
+            Some(false)
+        );
+        assert_eq!(filter.is_visible("I (31) mdns: started"), Some(false));
+        assert_eq!(filter.is_visible("I (3"), None);
+        assert_eq!(filter.is_visible("\x1b[0;3"), None);
+        assert_eq!(filter.is_visible("Guru Meditation Error"), Some(true));
+
+        assert!(LogFilter::new(None, &[]).is_none());
+    }
+
+    #[test]
+    fn test_ansi() {
+        assert_eq!(strip_ansi("\x1b[0;32mI (31) boot\x1b[0m"), "I (31) boot");
+
+        let mut text = "\x1b[0;32mI (31) boot\x1b[0".to_string();
+        assert_eq!(split_partial_ansi(&mut text), "\x1b[0");
+        assert_eq!(text, "\x1b[0;32mI (31) boot");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("wifi*", "wifi"));
+        assert!(glob_match("wifi*", "wifi_init"));
+        assert!(glob_match("*_?nit", "wifi_init"));
+        assert!(!glob_match("wifi", "wifi_init"));
+        assert!(!glob_match("*x*", "wifi"));
+    }
+}
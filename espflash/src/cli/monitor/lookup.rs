@@ -0,0 +1,117 @@
+use std::io::Write;
+
+use crossterm::{
+    event::{KeyCode, KeyEvent},
+    style::{Print, PrintStyledContent, Stylize},
+    QueueableCommand,
+};
+
+use super::symbols::Symbols;
+
+/// Prompt for an address to look up in the ELF file's symbols
+///
+/// Output from the device is held while the address is entered, so that it
+/// does not disrupt the prompt.
+pub(crate) struct AddressPrompt {
+    input: String,
+}
+
+impl AddressPrompt {
+    /// Display the prompt
+    pub fn new(out: &mut dyn Write) -> Self {
+        out.queue(PrintStyledContent("\r\nAddress to look up: 0x".yellow()))
+            .ok();
+        out.flush().ok();
+
+        Self {
+            input: String::new(),
+        }
+    }
+
+    /// Handle a key pressed while the prompt is displayed, returning `true`
+    /// once the prompt has been completed or cancelled
+    pub fn handle_key(
+        &mut self,
+        key: KeyEvent,
+        symbols: Option<&Symbols>,
+        out: &mut dyn Write,
+    ) -> bool {
+        match key.code {
+            KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.input.len() < 16 => {
+                self.input.push(ch);
+                out.queue(Print(ch)).ok();
+            }
+            KeyCode::Backspace if self.input.pop().is_some() => {
+                out.write_all(b"\x08 \x08").ok();
+            }
+            KeyCode::Enter => {
+                let result = match u64::from_str_radix(&self.input, 16) {
+                    Ok(addr) => describe_address(symbols, addr),
+                    Err(_) => "No address was entered".into(),
+                };
+                out.queue(PrintStyledContent(format!("\r\n{result}\r\n").yellow()))
+                    .ok();
+                out.flush().ok();
+                return true;
+            }
+            KeyCode::Esc => {
+                out.write_all(b"\r\n").ok();
+                out.flush().ok();
+                return true;
+            }
+            _ => {}
+        }
+
+        out.flush().ok();
+        false
+    }
+}
+
+/// The function and source location containing `addr`
+fn describe_address(symbols: Option<&Symbols>, addr: u64) -> String {
+    let symbols = match symbols {
+        Some(symbols) => symbols,
+        None => return "No ELF file was provided, so addresses can not be looked up".into(),
+    };
+
+    match (symbols.get_name(addr), symbols.get_location(addr)) {
+        (Some(name), Some((file, line))) => {
+            format!("{addr:#010x} - {name}\r\n    at {file}:{line}")
+        }
+        (Some(name), None) => format!("{addr:#010x} - {name}"),
+        (None, _) => format!("{addr:#010x} does not belong to a known function"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyModifiers;
+
+    use super::*;
+
+    #[test]
+    fn test_address_prompt() {
+        let mut out = Vec::new();
+        let mut prompt = AddressPrompt::new(&mut out);
+
+        for code in [
+            KeyCode::Char('4'),
+            KeyCode::Char('g'),
+            KeyCode::Char('2'),
+            KeyCode::Backspace,
+        ] {
+            assert!(!prompt.handle_key(KeyEvent::new(code, KeyModifiers::NONE), None, &mut out));
+        }
+        assert_eq!(prompt.input, "4");
+
+        out.clear();
+        assert!(prompt.handle_key(
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            None,
+            &mut out
+        ));
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("No ELF file was provided"));
+    }
+}
@@ -3,7 +3,24 @@
 //! While simple, this serial monitor does provide some nice features such as:
 //!
 //! - Keyboard shortcut for resetting the device (Ctrl-R)
-//! - Decoding of function addresses in serial output
+//! - Decoding of function addresses in serial output, which are underlined,
+//!   and of addresses entered by the user (Ctrl-T Ctrl-L)
+//! - Sending typed and pasted input to the device, with a configurable line
+//!   ending and optional local echo, for firmware hosting a serial CLI/REPL
+//! - Displaying binary data as hex, either inline or as a hex dump
+//! - Waiting for expected output without a terminal, for hardware-in-the-loop
+//!   tests
+//! - Configurable hooks which highlight lines, ring the bell, run a command or
+//!   exit when a pattern (eg. a panic) is matched in the output
+//! - Hardware or software flow control, and a notice whenever received data
+//!   is lost to overruns, for high-rate logging
+//! - An optional status line showing the port, baud rate, elapsed time, amount
+//!   of data received and the cause of the last reset (Ctrl-T Ctrl-S)
+//! - Prefixing each line with the time it was received, either the time of
+//!   day, or the time since the monitor started or the previous line began
+//! - Filtering ESP-IDF log lines by their level and tag, and removing colors
+//!   from the device's output
+//! - Explaining the reset cause and boot mode in the ROM's boot banner
 //!
 //! While some serial monitors buffer output until a newline is encountered,
 //! that is not the case here. With other monitors the output of a `print!()`
@@ -12,25 +29,62 @@
 
 use std::{
     io::{stdout, ErrorKind, Write},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use clap::{Args, ValueEnum};
 use crossterm::{
-    event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers},
-    style::{Color, Print, PrintStyledContent, Stylize},
-    terminal::{disable_raw_mode, enable_raw_mode},
+    event::{
+        poll, read, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent,
+        KeyModifiers,
+    },
+    execute,
+    style::{Attribute, Color, Print, PrintStyledContent, SetAttribute, Stylize},
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType},
+    tty::IsTty,
     QueueableCommand,
 };
 use lazy_static::lazy_static;
-use log::error;
 use miette::{IntoDiagnostic, Result};
 use regex::Regex;
+use tracing::error;
+
+use self::{
+    boot_banner::BootBanner,
+    hex_dump::{decode_raw, HexDump},
+    hooks::{run_command, Hooks},
+    line_endings::normalized,
+    log_filter::{split_partial_ansi, strip_ansi, LogFilter},
+    lookup::AddressPrompt,
+    status::StatusLine,
+    symbols::Symbols,
+    timestamps::Timestamper,
+};
+pub use self::{
+    expect::{expect_output, TestOutcome},
+    log_filter::{ColorMode, LogLevel},
+    timestamps::Timestamps,
+};
+use crate::{
+    cli::config::MonitorHook,
+    connection::reset_after_flash,
+    interface::{FlowControl, Interface},
+    targets::Chip,
+};
 
-use self::{line_endings::normalized, symbols::Symbols};
-use crate::{connection::reset_after_flash, interface::Interface};
-
+mod boot_banner;
+mod expect;
+mod hex_dump;
+mod hooks;
 mod line_endings;
+mod log_filter;
+mod lookup;
+mod status;
 mod symbols;
+mod timestamps;
+
+/// How often the serial port's count of overruns is checked
+const OVERRUN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 // Pattern to much a function address in serial output.
 lazy_static! {
@@ -39,70 +93,229 @@ lazy_static! {
 
 #[derive(Default)]
 struct SerialContext<'ctx> {
+    chip: Option<Chip>,
     symbols: Option<Symbols<'ctx>>,
     previous_frag: Option<String>,
     previous_line: Option<String>,
+    raw: bool,
+    pending: Vec<u8>,
+    hooks: Hooks,
+    exit_code: Option<i32>,
+    reset_cause: Option<String>,
+    timestamper: Option<Timestamper>,
+    line_prefix: String,
+    filter: Option<LogFilter>,
+    // Whether the current line is displayed, if this has been decided
+    visible: Option<bool>,
+    strip_colors: bool,
+    partial_ansi: String,
 }
 
 impl<'ctx> SerialContext<'ctx> {
-    fn new(symbols: Option<Symbols<'ctx>>) -> Self {
+    fn new(chip: Chip, symbols: Option<Symbols<'ctx>>, raw: bool, hooks: Hooks) -> Self {
         Self {
+            chip: Some(chip),
             symbols,
+            raw,
+            hooks,
             ..Self::default()
         }
     }
 }
 
+/// Line ending sent to the device when Enter is pressed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    /// Carriage return followed by a line feed (`\r\n`)
+    Crlf,
+    /// Carriage return (`\r`)
+    #[default]
+    Cr,
+    /// Line feed (`\n`)
+    Lf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+            LineEnding::Lf => "\n",
+        }
+    }
+}
+
+/// Options controlling how input is sent to the device
+#[derive(Debug, Default, Clone, Args)]
+#[non_exhaustive]
+pub struct MonitorOptions {
+    /// Line ending to send to the device when Enter is pressed
+    #[arg(long, value_enum, default_value_t = LineEnding::Cr)]
+    pub eol: LineEnding,
+    /// Display typed characters locally, for firmware which does not echo its
+    /// input; can also be toggled using CTRL+E
+    #[arg(long)]
+    pub echo: bool,
+    /// Display bytes which are not valid UTF-8, and unprintable control
+    /// characters, as hex rather than replacement characters
+    #[arg(long, conflicts_with = "hex")]
+    pub raw: bool,
+    /// Display all data received as a hex dump, alongside its ASCII
+    /// representation
+    #[arg(long)]
+    pub hex: bool,
+    /// Flow control to use while monitoring, for adapters which drop data at
+    /// high baud rates. Hardware flow control requires the RTS and CTS lines
+    /// to be connected; software flow control is not suitable for binary data.
+    #[arg(long, value_enum, default_value_t = FlowControl::None)]
+    pub flow_control: FlowControl,
+    /// Display a status line at the bottom of the terminal; can also be
+    /// toggled using CTRL+T followed by CTRL+S
+    #[arg(long)]
+    pub status_line: bool,
+    /// Prefix each line with the time it was received
+    #[arg(long, value_enum, conflicts_with = "hex")]
+    pub timestamps: Option<Timestamps>,
+    /// Only display ESP-IDF log lines of this level or more severe
+    #[arg(long, value_enum, conflicts_with = "hex")]
+    pub log_level: Option<LogLevel>,
+    /// Only display ESP-IDF log lines with these tags, which may contain
+    /// wildcards; tags prefixed with '-' are hidden instead, eg. 'wifi*,-mdns'
+    #[arg(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        conflicts_with = "hex"
+    )]
+    pub tags: Vec<String>,
+    /// When to display the colors in the device's output
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+}
+
 struct RawModeGuard;
 
 impl RawModeGuard {
     pub fn new() -> Result<Self> {
         enable_raw_mode().into_diagnostic()?;
+        // Receive pasted text as a whole, rather than as individual key presses.
+        execute!(stdout(), EnableBracketedPaste).into_diagnostic()?;
         Ok(RawModeGuard)
     }
 }
 
 impl Drop for RawModeGuard {
     fn drop(&mut self) {
+        if let Err(e) = execute!(stdout(), DisableBracketedPaste) {
+            error!("{:#}", e)
+        }
         if let Err(e) = disable_raw_mode() {
             error!("{:#}", e)
         }
     }
 }
 
+/// Why the serial monitor was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorExit {
+    /// The user closed the monitor
+    User,
+    /// The `stop` callback returned `true`
+    Stopped,
+    /// A monitor hook requested that the process exits with this code
+    ExitCode(i32),
+}
+
 /// Open a serial monitor on the given interface
+///
+/// Each line of output is matched against `hooks`. If a hook with an exit code
+/// matches, the monitor is closed once the line has been displayed, and the
+/// code is returned so that the caller can exit with it.
 pub fn monitor(
+    serial: Interface,
+    chip: Chip,
+    elfs: &[&[u8]],
+    pid: u16,
+    baud: u32,
+    options: &MonitorOptions,
+    hooks: &[MonitorHook],
+) -> serialport::Result<Option<i32>> {
+    let exit = monitor_until(serial, chip, elfs, pid, baud, options, hooks, &mut || false)?;
+
+    Ok(match exit {
+        MonitorExit::ExitCode(code) => Some(code),
+        _ => None,
+    })
+}
+
+/// Open a serial monitor on the given interface, until `stop` returns `true`
+pub fn monitor_until(
     mut serial: Interface,
-    elf: Option<&[u8]>,
+    chip: Chip,
+    elfs: &[&[u8]],
     pid: u16,
     baud: u32,
-) -> serialport::Result<()> {
+    options: &MonitorOptions,
+    hooks: &[MonitorHook],
+    stop: &mut dyn FnMut() -> bool,
+) -> serialport::Result<MonitorExit> {
     println!("Commands:");
     println!("    CTRL+R    Reset chip");
+    println!("    CTRL+E    Toggle local echo");
+    println!("    CTRL+T    Followed by:");
+    println!("      CTRL+S  Toggle status line");
+    println!("      CTRL+L  Look up an address");
+    println!("      CTRL+T  Send CTRL+T");
     println!("    CTRL+C    Exit");
     println!();
 
+    let mut echo = options.echo;
+
     // Explicitly set the baud rate when starting the serial monitor, to allow using
     // different rates for flashing.
     serial.serial_port_mut().set_baud_rate(baud)?;
     serial
         .serial_port_mut()
         .set_timeout(Duration::from_millis(5))?;
-
-    // Load symbols from the ELF file (if provided) and initialize the context.
-    let symbols = if let Some(bytes) = elf {
-        Symbols::try_from(bytes).ok()
-    } else {
-        None
+    serial
+        .serial_port_mut()
+        .set_flow_control(options.flow_control)?;
+
+    let initial_overruns = serial.serial_port().overruns();
+    let mut overruns = initial_overruns;
+    let mut overruns_checked = Instant::now();
+
+    let port = serial.serial_port().name().unwrap_or_default();
+    let mut status = StatusLine::new(port, baud, initial_overruns);
+
+    // Load symbols from the ELF files (if provided) and initialize the context.
+    let symbols = Symbols::new(elfs);
+    let mut ctx = SerialContext::new(chip, symbols, options.raw, Hooks::new(hooks));
+    ctx.timestamper = options.timestamps.map(Timestamper::new);
+    ctx.filter = LogFilter::new(options.log_level, &options.tags);
+    ctx.strip_colors = match options.color {
+        ColorMode::Auto => !stdout().is_tty(),
+        ColorMode::Always => false,
+        ColorMode::Never => true,
     };
-    let mut ctx = SerialContext::new(symbols);
+    let mut hex_dump = options.hex.then(HexDump::default);
 
     // We are in raw mode until `_raw_mode` is dropped (ie. this function returns).
-    let _raw_mode = RawModeGuard::new();
+    let raw_mode = RawModeGuard::new();
 
     let stdout = stdout();
     let mut stdout = stdout.lock();
 
+    if options.status_line {
+        status.show(&mut stdout);
+    }
+
+    // Whether CTRL+T has been pressed, so the next key is a monitor command
+    let mut menu = false;
+    let mut prompt: Option<AddressPrompt> = None;
+    let mut received = Vec::new();
+
+    let mut exit = MonitorExit::User;
     let mut buff = [0; 1024];
     loop {
         let read_count = match serial.serial_port_mut().read(&mut buff) {
@@ -111,38 +324,143 @@ pub fn monitor(
             Err(e) if e.kind() == ErrorKind::Interrupted => continue,
             err => err,
         }?;
+        status.received(read_count);
+
+        // Output is held while the user is entering an address.
+        received.extend_from_slice(&buff[0..read_count]);
+        if prompt.is_none() {
+            if let Some(hex_dump) = &mut hex_dump {
+                if !received.is_empty() {
+                    hex_dump.write(&received, &mut stdout);
+                } else {
+                    // Display incomplete rows once the device stops sending data.
+                    hex_dump.flush(&mut stdout);
+                }
+            } else if !received.is_empty() {
+                handle_serial(&mut ctx, &received, &mut stdout);
+                if let Some(cause) = ctx.reset_cause.take() {
+                    status.set_reset_cause(cause);
+                }
+            }
+            received.clear();
+        }
+
+        if let Some(code) = ctx.exit_code {
+            exit = MonitorExit::ExitCode(code);
+            break;
+        }
+        if stop() {
+            exit = MonitorExit::Stopped;
+            break;
+        }
 
-        if read_count > 0 {
-            handle_serial(&mut ctx, &buff[0..read_count], &mut stdout);
+        if overruns_checked.elapsed() >= OVERRUN_CHECK_INTERVAL {
+            overruns_checked = Instant::now();
+
+            let current = serial.serial_port().overruns();
+            if let Some(lost) = new_overruns(overruns, current) {
+                print_overrun_notice(lost, &mut stdout);
+                status.add_overruns(lost);
+                overruns = current;
+            }
         }
+        status.tick(&mut stdout);
 
         if poll(Duration::from_secs(0))? {
-            if let Event::Key(key) = read()? {
-                if key.modifiers.contains(KeyModifiers::CONTROL) {
-                    match key.code {
-                        KeyCode::Char('c') => break,
-                        KeyCode::Char('r') => {
-                            reset_after_flash(&mut serial, pid)?;
-                            continue;
+            let bytes = match read()? {
+                Event::Key(key) => {
+                    if let Some(address_prompt) = &mut prompt {
+                        if address_prompt.handle_key(key, ctx.symbols.as_ref(), &mut stdout) {
+                            prompt = None;
+                        }
+                        continue;
+                    }
+
+                    if menu {
+                        menu = false;
+                        match key.code {
+                            KeyCode::Char('s') => {
+                                status.toggle(&mut stdout);
+                                continue;
+                            }
+                            KeyCode::Char('l') => {
+                                prompt = Some(AddressPrompt::new(&mut stdout));
+                                continue;
+                            }
+                            // Pressing CTRL+T twice sends it to the device
+                            KeyCode::Char('t') => {}
+                            _ => continue,
+                        }
+                    } else if key.modifiers.contains(KeyModifiers::CONTROL) {
+                        match key.code {
+                            KeyCode::Char('c') => break,
+                            KeyCode::Char('r') => {
+                                reset_after_flash(&mut serial, pid)?;
+                                continue;
+                            }
+                            KeyCode::Char('e') => {
+                                echo = !echo;
+                                continue;
+                            }
+                            KeyCode::Char('t') => {
+                                menu = true;
+                                continue;
+                            }
+                            _ => {}
                         }
-                        _ => {}
                     }
+
+                    handle_key_event(key, options.eol)
+                }
+                Event::Paste(text) => Some(handle_paste(&text, options.eol)),
+                Event::Resize(..) => {
+                    status.resize(&mut stdout);
+                    None
                 }
+                _ => None,
+            };
 
-                if let Some(bytes) = handle_key_event(key) {
-                    serial.serial_port_mut().write_all(&bytes)?;
-                    serial.serial_port_mut().flush()?;
+            if let Some(bytes) = bytes {
+                serial.serial_port_mut().write_all(&bytes)?;
+                serial.serial_port_mut().flush()?;
+
+                if echo {
+                    echo_input(&bytes, options.eol, &mut stdout);
                 }
             }
         }
     }
 
-    Ok(())
+    drop(status);
+    drop(stdout);
+    drop(raw_mode);
+
+    if let Some(lost) = new_overruns(initial_overruns, serial.serial_port().overruns()) {
+        println!();
+        println!("{lost} overruns occurred while monitoring, so some output was lost");
+    }
+
+    if let MonitorExit::ExitCode(code) = exit {
+        println!();
+        println!("Exiting with code {code}, as requested by a monitor hook");
+    }
+
+    Ok(exit)
 }
 
 fn handle_serial(ctx: &mut SerialContext, buff: &[u8], out: &mut dyn Write) {
     let text: Vec<u8> = normalized(buff.iter().copied()).collect();
-    let text = String::from_utf8_lossy(&text).to_string();
+    let mut text = if ctx.raw {
+        decode_raw(&text, &mut ctx.pending)
+    } else {
+        String::from_utf8_lossy(&text).to_string()
+    };
+    if ctx.strip_colors {
+        // Escape sequences split between reads are removed once complete.
+        text = format!("{}{}", ctx.partial_ansi, text);
+        ctx.partial_ansi = split_partial_ansi(&mut text);
+        text = strip_ansi(&text).into_owned();
+    }
 
     // Split the text into lines, storing the last of which separately if it is
     // incomplete (ie. does not end with '\n') because these need special handling.
@@ -155,19 +473,71 @@ fn handle_serial(ctx: &mut SerialContext, buff: &[u8], out: &mut dyn Write) {
 
     // Iterate through all *complete* lines (ie. those ending with '\n') ...
     for line in lines {
-        // ... and print the line.
-        out.queue(Print(line)).ok();
-
         // If there is a previous line fragment, that means that the current line must
         // be appended to it in order to form the complete line. Since we want to look
-        // for function addresses in the *entire* previous line we combine these prior
-        // to performing the symbol lookup(s).
-        ctx.previous_line = if let Some(frag) = &ctx.previous_frag {
-            Some(format!("{frag}{line}"))
+        // for function addresses and hook patterns in the *entire* previous line we
+        // combine these prior to performing the symbol lookup(s).
+        let full_line = if let Some(frag) = &ctx.previous_frag {
+            format!("{frag}{line}")
         } else {
-            Some(line.to_string())
+            line.to_string()
         };
 
+        // Lines which are filtered out are held until they can be identified, so the
+        // fragment of this line may not have been printed yet.
+        let decided = ctx.visible.take();
+        let visible = decided
+            .or_else(|| ctx.filter.as_ref().and_then(|f| f.is_visible(&full_line)))
+            .unwrap_or(true);
+        if !visible {
+            ctx.previous_frag = None;
+            continue;
+        }
+        let frag_printed = ctx.previous_frag.is_some() && decided == Some(true);
+        if !frag_printed {
+            begin_line(ctx, out);
+        }
+        let line = if frag_printed { line } else { &full_line };
+
+        let mut highlight = false;
+        let mut bell = false;
+        let mut commands = Vec::new();
+        for hook in ctx.hooks.matching(&full_line) {
+            highlight |= hook.highlight;
+            bell |= hook.bell;
+            commands.extend(hook.command.clone());
+            if ctx.exit_code.is_none() {
+                ctx.exit_code = hook.exit_code;
+            }
+        }
+
+        // ... and print the line. When highlighting, the fragment which has already
+        // been printed is cleared and printed again along with the rest of the line.
+        let symbols = ctx.symbols.as_ref();
+        if highlight && frag_printed {
+            out.queue(Print('\r')).ok();
+            out.queue(Clear(ClearType::CurrentLine)).ok();
+            out.queue(Print(&ctx.line_prefix)).ok();
+            print_text(symbols, &full_line, true, out);
+        } else {
+            print_text(symbols, line, highlight, out);
+        }
+
+        if bell {
+            out.write_all(b"\x07").ok();
+        }
+        for command in commands {
+            run_command(&command, &full_line);
+        }
+
+        // Colors set by the line do not carry over to those which follow it.
+        if full_line.contains('\x1b') && !full_line.ends_with("\x1b[0m") {
+            out.queue(Print("\x1b[0m")).ok();
+        }
+
+        let banner = ctx.chip.zip(BootBanner::parse(&full_line));
+        ctx.previous_line = Some(full_line);
+
         // The previous fragment has been completed (by this current line).
         ctx.previous_frag = None;
 
@@ -201,6 +571,20 @@ fn handle_serial(ctx: &mut SerialContext, buff: &[u8], out: &mut dyn Write) {
             }
         }
 
+        // Explain the codes in the ROM's boot banner, which are otherwise cryptic.
+        if let Some((chip, banner)) = banner {
+            let cause = banner.reset_cause(chip);
+            out.queue(PrintStyledContent(
+                format!(
+                    "\r\n[reset cause: {cause}; boot mode: {}]",
+                    banner.boot_mode(chip)
+                )
+                .with(Color::Yellow),
+            ))
+            .ok();
+            ctx.reset_cause = Some(cause);
+        }
+
         // Remember to begin a new line after we have printed this one!
         out.write_all(b"\r\n").ok();
     }
@@ -208,24 +592,134 @@ fn handle_serial(ctx: &mut SerialContext, buff: &[u8], out: &mut dyn Write) {
     // If there is an incomplete line we will still print it. However, we will not
     // perform function name lookups or terminate it with a newline.
     if let Some(line) = incomplete {
-        out.queue(Print(line)).ok();
+        let frag = match &ctx.previous_frag {
+            Some(frag) => format!("{frag}{line}"),
+            None => line.to_string(),
+        };
 
-        if let Some(frag) = &ctx.previous_frag {
-            ctx.previous_frag = Some(format!("{frag}{line}"));
-        } else {
-            ctx.previous_frag = Some(line.to_string());
+        match ctx.visible {
+            Some(true) => print_text(ctx.symbols.as_ref(), line, false, out),
+            Some(false) => {}
+            None => {
+                ctx.visible = match &ctx.filter {
+                    Some(filter) => filter.is_visible(&frag),
+                    None => Some(true),
+                };
+                if ctx.visible == Some(true) {
+                    begin_line(ctx, out);
+                    print_text(ctx.symbols.as_ref(), &frag, false, out);
+                }
+            }
         }
+
+        ctx.previous_frag = Some(frag);
     }
 
     // Don't forget to flush the writer!
     out.flush().ok();
 }
 
+// Print `text`, in red if it is highlighted, underlining any addresses which
+// belong to known functions
+fn print_text(symbols: Option<&Symbols>, text: &str, highlight: bool, out: &mut dyn Write) {
+    let print = |text: &str, out: &mut dyn Write| {
+        if highlight {
+            out.queue(PrintStyledContent(text.red().bold())).ok();
+        } else {
+            out.queue(Print(text)).ok();
+        }
+    };
+
+    let mut start = 0;
+    if let Some(symbols) = symbols {
+        for addr in RE_FN_ADDR.find_iter(text) {
+            if symbols
+                .get_name(parse_int::parse::<u64>(addr.as_str()).unwrap())
+                .is_none()
+            {
+                continue;
+            }
+
+            print(&text[start..addr.start()], out);
+            out.queue(SetAttribute(Attribute::Underlined)).ok();
+            print(addr.as_str(), out);
+            out.queue(SetAttribute(Attribute::NoUnderline)).ok();
+            start = addr.end();
+        }
+    }
+    print(&text[start..], out);
+}
+
+// Print the prefix of a line which is beginning, if lines are prefixed
+fn begin_line(ctx: &mut SerialContext, out: &mut dyn Write) {
+    if let Some(timestamper) = &mut ctx.timestamper {
+        ctx.line_prefix = timestamper.prefix();
+        out.queue(Print(&ctx.line_prefix)).ok();
+    }
+}
+
+// The number of overruns since `previous`, if any have occurred
+fn new_overruns(previous: Option<u32>, current: Option<u32>) -> Option<u32> {
+    match (previous, current) {
+        (Some(previous), Some(current)) if current > previous => Some(current - previous),
+        _ => None,
+    }
+}
+
+// Notify the user that received data has been lost, on a line of its own
+fn print_overrun_notice(lost: u32, out: &mut dyn Write) {
+    out.queue(PrintStyledContent(
+        format!(
+            "\r\n[{lost} overrun(s), received data was lost; try `--flow-control` or a lower baud rate]\r\n"
+        )
+        .yellow(),
+    ))
+    .ok();
+    out.flush().ok();
+}
+
+// Display input sent to the device, as the device would if it echoed its input.
+// Escape sequences, eg. for the arrow keys, are not displayed.
+fn echo_input(bytes: &[u8], eol: LineEnding, out: &mut dyn Write) {
+    let text = String::from_utf8_lossy(bytes).replace(eol.as_str(), "\r\n");
+
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x1b' => {
+                // Skip the remainder of the escape sequence
+                for ch in chars.by_ref() {
+                    if ch.is_ascii_alphabetic() || ch == '~' {
+                        break;
+                    }
+                }
+            }
+            '\x08' => {
+                out.write_all(b"\x08 \x08").ok();
+            }
+            ch if ch.is_control() && !matches!(ch, '\r' | '\n' | '\t') => {}
+            ch => {
+                out.queue(Print(ch)).ok();
+            }
+        }
+    }
+
+    out.flush().ok();
+}
+
+// Converts pasted text into the bytes to send, using the configured line ending
+fn handle_paste(text: &str, eol: LineEnding) -> Vec<u8> {
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', eol.as_str())
+        .into_bytes()
+}
+
 // Converts key events from crossterm into appropriate character/escape
 // sequences which are then sent over the serial connection.
 //
 // Adapted from: https://github.com/dhylands/serial-monitor
-fn handle_key_event(key_event: KeyEvent) -> Option<Vec<u8>> {
+fn handle_key_event(key_event: KeyEvent, eol: LineEnding) -> Option<Vec<u8>> {
     // The following escape sequences come from the MicroPython codebase.
     //
     //  Up      ESC [A
@@ -241,7 +735,7 @@ fn handle_key_event(key_event: KeyEvent) -> Option<Vec<u8>> {
 
     let key_str: Option<&[u8]> = match key_event.code {
         KeyCode::Backspace => Some(b"\x08"),
-        KeyCode::Enter => Some(b"\r"),
+        KeyCode::Enter => Some(eol.as_str().as_bytes()),
         KeyCode::Left => Some(b"\x1b[D"),
         KeyCode::Right => Some(b"\x1b[C"),
         KeyCode::Home => Some(b"\x1b[H"),
@@ -275,3 +769,86 @@ fn handle_key_event(key_event: KeyEvent) -> Option<Vec<u8>> {
 
     key_str.map(|slice| slice.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paste_and_echo() {
+        let pasted = handle_paste("help\r\nreset\nstatus\r", LineEnding::Crlf);
+        assert_eq!(pasted, b"help\r\nreset\r\nstatus\r\n");
+        assert_eq!(handle_paste("a\r\nb", LineEnding::Lf), b"a\nb");
+
+        let mut out = Vec::new();
+        echo_input(b"ls\x1b[A\x08\r", LineEnding::Cr, &mut out);
+        assert_eq!(out, b"ls\x08 \x08\r\n");
+    }
+
+    #[test]
+    fn test_new_overruns() {
+        assert_eq!(new_overruns(Some(2), Some(5)), Some(3));
+        assert_eq!(new_overruns(Some(2), Some(2)), None);
+        assert_eq!(new_overruns(None, Some(2)), None);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut ctx = SerialContext::new(Chip::Esp32, None, false, Hooks::default());
+        ctx.timestamper = Some(Timestamper::new(Timestamps::Delta));
+
+        let mut out = Vec::new();
+        handle_serial(&mut ctx, b"I (31) bo", &mut out);
+        handle_serial(&mut ctx, b"ot: ok\r\nI (32) ", &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("[+   0.000] I (31) boot: ok\r\n[+"));
+        assert_eq!(out.matches("[+").count(), 2);
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let mut ctx = SerialContext::new(Chip::Esp32, None, false, Hooks::default());
+        ctx.filter = LogFilter::new(Some(LogLevel::Info), &[]);
+        ctx.strip_colors = true;
+
+        let mut out = Vec::new();
+        handle_serial(
+            &mut ctx,
+            b"\x1b[0;32mI (31) boot: ok\x1b[0m\r\nD (3",
+            &mut out,
+        );
+        handle_serial(&mut ctx, b"2) wifi: hidden\r\nW (33) wifi: sh", &mut out);
+        handle_serial(&mut ctx, b"own\r\n> ", &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "I (31) boot: ok\r\nW (33) wifi: shown\r\n> "
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let hooks = crate::cli::config::Monitor {
+            hooks: vec![MonitorHook {
+                pattern: "panic'ed".into(),
+                exit_code: Some(3),
+                ..MonitorHook::default()
+            }],
+            disable_default_hooks: false,
+        }
+        .hooks();
+        let mut ctx = SerialContext::new(Chip::Esp32, None, false, Hooks::new(&hooks));
+
+        let mut out = Vec::new();
+        handle_serial(&mut ctx, b"boot\r\nGuru Meditation", &mut out);
+        assert_eq!(out, b"boot\r\nGuru Meditation");
+        assert_eq!(ctx.exit_code, None);
+
+        out.clear();
+        handle_serial(&mut ctx, b" Error: Core 0 panic'ed\r\n", &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with('\r'));
+        assert!(out.contains("Guru Meditation Error: Core 0 panic'ed"));
+        assert!(out.ends_with("\x07\r\n"));
+        assert_eq!(ctx.exit_code, Some(3));
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    io::{stdout, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor::{MoveTo, MoveUp, RestorePosition, SavePosition},
+    style::{Print, PrintStyledContent, Stylize},
+    terminal::{self, Clear, ClearType},
+    tty::IsTty,
+    QueueableCommand,
+};
+
+/// How often the status line is redrawn, to keep the elapsed time current
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection information displayed on the bottom row of the terminal
+///
+/// The rows above it are made into a scrolling region, so that the device's
+/// output scrolls without overwriting the status line. Nothing is displayed
+/// unless stdout is a terminal.
+pub(crate) struct StatusLine {
+    port: String,
+    baud: u32,
+    started: Instant,
+    received: u64,
+    reset_cause: Option<String>,
+    overruns: Option<u32>,
+    visible: bool,
+    drawn: Instant,
+}
+
+impl StatusLine {
+    pub fn new(port: String, baud: u32, overruns: Option<u32>) -> Self {
+        Self {
+            port,
+            baud,
+            started: Instant::now(),
+            received: 0,
+            reset_cause: None,
+            overruns: overruns.map(|_| 0),
+            visible: false,
+            drawn: Instant::now(),
+        }
+    }
+
+    /// Record that `count` bytes have been received
+    pub fn received(&mut self, count: usize) {
+        self.received += count as u64;
+    }
+
+    /// Record the cause of the device's most recent reset
+    pub fn set_reset_cause(&mut self, cause: String) {
+        self.reset_cause = Some(cause);
+    }
+
+    /// Record that `count` more overruns have occurred
+    pub fn add_overruns(&mut self, count: u32) {
+        self.overruns = Some(self.overruns.unwrap_or_default() + count);
+    }
+
+    /// Show or hide the status line
+    pub fn toggle(&mut self, out: &mut dyn Write) {
+        if self.visible {
+            self.hide(out);
+        } else {
+            self.show(out);
+        }
+    }
+
+    /// Show the status line, if stdout is a terminal
+    pub fn show(&mut self, out: &mut dyn Write) {
+        if !stdout().is_tty() {
+            return;
+        }
+
+        // Make sure the cursor is not on the bottom row before it is reserved,
+        // scrolling the output up if needed.
+        out.queue(Print('\n')).ok();
+        out.queue(MoveUp(1)).ok();
+
+        self.visible = true;
+        self.resize(out);
+    }
+
+    /// Hide the status line, returning the bottom row to the output
+    pub fn hide(&mut self, out: &mut dyn Write) {
+        if !self.visible {
+            return;
+        }
+        self.visible = false;
+
+        let rows = rows();
+        out.queue(SavePosition).ok();
+        out.queue(Print("\x1b[r")).ok();
+        out.queue(MoveTo(0, rows - 1)).ok();
+        out.queue(Clear(ClearType::CurrentLine)).ok();
+        out.queue(RestorePosition).ok();
+        out.flush().ok();
+    }
+
+    /// Reserve the bottom row again after the terminal has been resized
+    pub fn resize(&mut self, out: &mut dyn Write) {
+        if !self.visible {
+            return;
+        }
+
+        // Setting the scrolling region moves the cursor, so its position is
+        // restored afterwards.
+        out.queue(SavePosition).ok();
+        out.queue(Print(format!("\x1b[1;{}r", rows() - 1))).ok();
+        out.queue(RestorePosition).ok();
+        self.draw(out);
+    }
+
+    /// Redraw the status line, if it is visible and out of date
+    pub fn tick(&mut self, out: &mut dyn Write) {
+        if self.visible && self.drawn.elapsed() >= REDRAW_INTERVAL {
+            self.draw(out);
+        }
+    }
+
+    fn draw(&mut self, out: &mut dyn Write) {
+        self.drawn = Instant::now();
+
+        let columns = terminal::size().map(|(columns, _)| columns).unwrap_or(80);
+        out.queue(SavePosition).ok();
+        out.queue(MoveTo(0, rows() - 1)).ok();
+        out.queue(Clear(ClearType::CurrentLine)).ok();
+        out.queue(PrintStyledContent(
+            self.text(self.started.elapsed(), columns as usize)
+                .reverse(),
+        ))
+        .ok();
+        out.queue(RestorePosition).ok();
+        out.flush().ok();
+    }
+
+    /// The status line's text, padded or truncated to `width` characters
+    fn text(&self, elapsed: Duration, width: usize) -> String {
+        let secs = elapsed.as_secs();
+        let mut fields = vec![
+            self.port.clone(),
+            format!("{} baud", self.baud),
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+            format!("{} received", format_bytes(self.received)),
+        ];
+        if let Some(cause) = &self.reset_cause {
+            fields.push(format!("reset: {cause}"));
+        }
+        if let Some(overruns) = self.overruns {
+            fields.push(format!("{overruns} overruns"));
+        }
+
+        let text = format!(" {}", fields.join(" | "));
+        format!("{text:width$.width$}")
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        self.hide(&mut stdout());
+    }
+}
+
+fn rows() -> u16 {
+    terminal::size().map(|(_, rows)| rows).unwrap_or(24).max(2)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_text() {
+        let mut status = StatusLine::new("/dev/ttyUSB0".into(), 115_200, None);
+        status.received(2048);
+        status.set_reset_cause("power on".into());
+
+        assert_eq!(
+            status.text(Duration::from_secs(3725), 80).trim_end(),
+            " /dev/ttyUSB0 | 115200 baud | 01:02:05 | 2.0 KiB received | reset: power on"
+        );
+        assert_eq!(status.text(Duration::ZERO, 12), " /dev/ttyUSB");
+
+        status.add_overruns(3);
+        assert!(status.text(Duration::ZERO, 200).contains("| 3 overruns"));
+    }
+}
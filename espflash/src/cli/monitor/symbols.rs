@@ -1,25 +1,80 @@
-use std::error::Error;
+use std::{error::Error, ops::Range};
 
 use addr2line::{
     gimli::{EndianRcSlice, RunTimeEndian},
-    object::{read::File, Object},
+    object::{read::File, Object, ObjectSegment},
     Context,
 };
+use tracing::warn;
 
+/// Symbols loaded from one or more ELF files, such as the application and the
+/// second-stage bootloader
+///
+/// Addresses are looked up in the file which is loaded at that address, or in
+/// each file in turn if none is.
 pub(crate) struct Symbols<'sym> {
+    elfs: Vec<ElfSymbols<'sym>>,
+}
+
+impl<'sym> Symbols<'sym> {
+    /// Load the symbols from each of the ELF files `elfs`, or `None` if none
+    /// of them could be loaded
+    pub fn new(elfs: &[&'sym [u8]]) -> Option<Self> {
+        let elfs = elfs
+            .iter()
+            .filter_map(|bytes| match ElfSymbols::try_from(bytes) {
+                Ok(elf) => Some(elf),
+                Err(e) => {
+                    warn!("Failed to load symbols from an ELF file: {e}");
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        (!elfs.is_empty()).then_some(Self { elfs })
+    }
+
+    pub fn get_name(&self, addr: u64) -> Option<String> {
+        self.candidates(addr).find_map(|elf| elf.get_name(addr))
+    }
+
+    pub fn get_location(&self, addr: u64) -> Option<(String, u32)> {
+        self.candidates(addr).find_map(|elf| elf.get_location(addr))
+    }
+
+    // The file loaded at `addr`, or all of them if none is
+    fn candidates(&self, addr: u64) -> Box<dyn Iterator<Item = &ElfSymbols<'sym>> + '_> {
+        match self.elfs.iter().find(|elf| elf.contains(addr)) {
+            Some(elf) => Box::new(std::iter::once(elf)),
+            None => Box::new(self.elfs.iter()),
+        }
+    }
+}
+
+struct ElfSymbols<'sym> {
     file: File<'sym, &'sym [u8]>,
     ctx: Context<EndianRcSlice<RunTimeEndian>>,
+    ranges: Vec<Range<u64>>,
 }
 
-impl<'sym> Symbols<'sym> {
-    pub fn try_from(bytes: &'sym [u8]) -> Result<Self, Box<dyn Error>> {
+impl<'sym> ElfSymbols<'sym> {
+    fn try_from(bytes: &'sym [u8]) -> Result<Self, Box<dyn Error>> {
         let file = File::parse(bytes)?;
         let ctx = Context::new(&file)?;
+        let ranges = file
+            .segments()
+            .map(|segment| segment.address()..segment.address() + segment.size())
+            .filter(|range| !range.is_empty())
+            .collect();
 
-        Ok(Self { file, ctx })
+        Ok(Self { file, ctx, ranges })
     }
 
-    pub fn get_name(&self, addr: u64) -> Option<String> {
+    fn contains(&self, addr: u64) -> bool {
+        self.ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    fn get_name(&self, addr: u64) -> Option<String> {
         // The basic steps here are:
         //   1. find which frame `addr` is in
         //   2. look up and demangle the function name
@@ -44,7 +99,7 @@ impl<'sym> Symbols<'sym> {
             })
     }
 
-    pub fn get_location(&self, addr: u64) -> Option<(String, u32)> {
+    fn get_location(&self, addr: u64) -> Option<(String, u32)> {
         // Find the location which `addr` is in. If we can dedetermine a file name and
         // line number for this function we will return them both in a tuple.
         self.ctx.find_location(addr).ok()?.map(|location| {
@@ -58,3 +113,31 @@ impl<'sym> Symbols<'sym> {
         })?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_multiple_elfs() {
+        let esp32 = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let esp8266 = fs::read("tests/resources/esp8266_hal_blinky").unwrap();
+        let entry = File::parse(esp8266.as_slice()).unwrap().entry();
+
+        let symbols = Symbols::new(&[&esp32, &esp8266]).unwrap();
+        let elf = symbols
+            .candidates(entry)
+            .map(|elf| elf.file.entry())
+            .collect::<Vec<_>>();
+        assert_eq!(elf, [entry]);
+        assert_eq!(
+            symbols.get_name(entry),
+            Symbols::new(&[&esp8266]).unwrap().get_name(entry)
+        );
+        assert!(symbols.get_name(entry).is_some());
+
+        assert!(Symbols::new(&[b"not an ELF file"]).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+/// Time prefixed to each line of output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Timestamps {
+    /// Wall-clock time, in UTC
+    Absolute,
+    /// Time since the monitor was started
+    Relative,
+    /// Time since the previous line began
+    Delta,
+}
+
+/// Produces the prefix of each line, as it begins
+pub(crate) struct Timestamper {
+    kind: Timestamps,
+    started: Instant,
+    previous: Option<Instant>,
+}
+
+impl Timestamper {
+    pub fn new(kind: Timestamps) -> Self {
+        Self {
+            kind,
+            started: Instant::now(),
+            previous: None,
+        }
+    }
+
+    /// The prefix of a line which began now
+    pub fn prefix(&mut self) -> String {
+        self.prefix_at(Instant::now(), SystemTime::now())
+    }
+
+    fn prefix_at(&mut self, now: Instant, wall: SystemTime) -> String {
+        let previous = self.previous.replace(now);
+
+        match self.kind {
+            Timestamps::Absolute => {
+                let time = wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let secs = time.as_secs() % 86_400;
+                format!(
+                    "[{:02}:{:02}:{:02}.{:03}] ",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60,
+                    time.subsec_millis()
+                )
+            }
+            Timestamps::Relative => format!("[{:>9.3}] ", (now - self.started).as_secs_f64()),
+            Timestamps::Delta => format!(
+                "[+{:>8.3}] ",
+                previous
+                    .map_or(Duration::ZERO, |previous| now - previous)
+                    .as_secs_f64()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix() {
+        let wall = UNIX_EPOCH + Duration::from_millis(45_296_789);
+
+        let mut absolute = Timestamper::new(Timestamps::Absolute);
+        assert_eq!(absolute.prefix_at(Instant::now(), wall), "[12:34:56.789] ");
+
+        let mut relative = Timestamper::new(Timestamps::Relative);
+        let now = relative.started + Duration::from_millis(1500);
+        assert_eq!(relative.prefix_at(now, wall), "[    1.500] ");
+
+        let mut delta = Timestamper::new(Timestamps::Delta);
+        let now = delta.started;
+        assert_eq!(delta.prefix_at(now, wall), "[+   0.000] ");
+        assert_eq!(
+            delta.prefix_at(now + Duration::from_millis(12), wall),
+            "[+   0.012] "
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Listing the attached serial ports
+//!
+//! Along with the information reported by the operating system about each
+//! port's USB device, the ports may optionally be probed to determine which
+//! Espressif chip, if any, is attached to them. Probing resets the attached
+//! devices into their bootloaders, so is only done when requested.
+
+use clap::{Args, ValueEnum};
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Table};
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use tracing::debug;
+
+use super::serial::{bridge, detect_usb_serial_ports, usb_port_path};
+use crate::{
+    connection::RetryPolicy,
+    flasher::{ConnectOptions, Flasher},
+    interface::Interface,
+    targets::Chip,
+};
+
+/// List the attached serial ports
+#[derive(Debug, Args)]
+pub struct ListPortsArgs {
+    /// Format in which to display the ports
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = PortsFormat::Table)]
+    format: PortsFormat,
+    /// Connect to each port to detect the attached chip, which resets the
+    /// devices into their bootloaders
+    #[arg(long)]
+    probe: bool,
+}
+
+/// Format in which to display the attached serial ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PortsFormat {
+    /// A table of the ports
+    Table,
+    /// The same information as the table, as JSON
+    Json,
+}
+
+/// A serial port, and the USB device it belongs to
+#[derive(Debug, Serialize)]
+struct Port {
+    port: String,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+    usb_path: Option<String>,
+    /// The USB to UART bridge, if it is a known one
+    bridge: Option<&'static str>,
+    /// The attached chip, if the port was probed
+    chip: Option<String>,
+}
+
+/// Display the attached serial ports
+pub fn list_ports(args: &ListPortsArgs) -> Result<()> {
+    let ports = detect_usb_serial_ports()?
+        .into_iter()
+        .map(|port| {
+            let chip = if args.probe { probe(&port) } else { None };
+            Port::new(port, chip)
+        })
+        .collect::<Vec<_>>();
+
+    match args.format {
+        PortsFormat::Table => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+                .set_header(
+                    [
+                        "Port",
+                        "VID:PID",
+                        "Manufacturer",
+                        "Product",
+                        "Serial Number",
+                        "USB Path",
+                        "Chip",
+                    ]
+                    .map(|header| Cell::new(header).add_attribute(Attribute::Bold)),
+                );
+
+            for port in &ports {
+                let ids = match (port.vid, port.pid) {
+                    (Some(vid), Some(pid)) => format!("{vid:04x}:{pid:04x}"),
+                    _ => String::new(),
+                };
+                let chip = match &port.chip {
+                    Some(chip) => chip.clone(),
+                    None if args.probe => "none".into(),
+                    None => String::new(),
+                };
+
+                table.add_row(vec![
+                    port.port.clone(),
+                    ids,
+                    port.manufacturer.clone().unwrap_or_default(),
+                    port.product
+                        .clone()
+                        .or_else(|| port.bridge.map(String::from))
+                        .unwrap_or_default(),
+                    port.serial_number.clone().unwrap_or_default(),
+                    port.usb_path
+                        .as_ref()
+                        .map(|path| format!("usb:{path}"))
+                        .unwrap_or_default(),
+                    chip,
+                ]);
+            }
+
+            println!("{table}");
+        }
+        PortsFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&ports).into_diagnostic()?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl Port {
+    fn new(port: SerialPortInfo, chip: Option<Chip>) -> Self {
+        let usb_path = usb_port_path(&port.port_name);
+        let info = match port.port_type {
+            SerialPortType::UsbPort(info) => Some(info),
+            _ => None,
+        };
+
+        Self {
+            port: port.port_name,
+            vid: info.as_ref().map(|info| info.vid),
+            pid: info.as_ref().map(|info| info.pid),
+            bridge: info
+                .as_ref()
+                .and_then(|info| bridge(info.vid, info.pid))
+                .map(|(name, _)| name),
+            manufacturer: info.as_ref().and_then(|info| info.manufacturer.clone()),
+            product: info.as_ref().and_then(|info| info.product.clone()),
+            serial_number: info.and_then(|info| info.serial_number),
+            usb_path,
+            chip: chip.map(|chip| chip.to_string()),
+        }
+    }
+}
+
+/// Detect the chip attached to a serial port, resetting it afterwards so that
+/// it runs its application again
+fn probe(port: &SerialPortInfo) -> Option<Chip> {
+    let usb_info = match &port.port_type {
+        SerialPortType::UsbPort(info) => info.clone(),
+        _ => UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        },
+    };
+
+    let result = Interface::new(port, None, None, false).and_then(|interface| {
+        let options = ConnectOptions::default()
+            .use_stub(false)
+            .retry_policy(RetryPolicy::default().connect_attempts(2));
+        let mut flasher = Flasher::connect(interface, usb_info, options)?;
+        flasher.connection().reset()?;
+
+        Ok(flasher.chip())
+    });
+
+    match result {
+        Ok(chip) => Some(chip),
+        Err(err) => {
+            debug!("Failed to probe {}: {err:?}", port.port_name);
+            None
+        }
+    }
+}
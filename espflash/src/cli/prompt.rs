@@ -0,0 +1,123 @@
+//! Confirmation of destructive operations
+//!
+//! Operations such as erasing the flash or replacing the partition table on
+//! the device ask for confirmation first. Prompts are skipped, as though they
+//! had been accepted, when `--non-interactive` is given or there is no user to
+//! answer them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossterm::style::Stylize;
+use dialoguer::{console, theme::ColorfulTheme, Confirm};
+
+use crate::error::Error;
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Never ask for confirmation, eg. when running from a script
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Whether there is a user to answer prompts
+pub fn is_interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed) && console::user_attended()
+}
+
+/// Ask the user to confirm `prompt`, failing with [Error::Cancelled] if they
+/// decline
+pub fn confirm(prompt: &str) -> Result<(), Error> {
+    if !is_interactive() {
+        return Ok(());
+    }
+
+    if !Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .default(false)
+        .interact()?
+    {
+        return Err(Error::Cancelled);
+    }
+
+    Ok(())
+}
+
+/// Print the changes from `old` to `new`, line by line, marking removed lines
+/// with `-` and added lines with `+`
+pub fn print_diff(old: &str, new: &str) {
+    for change in diff_lines(old, new) {
+        match change {
+            Change::Removed(line) => println!("{}", format!("- {line}").red()),
+            Change::Added(line) => println!("{}", format!("+ {line}").green()),
+            Change::Unchanged(line) => println!("  {line}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Change<'a> {
+    Removed(&'a str),
+    Added(&'a str),
+    Unchanged(&'a str),
+}
+
+/// Compute the changes from `old` to `new`, using their longest common
+/// subsequence of lines
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..]
+    // and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut changes = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            changes.push(Change::Unchanged(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_lines() {
+        let old = "nvs,data,nvs,0x9000,0x6000\nfactory,app,factory,0x10000,0x100000\n";
+        let new = "nvs,data,nvs,0x9000,0x6000\nfactory,app,factory,0x10000,0x200000\n";
+
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                Change::Unchanged("nvs,data,nvs,0x9000,0x6000"),
+                Change::Removed("factory,app,factory,0x10000,0x100000"),
+                Change::Added("factory,app,factory,0x10000,0x200000"),
+            ]
+        );
+        assert!(diff_lines(old, old)
+            .iter()
+            .all(|change| matches!(change, Change::Unchanged(_))));
+    }
+}
@@ -0,0 +1,340 @@
+//! Scripts of operations to perform on a device
+//!
+//! A script is a TOML file listing steps to perform in order, such as writing
+//! binaries and verifying them, so that provisioning flows can be kept under
+//! version control. The device is connected to once, when the first step
+//! needing it is run, rather than once per operation:
+//!
+//! ```toml
+//! [vars]
+//! app = "build/app.bin"
+//! app_offset = 0x10000
+//!
+//! [[steps]]
+//! op = "erase-parts"
+//! parts = ["nvs", "otadata"]
+//!
+//! [[steps]]
+//! op = "write-bin"
+//! addr = "${app_offset}"
+//! file = "${app}"
+//!
+//! [[steps]]
+//! op = "verify"
+//! addr = "${app_offset}"
+//! file = "${app}"
+//!
+//! [[steps]]
+//! op = "reset"
+//! ```
+//!
+//! Variables are referenced as `${name}` in any string, and can be overridden
+//! from the command line. Relative paths are resolved from the script's
+//! directory.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{stdin, Read},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+use tracing::info;
+
+use super::{
+    config::Config, connect, erase_partitions, parse_partition_table, print_board_info, prompt,
+    ConnectArgs, EspflashProgress,
+};
+use crate::flasher::Flasher;
+
+/// Run a script of operations, such as a provisioning flow
+#[derive(Debug, Args)]
+pub struct ScriptArgs {
+    /// TOML file containing the script, or '-' to read it from stdin
+    file: PathBuf,
+    /// Set a variable, overriding its value in the script
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+/// A script of operations to perform on a device
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Script {
+    /// Variables which can be referenced by the steps
+    #[serde(default)]
+    vars: HashMap<String, toml::Value>,
+    /// Operations to perform, in order
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+/// A single operation in a script
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+enum Step {
+    /// Connect to the device, reconnecting if it is already connected
+    Connect { baud: Option<u32> },
+    /// Erase partitions by label, using the given partition table or else the
+    /// one on the device
+    EraseParts {
+        parts: Vec<String>,
+        #[serde(rename = "partition-table")]
+        partition_table: Option<String>,
+    },
+    /// Write a binary file to flash
+    WriteBin { addr: Number, file: String },
+    /// Check that the contents of flash match a binary file
+    Verify { addr: Number, file: String },
+    /// Reset the device, so that it runs its application
+    Reset,
+}
+
+/// An integer, or a string containing one such as `"0x10000"` or `"${var}"`
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Int(u32),
+    Str(String),
+}
+
+/// Run a script of operations
+pub fn script(args: ScriptArgs, config: &Config) -> Result<()> {
+    let (text, base_dir) = if args.file == Path::new("-") {
+        let mut text = String::new();
+        stdin()
+            .read_to_string(&mut text)
+            .into_diagnostic()
+            .wrap_err("Failed to read the script from stdin")?;
+
+        (text, PathBuf::new())
+    } else {
+        let text = fs::read_to_string(&args.file)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read script {}", args.file.display()))?;
+        let base_dir = args.file.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        (text, base_dir)
+    };
+
+    let script: Script = toml::from_str(&text)
+        .into_diagnostic()
+        .wrap_err("Failed to parse the script")?;
+    let vars = script.variables(args.vars)?;
+
+    prompt::confirm(&format!(
+        "Run the {} steps of the script, which may overwrite data on the device?",
+        script.steps.len()
+    ))?;
+
+    let mut runner = Runner {
+        args: &args.connect_args,
+        config,
+        vars,
+        base_dir,
+        flasher: None,
+    };
+    for (i, step) in script.steps.iter().enumerate() {
+        runner
+            .run(step)
+            .wrap_err_with(|| format!("Step {} of the script failed", i + 1))?;
+    }
+
+    Ok(())
+}
+
+impl Script {
+    /// The script's variables, with those set on the command line taking
+    /// precedence
+    fn variables(&self, overrides: Vec<(String, String)>) -> Result<HashMap<String, String>> {
+        let mut vars = HashMap::new();
+        for (name, value) in &self.vars {
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!("Variable '{name}' must be a string, integer or boolean"),
+            };
+            vars.insert(name.clone(), value);
+        }
+        vars.extend(overrides);
+
+        Ok(vars)
+    }
+}
+
+struct Runner<'a> {
+    args: &'a ConnectArgs,
+    config: &'a Config,
+    vars: HashMap<String, String>,
+    base_dir: PathBuf,
+    flasher: Option<Flasher>,
+}
+
+impl Runner<'_> {
+    fn run(&mut self, step: &Step) -> Result<()> {
+        match step {
+            Step::Connect { baud } => {
+                info!("Connecting to the device");
+                // The serial port must be released before it can be opened again
+                self.flasher = None;
+                let flasher = self.flasher()?;
+                if let Some(baud) = baud {
+                    flasher.change_baud(*baud)?;
+                }
+            }
+            Step::EraseParts {
+                parts,
+                partition_table,
+            } => {
+                info!("Erasing partitions: {}", parts.join(", "));
+                let partition_table = match partition_table {
+                    Some(path) => Some(parse_partition_table(&self.path(path)?)?),
+                    None => self.flasher()?.read_partition_table()?,
+                };
+                erase_partitions(self.flasher()?, partition_table, Some(parts.clone()), None)?;
+            }
+            Step::WriteBin { addr, file } => {
+                let (addr, path) = (self.number(addr)?, self.path(file)?);
+                info!("Writing {} to {:#x}", path.display(), addr);
+                let data = read_file(&path)?;
+                self.flasher()?.write_bin_to_flash(
+                    addr,
+                    &data,
+                    Some(&mut EspflashProgress::default()),
+                )?;
+            }
+            Step::Verify { addr, file } => {
+                let (addr, path) = (self.number(addr)?, self.path(file)?);
+                info!("Verifying {} at {:#x}", path.display(), addr);
+                let data = read_file(&path)?;
+                self.flasher()?.verify_flash(addr, &data)?;
+            }
+            Step::Reset => {
+                info!("Resetting the device");
+                self.flasher()?.connection().reset()?;
+                // The device is no longer in its bootloader
+                self.flasher = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The connection to the device, connecting to it if needed
+    fn flasher(&mut self) -> Result<&mut Flasher> {
+        if self.flasher.is_none() {
+            let mut flasher = connect(self.args, self.config)?;
+            print_board_info(&mut flasher)?;
+            // The device remains in its bootloader until a reset step
+            flasher.set_reset_after_flash(false);
+            self.flasher = Some(flasher);
+        }
+
+        Ok(self.flasher.as_mut().unwrap())
+    }
+
+    fn path(&self, path: &str) -> Result<PathBuf> {
+        Ok(self.base_dir.join(expand(path, &self.vars)?))
+    }
+
+    fn number(&self, number: &Number) -> Result<u32> {
+        match number {
+            Number::Int(value) => Ok(*value),
+            Number::Str(text) => {
+                let text = expand(text, &self.vars)?;
+                parse_int::parse(&text)
+                    .map_err(|_| miette!("'{text}' is not a valid unsigned 32-bit integer"))
+            }
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))
+}
+
+/// Replace each reference to a variable in `text`, eg. `${name}`, with its
+/// value
+fn expand(text: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unterminated variable reference in '{text}'");
+        };
+        let name = &rest[start + 2..start + len];
+        let value = vars
+            .get(name)
+            .ok_or_else(|| miette!("Undefined variable '{name}' in '{text}'"))?;
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(value);
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn parse_var(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("'{input}' is not of the form NAME=VALUE")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let script: Script = toml::from_str(
+            r#"
+            [vars]
+            app = "app.bin"
+            offset = 0x10000
+
+            [[steps]]
+            op = "write-bin"
+            addr = "${offset}"
+            file = "build/${app}"
+
+            [[steps]]
+            op = "reset"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            script.steps,
+            [
+                Step::WriteBin {
+                    addr: Number::Str("${offset}".into()),
+                    file: "build/${app}".into()
+                },
+                Step::Reset
+            ]
+        );
+
+        let vars = script
+            .variables(vec![("app".into(), "factory.bin".into())])
+            .unwrap();
+        assert_eq!(expand("build/${app}", &vars).unwrap(), "build/factory.bin");
+        assert_eq!(expand("${offset}", &vars).unwrap(), "65536");
+        assert!(expand("${missing}", &vars).is_err());
+        assert!(expand("${app", &vars).is_err());
+
+        assert!(toml::from_str::<Script>("[[steps]]\nop = \"connect\"\nspeed = 1").is_err());
+        assert!(parse_var("=value").is_err());
+    }
+}
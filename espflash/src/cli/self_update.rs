@@ -0,0 +1,219 @@
+//! Update the application to the latest release published on GitHub
+//!
+//! Only the targets for which prebuilt binaries are published can be updated;
+//! applications installed using `cargo install` should be updated the same
+//! way. The downloaded archive is verified against the SHA-256 checksum which
+//! is published alongside it before the current executable is replaced.
+
+use std::{
+    env::{self, consts::EXE_SUFFIX},
+    fs,
+    io::{Cursor, Read},
+};
+
+use clap::Args;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::prompt;
+
+/// Latest release of the application
+const RELEASES_URL: &str = "https://api.github.com/repos/esp-rs/espflash/releases/latest";
+
+/// Update to the latest release
+#[derive(Debug, Args)]
+pub struct SelfUpdateArgs {
+    /// Only check whether a new version is available
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn download(&self, name: &str) -> Result<Vec<u8>> {
+        let asset = self
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| miette!("Release {} does not include {}", self.tag_name, name))?;
+
+        info!("Downloading {}", asset.browser_download_url);
+        let mut data = Vec::new();
+        ureq::get(&asset.browser_download_url)
+            .call()
+            .into_diagnostic()?
+            .into_reader()
+            .read_to_end(&mut data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to download {name}"))?;
+
+        Ok(data)
+    }
+}
+
+/// Update the application `name`, whose current version is `version`, to the
+/// latest release
+pub fn self_update(args: &SelfUpdateArgs, name: &str, version: &str) -> Result<()> {
+    let target = match release_target() {
+        Some(target) => target,
+        None => bail!("Prebuilt binaries are not published for this platform, please update {name} using `cargo install`"),
+    };
+
+    let release: Release = ureq::get(RELEASES_URL)
+        .set("User-Agent", name)
+        .call()
+        .into_diagnostic()?
+        .into_json()
+        .into_diagnostic()
+        .wrap_err("Failed to check for the latest release")?;
+
+    let latest = Version::parse(release.tag_name.trim_start_matches('v')).into_diagnostic()?;
+    if latest <= Version::parse(version).into_diagnostic()? {
+        info!("{name} {version} is up to date");
+        return Ok(());
+    }
+
+    info!("A new version of {name} is available: {latest}");
+    if args.check {
+        return Ok(());
+    }
+
+    prompt::confirm(&format!("Update {name} from {version} to {latest}?"))?;
+
+    let archive_name = format!("{name}-{target}.zip");
+    let archive = release.download(&archive_name)?;
+    let checksum = release.download(&format!("{archive_name}.sha256"))?;
+    verify_checksum(&archive, &String::from_utf8_lossy(&checksum))?;
+
+    let binary = extract(&archive, &format!("{name}{EXE_SUFFIX}"))?;
+    replace_current_exe(&binary)?;
+
+    info!("Updated {name} to {latest}");
+
+    Ok(())
+}
+
+/// The target triple of the release archive matching this build, if one is
+/// published
+fn release_target() -> Option<&'static str> {
+    if cfg!(all(
+        target_arch = "x86_64",
+        target_os = "linux",
+        target_env = "gnu"
+    )) {
+        Some("x86_64-unknown-linux-gnu")
+    } else if cfg!(all(
+        target_arch = "x86_64",
+        target_os = "linux",
+        target_env = "musl"
+    )) {
+        Some("x86_64-unknown-linux-musl")
+    } else if cfg!(all(
+        target_arch = "aarch64",
+        target_os = "linux",
+        target_env = "gnu"
+    )) {
+        Some("aarch64-unknown-linux-gnu")
+    } else if cfg!(all(
+        target_arch = "arm",
+        target_os = "linux",
+        target_env = "gnu"
+    )) {
+        Some("armv7-unknown-linux-gnueabihf")
+    } else if cfg!(all(target_arch = "x86_64", target_os = "macos")) {
+        Some("x86_64-apple-darwin")
+    } else if cfg!(all(target_arch = "aarch64", target_os = "macos")) {
+        Some("aarch64-apple-darwin")
+    } else if cfg!(all(target_arch = "x86_64", target_os = "windows")) {
+        Some("x86_64-pc-windows-msvc")
+    } else {
+        None
+    }
+}
+
+/// Verify `data` against a checksum file in the format output by `sha256sum`
+fn verify_checksum(data: &[u8], checksum: &str) -> Result<()> {
+    let expected = checksum
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| miette!("The checksum file is empty"))?;
+    let actual = Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("Checksum mismatch, expected {expected} but the download has {actual}");
+    }
+
+    Ok(())
+}
+
+/// Extract the file `name` from the zip archive `archive`
+fn extract(archive: &[u8], name: &str) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(archive)).into_diagnostic()?;
+    let mut file = archive
+        .by_name(name)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("The archive does not contain {name}"))?;
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).into_diagnostic()?;
+
+    Ok(data)
+}
+
+/// Replace the running executable with `binary`
+///
+/// The new binary is written alongside the current one and then renamed over
+/// it. Windows does not allow a running executable to be replaced, however it
+/// can be renamed out of the way first.
+fn replace_current_exe(binary: &[u8]) -> Result<()> {
+    let exe = env::current_exe().into_diagnostic()?;
+    let new = exe.with_extension("new");
+
+    fs::write(&new, binary)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", new.display()))?;
+    fs::set_permissions(&new, fs::metadata(&exe).into_diagnostic()?.permissions())
+        .into_diagnostic()?;
+
+    if cfg!(windows) {
+        let old = exe.with_extension("old");
+        fs::remove_file(&old).ok();
+        fs::rename(&exe, &old).into_diagnostic()?;
+    }
+
+    fs::rename(&new, &exe)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to replace {}", exe.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_checksum() {
+        let checksum =
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  hello.zip\n";
+
+        assert!(verify_checksum(b"hello", checksum).is_ok());
+        assert!(verify_checksum(b"hello!", checksum).is_err());
+        assert!(verify_checksum(b"hello", "").is_err());
+    }
+}
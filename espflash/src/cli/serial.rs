@@ -1,16 +1,25 @@
-use std::cmp::Ordering;
 #[cfg(not(target_os = "windows"))]
 use std::fs;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::{
+    cmp::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
 
 use crossterm::style::Stylize;
 use dialoguer::{theme::ColorfulTheme, Confirm, Select};
-use log::{error, info};
 use miette::{IntoDiagnostic, Result};
 use serialport::{available_ports, SerialPortInfo, SerialPortType, UsbPortInfo};
+use tracing::{error, info};
 
-use super::{config::Config, ConnectArgs};
+use super::{config::Config, prompt, ConnectArgs};
 use crate::{cli::config::UsbDevice, error::Error};
 
+/// How often to check for the device while waiting for it to be connected
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 pub fn get_serial_port_info(
     matches: &ConnectArgs,
     config: &Config,
@@ -30,17 +39,22 @@ pub fn get_serial_port_info(
     // doesn't work (on Windows) with "dummy" device paths like `COM4`. That's
     // the reason we need to handle Windows/Posix differently.
 
-    let ports = detect_usb_serial_ports().unwrap_or_default();
+    let ports = match matches.wait_for_device {
+        Some(timeout) => wait_for_device(matches, config, timeout.map(Duration::from_secs)),
+        None => detect_usb_serial_ports().unwrap_or_default(),
+    };
 
     if let Some(serial) = &matches.port {
         find_serial_port(&ports, serial)
+    } else if let Some(serial_number) = &matches.serial_number {
+        find_serial_number(ports, serial_number, config)
     } else if let Some(serial) = &config.connection.serial {
         find_serial_port(&ports, serial)
     } else {
         let (port, matches) = select_serial_port(ports, config)?;
 
         match &port.port_type {
-            SerialPortType::UsbPort(usb_info) if !matches => {
+            SerialPortType::UsbPort(usb_info) if !matches && prompt::is_interactive() => {
                 let remember = Confirm::with_theme(&ColorfulTheme::default())
                     .with_prompt("Remember this serial port for future use?")
                     .interact_opt()?
@@ -66,9 +80,65 @@ pub fn get_serial_port_info(
     }
 }
 
+/// Wait for the selected device to be connected, or if no device has been
+/// selected for one matching a known dev board, returning the attached serial
+/// ports once it is or the timeout elapses
+fn wait_for_device(
+    matches: &ConnectArgs,
+    config: &Config,
+    timeout: Option<Duration>,
+) -> Vec<SerialPortInfo> {
+    let start = Instant::now();
+    let mut waiting = false;
+
+    loop {
+        let ports = detect_usb_serial_ports().unwrap_or_default();
+        let found = if let Some(serial) = &matches.port {
+            find_serial_port(&ports, serial).is_ok()
+        } else if let Some(serial_number) = &matches.serial_number {
+            ports.iter().any(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => {
+                    info.serial_number.as_deref() == Some(serial_number.as_str())
+                }
+                _ => false,
+            })
+        } else if let Some(serial) = &config.connection.serial {
+            find_serial_port(&ports, serial).is_ok()
+        } else {
+            ports.iter().any(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => device_matches(config, info),
+                _ => false,
+            })
+        };
+
+        if found || timeout.map_or(false, |timeout| start.elapsed() >= timeout) {
+            return ports;
+        }
+        if !waiting {
+            info!("Waiting for the device to be connected...");
+            waiting = true;
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
 /// Given a vector of `SerialPortInfo` structs, attempt to find and return one
 /// whose `port_name` field matches the provided `name` argument.
 fn find_serial_port(ports: &[SerialPortInfo], name: &str) -> Result<SerialPortInfo, Error> {
+    // Ports may also be addressed by the path of the physical USB port which they
+    // are connected to, eg. `usb:1-3.2`
+    if let Some(path) = name.strip_prefix("usb:") {
+        if cfg!(not(target_os = "linux")) {
+            return Err(Error::UsbPortPathUnsupported);
+        }
+
+        return ports
+            .iter()
+            .find(|port| usb_port_path(&port.port_name).as_deref() == Some(path))
+            .cloned()
+            .ok_or_else(|| Error::SerialNotFound(name.to_string()));
+    }
+
     #[cfg(not(target_os = "windows"))]
     let name = fs::canonicalize(name)?;
     #[cfg(not(target_os = "windows"))]
@@ -85,11 +155,54 @@ fn find_serial_port(ports: &[SerialPortInfo], name: &str) -> Result<SerialPortIn
     }
 }
 
+/// Find the serial port of the USB device with the given serial number,
+/// prompting the user to select one if the device has several
+fn find_serial_number(
+    ports: Vec<SerialPortInfo>,
+    serial_number: &str,
+    config: &Config,
+) -> Result<SerialPortInfo, Error> {
+    let mut ports = ports
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => info.serial_number.as_deref() == Some(serial_number),
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+
+    match ports.len() {
+        0 => Err(Error::SerialNumberNotFound(serial_number.to_string())),
+        1 => Ok(ports.remove(0)),
+        _ => Ok(select_serial_port(ports, config)?.0),
+    }
+}
+
+/// The path of the physical USB port which a serial port's device is connected
+/// to, such as `1-3.2` for port 2 of the hub on port 3 of bus 1
+#[cfg(target_os = "linux")]
+pub(super) fn usb_port_path(port_name: &str) -> Option<String> {
+    let name = Path::new(port_name).file_name()?;
+    let device = fs::canonicalize(Path::new("/sys/class/tty").join(name).join("device")).ok()?;
+
+    // The closest ancestor which is a USB device, rather than an interface
+    device
+        .ancestors()
+        .find(|dir| dir.join("busnum").exists() && dir.join("devpath").exists())?
+        .file_name()?
+        .to_str()
+        .map(String::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn usb_port_path(_port_name: &str) -> Option<String> {
+    None
+}
+
 /// serialport's autodetect doesn't provide any port information when using musl
 /// linux we can do some manual parsing of sysfs to get the relevant bits
 /// without udev
 #[cfg(all(target_os = "linux", target_env = "musl"))]
-fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
+pub(super) fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
     use std::{
         fs::{read_link, read_to_string},
         path::PathBuf,
@@ -132,7 +245,9 @@ fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
                     vid: u16::from_str_radix(vid.trim(), 16).ok()?,
                     pid: u16::from_str_radix(pid.trim(), 16).ok()?,
                     product: interface,
-                    serial_number: None,
+                    serial_number: read_to_string(parent_dev.join("serial"))
+                        .ok()
+                        .map(|s| s.trim().to_string()),
                     manufacturer: None,
                 }),
                 port_name: format!("/dev/{}", path.file_name()?.to_str()?),
@@ -144,7 +259,7 @@ fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
 }
 
 #[cfg(not(all(target_os = "linux", target_env = "musl")))]
-fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
+pub(super) fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
     let ports = available_ports().into_diagnostic()?;
     let ports = ports
         .into_iter()
@@ -164,6 +279,26 @@ fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
     Ok(ports)
 }
 
+/// The attached serial ports, along with a description of each, for use when
+/// completing the `--port` option
+pub(super) fn port_candidates() -> Vec<(String, String)> {
+    detect_usb_serial_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|port| {
+            let description = match &port.port_type {
+                SerialPortType::UsbPort(info) => match &info.product {
+                    Some(product) => format!("{} ({:04x}:{:04x})", product, info.vid, info.pid),
+                    None => format!("{:04x}:{:04x}", info.vid, info.pid),
+                },
+                _ => String::new(),
+            };
+
+            (port.port_name, description)
+        })
+        .collect()
+}
+
 /// USB UART adapters which are known to be on common dev boards
 const KNOWN_DEVICES: &[UsbDevice] = &[
     UsbDevice {
@@ -176,18 +311,38 @@ const KNOWN_DEVICES: &[UsbDevice] = &[
     }, // QinHeng Electronics CH340 serial converter
 ];
 
+/// USB to UART bridges found on common development boards, and the Linux
+/// drivers which support them
+const BRIDGES: &[(u16, u16, &str, &str)] = &[
+    (0x10c4, 0xea60, "Silicon Labs CP210x", "cp210x"),
+    (0x1a86, 0x7523, "WCH CH340", "ch341"),
+    (0x1a86, 0x55d4, "WCH CH9102", "cdc_acm"),
+    (0x0403, 0x6001, "FTDI FT232R", "ftdi_sio"),
+    (0x0403, 0x6010, "FTDI FT2232", "ftdi_sio"),
+    (0x303a, 0x1001, "Espressif USB-JTAG-Serial", "cdc_acm"),
+];
+
+/// The name and Linux driver of a known USB to UART bridge
+pub(super) fn bridge(vid: u16, pid: u16) -> Option<(&'static str, &'static str)> {
+    BRIDGES
+        .iter()
+        .find(|bridge| (bridge.0, bridge.1) == (vid, pid))
+        .map(|bridge| (bridge.2, bridge.3))
+}
+
+/// Whether a USB device is configured, or known to be on common dev boards
+fn device_matches(config: &Config, info: &UsbPortInfo) -> bool {
+    config
+        .usb_device
+        .iter()
+        .chain(KNOWN_DEVICES.iter())
+        .any(|dev| dev.matches(info))
+}
+
 fn select_serial_port(
     mut ports: Vec<SerialPortInfo>,
     config: &Config,
 ) -> Result<(SerialPortInfo, bool), Error> {
-    fn device_matches(config: &Config, info: &UsbPortInfo) -> bool {
-        config
-            .usb_device
-            .iter()
-            .chain(KNOWN_DEVICES.iter())
-            .any(|dev| dev.matches(info))
-    }
-
     if ports.len() > 1 {
         // Multiple serial ports detected
         info!("Detected {} serial ports", ports.len());
@@ -274,7 +429,7 @@ fn select_serial_port(
 
         if device_matches(config, port_info) {
             Ok((port.to_owned(), true))
-        } else if confirm_port(&port_name, port_info)? {
+        } else if !prompt::is_interactive() || confirm_port(&port_name, port_info)? {
             Ok((port.to_owned(), false))
         } else {
             Err(Error::SerialNotFound(port_name))
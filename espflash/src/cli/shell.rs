@@ -0,0 +1,261 @@
+//! Interactive shell which keeps the connection to a device open
+//!
+//! Connecting to a device and synchronizing with its bootloader can take
+//! several seconds on some chips, which adds up when running many commands.
+//! The shell connects once, when the first command needing the device is run,
+//! and then runs each command over the same connection. The device remains in
+//! its bootloader until it is reset.
+
+use std::{
+    fs,
+    io::{stdin, stdout, BufRead, Write},
+    path::PathBuf,
+};
+
+use clap::{Args, Parser, Subcommand};
+use crossterm::tty::IsTty;
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+use tracing::info;
+
+use super::{
+    check_partition_table, config::Config, connect, flash_app_bin, flash_elf_image,
+    parse_partition_table, print_board_info, read_bootloader, ConnectArgs, EspflashProgress,
+};
+use crate::{
+    error::Error,
+    flasher::{FlashData, Flasher},
+    image_format::is_app_image,
+};
+
+/// Open an interactive shell which runs commands over a single connection
+#[derive(Debug, Args)]
+pub struct ShellArgs {
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+// A line entered in the shell
+#[derive(Debug, Parser)]
+#[command(
+    no_binary_name = true,
+    disable_version_flag = true,
+    override_usage = "<COMMAND> [ARGS]...",
+    help_template = "{usage-heading} {usage}\n\n{all-args}"
+)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: ShellCommand,
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+enum ShellCommand {
+    /// Display information about the connected board
+    BoardInfo,
+    /// Flash an ELF image or pre-built application binary
+    Flash {
+        /// ELF image or pre-built application binary (.bin) to flash
+        image: PathBuf,
+        /// Path to a binary (.bin) bootloader file
+        #[arg(long, value_name = "FILE")]
+        bootloader: Option<PathBuf>,
+        /// Path to a CSV file containing partition table
+        #[arg(long, value_name = "FILE")]
+        partition_table: Option<PathBuf>,
+    },
+    /// Save the contents of a region of flash to a file
+    ReadFlash {
+        /// Offset of the region
+        #[arg(value_parser = parse_int::parse::<u32>)]
+        addr: u32,
+        /// Size of the region, in bytes
+        #[arg(value_parser = parse_int::parse::<u32>)]
+        size: u32,
+        /// File to save the contents to
+        file: PathBuf,
+    },
+    /// Write a binary file to flash
+    WriteBin {
+        /// Address at which to write the binary file
+        #[arg(value_parser = parse_int::parse::<u32>)]
+        addr: u32,
+        /// File containing the binary data to write
+        file: PathBuf,
+    },
+    /// Reset the device, so that it runs its application
+    ///
+    /// The next command which needs the device connects to it again.
+    Reset,
+    /// Close the connection and exit the shell
+    #[command(alias = "quit")]
+    Exit,
+}
+
+/// Run commands read from stdin over a single connection to the device
+pub fn shell(args: ShellArgs, config: &Config) -> Result<()> {
+    let interactive = stdin().is_tty();
+    if interactive {
+        println!("Type 'help' for a list of commands, or 'exit' to quit");
+    }
+
+    let mut flasher = None;
+    let mut lines = stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("espflash> ");
+            stdout().flush().into_diagnostic()?;
+        }
+
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.into_diagnostic()?;
+        let words = match split_words(&line) {
+            Ok(words) if words.is_empty() => continue,
+            Ok(words) => words,
+            Err(err) => {
+                eprintln!("{err:?}");
+                continue;
+            }
+        };
+
+        // Help and invalid commands are displayed rather than exiting
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(line) => line.command,
+            Err(err) => {
+                err.print().ok();
+                continue;
+            }
+        };
+        if command == ShellCommand::Exit {
+            break;
+        }
+
+        if let Err(err) = run(&command, &mut flasher, &args.connect_args, config) {
+            // The next command connects again if the connection was lost
+            if let Some(Error::Connection(_) | Error::Flashing(_)) = err.downcast_ref() {
+                flasher = None;
+            }
+            eprintln!("{err:?}");
+        }
+    }
+
+    Ok(())
+}
+
+fn run(
+    command: &ShellCommand,
+    flasher: &mut Option<Flasher>,
+    args: &ConnectArgs,
+    config: &Config,
+) -> Result<()> {
+    if flasher.is_none() {
+        let mut connected = connect(args, config)?;
+        // The device remains in its bootloader until it is reset
+        connected.set_reset_after_flash(false);
+        *flasher = Some(connected);
+    }
+    let connected = flasher.as_mut().unwrap();
+
+    match command {
+        ShellCommand::BoardInfo => print_board_info(connected)?,
+        ShellCommand::Flash {
+            image,
+            bootloader,
+            partition_table,
+        } => {
+            let data = fs::read(image)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open image {}", image.display()))?;
+
+            check_partition_table(connected, partition_table.as_deref(), false);
+            let partition_table = match partition_table {
+                Some(path) => Some(parse_partition_table(path)?),
+                None => None,
+            };
+
+            let flash_data = FlashData::builder()
+                .bootloader(read_bootloader(bootloader.as_deref())?)
+                .partition_table(partition_table)
+                .build();
+
+            if is_app_image(&data) {
+                flash_app_bin(connected, &data, flash_data)?;
+            } else {
+                flash_elf_image(connected, &data, flash_data, None)?;
+            }
+        }
+        ShellCommand::ReadFlash { addr, size, file } => {
+            let data =
+                connected.read_flash(*addr, *size, Some(&mut EspflashProgress::default()))?;
+            fs::write(file, data)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write {}", file.display()))?;
+            info!("Saved {size} bytes from {addr:#x} to {}", file.display());
+        }
+        ShellCommand::WriteBin { addr, file } => {
+            let data = fs::read(file)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+            connected.write_bin_to_flash(*addr, &data, Some(&mut EspflashProgress::default()))?;
+        }
+        ShellCommand::Reset => {
+            connected.connection().reset()?;
+            *flasher = None;
+        }
+        ShellCommand::Exit => {}
+    }
+
+    Ok(())
+}
+
+/// Split a line into words at whitespace, except within single or double
+/// quotes
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    for ch in line.chars() {
+        match (quote, ch) {
+            (Some(open), ch) if ch == open => quote = None,
+            (Some(_), ch) => word.get_or_insert_with(String::new).push(ch),
+            (None, '"' | '\'') => {
+                quote = Some(ch);
+                word.get_or_insert_with(String::new);
+            }
+            (None, ch) if ch.is_whitespace() => words.extend(word.take()),
+            (None, ch) => word.get_or_insert_with(String::new).push(ch),
+        }
+    }
+
+    if quote.is_some() {
+        bail!("Unterminated quote in '{line}'");
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_line() {
+        let words = split_words(r#"  write-bin 0x10000 "my app.bin"  "#).unwrap();
+        assert_eq!(words, ["write-bin", "0x10000", "my app.bin"]);
+        assert_eq!(split_words("flash ''").unwrap(), ["flash", ""]);
+        assert!(split_words("flash 'app.elf").is_err());
+
+        let line = ShellLine::try_parse_from(words).unwrap();
+        assert_eq!(
+            line.command,
+            ShellCommand::WriteBin {
+                addr: 0x10000,
+                file: "my app.bin".into()
+            }
+        );
+        assert!(ShellLine::try_parse_from(["quit"]).is_ok());
+        assert!(ShellLine::try_parse_from(["read-flash", "0x0"]).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! Snapshots of a device's flash, which can be restored later
+//!
+//! A snapshot is a zip archive containing the contents of the entire flash, or
+//! of selected partitions, alongside a `snapshot.json` file describing the
+//! board it was taken from and the offset of each region. This allows devices
+//! to be returned to a known state, eg. between test runs.
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use esp_idf_part::PartitionTable;
+use indicatif::HumanBytes;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{
+    check_partition_table, config::Config, connect, print_board_info, prompt, ConnectArgs,
+    EspflashProgress,
+};
+use crate::{elf::RomSegment, flasher::Flasher, targets::Chip};
+
+/// Name of the file describing the snapshot within its archive
+const METADATA_FILE: &str = "snapshot.json";
+/// Size of the region read for the partition table within a snapshot of the
+/// entire flash
+const MAX_PARTITION_TABLE_SIZE: usize = 0xC00;
+
+/// Save or restore a snapshot of the device's flash
+#[derive(Debug, Args)]
+pub struct SnapshotArgs {
+    #[command(subcommand)]
+    command: SnapshotCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum SnapshotCommand {
+    /// Save the contents of the device's flash to an archive
+    Save(SaveArgs),
+    /// Restore the contents of the device's flash from an archive
+    Restore(RestoreArgs),
+}
+
+#[derive(Debug, Args)]
+struct SaveArgs {
+    /// Only save these partitions, by label, rather than the entire flash
+    #[arg(long, value_delimiter = ',', value_name = "LABELS")]
+    partitions: Vec<String>,
+    /// Archive to save the snapshot to
+    file: PathBuf,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+struct RestoreArgs {
+    /// Only restore these partitions, by label, rather than the entire
+    /// snapshot
+    #[arg(long, value_delimiter = ',', value_name = "LABELS")]
+    partitions: Vec<String>,
+    /// Archive to restore the snapshot from
+    file: PathBuf,
+
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+/// Contents of the `snapshot.json` file in a snapshot archive
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Chip the snapshot was taken from
+    pub chip: String,
+    /// Revision of the chip, as 'MAJOR.MINOR'
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Size of the flash chip
+    pub flash_size: String,
+    /// Features of the chip
+    pub features: Vec<String>,
+    /// MAC address of the board
+    pub mac_address: String,
+    /// Each region of flash in the snapshot
+    pub regions: Vec<Region>,
+}
+
+/// A single region of flash in a snapshot
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    /// Label of the partition, if only a single partition was saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    /// Offset of the region in flash
+    pub offset: u32,
+    /// Name of the file within the archive containing the region
+    pub file: String,
+    /// MD5 digest of the region's contents, in hex
+    pub md5: String,
+}
+
+/// Save or restore a snapshot of the device's flash
+pub fn snapshot(args: SnapshotArgs, config: &Config) -> Result<()> {
+    match args.command {
+        SnapshotCommand::Save(args) => save(args, config),
+        SnapshotCommand::Restore(args) => restore(args, config),
+    }
+}
+
+fn save(args: SaveArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config)?;
+    print_board_info(&mut flasher)?;
+
+    let regions = if args.partitions.is_empty() {
+        vec![(None, 0, flasher.flash_size().size())]
+    } else {
+        let table = device_partition_table(&mut flasher)?;
+        args.partitions
+            .iter()
+            .map(|label| match table.find(label) {
+                Some(partition) => Ok((Some(label.clone()), partition.offset(), partition.size())),
+                None => bail!("The device's partition table does not contain '{label}'"),
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut segments = Vec::new();
+    for (partition, offset, size) in regions {
+        let data = flasher.read_flash(offset, size, Some(&mut EspflashProgress::default()))?;
+        segments.push((
+            partition,
+            RomSegment {
+                addr: offset,
+                data: Cow::Owned(data),
+                encrypted: false,
+            },
+        ));
+    }
+
+    let info = flasher.device_info()?;
+    let mut metadata = Metadata {
+        chip: info.chip.to_string(),
+        revision: info
+            .revision
+            .map(|(major, minor)| format!("{major}.{minor}")),
+        flash_size: info.flash_size.to_string(),
+        features: info.features,
+        mac_address: info.mac_address,
+        regions: Vec::new(),
+    };
+    save_archive(&args.file, &mut metadata, &segments)?;
+
+    info!("Snapshot saved to {}", args.file.display());
+
+    Ok(())
+}
+
+fn restore(args: RestoreArgs, config: &Config) -> Result<()> {
+    // The archive is loaded before connecting, so that any problems are reported
+    // without needing to wait for the device.
+    let (metadata, segments) = load_archive(&args.file)?;
+
+    let mut flasher = connect(&args.connect_args, config)?;
+    print_board_info(&mut flasher)?;
+
+    let chip = metadata.chip.parse::<Chip>().ok();
+    if chip != Some(flasher.chip()) {
+        bail!(
+            "The snapshot was taken from the {}, so can not be restored to the {}",
+            metadata.chip,
+            flasher.chip()
+        );
+    }
+    let mac_address = flasher.device_info()?.mac_address;
+    if metadata.mac_address != mac_address {
+        warn!(
+            "The snapshot was taken from a different board, with the MAC address {}",
+            metadata.mac_address
+        );
+    }
+
+    let segments = if args.partitions.is_empty() {
+        segments
+    } else {
+        select_partitions(flasher.chip(), &metadata, segments, &args.partitions)?
+    };
+
+    let flash_size = flasher.flash_size().size();
+    let size = segments
+        .iter()
+        .map(|segment| segment.data.len() as u64)
+        .sum::<u64>();
+    if let Some(segment) = segments
+        .iter()
+        .find(|segment| segment.addr as u64 + segment.data.len() as u64 > flash_size as u64)
+    {
+        bail!(
+            "The snapshot's region at {:#x} extends beyond the end of the {} flash",
+            segment.addr,
+            flasher.flash_size()
+        );
+    }
+
+    prompt::confirm(&format!(
+        "Overwrite {} of flash with the snapshot?",
+        HumanBytes(size)
+    ))?;
+    check_partition_table(&mut flasher, None, false);
+    flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
+
+    info!("Snapshot restored from {}", args.file.display());
+
+    Ok(())
+}
+
+/// The partition table which is currently on the device
+fn device_partition_table(flasher: &mut Flasher) -> Result<PartitionTable> {
+    flasher
+        .read_partition_table()?
+        .ok_or_else(|| miette!("The device does not have a valid partition table"))
+}
+
+/// Write a snapshot archive containing each of the `segments`, recording them
+/// in the `metadata`
+fn save_archive(
+    path: &Path,
+    metadata: &mut Metadata,
+    segments: &[(Option<String>, RomSegment<'_>)],
+) -> Result<()> {
+    let file = File::create(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+    let mut archive = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for (partition, segment) in segments {
+        let file = match partition {
+            Some(label) => format!("{label}.bin"),
+            None => format!("{:#x}.bin", segment.addr),
+        };
+        archive.start_file(&file, options).into_diagnostic()?;
+        archive.write_all(&segment.data).into_diagnostic()?;
+
+        metadata.regions.push(Region {
+            partition: partition.clone(),
+            offset: segment.addr,
+            file,
+            md5: format!("{:x}", md5::compute(&segment.data)),
+        });
+    }
+
+    let json = serde_json::to_string_pretty(metadata).into_diagnostic()?;
+    archive
+        .start_file(METADATA_FILE, options)
+        .into_diagnostic()?;
+    archive.write_all(json.as_bytes()).into_diagnostic()?;
+    archive.finish().into_diagnostic()?;
+
+    Ok(())
+}
+
+/// Load a snapshot archive, verifying the contents of each region
+fn load_archive(path: &Path) -> Result<(Metadata, Vec<RomSegment<'static>>)> {
+    let file = File::open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = ZipArchive::new(file)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("{} is not a snapshot", path.display()))?;
+
+    let metadata: Metadata = serde_json::from_slice(&read_file(&mut archive, METADATA_FILE)?)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse the {METADATA_FILE} of the snapshot"))?;
+
+    let mut segments = Vec::new();
+    for region in &metadata.regions {
+        let data = read_file(&mut archive, &region.file)?;
+        if format!("{:x}", md5::compute(&data)) != region.md5 {
+            bail!("The snapshot's {} is corrupted", region.file);
+        }
+
+        segments.push(RomSegment {
+            addr: region.offset,
+            data: Cow::Owned(data),
+            encrypted: false,
+        });
+    }
+
+    Ok((metadata, segments))
+}
+
+/// Read the file `name` from a snapshot archive
+fn read_file(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>> {
+    let mut file = archive
+        .by_name(name)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("The snapshot does not contain {name}"))?;
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).into_diagnostic()?;
+
+    Ok(data)
+}
+
+/// Select the regions of a snapshot containing the partitions with the given
+/// `labels`
+///
+/// Partitions which were not saved individually are found in a snapshot of the
+/// entire flash, using the partition table within it.
+fn select_partitions(
+    chip: Chip,
+    metadata: &Metadata,
+    segments: Vec<RomSegment<'static>>,
+    labels: &[String],
+) -> Result<Vec<RomSegment<'static>>> {
+    let full = metadata
+        .regions
+        .iter()
+        .zip(&segments)
+        .find(|(region, segment)| region.partition.is_none() && segment.addr == 0)
+        .map(|(_, segment)| segment);
+    let table = full
+        .zip(chip.partition_table_address())
+        .and_then(|(full, addr)| {
+            let data = full.data.get(addr as usize..)?;
+            let data = &data[..data.len().min(MAX_PARTITION_TABLE_SIZE)];
+            PartitionTable::try_from_bytes(data).ok()
+        });
+
+    labels
+        .iter()
+        .map(|label| {
+            let saved = metadata
+                .regions
+                .iter()
+                .zip(&segments)
+                .find(|(region, _)| region.partition.as_deref() == Some(label.as_str()));
+            if let Some((_, segment)) = saved {
+                return Ok(segment.clone());
+            }
+
+            let partition = table.as_ref().and_then(|table| table.find(label));
+            match (full, partition) {
+                (Some(full), Some(partition)) => {
+                    let start = partition.offset() as usize;
+                    let end = start + partition.size() as usize;
+                    match full.data.get(start..end) {
+                        Some(data) => Ok(RomSegment {
+                            addr: partition.offset(),
+                            data: Cow::Owned(data.to_vec()),
+                            encrypted: false,
+                        }),
+                        None => bail!("The snapshot does not contain all of '{label}'"),
+                    }
+                }
+                _ => bail!("The snapshot does not contain the partition '{label}'"),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_archive() {
+        let path = env::temp_dir().join(format!("espflash-snapshot-{}.zip", process::id()));
+
+        let mut table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x1000\nfactory,app,factory,0x10000,0x1000\n",
+        )
+        .unwrap()
+        .to_bin()
+        .unwrap();
+        table.resize(0x1000, 0xFF);
+        let mut flash = vec![0xFF; 0x8000];
+        flash.extend(table);
+        flash.extend([0xAB; 0x1000]);
+
+        let segments = vec![(
+            None,
+            RomSegment {
+                addr: 0,
+                data: Cow::Owned(flash),
+                encrypted: false,
+            },
+        )];
+        let mut metadata = Metadata {
+            chip: "esp32".into(),
+            revision: Some("3.0".into()),
+            flash_size: "4MB".into(),
+            features: vec!["WiFi".into()],
+            mac_address: "00:11:22:33:44:55".into(),
+            regions: Vec::new(),
+        };
+
+        save_archive(&path, &mut metadata, &segments).unwrap();
+        let loaded = load_archive(&path);
+        fs::remove_file(&path).ok();
+        let (loaded, segments) = loaded.unwrap();
+
+        assert_eq!(loaded, metadata);
+        assert_eq!(loaded.regions[0].file, "0x0.bin");
+        assert_eq!(segments[0].data.len(), 0xA000);
+
+        let nvs =
+            select_partitions(Chip::Esp32, &loaded, segments.clone(), &["nvs".into()]).unwrap();
+        assert_eq!(nvs[0].addr, 0x9000);
+        assert_eq!(nvs[0].data.as_ref(), [0xAB; 0x1000]);
+        assert!(select_partitions(Chip::Esp32, &loaded, segments, &["factory".into()]).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Watching files for changes, to reflash the device when they are modified
+//!
+//! Files are polled for their modification times rather than relying on
+//! platform-specific notifications. Hidden entries and `target` directories
+//! are ignored, so that build output does not trigger another build.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often the watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long files must go unmodified before a change is reported, so that a
+/// file which is still being written is not read
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Detects modifications to a set of files and directories
+pub struct Watcher {
+    paths: Vec<PathBuf>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Watcher {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let modified = last_modified(&paths);
+
+        Self {
+            paths,
+            modified,
+            checked: Instant::now(),
+        }
+    }
+
+    /// Whether any of the files have been modified since the last change was
+    /// reported, checking at most once per [POLL_INTERVAL]
+    pub fn changed(&mut self) -> bool {
+        if self.checked.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+
+        let mut modified = last_modified(&self.paths);
+        if modified == self.modified {
+            return false;
+        }
+
+        // Wait for the files to stop changing, eg. while a build is linking
+        loop {
+            thread::sleep(SETTLE_TIME);
+            let settled = last_modified(&self.paths);
+            if settled == modified {
+                break;
+            }
+            modified = settled;
+        }
+        self.modified = modified;
+
+        true
+    }
+
+    /// Block until any of the files have been modified
+    pub fn wait(&mut self) {
+        while !self.changed() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// The most recent modification time of the paths, or of any file within them
+fn last_modified(paths: &[PathBuf]) -> Option<SystemTime> {
+    paths.iter().filter_map(|path| modified(path)).max()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+
+    fs::read_dir(path)
+        .ok()?
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && name != "target"
+        })
+        .filter_map(|entry| modified(&entry.path()))
+        .chain(metadata.modified().ok())
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn test_watcher() {
+        let dir = env::temp_dir().join(format!("espflash-watch-{}", process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+
+        let mut watcher = Watcher::new(vec![dir.clone()]);
+        watcher.checked -= POLL_INTERVAL;
+        assert!(!watcher.changed());
+
+        // Changes to build output are ignored
+        thread::sleep(Duration::from_millis(10));
+        fs::write(dir.join("target/app.elf"), "").unwrap();
+        watcher.checked -= POLL_INTERVAL;
+        assert!(!watcher.changed());
+
+        fs::write(dir.join("src/main.rs"), "fn main() { loop {} }").unwrap();
+        watcher.checked -= POLL_INTERVAL;
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
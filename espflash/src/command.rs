@@ -3,18 +3,19 @@
 use std::{io::Write, mem::size_of, time::Duration};
 
 use bytemuck::{bytes_of, Pod, Zeroable};
-use strum::Display;
+use strum::{Display, FromRepr};
 
 use crate::flasher::{checksum, SpiAttachParams, CHECKSUM_INIT};
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 const ERASE_REGION_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
 const ERASE_WRITE_TIMEOUT_PER_MB: Duration = Duration::from_secs(40);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
 const ERASE_CHIP_TIMEOUT: Duration = Duration::from_secs(120);
 const MEM_END_TIMEOUT: Duration = Duration::from_millis(50);
 const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
 
-#[derive(Copy, Clone, Debug, Display)]
+#[derive(Copy, Clone, Debug, Display, FromRepr)]
 #[non_exhaustive]
 #[repr(u8)]
 pub enum CommandType {
@@ -35,10 +36,13 @@ pub enum CommandType {
     FlashDeflateData = 0x11,
     FlashDeflateEnd = 0x12,
     FlashMd5 = 0x13,
+    GetSecurityInfo = 0x14,
     FlashDetect = 0x9f,
     // Some commands supported by stub only
     EraseFlash = 0xd0,
     EraseRegion = 0xd1,
+    ReadFlash = 0xd2,
+    FlashEncryptData = 0xd4,
 }
 
 impl CommandType {
@@ -63,9 +67,14 @@ impl CommandType {
             CommandType::FlashBegin | CommandType::FlashDeflateBegin | CommandType::EraseRegion => {
                 calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
             }
-            CommandType::FlashData | CommandType::FlashDeflateData => {
-                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
-            }
+            CommandType::FlashData
+            | CommandType::FlashDeflateData
+            | CommandType::FlashEncryptData => calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size),
+            CommandType::FlashMd5 => calc_timeout(MD5_TIMEOUT_PER_MB, size),
+            CommandType::EraseFlash => std::cmp::max(
+                ERASE_CHIP_TIMEOUT,
+                calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size),
+            ),
             _ => self.timeout(),
         }
     }
@@ -80,6 +89,8 @@ pub enum Command<'a> {
         block_size: u32,
         offset: u32,
         supports_encryption: bool,
+        /// Encrypt the data as it is written, on chips whose ROM supports it
+        encrypted: bool,
     },
     FlashData {
         data: &'a [u8],
@@ -122,6 +133,14 @@ pub enum Command<'a> {
     SpiAttachStub {
         spi_params: SpiAttachParams,
     },
+    FlashMd5 {
+        offset: u32,
+        size: u32,
+    },
+    SpiSetParams {
+        /// Total size of the flash chip, in bytes
+        size: u32,
+    },
     ChangeBaud {
         /// New baud rate
         new_baud: u32,
@@ -145,11 +164,25 @@ pub enum Command<'a> {
         reboot: bool,
     },
     FlashDetect,
+    GetSecurityInfo,
     EraseFlash,
     EraseRegion {
         offset: u32,
         size: u32,
     },
+    ReadFlash {
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    },
+    /// Write a block of data to flash, encrypted by the stub
+    FlashEncryptData {
+        data: &'a [u8],
+        pad_to: usize,
+        pad_byte: u8,
+        sequence: u32,
+    },
 }
 
 impl<'a> Command<'a> {
@@ -166,13 +199,18 @@ impl<'a> Command<'a> {
             Command::ReadReg { .. } => CommandType::ReadReg,
             Command::SpiAttach { .. } => CommandType::SpiAttach,
             Command::SpiAttachStub { .. } => CommandType::SpiAttach,
+            Command::FlashMd5 { .. } => CommandType::FlashMd5,
+            Command::SpiSetParams { .. } => CommandType::SpiSetParams,
             Command::ChangeBaud { .. } => CommandType::ChangeBaud,
             Command::FlashDeflateBegin { .. } => CommandType::FlashDeflateBegin,
             Command::FlashDeflateData { .. } => CommandType::FlashDeflateData,
             Command::FlashDeflateEnd { .. } => CommandType::FlashDeflateEnd,
             Command::FlashDetect => CommandType::FlashDetect,
+            Command::GetSecurityInfo => CommandType::GetSecurityInfo,
             Command::EraseFlash { .. } => CommandType::EraseFlash,
             Command::EraseRegion { .. } => CommandType::EraseRegion,
+            Command::ReadFlash { .. } => CommandType::ReadFlash,
+            Command::FlashEncryptData { .. } => CommandType::FlashEncryptData,
         }
     }
 
@@ -189,6 +227,7 @@ impl<'a> Command<'a> {
                 block_size,
                 offset,
                 supports_encryption,
+                encrypted,
             } => {
                 begin_command(
                     writer,
@@ -197,6 +236,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    encrypted,
                 )?;
             }
             Command::FlashData {
@@ -224,6 +264,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    false,
                 )?;
             }
             Command::MemData {
@@ -291,6 +332,34 @@ impl<'a> Command<'a> {
             Command::SpiAttachStub { spi_params } => {
                 write_basic(writer, &spi_params.encode(true), 0)?;
             }
+            Command::FlashMd5 { offset, size } => {
+                let mut data = Vec::with_capacity(16);
+                data.extend_from_slice(&offset.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                data.extend_from_slice(&[0; 8]);
+                write_basic(writer, &data, 0)?;
+            }
+            Command::SpiSetParams { size } => {
+                #[derive(Zeroable, Pod, Copy, Clone, Debug)]
+                #[repr(C)]
+                struct SpiSetParams {
+                    id: u32,
+                    total_size: u32,
+                    block_size: u32,
+                    sector_size: u32,
+                    page_size: u32,
+                    status_mask: u32,
+                }
+                let params = SpiSetParams {
+                    id: 0,
+                    total_size: size,
+                    block_size: 64 * 1024,
+                    sector_size: 4 * 1024,
+                    page_size: 256,
+                    status_mask: 0xFFFF,
+                };
+                write_basic(writer, bytes_of(&params), 0)?;
+            }
             Command::ChangeBaud {
                 new_baud,
                 prior_baud,
@@ -317,6 +386,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    false,
                 )?;
             }
             Command::FlashDeflateData {
@@ -333,6 +403,9 @@ impl<'a> Command<'a> {
             Command::FlashDetect => {
                 write_basic(writer, &[], 0)?;
             }
+            Command::GetSecurityInfo => {
+                write_basic(writer, &[], 0)?;
+            }
             Command::EraseFlash => {
                 write_basic(writer, &[], 0)?;
             }
@@ -345,6 +418,27 @@ impl<'a> Command<'a> {
                 writer.write_all(&offset.to_le_bytes())?;
                 writer.write_all(&size.to_le_bytes())?;
             }
+            Command::ReadFlash {
+                offset,
+                size,
+                block_size,
+                max_in_flight,
+            } => {
+                let mut data = Vec::with_capacity(16);
+                data.extend_from_slice(&offset.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                data.extend_from_slice(&block_size.to_le_bytes());
+                data.extend_from_slice(&max_in_flight.to_le_bytes());
+                write_basic(writer, &data, 0)?;
+            }
+            Command::FlashEncryptData {
+                pad_to,
+                pad_byte,
+                data,
+                sequence,
+            } => {
+                data_command(writer, data, pad_to, pad_byte, sequence)?;
+            }
         };
         Ok(())
     }
@@ -364,6 +458,7 @@ fn begin_command<W: Write>(
     block_size: u32,
     offset: u32,
     supports_encryption: bool,
+    encrypted: bool,
 ) -> std::io::Result<()> {
     #[derive(Zeroable, Pod, Copy, Clone, Debug)]
     #[repr(C)]
@@ -379,7 +474,7 @@ fn begin_command<W: Write>(
         blocks,
         block_size,
         offset,
-        encrypted: 0,
+        encrypted: u32::from(encrypted),
     };
 
     let bytes = bytes_of(&params);
@@ -4,23 +4,172 @@
 //! sending/decoding of commands, and provides higher-level operations with the
 //! device.
 
-use std::{io::BufWriter, thread::sleep, time::Duration};
+use std::{
+    fmt,
+    io::{self, BufRead, BufWriter, Write},
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use binread::{io::Cursor, BinRead, BinReaderExt};
-use log::info;
 use serialport::UsbPortInfo;
 use slip_codec::SlipDecoder;
+use tracing::{debug, debug_span, info};
 
-use self::encoder::SlipEncoder;
+use self::{encoder::SlipEncoder, trace::Tracer};
 use crate::{
-    command::{Command, CommandType},
+    command::{Command, CommandType, DEFAULT_TIMEOUT},
     error::{ConnectionError, Error, ResultExt, RomError, RomErrorKind},
     interface::Interface,
 };
 
 const DEFAULT_CONNECT_ATTEMPTS: usize = 7;
+const DEFAULT_SYNC_ATTEMPTS: usize = 5;
+const DEFAULT_RESET_DELAY: u64 = 50; // ms
+const EXTRA_RESET_DELAY: u64 = 500; // ms
+const ROM_LOG_TIMEOUT: Duration = Duration::from_millis(100);
+const ROM_LOG_MAX_DURATION: Duration = Duration::from_secs(1);
+const ROM_LOG_MAX_LEN: usize = 4096;
 pub(crate) const USB_SERIAL_JTAG_PID: u16 = 0x1001;
 
+/// The likely reason that a device could not be connected to, as determined
+/// from its output following a reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BootDiagnosis {
+    /// Nothing was received from the device
+    NoOutput,
+    /// The device booted normally, rather than entering its bootloader
+    AppRunning,
+    /// The device entered its bootloader, but did not respond to commands
+    DownloadModeNoResponse,
+    /// The output could not be read, which usually means it was sent at a
+    /// different baud rate
+    UnreadableOutput,
+}
+
+impl BootDiagnosis {
+    /// Determine why a device could not be connected to from its output, if
+    /// the output is recognized
+    pub fn from_output(output: &[u8]) -> Option<Self> {
+        if output.is_empty() {
+            return Some(BootDiagnosis::NoOutput);
+        }
+
+        let text = String::from_utf8_lossy(output);
+        if text.contains("waiting for download") {
+            return Some(BootDiagnosis::DownloadModeNoResponse);
+        }
+
+        let readable = output
+            .iter()
+            .filter(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+            .count();
+        if readable * 4 < output.len() * 3 {
+            return Some(BootDiagnosis::UnreadableOutput);
+        }
+
+        // The boot mode most recently reported by the ROM, or log output from an
+        // application
+        match boot_mode(&text) {
+            Some(mode) if mode.contains("DOWNLOAD") => {
+                return Some(BootDiagnosis::DownloadModeNoResponse)
+            }
+            Some(_) => return Some(BootDiagnosis::AppRunning),
+            None => {}
+        }
+        if text.lines().any(is_app_log) {
+            return Some(BootDiagnosis::AppRunning);
+        }
+
+        None
+    }
+
+    /// What to try in order to connect to the device
+    pub fn help(&self) -> &'static str {
+        match self {
+            BootDiagnosis::NoOutput => {
+                "Make sure the correct serial port is selected and the device is powered. If the \
+                 device is connected using a USB to UART bridge, check that its TX and RX lines \
+                 are connected"
+            }
+            BootDiagnosis::AppRunning => {
+                "Your board likely lacks auto-reset circuitry: hold down BOOT, press and release \
+                 RESET (EN), then release BOOT, or use `--reset-sequence manual`"
+            }
+            BootDiagnosis::DownloadModeNoResponse => {
+                "Make sure no other program is using the serial port. If the device has both a \
+                 USB and a UART interface, it may be waiting for the other one"
+            }
+            BootDiagnosis::UnreadableOutput => {
+                "The device may be running an application at a different baud rate because the \
+                 reset did not work, in which case hold down BOOT, press and release RESET (EN), \
+                 then release BOOT. Devices with a 26MHz crystal, such as the ESP8266, also send \
+                 their boot messages at 74880 baud"
+            }
+        }
+    }
+}
+
+/// The name of the last boot mode reported by the ROM, eg. `SPI_FAST_FLASH_BOOT`
+/// from `rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+///
+/// The numeric value depends on the chip's strapping pins, so only the name is
+/// meaningful.
+fn boot_mode(text: &str) -> Option<&str> {
+    let (_, banner) = text.rsplit_once("boot:0x")?;
+    let banner = banner.lines().next().unwrap_or_default();
+    let name = banner
+        .trim_start_matches(|c: char| c.is_ascii_hexdigit())
+        .strip_prefix(" (")?
+        .trim_end();
+
+    // Names may themselves contain parentheses, eg. `DOWNLOAD(USB/UART0)`
+    Some(name.strip_suffix(')').unwrap_or(name))
+}
+
+/// Whether a line is output from the ESP-IDF logging library, eg. `I (1234)
+/// tag: message`, which may be preceded by an ANSI colour code
+fn is_app_log(line: &str) -> bool {
+    let line = match line.strip_prefix('\x1b') {
+        Some(rest) => rest.split_once('m').map_or("", |(_, rest)| rest),
+        None => line,
+    };
+    let mut chars = line.chars();
+
+    matches!(chars.next(), Some('E' | 'W' | 'I' | 'D' | 'V'))
+        && chars
+            .as_str()
+            .strip_prefix(" (")
+            .and_then(|rest| rest.split_once(')'))
+            .map_or(false, |(time, _)| {
+                !time.is_empty() && time.chars().all(|c| c.is_ascii_digit())
+            })
+}
+
+impl fmt::Display for BootDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootDiagnosis::NoOutput => write!(f, "nothing was received from the device"),
+            BootDiagnosis::AppRunning => write!(
+                f,
+                "the device booted its application instead of entering download mode, so the \
+                 reset sequence did not select the boot mode"
+            ),
+            BootDiagnosis::DownloadModeNoResponse => write!(
+                f,
+                "the device entered download mode, but did not respond to synchronization"
+            ),
+            BootDiagnosis::UnreadableOutput => write!(
+                f,
+                "the device's output was unreadable, which usually means it was sent at a \
+                 different baud rate"
+            ),
+        }
+    }
+}
+
 /// A response from a target device following a command
 #[derive(Debug, Copy, Clone, BinRead)]
 pub struct CommandResponse {
@@ -28,8 +177,324 @@ pub struct CommandResponse {
     pub return_op: u8,
     pub return_length: u16,
     pub value: u32,
-    pub error: u8,
+    /// Non-zero if the command failed
     pub status: u8,
+    /// The reason the command failed, see [RomErrorKind]
+    pub error: u8,
+}
+
+/// A method of resetting a target device into its bootloader
+pub trait ResetStrategy {
+    /// Reset the device connected to `serial` into its bootloader
+    fn reset(&self, serial: &mut Interface) -> Result<(), Error>;
+}
+
+/// Classic reset using the DTR and RTS lines, as found on most development
+/// boards
+#[derive(Debug, Clone, Copy)]
+pub struct ClassicReset {
+    delay: Duration,
+}
+
+impl ClassicReset {
+    /// Hold the chip in reset for `delay` after releasing EN
+    pub fn new(delay: Duration) -> Self {
+        ClassicReset { delay }
+    }
+}
+
+impl ResetStrategy for ClassicReset {
+    fn reset(&self, serial: &mut Interface) -> Result<(), Error> {
+        serial.write_data_terminal_ready(false)?;
+        serial.write_request_to_send(true)?;
+
+        sleep(Duration::from_millis(100));
+
+        serial.write_data_terminal_ready(true)?;
+        serial.write_request_to_send(false)?;
+
+        sleep(self.delay);
+
+        serial.write_data_terminal_ready(false)?;
+
+        Ok(())
+    }
+}
+
+/// Reset for chips connected using their built-in USB-JTAG-Serial peripheral
+#[derive(Debug, Clone, Copy)]
+pub struct UsbJtagSerialReset;
+
+impl ResetStrategy for UsbJtagSerialReset {
+    fn reset(&self, serial: &mut Interface) -> Result<(), Error> {
+        serial.write_data_terminal_ready(false)?;
+        serial.write_request_to_send(false)?;
+
+        sleep(Duration::from_millis(100));
+
+        serial.write_data_terminal_ready(true)?;
+        serial.write_request_to_send(false)?;
+
+        sleep(Duration::from_millis(100));
+
+        serial.write_request_to_send(true)?;
+        serial.write_data_terminal_ready(false)?;
+        serial.write_request_to_send(true)?;
+
+        sleep(Duration::from_millis(100));
+
+        serial.write_data_terminal_ready(false)?;
+        serial.write_request_to_send(false)?;
+
+        Ok(())
+    }
+}
+
+/// Ask the user to reset the device into its bootloader using its buttons
+#[derive(Debug, Clone, Copy)]
+pub struct ManualReset;
+
+impl ResetStrategy for ManualReset {
+    fn reset(&self, _serial: &mut Interface) -> Result<(), Error> {
+        info!("Hold down BOOT, press and release RESET (EN), then release BOOT and press Enter");
+
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+
+        Ok(())
+    }
+}
+
+/// Reset using the EN and IO0 strapping pins, wired directly to the host's
+/// GPIOs
+#[cfg(feature = "gpio")]
+#[derive(Debug, Clone, Copy)]
+pub struct GpioReset;
+
+#[cfg(feature = "gpio")]
+impl ResetStrategy for GpioReset {
+    fn reset(&self, serial: &mut Interface) -> Result<(), Error> {
+        let pins = match serial.strapping_pins.as_mut() {
+            Some(pins) => pins,
+            None => return Ok(()),
+        };
+
+        pins.set_boot(false)?;
+        pins.set_en(false)?;
+
+        sleep(Duration::from_millis(100));
+
+        pins.set_en(true)?;
+
+        sleep(Duration::from_millis(DEFAULT_RESET_DELAY));
+
+        pins.set_boot(true)?;
+
+        Ok(())
+    }
+}
+
+/// A single step of a [CustomReset] sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetStep {
+    /// Set the DTR line
+    Dtr(bool),
+    /// Set the RTS line
+    Rts(bool),
+    /// Set the DTR and RTS lines, in that order
+    DtrRts(bool, bool),
+    /// Wait for the given duration
+    Wait(Duration),
+}
+
+/// User-defined reset sequence, for boards with non-standard reset circuits
+///
+/// Sequences use the same format as `esptool.py`: steps are separated by `|`,
+/// and each step is one of `D0`/`D1` (set DTR), `R0`/`R1` (set RTS), `U0,1`
+/// (set DTR and RTS) or `W0.1` (wait for the given number of seconds). For
+/// example, the classic reset is `D0|R1|W0.1|D1|R0|W0.05|D0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomReset {
+    steps: Vec<ResetStep>,
+}
+
+impl CustomReset {
+    /// Create a reset sequence from its individual steps
+    pub fn new(steps: Vec<ResetStep>) -> Self {
+        CustomReset { steps }
+    }
+}
+
+impl FromStr for CustomReset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidResetSequence(s.to_string());
+        let level = |value: &str| match value.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(invalid()),
+        };
+
+        let steps = s
+            .split('|')
+            .map(|step| {
+                let step = step.trim();
+                let (kind, value) = step.split_at(step.len().min(1));
+
+                match kind {
+                    "D" => Ok(ResetStep::Dtr(level(value)?)),
+                    "R" => Ok(ResetStep::Rts(level(value)?)),
+                    "U" => {
+                        let (dtr, rts) = value.split_once(',').ok_or_else(invalid)?;
+                        Ok(ResetStep::DtrRts(level(dtr)?, level(rts)?))
+                    }
+                    "W" => value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                        .map(|secs| ResetStep::Wait(Duration::from_secs_f64(secs)))
+                        .ok_or_else(invalid),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CustomReset::new(steps))
+    }
+}
+
+impl ResetStrategy for CustomReset {
+    fn reset(&self, serial: &mut Interface) -> Result<(), Error> {
+        for step in &self.steps {
+            match *step {
+                ResetStep::Dtr(level) => serial.write_data_terminal_ready(level)?,
+                ResetStep::Rts(level) => serial.write_request_to_send(level)?,
+                ResetStep::DtrRts(dtr, rts) => {
+                    serial.write_data_terminal_ready(dtr)?;
+                    serial.write_request_to_send(rts)?;
+                }
+                ResetStep::Wait(duration) => sleep(duration),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The sequence used to reset a target device into its bootloader
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub enum ResetSequence {
+    /// Select a sequence based on the serial adapter, alternating between the
+    /// classic reset with and without an extra delay
+    ///
+    /// If the strapping pins are wired to the host's GPIOs, they are used
+    /// instead.
+    #[default]
+    Auto,
+    /// Classic reset using the DTR and RTS lines
+    Classic,
+    /// Classic reset with an extra delay, required by revision 0 ESP32s
+    Esp32r0,
+    /// Reset for the built-in USB-JTAG-Serial peripheral
+    UsbJtagSerial,
+    /// Prompt the user to reset the device using its buttons
+    Manual,
+    /// A user-defined reset sequence
+    Custom(CustomReset),
+}
+
+impl ResetSequence {
+    /// The names of the built-in sequences
+    pub const NAMES: &'static [&'static str] =
+        &["auto", "classic", "esp32r0", "usb-jtag-serial", "manual"];
+
+    /// The strategies to try, in order, when connecting to a device
+    fn strategies(&self, serial: &Interface, pid: u16) -> Vec<Box<dyn ResetStrategy>> {
+        let classic = ClassicReset::new(Duration::from_millis(DEFAULT_RESET_DELAY));
+        let esp32r0 = ClassicReset::new(Duration::from_millis(EXTRA_RESET_DELAY));
+
+        #[cfg(feature = "gpio")]
+        if *self == ResetSequence::Auto && serial.strapping_pins.is_some() {
+            return vec![Box::new(GpioReset)];
+        }
+        #[cfg(not(feature = "gpio"))]
+        let _ = serial;
+
+        match self {
+            ResetSequence::Auto if pid == USB_SERIAL_JTAG_PID => vec![Box::new(UsbJtagSerialReset)],
+            ResetSequence::Auto => vec![Box::new(classic), Box::new(esp32r0)],
+            ResetSequence::Classic => vec![Box::new(classic)],
+            ResetSequence::Esp32r0 => vec![Box::new(esp32r0)],
+            ResetSequence::UsbJtagSerial => vec![Box::new(UsbJtagSerialReset)],
+            ResetSequence::Manual => vec![Box::new(ManualReset)],
+            ResetSequence::Custom(custom) => vec![Box::new(custom.clone())],
+        }
+    }
+}
+
+impl FromStr for ResetSequence {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ResetSequence::Auto),
+            "classic" => Ok(ResetSequence::Classic),
+            "esp32r0" => Ok(ResetSequence::Esp32r0),
+            "usb-jtag-serial" => Ok(ResetSequence::UsbJtagSerial),
+            "manual" => Ok(ResetSequence::Manual),
+            custom => Ok(ResetSequence::Custom(custom.parse()?)),
+        }
+    }
+}
+
+/// How persistently to attempt communicating with a target device
+///
+/// Flaky connections, eg.) through unpowered USB hubs, may need more attempts
+/// or longer timeouts, while automated setups may prefer to fail fast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    connect_attempts: usize,
+    sync_attempts: usize,
+    timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            connect_attempts: DEFAULT_CONNECT_ATTEMPTS,
+            sync_attempts: DEFAULT_SYNC_ATTEMPTS,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The number of times to reset the device while connecting
+    pub fn connect_attempts(mut self, attempts: usize) -> Self {
+        self.connect_attempts = attempts.max(1);
+        self
+    }
+
+    /// The number of times to try synchronizing after each reset
+    pub fn sync_attempts(mut self, attempts: usize) -> Self {
+        self.sync_attempts = attempts.max(1);
+        self
+    }
+
+    /// The timeout for commands whose duration does not depend on the amount
+    /// of data involved
+    ///
+    /// All other command timeouts are scaled proportionally.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn scale_timeout(&self, timeout: Duration) -> Duration {
+        timeout.mul_f64(self.timeout.as_secs_f64() / DEFAULT_TIMEOUT.as_secs_f64())
+    }
 }
 
 /// An established connection with a target device
@@ -37,6 +502,10 @@ pub struct Connection {
     serial: Interface,
     port_info: UsbPortInfo,
     decoder: SlipDecoder,
+    reset_sequence: ResetSequence,
+    retry_policy: RetryPolicy,
+    tracer: Option<Tracer>,
+    rom_log: Option<Vec<u8>>,
 }
 
 impl Connection {
@@ -45,31 +514,85 @@ impl Connection {
             serial,
             port_info,
             decoder: SlipDecoder::new(),
+            reset_sequence: ResetSequence::default(),
+            retry_policy: RetryPolicy::default(),
+            tracer: None,
+            rom_log: None,
         }
     }
 
+    /// Set the sequence used to reset the device into its bootloader
+    pub fn set_reset_sequence(&mut self, reset_sequence: ResetSequence) {
+        self.reset_sequence = reset_sequence;
+    }
+
+    /// Set how persistently to attempt communicating with the device
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Log every frame sent to and received from the device to `writer`
+    pub fn set_trace(&mut self, writer: Box<dyn Write + Send>) {
+        self.tracer = Some(Tracer::new(writer));
+    }
+
+    /// Record the output printed by the ROM after each reset, which is
+    /// included in the error if the device cannot be synchronized with
+    pub fn set_capture_rom_log(&mut self, capture: bool) {
+        self.rom_log = capture.then(Vec::new);
+    }
+
+    /// The timeout for commands whose duration does not depend on the amount
+    /// of data involved
+    pub fn default_timeout(&self) -> Duration {
+        self.retry_policy.timeout
+    }
+
     pub fn begin(&mut self) -> Result<(), Error> {
-        let mut extra_delay = false;
-        for _ in 0..DEFAULT_CONNECT_ATTEMPTS {
-            if self.connect_attempt(extra_delay).is_err() {
-                extra_delay = !extra_delay;
-
-                info!(
-                    "Unable to connect, retrying with {} delay...",
-                    if extra_delay { "extra" } else { "default" }
-                );
-            } else {
+        let _span = debug_span!("sync").entered();
+
+        let strategies = self
+            .reset_sequence
+            .strategies(&self.serial, self.port_info.pid);
+
+        for strategy in strategies
+            .iter()
+            .cycle()
+            .take(self.retry_policy.connect_attempts)
+        {
+            if self.connect_attempt(strategy.as_ref()).is_ok() {
                 return Ok(());
             }
+
+            info!("Unable to connect, retrying...");
         }
 
-        Err(Error::Connection(ConnectionError::ConnectionFailed))
+        if self.rom_log.is_none() {
+            if let Some(diagnosis) = self.diagnose_boot(&strategies) {
+                return Err(Error::Connection(
+                    ConnectionError::ConnectionFailedDiagnosed { diagnosis },
+                ));
+            }
+        }
+
+        match &self.rom_log {
+            Some(log) if log.is_empty() => Err(Error::Connection(
+                ConnectionError::ConnectionFailedWithRomLog("(nothing was received)".into()),
+            )),
+            Some(log) => Err(Error::Connection(
+                ConnectionError::ConnectionFailedWithRomLog(
+                    String::from_utf8_lossy(log).trim_end().to_string(),
+                ),
+            )),
+            None => Err(Error::Connection(ConnectionError::ConnectionFailed)),
+        }
     }
 
-    fn connect_attempt(&mut self, extra_delay: bool) -> Result<(), Error> {
-        self.reset_to_flash(extra_delay)?;
+    fn connect_attempt(&mut self, strategy: &dyn ResetStrategy) -> Result<(), Error> {
+        strategy.reset(&mut self.serial)?;
+        self.capture_rom_log()?;
 
-        for _ in 0..5 {
+        for _ in 0..self.retry_policy.sync_attempts {
             self.flush()?;
             if self.sync().is_ok() {
                 return Ok(());
@@ -79,6 +602,59 @@ impl Connection {
         Err(Error::Connection(ConnectionError::ConnectionFailed))
     }
 
+    /// Reset the device once more, capturing its output in order to determine
+    /// why it could not be connected to
+    fn diagnose_boot(&mut self, strategies: &[Box<dyn ResetStrategy>]) -> Option<BootDiagnosis> {
+        // Manual resets would ask the user to reset the device yet again
+        if self.reset_sequence != ResetSequence::Manual {
+            strategies.first()?.reset(&mut self.serial).ok()?;
+        }
+
+        self.rom_log = Some(Vec::new());
+        let captured = self.capture_rom_log();
+        let output = self.rom_log.take()?;
+        captured.ok()?;
+
+        BootDiagnosis::from_output(&output)
+    }
+
+    /// Read the ROM's output following a reset, if it is being captured
+    ///
+    /// Only the most recent output is kept, as a device stuck in a boot loop
+    /// prints the same output repeatedly.
+    fn capture_rom_log(&mut self) -> Result<(), Error> {
+        let log = match &mut self.rom_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        let serial = self.serial.serial_port_mut();
+        let old_timeout = serial.timeout();
+        serial.set_timeout(ROM_LOG_TIMEOUT)?;
+
+        let started = Instant::now();
+        let mut buff = [0; 256];
+        let result = loop {
+            match serial.read(&mut buff) {
+                Ok(0) => break Ok(()),
+                Ok(count) => log.extend_from_slice(&buff[..count]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ok(()),
+                Err(e) => break Err(e),
+            }
+            if started.elapsed() >= ROM_LOG_MAX_DURATION {
+                break Ok(());
+            }
+        };
+        if log.len() > ROM_LOG_MAX_LEN {
+            log.drain(..log.len() - ROM_LOG_MAX_LEN);
+        }
+
+        serial.set_timeout(old_timeout)?;
+        result?;
+
+        Ok(())
+    }
+
     pub(crate) fn sync(&mut self) -> Result<(), Error> {
         self.with_timeout(CommandType::Sync.timeout(), |connection| {
             connection.command(Command::Sync)?;
@@ -115,42 +691,31 @@ impl Connection {
         Ok(reset_after_flash(&mut self.serial, pid)?)
     }
 
-    pub fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
-        if self.port_info.pid == USB_SERIAL_JTAG_PID {
-            self.serial.write_data_terminal_ready(false)?;
-            self.serial.write_request_to_send(false)?;
-
-            sleep(Duration::from_millis(100));
-
-            self.serial.write_data_terminal_ready(true)?;
-            self.serial.write_request_to_send(false)?;
+    /// Reset the device into its bootloader using the reset sequence, without
+    /// synchronizing with it
+    pub fn reset_to_bootloader(&mut self) -> Result<(), Error> {
+        let strategies = self
+            .reset_sequence
+            .strategies(&self.serial, self.port_info.pid);
 
-            sleep(Duration::from_millis(100));
-
-            self.serial.write_request_to_send(true)?;
-            self.serial.write_data_terminal_ready(false)?;
-            self.serial.write_request_to_send(true)?;
-
-            sleep(Duration::from_millis(100));
+        match strategies.first() {
+            Some(strategy) => strategy.reset(&mut self.serial),
+            None => Ok(()),
+        }
+    }
 
-            self.serial.write_data_terminal_ready(false)?;
-            self.serial.write_request_to_send(false)?;
+    pub fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
+        let strategy: Box<dyn ResetStrategy> = if self.port_info.pid == USB_SERIAL_JTAG_PID {
+            Box::new(UsbJtagSerialReset)
+        } else if extra_delay {
+            Box::new(ClassicReset::new(Duration::from_millis(EXTRA_RESET_DELAY)))
         } else {
-            self.serial.write_data_terminal_ready(false)?;
-            self.serial.write_request_to_send(true)?;
-
-            sleep(Duration::from_millis(100));
-
-            self.serial.write_data_terminal_ready(true)?;
-            self.serial.write_request_to_send(false)?;
-
-            let millis = if extra_delay { 500 } else { 50 };
-            sleep(Duration::from_millis(millis));
-
-            self.serial.write_data_terminal_ready(false)?;
-        }
+            Box::new(ClassicReset::new(Duration::from_millis(
+                DEFAULT_RESET_DELAY,
+            )))
+        };
 
-        Ok(())
+        strategy.reset(&mut self.serial)
     }
 
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
@@ -164,6 +729,11 @@ impl Connection {
         Ok(())
     }
 
+    /// Whether the baud rate of the connection can be changed
+    pub fn can_set_baud(&self) -> bool {
+        self.serial.serial_port().can_set_baud_rate()
+    }
+
     pub fn get_baud(&self) -> Result<u32, Error> {
         Ok(self.serial.serial_port().baud_rate()?)
     }
@@ -173,6 +743,7 @@ impl Connection {
         timeout: Duration,
         mut f: F,
     ) -> Result<T, Error> {
+        let timeout = self.retry_policy.scale_timeout(timeout);
         let old_timeout = {
             let serial = self.serial.serial_port_mut();
             let old_timeout = serial.timeout();
@@ -199,9 +770,13 @@ impl Connection {
     }
 
     pub fn write_command(&mut self, command: Command) -> Result<(), Error> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.command(&command);
+        }
+
         let serial = self.serial.serial_port_mut();
 
-        serial.clear(serialport::ClearBuffer::Input)?;
+        serial.clear_input()?;
         let mut writer = BufWriter::new(serial);
         let mut encoder = SlipEncoder::new(&mut writer)?;
         command.write(&mut encoder)?;
@@ -209,14 +784,36 @@ impl Connection {
         Ok(())
     }
 
+    /// Write a SLIP-encoded frame which is not a command, such as the
+    /// acknowledgements sent while reading flash
+    ///
+    /// Unlike [Connection::write_command], any input which has already been
+    /// received is kept.
+    pub(crate) fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        let serial = self.serial.serial_port_mut();
+
+        let mut writer = BufWriter::new(serial);
+        let mut encoder = SlipEncoder::new(&mut writer)?;
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        writer.flush()?;
+        Ok(())
+    }
+
     pub fn command(&mut self, command: Command) -> Result<u32, Error> {
         let ty = command.command_type();
         self.write_command(command).for_command(ty)?;
 
-        for _ in 0..100 {
+        // Long-running operations such as erasing the flash may be interleaved with
+        // progress notifications from the stub, each of which extends the deadline
+        // for the final response.
+        let timeout = self.serial.serial_port().timeout();
+        let mut deadline = Instant::now() + timeout;
+
+        loop {
             match self.read_response().for_command(ty)? {
                 Some(response) if response.return_op == ty as u8 => {
-                    return if response.error != 0 {
+                    return if response.status != 0 {
                         let _error = self.flush();
                         Err(Error::RomError(RomError::new(
                             command.command_type(),
@@ -226,11 +823,45 @@ impl Connection {
                         Ok(response.value)
                     }
                 }
-                _ => {
-                    continue;
+                Some(response) => {
+                    debug!(
+                        "Received notification {:#04x} while waiting for {ty}",
+                        response.return_op
+                    );
+                    deadline = Instant::now() + timeout;
                 }
+                None => {}
+            }
+
+            if Instant::now() >= deadline {
+                return Err(Error::Connection(ConnectionError::ConnectionFailed));
+            }
+        }
+    }
+
+    /// Send a command and return the data contained in the response
+    ///
+    /// The returned data includes the trailing status bytes, whose length
+    /// depends on whether the ROM or the flasher stub is responding.
+    pub(crate) fn command_data(&mut self, command: Command) -> Result<Vec<u8>, Error> {
+        let ty = command.command_type();
+        self.write_command(command).for_command(ty)?;
+
+        for _ in 0..100 {
+            let response = match self.read(10).for_command(ty)? {
+                Some(response) => response,
+                None => continue,
+            };
+
+            let header: CommandResponse = Cursor::new(&response).read_le()?;
+            if header.return_op != ty as u8 {
+                continue;
             }
+
+            let end = response.len().min(8 + header.return_length as usize);
+            return Ok(response[8..end].to_vec());
         }
+
         Err(Error::Connection(ConnectionError::ConnectionFailed))
     }
 
@@ -255,7 +886,11 @@ impl Connection {
     pub(crate) fn read(&mut self, len: usize) -> Result<Option<Vec<u8>>, Error> {
         let mut tmp = Vec::with_capacity(1024);
         loop {
+            let start = tmp.len();
             self.decoder.decode(&mut self.serial, &mut tmp)?;
+            if let Some(tracer) = &mut self.tracer {
+                tracer.response(&tmp[start..]);
+            }
             if tmp.len() >= len {
                 return Ok(Some(tmp));
             }
@@ -280,6 +915,17 @@ impl Connection {
 pub fn reset_after_flash(serial: &mut Interface, pid: u16) -> Result<(), serialport::Error> {
     sleep(Duration::from_millis(100));
 
+    #[cfg(feature = "gpio")]
+    if let Some(pins) = serial.strapping_pins.as_mut() {
+        pins.set_en(false)?;
+
+        sleep(Duration::from_millis(100));
+
+        pins.set_en(true)?;
+
+        return Ok(());
+    }
+
     if pid == USB_SERIAL_JTAG_PID {
         serial.write_data_terminal_ready(false)?;
 
@@ -303,6 +949,92 @@ pub fn reset_after_flash(serial: &mut Interface, pid: u16) -> Result<(), serialp
     Ok(())
 }
 
+mod trace {
+    use std::{io::Write, time::Instant};
+
+    use binread::{io::Cursor, BinReaderExt};
+
+    use super::CommandResponse;
+    use crate::{
+        command::{Command, CommandType},
+        error::RomErrorKind,
+    };
+
+    const BYTES_PER_LINE: usize = 16;
+
+    /// Logs frames exchanged with the device, as hex dumps annotated with the
+    /// command name and the time elapsed since tracing began
+    ///
+    /// Failing to write the trace is not considered an error.
+    pub struct Tracer {
+        writer: Box<dyn Write + Send>,
+        start: Instant,
+    }
+
+    impl Tracer {
+        pub fn new(writer: Box<dyn Write + Send>) -> Self {
+            Tracer {
+                writer,
+                start: Instant::now(),
+            }
+        }
+
+        pub fn command(&mut self, command: &Command) {
+            let mut data = Vec::new();
+            if command.write(&mut data).is_err() {
+                return;
+            }
+
+            let ty = command.command_type();
+            let summary = format!("{} ({:#04x}), {} bytes", ty, ty as u8, data.len());
+            self.frame("->", &summary, &data);
+        }
+
+        pub fn response(&mut self, data: &[u8]) {
+            let summary = match Cursor::new(data).read_le::<CommandResponse>() {
+                Ok(response) if response.resp == 1 => {
+                    let name = CommandType::from_repr(response.return_op)
+                        .map(|ty| ty.to_string())
+                        .unwrap_or_else(|| "Unknown".into());
+                    let status = if response.status == 0 {
+                        "ok".to_string()
+                    } else {
+                        format!("failed: {}", RomErrorKind::from(response.error))
+                    };
+
+                    format!(
+                        "{} ({:#04x}), {} bytes, value {:#010x}, status {}",
+                        name,
+                        response.return_op,
+                        data.len(),
+                        response.value,
+                        status
+                    )
+                }
+                _ => format!("{} bytes, not a response", data.len()),
+            };
+
+            self.frame("<-", &summary, data);
+        }
+
+        fn frame(&mut self, direction: &str, summary: &str, data: &[u8]) {
+            let elapsed = self.start.elapsed().as_secs_f64();
+            let _ = writeln!(self.writer, "[{elapsed:>10.6}] {direction} {summary}");
+
+            for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+                let hex = line
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let _ = writeln!(self.writer, "    {:04x}  {hex}", i * BYTES_PER_LINE);
+            }
+
+            let _ = self.writer.flush();
+        }
+    }
+}
+
 mod encoder {
     use std::io::Write;
 
@@ -353,3 +1085,112 @@ mod encoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::MockTransport;
+
+    #[test]
+    fn test_parse_custom_reset() {
+        let reset: CustomReset = "D0|R1|W0.1|U1,0".parse().unwrap();
+        assert_eq!(
+            reset.steps,
+            vec![
+                ResetStep::Dtr(false),
+                ResetStep::Rts(true),
+                ResetStep::Wait(Duration::from_millis(100)),
+                ResetStep::DtrRts(true, false),
+            ]
+        );
+
+        assert!("D2".parse::<CustomReset>().is_err());
+        assert!("X0".parse::<CustomReset>().is_err());
+        assert!("W-1".parse::<CustomReset>().is_err());
+    }
+
+    #[test]
+    fn test_reset_to_bootloader() {
+        let mock = MockTransport::new();
+        let port_info = UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+        let mut connection = Connection::new(Interface::from_transport(mock.clone()), port_info);
+        connection.set_reset_sequence("D0|R1|W0.01|U1,0".parse().unwrap());
+
+        connection.reset_to_bootloader().unwrap();
+        assert_eq!(mock.control_lines(), (true, false));
+        assert!(mock.written().is_empty());
+    }
+
+    #[test]
+    fn test_boot_diagnosis() {
+        assert_eq!(
+            BootDiagnosis::from_output(b""),
+            Some(BootDiagnosis::NoOutput)
+        );
+        assert_eq!(
+            BootDiagnosis::from_output(
+                b"rst:0x1 (POWERON),boot:0x3 (DOWNLOAD(USB/UART0))\r\nwaiting for download\r\n"
+            ),
+            Some(BootDiagnosis::DownloadModeNoResponse)
+        );
+        assert_eq!(
+            BootDiagnosis::from_output(b"rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)"),
+            Some(BootDiagnosis::AppRunning)
+        );
+        // Download mode, captured before the ROM reported that it was waiting
+        assert_eq!(
+            BootDiagnosis::from_output(
+                b"rst:0x1 (POWERON_RESET),boot:0x3 (DOWNLOAD_BOOT(UART0/UART1/SDIO_REI_REO_V2))\r\n"
+            ),
+            Some(BootDiagnosis::DownloadModeNoResponse)
+        );
+        assert_eq!(
+            BootDiagnosis::from_output(
+                b"rst:0x15 (USB_UART_CHIP_RESET),boot:0x0 (DOWNLOAD(USB/UART0))\r\n\
+                  rst:0x1 (POWERON),boot:0x8 (SPI_FAST_FLASH_BOOT)\r\n"
+            ),
+            Some(BootDiagnosis::AppRunning)
+        );
+        assert_eq!(
+            BootDiagnosis::from_output(b"\x1b[0;32mI (312) app: running\x1b[0m\r\n"),
+            Some(BootDiagnosis::AppRunning)
+        );
+        assert_eq!(
+            BootDiagnosis::from_output(&[0xf8, 0x80, 0x00, 0x3c, 0xfe, 0x8c, 0x1e, 0x9f]),
+            Some(BootDiagnosis::UnreadableOutput)
+        );
+        assert_eq!(BootDiagnosis::from_output(b"hello"), None);
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let data = [0x01, 0x03, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08];
+        let response: CommandResponse = Cursor::new(&data).read_le().unwrap();
+
+        assert_eq!(response.status, 1);
+        assert_eq!(
+            RomErrorKind::from(response.error),
+            RomErrorKind::FlashWriteError
+        );
+    }
+
+    #[test]
+    fn test_scale_timeout() {
+        let policy = RetryPolicy::default().timeout(Duration::from_secs(6));
+
+        assert_eq!(
+            policy.scale_timeout(DEFAULT_TIMEOUT),
+            Duration::from_secs(6)
+        );
+        assert_eq!(
+            policy.scale_timeout(Duration::from_millis(50)),
+            Duration::from_millis(100)
+        );
+    }
+}
@@ -10,7 +10,7 @@ use std::{
 
 use xmas_elf::{
     program::Type,
-    sections::{SectionData, ShType},
+    sections::{ShType, SHF_ALLOC},
     ElfFile,
 };
 
@@ -38,13 +38,22 @@ pub trait FirmwareImage<'a> {
         )
     }
 
-    /// Firmware image RAM segments
+    /// Firmware image RAM segments, including any placed in RTC memory
     fn ram_segments(&'a self, chip: Chip) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
         Box::new(
             self.segments()
                 .filter(move |segment| !chip.into_target().addr_is_flash(segment.addr)),
         )
     }
+
+    /// Firmware image segments placed in RTC memory, such as deep-sleep wake
+    /// stubs
+    fn rtc_segments(&'a self, chip: Chip) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+        Box::new(
+            self.segments()
+                .filter(move |segment| chip.into_target().addr_is_rtc(segment.addr)),
+        )
+    }
 }
 
 /// A firmware image built from an ELF file
@@ -56,6 +65,32 @@ impl<'a> ElfFirmwareImage<'a> {
     pub fn new(elf: ElfFile<'a>) -> Self {
         Self { elf }
     }
+
+    /// Sections which occupy memory at runtime, including those without any
+    /// data in the file such as `.bss`
+    pub fn allocated_sections(&self) -> impl Iterator<Item = AllocatedSection<'a>> + '_ {
+        self.elf
+            .section_iter()
+            .filter(|header| {
+                header.flags() & SHF_ALLOC != 0 && header.size() > 0 && header.address() > 0
+            })
+            .map(|header| AllocatedSection {
+                name: header.get_name(&self.elf).unwrap_or("<unknown>"),
+                addr: header.address() as u32,
+                size: header.size() as u32,
+            })
+    }
+}
+
+/// A section of an ELF file which occupies memory at runtime
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatedSection<'a> {
+    /// Name of the section
+    pub name: &'a str,
+    /// Address of the section
+    pub addr: u32,
+    /// Size of the section, in bytes
+    pub size: u32,
 }
 
 impl<'a> TryFrom<&'a [u8]> for ElfFirmwareImage<'a> {
@@ -80,18 +115,21 @@ impl<'a> FirmwareImage<'a> for ElfFirmwareImage<'a> {
             self.elf
                 .section_iter()
                 .filter(|header| {
+                    // As with esptool, the arrays of constructor and destructor pointers
+                    // are loaded alongside the sections containing code and data
                     header.size() > 0
-                        && header.get_type() == Ok(ShType::ProgBits)
+                        && matches!(
+                            header.get_type(),
+                            Ok(ShType::ProgBits
+                                | ShType::InitArray
+                                | ShType::FiniArray
+                                | ShType::PreInitArray)
+                        )
                         && header.offset() > 0
                         && header.address() > 0
                 })
-                .flat_map(move |header| {
-                    let addr = header.address() as u32;
-                    let data = match header.get_data(&self.elf) {
-                        Ok(SectionData::Undefined(data)) => data,
-                        _ => return None,
-                    };
-                    Some(CodeSegment::new(addr, data))
+                .map(move |header| {
+                    CodeSegment::new(header.address() as u32, header.raw_data(&self.elf))
                 }),
         )
     }
@@ -171,6 +209,10 @@ impl<'a> CodeSegment<'a> {
         self.data.as_ref()
     }
 
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data.to_mut()
+    }
+
     pub fn pad_align(&mut self, align: usize) {
         let padding = (align - self.data.len() % align) % align;
         if padding > 0 {
@@ -234,6 +276,8 @@ pub struct RomSegment<'a> {
     pub addr: u32,
     /// Segment data
     pub data: Cow<'a, [u8]>,
+    /// Encrypt the segment as it is written, when flash encryption is enabled
+    pub encrypted: bool,
 }
 
 impl<'a> RomSegment<'a> {
@@ -244,6 +288,7 @@ impl<'a> RomSegment<'a> {
         RomSegment {
             addr: self.addr,
             data: Cow::Borrowed(self.data.as_ref()),
+            encrypted: self.encrypted,
         }
     }
 }
@@ -253,6 +298,28 @@ impl<'a> From<CodeSegment<'a>> for RomSegment<'a> {
         RomSegment {
             addr: segment.addr,
             data: segment.data,
+            encrypted: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_init_array_segments() {
+        let elf_data = fs::read("tests/resources/golden/esp32s3_synthetic").unwrap();
+        let image = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+
+        // `.init_array` is of type INIT_ARRAY rather than PROGBITS, but the
+        // pointers to the constructors it contains must still be loaded
+        let init_array = image
+            .segments()
+            .find(|segment| segment.addr == 0x3c01_2364)
+            .unwrap();
+        assert_eq!(init_array.size(), 8);
+    }
+}
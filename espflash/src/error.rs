@@ -3,17 +3,19 @@
 use std::{
     fmt::{Display, Formatter},
     io,
+    path::PathBuf,
 };
 
-use miette::Diagnostic;
+use miette::{Diagnostic, LabeledSpan, NamedSource};
 use slip_codec::SlipError;
 use strum::{FromRepr, VariantNames};
 use thiserror::Error;
 
 use crate::{
     command::CommandType,
-    flasher::{FlashFrequency, FlashSize},
-    image_format::ImageFormatKind,
+    connection::{BootDiagnosis, ResetSequence},
+    flasher::{FlashFrequency, FlashMode, FlashSize},
+    image_format::{registered_image_formats, ImageFormatKind},
     interface::SerialConfigError,
     targets::Chip,
 };
@@ -34,6 +36,13 @@ pub enum Error {
     )]
     ChipDetectError(u32),
 
+    #[error("Unrecognized chip ID: {0}")]
+    #[diagnostic(
+        code(espflash::chip_id_detect_error),
+        help("Supported chips are: {}", Chip::VARIANTS.join(", "))
+    )]
+    ChipIdDetectError(u32),
+
     #[error("Supplied ELF image can not be run from RAM, as it includes segments mapped to ROM addresses")]
     #[diagnostic(
         code(espflash::not_ram_loadable),
@@ -44,15 +53,148 @@ pub enum Error {
     #[error(
         "Supplied ELF image of {0}B is too big, and doesn't fit configured app partition of {1}B"
     )]
+    #[diagnostic(
+        code(espflash::image_too_big),
+        help("Reduce the size of the application, provide a partition table with a larger app partition, or use `--auto-grow-partition` with the default partition table")
+    )]
     ElfTooBig(u32, u32),
 
+    #[error(
+        "Writing the application at {offset:#x} would erase flash up to {erase_end:#x}, beyond the \
+         end of its {size:#x} byte partition"
+    )]
+    #[diagnostic(
+        code(espflash::unaligned_app_partition),
+        help("Flash is erased in 4KB sectors; ensure the offset and size of the application partition are multiples of 0x1000")
+    )]
+    UnalignedAppPartition {
+        offset: u32,
+        size: u32,
+        erase_end: u32,
+    },
+
+    #[error("The factory partition can only be grown when using the default partition table")]
+    #[diagnostic(
+        code(espflash::auto_grow_custom_partition_table),
+        help("Increase the size of the app partition in the custom partition table instead")
+    )]
+    AutoGrowCustomPartitionTable,
+
+    #[error("Filesystem image of {0}B is too big, and doesn't fit the partition of {1}B")]
+    #[diagnostic(code(espflash::fs_image_too_big))]
+    FsImageTooBig(u32, u32),
+
+    #[error("The files to include in the filesystem image don't fit in the partition of {0}B")]
+    #[diagnostic(
+        code(espflash::fs_contents_too_big),
+        help("Remove some of the files, or increase the size of the partition")
+    )]
+    FsContentsTooBig(u32),
+
+    #[error("The file name '{0}' is too long for the filesystem")]
+    #[diagnostic(
+        code(espflash::fs_name_too_long),
+        help("File names (including their path, for SPIFFS) are limited to {1} bytes")
+    )]
+    FsNameTooLong(String, u32),
+
     #[error("Failed to connect to on-device flash")]
     #[diagnostic(code(espflash::flash_connect))]
     FlashConnect,
 
+    #[error("Failed to detect the size of the flash chip")]
+    #[diagnostic(
+        code(espflash::flash_size_detection),
+        help("Specify the size of the flash chip using `--flash-size` instead")
+    )]
+    FlashSizeDetection,
+
+    #[error("The flash size can only be detected when a device is connected")]
+    #[diagnostic(
+        code(espflash::flash_size_detection_offline),
+        help("Specify the size of the flash chip using `--flash-size` instead")
+    )]
+    FlashSizeDetectionOffline,
+
+    #[error("The image extends to {end:#x}, beyond the end of the {flash_size} flash")]
+    #[diagnostic(
+        code(espflash::image_exceeds_flash),
+        help("Use a partition table which fits within the flash, or check the flash size")
+    )]
+    ImageExceedsFlash { end: u32, flash_size: FlashSize },
+
+    #[error("The provided application binary is not a valid ESP image")]
+    #[diagnostic(
+        code(espflash::invalid_app_image),
+        help("Application binaries must begin with the ESP image magic byte (0xE9)")
+    )]
+    InvalidAppImage,
+
+    #[error("The application does not contain an application description")]
+    #[diagnostic(
+        code(espflash::app_description_not_found),
+        help("The secure version is stored in the application description (`esp_app_desc_t`), which is included in applications built using ESP-IDF")
+    )]
+    AppDescriptionNotFound,
+
+    #[error("The secure version {0} exceeds the maximum of {1} supported by the eFuses")]
+    #[diagnostic(code(espflash::secure_version_too_large))]
+    SecureVersionTooLarge(u32, u32),
+
+    #[error("The secure version can not be lowered from {current} to {requested}")]
+    #[diagnostic(
+        code(espflash::secure_version_rollback),
+        help("eFuse bits can only be set, so the secure version can never be decreased")
+    )]
+    SecureVersionRollback { current: u32, requested: u32 },
+
+    #[error("Failed to burn the eFuses, as {0}")]
+    #[diagnostic(code(espflash::efuse_burn_failed))]
+    EFuseBurnFailed(String),
+
+    #[error("The device returned an invalid security info response")]
+    #[diagnostic(code(espflash::invalid_security_info))]
+    InvalidSecurityInfo,
+
     #[error("The provided bootloader binary is invalid")]
     InvalidBootloader,
 
+    #[error("The bootloader's flash {setting} is not supported by the {chip}")]
+    #[diagnostic(
+        code(espflash::unsupported_bootloader_flash_setting),
+        help("Override the setting on the command line, or make sure the bootloader was built for the correct chip")
+    )]
+    UnsupportedBootloaderFlashSetting { setting: &'static str, chip: Chip },
+
+    #[error("No filesystem partition could be found in the partition table")]
+    #[diagnostic(
+        code(espflash::missing_fs_partition),
+        help("Specify the label of the partition to write to using `--partition`")
+    )]
+    MissingFsPartition,
+
+    #[error("The partition table does not contain a partition labelled '{0}'")]
+    #[diagnostic(code(espflash::partition_not_found))]
+    PartitionNotFound(String),
+
+    #[error("The image is malformed: {0}")]
+    #[diagnostic(code(espflash::malformed_image))]
+    MalformedImage(&'static str),
+
+    #[error("Invalid reset sequence '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_reset_sequence),
+        help("Use one of {}, or a custom sequence such as `D0|R1|W0.1|D1|R0|W0.05|D0`", ResetSequence::NAMES.join(", "))
+    )]
+    InvalidResetSequence(String),
+
+    #[error("Invalid SPI connection '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_spi_connection),
+        help("Use `SPI`, `HSPI`, or the `CLK,Q,D,HD,CS` pin numbers, each no greater than 63")
+    )]
+    InvalidSpiConnection(String),
+
     #[error("Binary is not set up correctly to support direct boot")]
     #[diagnostic(
         code(espflash::invalid_direct_boot),
@@ -91,13 +233,180 @@ pub enum Error {
     )]
     SerialNotFound(String),
 
+    #[error("No serial port was found for the USB device with serial number '{0}'")]
+    #[diagnostic(
+        code(espflash::serial_number_not_found),
+        help("Make sure the correct device is connected to the host system")
+    )]
+    SerialNumberNotFound(String),
+
+    #[error("Serial ports can only be selected by USB port path on Linux")]
+    #[diagnostic(
+        code(espflash::usb_port_path_unsupported),
+        help("Select the device using `--serial-number` instead")
+    )]
+    UsbPortPathUnsupported,
+
+    #[error("The serial port '{port}' is in use by another process, or access to it was denied")]
+    #[diagnostic(
+        code(espflash::serial_port_in_use),
+        help("{}", serial_port_in_use_help(holders))
+    )]
+    SerialPortInUse { port: String, holders: Vec<String> },
+
+    #[error("Exclusive access to serial ports is not supported on this platform")]
+    #[diagnostic(
+        code(espflash::exclusive_access_unsupported),
+        help("Run the command again without `--exclusive`")
+    )]
+    ExclusiveAccessUnsupported,
+
+    #[error("Permission to open the serial port '{port}' was denied")]
+    #[diagnostic(code(espflash::serial_port_permission_denied), help("{guidance}"))]
+    SerialPortPermissionDenied { port: String, guidance: String },
+
+    #[error("An image format named '{0}' already exists")]
+    #[diagnostic(code(espflash::duplicate_image_format))]
+    DuplicateImageFormat(String),
+
     #[error("Unrecognized image format '{0}'")]
     #[diagnostic(
         code(espflash::unknown_format),
-        help("The following image formats are supported: {}", ImageFormatKind::VARIANTS.join(", "))
+        help("The following image formats are supported: {}", ImageFormatKind::names().join(", "))
     )]
     UnknownImageFormat(String),
 
+    #[error("The device is in secure download mode, which does not permit {0}")]
+    #[diagnostic(
+        code(espflash::secure_download_mode),
+        help("Only writing to flash is possible in secure download mode, and it can not be disabled once enabled")
+    )]
+    SecureDownloadMode(&'static str),
+
+    #[error("The image requires chip revision v{}.{} or newer, but the connected chip is revision v{}.{}", .required.0, .required.1, .detected.0, .detected.1)]
+    #[diagnostic(
+        code(espflash::unsupported_chip_revision),
+        help("Rebuild the application for an older chip revision, or pass `--ignore-chip-rev` to flash it anyway")
+    )]
+    UnsupportedChipRevision {
+        required: (u32, u32),
+        detected: (u32, u32),
+    },
+
+    #[error("The flash chip's write protection could not be cleared")]
+    #[diagnostic(
+        code(espflash::flash_protection_not_cleared),
+        help("The status register may be locked by the flash chip's WP pin")
+    )]
+    FlashProtectionNotCleared,
+
+    #[error("Address {0:#x} is not aligned to a word boundary")]
+    #[diagnostic(
+        code(espflash::unaligned_address),
+        help("Memory can only be accessed in whole 32-bit words")
+    )]
+    UnalignedAddress(u32),
+
+    #[error("Invalid chip revision '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_chip_revision),
+        help("Chip revisions must be given as 'MAJOR.MINOR', eg. 'v0.3' or '3.1'")
+    )]
+    InvalidChipRevision(String),
+
+    #[error("Invalid application '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_app_partition),
+        help("Applications must be given as 'FILE@LABEL', where LABEL is the app partition to write to")
+    )]
+    InvalidAppPartition(String),
+
+    #[error("The {part} was built for the {found}, not the {expected}")]
+    #[diagnostic(
+        code(espflash::image_chip_mismatch),
+        help("Make sure the {part} was built for the correct chip")
+    )]
+    ImageChipMismatch {
+        part: &'static str,
+        expected: Chip,
+        found: Chip,
+    },
+
+    #[error("The application was built for {target}, which is not supported by the {chip}")]
+    #[diagnostic(
+        code(espflash::unsupported_build_target),
+        help(
+            "The following targets are supported by the {chip}: {}",
+            chip.into_target().supported_build_targets().join(", ")
+        )
+    )]
+    UnsupportedBuildTarget { target: String, chip: Chip },
+
+    #[error("No applications were provided to flash")]
+    #[diagnostic(code(espflash::no_apps))]
+    NoApps,
+
+    #[error("The image format does not include a {0}")]
+    #[diagnostic(
+        code(espflash::missing_image_part),
+        help("Only images using the ESP-IDF bootloader include a bootloader and partition table")
+    )]
+    MissingImagePart(&'static str),
+
+    #[error("Multiple applications would be written to the partition at {0:#x}")]
+    #[diagnostic(
+        code(espflash::duplicate_app_partition),
+        help("Write each application to a different app partition")
+    )]
+    DuplicateAppPartition(u32),
+
+    #[error("Multiple applications can only be flashed using the ESP-IDF bootloader")]
+    #[diagnostic(code(espflash::multiple_apps_unsupported))]
+    MultipleAppsUnsupported,
+
+    #[error("QEMU does not support a flash size of {0}")]
+    #[diagnostic(
+        code(espflash::unsupported_qemu_flash_size),
+        help("Use a flash size of 2MB, 4MB, 8MB or 16MB")
+    )]
+    UnsupportedQemuFlashSize(FlashSize),
+
+    #[error("The device responded with a malformed MD5 digest")]
+    #[diagnostic(code(espflash::invalid_md5_response))]
+    InvalidMd5Response,
+
+    #[error("The contents of flash at {0:#x} do not match the data which was written")]
+    #[diagnostic(
+        code(espflash::verify_failed),
+        help("Make sure the flash chip is connected correctly and is not write-protected")
+    )]
+    VerifyFailed(u32),
+
+    #[error("The data read from flash at {0:#x} was corrupted in transfer")]
+    #[diagnostic(
+        code(espflash::read_flash_failed),
+        help("Try again, or use a lower baud rate")
+    )]
+    ReadFlashFailed(u32),
+
+    #[error("The NVS partition '{name}' can not be migrated to a smaller partition")]
+    #[diagnostic(
+        code(espflash::nvs_migration_failed),
+        help("The partition is {size:#x} bytes, but would be {new_size:#x} bytes in the new partition table")
+    )]
+    NvsMigrationFailed {
+        name: String,
+        size: u32,
+        new_size: u32,
+    },
+
+    #[error("Encrypted data can't be written at {0:#x}, which is not 32-byte aligned")]
+    #[diagnostic(
+        code(espflash::unaligned_encrypted_write),
+        help("Only encrypt partitions whose offsets are aligned to 32 bytes")
+    )]
+    UnalignedEncryptedWrite(u32),
+
     #[error("The {chip} does not support {feature}")]
     #[diagnostic(code(espflash::unsupported_feature))]
     UnsupportedFeature { chip: Chip, feature: String },
@@ -106,13 +415,114 @@ pub enum Error {
     #[diagnostic(code(espflash::unrecognized_flash))]
     UnsupportedFlash(u8),
 
+    #[error("The flash mode '{flash_mode}' is not supported by the {chip}")]
+    #[diagnostic(
+        code(espflash::unsupported_flash_mode),
+        help("The {chip} supports the following flash modes: {}", supported_flash_modes(*chip))
+    )]
+    UnsupportedFlashMode { chip: Chip, flash_mode: FlashMode },
+
     #[error("The specified flash frequency '{frequency}' is not supported by the {chip}")]
-    #[diagnostic(code(espflash::unsupported_flash_frequency))]
+    #[diagnostic(
+        code(espflash::unsupported_flash_frequency),
+        help("The {chip} supports the following flash frequencies: {}", supported_flash_frequencies(*chip))
+    )]
     UnsupportedFlashFrequency {
         chip: Chip,
         frequency: FlashFrequency,
     },
 
+    #[error("The flash size '{flash_size}' is not supported by the {chip}")]
+    #[diagnostic(
+        code(espflash::unsupported_flash_size),
+        help("The {chip} can address at most {} of flash", chip.into_target().max_flash_size())
+    )]
+    UnsupportedFlashSize { chip: Chip, flash_size: FlashSize },
+
+    #[error("The baud rate of the connection can't be changed")]
+    #[diagnostic(
+        code(espflash::unchangeable_baud_rate),
+        help("The baud rate of a raw TCP port is configured by the server; connect using an rfc2217:// port to allow it to be changed")
+    )]
+    UnchangeableBaudRate,
+
+    #[error("No serial updater responded on the serial port")]
+    #[diagnostic(
+        code(espflash::updater_not_found),
+        help("Make sure the application running on the device provides the serial update service, and that the baud rate matches that of its console")
+    )]
+    UpdaterNotFound,
+
+    #[error("The serial updater uses version {0} of its protocol, which is not supported")]
+    #[diagnostic(code(espflash::unsupported_updater_version))]
+    UnsupportedUpdaterVersion(u8),
+
+    #[error("The serial updater sent a malformed response")]
+    #[diagnostic(code(espflash::invalid_updater_response))]
+    InvalidUpdaterResponse,
+
+    #[error("The serial updater did not respond to the {0} command")]
+    #[diagnostic(code(espflash::updater_timeout))]
+    UpdaterTimeout(&'static str),
+
+    #[error("The serial updater rejected the {command} command: {reason}")]
+    #[diagnostic(code(espflash::updater_rejected))]
+    UpdaterRejected {
+        command: &'static str,
+        reason: &'static str,
+    },
+
+    #[error("The image is {size} bytes, which does not fit in the serial updater's {partition_size} byte partition")]
+    #[diagnostic(code(espflash::updater_image_too_large))]
+    UpdaterImageTooLarge { size: usize, partition_size: u32 },
+
+    #[error("Failed to run OpenOCD using '{}'", .0.display())]
+    #[diagnostic(
+        code(espflash::openocd_not_found),
+        help("Install Espressif's fork of OpenOCD, eg. using the ESP-IDF's install script, and make sure it is in your PATH or pass its path using `--openocd`")
+    )]
+    OpenOcdNotFound(PathBuf, #[source] io::Error),
+
+    #[error("OpenOCD failed to flash the device{}", .0.map(|code| format!(", exiting with status {code}")).unwrap_or_default())]
+    #[diagnostic(
+        code(espflash::openocd_failed),
+        help("Check OpenOCD's output above; make sure the JTAG adapter is connected, and select its interface configuration using `--jtag-interface` if it is not the chip's default")
+    )]
+    OpenOcdFailed(Option<i32>),
+
+    #[error("The signing key is not a valid RSA-3072 or ECDSA P-256 private key")]
+    #[diagnostic(
+        code(espflash::invalid_signing_key),
+        help("Generate a key using `espflash keygen --secure-boot-v2`")
+    )]
+    InvalidSigningKey,
+
+    #[error("Failed to generate the key: {0}")]
+    #[diagnostic(code(espflash::key_generation_failed))]
+    KeyGenerationFailed(String),
+
+    #[error("Failed to sign the image: {0}")]
+    #[diagnostic(code(espflash::signing_failed))]
+    SigningFailed(String),
+
+    #[error("Invalid PKCS#11 URI {0}")]
+    #[diagnostic(
+        code(espflash::invalid_pkcs11_uri),
+        help("Reference the key using eg. 'pkcs11:token=TOKEN;object=LABEL?module-path=MODULE'")
+    )]
+    InvalidPkcs11Uri(String),
+
+    #[error("The image can't be signed, as {0}")]
+    #[diagnostic(
+        code(espflash::unsignable_image),
+        help("Sign the unmodified image produced by the build, which must have a SHA-256 digest appended")
+    )]
+    UnsignableImage(&'static str),
+
+    #[error("The image's signature is invalid, as {0}")]
+    #[diagnostic(code(espflash::invalid_signature))]
+    InvalidSignature(&'static str),
+
     #[error("Error while connecting to device")]
     #[diagnostic(transparent)]
     Connection(#[source] ConnectionError),
@@ -177,6 +587,19 @@ pub enum ConnectionError {
     )]
     ConnectionFailed,
 
+    #[error(
+        "Failed to connect to the device\n\nOutput from the ROM after resetting the device:\n{0}"
+    )]
+    #[diagnostic(
+        code(espflash::connection_failed),
+        help("The ROM's output shows the boot mode selected by the strapping pins, and whether the device is stuck in a boot loop")
+    )]
+    ConnectionFailedWithRomLog(String),
+
+    #[error("Failed to connect to the device: {diagnosis}")]
+    #[diagnostic(code(espflash::connection_failed), help("{}", diagnosis.help()))]
+    ConnectionFailedDiagnosed { diagnosis: BootDiagnosis },
+
     #[error("Serial port not found")]
     #[diagnostic(
         code(espflash::connection_failed),
@@ -268,12 +691,16 @@ impl From<CommandType> for TimedOutCommand {
     }
 }
 
-#[derive(Clone, Copy, Debug, Default, Diagnostic, Error, FromRepr)]
+/// The error codes returned by the ROM bootloader and flasher stub
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Diagnostic, Error, FromRepr)]
 #[non_exhaustive]
 #[repr(u8)]
 pub enum RomErrorKind {
     #[error("Invalid message received")]
-    #[diagnostic(code(espflash::rom::invalid_message))]
+    #[diagnostic(
+        code(espflash::rom::invalid_message),
+        help("The message was likely corrupted in transit; check the wiring or USB cable, or try a lower baud rate")
+    )]
     InvalidMessage = 0x05,
 
     #[error("Bootloader failed to execute command")]
@@ -281,15 +708,24 @@ pub enum RomErrorKind {
     FailedToAct = 0x06,
 
     #[error("Received message has invalid CRC")]
-    #[diagnostic(code(espflash::rom::crc))]
+    #[diagnostic(
+        code(espflash::rom::crc),
+        help("The message was likely corrupted in transit; check the wiring or USB cable, or try a lower baud rate")
+    )]
     InvalidCrc = 0x07,
 
     #[error("Bootloader failed to write to flash")]
-    #[diagnostic(code(espflash::rom::flash_write))]
+    #[diagnostic(
+        code(espflash::rom::flash_write),
+        help("The flash chip may be write-protected; check its WP pin and status register")
+    )]
     FlashWriteError = 0x08,
 
     #[error("Bootloader failed to read from flash")]
-    #[diagnostic(code(espflash::rom::flash_read))]
+    #[diagnostic(
+        code(espflash::rom::flash_read),
+        help("Make sure the flash chip is connected and powered correctly")
+    )]
     FlashReadError = 0x09,
 
     #[error("Invalid length for flash read")]
@@ -305,7 +741,10 @@ pub enum RomErrorKind {
     BadDataLen = 0xc0,
 
     #[error("Bad data checksum")]
-    #[diagnostic(code(espflash::rom::data_crc))]
+    #[diagnostic(
+        code(espflash::rom::data_crc),
+        help("The data was likely corrupted in transit; check the wiring or USB cable, or try a lower baud rate")
+    )]
     BadDataChecksum = 0xc1,
 
     #[error("Bad block size")]
@@ -313,7 +752,10 @@ pub enum RomErrorKind {
     BadBlocksize = 0xc2,
 
     #[error("Invalid command")]
-    #[diagnostic(code(espflash::rom::cmd))]
+    #[diagnostic(
+        code(espflash::rom::cmd),
+        help("The command is not supported by the flasher stub")
+    )]
     InvalidCommand = 0xc3,
 
     #[error("SPI operation failed")]
@@ -321,7 +763,10 @@ pub enum RomErrorKind {
     FailedSpiOp = 0xc4,
 
     #[error("SPI unlock failed")]
-    #[diagnostic(code(espflash::rom::spi_unlock))]
+    #[diagnostic(
+        code(espflash::rom::spi_unlock),
+        help("The flash chip's status register may be locked; check its WP pin")
+    )]
     FailedSpiUnlock = 0xc5,
 
     #[error("Not in flash mode")]
@@ -341,8 +786,11 @@ pub enum RomErrorKind {
     TooMuchData = 0xc9,
 
     #[default]
-    #[error("Other")]
-    #[diagnostic(code(espflash::rom::other))]
+    #[error("Unknown error, or command not implemented")]
+    #[diagnostic(
+        code(espflash::rom::other),
+        help("The command may not be supported by this chip's ROM bootloader; try using the flasher stub")
+    )]
     Other = 0xff,
 }
 
@@ -352,7 +800,9 @@ impl From<u8> for RomErrorKind {
     }
 }
 
-#[derive(Clone, Copy, Debug, Diagnostic, Error)]
+/// An error reported by the ROM bootloader or flasher stub in response to a
+/// command
+#[derive(Clone, Copy, Debug, Error)]
 #[error("Error while running {command} command")]
 #[non_exhaustive]
 pub struct RomError {
@@ -365,6 +815,26 @@ impl RomError {
     pub fn new(command: CommandType, kind: RomErrorKind) -> RomError {
         RomError { command, kind }
     }
+
+    /// The command which failed
+    pub fn command(&self) -> CommandType {
+        self.command
+    }
+
+    /// The error reported by the device
+    pub fn kind(&self) -> RomErrorKind {
+        self.kind
+    }
+}
+
+impl Diagnostic for RomError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.kind.code()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.kind.help()
+    }
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -386,6 +856,161 @@ impl From<String> for MissingPartition {
 #[diagnostic(code(espflash::partition_table::missing_partition_table))]
 pub struct MissingPartitionTable;
 
+/// An invalid CSV partition table, with labels pointing at the partitions
+/// responsible
+#[derive(Debug)]
+pub struct PartitionTableError {
+    err: esp_idf_part::Error,
+    source: NamedSource,
+    labels: Vec<LabeledSpan>,
+}
+
+impl PartitionTableError {
+    pub fn new(err: esp_idf_part::Error, name: &str, csv: String) -> Self {
+        let labels = partition_table_labels(&err, &csv);
+
+        Self {
+            err,
+            source: NamedSource::new(name, csv),
+            labels,
+        }
+    }
+}
+
+impl Display for PartitionTableError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.err {
+            esp_idf_part::Error::CsvError(_) => write!(f, "Failed to parse the partition table"),
+            err => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Diagnostic for PartitionTableError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(
+            "espflash::partition_table::invalid_partition_table",
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        Some(&self.source)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        if self.labels.is_empty() {
+            None
+        } else {
+            Some(Box::new(self.labels.iter().cloned()))
+        }
+    }
+}
+
+// NOTE: no `source` on purpose to prevent duplicating the message
+impl std::error::Error for PartitionTableError {}
+
+/// Label the lines of a CSV partition table responsible for an error
+fn partition_table_labels(err: &esp_idf_part::Error, csv: &str) -> Vec<LabeledSpan> {
+    // The line number, byte offset and contents of each line
+    let mut offset = 0;
+    let lines = csv
+        .split_inclusive('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            let start = offset;
+            offset += line.len();
+            (i as u64 + 1, start, line.trim_end())
+        })
+        .collect::<Vec<_>>();
+
+    // The byte span of a field within a line, excluding surrounding whitespace
+    let field_span = |start: usize, line: &str, field: usize| {
+        let mut field_start = 0;
+        for (i, value) in line.split(',').enumerate() {
+            if i == field {
+                let trimmed = value.trim_start();
+                let leading = value.len() - trimmed.len();
+                return (start + field_start + leading, trimmed.trim_end().len());
+            }
+            field_start += value.len() + 1;
+        }
+
+        (start + line.len(), 0)
+    };
+
+    let label_partition = |name: &str, label: String| {
+        lines
+            .iter()
+            .filter(|(_, _, line)| !line.trim_start().starts_with('#'))
+            .filter(|(_, _, line)| line.split(',').next().map(str::trim) == Some(name))
+            .map(|(_, start, line)| {
+                let (offset, len) = field_span(*start, line, 0);
+                LabeledSpan::new(Some(label.clone()), offset, len)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    match err {
+        esp_idf_part::Error::CsvError(err) => match err.kind() {
+            csv::ErrorKind::Deserialize {
+                pos: Some(pos),
+                err,
+            } => lines
+                .iter()
+                .find(|(line_number, ..)| *line_number == pos.line())
+                .map(|(_, start, line)| {
+                    let message = err.kind().to_string();
+                    let field = err
+                        .field()
+                        .map(|field| field as usize)
+                        .or_else(|| invalid_csv_field(&message, line));
+                    let (offset, len) = match field {
+                        Some(field) => field_span(*start, line, field),
+                        None => (*start, line.len()),
+                    };
+                    LabeledSpan::new(Some(message), offset, len)
+                })
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        },
+        esp_idf_part::Error::DuplicatePartitions(name) => {
+            label_partition(name, "defined here".into())
+        }
+        esp_idf_part::Error::OverlappingPartitions(a, b) => {
+            let mut labels = label_partition(a, format!("overlaps with '{b}'"));
+            labels.extend(label_partition(b, format!("overlaps with '{a}'")));
+            labels
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Find the field of a CSV partition which failed to parse, based on the error
+/// message, as the position of the field is not reported
+fn invalid_csv_field(message: &str, line: &str) -> Option<usize> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let is_offset_or_size = |value: &str| match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).is_ok(),
+        None => value
+            .strip_suffix(['k', 'K', 'm', 'M'])
+            .unwrap_or(value)
+            .parse::<u32>()
+            .is_ok(),
+    };
+
+    match message {
+        "invalid partition type" => Some(1),
+        "invalid partition subtype" => Some(2),
+        // The offset may be omitted, but the size may not
+        "invalid partition size/offset format" => match fields.get(3) {
+            Some(offset) if !offset.is_empty() && !is_offset_or_size(offset) => Some(3),
+            _ => Some(4),
+        },
+        _ => None,
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("{0}")]
 pub struct ElfError(&'static str);
@@ -396,6 +1021,36 @@ impl From<&'static str> for ElfError {
     }
 }
 
+fn serial_port_in_use_help(holders: &[String]) -> String {
+    if holders.is_empty() {
+        "Close any other program using the port, such as a serial monitor, and make sure you have \
+         permission to access it"
+            .into()
+    } else {
+        format!("The port is open in: {}", holders.join(", "))
+    }
+}
+
+fn supported_flash_frequencies(chip: Chip) -> String {
+    let mut frequencies = chip.into_target().flash_frequencies();
+    frequencies.sort_by_key(|frequency| *frequency as u8);
+
+    frequencies
+        .iter()
+        .map(|frequency| frequency.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn supported_flash_modes(chip: Chip) -> String {
+    chip.into_target()
+        .flash_modes()
+        .iter()
+        .map(|mode| mode.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[derive(Debug)]
 pub struct UnsupportedImageFormatError {
     format: ImageFormatKind,
@@ -419,8 +1074,14 @@ impl UnsupportedImageFormatError {
             .into_target()
             .supported_image_formats()
             .iter()
-            .map(|format| format.into())
-            .collect::<Vec<&'static str>>()
+            .map(ImageFormatKind::as_str)
+            .chain(
+                registered_image_formats()
+                    .iter()
+                    .filter(|factory| factory.supports_chip(self.chip))
+                    .map(|factory| factory.name()),
+            )
+            .collect::<Vec<_>>()
             .join(", ")
     }
 
@@ -507,3 +1168,33 @@ where
         _ => ConnectionError::Serial(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use esp_idf_part::PartitionTable;
+
+    use super::*;
+
+    fn labels(csv: &str) -> Vec<(usize, usize)> {
+        let err = PartitionTable::try_from_str(csv).unwrap_err();
+        PartitionTableError::new(err, "partitions.csv", csv.into())
+            .labels()
+            .map(|labels| labels.map(|label| (label.offset(), label.len())).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_partition_table_error_labels() {
+        // The invalid size of the factory partition
+        let csv = "# Name, Type, SubType, Offset, Size\n\
+                   nvs, data, nvs, 0x9000, 0x6000,\n\
+                   factory, app, factory, 0x10000, 1Q,\n";
+        assert_eq!(labels(csv), vec![(100, 2)]);
+
+        // The names of both overlapping partitions
+        let csv = "nvs, data, nvs, 0x9000, 0x6000,\n\
+                   factory, app, factory, 0x10000, 1M,\n\
+                   store, data, spiffs, 0x9000, 64K,\n";
+        assert_eq!(labels(csv), vec![(0, 3), (68, 5)]);
+    }
+}
@@ -0,0 +1,220 @@
+//! C-compatible API for embedding the flasher in other applications
+//!
+//! This allows tools written in other languages, such as C++ provisioning
+//! tools, to flash devices without shelling out to the `espflash` binary. The
+//! corresponding header can be found in `include/espflash.h`, and a shared
+//! library can be built using:
+//!
+//! ```bash
+//! $ cargo rustc -p espflash --lib --release --no-default-features --features=ffi --crate-type=cdylib
+//! ```
+//!
+//! All functions return [ESPFLASH_OK] on success, or a negative error code on
+//! failure, in which case a description of the error can be retrieved using
+//! [espflash_last_error].
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{
+    flasher::{ConnectOptions, FlashData, Flasher, ProgressCallbacks},
+    interface::{find_port, Interface},
+};
+
+/// The operation completed successfully
+pub const ESPFLASH_OK: c_int = 0;
+/// One of the arguments was invalid, eg.) a null pointer
+pub const ESPFLASH_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The operation failed
+pub const ESPFLASH_ERR_FAILED: c_int = -2;
+/// The operation panicked
+pub const ESPFLASH_ERR_PANIC: c_int = -3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque handle to a connected target device
+pub struct EspflashFlasher {
+    flasher: Flasher,
+}
+
+/// Callbacks used to report the progress of flashing
+///
+/// Any of the callbacks may be null, and `user_data` is passed to each of them
+/// unchanged.
+#[repr(C)]
+pub struct EspflashProgressCallbacks {
+    pub user_data: *mut c_void,
+    pub init: Option<extern "C" fn(user_data: *mut c_void, addr: u32, total: usize)>,
+    pub update: Option<extern "C" fn(user_data: *mut c_void, current: usize)>,
+    pub finish: Option<extern "C" fn(user_data: *mut c_void)>,
+}
+
+impl ProgressCallbacks for &EspflashProgressCallbacks {
+    fn init(&mut self, addr: u32, total: usize) {
+        if let Some(init) = self.init {
+            init(self.user_data, addr, total);
+        }
+    }
+
+    fn update(&mut self, current: usize) {
+        if let Some(update) = self.update {
+            update(self.user_data, current);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(finish) = self.finish {
+            finish(self.user_data);
+        }
+    }
+}
+
+/// Connect to the target device attached to the serial port `port`
+///
+/// If `baud` is non-zero, the baud rate is changed to it once connected. On
+/// success, the handle is written to `flasher`, and must be released using
+/// [espflash_free].
+///
+/// # Safety
+///
+/// `port` must be a valid, nul-terminated string, and `flasher` must be valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_connect(
+    port: *const c_char,
+    baud: u32,
+    flasher: *mut *mut EspflashFlasher,
+) -> c_int {
+    if port.is_null() || flasher.is_null() {
+        return invalid_argument("port and flasher must not be null");
+    }
+    let port = match CStr::from_ptr(port).to_str() {
+        Ok(port) => port,
+        Err(_) => return invalid_argument("port is not valid UTF-8"),
+    };
+
+    ffi_call(|| {
+        let (port_info, usb_info) = find_port(port);
+
+        let interface = Interface::new(&port_info, None, None, false).map_err(|e| e.to_string())?;
+        let options = ConnectOptions::default().speed((baud != 0).then_some(baud));
+        let connected =
+            Flasher::connect(interface, usb_info, options).map_err(|e| e.to_string())?;
+
+        *flasher = Box::into_raw(Box::new(EspflashFlasher { flasher: connected }));
+
+        Ok(())
+    })
+}
+
+/// Write the ELF image `data` of `len` bytes to the device's flash, along with
+/// the default bootloader and partition table, and reset the device
+///
+/// `progress` may be null if progress does not need to be reported.
+///
+/// # Safety
+///
+/// `flasher` must have been returned by [espflash_connect] and not yet freed,
+/// `data` must be valid for reads of `len` bytes, and `progress` must be null
+/// or point to a valid set of callbacks.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_flash_elf(
+    flasher: *mut EspflashFlasher,
+    data: *const u8,
+    len: usize,
+    progress: *const EspflashProgressCallbacks,
+) -> c_int {
+    if flasher.is_null() || data.is_null() {
+        return invalid_argument("flasher and data must not be null");
+    }
+    let flasher = &mut (*flasher).flasher;
+    let data = slice::from_raw_parts(data, len);
+    let mut progress = progress.as_ref();
+
+    ffi_call(|| {
+        flasher
+            .load_elf_to_flash(
+                data,
+                FlashData::default(),
+                progress
+                    .as_mut()
+                    .map(|progress| progress as &mut dyn ProgressCallbacks),
+            )
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Disconnect from the device and release the handle
+///
+/// # Safety
+///
+/// `flasher` must be null, or have been returned by [espflash_connect] and
+/// not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_free(flasher: *mut EspflashFlasher) {
+    if !flasher.is_null() {
+        drop(Box::from_raw(flasher));
+    }
+}
+
+/// Describe the most recent error which occurred on the calling thread
+///
+/// Returns null if no error has occurred. The string remains valid until the
+/// next call to a function of this API on the same thread.
+#[no_mangle]
+pub extern "C" fn espflash_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |error| error.as_ptr())
+    })
+}
+
+fn set_last_error(message: &str) {
+    let error = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(error));
+}
+
+fn invalid_argument(message: &str) -> c_int {
+    set_last_error(message);
+    ESPFLASH_ERR_INVALID_ARGUMENT
+}
+
+/// Run `f`, recording its error if it fails, without unwinding into the caller
+fn ffi_call(f: impl FnOnce() -> Result<(), String>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ESPFLASH_OK,
+        Ok(Err(error)) => {
+            set_last_error(&error);
+            ESPFLASH_ERR_FAILED
+        }
+        Err(_) => {
+            set_last_error("espflash panicked");
+            ESPFLASH_ERR_PANIC
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_argument() {
+        let mut flasher = ptr::null_mut();
+        let result = unsafe { espflash_connect(ptr::null(), 0, &mut flasher) };
+
+        assert_eq!(result, ESPFLASH_ERR_INVALID_ARGUMENT);
+        assert!(flasher.is_null());
+
+        let error = unsafe { CStr::from_ptr(espflash_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "port and flasher must not be null");
+    }
+}
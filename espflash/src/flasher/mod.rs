@@ -4,23 +4,25 @@
 //! application to a target device. It additionally provides some operations to
 //! read information from the target device.
 
-use std::{borrow::Cow, str::FromStr, thread::sleep};
+use std::{borrow::Cow, io::Write, str::FromStr, thread::sleep};
 
 use bytemuck::{Pod, Zeroable, __core::time::Duration};
-use esp_idf_part::PartitionTable;
-use log::{debug, info, warn};
+use esp_idf_part::{DataType, Partition, PartitionTable, SubType, Type};
 use serialport::UsbPortInfo;
 use strum::{Display, EnumIter, EnumVariantNames};
+use tracing::{debug, debug_span, info, warn};
 
 use self::stubs::FlashStub;
 use crate::{
     command::{Command, CommandType},
-    connection::Connection,
+    connection::{Connection, ResetSequence, RetryPolicy, USB_SERIAL_JTAG_PID},
     elf::{ElfFirmwareImage, FirmwareImage, RomSegment},
-    error::{ConnectionError, Error, ResultExt},
-    image_format::ImageFormatKind,
+    error::{ConnectionError, Error, ResultExt, RomError, RomErrorKind, TimedOutCommand},
+    image_format::{
+        is_app_image, min_chip_rev_full, ImageFormat, ImageFormatKind, ImageVersion, HEADERS_LEN,
+    },
     interface::Interface,
-    targets::Chip,
+    targets::{Chip, EFuseController, EmbeddedMemory, FlashTarget, PartitionLayout},
 };
 
 mod stubs;
@@ -30,10 +32,26 @@ pub(crate) const FLASH_SECTOR_SIZE: usize = 0x1000;
 pub(crate) const FLASH_WRITE_SIZE: usize = 0x400;
 
 const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 const EXPECTED_STUB_HANDSHAKE: &str = "OHAI";
 const FLASH_BLOCK_SIZE: usize = 0x100;
 const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
+const READ_FLASH_MAX_IN_FLIGHT: u32 = 64;
+const MAX_PARTITION_TABLE_SIZE: u32 = 0xC00;
+/// Size of the blocks compared against flash when skipping unchanged data
+const UNCHANGED_BLOCK_SIZE: usize = 0x10000;
+const MAX_SPI_PIN: u8 = 63;
+
+// SPI flash commands and status register bits used when unprotecting the flash
+const FLASH_WRSR: u8 = 0x01;
+const FLASH_WRDI: u8 = 0x04;
+const FLASH_RDSR: u8 = 0x05;
+const FLASH_WREN: u8 = 0x06;
+const FLASH_RDSR3: u8 = 0x15;
+const FLASH_RDSR2: u8 = 0x35;
+const FLASH_STATUS_WIP: u32 = 1 << 0;
+/// The block protect, complement protect and status register protect bits
+pub const FLASH_STATUS_PROTECTION: u32 = 0x7C | 1 << 7 | 1 << 8 | 1 << 14;
+const FLASH_STATUS_QE: u32 = 1 << 9;
 
 /// Supported flash frequencies
 ///
@@ -44,33 +62,61 @@ const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
 #[repr(u8)]
 pub enum FlashFrequency {
     /// 12 MHz
+    #[strum(serialize = "12MHz")]
     _12Mhz,
     /// 15 MHz
+    #[strum(serialize = "15MHz")]
     _15Mhz,
     /// 16 MHz
+    #[strum(serialize = "16MHz")]
     _16Mhz,
     /// 20 MHz
+    #[strum(serialize = "20MHz")]
     _20Mhz,
     /// 24 MHz
+    #[strum(serialize = "24MHz")]
     _24Mhz,
     /// 26 MHz
+    #[strum(serialize = "26MHz")]
     _26Mhz,
     /// 30 MHz
+    #[strum(serialize = "30MHz")]
     _30Mhz,
     /// 40 MHz
     #[default]
+    #[strum(serialize = "40MHz")]
     _40Mhz,
     /// 48 MHz
+    #[strum(serialize = "48MHz")]
     _48Mhz,
     /// 60 MHz
+    #[strum(serialize = "60MHz")]
     _60Mhz,
     /// 80 MHz
+    #[strum(serialize = "80MHz")]
     _80Mhz,
+    /// 120 MHz
+    #[strum(serialize = "120MHz")]
+    _120Mhz,
+}
+
+impl FlashFrequency {
+    /// The frequency written to image headers
+    ///
+    /// Image headers can not represent frequencies above 80 MHz, so as with
+    /// ESP-IDF, 80 MHz is written instead and the bootloader raises the
+    /// frequency itself.
+    pub fn header_frequency(self) -> FlashFrequency {
+        match self {
+            FlashFrequency::_120Mhz => FlashFrequency::_80Mhz,
+            frequency => frequency,
+        }
+    }
 }
 
 /// Supported flash modes
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
-#[derive(Copy, Clone, Debug, Default, EnumVariantNames)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Display, EnumVariantNames)]
 #[non_exhaustive]
 #[strum(serialize_all = "lowercase")]
 pub enum FlashMode {
@@ -83,6 +129,21 @@ pub enum FlashMode {
     Dio,
     /// Dual Output (2 pins used for data)
     Dout,
+    /// Octal I/O (8 pins used for address & data)
+    Opi,
+}
+
+impl FlashMode {
+    /// The mode written to image headers
+    ///
+    /// Image headers have no value for octal modes, so as with ESP-IDF, DIO is
+    /// written instead and the bootloader switches to octal mode itself.
+    pub fn header_mode(self) -> FlashMode {
+        match self {
+            FlashMode::Opi => FlashMode::Dio,
+            mode => mode,
+        }
+    }
 }
 
 /// Supported flash sizes
@@ -167,8 +228,389 @@ impl FromStr for FlashSize {
     }
 }
 
+/// Inputs used when building an image for flashing
+///
+/// Any values which are not provided fall back to the defaults for the chip.
+/// Construct using [FlashData::builder].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct FlashData {
+    /// Bootloader to use instead of the default one
+    pub bootloader: Option<Vec<u8>>,
+    /// Partition table to use instead of the default one
+    pub partition_table: Option<PartitionTable>,
+    /// Label of the partition to write the application to, instead of the
+    /// `factory` partition
+    pub target_app_partition: Option<String>,
+    /// Flash mode written to the image header
+    pub flash_mode: Option<FlashMode>,
+    /// Flash size written to the image header
+    pub flash_size: Option<FlashSize>,
+    /// Flash frequency written to the image header
+    pub flash_freq: Option<FlashFrequency>,
+    /// Zero the build time and date embedded in the application, so that the
+    /// image is reproducible
+    pub reproducible: bool,
+    /// Grow the app partitions of the default partition table up to the
+    /// end of the flash if the application does not fit in it
+    pub auto_grow_partition: bool,
+    /// Layout of the default partition table
+    pub partition_layout: Option<PartitionLayout>,
+    /// Which parts of the image to write to flash
+    pub flash_parts: FlashParts,
+    /// Minimum chip revision, as `(major, minor)`, which the application may
+    /// be booted on
+    pub min_chip_rev: Option<(u32, u32)>,
+    /// Anti-rollback secure version written to the application description
+    pub secure_version: Option<u32>,
+    /// Don't append a SHA-256 digest to the application image
+    pub no_hash_appended: bool,
+    /// Version of the ESP8266 image format
+    pub image_version: Option<ImageVersion>,
+}
+
+impl FlashData {
+    /// Start building a set of inputs
+    pub fn builder() -> FlashDataBuilder {
+        FlashDataBuilder::default()
+    }
+}
+
+/// Builder for [FlashData]
+#[derive(Debug, Clone, Default)]
+pub struct FlashDataBuilder {
+    data: FlashData,
+}
+
+impl FlashDataBuilder {
+    /// Use a custom bootloader
+    pub fn bootloader(mut self, bootloader: impl Into<Option<Vec<u8>>>) -> Self {
+        self.data.bootloader = bootloader.into();
+        self
+    }
+
+    /// Use a custom partition table
+    pub fn partition_table(mut self, partition_table: impl Into<Option<PartitionTable>>) -> Self {
+        self.data.partition_table = partition_table.into();
+        self
+    }
+
+    /// Write the application to the partition with the label `label`
+    pub fn target_app_partition(mut self, label: impl Into<Option<String>>) -> Self {
+        self.data.target_app_partition = label.into();
+        self
+    }
+
+    /// Set the flash mode
+    pub fn flash_mode(mut self, flash_mode: impl Into<Option<FlashMode>>) -> Self {
+        self.data.flash_mode = flash_mode.into();
+        self
+    }
+
+    /// Set the flash size
+    pub fn flash_size(mut self, flash_size: impl Into<Option<FlashSize>>) -> Self {
+        self.data.flash_size = flash_size.into();
+        self
+    }
+
+    /// Set the flash frequency
+    pub fn flash_freq(mut self, flash_freq: impl Into<Option<FlashFrequency>>) -> Self {
+        self.data.flash_freq = flash_freq.into();
+        self
+    }
+
+    /// Zero the build time and date embedded in the application
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.data.reproducible = reproducible;
+        self
+    }
+
+    /// Grow the app partitions of the default partition table if needed
+    pub fn auto_grow_partition(mut self, auto_grow_partition: bool) -> Self {
+        self.data.auto_grow_partition = auto_grow_partition;
+        self
+    }
+
+    /// Set the layout of the default partition table
+    pub fn partition_layout(
+        mut self,
+        partition_layout: impl Into<Option<PartitionLayout>>,
+    ) -> Self {
+        self.data.partition_layout = partition_layout.into();
+        self
+    }
+
+    /// Only write some parts of the image to flash
+    pub fn flash_parts(mut self, flash_parts: FlashParts) -> Self {
+        self.data.flash_parts = flash_parts;
+        self
+    }
+
+    /// Prevent the application from being booted on chips older than the
+    /// revision `(major, minor)`
+    pub fn min_chip_rev(mut self, min_chip_rev: impl Into<Option<(u32, u32)>>) -> Self {
+        self.data.min_chip_rev = min_chip_rev.into();
+        self
+    }
+
+    /// Set the application's anti-rollback secure version, which must be at
+    /// least the value of the device's secure version eFuse for it to boot
+    pub fn secure_version(mut self, secure_version: impl Into<Option<u32>>) -> Self {
+        self.data.secure_version = secure_version.into();
+        self
+    }
+
+    /// Don't append a SHA-256 digest to the application image
+    pub fn no_hash_appended(mut self, no_hash_appended: bool) -> Self {
+        self.data.no_hash_appended = no_hash_appended;
+        self
+    }
+
+    /// Set the version of the ESP8266 image format
+    pub fn image_version(mut self, image_version: impl Into<Option<ImageVersion>>) -> Self {
+        self.data.image_version = image_version.into();
+        self
+    }
+
+    /// Finish building
+    pub fn build(self) -> FlashData {
+        self.data
+    }
+}
+
+/// Which parts of an image to write to flash
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlashParts {
+    /// The bootloader, partition table and application
+    #[default]
+    All,
+    /// The bootloader and partition table, but not the application
+    NoApp,
+    /// Only the bootloader
+    BootloaderOnly,
+    /// Only the partition table
+    PartitionTableOnly,
+}
+
+impl FlashParts {
+    /// Select the segments of `image` to write
+    fn segments<'a>(self, image: &'a dyn ImageFormat<'_>) -> Result<Vec<FlashSegment<'a>>, Error> {
+        let bootloader = || {
+            image
+                .bootloader_segment()
+                .ok_or(Error::MissingImagePart("bootloader"))
+        };
+        let partition_table = || {
+            image
+                .partition_table_segment()
+                .ok_or(Error::MissingImagePart("partition table"))
+        };
+
+        let segments = match self {
+            FlashParts::All if image.app_addr().is_some() => {
+                let mut segments = image
+                    .bootloader_segment()
+                    .into_iter()
+                    .chain(image.partition_table_segment())
+                    .map(FlashSegment::Rom)
+                    .collect::<Vec<_>>();
+                segments.extend(app_parts(image)?);
+                return Ok(segments);
+            }
+            FlashParts::All => {
+                return image
+                    .flash_segments()
+                    .map(|segment| segment.map(FlashSegment::Rom))
+                    .collect();
+            }
+            FlashParts::NoApp => vec![bootloader()?, partition_table()?],
+            FlashParts::BootloaderOnly => vec![bootloader()?],
+            FlashParts::PartitionTableOnly => vec![partition_table()?],
+        };
+
+        Ok(segments.into_iter().map(FlashSegment::Rom).collect())
+    }
+}
+
+/// Generates data by writing it to the given writer
+type Generate<'a> = dyn Fn(&mut dyn Write) -> Result<(), Error> + 'a;
+
+/// A segment to write to flash
+pub(crate) enum FlashSegment<'a> {
+    /// A segment whose data is held in memory
+    Rom(RomSegment<'a>),
+    /// An application image of `size` bytes, which is generated by `write` as
+    /// it is written rather than held in memory
+    App {
+        addr: u32,
+        size: u32,
+        encrypted: bool,
+        write: Box<Generate<'a>>,
+    },
+}
+
+impl<'a> FlashSegment<'a> {
+    /// Flash address at which the segment begins
+    fn addr(&self) -> u32 {
+        match self {
+            FlashSegment::Rom(segment) => segment.addr,
+            FlashSegment::App { addr, .. } => *addr,
+        }
+    }
+
+    /// Size of the segment's data
+    fn size(&self) -> u32 {
+        match self {
+            FlashSegment::Rom(segment) => segment.data.len() as u32,
+            FlashSegment::App { size, .. } => *size,
+        }
+    }
+
+    fn encrypted(&self) -> bool {
+        match self {
+            FlashSegment::Rom(segment) => segment.encrypted,
+            FlashSegment::App { encrypted, .. } => *encrypted,
+        }
+    }
+
+    fn set_encrypted(&mut self, value: bool) {
+        match self {
+            FlashSegment::Rom(segment) => segment.encrypted = value,
+            FlashSegment::App { encrypted, .. } => *encrypted = value,
+        }
+    }
+
+    /// The segment, if its data is held in memory
+    fn rom(&self) -> Option<&RomSegment<'a>> {
+        match self {
+            FlashSegment::Rom(segment) => Some(segment),
+            FlashSegment::App { .. } => None,
+        }
+    }
+
+    /// The segment's data, which is generated in memory if it isn't held there
+    fn data(&self) -> Result<Cow<'_, [u8]>, Error> {
+        match self {
+            FlashSegment::Rom(segment) => Ok(Cow::Borrowed(&segment.data)),
+            FlashSegment::App { size, write, .. } => {
+                let mut data = Vec::with_capacity(*size as usize);
+                write(&mut data)?;
+                Ok(Cow::Owned(data))
+            }
+        }
+    }
+
+    /// The headers at the start of the segment's data
+    ///
+    /// The rest of a generated application image is discarded as it is
+    /// generated, rather than held in memory.
+    fn headers(&self) -> Result<Cow<'_, [u8]>, Error> {
+        match self {
+            FlashSegment::Rom(segment) => Ok(Cow::Borrowed(&segment.data)),
+            FlashSegment::App { write, .. } => {
+                let mut headers = Vec::with_capacity(HEADERS_LEN);
+                write(&mut Prefix(&mut headers))?;
+                Ok(Cow::Owned(headers))
+            }
+        }
+    }
+
+    /// The MD5 digest of the segment's data
+    fn md5(&self) -> Result<[u8; 16], Error> {
+        match self {
+            FlashSegment::Rom(segment) => Ok(md5::compute(&segment.data).0),
+            FlashSegment::App { write, .. } => {
+                let mut context = md5::Context::new();
+                write(&mut context)?;
+                Ok(context.compute().0)
+            }
+        }
+    }
+
+    /// Generate the segment's data in memory, if it isn't held there already
+    pub(crate) fn load(self) -> Result<RomSegment<'a>, Error> {
+        match self {
+            FlashSegment::Rom(segment) => Ok(segment),
+            app => Ok(RomSegment {
+                addr: app.addr(),
+                data: Cow::Owned(app.data()?.into_owned()),
+                encrypted: app.encrypted(),
+            }),
+        }
+    }
+
+    /// Write the segment to flash using `target`
+    fn write(
+        &self,
+        target: &mut dyn FlashTarget,
+        connection: &mut Connection,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        match self {
+            FlashSegment::Rom(segment) => {
+                target.write_segment(connection, segment.borrow(), progress)
+            }
+            // Encrypted data is written uncompressed, so the application is
+            // generated in memory in order to write it
+            FlashSegment::App {
+                addr,
+                encrypted: true,
+                ..
+            } => {
+                let segment = RomSegment {
+                    addr: *addr,
+                    data: self.data()?,
+                    encrypted: true,
+                };
+                target.write_segment(connection, segment, progress)
+            }
+            FlashSegment::App { addr, write, .. } => {
+                target.write_generated_segment(connection, *addr, write.as_ref(), progress)
+            }
+        }
+    }
+}
+
+/// Writer which keeps the first [HEADERS_LEN] bytes written to it, discarding
+/// the rest
+struct Prefix<'a>(&'a mut Vec<u8>);
+
+impl Write for Prefix<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(HEADERS_LEN.saturating_sub(self.0.len()));
+        self.0.extend_from_slice(&buf[..len]);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The segments of the application in `image`, which is generated as it is
+/// written if the format supports it
+fn app_parts<'a>(image: &'a dyn ImageFormat<'_>) -> Result<Vec<FlashSegment<'a>>, Error> {
+    match image.app_addr() {
+        Some(addr) => Ok(vec![FlashSegment::App {
+            addr,
+            size: image.app_size(),
+            encrypted: false,
+            write: Box::new(move |writer| image.write_to(writer)),
+        }]),
+        None => image
+            .ota_segments()
+            .map(|segment| segment.map(FlashSegment::Rom))
+            .collect(),
+    }
+}
+
 /// Parameters for attaching to a target devices SPI flash
-#[derive(Copy, Clone, Debug)]
+///
+/// Can be parsed from `SPI` (the default pins), `HSPI`, or a comma-separated
+/// list of the `CLK,Q,D,HD,CS` pins.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct SpiAttachParams {
     clk: u8,
@@ -189,6 +631,29 @@ impl SpiAttachParams {
         }
     }
 
+    /// Use the HSPI peripheral's default pins
+    pub const fn hspi() -> Self {
+        // Encoded as a CLK pin of 1, with all other pins 0
+        SpiAttachParams {
+            clk: 1,
+            q: 0,
+            d: 0,
+            hd: 0,
+            cs: 0,
+        }
+    }
+
+    /// Use custom pins, as is required for some boards with external flash
+    pub fn new(clk: u8, q: u8, d: u8, hd: u8, cs: u8) -> Result<Self, Error> {
+        if [clk, q, d, hd, cs].iter().any(|&pin| pin > MAX_SPI_PIN) {
+            return Err(Error::InvalidSpiConnection(format!(
+                "{clk},{q},{d},{hd},{cs}"
+            )));
+        }
+
+        Ok(SpiAttachParams { clk, q, d, hd, cs })
+    }
+
     pub const fn esp32_pico_d4() -> Self {
         SpiAttachParams {
             clk: 6,
@@ -216,6 +681,147 @@ impl SpiAttachParams {
     }
 }
 
+impl FromStr for SpiAttachParams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "SPI" => return Ok(SpiAttachParams::default()),
+            "HSPI" => return Ok(SpiAttachParams::hspi()),
+            _ => {}
+        }
+
+        let pins = s
+            .split(',')
+            .map(|pin| pin.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::InvalidSpiConnection(s.to_string()))?;
+
+        match pins[..] {
+            [clk, q, d, hd, cs] => SpiAttachParams::new(clk, q, d, hd, cs),
+            _ => Err(Error::InvalidSpiConnection(s.to_string())),
+        }
+    }
+}
+
+/// Parse each of the applications which is an ELF image, rather than a
+/// pre-built application binary
+pub(crate) fn parse_apps<'a>(
+    apps: &[(&'a [u8], Option<&str>)],
+) -> Result<Vec<Option<ElfFirmwareImage<'a>>>, Error> {
+    apps.iter()
+        .map(|(data, _)| {
+            if is_app_image(data) {
+                Ok(None)
+            } else {
+                ElfFirmwareImage::try_from(*data).map(Some)
+            }
+        })
+        .collect()
+}
+
+/// Build an image for each of several applications, each in its own app
+/// partition
+pub(crate) fn app_images<'a>(
+    chip: Chip,
+    apps: &[(&'a [u8], Option<&str>)],
+    elfs: &'a [Option<ElfFirmwareImage<'a>>],
+    flash_data: &FlashData,
+    image_format: Option<ImageFormatKind>,
+    chip_revision: Option<(u32, u32)>,
+) -> Result<Vec<Box<dyn ImageFormat<'a> + 'a>>, Error> {
+    let mut images = Vec::with_capacity(apps.len());
+    for ((data, label), elf) in apps.iter().zip(elfs) {
+        let mut flash_data = flash_data.clone();
+        if let Some(label) = label {
+            flash_data.target_app_partition = Some(label.to_string());
+        }
+
+        let image = match elf {
+            Some(elf) => {
+                chip.into_target()
+                    .get_flash_image(elf, flash_data, image_format, chip_revision)?
+            }
+            None => chip
+                .into_target()
+                .get_flash_image_from_bin(data, flash_data)?,
+        };
+
+        if apps.len() > 1 && image.part_size().is_none() {
+            return Err(Error::MultipleAppsUnsupported);
+        }
+
+        images.push(image);
+    }
+
+    Ok(images)
+}
+
+/// Select the segments to write for several applications, each in its own app
+/// partition
+pub(crate) fn app_segments<'a>(
+    images: &'a [Box<dyn ImageFormat<'_> + '_>],
+    flash_parts: FlashParts,
+    flash_size: FlashSize,
+) -> Result<Vec<FlashSegment<'a>>, Error> {
+    // The bootloader and partition table are the same for every application, so
+    // they are only written alongside the first one.
+    let (first, rest) = images.split_first().ok_or(Error::NoApps)?;
+    let mut segments = flash_parts.segments(first.as_ref())?;
+    if flash_parts == FlashParts::All {
+        for image in rest {
+            for segment in app_parts(image.as_ref())? {
+                if segments.iter().any(|s| s.addr() == segment.addr()) {
+                    return Err(Error::DuplicateAppPartition(segment.addr()));
+                }
+                segments.push(segment);
+            }
+        }
+    }
+    check_flash_bounds(flash_extents(&segments), flash_size)?;
+
+    Ok(segments)
+}
+
+/// The address and size of each segment
+fn flash_extents<'a>(segments: &'a [FlashSegment<'_>]) -> impl Iterator<Item = (u32, u32)> + 'a {
+    segments
+        .iter()
+        .map(|segment| (segment.addr(), segment.size()))
+}
+
+/// Check that none of the segments, given by their address and size, extend
+/// beyond the end of the flash
+pub(crate) fn check_flash_bounds(
+    segments: impl IntoIterator<Item = (u32, u32)>,
+    flash_size: FlashSize,
+) -> Result<(), Error> {
+    match segments.into_iter().map(|(addr, size)| addr + size).max() {
+        Some(end) if end > flash_size.size() => Err(Error::ImageExceedsFlash { end, flash_size }),
+        _ => Ok(()),
+    }
+}
+
+/// The data partitions of `old` which are missing from `new`, or have moved
+/// or been resized in it, and whose contents would be lost by replacing `old`
+/// with `new`
+///
+/// Partitions are matched by their labels.
+pub fn moved_data_partitions<'a>(
+    old: &'a PartitionTable,
+    new: &PartitionTable,
+) -> Vec<&'a Partition> {
+    old.partitions()
+        .iter()
+        .filter(|partition| partition.ty() == Type::Data)
+        .filter(|partition| {
+            new.find(&partition.name()).map_or(true, |moved| {
+                moved.offset() != partition.offset() || moved.size() != partition.size()
+            })
+        })
+        .collect()
+}
+
 /// List of spi params to try while detecting flash size
 const TRY_SPI_PARAMS: [SpiAttachParams; 2] =
     [SpiAttachParams::default(), SpiAttachParams::esp32_pico_d4()];
@@ -259,95 +865,503 @@ pub struct DeviceInfo {
     pub flash_size: FlashSize,
     /// Device features
     pub features: Vec<String>,
+    /// Flash and PSRAM included in the chip's package
+    pub embedded_memory: EmbeddedMemory,
     /// MAC address
     pub mac_address: String,
 }
 
-/// Progress update callbacks
-pub trait ProgressCallbacks {
-    /// Initialize some progress report
-    fn init(&mut self, addr: u32, total: usize);
-    /// Update some progress report
-    fn update(&mut self, current: usize);
-    /// Finish some prgoress report
-    fn finish(&mut self);
-}
-
-/// Connect to and flash a target device
-pub struct Flasher {
-    /// Connection for flash operations
-    connection: Connection,
-    /// Chip ID
-    chip: Chip,
-    /// Flash size, loaded from SPI flash
-    flash_size: FlashSize,
-    /// Configuration for SPI attached flash (0 to use fused values)
-    spi_params: SpiAttachParams,
-    /// Indicate RAM stub loader is in use
-    use_stub: bool,
+/// Security-related state of the connected device, as reported by the ROM
+#[derive(Debug, Clone)]
+pub struct SecurityInfo {
+    /// Raw security flags
+    pub flags: u32,
+    /// Value of the `FLASH_CRYPT_CNT` (or `SPI_BOOT_CRYPT_CNT`) eFuse
+    pub flash_crypt_cnt: u8,
+    /// Purposes of the eFuse key blocks
+    pub key_purposes: [u8; 7],
+    /// Chip ID, if reported by the device
+    pub chip_id: Option<u32>,
+    /// ROM API version, if reported by the device
+    pub api_version: Option<u32>,
 }
 
-impl Flasher {
-    pub fn connect(
-        serial: Interface,
-        port_info: UsbPortInfo,
-        speed: Option<u32>,
-        use_stub: bool,
-    ) -> Result<Self, Error> {
-        // Establish a connection to the device using the default baud rate of 115,200
-        // and timeout of 3 seconds.
-        let mut connection = Connection::new(serial, port_info);
-        connection.begin()?;
-        connection.set_timeout(DEFAULT_TIMEOUT)?;
+impl SecurityInfo {
+    const SECURE_BOOT_EN: u32 = 1 << 0;
+    const SECURE_BOOT_AGGRESSIVE_REVOKE: u32 = 1 << 1;
+    const SECURE_DOWNLOAD_ENABLE: u32 = 1 << 2;
+    const SECURE_BOOT_KEY_REVOKE0: u32 = 1 << 3;
+    const SOFT_DIS_JTAG: u32 = 1 << 6;
+    const HARD_DIS_JTAG: u32 = 1 << 7;
+    const DIS_USB: u32 = 1 << 8;
+    const DIS_DOWNLOAD_DCACHE: u32 = 1 << 9;
+    const DIS_DOWNLOAD_ICACHE: u32 = 1 << 10;
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 12 {
+            return None;
+        }
 
-        // Detect which chip we are connected to.
-        let magic = connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR)?;
-        let chip = Chip::from_magic(magic)?;
+        let word = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
 
-        let mut flasher = Flasher {
-            connection,
-            chip,
-            flash_size: FlashSize::_4Mb,
-            spi_params: SpiAttachParams::default(),
-            use_stub,
+        // The ESP32-S2 does not report its chip ID or API version
+        let (chip_id, api_version) = match (word(12), word(16)) {
+            (Some(chip_id), Some(api_version)) => (Some(chip_id), Some(api_version)),
+            _ => (None, None),
         };
 
-        // Load flash stub if enabled
-        if use_stub {
-            info!("Using flash stub");
-            flasher.load_stub()?;
-        }
+        Some(SecurityInfo {
+            flags: word(0).unwrap(),
+            flash_crypt_cnt: data[4],
+            key_purposes: data[5..12].try_into().unwrap(),
+            chip_id,
+            api_version,
+        })
+    }
 
-        flasher.spi_autodetect()?;
+    /// Is secure boot enabled?
+    pub fn secure_boot_enabled(&self) -> bool {
+        self.flags & Self::SECURE_BOOT_EN != 0
+    }
 
-        // Now that we have established a connection and detected the chip and flash
-        // size, we can set the baud rate of the connection to the configured value.
-        if let Some(baud) = speed {
-            match flasher.chip {
-                Chip::Esp8266 => (), // Not available
-                _ => {
-                    if baud > 115_200 {
-                        warn!("Setting baud rate higher than 115,200 can cause issues");
-                        flasher.change_baud(baud)?;
-                    }
-                }
-            }
-        }
+    /// Is aggressive revocation of secure boot keys enabled?
+    pub fn secure_boot_aggressive_revoke(&self) -> bool {
+        self.flags & Self::SECURE_BOOT_AGGRESSIVE_REVOKE != 0
+    }
 
-        Ok(flasher)
+    /// Indices of the secure boot keys which have been revoked
+    pub fn revoked_secure_boot_keys(&self) -> Vec<u32> {
+        (0..3)
+            .filter(|i| self.flags & (Self::SECURE_BOOT_KEY_REVOKE0 << i) != 0)
+            .collect()
     }
 
-    pub fn disable_watchdog(&mut self) -> Result<(), Error> {
-        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
-        target.begin(&mut self.connection).flashing()?;
-        Ok(())
+    /// Is flash encryption enabled?
+    ///
+    /// Flash encryption is enabled when an odd number of bits are set in the
+    /// `FLASH_CRYPT_CNT` eFuse.
+    pub fn flash_encryption_enabled(&self) -> bool {
+        self.flash_crypt_cnt.count_ones() % 2 != 0
     }
 
-    /// Load flash stub
-    fn load_stub(&mut self) -> Result<(), Error> {
-        debug!("Loading flash stub for chip: {:?}", self.chip);
+    /// Is secure download mode enabled?
+    pub fn secure_download_enabled(&self) -> bool {
+        self.flags & Self::SECURE_DOWNLOAD_ENABLE != 0
+    }
 
-        // Load flash stub
+    /// Is JTAG disabled in software (re-enabled using the HMAC peripheral)?
+    pub fn jtag_soft_disabled(&self) -> bool {
+        self.flags & Self::SOFT_DIS_JTAG != 0
+    }
+
+    /// Is JTAG permanently disabled?
+    pub fn jtag_hard_disabled(&self) -> bool {
+        self.flags & Self::HARD_DIS_JTAG != 0
+    }
+
+    /// Is USB access disabled?
+    pub fn usb_disabled(&self) -> bool {
+        self.flags & Self::DIS_USB != 0
+    }
+
+    /// Is the data cache disabled while in download mode?
+    pub fn download_dcache_disabled(&self) -> bool {
+        self.flags & Self::DIS_DOWNLOAD_DCACHE != 0
+    }
+
+    /// Is the instruction cache disabled while in download mode?
+    pub fn download_icache_disabled(&self) -> bool {
+        self.flags & Self::DIS_DOWNLOAD_ICACHE != 0
+    }
+}
+
+/// Read the device's security info using the `GET_SECURITY_INFO` command
+fn read_security_info(connection: &mut Connection, use_stub: bool) -> Result<SecurityInfo, Error> {
+    let data = connection.with_timeout(CommandType::GetSecurityInfo.timeout(), |connection| {
+        connection.command_data(Command::GetSecurityInfo)
+    })?;
+
+    // The response ends with either two (stub) or four (ROM) status bytes, the
+    // first two of which indicate success or failure
+    let status_len = if use_stub { 2 } else { 4 };
+    let payload_len = data.len().saturating_sub(status_len);
+    match data.get(payload_len..payload_len + 2) {
+        Some([0, _]) => {}
+        Some(&[_, error]) => {
+            return Err(Error::RomError(RomError::new(
+                CommandType::GetSecurityInfo,
+                RomErrorKind::from(error),
+            )))
+        }
+        _ => return Err(Error::InvalidSecurityInfo),
+    }
+
+    SecurityInfo::decode(&data[..payload_len]).ok_or(Error::InvalidSecurityInfo)
+}
+
+/// Timings and sizes from writing a single segment to flash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentSummary {
+    /// Address the segment was written to
+    pub addr: u32,
+    /// Size of the segment, in bytes
+    pub size: usize,
+    /// Number of bytes sent to the device, if the segment was compressed
+    pub compressed_size: Option<usize>,
+    /// Time spent beginning the write, during which the region is erased
+    pub erase_time: Duration,
+    /// Time spent sending the segment's data
+    pub write_time: Duration,
+}
+
+impl SegmentSummary {
+    /// Bytes of the segment written per second, including the time spent
+    /// erasing
+    pub fn throughput(&self) -> f64 {
+        throughput(self.size, self.erase_time + self.write_time)
+    }
+}
+
+/// Timings and sizes from writing an image to flash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlashSummary {
+    /// Each of the segments written, in the order they were written
+    pub segments: Vec<SegmentSummary>,
+}
+
+impl FlashSummary {
+    /// Total size of the segments, in bytes
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// Total number of bytes sent to the device, if any segments were
+    /// compressed
+    pub fn compressed_size(&self) -> Option<usize> {
+        self.segments
+            .iter()
+            .map(|segment| segment.compressed_size)
+            .sum()
+    }
+
+    /// Total time spent erasing
+    pub fn erase_time(&self) -> Duration {
+        self.segments.iter().map(|segment| segment.erase_time).sum()
+    }
+
+    /// Total time spent writing
+    pub fn write_time(&self) -> Duration {
+        self.segments.iter().map(|segment| segment.write_time).sum()
+    }
+
+    /// Bytes written per second, including the time spent erasing
+    pub fn throughput(&self) -> f64 {
+        throughput(self.size(), self.erase_time() + self.write_time())
+    }
+}
+
+fn throughput(size: usize, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.0
+    } else {
+        size as f64 / time.as_secs_f64()
+    }
+}
+
+/// Progress update callbacks
+pub trait ProgressCallbacks {
+    /// Initialize some progress report
+    fn init(&mut self, addr: u32, total: usize);
+    /// Update some progress report
+    fn update(&mut self, current: usize);
+    /// Finish some prgoress report
+    fn finish(&mut self);
+}
+
+/// Called before a partition table is written, with the table which is
+/// currently on the device, if it could be read, and the table being written
+///
+/// Returning an error aborts flashing before anything has been written.
+pub type PartitionTableCheck =
+    Box<dyn FnMut(Option<&PartitionTable>, &PartitionTable) -> Result<(), Error> + Send>;
+
+/// Options used by [Flasher::connect] when connecting to a device
+///
+/// The defaults match those used by the `espflash` application: the flash
+/// stub is used, the device is reset using [ResetSequence::Auto], and the
+/// baud rate is left at 115,200.
+pub struct ConnectOptions {
+    speed: Option<u32>,
+    use_stub: bool,
+    reset_sequence: ResetSequence,
+    retry_policy: RetryPolicy,
+    trace: Option<Box<dyn Write + Send>>,
+    spi_params: Option<SpiAttachParams>,
+    capture_rom_log: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            speed: None,
+            use_stub: true,
+            reset_sequence: ResetSequence::default(),
+            retry_policy: RetryPolicy::default(),
+            trace: None,
+            spi_params: None,
+            capture_rom_log: false,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Change the baud rate to `speed` once connected
+    pub fn speed(mut self, speed: impl Into<Option<u32>>) -> Self {
+        self.speed = speed.into();
+        self
+    }
+
+    /// Load the flash stub once connected
+    pub fn use_stub(mut self, use_stub: bool) -> Self {
+        self.use_stub = use_stub;
+        self
+    }
+
+    /// Sequence used to reset the device into download mode
+    pub fn reset_sequence(mut self, reset_sequence: ResetSequence) -> Self {
+        self.reset_sequence = reset_sequence;
+        self
+    }
+
+    /// Number of attempts and timeouts used while connecting
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Write a trace of the packets exchanged with the device to `trace`
+    pub fn trace(mut self, trace: impl Into<Option<Box<dyn Write + Send>>>) -> Self {
+        self.trace = trace.into();
+        self
+    }
+
+    /// Attach the flash using `spi_params` instead of detecting how it is
+    /// connected
+    pub fn spi_params(mut self, spi_params: impl Into<Option<SpiAttachParams>>) -> Self {
+        self.spi_params = spi_params.into();
+        self
+    }
+
+    /// Keep the output printed by the ROM bootloader while connecting
+    pub fn capture_rom_log(mut self, capture_rom_log: bool) -> Self {
+        self.capture_rom_log = capture_rom_log;
+        self
+    }
+}
+
+/// Connect to and flash a target device
+pub struct Flasher {
+    /// Connection for flash operations
+    connection: Connection,
+    /// Chip ID
+    chip: Chip,
+    /// Flash size, loaded from SPI flash
+    flash_size: FlashSize,
+    /// Crystal frequency in MHz, detected when connecting
+    xtal_freq: u32,
+    /// Configuration for SPI attached flash (0 to use fused values)
+    spi_params: SpiAttachParams,
+    /// Indicate RAM stub loader is in use
+    use_stub: bool,
+    /// Flash images even if they require a newer chip revision
+    ignore_chip_rev: bool,
+    /// Verify the contents of flash after writing
+    verify: bool,
+    /// Only write the blocks of each segment which differ from flash
+    skip_unchanged: bool,
+    /// Reset the device once writing to flash has finished
+    reset_after_flash: bool,
+    /// Indicate the device is in secure download mode
+    secure_download: bool,
+    /// Timings and sizes from the most recent write to flash
+    flash_summary: FlashSummary,
+    /// Check performed before writing a partition table
+    partition_table_check: Option<PartitionTableCheck>,
+    /// Copy the contents of NVS partitions which are moved by a new partition
+    /// table to their new offsets
+    migrate_nvs: bool,
+    /// Labels of the partitions, or `bootloader` and `partition-table`, which
+    /// are encrypted as they are written
+    encrypted_partitions: Vec<String>,
+}
+
+impl Flasher {
+    pub fn connect(
+        serial: Interface,
+        port_info: UsbPortInfo,
+        options: ConnectOptions,
+    ) -> Result<Self, Error> {
+        let ConnectOptions {
+            speed,
+            use_stub,
+            reset_sequence,
+            retry_policy,
+            trace,
+            spi_params,
+            capture_rom_log,
+        } = options;
+
+        // Establish a connection to the device using the default baud rate of 115,200
+        // and the default timeout of 3 seconds, unless configured otherwise.
+        let mut connection = Connection::new(serial, port_info);
+        connection.set_reset_sequence(reset_sequence);
+        connection.set_retry_policy(retry_policy);
+        if let Some(trace) = trace {
+            connection.set_trace(trace);
+        }
+        connection.set_capture_rom_log(capture_rom_log);
+        connection.begin()?;
+        connection.set_timeout(connection.default_timeout())?;
+
+        // Detect which chip we are connected to. Registers can not be read in secure
+        // download mode, in which case the chip is instead identified by the chip ID
+        // contained in its security info.
+        let (chip, secure_download) = match connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR) {
+            Ok(magic) => (Chip::from_magic(magic)?, false),
+            Err(Error::RomError(err)) => match read_security_info(&mut connection, false) {
+                Ok(info) if info.secure_download_enabled() => {
+                    // The ESP32-S2 is the only chip supporting secure download mode which
+                    // does not report its chip ID
+                    let chip = match info.chip_id {
+                        Some(chip_id) => {
+                            Chip::from_chip_id(chip_id).ok_or(Error::ChipIdDetectError(chip_id))?
+                        }
+                        None => Chip::Esp32s2,
+                    };
+
+                    (chip, true)
+                }
+                _ => return Err(Error::RomError(err)),
+            },
+            Err(err) => return Err(err),
+        };
+
+        // When connected using USB-Serial-JTAG, the ROM leaves the watchdogs running,
+        // which would otherwise reset the chip out of download mode partway through
+        // long operations such as erasing the flash
+        if !secure_download && connection.get_usb_pid()? == USB_SERIAL_JTAG_PID {
+            if let Some(registers) = chip.into_target().watchdog_registers() {
+                debug!("Disabling watchdogs");
+                registers.disable(&mut connection)?;
+            }
+        }
+
+        // The crystal frequency is estimated from the UART clock divider, which the
+        // ROM configured while synchronizing, so it must be read before the baud
+        // rate is changed.
+        let xtal_freq = if secure_download {
+            debug!("Unable to detect crystal frequency, assuming 40MHz");
+            40
+        } else {
+            chip.into_target().crystal_freq(&mut connection)?
+        };
+        debug!("Detected crystal frequency: {}MHz", xtal_freq);
+
+        let mut flasher = Flasher {
+            connection,
+            chip,
+            flash_size: FlashSize::_4Mb,
+            xtal_freq,
+            spi_params: spi_params.unwrap_or(SpiAttachParams::default()),
+            use_stub: use_stub && !secure_download,
+            ignore_chip_rev: false,
+            verify: false,
+            skip_unchanged: false,
+            reset_after_flash: true,
+            secure_download,
+            flash_summary: FlashSummary::default(),
+            partition_table_check: None,
+            migrate_nvs: false,
+            encrypted_partitions: Vec::new(),
+        };
+
+        if secure_download {
+            warn!("Secure download mode is enabled, only a limited set of operations is available");
+
+            if use_stub {
+                warn!("The flash stub can not be used in secure download mode");
+            }
+
+            // Flash detection requires register access, so the default SPI parameters
+            // and flash size must be used instead.
+            flasher.enable_flash(flasher.spi_params)?;
+            warn!(
+                "Unable to detect the flash size in secure download mode, assuming {}",
+                flasher.flash_size
+            );
+        } else {
+            // Load flash stub if enabled
+            if use_stub {
+                info!("Using flash stub");
+                flasher.load_stub()?;
+            }
+
+            // Chips with embedded flash don't need to try each of the known SPI
+            // parameters, as the package determines how the flash is connected
+            let spi_params = match spi_params {
+                Some(spi_params) => Some(spi_params),
+                None => chip
+                    .into_target()
+                    .spi_attach_params(&mut flasher.connection)?,
+            };
+
+            match spi_params {
+                Some(spi_params) => flasher.spi_attach(spi_params)?,
+                None => flasher.spi_autodetect()?,
+            }
+        }
+
+        // Now that we have established a connection and detected the chip and flash
+        // size, we can set the baud rate of the connection to the configured value.
+        if let Some(baud) = speed {
+            match flasher.chip {
+                Chip::Esp8266 => (), // Not available
+                _ if !flasher.connection.can_set_baud() => {
+                    warn!(
+                        "The baud rate of the connection can't be changed, keeping the current one"
+                    );
+                }
+                _ => {
+                    if baud > 115_200 {
+                        warn!("Setting baud rate higher than 115,200 can cause issues");
+                        flasher.change_baud(baud)?;
+                    }
+                }
+            }
+        }
+
+        Ok(flasher)
+    }
+
+    pub fn disable_watchdog(&mut self) -> Result<(), Error> {
+        // Register writes are not permitted in secure download mode
+        if self.secure_download {
+            return Ok(());
+        }
+
+        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
+        target.begin(&mut self.connection).flashing()?;
+        Ok(())
+    }
+
+    /// Load flash stub
+    fn load_stub(&mut self) -> Result<(), Error> {
+        debug!("Loading flash stub for chip: {:?}", self.chip);
+
+        // Load flash stub
         let stub = FlashStub::get(self.chip);
 
         let mut ram_target = self.chip.ram_target(
@@ -367,6 +1381,7 @@ impl Flasher {
                 RomSegment {
                     addr: text_addr,
                     data: Cow::Borrowed(&text),
+                    encrypted: false,
                 },
                 &mut None,
             )
@@ -381,6 +1396,7 @@ impl Flasher {
                 RomSegment {
                     addr: data_addr,
                     data: Cow::Borrowed(&data),
+                    encrypted: false,
                 },
                 &mut None,
             )
@@ -436,10 +1452,27 @@ impl Flasher {
         Err(Error::FlashConnect)
     }
 
+    /// Attach to the flash using the provided SPI parameters, rather than
+    /// trying each of the known ones
+    fn spi_attach(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        debug!("Enabling flash with: {:?}", spi_params);
+        self.enable_flash(spi_params)?;
+
+        match self.flash_detect()? {
+            Some(flash_size) => self.flash_size = flash_size,
+            None => warn!(
+                "Unable to detect the flash size, assuming {}",
+                self.flash_size
+            ),
+        }
+
+        Ok(())
+    }
+
     fn flash_detect(&mut self) -> Result<Option<FlashSize>, Error> {
         const FLASH_RETRY: u8 = 0xFF;
 
-        let flash_id = self.spi_command(CommandType::FlashDetect, &[], 24)?;
+        let flash_id = self.spi_command(CommandType::FlashDetect as u8, &[], 24)?;
         let size_id = (flash_id >> 16) as u8;
 
         // This value indicates that an alternate detection method should be tried.
@@ -467,6 +1500,7 @@ impl Flasher {
             Chip::Esp8266 => {
                 self.connection.command(Command::FlashBegin {
                     supports_encryption: false,
+                    encrypted: false,
                     offset: 0,
                     block_size: FLASH_WRITE_SIZE as u32,
                     size: 0,
@@ -487,12 +1521,9 @@ impl Flasher {
         Ok(())
     }
 
-    fn spi_command(
-        &mut self,
-        command: CommandType,
-        data: &[u8],
-        read_bits: u32,
-    ) -> Result<u32, Error> {
+    /// Run a command on the SPI flash chip, returning up to 32 bits of its
+    /// response
+    fn spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
         assert!(read_bits < 32);
         assert!(data.len() < 64);
 
@@ -562,7 +1593,9 @@ impl Flasher {
             }
             i += 1;
             if i > 10 {
-                return Err(Error::Connection(ConnectionError::Timeout(command.into())));
+                return Err(Error::Connection(ConnectionError::Timeout(
+                    TimedOutCommand::default(),
+                )));
             }
         }
 
@@ -575,6 +1608,67 @@ impl Flasher {
         Ok(result)
     }
 
+    /// Read the flash chip's status registers
+    ///
+    /// The three status registers are combined into a single value, with the
+    /// first in the least significant byte.
+    pub fn read_flash_status(&mut self) -> Result<u32, Error> {
+        self.ensure_not_secure_download("reading the flash status")?;
+
+        let mut status = 0;
+        for (i, opcode) in [FLASH_RDSR, FLASH_RDSR2, FLASH_RDSR3]
+            .into_iter()
+            .enumerate()
+        {
+            status |= (self.spi_command(opcode, &[], 8)? & 0xFF) << (8 * i);
+        }
+
+        Ok(status)
+    }
+
+    /// Write the flash chip's first two status registers
+    pub fn write_flash_status(&mut self, status: u32) -> Result<(), Error> {
+        self.ensure_not_secure_download("writing the flash status")?;
+
+        self.spi_command(FLASH_WREN, &[], 0)?;
+        self.spi_command(FLASH_WRSR, &(status as u16).to_le_bytes(), 0)?;
+
+        // Wait for the write to complete
+        let mut attempts = 0;
+        while self.spi_command(FLASH_RDSR, &[], 8)? & FLASH_STATUS_WIP != 0 {
+            attempts += 1;
+            if attempts > 100 {
+                return Err(Error::Connection(ConnectionError::Timeout(
+                    TimedOutCommand::default(),
+                )));
+            }
+            sleep(Duration::from_millis(10));
+        }
+
+        self.spi_command(FLASH_WRDI, &[], 0)?;
+
+        Ok(())
+    }
+
+    /// Clear the flash chip's write protection bits
+    ///
+    /// The quad enable bit is preserved, as clearing it would prevent the chip
+    /// from booting in QIO mode.
+    pub fn clear_flash_protection(&mut self) -> Result<(), Error> {
+        let status = self.read_flash_status()?;
+        let cleared = status & FLASH_STATUS_QE;
+
+        if status & 0xFFFF != cleared {
+            self.write_flash_status(cleared)?;
+
+            if self.read_flash_status()? & FLASH_STATUS_PROTECTION != 0 {
+                return Err(Error::FlashProtectionNotCleared);
+            }
+        }
+
+        Ok(())
+    }
+
     /// The active serial connection being used by the flasher
     pub fn connection(&mut self) -> &mut Connection {
         &mut self.connection
@@ -585,120 +1679,830 @@ impl Flasher {
         self.chip
     }
 
-    /// Read and print any information we can about the connected device
-    pub fn device_info(&mut self) -> Result<DeviceInfo, Error> {
-        let chip = self.chip();
-        let target = chip.into_target();
+    /// Timings and sizes from the most recent write to flash
+    pub fn flash_summary(&self) -> &FlashSummary {
+        &self.flash_summary
+    }
 
-        // The ESP8266 does not have readable major/minor revision numbers, so we have
-        // nothing to return if targeting it.
-        let revision = if chip != Chip::Esp8266 {
-            Some(target.chip_revision(self.connection())?)
-        } else {
-            None
-        };
+    /// Is the connected device in secure download mode?
+    ///
+    /// Only a limited set of operations, essentially writing to flash, is
+    /// available in secure download mode.
+    pub fn secure_download_mode(&self) -> bool {
+        self.secure_download
+    }
 
-        let crystal_frequency = target.crystal_freq(self.connection())?;
-        let features = target
-            .chip_features(self.connection())?
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
-        let mac_address = target.mac_address(self.connection())?;
+    /// Attach to a secondary flash chip connected to the pins `spi_params`
+    ///
+    /// All subsequent flash operations target the secondary chip, whose size is
+    /// detected when attaching.
+    pub fn attach_external_flash(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        self.ensure_not_secure_download("attaching external flash")?;
 
-        let info = DeviceInfo {
-            chip,
-            revision,
-            crystal_frequency,
-            flash_size: self.flash_size,
-            features,
-            mac_address,
-        };
+        debug!("Attaching external flash with: {:?}", spi_params);
+        self.enable_flash(spi_params)?;
+        self.flash_size = self.flash_detect()?.ok_or(Error::FlashConnect)?;
+        self.spi_params = spi_params;
 
-        Ok(info)
+        info!("External flash size: {}", self.flash_size);
+
+        Ok(())
     }
 
-    /// Load an elf image to ram and execute it
-    ///
-    /// Note that this will not touch the flash on the device
-    pub fn load_elf_to_ram(
-        &mut self,
-        elf_data: &[u8],
-        mut progress: Option<&mut dyn ProgressCallbacks>,
-    ) -> Result<(), Error> {
-        let image = ElfFirmwareImage::try_from(elf_data)?;
-        if image.rom_segments(self.chip).next().is_some() {
-            return Err(Error::ElfNotRamLoadable);
-        }
+    /// Verify the contents of flash after writing, by comparing MD5 digests
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
 
-        let mut target = self.chip.ram_target(
-            Some(image.entry()),
-            self.chip
-                .into_target()
-                .max_ram_block_size(&mut self.connection)?,
-        );
-        target.begin(&mut self.connection).flashing()?;
+    /// Only write the blocks of each segment whose contents differ from those
+    /// of flash, by comparing MD5 digests
+    pub fn set_skip_unchanged(&mut self, skip_unchanged: bool) {
+        self.skip_unchanged = skip_unchanged;
+    }
 
-        for segment in image.ram_segments(self.chip) {
-            target
-                .write_segment(&mut self.connection, segment.into(), &mut progress)
-                .flashing()?;
-        }
+    /// Reset the device once writing to flash has finished, so that it runs
+    /// the new application
+    ///
+    /// When disabled, the device remains in its bootloader, so that further
+    /// operations can be performed without connecting again.
+    pub fn set_reset_after_flash(&mut self, reset_after_flash: bool) {
+        self.reset_after_flash = reset_after_flash;
+    }
 
-        target.finish(&mut self.connection, true).flashing()
+    /// Check each partition table before it is written, against the table
+    /// which is currently on the device
+    pub fn set_partition_table_check(&mut self, check: PartitionTableCheck) {
+        self.partition_table_check = Some(check);
     }
 
-    /// Load an elf image to flash and execute it
-    pub fn load_elf_to_flash_with_format(
-        &mut self,
-        elf_data: &[u8],
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
-        image_format: Option<ImageFormatKind>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
-        mut progress: Option<&mut dyn ProgressCallbacks>,
-    ) -> Result<(), Error> {
-        let image = ElfFirmwareImage::try_from(elf_data)?;
+    /// Copy the contents of NVS partitions to their new offsets when writing a
+    /// partition table which moves or resizes them
+    pub fn set_migrate_nvs(&mut self, migrate_nvs: bool) {
+        self.migrate_nvs = migrate_nvs;
+    }
 
-        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
-        target.begin(&mut self.connection).flashing()?;
+    /// Encrypt the segments written to the given partitions as they are
+    /// written, for devices with flash encryption enabled
+    ///
+    /// Along with partition labels, `bootloader` and `partition-table` select
+    /// the bootloader and the partition table. Other segments are written as
+    /// plaintext.
+    pub fn set_encrypted_partitions(&mut self, partitions: Vec<String>) {
+        self.encrypted_partitions = partitions;
+    }
 
-        // The ESP8266 does not have readable major/minor revision numbers, so we have
-        // nothing to return if targeting it.
-        let chip_revision = if self.chip != Chip::Esp8266 {
-            Some(
-                self.chip
-                    .into_target()
-                    .chip_revision(&mut self.connection)?,
-            )
+    /// Read the partition table which is currently on the device
+    ///
+    /// Returns `None` if the flash does not contain a valid partition table.
+    pub fn read_partition_table(&mut self) -> Result<Option<PartitionTable>, Error> {
+        let addr = match self.chip.partition_table_address() {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let data = self.read_flash(addr, MAX_PARTITION_TABLE_SIZE, None)?;
+
+        Ok(PartitionTable::try_from_bytes(data).ok())
+    }
+
+    /// Compare any partition table among the segments about to be written
+    /// against the one on the device, running the partition table check
+    ///
+    /// Returns any segments which copy the contents of NVS partitions to their
+    /// new offsets, which are read from the device before anything is written.
+    fn check_partition_table(
+        &mut self,
+        segments: &[FlashSegment<'_>],
+    ) -> Result<Vec<FlashSegment<'static>>, Error> {
+        let new = segments
+            .iter()
+            .filter_map(FlashSegment::rom)
+            .find(|segment| Some(segment.addr) == self.chip.partition_table_address())
+            .and_then(|segment| PartitionTable::try_from_bytes(&*segment.data).ok());
+        let new = match new {
+            Some(new) => new,
+            None => return Ok(Vec::new()),
+        };
+
+        let old = match self.read_partition_table() {
+            Ok(old) => old,
+            // Migrating NVS partitions is not possible without the old partition table
+            Err(err) if self.migrate_nvs => return Err(err),
+            Err(err) => {
+                debug!("Unable to read the partition table from the device: {err}");
+                None
+            }
+        };
+
+        if let Some(check) = &mut self.partition_table_check {
+            check(old.as_ref(), &new)?;
+        }
+
+        let old = match old {
+            Some(old) if self.migrate_nvs => old,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut migrated = Vec::new();
+        for partition in moved_data_partitions(&old, &new) {
+            let moved = match new.find(&partition.name()) {
+                Some(moved) if partition.subtype() == SubType::Data(DataType::Nvs) => moved,
+                _ => continue,
+            };
+            if moved.size() < partition.size() {
+                return Err(Error::NvsMigrationFailed {
+                    name: partition.name(),
+                    size: partition.size(),
+                    new_size: moved.size(),
+                });
+            }
+
+            info!(
+                "Migrating NVS partition '{}' from {:#x} to {:#x}",
+                partition.name(),
+                partition.offset(),
+                moved.offset()
+            );
+            let mut data = self.read_flash(partition.offset(), partition.size(), None)?;
+            // The remainder of a larger partition is left erased, as free NVS pages
+            data.resize(moved.size() as usize, 0xFF);
+
+            migrated.push(FlashSegment::Rom(RomSegment {
+                addr: moved.offset(),
+                data: Cow::Owned(data),
+                encrypted: false,
+            }));
+        }
+
+        Ok(migrated)
+    }
+
+    /// Calculate the MD5 digest of a region of flash on the device
+    pub fn flash_md5(&mut self, addr: u32, size: u32) -> Result<[u8; 16], Error> {
+        if self.chip == Chip::Esp8266 && !self.use_stub {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "calculating MD5 digests without the flash stub".into(),
+            });
+        }
+
+        let data = self
+            .connection
+            .with_timeout(CommandType::FlashMd5.timeout_for_size(size), |connection| {
+                connection.command_data(Command::FlashMd5 { offset: addr, size })
+            })?;
+
+        // The stub responds with the raw digest followed by two status bytes, while
+        // the ROM responds with the digest in hex followed by four status bytes
+        let digest_len = if self.use_stub { 16 } else { 32 };
+        match data.get(digest_len..digest_len + 2) {
+            Some([0, _]) => {}
+            Some(&[_, error]) => {
+                return Err(Error::RomError(RomError::new(
+                    CommandType::FlashMd5,
+                    RomErrorKind::from(error),
+                )))
+            }
+            _ => return Err(Error::InvalidMd5Response),
+        }
+
+        let mut digest = [0u8; 16];
+        if self.use_stub {
+            digest.copy_from_slice(&data[..16]);
+        } else {
+            for (byte, hex) in digest.iter_mut().zip(data[..32].chunks(2)) {
+                *byte = std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(Error::InvalidMd5Response)?;
+            }
+        }
+
+        Ok(digest)
+    }
+
+    /// Read `size` bytes of flash, starting at `addr`
+    ///
+    /// Reading flash requires the flasher stub. The data is verified against
+    /// the MD5 digest which the stub sends once it has finished.
+    pub fn read_flash(
+        &mut self,
+        addr: u32,
+        size: u32,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Vec<u8>, Error> {
+        if !self.use_stub {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "reading flash without the flasher stub".into(),
+            });
+        }
+
+        let _span =
+            debug_span!("read_flash", addr = %format_args!("{:#010x}", addr), size).entered();
+        debug!("Reading 0x{:x}B of flash at 0x{:08x}", size, addr);
+
+        self.connection.command(Command::ReadFlash {
+            offset: addr,
+            size,
+            block_size: FLASH_SECTOR_SIZE as u32,
+            max_in_flight: READ_FLASH_MAX_IN_FLIGHT,
+        })?;
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, size as usize);
+        }
+
+        // Each block must be acknowledged with the total number of bytes
+        // received so far before the stub sends any more
+        let mut data = Vec::with_capacity(size as usize);
+        while data.len() < size as usize {
+            let block = self
+                .connection
+                .read(1)?
+                .ok_or(Error::ReadFlashFailed(addr))?;
+            if block.len() > size as usize - data.len()
+                || (block.len() < FLASH_SECTOR_SIZE && data.len() + block.len() < size as usize)
+            {
+                return Err(Error::ReadFlashFailed(addr));
+            }
+
+            data.extend_from_slice(&block);
+            self.connection
+                .write_frame(&(data.len() as u32).to_le_bytes())?;
+
+            if let Some(cb) = progress.as_mut() {
+                cb.update(data.len());
+            }
+        }
+
+        if let Some(cb) = progress.as_mut() {
+            cb.finish();
+        }
+
+        let digest = self.connection.read(16)?.ok_or(Error::InvalidMd5Response)?;
+        if digest.len() != 16 {
+            return Err(Error::InvalidMd5Response);
+        }
+        if digest != md5::compute(&data).0 {
+            return Err(Error::ReadFlashFailed(addr));
+        }
+
+        Ok(data)
+    }
+
+    /// Check that the contents of flash match the provided data
+    pub fn verify_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.verify_digest(addr, data.len() as u32, md5::compute(data).0)
+    }
+
+    /// Check that the MD5 digest of `size` bytes of flash at `addr` matches
+    /// `digest`
+    fn verify_digest(&mut self, addr: u32, size: u32, digest: [u8; 16]) -> Result<(), Error> {
+        debug!("Verifying {} bytes at 0x{:08x}", size, addr);
+
+        if self.flash_md5(addr, size)? != digest {
+            return Err(Error::VerifyFailed(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Mark the segments which are written to the encrypted partitions
+    fn mark_encrypted(&mut self, segments: &mut [FlashSegment<'_>]) -> Result<(), Error> {
+        if self.encrypted_partitions.is_empty() {
+            return Ok(());
+        }
+
+        let table_addr = self.chip.partition_table_address();
+        let table = segments
+            .iter()
+            .filter_map(FlashSegment::rom)
+            .find(|segment| Some(segment.addr) == table_addr)
+            .and_then(|segment| PartitionTable::try_from_bytes(&*segment.data).ok());
+        let table = match table {
+            Some(table) => Some(table),
+            None => self.read_partition_table()?,
+        };
+
+        for name in &self.encrypted_partitions {
+            let known = match name.as_str() {
+                "bootloader" | "partition-table" => true,
+                _ => table.as_ref().and_then(|table| table.find(name)).is_some(),
+            };
+            if !known {
+                return Err(Error::PartitionNotFound(name.clone()));
+            }
+        }
+
+        for segment in segments {
+            let addr = segment.addr();
+            let name = if addr == self.chip.boot_address() {
+                Some(String::from("bootloader"))
+            } else if Some(addr) == table_addr {
+                Some(String::from("partition-table"))
+            } else {
+                table.as_ref().and_then(|table| {
+                    table
+                        .partitions()
+                        .iter()
+                        .find(|partition| {
+                            (partition.offset()..partition.offset() + partition.size())
+                                .contains(&addr)
+                        })
+                        .map(|partition| partition.name())
+                })
+            };
+
+            if let Some(name) = name {
+                if self.encrypted_partitions.contains(&name) {
+                    segment.set_encrypted(true);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the blocks of each segment which already match the contents of
+    /// flash, if unchanged blocks are being skipped
+    ///
+    /// Encrypted segments are always written, as flash holds their ciphertext.
+    fn changed_segments<'a>(
+        &mut self,
+        segments: Vec<FlashSegment<'a>>,
+    ) -> Result<Vec<FlashSegment<'a>>, Error> {
+        if !self.skip_unchanged {
+            return Ok(segments);
+        }
+
+        let total = segments
+            .iter()
+            .map(|segment| segment.size() as usize)
+            .sum::<usize>();
+        let mut changed = Vec::new();
+        for segment in segments {
+            if segment.encrypted() {
+                changed.push(segment);
+                continue;
+            }
+
+            // Unchanged blocks are found by comparing their data, so an application
+            // which is generated as it is written is generated in memory instead
+            let segment = segment.load()?;
+
+            // Adjacent changed blocks are merged, so that they are written together
+            let mut pending: Option<RomSegment> = None;
+            for (i, block) in segment.data.chunks(UNCHANGED_BLOCK_SIZE).enumerate() {
+                let addr = segment.addr + (i * UNCHANGED_BLOCK_SIZE) as u32;
+                let unchanged = match self.flash_md5(addr, block.len() as u32) {
+                    Ok(digest) => digest == md5::compute(block).0,
+                    // Without the digest every block is written, as when not skipping
+                    Err(Error::UnsupportedFeature { .. }) => false,
+                    Err(err) => return Err(err),
+                };
+
+                if unchanged {
+                    changed.extend(pending.take().map(FlashSegment::Rom));
+                } else {
+                    pending
+                        .get_or_insert_with(|| RomSegment {
+                            addr,
+                            data: Cow::Owned(Vec::new()),
+                            encrypted: segment.encrypted,
+                        })
+                        .data
+                        .to_mut()
+                        .extend_from_slice(block);
+                }
+            }
+            changed.extend(pending.map(FlashSegment::Rom));
+        }
+
+        let written = changed
+            .iter()
+            .map(|segment| segment.size() as usize)
+            .sum::<usize>();
+        info!(
+            "Skipping {} of {} bytes, which are unchanged",
+            total - written,
+            total
+        );
+
+        Ok(changed)
+    }
+
+    fn verify_segments(&mut self, segments: &[FlashSegment<'_>]) -> Result<(), Error> {
+        if self.verify {
+            info!("Verifying flash contents...");
+            // Flash holds the ciphertext of encrypted segments, which can't be verified
+            for segment in segments.iter().filter(|segment| !segment.encrypted()) {
+                self.verify_digest(segment.addr(), segment.size(), segment.md5()?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Detect the size of the flash chip using its JEDEC ID
+    ///
+    /// Unlike the detection performed when connecting, this fails rather than
+    /// assuming a size if the flash chip does not report it.
+    pub fn detect_flash_size(&mut self) -> Result<FlashSize, Error> {
+        let flash_size = self.flash_detect()?.ok_or(Error::FlashSizeDetection)?;
+        self.flash_size = flash_size;
+
+        Ok(flash_size)
+    }
+
+    /// Override the detected size of the flash chip
+    ///
+    /// The new size is also passed on to the device, which is required for flash
+    /// chips whose size can not be detected.
+    pub fn set_flash_size(&mut self, flash_size: FlashSize) -> Result<(), Error> {
+        self.connection
+            .with_timeout(CommandType::SpiSetParams.timeout(), |connection| {
+                connection.command(Command::SpiSetParams {
+                    size: flash_size.size(),
+                })
+            })?;
+
+        self.flash_size = flash_size;
+
+        Ok(())
+    }
+
+    /// Return an error if the device is in secure download mode
+    fn ensure_not_secure_download(&self, operation: &'static str) -> Result<(), Error> {
+        if self.secure_download {
+            Err(Error::SecureDownloadMode(operation))
         } else {
-            None
+            Ok(())
+        }
+    }
+
+    /// The size of the connected device's flash
+    pub fn flash_size(&self) -> FlashSize {
+        self.flash_size
+    }
+
+    /// The crystal frequency of the connected chip, in MHz
+    pub fn crystal_freq(&self) -> u32 {
+        self.xtal_freq
+    }
+
+    /// Flash images even if they require a newer revision than the connected
+    /// chip
+    pub fn set_ignore_chip_rev(&mut self, ignore_chip_rev: bool) {
+        self.ignore_chip_rev = ignore_chip_rev;
+    }
+
+    /// Read the connected chip's revision
+    ///
+    /// The ESP8266 does not have readable major/minor revision numbers, so
+    /// `None` is returned when connected to it.
+    fn chip_revision(&mut self) -> Result<Option<(u32, u32)>, Error> {
+        if self.chip == Chip::Esp8266 {
+            return Ok(None);
+        }
+
+        // The eFuses containing the revision can not be read in secure download mode
+        if self.secure_download {
+            warn!("Unable to read the chip revision in secure download mode");
+            return Ok(None);
+        }
+
+        let revision = self
+            .chip
+            .into_target()
+            .chip_revision(&mut self.connection)?;
+
+        Ok(Some(revision))
+    }
+
+    /// Ensure the application in `image` supports the detected chip revision
+    fn check_chip_revision(
+        &self,
+        image: &dyn ImageFormat<'_>,
+        chip_revision: Option<(u32, u32)>,
+    ) -> Result<(), Error> {
+        let detected = match chip_revision {
+            Some(detected) if !self.ignore_chip_rev => detected,
+            _ => return Ok(()),
         };
 
+        for segment in app_parts(image)? {
+            // Direct boot images and the like carry no revision information
+            if let Some(rev) = min_chip_rev_full(&segment.headers()?, self.chip) {
+                let required = (rev as u32 / 100, rev as u32 % 100);
+                if required > detected {
+                    return Err(Error::UnsupportedChipRevision { required, detected });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read and print any information we can about the connected device
+    pub fn device_info(&mut self) -> Result<DeviceInfo, Error> {
+        self.ensure_not_secure_download("reading device information")?;
+
+        let chip = self.chip();
+        let target = chip.into_target();
+
+        let revision = self.chip_revision()?;
+
+        let crystal_frequency = self.xtal_freq;
+        let features = target
+            .chip_features(self.connection())?
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let embedded_memory = target.embedded_memory(self.connection())?;
+        let mac_address = target.mac_address(self.connection())?;
+
+        let info = DeviceInfo {
+            chip,
+            revision,
+            crystal_frequency,
+            flash_size: self.flash_size,
+            features,
+            embedded_memory,
+            mac_address,
+        };
+
+        Ok(info)
+    }
+
+    /// Read the security-related state of the connected device
+    ///
+    /// The ESP8266 and ESP32 do not support this operation.
+    pub fn security_info(&mut self) -> Result<SecurityInfo, Error> {
+        if matches!(self.chip, Chip::Esp8266 | Chip::Esp32) {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "reading security info".into(),
+            });
+        }
+
+        read_security_info(&mut self.connection, self.use_stub)
+    }
+
+    /// Read the anti-rollback secure version from the device's eFuses
+    ///
+    /// Applications whose secure version is lower than this are not booted.
+    pub fn secure_version(&mut self) -> Result<u32, Error> {
+        self.ensure_not_secure_download("reading eFuses")?;
+        let controller = self.efuse_controller("reading the secure version")?;
+
+        controller.read_secure_version(&mut self.connection)
+    }
+
+    /// Burn the device's eFuses to raise its anti-rollback secure version to
+    /// `version`
+    ///
+    /// This is irreversible, and prevents applications with a lower secure
+    /// version from being booted.
+    pub fn burn_secure_version(&mut self, version: u32) -> Result<(), Error> {
+        self.ensure_not_secure_download("burning eFuses")?;
+        let controller = self.efuse_controller("burning the secure version")?;
+
+        // The programming timings are only known for a 40MHz crystal
+        if self.xtal_freq != 40 {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: format!("burning eFuses with a {}MHz crystal", self.xtal_freq),
+            });
+        }
+
+        controller.burn_secure_version(&mut self.connection, version)
+    }
+
+    fn efuse_controller(&self, feature: &str) -> Result<EFuseController, Error> {
+        self.chip
+            .into_target()
+            .efuse_controller()
+            .ok_or_else(|| Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: feature.into(),
+            })
+    }
+
+    /// Read `len` bytes of the device's memory, starting at `addr`
+    ///
+    /// Memory is read one word at a time, so `len` is rounded up to a multiple
+    /// of four bytes.
+    pub fn read_memory(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        if addr % 4 != 0 {
+            return Err(Error::UnalignedAddress(addr));
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        for word_addr in (addr..addr.saturating_add(len)).step_by(4) {
+            let word = self.connection.read_reg(word_addr)?;
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+
+        Ok(data)
+    }
+
+    /// Write the word `value` to the device's memory at `addr`
+    ///
+    /// If a `mask` is provided, only the bits which are set in it are modified.
+    pub fn write_memory(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+        if addr % 4 != 0 {
+            return Err(Error::UnalignedAddress(addr));
+        }
+
+        self.connection.write_reg(addr, value, mask)
+    }
+
+    /// Load an elf image to ram and execute it
+    ///
+    /// Note that this will not touch the flash on the device
+    pub fn load_elf_to_ram(
+        &mut self,
+        elf_data: &[u8],
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.ensure_not_secure_download("loading images to RAM")?;
+
+        let image = ElfFirmwareImage::try_from(elf_data)?;
+        if image.rom_segments(self.chip).next().is_some() {
+            return Err(Error::ElfNotRamLoadable);
+        }
+
+        let mut target = self.chip.ram_target(
+            Some(image.entry()),
+            self.chip
+                .into_target()
+                .max_ram_block_size(&mut self.connection)?,
+        );
+        target.begin(&mut self.connection).flashing()?;
+
+        for segment in image.ram_segments(self.chip) {
+            target
+                .write_segment(&mut self.connection, segment.into(), &mut progress)
+                .flashing()?;
+        }
+
+        target.finish(&mut self.connection, true).flashing()
+    }
+
+    /// Load an elf image to flash and execute it
+    pub fn load_elf_to_flash_with_format(
+        &mut self,
+        elf_data: &[u8],
+        mut flash_data: FlashData,
+        image_format: Option<ImageFormatKind>,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let image = ElfFirmwareImage::try_from(elf_data)?;
+
+        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
+        target.begin(&mut self.connection).flashing()?;
+
+        let chip_revision = self.chip_revision()?;
+
+        let flash_size = *flash_data.flash_size.get_or_insert(self.flash_size);
+        let flash_parts = flash_data.flash_parts;
         let image = self.chip.into_target().get_flash_image(
             &image,
-            bootloader,
-            partition_table,
+            flash_data,
             image_format,
             chip_revision,
-            flash_mode,
-            flash_size.or(Some(self.flash_size)),
-            flash_freq,
         )?;
+        self.check_chip_revision(image.as_ref(), chip_revision)?;
 
         // When the "cli" feature is enabled, display the image size information.
         #[cfg(feature = "cli")]
         crate::cli::display_image_size(image.app_size(), image.part_size());
 
-        for segment in image.flash_segments() {
-            target
-                .write_segment(&mut self.connection, segment, &mut progress)
+        let segments = flash_parts.segments(image.as_ref())?;
+        check_flash_bounds(flash_extents(&segments), flash_size)?;
+        let migrated = self.check_partition_table(&segments)?;
+        let mut segments = segments.into_iter().chain(migrated).collect::<Vec<_>>();
+        self.mark_encrypted(&mut segments)?;
+        let segments = self.changed_segments(segments)?;
+
+        for segment in &segments {
+            segment
+                .write(target.as_mut(), &mut self.connection, &mut progress)
                 .flashing()?;
         }
 
-        target.finish(&mut self.connection, true).flashing()?;
+        self.flash_summary = target.summary();
+        self.verify_segments(&segments)?;
+        target
+            .finish(&mut self.connection, self.reset_after_flash)
+            .flashing()?;
+
+        Ok(())
+    }
+
+    /// Load a pre-built application binary to flash and execute it
+    ///
+    /// The bootloader and partition table are written alongside the
+    /// application, just as when flashing an ELF image.
+    pub fn load_app_bin_to_flash(
+        &mut self,
+        app_data: &[u8],
+        mut flash_data: FlashData,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
+        target.begin(&mut self.connection).flashing()?;
+
+        let chip_revision = self.chip_revision()?;
+        let flash_size = *flash_data.flash_size.get_or_insert(self.flash_size);
+        let flash_parts = flash_data.flash_parts;
+        let image = self
+            .chip
+            .into_target()
+            .get_flash_image_from_bin(app_data, flash_data)?;
+        self.check_chip_revision(image.as_ref(), chip_revision)?;
+
+        // When the "cli" feature is enabled, display the image size information.
+        #[cfg(feature = "cli")]
+        crate::cli::display_image_size(image.app_size(), image.part_size());
+
+        let segments = flash_parts.segments(image.as_ref())?;
+        check_flash_bounds(flash_extents(&segments), flash_size)?;
+        let migrated = self.check_partition_table(&segments)?;
+        let mut segments = segments.into_iter().chain(migrated).collect::<Vec<_>>();
+        self.mark_encrypted(&mut segments)?;
+        let segments = self.changed_segments(segments)?;
+
+        for segment in &segments {
+            segment
+                .write(target.as_mut(), &mut self.connection, &mut progress)
+                .flashing()?;
+        }
+
+        self.flash_summary = target.summary();
+        self.verify_segments(&segments)?;
+        target
+            .finish(&mut self.connection, self.reset_after_flash)
+            .flashing()?;
+
+        Ok(())
+    }
+
+    /// Load several applications to flash, each to its own app partition
+    ///
+    /// Each application is an ELF image or a pre-built application binary,
+    /// paired with the label of the partition to write it to; when no label is
+    /// given, the partition is chosen as for a single application. The
+    /// bootloader and partition table are written once, and the size of each
+    /// application is validated against its partition.
+    pub fn load_apps_to_flash(
+        &mut self,
+        apps: &[(&[u8], Option<&str>)],
+        mut flash_data: FlashData,
+        image_format: Option<ImageFormatKind>,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let elfs = parse_apps(apps)?;
+
+        let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
+        target.begin(&mut self.connection).flashing()?;
+
+        let chip_revision = self.chip_revision()?;
+        let flash_size = *flash_data.flash_size.get_or_insert(self.flash_size);
+
+        let images = app_images(
+            self.chip,
+            apps,
+            &elfs,
+            &flash_data,
+            image_format,
+            chip_revision,
+        )?;
+        for image in &images {
+            self.check_chip_revision(image.as_ref(), chip_revision)?;
+
+            // When the "cli" feature is enabled, display the image size information.
+            #[cfg(feature = "cli")]
+            crate::cli::display_image_size(image.app_size(), image.part_size());
+        }
+
+        let segments = app_segments(&images, flash_data.flash_parts, flash_size)?;
+        let migrated = self.check_partition_table(&segments)?;
+        let mut segments = segments.into_iter().chain(migrated).collect::<Vec<_>>();
+        self.mark_encrypted(&mut segments)?;
+        let segments = self.changed_segments(segments)?;
+
+        for segment in &segments {
+            segment
+                .write(target.as_mut(), &mut self.connection, &mut progress)
+                .flashing()?;
+        }
+
+        self.flash_summary = target.summary();
+        self.verify_segments(&segments)?;
+        target
+            .finish(&mut self.connection, self.reset_after_flash)
+            .flashing()?;
 
         Ok(())
     }
@@ -708,17 +2512,43 @@ impl Flasher {
         &mut self,
         addr: u32,
         data: &[u8],
-        mut progress: Option<&mut dyn ProgressCallbacks>,
+        progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
         let segment = RomSegment {
             addr,
             data: Cow::from(data),
+            encrypted: false,
         };
 
+        self.write_bins_to_flash(&[segment], progress)
+    }
+
+    /// Load several bin images to flash, each at its own address, resetting
+    /// the device once all of them have been written
+    pub fn write_bins_to_flash(
+        &mut self,
+        segments: &[RomSegment<'_>],
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
         let mut target = self.chip.flash_target(self.spi_params, self.use_stub);
         target.begin(&mut self.connection).flashing()?;
-        target.write_segment(&mut self.connection, segment, &mut progress)?;
-        target.finish(&mut self.connection, true).flashing()?;
+        let segments = segments
+            .iter()
+            .map(|segment| FlashSegment::Rom(segment.borrow()))
+            .collect::<Vec<_>>();
+        let migrated = self.check_partition_table(&segments)?;
+        let mut segments = segments.into_iter().chain(migrated).collect::<Vec<_>>();
+        self.mark_encrypted(&mut segments)?;
+        let segments = self.changed_segments(segments)?;
+
+        for segment in &segments {
+            segment.write(target.as_mut(), &mut self.connection, &mut progress)?;
+        }
+        self.flash_summary = target.summary();
+        self.verify_segments(&segments)?;
+        target
+            .finish(&mut self.connection, self.reset_after_flash)
+            .flashing()?;
 
         Ok(())
     }
@@ -727,43 +2557,37 @@ impl Flasher {
     pub fn load_elf_to_flash(
         &mut self,
         elf_data: &[u8],
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
+        flash_data: FlashData,
         progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        self.load_elf_to_flash_with_format(
-            elf_data,
-            bootloader,
-            partition_table,
-            None,
-            flash_mode,
-            flash_size,
-            flash_freq,
-            progress,
-        )
+        self.load_elf_to_flash_with_format(elf_data, flash_data, None, progress)
     }
 
     pub fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
         debug!("Change baud to: {}", speed);
 
+        // Check before the device is switched to the new baud rate, as the
+        // connection would otherwise be lost
+        if !self.connection.can_set_baud() {
+            return Err(Error::UnchangeableBaudRate);
+        }
+
         let prior_baud = match self.use_stub {
             true => self.connection.get_baud()?,
             false => 0,
         };
 
-        let target = self.chip.into_target();
-        let xtal_freq = target.crystal_freq(&mut self.connection)?;
-
         // Probably this is just a temporary solution until the next chip revision.
         //
         // The ROM code thinks it uses a 40 MHz XTAL. Recompute the baud rate in order
         // to trick the ROM code to set the correct baud rate for a 26 MHz XTAL.
         let mut new_baud = speed;
-        if self.chip == Chip::Esp32c2 && !self.use_stub && xtal_freq == 26 {
+        if self.chip == Chip::Esp32c2 && !self.use_stub && self.xtal_freq == 26 {
             new_baud = new_baud * 40 / 26;
+            warn!(
+                "Detected a 26MHz crystal, requesting {} baud from the ROM to achieve {} baud",
+                new_baud, speed
+            );
         }
 
         self.connection
@@ -784,13 +2608,32 @@ impl Flasher {
         self.connection.get_usb_pid()
     }
 
+    /// Erase the entire flash chip
+    ///
+    /// The timeout is scaled by the size of the flash, as erasing large flash
+    /// chips can take several minutes.
+    pub fn erase_flash(&mut self) -> Result<(), Error> {
+        let _span = debug_span!("erase_flash").entered();
+        debug!("Erasing the entire flash");
+
+        let timeout = CommandType::EraseFlash.timeout_for_size(self.flash_size.size());
+        self.connection.with_timeout(timeout, |connection| {
+            connection.command(Command::EraseFlash)
+        })?;
+        sleep(Duration::from_secs_f32(0.05));
+        self.connection.flush()?;
+
+        Ok(())
+    }
+
     pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        let _span = debug_span!("erase", addr = %format_args!("{:#010x}", offset), size).entered();
         debug!("Erasing region of 0x{:x}B at 0x{:08x}", size, offset);
 
-        self.connection
-            .with_timeout(CommandType::EraseRegion.timeout(), |connection| {
-                connection.command(Command::EraseRegion { offset, size })
-            })?;
+        self.connection.with_timeout(
+            CommandType::EraseRegion.timeout_for_size(size),
+            |connection| connection.command(Command::EraseRegion { offset, size }),
+        )?;
         std::thread::sleep(Duration::from_secs_f32(0.05));
         self.connection.flush()?;
         Ok(())
@@ -824,3 +2667,564 @@ pub(crate) fn checksum(data: &[u8], mut checksum: u8) -> u8 {
 
     checksum
 }
+
+#[cfg(test)]
+mod tests {
+    use flate2::{
+        write::{ZlibDecoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+    use crate::{interface::MockTransport, targets::Esp32Target};
+
+    fn connection(mock: &MockTransport) -> Connection {
+        let port_info = UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+
+        Connection::new(Interface::from_transport(mock.clone()), port_info)
+    }
+
+    fn words(data: &[u8]) -> Vec<u32> {
+        data.chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn flasher(mock: &MockTransport, chip: Chip) -> Flasher {
+        Flasher {
+            connection: connection(mock),
+            chip,
+            flash_size: FlashSize::_4Mb,
+            xtal_freq: 40,
+            spi_params: SpiAttachParams::default(),
+            use_stub: false,
+            ignore_chip_rev: false,
+            verify: true,
+            skip_unchanged: false,
+            reset_after_flash: true,
+            secure_download: false,
+            flash_summary: FlashSummary::default(),
+            partition_table_check: None,
+            migrate_nvs: false,
+            encrypted_partitions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_spi_connection() {
+        assert_eq!(
+            "spi".parse::<SpiAttachParams>().unwrap(),
+            SpiAttachParams::default()
+        );
+        assert_eq!(
+            "HSPI".parse::<SpiAttachParams>().unwrap(),
+            SpiAttachParams::hspi()
+        );
+        assert_eq!(
+            "6,17,8,11,16".parse::<SpiAttachParams>().unwrap(),
+            SpiAttachParams::esp32_pico_d4()
+        );
+
+        assert!("6,17,8,11".parse::<SpiAttachParams>().is_err());
+        assert!("6,17,8,11,64".parse::<SpiAttachParams>().is_err());
+    }
+
+    #[test]
+    fn test_flash_parts_segments() {
+        let elf_data = std::fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let elf = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        let image = Chip::Esp32
+            .into_target()
+            .get_flash_image(&elf, FlashData::default(), None, None)
+            .unwrap();
+
+        let addrs = |parts: FlashParts| {
+            parts
+                .segments(image.as_ref())
+                .unwrap()
+                .iter()
+                .map(FlashSegment::addr)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(addrs(FlashParts::All), [0x1000, 0x8000, 0x10000]);
+        assert_eq!(addrs(FlashParts::NoApp), [0x1000, 0x8000]);
+        assert_eq!(addrs(FlashParts::BootloaderOnly), [0x1000]);
+        assert_eq!(addrs(FlashParts::PartitionTableOnly), [0x8000]);
+
+        // ESP8266 images do not include a bootloader or partition table
+        let elf_data = std::fs::read("tests/resources/esp8266_hal_blinky").unwrap();
+        let elf = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        let image = Chip::Esp8266
+            .into_target()
+            .get_flash_image(&elf, FlashData::default(), None, None)
+            .unwrap();
+        assert!(matches!(
+            FlashParts::NoApp.segments(image.as_ref()),
+            Err(Error::MissingImagePart("bootloader"))
+        ));
+    }
+
+    #[test]
+    fn test_write_generated_app() {
+        let elf_data = std::fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let elf = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        let image = Chip::Esp32
+            .into_target()
+            .get_flash_image(&elf, FlashData::default(), None, None)
+            .unwrap();
+        let mut app = Vec::new();
+        image.write_to(&mut app).unwrap();
+
+        // The application is generated as it is written, rather than held in memory
+        let segments = FlashParts::All.segments(image.as_ref()).unwrap();
+        let segment = &segments[2];
+        assert!(segment.rom().is_none());
+        assert_eq!(segment.size(), app.len() as u32);
+        assert_eq!(segment.md5().unwrap(), md5::compute(&app).0);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&app).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let blocks = (compressed.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
+
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashDeflateBegin, 0, &[]);
+        for _ in 0..blocks {
+            mock.push_response(CommandType::FlashDeflateData, 0, &[]);
+        }
+
+        let mut target = Esp32Target::new(Chip::Esp32, SpiAttachParams::default(), true);
+        segment
+            .write(&mut target, &mut connection(&mock), &mut None)
+            .unwrap();
+        assert!(mock.is_exhausted());
+
+        let commands = mock.written_commands();
+        let (opcode, begin) = &commands[0];
+        assert_eq!(*opcode, CommandType::FlashDeflateBegin as u8);
+        assert_eq!(
+            words(&begin[..16]),
+            [
+                app.len() as u32,
+                blocks as u32,
+                FLASH_WRITE_SIZE as u32,
+                0x10000
+            ]
+        );
+
+        let mut decoder = ZlibDecoder::new(Vec::new());
+        for (opcode, data) in &commands[1..] {
+            assert_eq!(*opcode, CommandType::FlashDeflateData as u8);
+            decoder.write_all(&data[16..]).unwrap();
+        }
+        assert_eq!(decoder.finish().unwrap(), app);
+    }
+
+    #[test]
+    fn test_chip_revision() {
+        let mock = MockTransport::new();
+        // Major revision 1, and minor revision 11 split across two eFuse words
+        mock.push_response(CommandType::ReadReg, 1 << 24 | 1 << 23, &[]);
+        mock.push_response(CommandType::ReadReg, 1 << 24 | 1 << 23, &[]);
+        mock.push_response(CommandType::ReadReg, 3 << 18, &[]);
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        assert_eq!(flasher.chip_revision().unwrap(), Some((1, 11)));
+
+        let addrs = mock
+            .written_commands()
+            .iter()
+            .map(|(_, data)| words(data)[0])
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [0x6000_8858, 0x6000_8858, 0x6000_8850]);
+
+        // The revision can't be read in secure download mode
+        flasher.secure_download = true;
+        assert_eq!(flasher.chip_revision().unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_chip_revision() {
+        let elf_data = std::fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let elf = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        let flash_data = FlashData::builder().min_chip_rev((3, 1)).build();
+        let image = Chip::Esp32
+            .into_target()
+            .get_flash_image(&elf, flash_data, None, None)
+            .unwrap();
+
+        let mut flasher = flasher(&MockTransport::new(), Chip::Esp32);
+        assert!(matches!(
+            flasher.check_chip_revision(image.as_ref(), Some((3, 0))),
+            Err(Error::UnsupportedChipRevision {
+                required: (3, 1),
+                detected: (3, 0),
+            })
+        ));
+        assert!(flasher
+            .check_chip_revision(image.as_ref(), Some((3, 1)))
+            .is_ok());
+        assert!(flasher
+            .check_chip_revision(image.as_ref(), Some((4, 0)))
+            .is_ok());
+
+        // Nothing is checked without a revision, or when ignoring it
+        assert!(flasher.check_chip_revision(image.as_ref(), None).is_ok());
+        flasher.ignore_chip_rev = true;
+        assert!(flasher
+            .check_chip_revision(image.as_ref(), Some((3, 0)))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_change_baud_26mhz_crystal() {
+        // The ESP32-C2's ROM assumes a 40MHz crystal, so a higher rate is
+        // requested with a 26MHz one
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::ChangeBaud, 0, &[]);
+
+        let mut rom = flasher(&mock, Chip::Esp32c2);
+        rom.xtal_freq = 26;
+        rom.change_baud(115_200).unwrap();
+
+        let commands = mock.written_commands();
+        assert_eq!(commands[0].0, CommandType::ChangeBaud as u8);
+        assert_eq!(words(&commands[0].1), [177_230, 0]);
+        assert_eq!(rom.connection.get_baud().unwrap(), 115_200);
+
+        // The stub configures the UART for the actual crystal frequency
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::ChangeBaud, 0, &[]);
+
+        let mut stub = flasher(&mock, Chip::Esp32c2);
+        stub.xtal_freq = 26;
+        stub.use_stub = true;
+        stub.change_baud(460_800).unwrap();
+        assert_eq!(words(&mock.written_commands()[0].1), [460_800, 115_200]);
+    }
+
+    #[test]
+    fn test_flash_md5() {
+        let digest = md5::compute(b"espflash").0;
+
+        // The stub responds with the raw digest
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashMd5, 0, &digest);
+
+        let mut stub = flasher(&mock, Chip::Esp32c3);
+        stub.use_stub = true;
+        assert_eq!(stub.flash_md5(0x1000, 0x2000).unwrap(), digest);
+
+        let commands = mock.written_commands();
+        assert_eq!(commands[0].0, CommandType::FlashMd5 as u8);
+        assert_eq!(words(&commands[0].1), [0x1000, 0x2000, 0, 0]);
+
+        // The ROM responds with the digest in hex
+        let hex = digest
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashMd5, 0, hex.as_bytes());
+
+        let mut rom = flasher(&mock, Chip::Esp32c3);
+        assert_eq!(rom.flash_md5(0x1000, 0x2000).unwrap(), digest);
+    }
+
+    #[test]
+    fn test_flash_md5_errors() {
+        // A failure is reported in the status bytes following the digest
+        let mock = MockTransport::new();
+        let mut data = md5::compute(b"espflash").0.to_vec();
+        data.extend([1, 0x07]);
+        mock.push_response(CommandType::FlashMd5, 0, &data);
+        mock.push_response(CommandType::FlashMd5, 0, &[0; 8]);
+
+        let mut stub = flasher(&mock, Chip::Esp32c3);
+        stub.use_stub = true;
+        assert!(matches!(stub.flash_md5(0, 0x1000), Err(Error::RomError(_))));
+        assert!(matches!(
+            stub.flash_md5(0, 0x1000),
+            Err(Error::InvalidMd5Response)
+        ));
+
+        // The ROM's digest must be valid hex
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashMd5, 0, &[b'x'; 32]);
+        assert!(matches!(
+            flasher(&mock, Chip::Esp32c3).flash_md5(0, 0x1000),
+            Err(Error::InvalidMd5Response)
+        ));
+
+        // The ESP8266's ROM can't calculate digests
+        assert!(matches!(
+            flasher(&MockTransport::new(), Chip::Esp8266).flash_md5(0, 0x1000),
+            Err(Error::UnsupportedFeature { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_flash() {
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashMd5, 0, &md5::compute(b"espflash").0);
+        mock.push_response(CommandType::FlashMd5, 0, &md5::compute(b"espflasH").0);
+
+        let mut stub = flasher(&mock, Chip::Esp32c3);
+        stub.use_stub = true;
+        assert!(stub.verify_flash(0x1000, b"espflash").is_ok());
+        assert!(matches!(
+            stub.verify_flash(0x1000, b"espflash"),
+            Err(Error::VerifyFailed(0x1000))
+        ));
+
+        let commands = mock.written_commands();
+        assert_eq!(words(&commands[1].1), [0x1000, 8, 0, 0]);
+    }
+
+    #[test]
+    fn test_read_memory() {
+        // Memory is read one word at a time, rounding the length up to a word
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::ReadReg, 0x0403_0201, &[]);
+        mock.push_response(CommandType::ReadReg, 0x0807_0605, &[]);
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        assert_eq!(
+            flasher.read_memory(0x3fc8_0000, 6).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        let commands = mock.written_commands();
+        assert_eq!(commands.len(), 2);
+        for (i, (opcode, data)) in commands.iter().enumerate() {
+            assert_eq!(*opcode, CommandType::ReadReg as u8);
+            assert_eq!(words(data), [0x3fc8_0000 + 4 * i as u32]);
+        }
+
+        assert!(matches!(
+            flasher.read_memory(0x3fc8_0002, 4),
+            Err(Error::UnalignedAddress(0x3fc8_0002))
+        ));
+        assert_eq!(mock.written_commands().len(), 2);
+    }
+
+    #[test]
+    fn test_write_memory() {
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::WriteReg, 0, &[]);
+        mock.push_response(CommandType::WriteReg, 0, &[]);
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        flasher.write_memory(0x6000_8000, 0x1234, None).unwrap();
+        flasher.write_memory(0x6000_8004, 0x10, Some(0xf0)).unwrap();
+
+        // Without a mask every bit is written
+        let commands = mock.written_commands();
+        assert_eq!(commands[0].0, CommandType::WriteReg as u8);
+        assert_eq!(words(&commands[0].1), [0x6000_8000, 0x1234, 0xffff_ffff, 0]);
+        assert_eq!(words(&commands[1].1), [0x6000_8004, 0x10, 0xf0, 0]);
+
+        assert!(matches!(
+            flasher.write_memory(0x6000_8001, 0, None),
+            Err(Error::UnalignedAddress(0x6000_8001))
+        ));
+    }
+
+    /// Queue the responses to an SPI flash command run on an ESP32-C3, which
+    /// sends `data_len` bytes and reads `result` if `read` is set
+    fn push_spi_command(mock: &MockTransport, data_len: usize, read: bool, result: u32) {
+        // The user registers are saved, before being set up for the command
+        mock.push_response(CommandType::ReadReg, 0, &[]);
+        mock.push_response(CommandType::ReadReg, 0, &[]);
+        let data_words = (data_len + 3) / 4;
+        let writes = 2 + (data_len > 0) as usize + read as usize + data_words.max(1) + 1;
+        for _ in 0..writes {
+            mock.push_response(CommandType::WriteReg, 0, &[]);
+        }
+
+        // The command completes immediately, after which the registers are restored
+        mock.push_response(CommandType::ReadReg, 0, &[]);
+        mock.push_response(CommandType::ReadReg, result, &[]);
+        mock.push_response(CommandType::WriteReg, 0, &[]);
+        mock.push_response(CommandType::WriteReg, 0, &[]);
+    }
+
+    /// The opcodes of the SPI flash commands run on an ESP32-C3, from the
+    /// values written to its USR2 register
+    fn spi_opcodes(mock: &MockTransport) -> Vec<u32> {
+        mock.written_commands()
+            .iter()
+            .filter(|(opcode, _)| *opcode == CommandType::WriteReg as u8)
+            .map(|(_, data)| words(data))
+            .filter(|words| words[0] == 0x6000_2020 && words[1] >> 28 == 7)
+            .map(|words| words[1] & 0xff)
+            .collect()
+    }
+
+    /// The values written to the given register of an ESP32-C3
+    fn reg_writes(mock: &MockTransport, addr: u32) -> Vec<u32> {
+        mock.written_commands()
+            .iter()
+            .filter(|(opcode, _)| *opcode == CommandType::WriteReg as u8)
+            .map(|(_, data)| words(data))
+            .filter(|words| words[0] == addr)
+            .map(|words| words[1])
+            .collect()
+    }
+
+    #[test]
+    fn test_read_flash_status() {
+        let mock = MockTransport::new();
+        for result in [0x1c, 0x02, 0x60] {
+            push_spi_command(&mock, 0, true, result);
+        }
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        assert_eq!(flasher.read_flash_status().unwrap(), 0x60_021c);
+        assert!(mock.is_exhausted());
+
+        // RDSR, RDSR2 and RDSR3 each read 8 bits
+        assert_eq!(spi_opcodes(&mock), [0x05, 0x35, 0x15]);
+        assert_eq!(reg_writes(&mock, 0x6000_2028), [7, 7, 7]);
+
+        flasher.secure_download = true;
+        assert!(matches!(
+            flasher.read_flash_status(),
+            Err(Error::SecureDownloadMode(_))
+        ));
+    }
+
+    #[test]
+    fn test_clear_flash_protection() {
+        let mock = MockTransport::new();
+        // Block protection is set alongside the quad enable bit
+        for result in [0x1c, 0x02, 0x00] {
+            push_spi_command(&mock, 0, true, result);
+        }
+        push_spi_command(&mock, 0, false, 0);
+        push_spi_command(&mock, 2, false, 0);
+        push_spi_command(&mock, 0, true, 0);
+        push_spi_command(&mock, 0, false, 0);
+        for result in [0x00, 0x02, 0x00] {
+            push_spi_command(&mock, 0, true, result);
+        }
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        flasher.clear_flash_protection().unwrap();
+        assert!(mock.is_exhausted());
+
+        // Only the quad enable bit is written back, with WRSR between WREN and WRDI
+        assert_eq!(
+            spi_opcodes(&mock),
+            [0x05, 0x35, 0x15, 0x06, 0x01, 0x05, 0x04, 0x05, 0x35, 0x15]
+        );
+        assert_eq!(reg_writes(&mock, 0x6000_2024), [15]);
+        assert_eq!(
+            reg_writes(&mock, 0x6000_2058)
+                .into_iter()
+                .filter(|&word| word != 0)
+                .collect::<Vec<_>>(),
+            [0x0200]
+        );
+    }
+
+    #[test]
+    fn test_clear_flash_protection_failed() {
+        let mock = MockTransport::new();
+        for result in [0x1c, 0x00, 0x00] {
+            push_spi_command(&mock, 0, true, result);
+        }
+        push_spi_command(&mock, 0, false, 0);
+        push_spi_command(&mock, 2, false, 0);
+        push_spi_command(&mock, 0, true, 0);
+        push_spi_command(&mock, 0, false, 0);
+        // The status register protection prevents the bits from being cleared
+        for result in [0x1c, 0x00, 0x00] {
+            push_spi_command(&mock, 0, true, result);
+        }
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        assert!(matches!(
+            flasher.clear_flash_protection(),
+            Err(Error::FlashProtectionNotCleared)
+        ));
+    }
+
+    #[test]
+    fn test_clear_unprotected_flash() {
+        // Nothing is written when the flash isn't protected
+        let mock = MockTransport::new();
+        for result in [0x00, 0x02, 0x00] {
+            push_spi_command(&mock, 0, true, result);
+        }
+
+        let mut flasher = flasher(&mock, Chip::Esp32c3);
+        flasher.clear_flash_protection().unwrap();
+        assert_eq!(spi_opcodes(&mock), [0x05, 0x35, 0x15]);
+    }
+
+    #[test]
+    fn test_check_flash_bounds() {
+        assert!(check_flash_bounds([(0x1000, 0x1000)], FlashSize::_1Mb).is_ok());
+        assert!(check_flash_bounds([(0xf_f000, 0x1000)], FlashSize::_1Mb).is_ok());
+        assert!(matches!(
+            check_flash_bounds([(0xf_f000, 0x1001)], FlashSize::_1Mb),
+            Err(Error::ImageExceedsFlash { end: 0x10_0001, .. })
+        ));
+    }
+
+    #[test]
+    fn test_moved_data_partitions() {
+        let old = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x6000\n\
+             phy_init,data,phy,0xf000,0x1000\n\
+             factory,app,factory,0x10000,1M\n",
+        )
+        .unwrap();
+        let new = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x5000\n\
+             phy_init,data,phy,0xf000,0x1000\n\
+             factory,app,factory,0x20000,1M\n",
+        )
+        .unwrap();
+
+        let moved = moved_data_partitions(&old, &new)
+            .iter()
+            .map(|partition| partition.name())
+            .collect::<Vec<_>>();
+        assert_eq!(moved, ["nvs"]);
+        assert!(moved_data_partitions(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_flash_summary() {
+        let segment = |size, compressed_size| SegmentSummary {
+            addr: 0x1000,
+            size,
+            compressed_size,
+            erase_time: Duration::from_millis(250),
+            write_time: Duration::from_millis(750),
+        };
+
+        let summary = FlashSummary {
+            segments: vec![segment(0x1000, Some(0x400)), segment(0x3000, Some(0x800))],
+        };
+        assert_eq!(summary.size(), 0x4000);
+        assert_eq!(summary.compressed_size(), Some(0xc00));
+        assert_eq!(summary.erase_time(), Duration::from_millis(500));
+        assert_eq!(summary.write_time(), Duration::from_millis(1500));
+        assert_eq!(summary.throughput(), 0x4000 as f64 / 2.0);
+
+        let summary = FlashSummary {
+            segments: vec![segment(0x1000, None)],
+        };
+        assert_eq!(summary.compressed_size(), None);
+        assert_eq!(FlashSummary::default().throughput(), 0.0);
+    }
+}
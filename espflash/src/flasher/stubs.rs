@@ -24,6 +24,7 @@ const STUB_32: &str = include_str!("../../resources/stubs/stub_flasher_32.toml")
 const STUB_32C2: &str = include_str!("../../resources/stubs/stub_flasher_32c2.toml");
 const STUB_32C3: &str = include_str!("../../resources/stubs/stub_flasher_32c3.toml");
 const STUB_32C6: &str = include_str!("../../resources/stubs/stub_flasher_32c6.toml");
+const STUB_32C61: &str = include_str!("../../resources/stubs/stub_flasher_32c61.toml");
 const STUB_32S2: &str = include_str!("../../resources/stubs/stub_flasher_32s2.toml");
 const STUB_32S3: &str = include_str!("../../resources/stubs/stub_flasher_32s3.toml");
 const STUB_32H2: &str = include_str!("../../resources/stubs/stub_flasher_32h2.toml");
@@ -37,6 +38,7 @@ impl FlashStub {
             Chip::Esp32c2 => STUB_32C2,
             Chip::Esp32c3 => STUB_32C3,
             Chip::Esp32c6 => STUB_32C6,
+            Chip::Esp32c61 => STUB_32C61,
             Chip::Esp32s2 => STUB_32S2,
             Chip::Esp32s3 => STUB_32S3,
             Chip::Esp32h2 => STUB_32H2,
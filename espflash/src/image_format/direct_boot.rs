@@ -43,18 +43,18 @@ impl<'a> DirectBootFormat<'a> {
 }
 
 impl<'a> ImageFormat<'a> for DirectBootFormat<'a> {
-    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
-        Box::new(once(self.segment.borrow()))
+        Box::new(once(Ok(self.segment.borrow())))
     }
 
-    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
-        Box::new(once(self.segment.borrow()))
+        Box::new(once(Ok(self.segment.borrow())))
     }
 
     fn app_size(&self) -> u32 {
@@ -81,7 +81,10 @@ pub mod tests {
         let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
         let flash_image = DirectBootFormat::new(&image, 0).unwrap();
 
-        let segments = flash_image.flash_segments().collect::<Vec<_>>();
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
         assert_eq!(segments.len(), 1);
 
         let buf = segments[0].data.as_ref();
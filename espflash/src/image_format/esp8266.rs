@@ -1,18 +1,42 @@
 use std::{borrow::Cow, io::Write, iter::once, mem::size_of};
 
 use bytemuck::bytes_of;
+use flate2::Crc;
 
 use super::{
-    encode_flash_frequency, update_checksum, EspCommonHeader, ImageFormat, SegmentHeader,
-    ESP_CHECKSUM_MAGIC, ESP_MAGIC,
+    encode_flash_frequency, is_app_image, merge_adjacent_segments, update_checksum,
+    EspCommonHeader, ImageFormat, SegmentHeader, ESP_CHECKSUM_MAGIC, ESP_MAGIC,
 };
 use crate::{
     elf::{CodeSegment, FirmwareImage, RomSegment},
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize},
+    flasher::{FlashData, FlashSize},
     targets::Chip,
 };
 
+/// Magic byte of the first header of a V2 image
+const ESP_V2_MAGIC: u8 = 0xEA;
+/// Segment count of the first header of a V2 image; ignored by the bootloader
+const ESP_V2_SEGMENT_COUNT: u8 = 4;
+/// Offset at which the SDK bootloader expects a V2 image
+const ESP_V2_ADDR: u32 = 0x1000;
+
+/// Version of the image format used for the ESP8266
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageVersion {
+    /// Booted directly by the ROM; the flash-mapped IROM section is written as
+    /// a separate binary at its offset in flash (usually 0x10000)
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "1"))]
+    V1,
+    /// Booted by the SDK's second-stage bootloader, which must already be at
+    /// offset 0x0; written as a single image at offset 0x1000
+    #[cfg_attr(feature = "cli", value(name = "2"))]
+    V2,
+}
+
 /// Image format for flashing to the ESP8266
 pub struct Esp8266Format<'a> {
     irom_data: Option<RomSegment<'a>>,
@@ -21,29 +45,63 @@ pub struct Esp8266Format<'a> {
 }
 
 impl<'a> Esp8266Format<'a> {
-    pub fn new(
-        image: &'a dyn FirmwareImage<'a>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
-    ) -> Result<Self, Error> {
+    pub fn new(image: &'a dyn FirmwareImage<'a>, flash_data: &FlashData) -> Result<Self, Error> {
+        let target = Chip::Esp8266.into_target();
+        let (rom_segments, ram_segments): (Vec<_>, Vec<_>) =
+            merge_adjacent_segments(Chip::Esp8266, image.segments())
+                .into_iter()
+                .partition(|segment| target.addr_is_flash(segment.addr));
+
         // IROM goes into a separate plain binary
-        let irom_data = merge_rom_segments(image.rom_segments(Chip::Esp8266));
+        let irom_data = merge_rom_segments(rom_segments.into_iter());
 
         let mut common_data = Vec::with_capacity(
-            image
-                .ram_segments(Chip::Esp8266)
+            ram_segments
+                .iter()
                 .map(|segment| segment.size() as usize)
                 .sum(),
         );
 
         // Common header
-        let flash_mode = flash_mode.unwrap_or_default() as u8;
-        let flash_freq = flash_freq.unwrap_or_default();
-        let flash_size = flash_size.unwrap_or_default();
+        let flash_mode = flash_data.flash_mode.unwrap_or_default();
+        Chip::Esp8266.check_flash_mode(flash_mode)?;
+        let flash_mode = flash_mode.header_mode() as u8;
+        let flash_freq = flash_data.flash_freq.unwrap_or_default();
+        let flash_size = flash_data.flash_size.unwrap_or_default();
+        Chip::Esp8266.check_flash_size(flash_size)?;
         let flash_config =
             encode_flash_size(flash_size)? + encode_flash_frequency(Chip::Esp8266, flash_freq)?;
-        let segment_count = image.ram_segments(Chip::Esp8266).count() as u8;
+        let segment_count = ram_segments.len() as u8;
+
+        // V2 images begin with a header of their own, followed by the IROM
+        // section as a segment loaded at address zero.
+        let image_version = flash_data.image_version.unwrap_or_default();
+        let irom_data = if image_version == ImageVersion::V2 {
+            let header = EspCommonHeader {
+                magic: ESP_V2_MAGIC,
+                segment_count: ESP_V2_SEGMENT_COUNT,
+                flash_mode,
+                flash_config,
+                entry: image.entry(),
+            };
+            common_data.write_all(bytes_of(&header))?;
+
+            let mut data = irom_data
+                .map(|irom| irom.data.into_owned())
+                .unwrap_or_default();
+            data.resize((data.len() + 15) & !15, 0);
+
+            let segment_header = SegmentHeader {
+                addr: 0,
+                length: data.len() as u32,
+            };
+            common_data.write_all(bytes_of(&segment_header))?;
+            common_data.write_all(&data)?;
+
+            None
+        } else {
+            irom_data
+        };
 
         let header = EspCommonHeader {
             magic: ESP_MAGIC,
@@ -57,13 +115,13 @@ impl<'a> Esp8266Format<'a> {
         let mut total_len = 8;
         let mut checksum = ESP_CHECKSUM_MAGIC;
 
-        for segment in image.ram_segments(Chip::Esp8266) {
+        // Segments are already padded to a word boundary
+        for segment in &ram_segments {
             let data = segment.data();
-            let padding = 4 - data.len() % 4;
 
             let segment_header = SegmentHeader {
                 addr: segment.addr,
-                length: (data.len() + padding) as u32,
+                length: data.len() as u32,
             };
 
             total_len += size_of::<SegmentHeader>() as u32 + segment_header.length;
@@ -71,9 +129,6 @@ impl<'a> Esp8266Format<'a> {
             common_data.write_all(bytes_of(&segment_header))?;
             common_data.write_all(data)?;
 
-            let padding = &[0u8; 4][0..padding];
-            common_data.write_all(padding)?;
-
             checksum = update_checksum(data, checksum);
         }
 
@@ -83,9 +138,20 @@ impl<'a> Esp8266Format<'a> {
         common_data.write_all(padding)?;
         common_data.write_all(&[checksum])?;
 
+        let addr = match image_version {
+            ImageVersion::V2 => {
+                let crc = esp8266_crc32(&common_data);
+                common_data.write_all(&crc.to_le_bytes())?;
+
+                ESP_V2_ADDR
+            }
+            _ => 0,
+        };
+
         let flash_segment = RomSegment {
-            addr: 0,
+            addr,
             data: Cow::Owned(common_data),
+            encrypted: false,
         };
 
         let app_size = irom_data
@@ -100,30 +166,52 @@ impl<'a> Esp8266Format<'a> {
             app_size,
         })
     }
+
+    /// Build an image from a pre-built application binary
+    ///
+    /// The binary is written as-is to the beginning of the flash.
+    pub fn from_bin(app: &'a [u8]) -> Result<Self, Error> {
+        if !is_app_image(app) {
+            return Err(Error::InvalidAppImage);
+        }
+
+        let flash_segment = RomSegment {
+            addr: 0,
+            data: Cow::Borrowed(app),
+            encrypted: false,
+        };
+        let app_size = flash_segment.data.len() as u32;
+
+        Ok(Self {
+            irom_data: None,
+            flash_segment,
+            app_size,
+        })
+    }
 }
 
 impl<'a> ImageFormat<'a> for Esp8266Format<'a> {
-    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
         Box::new(
             self.irom_data
                 .iter()
-                .map(RomSegment::borrow)
-                .chain(once(self.flash_segment.borrow())),
+                .map(|segment| Ok(segment.borrow()))
+                .chain(once(Ok(self.flash_segment.borrow()))),
         )
     }
 
-    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
         Box::new(
             self.irom_data
                 .iter()
-                .map(RomSegment::borrow)
-                .chain(once(self.flash_segment.borrow())),
+                .map(|segment| Ok(segment.borrow()))
+                .chain(once(Ok(self.flash_segment.borrow()))),
         )
     }
 
@@ -160,10 +248,22 @@ fn merge_rom_segments<'a>(
     Some(RomSegment {
         addr: first.addr - IROM_MAP_START,
         data: Cow::Owned(data),
+        encrypted: false,
     })
 }
 
-fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
+/// The CRC-32 appended to V2 images, as calculated by the SDK bootloader
+fn esp8266_crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    match crc.sum() {
+        crc if crc & 0x8000_0000 != 0 => !crc,
+        crc => crc + 1,
+    }
+}
+
+pub(super) fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
     use FlashSize::*;
 
     match size {
@@ -191,12 +291,48 @@ mod tests {
         let expected_bin = fs::read("tests/resources/esp8266_hal_blinky.bin").unwrap();
 
         let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
-        let flash_image = Esp8266Format::new(&image, None, None, None).unwrap();
-
-        let segments = flash_image.flash_segments().collect::<Vec<_>>();
-        let buf = segments[0].data.as_ref();
-
+        let flash_image = Esp8266Format::new(&image, &FlashData::default()).unwrap();
+
+        // The IROM section is followed by the image at the beginning of the flash
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].addr, 0x1_0000);
+        assert_eq!(segments[1].addr, 0);
+
+        let buf = segments[1].data.as_ref();
         assert_eq!(expected_bin.len(), buf.len());
         assert_eq!(expected_bin.as_slice(), buf);
     }
+
+    #[test]
+    fn test_esp8266_v2_image_format() {
+        let input_bytes = fs::read("tests/resources/esp8266_hal_blinky").unwrap();
+
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+        let flash_data = FlashData::builder().image_version(ImageVersion::V2).build();
+        let flash_image = Esp8266Format::new(&image, &flash_data).unwrap();
+
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, ESP_V2_ADDR);
+
+        let data = segments[0].data.as_ref();
+        assert_eq!(data[0], ESP_V2_MAGIC);
+        assert_eq!(u32::from_le_bytes(data[8..12].try_into().unwrap()), 0);
+
+        // The IROM section is followed by a V1 image of the RAM segments
+        let irom_len = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+        assert_eq!(irom_len % 16, 0);
+        assert_eq!(data[16 + irom_len], ESP_MAGIC);
+
+        let (image, crc) = data.split_at(data.len() - 4);
+        assert_eq!(image.len() % 16, 0);
+        assert_eq!(crc, esp8266_crc32(image).to_le_bytes());
+    }
 }
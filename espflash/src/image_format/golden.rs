@@ -0,0 +1,268 @@
+//! Golden image tests
+//!
+//! Each case builds an image from one of the reference ELF files in
+//! `tests/resources`, using the same code path as flashing, and compares it
+//! byte-for-byte against the expected image generated from the same ELF file
+//! by `tests/golden.py`, a port of `esptool.py elf2image` and `merge_bin`.
+//! The expected images are generated independently of espflash, so a change
+//! to an image format can't be blessed into the expected images. They should
+//! be regenerated with `tests/golden.py --esptool` whenever esptool.py is
+//! available, so that the port is checked against it.
+
+use std::{fs, path::Path};
+
+use crate::{
+    elf::{ElfFirmwareImage, RomSegment},
+    error::Error,
+    flasher::{FlashData, FlashFrequency, FlashMode, FlashSize},
+    image_format::ImageFormatKind,
+    targets::Chip,
+};
+
+const RESOURCES: &str = "tests/resources";
+
+/// Which part of the built image is compared
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    /// The application image only, as written to an OTA partition
+    App,
+    /// All segments merged into a single image, without padding to the flash
+    /// size
+    Merged,
+}
+
+struct GoldenImage {
+    chip: Chip,
+    format: ImageFormatKind,
+    elf: &'static str,
+    expected: &'static str,
+    output: Output,
+    flash_mode: Option<FlashMode>,
+    flash_size: Option<FlashSize>,
+    flash_freq: Option<FlashFrequency>,
+}
+
+impl GoldenImage {
+    const fn app(
+        chip: Chip,
+        format: ImageFormatKind,
+        elf: &'static str,
+        expected: &'static str,
+    ) -> Self {
+        Self {
+            chip,
+            format,
+            elf,
+            expected,
+            output: Output::App,
+            flash_mode: None,
+            flash_size: None,
+            flash_freq: None,
+        }
+    }
+
+    const fn merged(mut self) -> Self {
+        self.output = Output::Merged;
+        self
+    }
+
+    const fn flash_config(
+        mut self,
+        flash_mode: FlashMode,
+        flash_size: FlashSize,
+        flash_freq: FlashFrequency,
+    ) -> Self {
+        self.flash_mode = Some(flash_mode);
+        self.flash_size = Some(flash_size);
+        self.flash_freq = Some(flash_freq);
+        self
+    }
+
+    fn build(&self) -> Vec<u8> {
+        let elf_data = fs::read(Path::new(RESOURCES).join(self.elf)).unwrap();
+        let image = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+
+        let flash_data = FlashData::builder()
+            .flash_mode(self.flash_mode)
+            .flash_size(self.flash_size)
+            .flash_freq(self.flash_freq)
+            .build();
+        let image = self
+            .chip
+            .into_target()
+            .get_flash_image(&image, flash_data, Some(self.format), None)
+            .unwrap();
+
+        match self.output {
+            Output::App => merge(image.ota_segments()),
+            Output::Merged => merge(image.flash_segments()),
+        }
+    }
+}
+
+/// Concatenate segments, filling any gaps between them with `0xFF`
+fn merge<'a>(segments: impl Iterator<Item = Result<RomSegment<'a>, Error>>) -> Vec<u8> {
+    let mut segments = segments.collect::<Result<Vec<_>, _>>().unwrap();
+    segments.sort_by_key(|segment| segment.addr);
+
+    let base = segments.first().map(|segment| segment.addr).unwrap_or(0);
+    let mut data = Vec::new();
+    for segment in segments {
+        data.resize((segment.addr - base) as usize, 0xFF);
+        data.extend_from_slice(&segment.data);
+    }
+
+    data
+}
+
+const GOLDEN_IMAGES: &[GoldenImage] = &[
+    GoldenImage::app(
+        Chip::Esp32,
+        ImageFormatKind::EspBootloader,
+        "esp32_hal_blinky",
+        "esp32_hal_blinky.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32,
+        ImageFormatKind::EspBootloader,
+        "esp32_hal_blinky",
+        "esp32_hal_blinky_merged.bin",
+    )
+    .merged(),
+    GoldenImage::app(
+        Chip::Esp32,
+        ImageFormatKind::EspBootloader,
+        "esp32_hal_blinky",
+        "esp32_hal_blinky_dio_8mb_26m.bin",
+    )
+    .merged()
+    .flash_config(FlashMode::Dio, FlashSize::_8Mb, FlashFrequency::_26Mhz),
+    GoldenImage::app(
+        Chip::Esp32c3,
+        ImageFormatKind::DirectBoot,
+        "esp32c3_hal_blinky_db",
+        "esp32c3_hal_blinky_db.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp8266,
+        ImageFormatKind::EspBootloader,
+        "esp8266_hal_blinky",
+        "esp8266_hal_blinky_merged.bin",
+    )
+    .merged(),
+    GoldenImage::app(
+        Chip::Esp32s2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32s2_synthetic",
+        "golden/esp32s2_synthetic.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32s2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32s2_synthetic",
+        "golden/esp32s2_synthetic_merged.bin",
+    )
+    .merged(),
+    GoldenImage::app(
+        Chip::Esp32s3,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32s3_synthetic",
+        "golden/esp32s3_synthetic.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32s3,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32s3_synthetic",
+        "golden/esp32s3_synthetic_dio_32mb_80m.bin",
+    )
+    .merged()
+    .flash_config(FlashMode::Dio, FlashSize::_32Mb, FlashFrequency::_80Mhz),
+    GoldenImage::app(
+        Chip::Esp32c2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c2_synthetic",
+        "golden/esp32c2_synthetic.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32c2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c2_synthetic",
+        "golden/esp32c2_synthetic_dout_2mb_15m.bin",
+    )
+    .merged()
+    .flash_config(FlashMode::Dout, FlashSize::_2Mb, FlashFrequency::_15Mhz),
+    GoldenImage::app(
+        Chip::Esp32c3,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c3_synthetic",
+        "golden/esp32c3_synthetic.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32c6,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c6_synthetic",
+        "golden/esp32c6_synthetic.bin",
+    )
+    .flash_config(FlashMode::Dio, FlashSize::_2Mb, FlashFrequency::_40Mhz),
+    GoldenImage::app(
+        Chip::Esp32c6,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c6_synthetic",
+        "golden/esp32c6_synthetic_dio_16mb_80m.bin",
+    )
+    .merged()
+    .flash_config(FlashMode::Dio, FlashSize::_16Mb, FlashFrequency::_80Mhz),
+    GoldenImage::app(
+        Chip::Esp32h2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32h2_synthetic",
+        "golden/esp32h2_synthetic.bin",
+    ),
+    GoldenImage::app(
+        Chip::Esp32h2,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32h2_synthetic",
+        "golden/esp32h2_synthetic_dio_4mb_48m.bin",
+    )
+    .merged()
+    .flash_config(FlashMode::Dio, FlashSize::_4Mb, FlashFrequency::_48Mhz),
+    GoldenImage::app(
+        Chip::Esp32c61,
+        ImageFormatKind::EspBootloader,
+        "golden/esp32c61_synthetic",
+        "golden/esp32c61_synthetic.bin",
+    ),
+];
+
+#[test]
+fn test_golden_images() {
+    let mut mismatches = Vec::new();
+
+    for golden in GOLDEN_IMAGES {
+        let actual = golden.build();
+        let expected = fs::read(Path::new(RESOURCES).join(golden.expected)).unwrap();
+        if actual != expected {
+            let offset = actual
+                .iter()
+                .zip(&expected)
+                .position(|(a, b)| a != b)
+                .unwrap_or(actual.len().min(expected.len()));
+
+            mismatches.push(format!(
+                "{} ({}, {:?}): {} bytes expected, {} bytes built, first difference at {:#x}",
+                golden.expected,
+                golden.chip,
+                golden.output,
+                expected.len(),
+                actual.len(),
+                offset
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "images differ from the golden images:\n{}",
+        mismatches.join("\n")
+    );
+}
@@ -1,35 +1,38 @@
-use std::{borrow::Cow, io::Write, iter::once};
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+    iter::once_with,
+    mem::size_of,
+    ops::Range,
+};
 
-use bytemuck::{bytes_of, from_bytes, Pod, Zeroable};
+use bytemuck::{bytes_of, from_bytes};
 use esp_idf_part::{PartitionTable, Type};
 use sha2::{Digest, Sha256};
+use tracing::{debug_span, warn};
 
 use super::{
-    encode_flash_frequency, update_checksum, EspCommonHeader, ImageFormat, SegmentHeader,
-    ESP_CHECKSUM_MAGIC, ESP_MAGIC, WP_PIN_DISABLED,
+    encode_flash_frequency, is_app_image, merge_adjacent_segments, update_checksum,
+    EspCommonHeader, ExtendedHeader, ImageFormat, ImageInfo, SegmentHeader, ESP_CHECKSUM_MAGIC,
+    ESP_MAGIC, WP_PIN_DISABLED,
 };
 use crate::{
     elf::{CodeSegment, FirmwareImage, RomSegment},
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize},
+    flasher::{FlashData, FlashFrequency, FlashMode, FlashSize, FLASH_SECTOR_SIZE},
     targets::{Chip, Esp32Params},
 };
 
 const IROM_ALIGN: u32 = 0x10000;
 const SEG_HEADER_LEN: u32 = 8;
-
-#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-struct ExtendedHeader {
-    wp_pin: u8,
-    clk_q_drv: u8,
-    d_cs_drv: u8,
-    gd_wp_drv: u8,
-    chip_id: u16,
-    min_rev: u8,
-    padding: [u8; 8],
-    append_digest: u8,
-}
+const DIGEST_LEN: usize = 32;
+/// Magic word at the start of the application description
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+/// Location of the anti-rollback secure version within the application
+/// description
+const APP_DESC_SECURE_VERSION: Range<usize> = 4..8;
+/// Location of the build time and date within the application description
+const APP_DESC_BUILD_TIME: Range<usize> = 80..112;
 
 /// Image format for ESP32 family chips using the second-stage bootloader from
 /// ESP-IDF
@@ -37,7 +40,8 @@ pub struct IdfBootloaderFormat<'a> {
     params: Esp32Params,
     bootloader: Cow<'a, [u8]>,
     partition_table: PartitionTable,
-    flash_segment: RomSegment<'a>,
+    app: AppImage<'a>,
+    app_addr: u32,
     app_size: u32,
     part_size: u32,
 }
@@ -47,187 +51,230 @@ impl<'a> IdfBootloaderFormat<'a> {
         image: &'a dyn FirmwareImage<'a>,
         chip: Chip,
         params: Esp32Params,
-        partition_table: Option<PartitionTable>,
-        bootloader: Option<Vec<u8>>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
+        flash_data: FlashData,
     ) -> Result<Self, Error> {
-        let partition_table = partition_table
-            .unwrap_or_else(|| params.default_partition_table(flash_size.map(|v| v.size())));
-        let mut bootloader = if let Some(bytes) = bootloader {
-            Cow::Owned(bytes)
-        } else {
-            Cow::Borrowed(params.default_bootloader)
-        };
+        let _span = debug_span!("build_image", %chip).entered();
 
-        let mut data = Vec::new();
-
-        // fetch the generated header from the bootloader
-        let mut header: EspCommonHeader = *from_bytes(&bootloader[0..8]);
-        if header.magic != ESP_MAGIC {
-            return Err(Error::InvalidBootloader);
-        }
-
-        // update the header if a user has specified any custom arguments
-        if let Some(mode) = flash_mode {
-            header.flash_mode = mode as u8;
-            bootloader.to_mut()[2] = bytes_of(&header)[2];
-        }
-
-        match (flash_size, flash_freq) {
-            (Some(s), Some(f)) => {
-                header.flash_config = encode_flash_size(s)? + encode_flash_frequency(chip, f)?;
-                bootloader.to_mut()[3] = bytes_of(&header)[3];
-            }
-            (Some(s), None) => {
-                header.flash_config = encode_flash_size(s)? + (header.flash_config & 0x0F);
-                bootloader.to_mut()[3] = bytes_of(&header)[3];
-            }
-            (None, Some(f)) => {
-                header.flash_config =
-                    (header.flash_config & 0xF0) + encode_flash_frequency(chip, f)?;
-                bootloader.to_mut()[3] = bytes_of(&header)[3];
-            }
-            (None, None) => {} // nothing to update
-        }
+        let (bootloader, mut header) = patch_bootloader(chip, &params, &flash_data)?;
 
         // write the header of the app
         // use the same settings as the bootloader
         // just update the entry point
         header.entry = image.entry();
-        data.write_all(bytes_of(&header))?;
-
-        let extended_header = ExtendedHeader {
-            wp_pin: WP_PIN_DISABLED,
-            chip_id: params.chip_id,
-            append_digest: 1,
 
-            ..ExtendedHeader::default()
-        };
-
-        data.write_all(bytes_of(&extended_header))?;
+        let layout = AppImageLayout::new(image, chip, &params, header, &flash_data)?;
 
-        let flash_segments: Vec<_> = merge_adjacent_segments(image.rom_segments(chip).collect());
-        let mut ram_segments: Vec<_> = merge_adjacent_segments(image.ram_segments(chip).collect());
-
-        let mut checksum = ESP_CHECKSUM_MAGIC;
-        let mut segment_count = 0;
+        Self::with_app(params, bootloader, &flash_data, AppImage::Layout(layout))
+    }
 
-        for segment in flash_segments {
-            loop {
-                let pad_len = get_segment_padding(data.len(), &segment);
-                if pad_len > 0 {
-                    if pad_len > SEG_HEADER_LEN {
-                        if let Some(ram_segment) = ram_segments.first_mut() {
-                            // save up to `pad_len` from the ram segment, any remaining bits in the
-                            // ram segments will be saved later
-                            let pad_segment = ram_segment.split_off(pad_len as usize);
-                            checksum = save_segment(&mut data, &pad_segment, checksum)?;
-                            if ram_segment.data().is_empty() {
-                                ram_segments.remove(0);
-                            }
-                            segment_count += 1;
-                            continue;
-                        }
-                    }
+    /// Build an image from a pre-built application binary, such as one
+    /// produced by the ESP-IDF build system
+    ///
+    /// The application binary is written as-is; only the bootloader header is
+    /// updated with any of the provided flash settings.
+    pub fn from_bin(
+        app: &'a [u8],
+        chip: Chip,
+        params: Esp32Params,
+        flash_data: FlashData,
+    ) -> Result<Self, Error> {
+        if !is_app_image(app) {
+            return Err(Error::InvalidAppImage);
+        }
 
-                    let pad_header = SegmentHeader {
-                        addr: 0,
-                        length: pad_len,
-                    };
-                    data.write_all(bytes_of(&pad_header))?;
+        if flash_data.min_chip_rev.is_some() || flash_data.no_hash_appended {
+            warn!(
+                "The minimum chip revision and appended digest can not be changed for pre-built \
+                 application binaries"
+            );
+        }
+        if flash_data.secure_version.is_some() {
+            warn!(
+                "The secure version can not be changed for pre-built application binaries; set \
+                 it using CONFIG_BOOTLOADER_APP_SECURE_VERSION instead"
+            );
+        }
 
-                    for _ in 0..pad_len {
-                        data.write_all(&[0])?;
-                    }
+        let (bootloader, _) = patch_bootloader(chip, &params, &flash_data)?;
 
-                    segment_count += 1;
-                } else {
-                    break;
-                }
-            }
+        Self::with_app(params, bootloader, &flash_data, AppImage::Bin(app))
+    }
 
-            checksum = save_flash_segment(&mut data, segment, checksum)?;
-            segment_count += 1;
+    /// Write the application image to `writer`
+    ///
+    /// Images built from an ELF image are generated as they are written, so
+    /// are never held in memory in full.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        match &self.app {
+            AppImage::Layout(layout) => layout.write(writer),
+            AppImage::Bin(data) => Ok(writer.write_all(data)?),
         }
+    }
 
-        for segment in ram_segments {
-            checksum = save_segment(&mut data, &segment, checksum)?;
-            segment_count += 1;
+    fn with_app(
+        params: Esp32Params,
+        bootloader: Cow<'a, [u8]>,
+        flash_data: &FlashData,
+        app: AppImage<'a>,
+    ) -> Result<Self, Error> {
+        if flash_data.image_version.is_some() {
+            warn!("The image version only applies to the ESP8266, and is ignored");
         }
 
-        let padding = 15 - (data.len() % 16);
-        let padding = &[0u8; 16][0..padding];
-        data.write_all(padding)?;
-
-        data.write_all(&[checksum])?;
-
-        // since we added some dummy segments, we need to patch the segment count
-        data[1] = segment_count as u8;
+        let mut partition_table = match &flash_data.partition_table {
+            Some(_) if flash_data.auto_grow_partition => {
+                return Err(Error::AutoGrowCustomPartitionTable)
+            }
+            Some(partition_table) => partition_table.clone(),
+            None => params.default_partition_table_with_layout(
+                flash_data.partition_layout.unwrap_or_default(),
+                flash_data.flash_size.map(|v| v.size()),
+            ),
+        };
 
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let hash = hasher.finalize();
-        data.write_all(&hash)?;
+        let app_size = app.len() as u32;
+        let (mut app_addr, mut part_size) =
+            find_app_partition(&partition_table, flash_data.target_app_partition.as_deref())?;
 
-        // The default partition table contains the "factory" partition, and if a user
-        // provides a partition table via command-line then the validation step confirms
-        // that at least one "app" partition is present. We prefer the "factory"
-        // partition, and use any available "app" partitions if not present.
-        let factory_partition = partition_table
-            .find("factory")
-            .or_else(|| partition_table.find_by_type(Type::App))
-            .unwrap();
+        // The default partition table only spans the whole flash when its size is
+        // known, so if requested, grow the app partitions up to the end of the
+        // flash before giving up.
+        if app_size > part_size && flash_data.auto_grow_partition {
+            let flash_size = flash_data.flash_size.unwrap_or_default().size();
+            let grown_table = params.default_partition_table_with_layout(
+                flash_data.partition_layout.unwrap_or_default(),
+                Some(flash_size),
+            );
+            let (grown_addr, grown_size) =
+                find_app_partition(&grown_table, flash_data.target_app_partition.as_deref())?;
 
-        let app_size = data.len() as u32;
-        let part_size = factory_partition.size();
+            if grown_size > part_size {
+                warn!(
+                    "Growing the app partition from {part_size}B to {grown_size}B to fit the \
+                     application"
+                );
+                partition_table = grown_table;
+                app_addr = grown_addr;
+                part_size = grown_size;
+            }
+        }
 
         // The size of the application must not exceed the size of the factory
         // partition.
-        if app_size as f32 / part_size as f32 > 1.0 {
+        if app_size > part_size {
             return Err(Error::ElfTooBig(app_size, part_size));
         }
 
-        let flash_segment = RomSegment {
-            addr: factory_partition.offset(),
-            data: Cow::Owned(data),
-        };
+        check_partition_geometry(app_addr, part_size, app_size)?;
 
         Ok(Self {
             params,
             bootloader,
             partition_table,
-            flash_segment,
+            app,
+            app_addr,
             app_size,
             part_size,
         })
     }
 }
 
+/// Find the offset and size of the partition the application is written to
+fn find_app_partition(
+    partition_table: &PartitionTable,
+    target_app_partition: Option<&str>,
+) -> Result<(u32, u32), Error> {
+    // The default partition table contains the "factory" partition, and if a user
+    // provides a partition table via command-line then the validation step confirms
+    // that at least one "app" partition is present. We prefer the "factory"
+    // partition, and use any available "app" partitions if not present, unless a
+    // specific partition was requested.
+    let partition = match target_app_partition {
+        Some(label) => partition_table
+            .find(label)
+            .filter(|partition| partition.ty() == Type::App)
+            .ok_or_else(|| Error::PartitionNotFound(label.to_string()))?,
+        None => partition_table
+            .find("factory")
+            .or_else(|| partition_table.find_by_type(Type::App))
+            .unwrap(),
+    };
+
+    Ok((partition.offset(), partition.size()))
+}
+
+/// Ensure that writing the application does not erase any of the flash
+/// following its partition
+///
+/// Flash is erased a whole sector at a time, so an application partition which
+/// does not start on a sector boundary, or whose last sector is shared with the
+/// next partition, would have that partition partially erased.
+fn check_partition_geometry(offset: u32, size: u32, app_size: u32) -> Result<(), Error> {
+    let sector_size = FLASH_SECTOR_SIZE as u32;
+    let erase_end = offset + (app_size + sector_size - 1) / sector_size * sector_size;
+
+    if offset % sector_size != 0 || erase_end > offset + size {
+        return Err(Error::UnalignedAppPartition {
+            offset,
+            size,
+            erase_end,
+        });
+    }
+
+    Ok(())
+}
+
 impl<'a> ImageFormat<'a> for IdfBootloaderFormat<'a> {
-    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
         Box::new(
-            once(RomSegment {
-                addr: self.params.boot_addr,
-                data: Cow::Borrowed(&self.bootloader),
-            })
-            .chain(once(RomSegment {
-                addr: self.params.partition_addr,
-                data: Cow::Owned(self.partition_table.to_bin().unwrap()),
-            }))
-            .chain(once(self.flash_segment.borrow())),
+            self.bootloader_segment()
+                .into_iter()
+                .chain(self.partition_table_segment())
+                .map(Ok)
+                .chain(self.ota_segments()),
         )
     }
 
-    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn bootloader_segment<'b>(&'b self) -> Option<RomSegment<'b>>
+    where
+        'a: 'b,
+    {
+        Some(RomSegment {
+            addr: self.params.boot_addr,
+            data: Cow::Borrowed(&self.bootloader),
+            encrypted: false,
+        })
+    }
+
+    fn partition_table_segment<'b>(&'b self) -> Option<RomSegment<'b>>
+    where
+        'a: 'b,
+    {
+        Some(RomSegment {
+            addr: self.params.partition_addr,
+            data: Cow::Owned(self.partition_table.to_bin().unwrap()),
+            encrypted: false,
+        })
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b,
     {
-        Box::new(once(self.flash_segment.borrow()))
+        // The application image is only generated once it is needed
+        Box::new(once_with(move || {
+            let mut data = Vec::with_capacity(self.app_size as usize);
+            self.write_to(&mut data)?;
+
+            Ok(RomSegment {
+                addr: self.app_addr,
+                data: Cow::Owned(data),
+                encrypted: false,
+            })
+        }))
     }
 
     fn app_size(&self) -> u32 {
@@ -237,9 +284,170 @@ impl<'a> ImageFormat<'a> for IdfBootloaderFormat<'a> {
     fn part_size(&self) -> Option<u32> {
         Some(self.part_size)
     }
+
+    fn write_to(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        IdfBootloaderFormat::write_to(self, writer)
+    }
+
+    fn app_addr(&self) -> Option<u32> {
+        Some(self.app_addr)
+    }
+}
+
+/// An application image, either built from an ELF image or pre-built
+enum AppImage<'a> {
+    Layout(AppImageLayout<'a>),
+    Bin(&'a [u8]),
 }
 
-fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
+impl AppImage<'_> {
+    fn len(&self) -> usize {
+        match self {
+            AppImage::Layout(layout) => layout.len(),
+            AppImage::Bin(data) => data.len(),
+        }
+    }
+}
+
+/// Load the bootloader (either the provided one or the chip's default), and
+/// update its header with any custom flash settings
+///
+/// Returns the bootloader along with its (updated) common header.
+fn patch_bootloader<'a>(
+    chip: Chip,
+    params: &Esp32Params,
+    flash_data: &FlashData,
+) -> Result<(Cow<'a, [u8]>, EspCommonHeader), Error> {
+    let mut bootloader = if let Some(bytes) = &flash_data.bootloader {
+        Cow::Owned(bytes.clone())
+    } else {
+        Cow::Borrowed(params.default_bootloader)
+    };
+
+    // The default bootloader's header must match the mode it uses the flash in,
+    // which is not always the selected one
+    let flash_mode = match flash_data.flash_mode {
+        Some(mode) if flash_data.bootloader.is_none() => {
+            chip.check_flash_mode(mode)?;
+
+            let written = params.bootloader_flash_mode(mode);
+            if written != mode {
+                warn!(
+                    "The default bootloader was built for DIO mode, and uses the flash in \
+                     {written:?} mode rather than {mode:?} mode; provide a bootloader built for \
+                     {mode:?} mode using `--bootloader`"
+                );
+            }
+
+            Some(written)
+        }
+        mode => mode,
+    };
+
+    // update the header if a user has specified any custom arguments
+    if flash_mode.is_some() || flash_data.flash_size.is_some() || flash_data.flash_freq.is_some() {
+        update_flash_params(
+            chip,
+            bootloader.to_mut(),
+            flash_mode,
+            flash_data.flash_size,
+            flash_data.flash_freq,
+        )?;
+    }
+
+    if flash_data.bootloader.is_some() {
+        validate_bootloader(chip, params, &bootloader)?;
+    }
+
+    // fetch the (updated) header from the bootloader
+    let header: EspCommonHeader = *from_bytes(&bootloader[0..8]);
+    if header.magic != ESP_MAGIC {
+        return Err(Error::InvalidBootloader);
+    }
+
+    Ok((bootloader, header))
+}
+
+/// Check that a custom bootloader is a valid image built for `chip`, and that
+/// the flash settings in its header are supported by it
+fn validate_bootloader(chip: Chip, params: &Esp32Params, bootloader: &[u8]) -> Result<(), Error> {
+    let info = ImageInfo::parse(bootloader, Some(chip)).map_err(|_| Error::InvalidBootloader)?;
+    if !info.checksum_valid() {
+        return Err(Error::InvalidBootloader);
+    }
+
+    if info.chip_id != Some(params.chip_id) {
+        return match info.chip_id.and_then(|id| Chip::from_chip_id(id as u32)) {
+            Some(found) => Err(Error::ImageChipMismatch {
+                part: "bootloader",
+                expected: chip,
+                found,
+            }),
+            None => Err(Error::InvalidBootloader),
+        };
+    }
+
+    let unsupported = if info.flash_mode.is_none() {
+        Some("mode")
+    } else if info.flash_size.is_none() {
+        Some("size")
+    } else if info.flash_freq.is_none() {
+        Some("frequency")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(setting) => Err(Error::UnsupportedBootloaderFlashSetting { setting, chip }),
+        None => Ok(()),
+    }
+}
+
+/// Update the flash settings in the header of an ESP-IDF image, for each one
+/// which is provided
+pub fn update_flash_params(
+    chip: Chip,
+    image: &mut [u8],
+    flash_mode: Option<FlashMode>,
+    flash_size: Option<FlashSize>,
+    flash_freq: Option<FlashFrequency>,
+) -> Result<(), Error> {
+    if image.len() < size_of::<EspCommonHeader>() || image[0] != ESP_MAGIC {
+        return Err(Error::InvalidBootloader);
+    }
+
+    if let Some(mode) = flash_mode {
+        chip.check_flash_mode(mode)?;
+        image[2] = mode.header_mode() as u8;
+    }
+
+    let size = match flash_size {
+        Some(size) => {
+            chip.check_flash_size(size)?;
+            encode_flash_size(size)?
+        }
+        None => image[3] & 0xF0,
+    };
+    let freq = match flash_freq {
+        Some(freq) => encode_flash_frequency(chip, freq)?,
+        None => image[3] & 0x0F,
+    };
+    image[3] = size + freq;
+
+    // The checksum only covers the segments' data, however any appended digest
+    // also covers the header, so must be recalculated
+    if let Ok(ImageInfo {
+        data_length,
+        calculated_digest: Some(digest),
+        ..
+    }) = ImageInfo::parse(image, Some(chip))
+    {
+        image[data_length..][..digest.len()].copy_from_slice(&digest);
+    }
+
+    Ok(())
+}
+
+pub(super) fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
     use FlashSize::*;
 
     match size {
@@ -248,9 +456,9 @@ fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
         _4Mb => Ok(0x20),
         _8Mb => Ok(0x30),
         _16Mb => Ok(0x40),
-        _32Mb => Ok(0x19),
-        _64Mb => Ok(0x1a),
-        _128Mb => Ok(0x21),
+        _32Mb => Ok(0x50),
+        _64Mb => Ok(0x60),
+        _128Mb => Ok(0x70),
         _ => Err(Error::UnsupportedFlash(size as u8)),
     }
 }
@@ -274,68 +482,299 @@ fn get_segment_padding(offset: usize, segment: &CodeSegment) -> u32 {
     }
 }
 
-fn merge_adjacent_segments(mut segments: Vec<CodeSegment>) -> Vec<CodeSegment> {
-    segments.sort();
+/// Find the application description (`esp_app_desc_t`), which is placed at
+/// the start of one of the flash segments
+fn find_app_desc<'s>(segments: &'s mut [CodeSegment]) -> Option<&'s mut [u8]> {
+    segments
+        .iter_mut()
+        .find(|segment| is_app_desc(segment))
+        .map(|segment| segment.data_mut())
+}
+
+/// Does the segment begin with the application description?
+fn is_app_desc(segment: &CodeSegment) -> bool {
+    segment.data().len() >= APP_DESC_BUILD_TIME.end
+        && segment.data()[..4] == APP_DESC_MAGIC.to_le_bytes()
+}
+
+/// Zero the build time and date in the application description, if present,
+/// so that the image does not depend on when it was built
+fn clear_build_time(segments: &mut [CodeSegment]) {
+    if let Some(app_desc) = find_app_desc(segments) {
+        app_desc[APP_DESC_BUILD_TIME].fill(0);
+    }
+}
+
+/// Write the anti-rollback secure version into the application description
+fn set_secure_version(segments: &mut [CodeSegment], secure_version: u32) -> Result<(), Error> {
+    let app_desc = find_app_desc(segments).ok_or(Error::AppDescriptionNotFound)?;
+    app_desc[APP_DESC_SECURE_VERSION].copy_from_slice(&secure_version.to_le_bytes());
+
+    Ok(())
+}
+
+/// The layout of an application image, computed up front so that the image
+/// can be streamed to any [Write] sink without buffering it, or patching the
+/// header after the fact
+struct AppImageLayout<'a> {
+    header: EspCommonHeader,
+    extended_header: ExtendedHeader,
+    entries: Vec<LayoutEntry<'a>>,
+}
+
+enum LayoutEntry<'a> {
+    /// A segment, followed by `tail` bytes of zero padding
+    Segment { segment: CodeSegment<'a>, tail: u32 },
+    /// A dummy segment used to align the following flash segment
+    Padding(u32),
+}
+
+impl<'a> LayoutEntry<'a> {
+    /// Length of the segment's data, including any padding
+    fn data_len(&self) -> u32 {
+        match self {
+            LayoutEntry::Segment { segment, tail } => align4(segment.size() + tail),
+            LayoutEntry::Padding(len) => *len,
+        }
+    }
+
+    /// Length of the segment, including its header
+    fn len(&self) -> u32 {
+        SEG_HEADER_LEN + self.data_len()
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, checksum: u8) -> Result<u8, Error> {
+        let (addr, data) = match self {
+            LayoutEntry::Segment { segment, .. } => (segment.addr, segment.data()),
+            LayoutEntry::Padding(_) => (0, &[][..]),
+        };
+
+        let header = SegmentHeader {
+            addr,
+            length: self.data_len(),
+        };
+
+        writer.write_all(bytes_of(&header))?;
+        writer.write_all(data)?;
+        write_zeros(writer, self.data_len() - data.len() as u32)?;
+
+        Ok(update_checksum(data, checksum))
+    }
+}
+
+impl<'a> AppImageLayout<'a> {
+    fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        chip: Chip,
+        params: &Esp32Params,
+        mut header: EspCommonHeader,
+        flash_data: &FlashData,
+    ) -> Result<Self, Error> {
+        let min_chip_rev_full = match flash_data.min_chip_rev {
+            Some((major, minor)) => (major * 100 + minor) as u16,
+            None => params.min_chip_rev_full,
+        };
 
-    let mut merged: Vec<CodeSegment> = Vec::with_capacity(segments.len());
-    for segment in segments {
-        match merged.last_mut() {
-            Some(last) if last.addr + last.size() == segment.addr => {
-                *last += segment.data();
+        let extended_header = ExtendedHeader {
+            wp_pin: WP_PIN_DISABLED,
+            chip_id: params.chip_id,
+            // The legacy field only holds the major revision
+            min_rev: (min_chip_rev_full / 100) as u8,
+            min_chip_rev_full,
+            max_chip_rev_full: params.max_chip_rev_full,
+            append_digest: !flash_data.no_hash_appended as u8,
+
+            ..ExtendedHeader::default()
+        };
+
+        // RTC segments are kept separate from the other RAM segments: they're
+        // never split off as padding, as the bootloader decides whether to load a
+        // segment by its address, and skips RTC memory when waking from deep sleep.
+        let target = chip.into_target();
+        let mut flash_segments = Vec::new();
+        let mut ram_segments = Vec::new();
+        let mut rtc_segments = Vec::new();
+        for segment in merge_adjacent_segments(chip, image.segments()) {
+            if target.addr_is_flash(segment.addr) {
+                flash_segments.push(segment);
+            } else if target.addr_is_rtc(segment.addr) {
+                rtc_segments.push(segment);
+            } else {
+                ram_segments.push(segment);
             }
-            _ => {
-                merged.push(segment);
+        }
+        flash_segments.sort();
+        ram_segments.sort();
+        rtc_segments.sort();
+
+        // The bootloader reads the application description from the start of the
+        // first segment, which on chips whose DROM is mapped above IROM isn't
+        // the one at the lowest address
+        if let Some(index) = flash_segments.iter().position(is_app_desc) {
+            let app_desc = flash_segments.remove(index);
+            flash_segments.insert(0, app_desc);
+        }
+
+        if flash_data.reproducible {
+            clear_build_time(&mut flash_segments);
+        }
+        if let Some(secure_version) = flash_data.secure_version {
+            set_secure_version(&mut flash_segments, secure_version)?;
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = (size_of::<EspCommonHeader>() + size_of::<ExtendedHeader>()) as u32;
+
+        for segment in flash_segments {
+            loop {
+                let pad_len = get_segment_padding(offset as usize, &segment);
+                if pad_len > 0 {
+                    if pad_len > SEG_HEADER_LEN {
+                        if let Some(ram_segment) = ram_segments.first_mut() {
+                            // save up to `pad_len` from the ram segment, any remaining bits in the
+                            // ram segments will be saved later
+                            let pad_segment = ram_segment.split_off(pad_len as usize);
+                            if ram_segment.data().is_empty() {
+                                ram_segments.remove(0);
+                            }
+
+                            let entry = LayoutEntry::Segment {
+                                segment: pad_segment,
+                                tail: 0,
+                            };
+                            offset += entry.len();
+                            entries.push(entry);
+                            continue;
+                        }
+                    }
+
+                    let entry = LayoutEntry::Padding(pad_len);
+                    offset += entry.len();
+                    entries.push(entry);
+                } else {
+                    break;
+                }
             }
+
+            // Work around a bug in ESP-IDF 2nd stage bootloader, that it didn't map the
+            // last MMU page, if an IROM/DROM segment was < 0x24 bytes over the page
+            // boundary.
+            let end_pos = offset + segment.size() + SEG_HEADER_LEN;
+            let tail = 0x24_u32.saturating_sub(end_pos % IROM_ALIGN);
+
+            let entry = LayoutEntry::Segment { segment, tail };
+            offset += entry.len();
+            entries.push(entry);
         }
+
+        for segment in ram_segments.into_iter().chain(rtc_segments) {
+            entries.push(LayoutEntry::Segment { segment, tail: 0 });
+        }
+
+        header.segment_count = entries.len() as u8;
+
+        Ok(Self {
+            header,
+            extended_header,
+            entries,
+        })
     }
 
-    merged
-}
+    /// Total length of the image, including the checksum and any appended
+    /// digest
+    fn len(&self) -> usize {
+        let len = size_of::<EspCommonHeader>()
+            + size_of::<ExtendedHeader>()
+            + self.entries.iter().map(|e| e.len() as usize).sum::<usize>();
 
-fn save_flash_segment(
-    data: &mut Vec<u8>,
-    mut segment: CodeSegment,
-    checksum: u8,
-) -> Result<u8, Error> {
-    let end_pos = (data.len() + segment.data().len()) as u32 + SEG_HEADER_LEN;
-    let segment_reminder = end_pos % IROM_ALIGN;
+        // padding, checksum
+        let len = len + (15 - len % 16) + 1;
 
-    if segment_reminder < 0x24 {
-        // Work around a bug in ESP-IDF 2nd stage bootloader, that it didn't map the
-        // last MMU page, if an IROM/DROM segment was < 0x24 bytes over the page
-        // boundary.
-        static PADDING: [u8; 0x24] = [0; 0x24];
+        if self.extended_header.append_digest == 1 {
+            len + DIGEST_LEN
+        } else {
+            len
+        }
+    }
 
-        segment += &PADDING[0..(0x24 - segment_reminder as usize)];
+    fn write<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let mut writer = HashingWriter::new(writer);
+
+        writer.write_all(bytes_of(&self.header))?;
+        writer.write_all(bytes_of(&self.extended_header))?;
+
+        let mut checksum = ESP_CHECKSUM_MAGIC;
+        for entry in &self.entries {
+            checksum = entry.write(&mut writer, checksum)?;
+        }
+
+        let padding = 15 - (writer.position % 16) as u32;
+        write_zeros(&mut writer, padding)?;
+        writer.write_all(&[checksum])?;
+
+        let (mut writer, digest) = writer.finish();
+        if self.extended_header.append_digest == 1 {
+            writer.write_all(&digest)?;
+        }
+
+        Ok(())
     }
+}
 
-    let checksum = save_segment(data, &segment, checksum)?;
+/// A [Write] adapter which calculates the SHA-256 digest of everything written
+/// through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    position: usize,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            position: 0,
+        }
+    }
 
-    Ok(checksum)
+    fn finish(self) -> (W, Vec<u8>) {
+        (self.inner, self.hasher.finalize().to_vec())
+    }
 }
 
-fn save_segment(data: &mut Vec<u8>, segment: &CodeSegment, checksum: u8) -> Result<u8, Error> {
-    let padding = (4 - segment.size() % 4) % 4;
-    let header = SegmentHeader {
-        addr: segment.addr,
-        length: segment.size() + padding,
-    };
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.position += len;
 
-    data.write_all(bytes_of(&header))?;
-    data.write_all(segment.data())?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_zeros<W: Write>(writer: &mut W, len: u32) -> Result<(), Error> {
+    io::copy(&mut io::repeat(0).take(len as u64), writer)?;
 
-    let padding = &[0u8; 4][0..padding as usize];
-    data.write_all(padding)?;
+    Ok(())
+}
 
-    Ok(update_checksum(segment.data(), checksum))
+fn align4(len: u32) -> u32 {
+    (len + 3) & !3
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::fs;
 
+    use strum::IntoEnumIterator;
+
     use super::*;
-    use crate::elf::ElfFirmwareImage;
+    use crate::{elf::ElfFirmwareImage, image_format::ImageInfo, targets::PartitionLayout};
 
     // Copied from: src/targets/esp32.rs
     const PARAMS: Esp32Params = Esp32Params::new(
@@ -353,14 +792,550 @@ pub mod tests {
 
         let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
         let flash_image =
-            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, None, None, None, None, None)
-                .unwrap();
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, FlashData::default()).unwrap();
 
-        let segments = flash_image.flash_segments().collect::<Vec<_>>();
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
         assert_eq!(segments.len(), 3);
 
         let buf = segments[2].data.as_ref();
         assert_eq!(expected_bin.len(), buf.len());
         assert_eq!(expected_bin.as_slice(), buf);
     }
+
+    #[test]
+    fn test_bootloader_flash_mode() {
+        let flash_data = |bootloader: Option<Vec<u8>>| {
+            FlashData::builder()
+                .bootloader(bootloader)
+                .flash_mode(FlashMode::Qio)
+                .flash_freq(FlashFrequency::_80Mhz)
+                .build()
+        };
+        let header = |flash_data: &FlashData| {
+            let (bootloader, _) = patch_bootloader(Chip::Esp32, &PARAMS, flash_data).unwrap();
+            ImageInfo::parse(&bootloader, Some(Chip::Esp32)).unwrap()
+        };
+
+        // The default bootloader was built for DIO mode, but uses the frequency in
+        // its header
+        let info = header(&flash_data(None));
+        assert_eq!(info.flash_mode, Some(FlashMode::Dio));
+        assert_eq!(info.flash_freq, Some(FlashFrequency::_80Mhz));
+
+        // Custom bootloaders are written with the selected mode
+        let info = header(&flash_data(Some(PARAMS.default_bootloader.to_vec())));
+        assert_eq!(info.flash_mode, Some(FlashMode::Qio));
+        assert_eq!(info.flash_freq, Some(FlashFrequency::_80Mhz));
+
+        assert_eq!(
+            PARAMS.bootloader_flash_mode(FlashMode::Dout),
+            FlashMode::Dout
+        );
+    }
+
+    #[test]
+    fn test_update_flash_params_digest() {
+        let mut bootloader = PARAMS.default_bootloader.to_vec();
+        update_flash_params(
+            Chip::Esp32,
+            &mut bootloader,
+            Some(FlashMode::Qio),
+            Some(FlashSize::_16Mb),
+            Some(FlashFrequency::_80Mhz),
+        )
+        .unwrap();
+
+        let info = ImageInfo::parse(&bootloader, Some(Chip::Esp32)).unwrap();
+        assert_eq!(info.flash_mode, Some(FlashMode::Qio));
+        assert_eq!(info.flash_size, Some(FlashSize::_16Mb));
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(true));
+    }
+
+    #[test]
+    fn test_default_partition_layout() {
+        let table =
+            PARAMS.default_partition_table_with_layout(PartitionLayout::TwoOta, Some(0x40_0000));
+        let partitions = table
+            .partitions()
+            .iter()
+            .map(|partition| (partition.name(), partition.offset(), partition.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            partitions,
+            vec![
+                ("nvs".to_string(), 0x9000, 0x4000),
+                ("otadata".to_string(), 0xd000, 0x2000),
+                ("phy_init".to_string(), 0xf000, 0x1000),
+                ("ota_0".to_string(), 0x1_0000, 0x1f_0000),
+                ("ota_1".to_string(), 0x20_0000, 0x1f_0000),
+            ]
+        );
+
+        // When the flash size is unknown, the app partitions share the default space
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+        let flash_data = FlashData::builder()
+            .partition_layout(PartitionLayout::TwoOtaWithFactory)
+            .target_app_partition("ota_1".to_string())
+            .build();
+        let flash_image =
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data).unwrap();
+        assert_eq!(
+            flash_image.ota_segments().next().unwrap().unwrap().addr,
+            0x1_0000 + 2 * 0x15_0000
+        );
+    }
+
+    #[test]
+    fn test_check_partition_geometry() {
+        assert!(check_partition_geometry(0x1_0000, 0x10_0000, 0x10_0000).is_ok());
+        assert!(check_partition_geometry(0x1_0000, 0x1_0800, 0x1_0000).is_ok());
+        assert!(check_partition_geometry(0x1_0000, 0x1_0800, 0x1_0001).is_err());
+        assert!(check_partition_geometry(0x1_0800, 0x10_0000, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_app_image_layout_len() {
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+
+        let header = EspCommonHeader {
+            magic: ESP_MAGIC,
+            segment_count: 0,
+            flash_mode: 0,
+            flash_config: 0,
+            entry: image.entry(),
+        };
+        let layout =
+            AppImageLayout::new(&image, Chip::Esp32, &PARAMS, header, &FlashData::default())
+                .unwrap();
+
+        let mut data = Vec::new();
+        layout.write(&mut data).unwrap();
+
+        assert_eq!(layout.len(), data.len());
+    }
+
+    #[test]
+    fn test_write_to_matches_ota_segments() {
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+        let flash_image =
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, FlashData::default()).unwrap();
+
+        let mut data = Vec::new();
+        flash_image.write_to(&mut data).unwrap();
+
+        let segments = flash_image
+            .ota_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, data);
+        assert_eq!(flash_image.app_size() as usize, data.len());
+    }
+
+    #[test]
+    fn test_update_flash_params_unsupported_size() {
+        let mut bootloader = PARAMS.default_bootloader.to_vec();
+
+        assert!(matches!(
+            update_flash_params(
+                Chip::Esp32,
+                &mut bootloader,
+                None,
+                Some(FlashSize::_32Mb),
+                None
+            ),
+            Err(Error::UnsupportedFlashSize {
+                chip: Chip::Esp32,
+                flash_size: FlashSize::_32Mb
+            })
+        ));
+        assert!(update_flash_params(
+            Chip::Esp32s3,
+            &mut bootloader,
+            None,
+            Some(FlashSize::_32Mb),
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_encode_flash_size() {
+        // The size is in the high nibble of the header's flash config byte, so
+        // it must never spill into the frequency
+        let encoded = FlashSize::iter()
+            .filter_map(|size| encode_flash_size(size).ok())
+            .collect::<Vec<_>>();
+        assert_eq!(encoded, [0x00, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70]);
+
+        let mut bootloader = PARAMS.default_bootloader.to_vec();
+        update_flash_params(
+            Chip::Esp32s3,
+            &mut bootloader,
+            None,
+            Some(FlashSize::_64Mb),
+            None,
+        )
+        .unwrap();
+        assert_eq!(bootloader[3] & 0xF0, 0x60);
+        assert_eq!(
+            ImageInfo::parse(&bootloader, Some(Chip::Esp32s3))
+                .unwrap()
+                .flash_size,
+            Some(FlashSize::_64Mb)
+        );
+    }
+
+    #[test]
+    fn test_update_flash_params_capabilities() {
+        let mut bootloader = PARAMS.default_bootloader.to_vec();
+
+        // Octal flash and 120MHz are only supported by the ESP32-S3
+        assert!(matches!(
+            update_flash_params(
+                Chip::Esp32,
+                &mut bootloader,
+                Some(FlashMode::Opi),
+                None,
+                None
+            ),
+            Err(Error::UnsupportedFlashMode {
+                chip: Chip::Esp32,
+                flash_mode: FlashMode::Opi
+            })
+        ));
+        assert!(matches!(
+            update_flash_params(
+                Chip::Esp32,
+                &mut bootloader,
+                None,
+                None,
+                Some(FlashFrequency::_120Mhz)
+            ),
+            Err(Error::UnsupportedFlashFrequency {
+                chip: Chip::Esp32,
+                frequency: FlashFrequency::_120Mhz
+            })
+        ));
+
+        // Neither can be represented in the header, so DIO and 80MHz are written
+        let mut bootloader =
+            include_bytes!("../../resources/bootloaders/esp32s3-bootloader.bin").to_vec();
+        update_flash_params(
+            Chip::Esp32s3,
+            &mut bootloader,
+            Some(FlashMode::Opi),
+            None,
+            Some(FlashFrequency::_120Mhz),
+        )
+        .unwrap();
+
+        let info = ImageInfo::parse(&bootloader, Some(Chip::Esp32s3)).unwrap();
+        assert_eq!(info.flash_mode, Some(FlashMode::Dio));
+        assert_eq!(info.flash_freq, Some(FlashFrequency::_80Mhz));
+    }
+
+    #[test]
+    fn test_custom_bootloader_validation() {
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+
+        let bootloader = fs::read("resources/bootloaders/esp32c3-bootloader.bin").unwrap();
+        let flash_data = FlashData::builder().bootloader(bootloader).build();
+        assert!(matches!(
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data),
+            Err(Error::ImageChipMismatch {
+                found: Chip::Esp32c3,
+                ..
+            })
+        ));
+
+        let mut bootloader = fs::read("resources/bootloaders/esp32-bootloader.bin").unwrap();
+        bootloader[3] = (bootloader[3] & 0xF0) | 0x0E;
+        let flash_data = FlashData::builder().bootloader(bootloader.clone()).build();
+        assert!(matches!(
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data),
+            Err(Error::UnsupportedBootloaderFlashSetting {
+                setting: "frequency",
+                ..
+            })
+        ));
+
+        // Settings provided on the command line replace those in the header
+        let flash_data = FlashData::builder()
+            .bootloader(bootloader)
+            .flash_freq(FlashFrequency::_40Mhz)
+            .build();
+        assert!(IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data).is_ok());
+    }
+
+    #[test]
+    fn test_min_chip_rev() {
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+
+        let flash_data = FlashData::builder().min_chip_rev((3, 1)).build();
+        let flash_image =
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data).unwrap();
+
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let info = ImageInfo::parse(&segments[2].data, Some(Chip::Esp32)).unwrap();
+        assert_eq!(info.min_rev, Some(3));
+        assert_eq!(info.min_chip_rev_full, Some(301));
+        assert_eq!(info.max_chip_rev_full, Some(u16::MAX));
+        assert_eq!(info.digest_valid(), Some(true));
+    }
+
+    #[test]
+    fn test_no_hash_appended() {
+        let input_bytes = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let expected_bin = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let image = ElfFirmwareImage::try_from(input_bytes.as_slice()).unwrap();
+
+        let flash_data = FlashData::builder().no_hash_appended(true).build();
+        let flash_image =
+            IdfBootloaderFormat::new(&image, Chip::Esp32, PARAMS, flash_data).unwrap();
+
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let buf = segments[2].data.as_ref();
+        assert_eq!(buf.len(), expected_bin.len() - DIGEST_LEN);
+
+        let info = ImageInfo::parse(buf, Some(Chip::Esp32)).unwrap();
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest, None);
+    }
+
+    #[test]
+    fn test_idf_bootloader_format_from_bin() {
+        let app = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+
+        let flash_image =
+            IdfBootloaderFormat::from_bin(&app, Chip::Esp32, PARAMS, FlashData::default()).unwrap();
+
+        let segments = flash_image
+            .flash_segments()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[2].addr, PARAMS.app_addr);
+        assert_eq!(app.as_slice(), segments[2].data.as_ref());
+    }
+
+    #[test]
+    fn test_idf_bootloader_format_target_app_partition() {
+        let app = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+
+        let flash_data = FlashData::builder()
+            .target_app_partition("factory".to_string())
+            .build();
+        assert!(IdfBootloaderFormat::from_bin(&app, Chip::Esp32, PARAMS, flash_data).is_ok());
+
+        let flash_data = FlashData::builder()
+            .target_app_partition("nvs".to_string())
+            .build();
+        assert!(matches!(
+            IdfBootloaderFormat::from_bin(&app, Chip::Esp32, PARAMS, flash_data),
+            Err(Error::PartitionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_idf_bootloader_format_auto_grow_partition() {
+        // A 1MB default app partition, as for the ESP32-S2 and ESP32-S3
+        let params = Esp32Params::new(0x1000, 0x1_0000, 0x10_0000, 0, PARAMS.default_bootloader);
+
+        let mut app = vec![0u8; 0x10_0001];
+        app[0] = ESP_MAGIC;
+
+        assert!(matches!(
+            IdfBootloaderFormat::from_bin(&app, Chip::Esp32, params, FlashData::default()),
+            Err(Error::ElfTooBig(0x10_0001, 0x10_0000))
+        ));
+
+        let flash_data = FlashData::builder().auto_grow_partition(true).build();
+        let image = IdfBootloaderFormat::from_bin(&app, Chip::Esp32, params, flash_data).unwrap();
+        assert_eq!(image.part_size(), Some(0x3f_0000));
+
+        let flash_data = FlashData::builder()
+            .partition_table(params.default_partition_table(None))
+            .auto_grow_partition(true)
+            .build();
+        assert!(matches!(
+            IdfBootloaderFormat::from_bin(&app, Chip::Esp32, params, flash_data),
+            Err(Error::AutoGrowCustomPartitionTable)
+        ));
+    }
+
+    #[test]
+    fn test_clear_build_time() {
+        let mut app_desc = vec![0u8; 256];
+        app_desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        app_desc[48..56].copy_from_slice(b"blinky\0\0");
+        app_desc[80..88].copy_from_slice(b"12:34:56");
+        app_desc[96..107].copy_from_slice(b"Jan  1 2023");
+
+        let mut segments = vec![
+            CodeSegment::new(0x4008_0000, &[0xAA; 128]),
+            CodeSegment::new(0x3F40_0020, &app_desc),
+        ];
+        clear_build_time(&mut segments);
+
+        assert_eq!(segments[0].data(), &[0xAA; 128]);
+        assert!(segments[1].data()[80..112].iter().all(|&b| b == 0));
+        assert_eq!(&segments[1].data()[48..56], b"blinky\0\0");
+    }
+
+    #[test]
+    fn test_app_desc_first() {
+        let mut app_desc = vec![0u8; 256];
+        app_desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+
+        // On the ESP32-C6 DROM is mapped above IROM, but the segment with the
+        // application description must still come first
+        let image = SegmentImage(vec![
+            (0x4200_0020, vec![0x11; 0x100]),
+            (0x4281_0020, app_desc),
+            (0x4080_0000, vec![0x22; 0x100]),
+        ]);
+        let segments = layout_segments(&image, Chip::Esp32c6);
+        assert_eq!(segments[0], (0x4281_0020, 0x100));
+        assert!(segments.contains(&(0x4200_0020, 0x100)));
+    }
+
+    #[test]
+    fn test_set_secure_version() {
+        let mut app_desc = vec![0u8; 256];
+        app_desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+
+        let mut segments = vec![CodeSegment::new(0x3F40_0020, &app_desc)];
+        set_secure_version(&mut segments, 3).unwrap();
+        assert_eq!(segments[0].data()[4..8], [3, 0, 0, 0]);
+
+        // Applications without a description can not be given a secure version
+        let mut segments = vec![CodeSegment::new(0x4008_0000, &[0xAA; 128])];
+        assert!(matches!(
+            set_secure_version(&mut segments, 3),
+            Err(Error::AppDescriptionNotFound)
+        ));
+    }
+
+    struct SegmentImage(Vec<(u32, Vec<u8>)>);
+
+    impl<'a> FirmwareImage<'a> for SegmentImage {
+        fn entry(&self) -> u32 {
+            0x4008_0000
+        }
+
+        fn segments(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+            Box::new(
+                self.0
+                    .iter()
+                    .map(|(addr, data)| CodeSegment::new(*addr, data)),
+            )
+        }
+
+        fn segments_with_load_addresses(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+            self.segments()
+        }
+    }
+
+    /// Lay out an image, returning the address and size of each segment
+    fn layout_segments(image: &SegmentImage, chip: Chip) -> Vec<(u32, u32)> {
+        let header = EspCommonHeader {
+            magic: ESP_MAGIC,
+            segment_count: 0,
+            flash_mode: 0,
+            flash_config: 0,
+            entry: image.entry(),
+        };
+        let layout =
+            AppImageLayout::new(image, chip, &PARAMS, header, &FlashData::default()).unwrap();
+
+        layout
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                LayoutEntry::Segment { segment, .. } => Some((segment.addr, segment.size())),
+                LayoutEntry::Padding(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rtc_segments() {
+        let image = SegmentImage(vec![
+            (0x3F40_0020, vec![0x11; 0x100]),
+            (0x400D_0020, vec![0x22; 0x100]),
+            (0x4009_FF00, vec![0x33; 0x100]),
+            (0x400C_0000, vec![0x44; 0x80]),
+            (0x5000_0000, vec![0x55; 0x40]),
+        ]);
+        assert_eq!(image.rtc_segments(Chip::Esp32).count(), 2);
+        assert_eq!(image.ram_segments(Chip::Esp32).count(), 3);
+
+        // Only the IRAM segment is split up to pad the flash segments, and the RTC
+        // segments are emitted whole
+        let segments = layout_segments(&image, Chip::Esp32);
+        assert!(segments.contains(&(0x400C_0000, 0x80)));
+        assert!(segments.contains(&(0x5000_0000, 0x40)));
+        assert_eq!(
+            segments
+                .iter()
+                .filter(|(addr, _)| (0x4009_FF00..0x400A_0000).contains(addr))
+                .map(|(_, size)| size)
+                .sum::<u32>(),
+            0x100
+        );
+
+        // On the ESP32-S2 the end of IRAM is directly followed by RTC fast memory,
+        // so the two must not be merged into a single segment
+        let image = SegmentImage(vec![
+            (0x4006_FF00, vec![0x33; 0x100]),
+            (0x4007_0000, vec![0x44; 0x80]),
+        ]);
+        assert_eq!(
+            layout_segments(&image, Chip::Esp32s2),
+            [(0x4006_FF00, 0x100), (0x4007_0000, 0x80)]
+        );
+    }
+
+    #[test]
+    fn test_merge_adjacent_segments() {
+        // Only sections which follow each other in the ELF file are merged, as
+        // esptool does: the vectors are adjacent to the second IRAM section in
+        // memory, but not in the file
+        let image = SegmentImage(vec![
+            (0x4008_0000, vec![0x11; 0x400]),
+            (0x3FFB_0000, vec![0x22; 0x10]),
+            (0x3FFB_0010, vec![0x33; 0x10]),
+            (0x4008_0400, vec![0x44; 0x100]),
+        ]);
+        let segments = merge_adjacent_segments(Chip::Esp32, image.segments());
+        assert_eq!(
+            segments
+                .iter()
+                .map(|segment| (segment.addr, segment.size()))
+                .collect::<Vec<_>>(),
+            [
+                (0x4008_0000, 0x400),
+                (0x3FFB_0000, 0x20),
+                (0x4008_0400, 0x100)
+            ]
+        );
+    }
 }
@@ -0,0 +1,319 @@
+use std::mem::size_of;
+
+use bytemuck::pod_read_unaligned;
+use sha2::{Digest, Sha256};
+use strum::IntoEnumIterator;
+
+use super::{
+    esp8266, idf_bootloader, update_checksum, EspCommonHeader, ExtendedHeader, SegmentHeader,
+    ESP_CHECKSUM_MAGIC, ESP_MAGIC,
+};
+use crate::{
+    error::Error,
+    flasher::{FlashFrequency, FlashMode, FlashSize},
+    targets::Chip,
+};
+
+const DIGEST_LEN: usize = 32;
+
+/// Information about an application image, parsed from its binary form
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    /// The chip which the image was built for
+    pub chip: Chip,
+    /// The entry point address
+    pub entry: u32,
+    /// The flash mode, if it is a known value
+    pub flash_mode: Option<FlashMode>,
+    /// The flash size, if it is a known value for the chip
+    pub flash_size: Option<FlashSize>,
+    /// The flash frequency, if it is a known value for the chip
+    pub flash_freq: Option<FlashFrequency>,
+    /// The write-protect pin, if the image has an extended header
+    pub wp_pin: Option<u8>,
+    /// The chip ID, if the image has an extended header
+    pub chip_id: Option<u16>,
+    /// The minimum chip revision, if the image has an extended header
+    pub min_rev: Option<u8>,
+    /// The minimum chip revision in `major * 100 + minor` form, if the image
+    /// has an extended header
+    pub min_chip_rev_full: Option<u16>,
+    /// The maximum chip revision in `major * 100 + minor` form, if the image
+    /// has an extended header
+    pub max_chip_rev_full: Option<u16>,
+    /// All segments contained in the image, in the order they appear
+    pub segments: Vec<SegmentInfo>,
+    /// The checksum stored in the image
+    pub checksum: u8,
+    /// The checksum calculated from the image's segments
+    pub calculated_checksum: u8,
+    /// The length of the image up to and including its checksum, which is
+    /// where any digest is appended
+    pub data_length: usize,
+    /// The SHA-256 digest appended to the image, if any
+    pub digest: Option<Vec<u8>>,
+    /// The SHA-256 digest calculated from the image's contents, if the image
+    /// has a digest appended
+    pub calculated_digest: Option<Vec<u8>>,
+}
+
+/// Information about a single segment of an application image
+#[derive(Debug, Clone)]
+pub struct SegmentInfo {
+    /// The address the segment is loaded to
+    pub addr: u32,
+    /// The length of the segment's data, in bytes
+    pub length: u32,
+    /// The offset of the segment's data within the image
+    pub file_offset: usize,
+    /// The names of the memory regions containing the segment's address
+    pub regions: Vec<&'static str>,
+}
+
+impl ImageInfo {
+    /// Parse the provided ESP application image
+    ///
+    /// If no `chip` is provided it will be determined from the extended header;
+    /// since ESP8266 images do not have one, it must always be specified for
+    /// that chip.
+    pub fn parse(data: &[u8], chip: Option<Chip>) -> Result<Self, Error> {
+        let header: EspCommonHeader = read(data, 0, "image header")?;
+        if header.magic != ESP_MAGIC {
+            return Err(Error::InvalidAppImage);
+        }
+
+        let mut offset = size_of::<EspCommonHeader>();
+
+        let extended_header: Option<ExtendedHeader> = if chip == Some(Chip::Esp8266) {
+            None
+        } else {
+            let extended_header = read(data, offset, "extended header")?;
+            offset += size_of::<ExtendedHeader>();
+
+            Some(extended_header)
+        };
+
+        let chip = match (chip, extended_header) {
+            (Some(chip), _) => chip,
+            (None, extended_header) => extended_header
+                .and_then(|header| Chip::from_chip_id(header.chip_id as u32))
+                .ok_or(Error::MalformedImage("unknown chip ID in extended header"))?,
+        };
+
+        let mut segments = Vec::with_capacity(header.segment_count as usize);
+        let mut calculated_checksum = ESP_CHECKSUM_MAGIC;
+
+        for _ in 0..header.segment_count {
+            let segment_header: SegmentHeader = read(data, offset, "segment header")?;
+            offset += size_of::<SegmentHeader>();
+
+            let length = segment_header.length as usize;
+            let segment_data = data
+                .get(offset..offset + length)
+                .ok_or(Error::MalformedImage("segment data is truncated"))?;
+            calculated_checksum = update_checksum(segment_data, calculated_checksum);
+
+            segments.push(SegmentInfo {
+                addr: segment_header.addr,
+                length: segment_header.length,
+                file_offset: offset,
+                regions: memory_regions(chip, segment_header.addr),
+            });
+
+            offset += length;
+        }
+
+        // The checksum is stored in the final byte of the padding, which aligns the
+        // image to 16 bytes
+        offset += 15 - (offset % 16);
+        let checksum = *data
+            .get(offset)
+            .ok_or(Error::MalformedImage("checksum is missing"))?;
+        offset += 1;
+
+        let (digest, calculated_digest) = match extended_header {
+            Some(extended_header) if extended_header.append_digest == 1 => {
+                let digest = data
+                    .get(offset..offset + DIGEST_LEN)
+                    .ok_or(Error::MalformedImage("appended digest is missing"))?;
+
+                let mut hasher = Sha256::new();
+                hasher.update(&data[..offset]);
+
+                (Some(digest.to_vec()), Some(hasher.finalize().to_vec()))
+            }
+            _ => (None, None),
+        };
+
+        Ok(Self {
+            chip,
+            entry: header.entry,
+            flash_mode: decode_flash_mode(header.flash_mode),
+            flash_size: decode_flash_size(chip, header.flash_config & 0xF0),
+            flash_freq: decode_flash_frequency(chip, header.flash_config & 0x0F),
+            wp_pin: extended_header.map(|h| h.wp_pin),
+            chip_id: extended_header.map(|h| h.chip_id),
+            min_rev: extended_header.map(|h| h.min_rev),
+            min_chip_rev_full: extended_header.map(|h| h.min_chip_rev_full),
+            max_chip_rev_full: extended_header.map(|h| h.max_chip_rev_full),
+            segments,
+            checksum,
+            calculated_checksum,
+            data_length: offset,
+            digest,
+            calculated_digest,
+        })
+    }
+
+    /// Does the stored checksum match the calculated one?
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.calculated_checksum
+    }
+
+    /// Does the appended digest match the calculated one?
+    ///
+    /// Returns `None` if the image has no digest appended.
+    pub fn digest_valid(&self) -> Option<bool> {
+        self.digest
+            .as_ref()
+            .map(|digest| Some(digest) == self.calculated_digest.as_ref())
+    }
+}
+
+/// The number of bytes at the start of an image which hold its common and
+/// extended headers
+pub(crate) const HEADERS_LEN: usize = size_of::<EspCommonHeader>() + size_of::<ExtendedHeader>();
+
+/// Read the minimum chip revision, in `major * 100 + minor` form, from the
+/// headers at the start of an image, without parsing the rest of it
+pub(crate) fn min_chip_rev_full(data: &[u8], chip: Chip) -> Option<u16> {
+    if chip == Chip::Esp8266 || data.first() != Some(&ESP_MAGIC) {
+        return None;
+    }
+
+    let extended_header: ExtendedHeader =
+        read(data, size_of::<EspCommonHeader>(), "extended header").ok()?;
+
+    Some(extended_header.min_chip_rev_full)
+}
+
+fn read<T: bytemuck::Pod>(data: &[u8], offset: usize, what: &'static str) -> Result<T, Error> {
+    data.get(offset..offset + size_of::<T>())
+        .map(pod_read_unaligned)
+        .ok_or(Error::MalformedImage(what))
+}
+
+fn decode_flash_mode(mode: u8) -> Option<FlashMode> {
+    match mode {
+        0 => Some(FlashMode::Qio),
+        1 => Some(FlashMode::Qout),
+        2 => Some(FlashMode::Dio),
+        3 => Some(FlashMode::Dout),
+        _ => None,
+    }
+}
+
+fn decode_flash_size(chip: Chip, encoded: u8) -> Option<FlashSize> {
+    FlashSize::iter().find(|&size| {
+        let encoding = match chip {
+            Chip::Esp8266 => esp8266::encode_flash_size(size),
+            _ => idf_bootloader::encode_flash_size(size),
+        };
+
+        encoding.ok() == Some(encoded)
+    })
+}
+
+/// Where several frequencies share an encoding, such as 40 and 80 MHz on the
+/// ESP32-C6, the highest of them is returned
+fn decode_flash_frequency(chip: Chip, encoded: u8) -> Option<FlashFrequency> {
+    chip.into_target()
+        .flash_frequency_encodings()
+        .into_iter()
+        .filter(|(_, encoding)| *encoding == encoded)
+        .map(|(frequency, _)| frequency)
+        .max_by_key(|frequency| *frequency as u8)
+}
+
+fn memory_regions(chip: Chip, addr: u32) -> Vec<&'static str> {
+    chip.memory_regions()
+        .iter()
+        .filter(|region| region.contains(addr))
+        .map(|region| region.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_image_info() {
+        let data = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let info = ImageInfo::parse(&data, None).unwrap();
+
+        assert_eq!(info.chip, Chip::Esp32);
+        assert!(!info.segments.is_empty());
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(true));
+    }
+
+    #[test]
+    fn test_decode_flash_frequency() {
+        for chip in Chip::iter() {
+            let encodings = chip.into_target().flash_frequency_encodings();
+            for encoding in encodings.values() {
+                let frequency = decode_flash_frequency(chip, *encoding).unwrap();
+                assert_eq!(encodings[&frequency], *encoding);
+            }
+        }
+
+        // The encodings used by esptool's `FLASH_FREQUENCY` tables
+        use FlashFrequency::*;
+        let esptool = [
+            (
+                Chip::Esp32,
+                &[(_80Mhz, 0xF), (_40Mhz, 0x0), (_26Mhz, 0x1), (_20Mhz, 0x2)][..],
+            ),
+            (
+                Chip::Esp32c2,
+                &[(_60Mhz, 0xF), (_30Mhz, 0x0), (_20Mhz, 0x1), (_15Mhz, 0x2)],
+            ),
+            (
+                Chip::Esp32c6,
+                &[(_80Mhz, 0x0), (_40Mhz, 0x0), (_20Mhz, 0x2)],
+            ),
+            (
+                Chip::Esp32h2,
+                &[(_48Mhz, 0xF), (_24Mhz, 0x0), (_16Mhz, 0x1), (_12Mhz, 0x2)],
+            ),
+        ];
+        for (chip, expected) in esptool {
+            let encodings = chip.into_target().flash_frequency_encodings();
+            assert_eq!(encodings.len(), expected.len());
+            for (frequency, encoding) in expected {
+                assert_eq!(encodings[frequency], *encoding, "{chip} at {frequency}");
+            }
+        }
+
+        assert_eq!(decode_flash_frequency(Chip::Esp32c6, 0x0), Some(_80Mhz));
+        assert_eq!(decode_flash_frequency(Chip::Esp32c6, 0xF), None);
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        for chip in Chip::iter() {
+            assert!(chip.memory_regions().iter().any(|region| region.is_flash()));
+        }
+
+        assert_eq!(memory_regions(Chip::Esp32, 0x400d_0018), ["IROM"]);
+        assert_eq!(memory_regions(Chip::Esp32c6, 0x4080_0000), ["IRAM", "DRAM"]);
+
+        let target = Chip::Esp32.into_target();
+        assert!(target.addr_is_flash(0x3f40_0020));
+        assert!(!target.addr_is_flash(0x4008_0000));
+        assert!(!target.addr_is_flash(0x5000_0000));
+    }
+}
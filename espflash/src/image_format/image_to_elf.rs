@@ -0,0 +1,224 @@
+use std::{io::Write, mem::size_of};
+
+use bytemuck::{bytes_of, Pod, Zeroable};
+
+use super::ImageInfo;
+use crate::{error::Error, targets::Chip};
+
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const SHT_PROGBITS: u32 = 1;
+const SHT_STRTAB: u32 = 3;
+
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+    align: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    addralign: u32,
+    entsize: u32,
+}
+
+/// Reconstruct a loadable ELF file from an application image
+///
+/// Each segment in the image becomes both a `PT_LOAD` program header and a
+/// section, named after the memory region it is loaded into. Padding segments
+/// are omitted. Symbols and debug information can not be recovered.
+pub fn image_to_elf(data: &[u8], chip: Option<Chip>) -> Result<Vec<u8>, Error> {
+    let info = ImageInfo::parse(data, chip)?;
+
+    let machine = match info.chip {
+        Chip::Esp32 | Chip::Esp32s2 | Chip::Esp32s3 | Chip::Esp8266 => EM_XTENSA,
+        _ => EM_RISCV,
+    };
+
+    let segments = info
+        .segments
+        .iter()
+        .filter(|segment| segment.addr != 0)
+        .collect::<Vec<_>>();
+
+    let mut shstrtab = vec![0u8];
+    let mut program_headers = Vec::with_capacity(segments.len());
+    let mut section_headers = vec![SectionHeader::default()];
+
+    let mut offset = (size_of::<ElfHeader>() + segments.len() * size_of::<ProgramHeader>()) as u32;
+
+    for (i, segment) in segments.iter().enumerate() {
+        let executable = segment
+            .regions
+            .iter()
+            .any(|region| region.contains("IRAM") || region.contains("IROM"));
+
+        let name = match segment.regions.first() {
+            Some(region) => format!(".{}.{i}", region.to_lowercase()),
+            None => format!(".segment.{i}"),
+        };
+
+        program_headers.push(ProgramHeader {
+            kind: PT_LOAD,
+            offset,
+            vaddr: segment.addr,
+            paddr: segment.addr,
+            filesz: segment.length,
+            memsz: segment.length,
+            flags: if executable { PF_R | PF_X } else { PF_R | PF_W },
+            align: 4,
+        });
+
+        section_headers.push(SectionHeader {
+            name: shstrtab.len() as u32,
+            kind: SHT_PROGBITS,
+            flags: if executable {
+                SHF_ALLOC | SHF_EXECINSTR
+            } else {
+                SHF_ALLOC | SHF_WRITE
+            },
+            addr: segment.addr,
+            offset,
+            size: segment.length,
+            addralign: 4,
+            ..SectionHeader::default()
+        });
+
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+
+        offset += align4(segment.length);
+    }
+
+    section_headers.push(SectionHeader {
+        name: shstrtab.len() as u32,
+        kind: SHT_STRTAB,
+        offset,
+        size: (shstrtab.len() + b".shstrtab\0".len()) as u32,
+        addralign: 1,
+        ..SectionHeader::default()
+    });
+    shstrtab.extend_from_slice(b".shstrtab\0");
+    offset += align4(shstrtab.len() as u32);
+
+    let mut ident = [0u8; 16];
+    ident[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+
+    let header = ElfHeader {
+        ident,
+        kind: ET_EXEC,
+        machine,
+        version: 1,
+        entry: info.entry,
+        phoff: size_of::<ElfHeader>() as u32,
+        shoff: offset,
+        flags: 0,
+        ehsize: size_of::<ElfHeader>() as u16,
+        phentsize: size_of::<ProgramHeader>() as u16,
+        phnum: program_headers.len() as u16,
+        shentsize: size_of::<SectionHeader>() as u16,
+        shnum: section_headers.len() as u16,
+        shstrndx: (section_headers.len() - 1) as u16,
+    };
+
+    let mut elf =
+        Vec::with_capacity(offset as usize + section_headers.len() * size_of::<SectionHeader>());
+    elf.write_all(bytes_of(&header))?;
+
+    for program_header in &program_headers {
+        elf.write_all(bytes_of(program_header))?;
+    }
+
+    for segment in &segments {
+        let start = segment.file_offset;
+        let end = start + segment.length as usize;
+
+        elf.write_all(&data[start..end])?;
+        elf.resize(elf.len() + padding4(segment.length), 0);
+    }
+
+    elf.write_all(&shstrtab)?;
+    elf.resize(elf.len() + padding4(shstrtab.len() as u32), 0);
+
+    for section_header in &section_headers {
+        elf.write_all(bytes_of(section_header))?;
+    }
+
+    Ok(elf)
+}
+
+fn padding4(len: u32) -> usize {
+    ((4 - len % 4) % 4) as usize
+}
+
+fn align4(len: u32) -> u32 {
+    len + padding4(len) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::elf::{ElfFirmwareImage, FirmwareImage};
+
+    #[test]
+    fn test_image_to_elf() {
+        let data = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let elf_data = image_to_elf(&data, None).unwrap();
+
+        let info = ImageInfo::parse(&data, None).unwrap();
+        let image = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+
+        assert_eq!(image.entry(), info.entry);
+        assert_eq!(
+            image.segments().count(),
+            info.segments.iter().filter(|s| s.addr != 0).count()
+        );
+    }
+}
@@ -5,20 +5,46 @@
 //! Certain devices additionall support direct boot, which needs its own unique
 //! image format.
 
-use std::str::FromStr;
+use std::{
+    fmt::{Display, Formatter},
+    io::Write,
+    str::FromStr,
+};
 
 use bytemuck::{Pod, Zeroable};
-use serde::Deserialize;
-use strum::{Display, EnumVariantNames, IntoStaticStr};
+use serde::{de, Deserialize, Deserializer};
 
+use self::registry::find_image_format;
 pub use self::{
-    direct_boot::DirectBootFormat, esp8266::Esp8266Format, idf_bootloader::IdfBootloaderFormat,
+    direct_boot::DirectBootFormat,
+    esp8266::{Esp8266Format, ImageVersion},
+    idf_bootloader::{update_flash_params, IdfBootloaderFormat},
+    image_info::{ImageInfo, SegmentInfo},
+    image_to_elf::image_to_elf,
+    registry::{register_image_format, unregister_image_format, ImageFormatFactory},
+    size_report::{RegionUsage, SectionUsage, SizeReport},
+};
+pub(crate) use self::{
+    image_info::{min_chip_rev_full, HEADERS_LEN},
+    registry::{build_custom_image, registered_image_formats},
+};
+use crate::{
+    elf::{CodeSegment, RomSegment},
+    error::Error,
+    flasher::FlashFrequency,
+    targets::Chip,
 };
-use crate::{elf::RomSegment, error::Error, flasher::FlashFrequency, targets::Chip};
 
 mod direct_boot;
 mod esp8266;
 mod idf_bootloader;
+mod image_info;
+mod image_to_elf;
+mod registry;
+mod size_report;
+
+#[cfg(test)]
+mod golden;
 
 const ESP_CHECKSUM_MAGIC: u8 = 0xef;
 const ESP_MAGIC: u8 = 0xE9;
@@ -41,10 +67,28 @@ struct SegmentHeader {
     length: u32,
 }
 
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct ExtendedHeader {
+    wp_pin: u8,
+    clk_q_drv: u8,
+    d_cs_drv: u8,
+    gd_wp_drv: u8,
+    chip_id: u16,
+    min_rev: u8,
+    min_chip_rev_full: u16,
+    max_chip_rev_full: u16,
+    padding: [u8; 4],
+    append_digest: u8,
+}
+
 /// Operations for working with firmware image formats
 pub trait ImageFormat<'a>: Send {
     /// Get the rom segments needed when flashing to device
-    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    ///
+    /// Segments may be generated as the iterator is advanced, which fails if
+    /// their data can't be generated.
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b;
 
@@ -52,31 +96,100 @@ pub trait ImageFormat<'a>: Send {
     ///
     /// Compared to `flash_segments` this excludes things like bootloader and
     /// partition table
-    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = Result<RomSegment<'b>, Error>> + 'b>
     where
         'a: 'b;
 
+    /// The second-stage bootloader, if the format includes one
+    fn bootloader_segment<'b>(&'b self) -> Option<RomSegment<'b>>
+    where
+        'a: 'b,
+    {
+        None
+    }
+
+    /// The partition table, if the format includes one
+    fn partition_table_segment<'b>(&'b self) -> Option<RomSegment<'b>>
+    where
+        'a: 'b,
+    {
+        None
+    }
+
     /// The size of the application binary
     fn app_size(&self) -> u32;
 
     /// If applicable, the size of the application partition (if it can be
     /// determined)
     fn part_size(&self) -> Option<u32>;
+
+    /// Write the data of the OTA segments to `writer`, one after another
+    ///
+    /// Formats which generate the application image as it is written override
+    /// this, so that it is never held in memory in full.
+    fn write_to(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        for segment in self.ota_segments() {
+            writer.write_all(&segment?.data)?;
+        }
+
+        Ok(())
+    }
+
+    /// The flash address of the application image generated by `write_to`,
+    /// if it is a single part of `app_size` bytes
+    ///
+    /// Formats which override `write_to` return the address, so that the
+    /// application is flashed as it is generated rather than from the OTA
+    /// segments.
+    fn app_addr(&self) -> Option<u32> {
+        None
+    }
 }
 
 /// All supported firmware image formats
-#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
-#[derive(
-    Debug, Copy, Clone, Eq, PartialEq, Display, IntoStaticStr, EnumVariantNames, Deserialize,
-)]
+///
+/// In addition to the built-in formats, custom formats can be made available
+/// using [register_image_format].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[non_exhaustive]
-#[strum(serialize_all = "kebab-case")]
-#[serde(rename_all = "kebab-case")]
 pub enum ImageFormatKind {
     /// Use the second-stage bootloader from ESP-IDF
     EspBootloader,
     /// Use direct boot and do not use a second-stage bootloader at all
     DirectBoot,
+    /// A custom image format, registered using [register_image_format]
+    Custom(&'static str),
+}
+
+impl ImageFormatKind {
+    /// The image formats which are built into espflash
+    pub const BUILTIN: &'static [ImageFormatKind] =
+        &[ImageFormatKind::EspBootloader, ImageFormatKind::DirectBoot];
+
+    /// The name of the image format
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormatKind::EspBootloader => "esp-bootloader",
+            ImageFormatKind::DirectBoot => "direct-boot",
+            ImageFormatKind::Custom(name) => name,
+        }
+    }
+
+    /// The names of all available image formats, including any custom formats
+    /// which have been registered
+    pub fn names() -> Vec<&'static str> {
+        Self::BUILTIN
+            .iter()
+            .map(ImageFormatKind::as_str)
+            .chain(registered_image_formats().iter().map(|f| f.name()))
+            .collect()
+    }
+}
+
+impl Display for ImageFormatKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl FromStr for ImageFormatKind {
@@ -86,14 +199,38 @@ impl FromStr for ImageFormatKind {
         match s {
             "esp-bootloader" => Ok(Self::EspBootloader),
             "direct-boot" => Ok(Self::DirectBoot),
-            _ => Err(Error::UnknownImageFormat(s.into())),
+            _ => find_image_format(s)
+                .map(|factory| Self::Custom(factory.name()))
+                .ok_or_else(|| Error::UnknownImageFormat(s.into())),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for ImageFormatKind {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+
+        Self::from_str(&name).map_err(de::Error::custom)
+    }
+}
+
+/// Does the provided data look like an application image which has already
+/// been converted to the ESP binary image format?
+///
+/// ELF files begin with the `0x7F 'E' 'L' 'F'` magic, so the two can be told
+/// apart by inspecting the first byte.
+pub fn is_app_image(data: &[u8]) -> bool {
+    data.first() == Some(&ESP_MAGIC)
+}
+
 fn encode_flash_frequency(chip: Chip, frequency: FlashFrequency) -> Result<u8, Error> {
+    chip.check_flash_frequency(frequency)?;
+
     let encodings = chip.into_target().flash_frequency_encodings();
-    if let Some(&f) = encodings.get(&frequency) {
+    if let Some(&f) = encodings.get(&frequency.header_frequency()) {
         Ok(f)
     } else {
         Err(Error::UnsupportedFlashFrequency { chip, frequency })
@@ -107,3 +244,34 @@ fn update_checksum(data: &[u8], mut checksum: u8) -> u8 {
 
     checksum
 }
+
+/// Merge each segment into the one before it in the ELF file, where it carries
+/// on from it in the same memory regions
+///
+/// As with esptool, the segments are not sorted first, so sections which are
+/// adjacent in memory but not in the ELF file are loaded as separate segments.
+fn merge_adjacent_segments<'a>(
+    chip: Chip,
+    segments: impl Iterator<Item = CodeSegment<'a>>,
+) -> Vec<CodeSegment<'a>> {
+    let regions = |addr| {
+        chip.memory_regions()
+            .iter()
+            .filter(move |region| region.contains(addr))
+    };
+
+    let mut merged: Vec<CodeSegment> = Vec::new();
+    for segment in segments {
+        match merged.last_mut() {
+            Some(last)
+                if last.addr + last.size() == segment.addr
+                    && regions(last.addr).eq(regions(segment.addr)) =>
+            {
+                *last += segment.data();
+            }
+            _ => merged.push(segment),
+        }
+    }
+
+    merged
+}
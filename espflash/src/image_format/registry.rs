@@ -0,0 +1,200 @@
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{ImageFormat, ImageFormatKind};
+use crate::{
+    elf::FirmwareImage,
+    error::{Error, UnsupportedImageFormatError},
+    flasher::FlashData,
+    targets::Chip,
+};
+
+type Registry = Vec<Arc<dyn ImageFormatFactory>>;
+
+static REGISTRY: RwLock<Registry> = RwLock::new(Vec::new());
+
+// A panic while the lock is held can't leave the registry partially modified,
+// as it is only ever changed by a single push or retain, so a poisoned lock is
+// recovered from rather than propagating the panic.
+fn registry() -> RwLockReadGuard<'static, Registry> {
+    REGISTRY.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn registry_mut() -> RwLockWriteGuard<'static, Registry> {
+    REGISTRY.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A factory for building images in a custom format
+///
+/// Once registered using [register_image_format], the format can be selected
+/// by name anywhere an [ImageFormatKind] is accepted, including on the
+/// command-line.
+pub trait ImageFormatFactory: Send + Sync {
+    /// The name used to select this format
+    fn name(&self) -> &'static str;
+
+    /// Is this format supported by the chip `chip`?
+    fn supports_chip(&self, _chip: Chip) -> bool {
+        true
+    }
+
+    /// Build an image in this format from the provided firmware image
+    fn build<'a>(
+        &self,
+        image: &'a dyn FirmwareImage<'a>,
+        chip: Chip,
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error>;
+}
+
+/// Register a custom image format
+///
+/// Returns an error if a format with the same name is already built-in or has
+/// already been registered.
+pub fn register_image_format<F>(factory: F) -> Result<(), Error>
+where
+    F: ImageFormatFactory + 'static,
+{
+    let name = factory.name();
+
+    // The lock is held across the check, so that concurrent registrations of
+    // the same name can't both succeed
+    let mut registry = registry_mut();
+    if ImageFormatKind::BUILTIN
+        .iter()
+        .any(|format| format.as_str() == name)
+        || registry.iter().any(|factory| factory.name() == name)
+    {
+        return Err(Error::DuplicateImageFormat(name.to_string()));
+    }
+
+    registry.push(Arc::new(factory));
+
+    Ok(())
+}
+
+/// Unregister a custom image format
+///
+/// Returns `true` if a format named `name` was registered.
+pub fn unregister_image_format(name: &str) -> bool {
+    let mut registry = registry_mut();
+    let len = registry.len();
+    registry.retain(|factory| factory.name() != name);
+
+    registry.len() != len
+}
+
+/// Look up a registered image format by name
+pub(crate) fn find_image_format(name: &str) -> Option<Arc<dyn ImageFormatFactory>> {
+    registry()
+        .iter()
+        .find(|factory| factory.name() == name)
+        .cloned()
+}
+
+/// All registered image formats
+pub(crate) fn registered_image_formats() -> Vec<Arc<dyn ImageFormatFactory>> {
+    registry().clone()
+}
+
+/// Build an image using the registered image format named `name`
+pub(crate) fn build_custom_image<'a>(
+    name: &str,
+    image: &'a dyn FirmwareImage<'a>,
+    chip: Chip,
+    flash_data: FlashData,
+) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+    let factory =
+        find_image_format(name).ok_or_else(|| Error::UnknownImageFormat(name.to_string()))?;
+
+    if !factory.supports_chip(chip) {
+        let format = ImageFormatKind::Custom(factory.name());
+        return Err(UnsupportedImageFormatError::new(format, chip, None).into());
+    }
+
+    factory.build(image, chip, flash_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr, thread};
+
+    use super::*;
+    use crate::{elf::ElfFirmwareImage, image_format::DirectBootFormat};
+
+    struct TestFormat;
+
+    struct ConcurrentFormat;
+
+    impl ImageFormatFactory for ConcurrentFormat {
+        fn name(&self) -> &'static str {
+            "concurrent-format"
+        }
+
+        fn build<'a>(
+            &self,
+            image: &'a dyn FirmwareImage<'a>,
+            _chip: Chip,
+            _flash_data: FlashData,
+        ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+            Ok(Box::new(DirectBootFormat::new(image, 0)?))
+        }
+    }
+
+    impl ImageFormatFactory for TestFormat {
+        fn name(&self) -> &'static str {
+            "test-format"
+        }
+
+        fn supports_chip(&self, chip: Chip) -> bool {
+            chip == Chip::Esp32c3
+        }
+
+        fn build<'a>(
+            &self,
+            image: &'a dyn FirmwareImage<'a>,
+            _chip: Chip,
+            _flash_data: FlashData,
+        ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+            Ok(Box::new(DirectBootFormat::new(image, 0)?))
+        }
+    }
+
+    #[test]
+    fn test_register_image_format() {
+        register_image_format(TestFormat).unwrap();
+
+        assert_eq!(
+            ImageFormatKind::from_str("test-format").unwrap(),
+            ImageFormatKind::Custom("test-format")
+        );
+        assert!(ImageFormatKind::names().contains(&"test-format"));
+        assert!(register_image_format(TestFormat).is_err());
+
+        let elf_data = fs::read("tests/resources/esp32c3_hal_blinky_db").unwrap();
+        let image = ElfFirmwareImage::try_from(elf_data.as_slice()).unwrap();
+        assert!(
+            build_custom_image("test-format", &image, Chip::Esp32c3, FlashData::default()).is_ok()
+        );
+        assert!(
+            build_custom_image("test-format", &image, Chip::Esp32, FlashData::default()).is_err()
+        );
+
+        assert!(unregister_image_format("test-format"));
+        assert!(!unregister_image_format("test-format"));
+        assert!(ImageFormatKind::from_str("test-format").is_err());
+    }
+
+    #[test]
+    fn test_concurrent_registration() {
+        let registered = (0..8)
+            .map(|_| thread::spawn(|| register_image_format(ConcurrentFormat).is_ok()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&ok| ok)
+            .count();
+        assert_eq!(registered, 1);
+
+        assert!(unregister_image_format("concurrent-format"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::ops::Range;
+
+use serde::{Serialize, Serializer};
+
+use crate::{elf::ElfFirmwareImage, error::Error, flasher::FlashData, targets::Chip};
+
+/// Memory usage of an application, computed from its ELF file
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeReport {
+    /// The chip which the application was built for
+    #[serde(serialize_with = "serialize_display")]
+    pub chip: Chip,
+    /// Usage of each of the chip's memory regions
+    pub regions: Vec<RegionUsage>,
+    /// All sections which occupy memory, in the order they appear in the ELF
+    pub sections: Vec<SectionUsage>,
+    /// Size of the application image, in bytes
+    pub app_size: u32,
+    /// Size of the partition the application image is written to, if any
+    pub part_size: Option<u32>,
+}
+
+/// Usage of one of a chip's memory regions
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionUsage {
+    /// Name of the region; regions sharing an address range are combined, eg.)
+    /// `IRAM/DRAM`
+    pub name: String,
+    /// Address at which the region begins
+    pub start: u32,
+    /// Size of the region, in bytes
+    pub size: u32,
+    /// Bytes of the region occupied by the application
+    pub used: u32,
+}
+
+/// A section which occupies memory
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionUsage {
+    /// Name of the section
+    pub name: String,
+    /// Address of the section
+    pub addr: u32,
+    /// Size of the section, in bytes
+    pub size: u32,
+    /// Name of the region containing the section, if any
+    pub region: Option<String>,
+}
+
+impl SizeReport {
+    /// Compute the memory usage of the application in `elf_data`
+    ///
+    /// The application image is built using `flash_data`, in order to
+    /// determine how full the partition it is written to would be.
+    pub fn new(elf_data: &[u8], chip: Chip, flash_data: FlashData) -> Result<Self, Error> {
+        let image = ElfFirmwareImage::try_from(elf_data)?;
+
+        let mut regions: Vec<(Range<u32>, RegionUsage)> = Vec::new();
+        for region in chip.memory_regions() {
+            match regions.iter_mut().find(|(range, _)| *range == region.range) {
+                Some((_, usage)) => usage.name = format!("{}/{}", usage.name, region.name),
+                None => regions.push((
+                    region.range.clone(),
+                    RegionUsage {
+                        name: region.name.to_string(),
+                        start: region.range.start,
+                        size: region.size(),
+                        used: 0,
+                    },
+                )),
+            }
+        }
+
+        let mut sections = Vec::new();
+        for section in image.allocated_sections() {
+            let region = regions
+                .iter_mut()
+                .find(|(range, _)| range.contains(&section.addr))
+                .map(|(_, region)| {
+                    region.used += section.size;
+                    region.name.clone()
+                });
+
+            sections.push(SectionUsage {
+                name: section.name.to_string(),
+                addr: section.addr,
+                size: section.size,
+                region,
+            });
+        }
+
+        let flash_image = chip
+            .into_target()
+            .get_flash_image(&image, flash_data, None, None)?;
+
+        Ok(Self {
+            chip,
+            regions: regions.into_iter().map(|(_, region)| region).collect(),
+            sections,
+            app_size: flash_image.app_size(),
+            part_size: flash_image.part_size(),
+        })
+    }
+}
+
+fn serialize_display<S: Serializer>(chip: &Chip, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(chip)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_size_report() {
+        let elf_data = fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let report = SizeReport::new(&elf_data, Chip::Esp32, FlashData::default()).unwrap();
+
+        let used = |name: &str| {
+            report
+                .regions
+                .iter()
+                .find(|region| region.name == name)
+                .unwrap()
+                .used
+        };
+        assert!(used("IROM") > 0);
+        assert!(used("DRAM") > 0);
+
+        // Every section within a region is counted towards it exactly once
+        let total: u32 = report.regions.iter().map(|region| region.used).sum();
+        let in_regions: u32 = report
+            .sections
+            .iter()
+            .filter(|section| section.region.is_some())
+            .map(|section| section.size)
+            .sum();
+        assert_eq!(total, in_regions);
+
+        assert_eq!(report.app_size, 84960);
+        assert!(report.part_size.is_some());
+    }
+}
@@ -0,0 +1,497 @@
+//! Transport which replays canned responses, for testing without hardware
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::Transport;
+use crate::command::CommandType;
+
+// SLIP special characters
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+#[derive(Debug)]
+struct MockState {
+    responses: VecDeque<u8>,
+    written: Vec<u8>,
+    baud_rate: u32,
+    timeout: Duration,
+    dtr: bool,
+    rts: bool,
+}
+
+/// A [Transport] which replays canned responses
+///
+/// Clones share the same state, so a clone can be kept to queue responses and
+/// inspect written data after the original has been handed to an
+/// [Interface](super::Interface).
+///
+/// Responses are queued before the commands which elicit them are sent, and
+/// the commands which were written can be inspected afterwards:
+///
+/// ```
+/// use espflash::{
+///     command::CommandType,
+///     connection::Connection,
+///     interface::{Interface, MockTransport},
+/// };
+/// use serialport::UsbPortInfo;
+///
+/// let mock = MockTransport::new();
+/// mock.push_response(CommandType::ReadReg, 0xdead_beef, &[]);
+///
+/// let port_info = UsbPortInfo {
+///     vid: 0,
+///     pid: 0,
+///     serial_number: None,
+///     manufacturer: None,
+///     product: None,
+/// };
+/// let mut connection = Connection::new(Interface::from_transport(mock.clone()), port_info);
+/// assert_eq!(connection.read_reg(0x4000_1000).unwrap(), 0xdead_beef);
+///
+/// let (op, data) = &mock.written_commands()[0];
+/// assert_eq!(*op, CommandType::ReadReg as u8);
+/// assert_eq!(data, &0x4000_1000u32.to_le_bytes());
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockTransport {
+    /// Create a transport with no queued responses
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                responses: VecDeque::new(),
+                written: Vec::new(),
+                baud_rate: 115_200,
+                timeout: Duration::ZERO,
+                dtr: false,
+                rts: false,
+            })),
+        }
+    }
+
+    /// Queue a raw SLIP frame containing `data`
+    pub fn push_frame(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.responses.push_back(END);
+        for &byte in data {
+            match byte {
+                END => state.responses.extend([ESC, ESC_END]),
+                ESC => state.responses.extend([ESC, ESC_ESC]),
+                _ => state.responses.push_back(byte),
+            }
+        }
+        state.responses.push_back(END);
+    }
+
+    /// Queue bytes which are not framed, such as the ROM's boot messages
+    pub fn push_raw(&self, data: &[u8]) {
+        self.state.lock().unwrap().responses.extend(data);
+    }
+
+    /// Queue a successful response to `command`, containing `value` and `data`
+    ///
+    /// The data is followed by the status bytes, as sent by the ROM
+    /// bootloader.
+    pub fn push_response(&self, command: CommandType, value: u32, data: &[u8]) {
+        self.push_status(command, value, data, 0, 0);
+    }
+
+    /// Queue a failed response to `command`, with the error code `error`
+    pub fn push_error(&self, command: CommandType, error: u8) {
+        self.push_status(command, 0, &[], 1, error);
+    }
+
+    fn push_status(&self, command: CommandType, value: u32, data: &[u8], status: u8, error: u8) {
+        let mut frame = vec![1, command as u8];
+        frame.extend(((data.len() + 2) as u16).to_le_bytes());
+        frame.extend(value.to_le_bytes());
+        frame.extend(data);
+        frame.extend([status, error]);
+
+        self.push_frame(&frame);
+    }
+
+    /// All bytes written so far
+    pub fn written(&self) -> Vec<u8> {
+        self.state.lock().unwrap().written.clone()
+    }
+
+    /// The decoded SLIP frames written so far
+    pub fn written_frames(&self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut frame = Vec::new();
+        let mut escaped = false;
+
+        for byte in self.written() {
+            match (escaped, byte) {
+                (false, END) if !frame.is_empty() => frames.push(std::mem::take(&mut frame)),
+                (false, END) => {}
+                (false, ESC) => escaped = true,
+                (false, _) => frame.push(byte),
+                (true, ESC_END) => {
+                    frame.push(END);
+                    escaped = false;
+                }
+                (true, _) => {
+                    frame.push(ESC);
+                    escaped = false;
+                }
+            }
+        }
+
+        frames
+    }
+
+    /// The opcode and data of each command written so far
+    pub fn written_commands(&self) -> Vec<(u8, Vec<u8>)> {
+        self.written_frames()
+            .into_iter()
+            .filter(|frame| frame.len() >= 8)
+            .map(|frame| (frame[1], frame[8..].to_vec()))
+            .collect()
+    }
+
+    /// Discard all bytes written so far
+    pub fn clear_written(&self) {
+        self.state.lock().unwrap().written.clear();
+    }
+
+    /// Have all queued responses been read?
+    pub fn is_exhausted(&self) -> bool {
+        self.state.lock().unwrap().responses.is_empty()
+    }
+
+    /// The current state of the DTR and RTS control lines
+    pub fn control_lines(&self) -> (bool, bool) {
+        let state = self.state.lock().unwrap();
+        (state.dtr, state.rts)
+    }
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.responses.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        let count = buf.len().min(state.responses.len());
+        for (dst, src) in buf.iter_mut().zip(state.responses.drain(..count)) {
+            *dst = src;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.lock().unwrap().written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockTransport {
+    fn timeout(&self) -> Duration {
+        self.state.lock().unwrap().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.state.lock().unwrap().timeout = timeout;
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.state.lock().unwrap().baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.state.lock().unwrap().baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.state.lock().unwrap().dtr = level;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.state.lock().unwrap().rts = level;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+
+    use super::*;
+    use crate::{
+        command::Command,
+        connection::{Connection, ResetSequence, RetryPolicy},
+        elf::RomSegment,
+        error::{ConnectionError, Error},
+        flasher::{SpiAttachParams, FLASH_WRITE_SIZE},
+        interface::Interface,
+        targets::{Chip, Esp32Target, Esp8266Target, FlashTarget},
+    };
+
+    fn connection(mock: &MockTransport) -> Connection {
+        let port_info = UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+
+        Connection::new(Interface::from_transport(mock.clone()), port_info)
+    }
+
+    fn words(data: &[u8]) -> Vec<u32> {
+        data.chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sync() {
+        let mock = MockTransport::new();
+        // The ROM responds to each sync command eight times
+        for _ in 0..8 {
+            mock.push_response(CommandType::Sync, 0, &[]);
+        }
+
+        let mut connection = connection(&mock);
+        connection.sync().unwrap();
+
+        let commands = mock.written_commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].0, CommandType::Sync as u8);
+        assert_eq!(&commands[0].1[..4], &[0x07, 0x07, 0x12, 0x20]);
+        assert!(mock.is_exhausted());
+    }
+
+    #[test]
+    fn test_sync_without_response() {
+        let mock = MockTransport::new();
+
+        let mut connection = connection(&mock);
+        assert!(connection.sync().is_err());
+    }
+
+    #[test]
+    fn test_capture_rom_log() {
+        let mock = MockTransport::new();
+        mock.push_raw(b"rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n");
+
+        let mut connection = connection(&mock);
+        connection.set_reset_sequence("W0".parse::<ResetSequence>().unwrap());
+        connection.set_retry_policy(RetryPolicy::default().connect_attempts(1).sync_attempts(1));
+        connection.set_capture_rom_log(true);
+
+        match connection.begin() {
+            Err(Error::Connection(ConnectionError::ConnectionFailedWithRomLog(log))) => {
+                assert_eq!(
+                    log,
+                    "rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)"
+                )
+            }
+            _ => panic!("expected the ROM's output in the error"),
+        }
+    }
+
+    #[test]
+    fn test_change_baud() {
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::ChangeBaud, 0, &[]);
+
+        let mut connection = connection(&mock);
+        connection
+            .command(Command::ChangeBaud {
+                new_baud: 921_600,
+                prior_baud: 0,
+            })
+            .unwrap();
+
+        let commands = mock.written_commands();
+        assert_eq!(commands[0].0, CommandType::ChangeBaud as u8);
+        assert_eq!(words(&commands[0].1), [921_600, 0]);
+    }
+
+    #[test]
+    fn test_erase_flash_with_notifications() {
+        let mock = MockTransport::new();
+        // Notifications sent while erasing precede the final response
+        for _ in 0..150 {
+            mock.push_response(CommandType::Unknown, 0, &[]);
+        }
+        mock.push_response(CommandType::EraseFlash, 0, &[]);
+
+        let mut connection = connection(&mock);
+        let timeout = CommandType::EraseFlash.timeout_for_size(16 * 1024 * 1024);
+        connection
+            .with_timeout(timeout, |connection| {
+                connection.command(Command::EraseFlash)
+            })
+            .unwrap();
+        assert!(mock.is_exhausted());
+        assert!(timeout > CommandType::EraseFlash.timeout());
+    }
+
+    #[test]
+    fn test_rom_error() {
+        let mock = MockTransport::new();
+        mock.push_error(CommandType::ReadReg, 0x05);
+
+        let mut connection = connection(&mock);
+        assert!(connection.read_reg(0x4000_1000).is_err());
+    }
+
+    #[test]
+    fn test_esp8266_flash_begin_size() {
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashBegin, 0, &[]);
+        for _ in 0..8 {
+            mock.push_response(CommandType::FlashData, 0, &[]);
+        }
+
+        let mut connection = connection(&mock);
+        let segment = RomSegment {
+            addr: 0x1000,
+            data: vec![0xAA; 8 * FLASH_WRITE_SIZE].into(),
+            encrypted: false,
+        };
+        Esp8266Target::new()
+            .write_segment(&mut connection, segment, &mut None)
+            .unwrap();
+
+        let commands = mock.written_commands();
+        assert_eq!(commands.len(), 9);
+
+        // 8KiB is two sectors, of which the ROM erases twice as many as requested
+        let [size, blocks, block_size, offset] = words(&commands[0].1)[..] else {
+            panic!("malformed FlashBegin command");
+        };
+        assert_eq!(size, 0x1000);
+        assert_eq!(blocks, 8);
+        assert_eq!(block_size, FLASH_WRITE_SIZE as u32);
+        assert_eq!(offset, 0x1000);
+    }
+
+    #[test]
+    fn test_encrypted_segment() {
+        let mock = MockTransport::new();
+        mock.push_response(CommandType::FlashBegin, 0, &[]);
+        mock.push_response(CommandType::FlashData, 0, &[]);
+
+        let mut connection = connection(&mock);
+        let segment = RomSegment {
+            addr: 0x10000,
+            data: vec![0xAA; 100].into(),
+            encrypted: true,
+        };
+        let mut target = Esp32Target::new(Chip::Esp32c3, SpiAttachParams::default(), false);
+        target
+            .write_segment(&mut connection, segment, &mut None)
+            .unwrap();
+        target.finish(&mut connection, false).unwrap();
+
+        // The ROM loader encrypts the uncompressed data, padded to 32 bytes, and
+        // no compressed write is ended
+        let commands = mock.written_commands();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].0, CommandType::FlashBegin as u8);
+        assert_eq!(
+            words(&commands[0].1),
+            [128, 1, FLASH_WRITE_SIZE as u32, 0x10000, 1]
+        );
+        assert_eq!(commands[1].0, CommandType::FlashData as u8);
+        assert!(mock.is_exhausted());
+    }
+
+    #[test]
+    fn test_unaligned_encrypted_segment() {
+        let mock = MockTransport::new();
+        let mut connection = connection(&mock);
+        let segment = RomSegment {
+            addr: 0x10010,
+            data: vec![0xAA; 32].into(),
+            encrypted: true,
+        };
+
+        let result = Esp32Target::new(Chip::Esp32c3, SpiAttachParams::default(), false)
+            .write_segment(&mut connection, segment, &mut None);
+        assert!(matches!(
+            result,
+            Err(Error::UnalignedEncryptedWrite(0x10010))
+        ));
+    }
+
+    #[test]
+    fn test_burn_secure_version() {
+        let mock = MockTransport::new();
+        let controller = Chip::Esp32c3.into_target().efuse_controller().unwrap();
+        let mut connection = connection(&mock);
+
+        // The secure version can not be lowered
+        mock.push_response(CommandType::ReadReg, 0b111, &[]);
+        assert!(matches!(
+            controller.burn_secure_version(&mut connection, 2),
+            Err(Error::SecureVersionRollback {
+                current: 3,
+                requested: 2
+            })
+        ));
+        assert_eq!(mock.written_commands().len(), 1);
+        mock.clear_written();
+
+        mock.push_response(CommandType::ReadReg, 0b1, &[]);
+        // Timing configuration
+        for _ in 0..4 {
+            mock.push_response(CommandType::ReadReg, 0, &[]);
+            mock.push_response(CommandType::WriteReg, 0, &[]);
+        }
+        // Programming, which also writes the data, then reloading the eFuses
+        for writes in [14, 13] {
+            for _ in 0..writes {
+                mock.push_response(CommandType::WriteReg, 0, &[]);
+            }
+            mock.push_response(CommandType::ReadReg, 0, &[]);
+        }
+        mock.push_response(CommandType::ReadReg, 0b11, &[]);
+        controller.burn_secure_version(&mut connection, 2).unwrap();
+        assert!(mock.is_exhausted());
+
+        // The secure version is the fifth word of block 0's programming data
+        let writes = mock
+            .written_commands()
+            .into_iter()
+            .filter(|(op, _)| *op == CommandType::WriteReg as u8)
+            .map(|(_, data)| words(&data)[..2].to_vec())
+            .collect::<Vec<_>>();
+        assert!(writes.contains(&vec![0x6000_8814, 0b11]));
+        assert!(writes.contains(&vec![0x6000_89d4, 0b10]));
+    }
+}
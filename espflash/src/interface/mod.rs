@@ -0,0 +1,721 @@
+//! Serial port wrapper to support platform-specific functionality
+//!
+//! Since we support flashing using a Raspberry Pi's built-in UART, we must be
+//! able to abstract over the differences between this setup and when using a
+//! serial port as one normally would, ie.) via USB.
+//!
+//! The underlying byte stream is abstracted by the [Transport] trait, allowing
+//! devices to be flashed over something other than a local serial port, such
+//! as a TCP socket, a WebSerial bridge, or a PTY. A [TcpTransport] is provided
+//! for devices attached to a remote serial server, and a [MockTransport] for
+//! testing the protocol without hardware.
+//!
+//! When the `gpio` feature is enabled, the target device's EN and IO0 strapping
+//! pins may also be wired directly to GPIOs of the host, which are then
+//! controlled using the Linux GPIO character device.
+
+#[cfg(any(feature = "gpio", target_os = "linux"))]
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::{fs, process};
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "gpio")]
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use miette::{Context, Result};
+#[cfg(feature = "raspberry")]
+use rppal::gpio::{Gpio, OutputPin};
+use serialport::{ClearBuffer, SerialPort, SerialPortInfo};
+#[cfg(any(feature = "ffi", feature = "python"))]
+use serialport::{SerialPortType, UsbPortInfo};
+use tracing::debug;
+
+pub use self::{
+    mock::MockTransport,
+    tcp::{TcpProtocol, TcpTransport},
+};
+use crate::error::Error;
+
+mod mock;
+#[cfg(target_os = "linux")]
+pub(crate) mod permissions;
+mod tcp;
+
+/// Errors relating to the configuration of a serial port
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum SerialConfigError {
+    #[cfg(feature = "raspberry")]
+    #[error("You need to specify both DTR and RTS pins when using an internal UART peripheral")]
+    MissingDtrRtsForInternalUart,
+    #[cfg(feature = "raspberry")]
+    #[error("GPIO {0} is not available")]
+    GpioUnavailable(u8),
+    #[cfg(feature = "gpio")]
+    #[error("GPIO chip '{0}' is not available")]
+    GpioChipUnavailable(String),
+    #[cfg(feature = "gpio")]
+    #[error("GPIO line {0} is not available")]
+    GpioLineUnavailable(u32),
+}
+
+/// The EN and IO0 strapping pins of a target device, wired directly to GPIOs
+/// of the host
+#[cfg(feature = "gpio")]
+pub struct StrappingPins {
+    en: LineHandle,
+    boot: LineHandle,
+}
+
+#[cfg(feature = "gpio")]
+impl StrappingPins {
+    /// Request the `en` and `boot` lines of the GPIO character device at `chip`
+    ///
+    /// Both lines are initially driven high, ie.) the device is running and
+    /// will boot normally when reset.
+    pub fn new(chip: &Path, en: u32, boot: u32) -> Result<Self, SerialConfigError> {
+        let mut chip = Chip::new(chip)
+            .map_err(|_| SerialConfigError::GpioChipUnavailable(chip.display().to_string()))?;
+
+        let mut request = |line: u32| {
+            chip.get_line(line)
+                .and_then(|line| line.request(LineRequestFlags::OUTPUT, 1, "espflash"))
+                .map_err(|_| SerialConfigError::GpioLineUnavailable(line))
+        };
+
+        Ok(Self {
+            en: request(en)?,
+            boot: request(boot)?,
+        })
+    }
+
+    /// Drive the EN pin; the device is held in reset while it is low
+    pub fn set_en(&mut self, level: bool) -> std::io::Result<()> {
+        write_line(&self.en, level)
+    }
+
+    /// Drive the IO0 pin; the device enters its bootloader if it is low when
+    /// released from reset
+    pub fn set_boot(&mut self, level: bool) -> std::io::Result<()> {
+        write_line(&self.boot, level)
+    }
+}
+
+#[cfg(feature = "gpio")]
+fn write_line(line: &LineHandle, level: bool) -> std::io::Result<()> {
+    line.set_value(u8::from(level))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+}
+
+/// Flow control used by a serial port
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum FlowControl {
+    /// No flow control
+    #[default]
+    None,
+    /// Hardware flow control, using the RTS and CTS signals
+    Hardware,
+    /// Software flow control, using XON and XOFF characters
+    Software,
+}
+
+impl From<FlowControl> for serialport::FlowControl {
+    fn from(flow_control: FlowControl) -> Self {
+        match flow_control {
+            FlowControl::None => serialport::FlowControl::None,
+            FlowControl::Hardware => serialport::FlowControl::Hardware,
+            FlowControl::Software => serialport::FlowControl::Software,
+        }
+    }
+}
+
+/// A byte stream over which a device can be communicated with
+///
+/// This is implemented for serial ports; the control line methods default to
+/// doing nothing, for transports which have no means of resetting the device.
+pub trait Transport: Read + Write + Send {
+    /// The name of the underlying port, if it has one
+    fn name(&self) -> Option<String> {
+        None
+    }
+
+    /// The current read timeout
+    fn timeout(&self) -> Duration;
+
+    /// Set the read timeout
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()>;
+
+    /// The current baud rate
+    fn baud_rate(&self) -> serialport::Result<u32>;
+
+    /// Set the baud rate
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()>;
+
+    /// Whether the baud rate can be changed; transports with no control over
+    /// the port's baud rate only accept the current one
+    fn can_set_baud_rate(&self) -> bool {
+        true
+    }
+
+    /// Discard any data which has been received but not yet read
+    fn clear_input(&mut self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    /// Set the state of the DTR control line
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    /// Set the state of the RTS control line
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    /// Set the flow control used when sending and receiving data
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    /// The number of times received data has been lost because it was not
+    /// read in time, if the transport keeps count
+    fn overruns(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn name(&self) -> Option<String> {
+        self.as_ref().name()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.as_ref().timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.as_mut().set_timeout(timeout)
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.as_ref().baud_rate()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.as_mut().set_baud_rate(baud_rate)
+    }
+
+    fn clear_input(&mut self) -> serialport::Result<()> {
+        self.clear(ClearBuffer::Input)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.as_mut().write_data_terminal_ready(level)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.as_mut().write_request_to_send(level)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.as_mut().set_flow_control(flow_control.into())
+    }
+}
+
+/// On Linux, serial ports are opened natively so that the kernel's count of
+/// overruns can be read
+#[cfg(target_os = "linux")]
+impl Transport for serialport::TTYPort {
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self)
+    }
+
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        SerialPort::set_timeout(self, timeout)
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        SerialPort::baud_rate(self)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        SerialPort::set_baud_rate(self, baud_rate)
+    }
+
+    fn clear_input(&mut self) -> serialport::Result<()> {
+        self.clear(ClearBuffer::Input)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_data_terminal_ready(self, level)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        SerialPort::write_request_to_send(self, level)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        SerialPort::set_flow_control(self, flow_control.into())
+    }
+
+    fn overruns(&self) -> Option<u32> {
+        use std::os::unix::io::AsRawFd;
+
+        /// `struct serial_icounter_struct` from `linux/serial.h`
+        #[derive(Default)]
+        #[repr(C)]
+        struct SerialIcounter {
+            cts: libc::c_int,
+            dsr: libc::c_int,
+            rng: libc::c_int,
+            dcd: libc::c_int,
+            rx: libc::c_int,
+            tx: libc::c_int,
+            frame: libc::c_int,
+            overrun: libc::c_int,
+            parity: libc::c_int,
+            brk: libc::c_int,
+            buf_overrun: libc::c_int,
+            reserved: [libc::c_int; 9],
+        }
+
+        let mut counts = SerialIcounter::default();
+        // SAFETY: `TIOCGICOUNT` writes a `serial_icounter_struct` to the pointer,
+        // which is valid for the duration of the call
+        let result = unsafe { libc::ioctl(self.as_raw_fd(), libc::TIOCGICOUNT, &mut counts) };
+
+        // Drivers which don't keep count fail, or leave the counts zeroed
+        (result == 0).then_some((counts.overrun + counts.buf_overrun) as u32)
+    }
+}
+
+/// Wrapper around a [Transport] where platform-specific modifications can be
+/// implemented.
+pub struct Interface {
+    /// Transport used for communication, usually a hardware serial port
+    pub serial_port: Box<dyn Transport>,
+    /// Data Transmit Ready pin
+    #[cfg(feature = "raspberry")]
+    pub dtr: Option<OutputPin>,
+    /// Ready To Send pin
+    #[cfg(feature = "raspberry")]
+    pub rts: Option<OutputPin>,
+    /// Strapping pins wired directly to the host's GPIOs
+    #[cfg(feature = "gpio")]
+    pub strapping_pins: Option<StrappingPins>,
+}
+
+#[cfg(feature = "raspberry")]
+fn write_gpio(gpio: &mut OutputPin, level: bool) {
+    if level {
+        gpio.set_high();
+    } else {
+        gpio.set_low();
+    }
+}
+
+/// How long to keep retrying to open a serial port which is in use, or which
+/// has not yet re-enumerated after the device was reset
+const OPEN_RETRY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Delay before retrying to open a serial port, which doubles after each
+/// attempt up to [MAX_OPEN_RETRY_DELAY]
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_OPEN_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Open a serial port, retrying for a while if it is not yet available
+///
+/// On Windows in particular, opening a port often fails with
+/// `ERROR_ACCESS_DENIED` for a short time after the device re-enumerates.
+fn open_port(port_info: &SerialPortInfo, exclusive: bool) -> Result<Box<dyn Transport>> {
+    if exclusive && !cfg!(any(unix, windows)) {
+        return Err(Error::ExclusiveAccessUnsupported.into());
+    }
+
+    let start = Instant::now();
+    let mut delay = OPEN_RETRY_DELAY;
+
+    loop {
+        let err = match try_open_port(port_info, exclusive) {
+            Ok(port) => return Ok(port),
+            Err(err) => err,
+        };
+
+        // On Linux, EACCES is reported when the user lacks permission to open the
+        // port, rather than when it is in use, so diagnose it before retrying
+        #[cfg(target_os = "linux")]
+        if let serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) = err.kind() {
+            let usb_ids = match &port_info.port_type {
+                serialport::SerialPortType::UsbPort(info) => Some((info.vid, info.pid)),
+                _ => None,
+            };
+            if let Some(problem) = permissions::diagnose(Path::new(&port_info.port_name), usb_ids) {
+                return Err(Error::SerialPortPermissionDenied {
+                    port: port_info.port_name.clone(),
+                    guidance: problem.to_string(),
+                }
+                .into());
+            }
+        }
+
+        let in_use = port_in_use(&err);
+        let missing = matches!(
+            err.kind(),
+            serialport::ErrorKind::NoDevice | serialport::ErrorKind::Io(io::ErrorKind::NotFound)
+        );
+        if (in_use || missing) && start.elapsed() < OPEN_RETRY_TIMEOUT {
+            debug!("Failed to open serial port: {err}, retrying in {delay:?}");
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_OPEN_RETRY_DELAY);
+            continue;
+        }
+
+        if in_use {
+            return Err(Error::SerialPortInUse {
+                port: port_info.port_name.clone(),
+                holders: port_holders(&port_info.port_name),
+            }
+            .into());
+        }
+
+        return Err(Error::from(err))
+            .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name));
+    }
+}
+
+#[cfg(unix)]
+fn try_open_port(
+    port_info: &SerialPortInfo,
+    exclusive: bool,
+) -> serialport::Result<Box<dyn Transport>> {
+    use std::os::unix::io::AsRawFd;
+
+    let port = serialport::new(&port_info.port_name, 115_200)
+        .flow_control(serialport::FlowControl::None)
+        .open_native()?;
+
+    // Ports are already opened with TIOCEXCL, which does not apply to
+    // privileged processes, so also take the advisory lock honoured by other
+    // tools such as esptool
+    if exclusive && unsafe { libc::flock(port.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    // Overruns are only counted on Linux
+    #[cfg(not(target_os = "linux"))]
+    let port: Box<dyn SerialPort> = Box::new(port);
+
+    Ok(Box::new(port))
+}
+
+#[cfg(not(unix))]
+fn try_open_port(
+    port_info: &SerialPortInfo,
+    _exclusive: bool,
+) -> serialport::Result<Box<dyn Transport>> {
+    // Serial ports on Windows can only ever be opened by a single process, so
+    // access is always exclusive; elsewhere `open_port` has already refused
+    // `exclusive`
+    serialport::new(&port_info.port_name, 115_200)
+        .flow_control(serialport::FlowControl::None)
+        .open()
+        .map(|port| Box::new(port) as Box<dyn Transport>)
+}
+
+/// Whether opening a port failed because another process is using it
+fn port_in_use(err: &serialport::Error) -> bool {
+    // Windows reports ERROR_ACCESS_DENIED as `NoDevice`
+    let description = err.description.to_lowercase();
+
+    matches!(
+        err.kind(),
+        serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied | io::ErrorKind::WouldBlock)
+    ) || description.contains("access is denied")
+        || description.contains("busy")
+}
+
+/// Describe the processes which have the port open, where this can be
+/// determined
+#[cfg(target_os = "linux")]
+fn port_holders(port: &str) -> Vec<String> {
+    let Ok(device) = fs::canonicalize(port) else {
+        return Vec::new();
+    };
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    processes
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            if pid == process::id() {
+                return None;
+            }
+
+            let holds_port = fs::read_dir(entry.path().join("fd"))
+                .ok()?
+                .flatten()
+                .any(|fd| fs::read_link(fd.path()).map_or(false, |path| path == device));
+            if !holds_port {
+                return None;
+            }
+
+            let name = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            Some(format!("{} (pid {pid})", name.trim()))
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn port_holders(port: &str) -> Vec<String> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt, ptr};
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_MORE_DATA, ERROR_SUCCESS},
+        System::RestartManager::{
+            RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+            RM_PROCESS_INFO,
+        },
+    };
+
+    let mut session = 0;
+    let mut session_key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+    if unsafe { RmStartSession(&mut session, 0, session_key.as_mut_ptr()) } != ERROR_SUCCESS {
+        return Vec::new();
+    }
+
+    // Ask the Restart Manager which processes have the port's device open
+    let device: Vec<u16> = OsStr::new(&format!(r"\\.\{port}"))
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+    let resources = [device.as_ptr()];
+
+    let mut processes: Vec<RM_PROCESS_INFO> = Vec::new();
+    let registered = unsafe {
+        RmRegisterResources(
+            session,
+            1,
+            resources.as_ptr(),
+            0,
+            ptr::null(),
+            0,
+            ptr::null(),
+        )
+    };
+    if registered == ERROR_SUCCESS {
+        let (mut needed, mut count, mut reasons) = (0, 0, 0);
+        let mut status = unsafe {
+            RmGetList(
+                session,
+                &mut needed,
+                &mut count,
+                ptr::null_mut(),
+                &mut reasons,
+            )
+        };
+
+        // The number of processes may change between calls, so try a few times
+        for _ in 0..3 {
+            if status != ERROR_MORE_DATA {
+                break;
+            }
+
+            processes = vec![unsafe { std::mem::zeroed() }; needed as usize];
+            count = needed;
+            status = unsafe {
+                RmGetList(
+                    session,
+                    &mut needed,
+                    &mut count,
+                    processes.as_mut_ptr(),
+                    &mut reasons,
+                )
+            };
+        }
+
+        processes.truncate(if status == ERROR_SUCCESS {
+            count as usize
+        } else {
+            0
+        });
+    }
+
+    unsafe { RmEndSession(session) };
+
+    processes
+        .iter()
+        .map(|process| {
+            let len = process
+                .strAppName
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(process.strAppName.len());
+            let name = String::from_utf16_lossy(&process.strAppName[..len]);
+
+            format!("{name} (pid {})", process.Process.dwProcessId)
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn port_holders(_port: &str) -> Vec<String> {
+    Vec::new()
+}
+
+/// Find the serial port named `port`, along with its USB information if it is a
+/// USB device
+#[cfg(any(feature = "ffi", feature = "python"))]
+pub(crate) fn find_port(port: &str) -> (SerialPortInfo, UsbPortInfo) {
+    let port_info = serialport::available_ports()
+        .ok()
+        .and_then(|ports| ports.into_iter().find(|info| info.port_name == port))
+        .unwrap_or_else(|| SerialPortInfo {
+            port_name: port.to_string(),
+            port_type: SerialPortType::Unknown,
+        });
+
+    let usb_info = match &port_info.port_type {
+        SerialPortType::UsbPort(info) => info.clone(),
+        _ => UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        },
+    };
+
+    (port_info, usb_info)
+}
+
+impl Interface {
+    /// Open a serial port, optionally locking it so that no other process can
+    /// open it while it is in use
+    #[cfg(feature = "raspberry")]
+    pub fn new(
+        port_info: &SerialPortInfo,
+        dtr: Option<u8>,
+        rts: Option<u8>,
+        exclusive: bool,
+    ) -> Result<Self> {
+        if port_info.port_type == serialport::SerialPortType::Unknown
+            && (dtr.is_none() || rts.is_none())
+        {
+            // Assume internal UART, which has no DTR pin and usually no RTS either.
+            return Err(Error::from(SerialConfigError::MissingDtrRtsForInternalUart).into());
+        }
+
+        let gpios = Gpio::new().unwrap();
+
+        let rts = if let Some(gpio) = rts {
+            match gpios.get(gpio) {
+                Ok(pin) => Some(pin.into_output()),
+                Err(_) => return Err(Error::from(SerialConfigError::GpioUnavailable(gpio)).into()),
+            }
+        } else {
+            None
+        };
+
+        let dtr = if let Some(gpio) = dtr {
+            match gpios.get(gpio) {
+                Ok(pin) => Some(pin.into_output()),
+                Err(_) => return Err(Error::from(SerialConfigError::GpioUnavailable(gpio)).into()),
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            serial_port: open_port(port_info, exclusive)?,
+            rts,
+            dtr,
+            #[cfg(feature = "gpio")]
+            strapping_pins: None,
+        })
+    }
+
+    /// Open a serial port, optionally locking it so that no other process can
+    /// open it while it is in use
+    #[cfg(not(feature = "raspberry"))]
+    pub fn new(
+        port_info: &SerialPortInfo,
+        _dtr: Option<u8>,
+        _rts: Option<u8>,
+        exclusive: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            serial_port: open_port(port_info, exclusive)?,
+            #[cfg(feature = "gpio")]
+            strapping_pins: None,
+        })
+    }
+
+    /// Communicate using an arbitrary [Transport], rather than a serial port
+    pub fn from_transport(transport: impl Transport + 'static) -> Self {
+        Self {
+            serial_port: Box::new(transport),
+            #[cfg(feature = "raspberry")]
+            dtr: None,
+            #[cfg(feature = "raspberry")]
+            rts: None,
+            #[cfg(feature = "gpio")]
+            strapping_pins: None,
+        }
+    }
+
+    /// Control the device's strapping pins directly using the host's GPIOs
+    #[cfg(feature = "gpio")]
+    pub fn set_strapping_pins(&mut self, strapping_pins: StrappingPins) {
+        self.strapping_pins = Some(strapping_pins);
+    }
+
+    pub fn write_data_terminal_ready(&mut self, pin_state: bool) -> serialport::Result<()> {
+        #[cfg(feature = "raspberry")]
+        if let Some(gpio) = self.dtr.as_mut() {
+            write_gpio(gpio, pin_state);
+            return Ok(());
+        }
+
+        self.serial_port.write_data_terminal_ready(pin_state)
+    }
+
+    pub fn write_request_to_send(&mut self, pin_state: bool) -> serialport::Result<()> {
+        #[cfg(feature = "raspberry")]
+        if let Some(gpio) = self.rts.as_mut() {
+            write_gpio(gpio, pin_state);
+            return Ok(());
+        }
+
+        self.serial_port.write_request_to_send(pin_state)
+    }
+
+    pub fn into_serial(self) -> Box<dyn Transport> {
+        self.serial_port
+    }
+
+    pub fn serial_port(&self) -> &dyn Transport {
+        self.serial_port.as_ref()
+    }
+
+    pub fn serial_port_mut(&mut self) -> &mut dyn Transport {
+        self.serial_port.as_mut()
+    }
+}
+
+// Note(dbuga): this impl is necessary because using `dyn Transport` as `dyn
+// Read` requires trait_upcasting which isn't stable yet.
+impl Read for Interface {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.serial_port.read(buf)
+    }
+}
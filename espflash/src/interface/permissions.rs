@@ -0,0 +1,150 @@
+//! Diagnosing why the current user may not open a serial port
+//!
+//! On most Linux distributions, serial ports are owned by a group such as
+//! `dialout` or `uucp`, which users must be added to before they can open
+//! them. Group membership only takes effect in new login sessions, which is a
+//! common source of confusion.
+
+use std::{
+    env,
+    ffi::CString,
+    fmt, fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+/// The reason the current user may not open a serial port
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PermissionProblem {
+    /// Group which owns the port, unless it is owned by `root`
+    group: Option<String>,
+    /// Whether the user belongs to the group, but not in the current session
+    pending: bool,
+    /// Vendor and product IDs of the port's USB device
+    usb_ids: Option<(u16, u16)>,
+}
+
+/// Determine why the current user may not read from and write to `port`, or
+/// `None` if they may
+pub(crate) fn diagnose(port: &Path, usb_ids: Option<(u16, u16)>) -> Option<PermissionProblem> {
+    let path = CString::new(port.as_os_str().as_bytes()).ok()?;
+    if unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) } == 0 {
+        return None;
+    }
+
+    let gid = fs::metadata(port).ok()?.gid();
+    let entry = fs::read_to_string("/etc/group")
+        .ok()
+        .and_then(|groups| group_entry(&groups, gid));
+
+    let (group, pending) = match entry {
+        Some((name, _)) if name == "root" => (None, false),
+        Some((name, members)) => {
+            let user = env::var("USER").or_else(|_| env::var("LOGNAME"));
+            let member = user.map_or(false, |user| members.contains(&user));
+
+            (Some(name), member && !session_groups().contains(&gid))
+        }
+        None => (None, false),
+    };
+
+    Some(PermissionProblem {
+        group,
+        pending,
+        usb_ids,
+    })
+}
+
+/// The name and members of the group with the given ID, from the contents of
+/// `/etc/group`
+fn group_entry(groups: &str, gid: u32) -> Option<(String, Vec<String>)> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let id = fields.nth(1)?.parse::<u32>().ok()?;
+        let members = fields.next().unwrap_or_default();
+
+        (id == gid).then(|| {
+            let members = members
+                .split(',')
+                .filter(|member| !member.is_empty())
+                .map(String::from)
+                .collect();
+
+            (name.to_string(), members)
+        })
+    })
+}
+
+/// The groups of the current process
+fn session_groups() -> Vec<u32> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+
+    groups
+}
+
+/// A udev rule granting the logged-in user access to a USB device
+pub(crate) fn udev_rule(vid: u16, pid: u16) -> String {
+    format!(
+        "SUBSYSTEM==\"tty\", ATTRS{{idVendor}}==\"{vid:04x}\", ATTRS{{idProduct}}==\"{pid:04x}\", MODE=\"0660\", TAG+=\"uaccess\""
+    )
+}
+
+impl fmt::Display for PermissionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.group, self.pending) {
+            (Some(group), true) => write!(
+                f,
+                "You were added to the '{group}' group, which only takes effect once you log out \
+                 and back in, or run `newgrp {group}`"
+            )?,
+            (Some(group), false) => write!(
+                f,
+                "Add yourself to the '{group}' group which owns the port using `sudo usermod -aG \
+                 {group} $USER`, then log out and back in"
+            )?,
+            (None, _) => write!(f, "The port is only accessible to root")?,
+        }
+
+        if let Some((vid, pid)) = self.usb_ids {
+            write!(
+                f,
+                ". Alternatively, allow access to the device by adding this rule to \
+                 /etc/udev/rules.d/99-espflash.rules and reconnecting it:\n  {}",
+                udev_rule(vid, pid)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_entry() {
+        let groups = "root:x:0:\ndialout:x:20:alice,bob\nuucp:x:14:\n";
+
+        assert_eq!(
+            group_entry(groups, 20),
+            Some(("dialout".into(), vec!["alice".into(), "bob".into()]))
+        );
+        assert_eq!(group_entry(groups, 14), Some(("uucp".into(), vec![])));
+        assert_eq!(group_entry(groups, 99), None);
+
+        let problem = PermissionProblem {
+            group: Some("dialout".into()),
+            pending: false,
+            usb_ids: Some((0x303a, 0x1001)),
+        };
+        let guidance = problem.to_string();
+        assert!(guidance.contains("usermod -aG dialout"));
+        assert!(guidance.contains(r#"ATTRS{idVendor}=="303a", ATTRS{idProduct}=="1001""#));
+    }
+}
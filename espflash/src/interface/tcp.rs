@@ -0,0 +1,390 @@
+//! Transport for devices attached to a remote serial server
+//!
+//! Both raw TCP connections and [RFC 2217] (Telnet COM port control) are
+//! supported; only the latter is able to set the baud rate and toggle the
+//! DTR/RTS control lines of the remote port, which is required for the reset
+//! sequences to work, as provided by eg.) ser2net or esp-link.
+//!
+//! [RFC 2217]: https://datatracker.ietf.org/doc/html/rfc2217
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use super::Transport;
+
+// Telnet commands
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+// Telnet options
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// COM port control commands, client to server
+const SET_BAUDRATE: u8 = 1;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+
+// SET_CONTROL values
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+
+// PURGE_DATA values
+const PURGE_RECEIVE_BUFFER: u8 = 1;
+
+/// Protocol spoken with the remote serial server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpProtocol {
+    /// The connection carries the serial data as-is
+    Raw,
+    /// Telnet with the COM port control option, as described in RFC 2217
+    Rfc2217,
+}
+
+impl TcpProtocol {
+    /// Parse a port of the form `tcp://host:port` or `rfc2217://host:port`,
+    /// returning the protocol and the address
+    pub fn parse_port(port: &str) -> Option<(Self, &str)> {
+        if let Some(addr) = port.strip_prefix("tcp://") {
+            Some((TcpProtocol::Raw, addr))
+        } else {
+            port.strip_prefix("rfc2217://")
+                .map(|addr| (TcpProtocol::Rfc2217, addr))
+        }
+    }
+}
+
+/// State of the Telnet decoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// A [Transport] connected to a remote serial server over TCP
+pub struct TcpTransport {
+    stream: TcpStream,
+    protocol: TcpProtocol,
+    baud_rate: u32,
+    state: TelnetState,
+}
+
+impl TcpTransport {
+    /// Connect to the serial server at `addr`
+    pub fn connect(addr: &str, protocol: TcpProtocol) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let mut transport = Self {
+            stream,
+            protocol,
+            baud_rate: 115_200,
+            state: TelnetState::Data,
+        };
+
+        if protocol == TcpProtocol::Rfc2217 {
+            transport.stream.write_all(&[
+                IAC,
+                WILL,
+                BINARY,
+                IAC,
+                DO,
+                BINARY,
+                IAC,
+                WILL,
+                SUPPRESS_GO_AHEAD,
+                IAC,
+                DO,
+                SUPPRESS_GO_AHEAD,
+                IAC,
+                WILL,
+                COM_PORT_OPTION,
+            ])?;
+            transport.com_port_command(SET_BAUDRATE, &transport.baud_rate.to_be_bytes())?;
+        }
+
+        Ok(transport)
+    }
+
+    fn com_port_command(&mut self, command: u8, value: &[u8]) -> io::Result<()> {
+        let mut data = vec![IAC, SB, COM_PORT_OPTION, command];
+        data.extend(escape(value));
+        data.extend([IAC, SE]);
+
+        self.stream.write_all(&data)
+    }
+
+    fn set_control(&mut self, value: u8) -> serialport::Result<()> {
+        if self.protocol == TcpProtocol::Rfc2217 {
+            self.com_port_command(SET_CONTROL, &[value])?;
+        }
+
+        Ok(())
+    }
+
+    /// Strip Telnet commands from `buf`, returning the length of the data
+    /// which remains
+    fn decode(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        let mut replies = Vec::new();
+
+        for i in 0..buf.len() {
+            let byte = buf[i];
+            self.state = match (self.state, byte) {
+                (TelnetState::Data, IAC) => TelnetState::Iac,
+                (TelnetState::Data, _) => {
+                    buf[len] = byte;
+                    len += 1;
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, IAC) => {
+                    buf[len] = IAC;
+                    len += 1;
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, DO | DONT | WILL | WONT) => TelnetState::Negotiation(byte),
+                (TelnetState::Iac, SB) => TelnetState::Subnegotiation,
+                (TelnetState::Iac, _) => TelnetState::Data,
+                (TelnetState::Negotiation(command), option) => {
+                    // Refuse any options which we have not already offered
+                    if !matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION) {
+                        match command {
+                            DO => replies.extend([IAC, WONT, option]),
+                            WILL => replies.extend([IAC, DONT, option]),
+                            _ => {}
+                        }
+                    }
+                    TelnetState::Data
+                }
+                // Responses to our COM port commands are ignored
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationIac,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationIac, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+            };
+        }
+
+        if !replies.is_empty() {
+            self.stream.write_all(&replies)?;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Escape any IAC bytes in `data` by doubling them
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+
+    escaped
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = match self.stream.read(buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(count) => count,
+                // Reads which time out are reported as `WouldBlock` on some platforms
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Err(ErrorKind::TimedOut.into())
+                }
+                Err(err) => return Err(err),
+            };
+
+            if self.protocol == TcpProtocol::Raw {
+                return Ok(count);
+            }
+
+            // A read consisting solely of Telnet commands must not be mistaken
+            // for the end of the stream
+            let len = self.decode(&mut buf[..count])?;
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.protocol {
+            TcpProtocol::Raw => self.stream.write(buf),
+            TcpProtocol::Rfc2217 => {
+                self.stream.write_all(&escape(buf))?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn timeout(&self) -> Duration {
+        self.stream
+            .read_timeout()
+            .ok()
+            .flatten()
+            .unwrap_or(Duration::ZERO)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        // A zero duration is rejected by the socket, and would otherwise mean no timeout
+        let timeout = (!timeout.is_zero()).then_some(timeout);
+        self.stream.set_read_timeout(timeout)?;
+
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        match self.protocol {
+            TcpProtocol::Rfc2217 => {
+                self.com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?
+            }
+            // The remote port's baud rate is configured by the server, so the
+            // device would be left at a different rate
+            TcpProtocol::Raw if baud_rate != self.baud_rate => {
+                return Err(serialport::Error::new(
+                    serialport::ErrorKind::InvalidInput,
+                    "the baud rate of a raw TCP port can't be changed",
+                ))
+            }
+            TcpProtocol::Raw => {}
+        }
+        self.baud_rate = baud_rate;
+
+        Ok(())
+    }
+
+    fn can_set_baud_rate(&self) -> bool {
+        self.protocol == TcpProtocol::Rfc2217
+    }
+
+    fn clear_input(&mut self) -> serialport::Result<()> {
+        if self.protocol == TcpProtocol::Rfc2217 {
+            self.com_port_command(PURGE_DATA, &[PURGE_RECEIVE_BUFFER])?;
+        }
+
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.set_control(if level { DTR_ON } else { DTR_OFF })
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.set_control(if level { RTS_ON } else { RTS_OFF })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_rfc2217_strips_telnet_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(&[
+                    IAC,
+                    DO,
+                    COM_PORT_OPTION,
+                    0xC0,
+                    IAC,
+                    IAC,
+                    IAC,
+                    SB,
+                    COM_PORT_OPTION,
+                    101,
+                    0,
+                    0,
+                    0x1C,
+                    0x20,
+                    IAC,
+                    SE,
+                    0x12,
+                ])
+                .unwrap();
+
+            // Closing the connection with the client's negotiation unread would
+            // reset it
+            stream
+        });
+
+        let mut transport = TcpTransport::connect(&addr, TcpProtocol::Rfc2217).unwrap();
+        let _stream = server.join().unwrap();
+
+        let mut data = Vec::new();
+        let mut buf = [0; 32];
+        while data.len() < 3 {
+            let count = transport.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..count]);
+        }
+
+        assert_eq!(data, [0xC0, IAC, 0x12]);
+    }
+
+    #[test]
+    fn test_raw_baud_rate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || listener.accept().unwrap().0);
+
+        let mut transport = TcpTransport::connect(&addr, TcpProtocol::Raw).unwrap();
+        let _stream = server.join().unwrap();
+
+        assert!(!transport.can_set_baud_rate());
+        let baud_rate = transport.baud_rate().unwrap();
+        assert!(transport.set_baud_rate(baud_rate).is_ok());
+
+        let err = transport.set_baud_rate(921_600).unwrap_err();
+        assert_eq!(err.kind(), serialport::ErrorKind::InvalidInput);
+        assert_eq!(transport.baud_rate().unwrap(), baud_rate);
+    }
+
+    #[test]
+    fn test_parse_port() {
+        assert_eq!(
+            TcpProtocol::parse_port("rfc2217://localhost:4000"),
+            Some((TcpProtocol::Rfc2217, "localhost:4000"))
+        );
+        assert_eq!(
+            TcpProtocol::parse_port("tcp://10.0.0.2:23"),
+            Some((TcpProtocol::Raw, "10.0.0.2:23"))
+        );
+        assert_eq!(TcpProtocol::parse_port("/dev/ttyUSB0"), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! Flashing over JTAG using OpenOCD
+//!
+//! Some boards don't break out the pins of the UART used by the ROM
+//! bootloader, but do provide access to the chip's JTAG interface, either
+//! through its built-in USB-JTAG peripheral or through an external adapter such
+//! as the ESP-Prog. Flash can then be written by halting the CPU through its
+//! debug module, loading a small flasher algorithm into RAM, and running it to
+//! write the data.
+//!
+//! Espressif's fork of [OpenOCD] implements both the debug module access, over
+//! each of the supported adapters, and the flasher algorithm for each chip, so
+//! rather than reimplementing them the segments to write are handed to its
+//! `program_esp` command. An `openocd` built from Espressif's fork, such as the
+//! one installed by the ESP-IDF's `install` script, must therefore be
+//! available.
+//!
+//! [OpenOCD]: https://github.com/espressif/openocd-esp32
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use tracing::debug;
+
+use crate::{elf::RomSegment, error::Error, targets::Chip};
+
+/// Interface configuration for the USB-JTAG peripheral built into newer chips
+const USB_JTAG_INTERFACE: &str = "interface/esp_usb_jtag.cfg";
+/// Interface configuration for the FT2232H based ESP-Prog and ESP-WROVER-KIT
+const ESP_PROG_INTERFACE: &str = "interface/ftdi/esp32_devkitj_v1.cfg";
+
+/// Flashes a device over JTAG by running OpenOCD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenOcd {
+    chip: Chip,
+    program: PathBuf,
+    interface: String,
+}
+
+impl OpenOcd {
+    /// Flash the given chip using the `openocd` found in the `PATH`, and the
+    /// interface configuration of its default JTAG adapter
+    ///
+    /// The default adapter is the built-in USB-JTAG peripheral for chips which
+    /// have one, and the ESP-Prog otherwise.
+    pub fn new(chip: Chip) -> Result<Self, Error> {
+        let interface = match chip {
+            Chip::Esp32 | Chip::Esp32c2 | Chip::Esp32s2 => ESP_PROG_INTERFACE,
+            Chip::Esp32c3 | Chip::Esp32c6 | Chip::Esp32c61 | Chip::Esp32s3 | Chip::Esp32h2 => {
+                USB_JTAG_INTERFACE
+            }
+            Chip::Esp8266 => {
+                return Err(Error::UnsupportedFeature {
+                    chip,
+                    feature: "flashing over JTAG".into(),
+                })
+            }
+        };
+
+        Ok(Self {
+            chip,
+            program: PathBuf::from("openocd"),
+            interface: interface.into(),
+        })
+    }
+
+    /// Use the given OpenOCD executable
+    pub fn program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Use the given interface configuration, eg.
+    /// `interface/ftdi/esp32s2_kaluga_v1.cfg`, to select the JTAG adapter
+    pub fn interface(mut self, interface: impl Into<String>) -> Self {
+        self.interface = interface.into();
+        self
+    }
+
+    /// Write the segments to flash, verify them, and reset the device to run
+    /// the application
+    ///
+    /// OpenOCD's output is displayed as it runs.
+    pub fn flash(&self, segments: &[RomSegment]) -> Result<(), Error> {
+        if segments.is_empty() {
+            return Ok(());
+        }
+
+        let dir = TempDir::new()?;
+        let mut files = Vec::with_capacity(segments.len());
+        for (i, segment) in segments.iter().enumerate() {
+            let path = dir.0.join(format!("segment{i}_{:#x}.bin", segment.addr));
+            fs::write(&path, &segment.data)?;
+            files.push((path, segment));
+        }
+
+        let args = self.args(&files);
+        debug!("Running {} {:?}", self.program.display(), args);
+
+        let status = Command::new(&self.program)
+            .args(&args)
+            .status()
+            .map_err(|err| Error::OpenOcdNotFound(self.program.clone(), err))?;
+        if !status.success() {
+            return Err(Error::OpenOcdFailed(status.code()));
+        }
+
+        Ok(())
+    }
+
+    /// Arguments to pass to OpenOCD in order to write each file at the address
+    /// of its segment
+    fn args(&self, files: &[(PathBuf, &RomSegment)]) -> Vec<String> {
+        let mut args = vec![
+            "-f".into(),
+            self.interface.clone(),
+            "-f".into(),
+            format!("target/{}.cfg", self.chip),
+        ];
+
+        for (i, (path, segment)) in files.iter().enumerate() {
+            let mut command = format!("program_esp {} {:#x} verify", tcl_quote(path), segment.addr);
+            if segment.encrypted {
+                command.push_str(" encrypt");
+            }
+            // The device is only reset, and OpenOCD exited, once everything has
+            // been written
+            if i == files.len() - 1 {
+                command.push_str(" reset exit");
+            }
+
+            args.push("-c".into());
+            args.push(command);
+        }
+
+        args
+    }
+}
+
+/// Quote a path as a single Tcl word, without substitutions
+fn tcl_quote(path: &Path) -> String {
+    format!("{{{}}}", path.display())
+}
+
+/// Directory holding the segments while OpenOCD writes them, which is removed
+/// once it is dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self, Error> {
+        let path = std::env::temp_dir().join(format!("espflash-jtag-{}", std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn segment(addr: u32, encrypted: bool) -> RomSegment<'static> {
+        RomSegment {
+            addr,
+            data: Cow::Borrowed(&[0xff; 4]),
+            encrypted,
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let bootloader = segment(0x0, false);
+        let app = segment(0x10000, true);
+        let files = [
+            (PathBuf::from("/tmp/segment0_0x0.bin"), &bootloader),
+            (PathBuf::from("/tmp/segment1_0x10000.bin"), &app),
+        ];
+
+        let openocd = OpenOcd::new(Chip::Esp32c3).unwrap();
+        assert_eq!(
+            openocd.args(&files),
+            [
+                "-f",
+                "interface/esp_usb_jtag.cfg",
+                "-f",
+                "target/esp32c3.cfg",
+                "-c",
+                "program_esp {/tmp/segment0_0x0.bin} 0x0 verify",
+                "-c",
+                "program_esp {/tmp/segment1_0x10000.bin} 0x10000 verify encrypt reset exit",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interface() {
+        let openocd = OpenOcd::new(Chip::Esp32).unwrap();
+        assert_eq!(openocd.interface, ESP_PROG_INTERFACE);
+
+        let openocd = openocd.interface("interface/ftdi/esp32s2_kaluga_v1.cfg");
+        assert_eq!(openocd.interface, "interface/ftdi/esp32s2_kaluga_v1.cfg");
+
+        assert!(matches!(
+            OpenOcd::new(Chip::Esp8266),
+            Err(Error::UnsupportedFeature { .. })
+        ));
+    }
+}
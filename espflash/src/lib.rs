@@ -40,6 +40,30 @@
 //! espflash = { version = "2.0", default-features = false, features = ["raspberry"] }
 //! ```
 //!
+//! C and C++ applications can use the API provided by the `ffi` feature, and
+//! Python applications the `pyespflash` module provided by the `python`
+//! feature; see the [ffi] and [python] modules for more information.
+//!
+//! The most commonly used types are re-exported from the crate root, and make
+//! up the stable public API of the library; they follow semantic versioning,
+//! and do not depend on any of the command-line application's dependencies:
+//!
+//! ```no_run
+//! use espflash::{ConnectOptions, Flasher, Interface, RetryPolicy};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (interface, port_info): (Interface, serialport::UsbPortInfo) = unimplemented!();
+//! let options = ConnectOptions::default()
+//!     .speed(921_600)
+//!     .retry_policy(RetryPolicy::default().connect_attempts(3));
+//! let mut flasher = Flasher::connect(interface, port_info, options)?;
+//! println!("{:?}", flasher.device_info()?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Items which are hidden from the documentation are implementation details,
+//! and may change in any release.
+//!
 //! [espflash]: https://crates.io/crates/espflash
 //! [cargo-binstall]: https://github.com/cargo-bins/cargo-binstall
 //! [rppal]: https://docs.rs/rppal/latest/rppal/
@@ -49,26 +73,70 @@
 #[cfg(feature = "cli")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cli")))]
 pub mod cli;
+#[doc(hidden)]
 pub mod command;
 pub mod connection;
 pub mod elf;
 pub mod error;
+#[cfg(feature = "ffi")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ffi")))]
+pub mod ffi;
 pub mod flasher;
 pub mod image_format;
 pub mod interface;
+pub mod jtag;
+#[cfg(feature = "python")]
+#[cfg_attr(docsrs, doc(cfg(feature = "python")))]
+pub mod python;
+pub mod security;
 pub mod targets;
+pub mod updater;
+
+pub use self::{
+    connection::{Connection, ResetSequence, ResetStrategy, RetryPolicy},
+    error::Error,
+    flasher::{
+        ConnectOptions, DeviceInfo, FlashData, FlashDataBuilder, FlashFrequency, FlashMode,
+        FlashParts, FlashSize, Flasher, ProgressCallbacks, SpiAttachParams,
+    },
+    image_format::{ImageFormat, ImageFormatKind},
+    interface::{Interface, Transport},
+    targets::Chip,
+};
 
 /// Logging utilties
 #[cfg(feature = "cli")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cli")))]
 pub mod logging {
-    use env_logger::Env;
-    use log::LevelFilter;
+    use std::io::stderr;
+
+    use crossterm::tty::IsTty;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
     /// Initialize the logger with the given [LevelFilter]
+    ///
+    /// The filter can be overridden using the `ESPFLASH_LOG` environment
+    /// variable, eg. `ESPFLASH_LOG=espflash::connection=trace`. When debug
+    /// output is enabled, the time spent in each span (syncing, erasing,
+    /// writing each segment, etc.) is logged as the span closes.
     pub fn initialize_logger(filter: LevelFilter) {
-        env_logger::Builder::from_env(Env::default().default_filter_or(filter.as_str()))
-            .format_target(false)
+        let env_filter = EnvFilter::builder()
+            .with_default_directive(filter.into())
+            .with_env_var("ESPFLASH_LOG")
+            .from_env_lossy();
+
+        let span_events = match env_filter.max_level_hint() {
+            Some(level) if level < LevelFilter::DEBUG => FmtSpan::NONE,
+            _ => FmtSpan::CLOSE,
+        };
+
+        tracing_subscriber::fmt()
+            .with_env_filter(env_filter)
+            .with_span_events(span_events)
+            .with_target(false)
+            .with_ansi(stderr().is_tty())
+            .with_writer(stderr)
             .init();
     }
 }
@@ -79,7 +147,7 @@ pub mod logging {
 pub mod update {
     use std::time::Duration;
 
-    use log::info;
+    use tracing::info;
     use update_informer::{registry, Check};
 
     /// Check crates.io for a new version of the application
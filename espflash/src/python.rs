@@ -0,0 +1,452 @@
+//! Python bindings for the flasher, built using [PyO3]
+//!
+//! The `pyespflash` module allows test automation written in Python to flash
+//! and interact with devices using the same code as the command-line
+//! application. It can be built and installed into the current virtual
+//! environment using [maturin]:
+//!
+//! ```bash
+//! $ maturin develop --manifest-path espflash/Cargo.toml
+//! ```
+//!
+//! ```python
+//! import pyespflash
+//!
+//! flasher = pyespflash.Flasher("/dev/ttyUSB0", baud=921600)
+//! flasher.flash_elf(open("app.elf", "rb").read())
+//!
+//! monitor = flasher.monitor()
+//! print(monitor.read_until(b"Hello, world!"))
+//! ```
+//!
+//! [PyO3]: https://pyo3.rs
+//! [maturin]: https://www.maturin.rs
+
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyTimeoutError, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+
+use crate::{
+    connection::reset_after_flash,
+    error::Error,
+    flasher::{
+        app_images, app_segments, parse_apps, ConnectOptions, FlashData, FlashParts, FlashSegment,
+        FlashSize, Flasher, ProgressCallbacks,
+    },
+    interface::{find_port, Interface},
+    targets::Chip,
+};
+
+create_exception!(
+    pyespflash,
+    EspflashError,
+    PyException,
+    "Raised when an operation on the device fails"
+);
+
+fn to_py_err(error: impl Display) -> PyErr {
+    EspflashError::new_err(error.to_string())
+}
+
+/// Convert a timeout in seconds passed from Python, which may be negative, NaN,
+/// or too large to be represented
+fn timeout_from_secs(timeout: f64) -> PyResult<Duration> {
+    if !(0.0..=u32::MAX as f64).contains(&timeout) {
+        return Err(PyValueError::new_err(format!(
+            "Invalid timeout {timeout}, which must be a non-negative number of seconds"
+        )));
+    }
+
+    Ok(Duration::from_secs_f64(timeout))
+}
+
+/// Reports progress to a Python callable, which is called with the address
+/// being written, the number of blocks written so far, and the total number
+/// of blocks
+struct PyProgress<'py> {
+    callback: &'py PyAny,
+    addr: u32,
+    total: usize,
+    error: Option<PyErr>,
+}
+
+impl PyProgress<'_> {
+    fn call(&mut self, current: usize) {
+        // Once the callback has raised, stop calling it; the exception is
+        // re-raised once the operation completes.
+        if self.error.is_none() {
+            if let Err(e) = self.callback.call1((self.addr, current, self.total)) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl ProgressCallbacks for PyProgress<'_> {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        self.call(0);
+    }
+
+    fn update(&mut self, current: usize) {
+        self.call(current);
+    }
+
+    fn finish(&mut self) {
+        self.call(self.total);
+    }
+}
+
+/// Run `f`, reporting its progress to the Python callable `progress` if one was
+/// provided
+fn with_progress(
+    progress: Option<&PyAny>,
+    f: impl FnOnce(Option<&mut dyn ProgressCallbacks>) -> Result<(), Error>,
+) -> PyResult<()> {
+    match progress {
+        Some(callback) => {
+            let mut progress = PyProgress {
+                callback,
+                addr: 0,
+                total: 0,
+                error: None,
+            };
+            let result = f(Some(&mut progress));
+
+            match progress.error {
+                Some(error) => Err(error),
+                None => result.map_err(to_py_err),
+            }
+        }
+        None => f(None).map_err(to_py_err),
+    }
+}
+
+/// A connection to a device in its bootloader
+#[pyclass(name = "Flasher", module = "pyespflash", unsendable)]
+pub struct PyFlasher {
+    flasher: Option<Flasher>,
+    pid: u16,
+}
+
+impl PyFlasher {
+    fn flasher(&mut self) -> PyResult<&mut Flasher> {
+        self.flasher
+            .as_mut()
+            .ok_or_else(|| to_py_err("The flasher has already been converted into a monitor"))
+    }
+}
+
+#[pymethods]
+impl PyFlasher {
+    /// Connect to the device attached to the serial port `port`, changing the
+    /// baud rate to `baud` once connected if provided
+    #[new]
+    #[pyo3(signature = (port, baud = None, use_stub = true))]
+    fn new(port: &str, baud: Option<u32>, use_stub: bool) -> PyResult<Self> {
+        let (port_info, usb_info) = find_port(port);
+        let pid = usb_info.pid;
+
+        let interface = Interface::new(&port_info, None, None, false).map_err(to_py_err)?;
+        let options = ConnectOptions::default().speed(baud).use_stub(use_stub);
+        let flasher = Flasher::connect(interface, usb_info, options).map_err(to_py_err)?;
+
+        Ok(Self {
+            flasher: Some(flasher),
+            pid,
+        })
+    }
+
+    /// The name of the connected chip, eg. `esp32c3`
+    #[getter]
+    fn chip(&mut self) -> PyResult<String> {
+        Ok(self.flasher()?.chip().to_string())
+    }
+
+    /// Information about the connected device, as a dictionary
+    fn device_info<'py>(&mut self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let info = self.flasher()?.device_info().map_err(to_py_err)?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("chip", info.chip.to_string())?;
+        dict.set_item("revision", info.revision)?;
+        dict.set_item("crystal_frequency", info.crystal_frequency)?;
+        dict.set_item("flash_size", info.flash_size.size())?;
+        dict.set_item("features", info.features)?;
+        dict.set_item("embedded_flash", info.embedded_memory.flash)?;
+        dict.set_item(
+            "embedded_psram",
+            info.embedded_memory
+                .psram
+                .map(|psram| (psram.size, psram.kind.to_string())),
+        )?;
+        dict.set_item("mac_address", info.mac_address)?;
+
+        Ok(dict)
+    }
+
+    /// Write an ELF image to flash, along with the default bootloader and
+    /// partition table, and reset the device
+    #[pyo3(signature = (elf, progress = None))]
+    fn flash_elf(&mut self, elf: &[u8], progress: Option<&PyAny>) -> PyResult<()> {
+        let flasher = self.flasher()?;
+        with_progress(progress, |progress| {
+            flasher.load_elf_to_flash(elf, FlashData::default(), progress)
+        })
+    }
+
+    /// Write raw data to flash at the address `addr`
+    #[pyo3(signature = (addr, data, progress = None))]
+    fn write_bin(&mut self, addr: u32, data: &[u8], progress: Option<&PyAny>) -> PyResult<()> {
+        let flasher = self.flasher()?;
+        with_progress(progress, |progress| {
+            flasher.write_bin_to_flash(addr, data, progress)
+        })
+    }
+
+    /// Erase the entire flash
+    fn erase_flash(&mut self) -> PyResult<()> {
+        self.flasher()?.erase_flash().map_err(to_py_err)
+    }
+
+    /// Close the connection to the bootloader, and monitor the application's
+    /// serial output at the baud rate `baud`
+    ///
+    /// The flasher can no longer be used afterwards.
+    #[pyo3(signature = (baud = 115_200))]
+    fn monitor(&mut self, baud: u32) -> PyResult<PyMonitor> {
+        let flasher = self
+            .flasher
+            .take()
+            .ok_or_else(|| to_py_err("The flasher has already been converted into a monitor"))?;
+
+        let mut serial = flasher.into_interface();
+        serial
+            .serial_port_mut()
+            .set_baud_rate(baud)
+            .map_err(to_py_err)?;
+
+        Ok(PyMonitor {
+            serial,
+            pid: self.pid,
+        })
+    }
+}
+
+/// The serial output of an application running on the device
+#[pyclass(name = "Monitor", module = "pyespflash", unsendable)]
+pub struct PyMonitor {
+    serial: Interface,
+    pid: u16,
+}
+
+impl PyMonitor {
+    fn read_into(&mut self, buffer: &mut Vec<u8>, timeout: Duration) -> PyResult<()> {
+        let port = self.serial.serial_port_mut();
+        port.set_timeout(timeout).map_err(to_py_err)?;
+
+        let mut buff = [0; 1024];
+        match port.read(&mut buff) {
+            Ok(count) => buffer.extend_from_slice(&buff[..count]),
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl PyMonitor {
+    /// Read whatever output is available, waiting up to `timeout` seconds for
+    /// some to arrive
+    #[pyo3(signature = (timeout = 1.0))]
+    fn read<'py>(&mut self, py: Python<'py>, timeout: f64) -> PyResult<&'py PyBytes> {
+        let mut buffer = Vec::new();
+        self.read_into(&mut buffer, timeout_from_secs(timeout)?)?;
+
+        Ok(PyBytes::new(py, &buffer))
+    }
+
+    /// Read output until `expected` is received, returning everything read up to
+    /// and including it
+    ///
+    /// Raises `TimeoutError` if `expected` is not received within `timeout`
+    /// seconds, and `ValueError` if `expected` is empty.
+    #[pyo3(signature = (expected, timeout = 10.0))]
+    fn read_until<'py>(
+        &mut self,
+        py: Python<'py>,
+        expected: &[u8],
+        timeout: f64,
+    ) -> PyResult<&'py PyBytes> {
+        if expected.is_empty() {
+            return Err(PyValueError::new_err(
+                "The expected output must not be empty",
+            ));
+        }
+        let deadline = Instant::now() + timeout_from_secs(timeout)?;
+        let mut buffer = Vec::new();
+
+        loop {
+            if let Some(end) = buffer
+                .windows(expected.len())
+                .position(|window| window == expected)
+                .map(|pos| pos + expected.len())
+            {
+                return Ok(PyBytes::new(py, &buffer[..end]));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(PyTimeoutError::new_err(format!(
+                    "Timed out waiting for {:?}",
+                    String::from_utf8_lossy(expected)
+                )));
+            }
+
+            // Allow the wait to be interrupted, eg. by Ctrl-C
+            py.check_signals()?;
+            self.read_into(&mut buffer, remaining.min(Duration::from_millis(100)))?;
+        }
+    }
+
+    /// Write `data` to the device
+    fn write(&mut self, data: &[u8]) -> PyResult<()> {
+        let port = self.serial.serial_port_mut();
+        port.write_all(data)?;
+        port.flush()?;
+
+        Ok(())
+    }
+
+    /// Reset the device
+    fn reset(&mut self) -> PyResult<()> {
+        reset_after_flash(&mut self.serial, self.pid).map_err(to_py_err)
+    }
+}
+
+/// Build the image which would be flashed for the given ELF file or application
+/// binary, as a list of `(address, data)` segments
+///
+/// `flash_size` accepts the same values as the `--flash-size` option, and
+/// defaults to 4MB.
+#[pyfunction]
+#[pyo3(signature = (chip, elf, flash_size = None))]
+fn elf_to_image<'py>(
+    py: Python<'py>,
+    chip: &str,
+    elf: &[u8],
+    flash_size: Option<&str>,
+) -> PyResult<Vec<(u32, &'py PyBytes)>> {
+    let chip = Chip::from_str(chip).map_err(|_| to_py_err(format!("Unknown chip '{chip}'")))?;
+    let flash_size = flash_size
+        .map(FlashSize::from_str)
+        .transpose()
+        .map_err(to_py_err)?;
+    let flash_data = FlashData::builder().flash_size(flash_size).build();
+
+    let apps = [(elf, None)];
+    let elfs = parse_apps(&apps).map_err(to_py_err)?;
+    let images = app_images(chip, &apps, &elfs, &flash_data, None, None).map_err(to_py_err)?;
+    let segments = app_segments(&images, FlashParts::All, flash_size.unwrap_or_default())
+        .and_then(|segments| {
+            segments
+                .into_iter()
+                .map(FlashSegment::load)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(to_py_err)?;
+
+    Ok(segments
+        .iter()
+        .map(|segment| (segment.addr, PyBytes::new(py, &segment.data)))
+        .collect())
+}
+
+/// Flash and interact with Espressif devices
+#[pymodule]
+fn pyespflash(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add("EspflashError", py.get_type::<EspflashError>())?;
+    m.add_class::<PyFlasher>()?;
+    m.add_class::<PyMonitor>()?;
+    m.add_function(wrap_pyfunction!(elf_to_image, m)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::interface::MockTransport;
+
+    fn with_gil<T>(f: impl FnOnce(Python<'_>) -> T) -> T {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(f)
+    }
+
+    fn monitor(mock: &MockTransport) -> PyMonitor {
+        PyMonitor {
+            serial: Interface::from_transport(mock.clone()),
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn test_elf_to_image() {
+        let elf_data = fs::read("tests/resources/esp32c3_hal_blinky_db").unwrap();
+
+        with_gil(|py| {
+            let segments = elf_to_image(py, "esp32c3", &elf_data, Some("4MB")).unwrap();
+            let addrs = segments.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+            assert_eq!(addrs, [0x0, 0x8000, 0x10000]);
+            assert!(segments.iter().all(|(_, data)| !data.as_bytes().is_empty()));
+
+            let err = elf_to_image(py, "esp32c4", &elf_data, None).unwrap_err();
+            assert!(err.is_instance_of::<EspflashError>(py));
+            assert!(elf_to_image(py, "esp32c3", b"not an elf", None).is_err());
+        });
+    }
+
+    #[test]
+    fn test_read_until() {
+        let mock = MockTransport::new();
+        mock.push_raw(b"booting\nHello, world!\nmore");
+        let mut monitor = monitor(&mock);
+
+        with_gil(|py| {
+            let output = monitor.read_until(py, b"world!", 1.0).unwrap();
+            assert_eq!(output.as_bytes(), b"booting\nHello, world!");
+        });
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let mut monitor = monitor(&MockTransport::new());
+
+        with_gil(|py| {
+            for timeout in [-1.0, f64::NAN, f64::INFINITY] {
+                let err = monitor.read(py, timeout).unwrap_err();
+                assert!(err.is_instance_of::<PyValueError>(py));
+                let err = monitor.read_until(py, b"ok", timeout).unwrap_err();
+                assert!(err.is_instance_of::<PyValueError>(py));
+            }
+
+            let err = monitor.read_until(py, b"", 1.0).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+        });
+    }
+}
@@ -0,0 +1,686 @@
+//! Keys for secure boot and flash encryption
+//!
+//! Secure boot V2 signing keys are stored as PKCS#8 PEM files, and may also be
+//! read from the PKCS#1 and SEC1 PEM files produced by `espsecure.py` and
+//! OpenSSL. The digest of a key's public key is what gets burned into the
+//! device's eFuses, allowing it to verify images signed using the key.
+//!
+//! Signed images are padded to a sector boundary and followed by a signature
+//! sector, which contains the signature block and is itself padded to a
+//! sector.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use flate2::Crc;
+use p256::{
+    ecdsa::{
+        self,
+        signature::hazmat::{PrehashSigner, PrehashVerifier},
+    },
+    elliptic_curve::sec1::ToEncodedPoint,
+    FieldBytes, PublicKey, SecretKey,
+};
+use rand_core::{OsRng, RngCore};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, LineEnding},
+    BigUint, Pss, PublicKey as _, PublicKeyParts, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use strum::Display;
+
+#[cfg(feature = "pkcs11")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pkcs11")))]
+pub use self::pkcs11::Pkcs11Signer;
+use crate::{error::Error, image_format::ImageInfo};
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+
+/// Size of RSA secure boot keys, in bits
+const RSA_KEY_BITS: usize = 3072;
+/// Identifier of the P-256 curve within a signature block
+const CURVE_ID_P256: u8 = 2;
+/// Size of a signature block, including its CRC and padding
+const SIGNATURE_BLOCK_SIZE: usize = 1216;
+/// Size of the part of a signature block which its CRC is calculated over
+const SIGNATURE_BLOCK_CRC_OFFSET: usize = 1196;
+const SIGNATURE_BLOCK_MAGIC: u8 = 0xe7;
+const SIGNATURE_VERSION_RSA: u8 = 0x02;
+const SIGNATURE_VERSION_ECDSA: u8 = 0x03;
+/// Signed images and their signature sectors are padded to the sector size
+const SECTOR_SIZE: usize = 0x1000;
+
+/// Signature scheme used by secure boot V2
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[non_exhaustive]
+pub enum SigningScheme {
+    /// RSA-PSS using a 3072 bit key
+    #[strum(serialize = "RSA-3072")]
+    Rsa3072,
+    /// ECDSA using the NIST P-256 curve
+    #[strum(serialize = "ECDSA-P256")]
+    Ecdsa256,
+}
+
+/// A secure boot V2 signing key
+#[derive(Debug, Clone)]
+pub enum SigningKey {
+    Rsa(Box<RsaPrivateKey>),
+    Ecdsa(SecretKey),
+}
+
+impl SigningKey {
+    /// Generate a new random key for the given scheme
+    pub fn generate(scheme: SigningScheme) -> Result<Self, Error> {
+        match scheme {
+            SigningScheme::Rsa3072 => RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)
+                .map(|key| SigningKey::Rsa(Box::new(key)))
+                .map_err(|err| Error::KeyGenerationFailed(err.to_string())),
+            SigningScheme::Ecdsa256 => Ok(SigningKey::Ecdsa(SecretKey::random(&mut OsRng))),
+        }
+    }
+
+    /// Read a key from a PEM file, in PKCS#8, PKCS#1 (RSA) or SEC1 (ECDSA)
+    /// format
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let key = if let Ok(key) = RsaPrivateKey::from_pkcs8_pem(pem) {
+            SigningKey::Rsa(Box::new(key))
+        } else if let Ok(key) = RsaPrivateKey::from_pkcs1_pem(pem) {
+            SigningKey::Rsa(Box::new(key))
+        } else if let Ok(key) = SecretKey::from_pkcs8_pem(pem) {
+            SigningKey::Ecdsa(key)
+        } else if let Ok(key) = SecretKey::from_sec1_pem(pem) {
+            SigningKey::Ecdsa(key)
+        } else {
+            return Err(Error::InvalidSigningKey);
+        };
+
+        if key.scheme().is_none() {
+            return Err(Error::InvalidSigningKey);
+        }
+
+        Ok(key)
+    }
+
+    /// Encode the key as a PKCS#8 PEM file
+    pub fn to_pem(&self) -> Result<String, Error> {
+        let pem = match self {
+            SigningKey::Rsa(key) => key.to_pkcs8_pem(LineEnding::LF),
+            SigningKey::Ecdsa(key) => key.to_pkcs8_pem(LineEnding::LF),
+        };
+
+        pem.map(|pem| pem.to_string())
+            .map_err(|err| Error::KeyGenerationFailed(err.to_string()))
+    }
+
+    /// The signature scheme which the key is used with, if it is supported
+    pub fn scheme(&self) -> Option<SigningScheme> {
+        match self {
+            SigningKey::Rsa(key) if key.size() * 8 == RSA_KEY_BITS => Some(SigningScheme::Rsa3072),
+            SigningKey::Rsa(_) => None,
+            SigningKey::Ecdsa(_) => Some(SigningScheme::Ecdsa256),
+        }
+    }
+
+    /// The public half of the key, which is used to verify signatures
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            SigningKey::Rsa(key) => VerifyingKey::Rsa(key.to_public_key()),
+            SigningKey::Ecdsa(key) => VerifyingKey::Ecdsa(key.public_key()),
+        }
+    }
+
+    /// The public key, in the format in which it is stored in signature blocks
+    pub fn public_key_block(&self) -> Vec<u8> {
+        self.verifying_key().public_key_block()
+    }
+
+    /// The SHA-256 digest of the public key, which is burned into the eFuses
+    pub fn public_key_digest(&self) -> [u8; 32] {
+        self.verifying_key().public_key_digest()
+    }
+}
+
+impl Signer for SigningKey {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying_key()
+    }
+
+    fn sign_digest(&self, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        match self {
+            SigningKey::Rsa(key) => {
+                let padding = Pss::new_with_salt::<Sha256>(32);
+                key.sign_with_rng(&mut OsRng, padding, image_digest)
+                    .map_err(|err| Error::SigningFailed(err.to_string()))
+            }
+            SigningKey::Ecdsa(key) => {
+                let signature: ecdsa::Signature = ecdsa::SigningKey::from(key)
+                    .sign_prehash(image_digest)
+                    .map_err(|err| Error::SigningFailed(err.to_string()))?;
+
+                Ok(signature.to_vec())
+            }
+        }
+    }
+}
+
+/// Signs the digests of images using a secure boot V2 key, which may be held
+/// by an HSM or an external signing service rather than in a file
+pub trait Signer {
+    /// The public half of the signing key
+    fn verifying_key(&self) -> VerifyingKey;
+
+    /// Sign the digest of a padded image
+    ///
+    /// The signature is returned big-endian: the RSA-PSS signature, or the
+    /// `r` and `s` values of the ECDSA signature.
+    fn sign_digest(&self, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error>;
+}
+
+/// Signs digests by running a command, which receives the digest on stdin and
+/// writes the signature to stdout
+///
+/// RSA-PSS signatures must use SHA-256 and a 32 byte salt, as produced by
+/// `openssl pkeyutl -sign -pkeyopt rsa_padding_mode:pss -pkeyopt
+/// rsa_pss_saltlen:32 -pkeyopt digest:sha256`. ECDSA signatures may be either
+/// DER-encoded or the raw `r` and `s` values.
+#[derive(Debug, Clone)]
+pub struct ExternalSigner {
+    command: String,
+    key: VerifyingKey,
+}
+
+impl ExternalSigner {
+    /// Sign using the given shell command, whose key has the given public key
+    pub fn new(command: impl Into<String>, key: VerifyingKey) -> Self {
+        Self {
+            command: command.into(),
+            key,
+        }
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.key.clone()
+    }
+
+    fn sign_digest(&self, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+
+        let failed = |reason: String| {
+            Error::SigningFailed(format!("the signer command '{}' {reason}", self.command))
+        };
+        let mut child = Command::new(shell)
+            .arg(flag)
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| failed(format!("could not be run: {err}")))?;
+
+        // Dropping stdin closes it, so that the command sees the end of the digest
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(image_digest)
+                .map_err(|err| failed(format!("did not read the digest: {err}")))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|err| failed(format!("failed: {err}")))?;
+        if !output.status.success() {
+            return Err(failed(format!("exited with {}", output.status)));
+        }
+
+        let signature = output.stdout;
+        match self.key {
+            VerifyingKey::Ecdsa(_) if signature.len() != 64 => {
+                ecdsa::Signature::from_der(&signature)
+                    .map(|signature| signature.to_vec())
+                    .map_err(|_| failed("returned a malformed ECDSA signature".into()))
+            }
+            _ => Ok(signature),
+        }
+    }
+}
+
+/// A secure boot V2 public key, used to verify signatures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyingKey {
+    Rsa(RsaPublicKey),
+    Ecdsa(PublicKey),
+}
+
+impl VerifyingKey {
+    /// Read a public key from a PEM file, in PKCS#8 or PKCS#1 (RSA) format
+    ///
+    /// The public half of a private key is also accepted.
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let key = if let Ok(key) = RsaPublicKey::from_public_key_pem(pem) {
+            VerifyingKey::Rsa(key)
+        } else if let Ok(key) = RsaPublicKey::from_pkcs1_pem(pem) {
+            VerifyingKey::Rsa(key)
+        } else if let Ok(key) = PublicKey::from_public_key_pem(pem) {
+            VerifyingKey::Ecdsa(key)
+        } else {
+            return SigningKey::from_pem(pem).map(|key| key.verifying_key());
+        };
+
+        if let VerifyingKey::Rsa(key) = &key {
+            if key.size() * 8 != RSA_KEY_BITS {
+                return Err(Error::InvalidSigningKey);
+            }
+        }
+
+        Ok(key)
+    }
+
+    /// Read a public key from a signature block
+    fn from_block(version: u8, block: &[u8]) -> Option<Self> {
+        match version {
+            SIGNATURE_VERSION_RSA => {
+                let n = BigUint::from_bytes_le(&block[..RSA_KEY_BITS / 8]);
+                let e = BigUint::from_bytes_le(&block[RSA_KEY_BITS / 8..][..4]);
+                RsaPublicKey::new(n, e).ok().map(VerifyingKey::Rsa)
+            }
+            SIGNATURE_VERSION_ECDSA if block[0] == CURVE_ID_P256 => {
+                let mut point = vec![0x04];
+                point.extend(block[1..33].iter().rev());
+                point.extend(block[33..65].iter().rev());
+                PublicKey::from_sec1_bytes(&point)
+                    .ok()
+                    .map(VerifyingKey::Ecdsa)
+            }
+            _ => None,
+        }
+    }
+
+    /// The public key, in the format in which it is stored in signature blocks
+    ///
+    /// RSA keys are stored as their modulus and exponent, along with the
+    /// precomputed Montgomery multiplication parameters, all little-endian.
+    /// ECDSA keys are stored as the curve's ID followed by the coordinates of
+    /// the public point, also little-endian.
+    pub fn public_key_block(&self) -> Vec<u8> {
+        match self {
+            VerifyingKey::Rsa(key) => {
+                let n = key.n();
+                let rinv = (BigUint::from(1u8) << (RSA_KEY_BITS * 2)) % n;
+
+                // -n^-1 mod 2^32, found using Newton's method on the lowest word
+                let n0 = le_bytes(n, 4);
+                let n0 = u32::from_le_bytes([n0[0], n0[1], n0[2], n0[3]]);
+                let mut inverse = 1u32;
+                for _ in 0..5 {
+                    inverse = inverse.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inverse)));
+                }
+
+                let e = le_bytes(key.e(), 4);
+                let mut block = le_bytes(n, RSA_KEY_BITS / 8);
+                block.extend_from_slice(&e);
+                block.extend(le_bytes(&rinv, RSA_KEY_BITS / 8));
+                block.extend_from_slice(&inverse.wrapping_neg().to_le_bytes());
+
+                block
+            }
+            VerifyingKey::Ecdsa(key) => {
+                let point = key.to_encoded_point(false);
+                let mut block = vec![CURVE_ID_P256];
+                for coordinate in [point.x(), point.y()].into_iter().flatten() {
+                    block.extend(coordinate.iter().rev());
+                }
+
+                block
+            }
+        }
+    }
+
+    /// The SHA-256 digest of the public key, which is burned into the eFuses
+    pub fn public_key_digest(&self) -> [u8; 32] {
+        Sha256::digest(self.public_key_block()).into()
+    }
+
+    /// Check a signature, stored little-endian as in signature blocks, of an
+    /// image's digest
+    fn verify(&self, image_digest: &[u8], signature: &[u8]) -> bool {
+        match self {
+            VerifyingKey::Rsa(key) => {
+                let signature = signature.iter().rev().copied().collect::<Vec<_>>();
+                let padding = Pss::new_with_salt::<Sha256>(32);
+                key.verify(padding, image_digest, &signature).is_ok()
+            }
+            VerifyingKey::Ecdsa(key) => {
+                let r = signature[..32].iter().rev().copied().collect::<Vec<_>>();
+                let s = signature[32..64].iter().rev().copied().collect::<Vec<_>>();
+                let Ok(signature) = ecdsa::Signature::from_scalars(
+                    *FieldBytes::from_slice(&r),
+                    *FieldBytes::from_slice(&s),
+                ) else {
+                    return false;
+                };
+
+                ecdsa::VerifyingKey::from(key)
+                    .verify_prehash(image_digest, &signature)
+                    .is_ok()
+            }
+        }
+    }
+}
+
+/// Create the signature block for the digest of a padded image
+///
+/// The signature is checked against the signer's public key, so that a
+/// misconfigured signer can't produce an image which fails to boot.
+pub fn signature_block(signer: &dyn Signer, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+    let key = signer.verifying_key();
+    let signature = signer.sign_digest(image_digest)?;
+
+    // Signatures are stored little-endian, like the public keys
+    let (version, signature) = match &key {
+        VerifyingKey::Rsa(_) if signature.len() == RSA_KEY_BITS / 8 => (
+            SIGNATURE_VERSION_RSA,
+            signature.iter().rev().copied().collect::<Vec<_>>(),
+        ),
+        VerifyingKey::Ecdsa(_) if signature.len() == 64 => {
+            let (r, s) = signature.split_at(32);
+            (
+                SIGNATURE_VERSION_ECDSA,
+                r.iter().rev().chain(s.iter().rev()).copied().collect(),
+            )
+        }
+        _ => {
+            return Err(Error::SigningFailed(format!(
+                "the signature is {} bytes long, which is invalid for the key",
+                signature.len()
+            )))
+        }
+    };
+    if !key.verify(image_digest, &signature) {
+        return Err(Error::SigningFailed(
+            "the signature does not match the public key".into(),
+        ));
+    }
+
+    let mut block = vec![SIGNATURE_BLOCK_MAGIC, version, 0, 0];
+    block.extend_from_slice(image_digest);
+    block.extend(key.public_key_block());
+    block.extend(signature);
+
+    block.resize(SIGNATURE_BLOCK_CRC_OFFSET, 0);
+    let mut crc = Crc::new();
+    crc.update(&block);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.resize(SIGNATURE_BLOCK_SIZE, 0);
+
+    Ok(block)
+}
+
+/// Sign an application or bootloader image for secure boot V2
+///
+/// The image's checksum and appended digest are verified before it is padded
+/// to a sector boundary. Returns the padded image, and the signature sector
+/// which follows it in the signed image.
+pub fn sign_image(image: &[u8], signer: &dyn Signer) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    if is_signed(image) {
+        return Err(Error::UnsignableImage("it is already signed"));
+    }
+
+    let info = ImageInfo::parse(image, None)?;
+    if !info.checksum_valid() {
+        return Err(Error::UnsignableImage("its checksum is invalid"));
+    }
+    match info.digest_valid() {
+        Some(true) => {}
+        Some(false) => return Err(Error::UnsignableImage("its appended digest is invalid")),
+        None => return Err(Error::UnsignableImage("it has no appended digest")),
+    }
+
+    let mut padded = image.to_vec();
+    padded.resize(
+        (image.len() + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE,
+        0xff,
+    );
+
+    let mut sector = signature_block(signer, &Sha256::digest(&padded).into())?;
+    sector.resize(SECTOR_SIZE, 0xff);
+
+    Ok((padded, sector))
+}
+
+/// Verify the signature blocks of a signed image
+///
+/// Returns the public key of each signature block, all of which contain a
+/// valid signature of the image. Fails if the image is not signed, or if any
+/// of its signature blocks is invalid.
+pub fn verify_signatures(image: &[u8]) -> Result<Vec<VerifyingKey>, Error> {
+    if !is_signed(image) {
+        return Err(Error::InvalidSignature("the image is not signed"));
+    }
+
+    let (signed, sector) = image.split_at(image.len() - SECTOR_SIZE);
+    let image_digest = Sha256::digest(signed);
+
+    let mut keys = Vec::new();
+    for block in sector.chunks_exact(SIGNATURE_BLOCK_SIZE) {
+        if block[0] != SIGNATURE_BLOCK_MAGIC {
+            break;
+        }
+
+        let mut crc = Crc::new();
+        crc.update(&block[..SIGNATURE_BLOCK_CRC_OFFSET]);
+        if block[SIGNATURE_BLOCK_CRC_OFFSET..][..4] != crc.sum().to_le_bytes() {
+            return Err(Error::InvalidSignature(
+                "a signature block's CRC is invalid",
+            ));
+        }
+        if block[4..36] != image_digest[..] {
+            return Err(Error::InvalidSignature(
+                "a signature block's image digest does not match the image",
+            ));
+        }
+
+        let (key_len, signature_len) = match block[1] {
+            SIGNATURE_VERSION_RSA => (776, RSA_KEY_BITS / 8),
+            _ => (65, 64),
+        };
+        let key = VerifyingKey::from_block(block[1], &block[36..][..key_len]).ok_or(
+            Error::InvalidSignature("a signature block's public key is invalid"),
+        )?;
+        if !key.verify(&block[4..36], &block[36 + key_len..][..signature_len]) {
+            return Err(Error::InvalidSignature(
+                "a signature does not match the image",
+            ));
+        }
+
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+/// Does the image end with a signature sector?
+///
+/// Only the presence of the first signature block is checked, so that corrupt
+/// signatures are reported by [verify_signatures] rather than ignored.
+pub fn is_signed(image: &[u8]) -> bool {
+    if image.len() < SECTOR_SIZE || image.len() % SECTOR_SIZE != 0 {
+        return false;
+    }
+
+    let block = &image[image.len() - SECTOR_SIZE..];
+    block[0] == SIGNATURE_BLOCK_MAGIC
+        && matches!(block[1], SIGNATURE_VERSION_RSA | SIGNATURE_VERSION_ECDSA)
+}
+
+/// Length of a flash encryption key
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeyLength {
+    /// 256 bits, for AES-256 on the ESP32 or XTS-AES-128 on later chips
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "256"))]
+    _256,
+    /// 512 bits, for XTS-AES-256
+    #[cfg_attr(feature = "cli", value(name = "512"))]
+    _512,
+}
+
+/// Generate a random flash encryption key
+pub fn generate_flash_encryption_key(length: KeyLength) -> Vec<u8> {
+    let mut key = match length {
+        KeyLength::_256 => vec![0; 32],
+        KeyLength::_512 => vec![0; 64],
+    };
+    OsRng.fill_bytes(&mut key);
+
+    key
+}
+
+/// The little-endian bytes of a number, zero-padded to the given length
+fn le_bytes(value: &BigUint, len: usize) -> Vec<u8> {
+    let mut bytes = value.to_bytes_le();
+    bytes.resize(len, 0);
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_ecdsa_public_key_block() {
+        let key = SigningKey::generate(SigningScheme::Ecdsa256).unwrap();
+        let block = key.public_key_block();
+        assert_eq!(block.len(), 65);
+        assert_eq!(block[0], CURVE_ID_P256);
+
+        let key = SigningKey::from_pem(&key.to_pem().unwrap()).unwrap();
+        assert_eq!(key.scheme(), Some(SigningScheme::Ecdsa256));
+        assert_eq!(key.public_key_block(), block);
+    }
+
+    #[test]
+    fn test_rsa_public_key_block() {
+        // A test key, in the PKCS#1 format written by `espsecure.py
+        // generate_signing_key`, and its public key block calculated in the same
+        // way as `espsecure.py`'s `_get_sbv2_rsa_pub_key_blocks`
+        let pem = fs::read_to_string("tests/resources/secure_boot_rsa3072.pem").unwrap();
+        let expected =
+            fs::read("tests/resources/secure_boot_rsa3072_public_key_block.bin").unwrap();
+
+        let key = SigningKey::from_pem(&pem).unwrap();
+        assert_eq!(key.scheme(), Some(SigningScheme::Rsa3072));
+        assert_eq!(key.public_key_block(), expected);
+        assert_eq!(
+            key.public_key_digest(),
+            [
+                0x27, 0xdf, 0xcb, 0xb0, 0x69, 0xed, 0x7d, 0xcd, 0x1b, 0x81, 0xcc, 0xb4, 0x18, 0xc5,
+                0x19, 0xd4, 0x3c, 0x7b, 0x56, 0x56, 0x45, 0x12, 0xe3, 0xbd, 0xf5, 0xd8, 0x68, 0x10,
+                0x28, 0x89, 0x83, 0xd4,
+            ]
+        );
+
+        // -n^-1 mod 2^32, used for Montgomery multiplication
+        assert_eq!(expected[772..], 0x7111_d3b1_u32.to_le_bytes());
+
+        // A signature made by OpenSSL, using PSS with SHA-256 and a 32 byte salt
+        let digest = Sha256::digest(b"espflash secure boot known answer");
+        let mut signature = fs::read("tests/resources/secure_boot_rsa3072_signature.bin").unwrap();
+        signature.reverse();
+        assert!(key.verifying_key().verify(&digest, &signature));
+        signature[0] ^= 1;
+        assert!(!key.verifying_key().verify(&digest, &signature));
+    }
+
+    #[test]
+    fn test_sign_image() {
+        let image = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let key = SigningKey::generate(SigningScheme::Ecdsa256).unwrap();
+
+        let (mut signed, sector) = sign_image(&image, &key).unwrap();
+        assert_eq!(signed.len() % SECTOR_SIZE, 0);
+        assert_eq!(sector.len(), SECTOR_SIZE);
+        assert_eq!(
+            sector[0..2],
+            [SIGNATURE_BLOCK_MAGIC, SIGNATURE_VERSION_ECDSA]
+        );
+        assert_eq!(sector[4..36], Sha256::digest(&signed)[..]);
+
+        signed.extend(sector);
+        assert!(is_signed(&signed));
+        assert!(!is_signed(&image));
+        assert!(matches!(
+            sign_image(&signed, &key),
+            Err(Error::UnsignableImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let image = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let key = SigningKey::generate(SigningScheme::Ecdsa256).unwrap();
+
+        let (mut signed, sector) = sign_image(&image, &key).unwrap();
+        signed.extend(sector);
+        assert_eq!(verify_signatures(&signed).unwrap(), [key.verifying_key()]);
+
+        let pem = fs::read_to_string("tests/resources/secure_boot_rsa3072.pem").unwrap();
+        let rsa_key = SigningKey::from_pem(&pem).unwrap();
+        let (mut rsa_signed, sector) = sign_image(&image, &rsa_key).unwrap();
+        assert_eq!(sector[0..2], [SIGNATURE_BLOCK_MAGIC, SIGNATURE_VERSION_RSA]);
+        rsa_signed.extend(sector);
+        assert_eq!(
+            verify_signatures(&rsa_signed).unwrap(),
+            [rsa_key.verifying_key()]
+        );
+
+        // Changing the image invalidates the signature
+        signed[0x100] ^= 1;
+        assert!(matches!(
+            verify_signatures(&signed),
+            Err(Error::InvalidSignature(_))
+        ));
+        assert!(verify_signatures(&image).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_signer() {
+        // Signs with one key while claiming to use another
+        struct Mismatched(SigningKey, VerifyingKey);
+
+        impl Signer for Mismatched {
+            fn verifying_key(&self) -> VerifyingKey {
+                self.1.clone()
+            }
+
+            fn sign_digest(&self, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+                self.0.sign_digest(image_digest)
+            }
+        }
+
+        let image = fs::read("tests/resources/esp32_hal_blinky.bin").unwrap();
+        let other = SigningKey::generate(SigningScheme::Ecdsa256).unwrap();
+        let signer = Mismatched(
+            SigningKey::generate(SigningScheme::Ecdsa256).unwrap(),
+            other.verifying_key(),
+        );
+
+        assert!(matches!(
+            sign_image(&image, &signer),
+            Err(Error::SigningFailed(_))
+        ));
+    }
+}
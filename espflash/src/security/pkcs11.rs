@@ -0,0 +1,281 @@
+//! Signing using keys held by a PKCS#11 token, such as an HSM
+//!
+//! Keys are referenced using PKCS#11 URIs (RFC 7512), eg.
+//! `pkcs11:token=release;object=secure-boot?module-path=/usr/lib/softhsm/libsofthsm2.so`.
+//! The module may instead be given using the `ESPFLASH_PKCS11_MODULE`
+//! environment variable, and the PIN using `ESPFLASH_PKCS11_PIN`, so that it
+//! doesn't appear on the command line.
+
+use std::{env, fs};
+
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    mechanism::{
+        rsa::{PkcsMgfType, PkcsPssParams},
+        Mechanism, MechanismType,
+    },
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use p256::PublicKey;
+use rsa::{BigUint, RsaPublicKey};
+
+use super::{Signer, VerifyingKey};
+use crate::error::Error;
+
+/// A key on a PKCS#11 token, as referenced by a PKCS#11 URI
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Pkcs11Uri {
+    token: Option<String>,
+    object: Option<String>,
+    id: Option<Vec<u8>>,
+    module_path: Option<String>,
+    pin: Option<String>,
+}
+
+impl Pkcs11Uri {
+    fn parse(uri: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidPkcs11Uri(format!("{uri}: {reason}"));
+        let rest = uri
+            .strip_prefix("pkcs11:")
+            .ok_or_else(|| invalid("the URI must begin with 'pkcs11:'"))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let path_attributes = path.split(';').map(|attribute| (attribute, true));
+        let query_attributes = query.split('&').map(|attribute| (attribute, false));
+
+        let mut parsed = Self::default();
+        for (attribute, in_path) in path_attributes.chain(query_attributes) {
+            if attribute.is_empty() {
+                continue;
+            }
+            let (name, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| invalid("attributes must be of the form 'name=value'"))?;
+            let value = percent_decode(value).ok_or_else(|| invalid("invalid percent-encoding"))?;
+            let text = || String::from_utf8(value.clone()).map_err(|_| invalid("invalid UTF-8"));
+
+            match (name, in_path) {
+                ("token", true) => parsed.token = Some(text()?),
+                ("object", true) => parsed.object = Some(text()?),
+                ("id", true) => parsed.id = Some(value),
+                ("module-path", false) => parsed.module_path = Some(text()?),
+                ("pin-value", false) => parsed.pin = Some(text()?),
+                ("pin-source", false) => {
+                    let source = text()?;
+                    let path = source.strip_prefix("file:").unwrap_or(&source);
+                    let pin = fs::read_to_string(path)
+                        .map_err(|err| invalid(&format!("failed to read the PIN: {err}")))?;
+                    parsed.pin = Some(pin.trim_end().to_string());
+                }
+                // Other attributes only narrow down the token, which is rarely needed
+                _ => {}
+            }
+        }
+
+        if parsed.object.is_none() && parsed.id.is_none() {
+            return Err(invalid("the key must be selected using 'object' or 'id'"));
+        }
+
+        Ok(parsed)
+    }
+
+    /// Attributes matching the key's objects of the given class
+    fn template(&self, class: ObjectClass) -> Vec<Attribute> {
+        let mut template = vec![Attribute::Class(class)];
+        if let Some(object) = &self.object {
+            template.push(Attribute::Label(object.as_bytes().to_vec()));
+        }
+        if let Some(id) = &self.id {
+            template.push(Attribute::Id(id.clone()));
+        }
+
+        template
+    }
+}
+
+/// Decode the `%XX` escapes in a URI component
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Signs digests using a private key held by a PKCS#11 token
+pub struct Pkcs11Signer {
+    session: Session,
+    private_key: ObjectHandle,
+    key: VerifyingKey,
+}
+
+impl Pkcs11Signer {
+    /// Open a session with the token containing the key referenced by a
+    /// PKCS#11 URI, logging in if a PIN is given
+    pub fn open(uri: &str) -> Result<Self, Error> {
+        let uri = Pkcs11Uri::parse(uri)?;
+        let failed = |err: cryptoki::error::Error| Error::SigningFailed(format!("PKCS#11: {err}"));
+
+        let module = uri
+            .module_path
+            .clone()
+            .or_else(|| env::var("ESPFLASH_PKCS11_MODULE").ok())
+            .ok_or_else(|| {
+                Error::SigningFailed(
+                    "PKCS#11: no module was given using 'module-path' or ESPFLASH_PKCS11_MODULE"
+                        .into(),
+                )
+            })?;
+        let pkcs11 = Pkcs11::new(module).map_err(failed)?;
+        pkcs11
+            .initialize(CInitializeArgs::OsThreads)
+            .map_err(failed)?;
+
+        let mut slot = None;
+        for candidate in pkcs11.get_slots_with_token().map_err(failed)? {
+            let info = pkcs11.get_token_info(candidate).map_err(failed)?;
+            if uri
+                .token
+                .as_deref()
+                .map_or(true, |token| info.label().trim() == token)
+            {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot =
+            slot.ok_or_else(|| Error::SigningFailed("PKCS#11: no token was found".into()))?;
+
+        let session = pkcs11.open_ro_session(slot).map_err(failed)?;
+        let pin = uri
+            .pin
+            .clone()
+            .or_else(|| env::var("ESPFLASH_PKCS11_PIN").ok());
+        if let Some(pin) = pin {
+            session
+                .login(UserType::User, Some(&AuthPin::new(pin)))
+                .map_err(failed)?;
+        }
+
+        let find = |class: ObjectClass| -> Result<ObjectHandle, Error> {
+            let objects = session.find_objects(&uri.template(class)).map_err(failed)?;
+            match objects[..] {
+                [object] => Ok(object),
+                [] => Err(Error::SigningFailed(
+                    "PKCS#11: the key was not found on the token".into(),
+                )),
+                _ => Err(Error::SigningFailed(
+                    "PKCS#11: several keys on the token match the URI".into(),
+                )),
+            }
+        };
+        let private_key = find(ObjectClass::PRIVATE_KEY)?;
+        let public_key = find(ObjectClass::PUBLIC_KEY)?;
+
+        let attributes = session
+            .get_attributes(
+                public_key,
+                &[
+                    AttributeType::KeyType,
+                    AttributeType::Modulus,
+                    AttributeType::PublicExponent,
+                    AttributeType::EcPoint,
+                ],
+            )
+            .map_err(failed)?;
+
+        let (mut key_type, mut modulus, mut exponent, mut point) = (None, None, None, None);
+        for attribute in attributes {
+            match attribute {
+                Attribute::KeyType(value) => key_type = Some(value),
+                Attribute::Modulus(value) => modulus = Some(value),
+                Attribute::PublicExponent(value) => exponent = Some(value),
+                Attribute::EcPoint(value) => point = Some(value),
+                _ => {}
+            }
+        }
+
+        let key = match (key_type, modulus, exponent, point) {
+            (Some(KeyType::RSA), Some(n), Some(e), _) => {
+                RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e))
+                    .ok()
+                    .map(VerifyingKey::Rsa)
+            }
+            (Some(KeyType::EC), _, _, Some(point)) => {
+                // The point is usually wrapped in a DER OCTET STRING
+                let point = match point[..] {
+                    [0x04, 0x41, ..] if point.len() == 67 => &point[2..],
+                    _ => &point[..],
+                };
+                PublicKey::from_sec1_bytes(point)
+                    .ok()
+                    .map(VerifyingKey::Ecdsa)
+            }
+            _ => None,
+        }
+        .ok_or(Error::InvalidSigningKey)?;
+
+        Ok(Self {
+            session,
+            private_key,
+            key,
+        })
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.key.clone()
+    }
+
+    fn sign_digest(&self, image_digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let mechanism = match self.key {
+            VerifyingKey::Rsa(_) => Mechanism::RsaPkcsPss(PkcsPssParams {
+                hash_alg: MechanismType::SHA256,
+                mgf: PkcsMgfType::MGF1_SHA256,
+                s_len: 32.into(),
+            }),
+            VerifyingKey::Ecdsa(_) => Mechanism::Ecdsa,
+        };
+
+        self.session
+            .sign(&mechanism, self.private_key, image_digest)
+            .map_err(|err| Error::SigningFailed(format!("PKCS#11: {err}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=release;object=secure%20boot;id=%01%02?module-path=/usr/lib/p11.so&\
+             pin-value=1234",
+        )
+        .unwrap();
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                token: Some("release".into()),
+                object: Some("secure boot".into()),
+                id: Some(vec![1, 2]),
+                module_path: Some("/usr/lib/p11.so".into()),
+                pin: Some("1234".into()),
+            }
+        );
+
+        assert!(Pkcs11Uri::parse("pkcs11:token=release").is_err());
+        assert!(Pkcs11Uri::parse("file:key.pem").is_err());
+    }
+}
@@ -1,21 +1,30 @@
-use std::ops::Range;
-
-use esp_idf_part::PartitionTable;
-
-use super::{bytes_to_mac_addr, Chip, Esp32Params, ReadEFuse, SpiRegisters, Target};
+use super::{
+    bytes_to_mac_addr, normalize_xtal_freq, Chip, EmbeddedMemory, Esp32Params, MemoryKind,
+    MemoryRegion, Psram, PsramKind, ReadEFuse, SpiRegisters, Target,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::{Error, UnsupportedImageFormatError},
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::FlashData,
+    image_format::{build_custom_image, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x00f0_1d83];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x400d_0000..0x4040_0000, // IROM
-    0x3f40_0000..0x3f80_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x3f40_0000..0x3f80_0000),
+    MemoryRegion::new(
+        "EXTRAM_DATA",
+        MemoryKind::ExternalRam,
+        0x3f80_0000..0x3fc0_0000,
+    ),
+    MemoryRegion::new("RTC_DRAM", MemoryKind::RtcFast, 0x3ff8_0000..0x3ff8_2000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3ffa_e000..0x4000_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4008_0000..0x400a_0000),
+    MemoryRegion::new("RTC_IRAM", MemoryKind::RtcFast, 0x400c_0000..0x400c_2000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x400d_0000..0x4040_0000),
+    MemoryRegion::new("RTC_DATA", MemoryKind::RtcSlow, 0x5000_0000..0x5000_2000),
 ];
 
 const PARAMS: Esp32Params = Esp32Params::new(
@@ -56,8 +65,8 @@ impl ReadEFuse for Esp32 {
 }
 
 impl Target for Esp32 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
     fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
@@ -118,6 +127,17 @@ impl Target for Esp32 {
         Ok(features)
     }
 
+    fn embedded_memory(&self, connection: &mut Connection) -> Result<EmbeddedMemory, Error> {
+        // The embedded flash is not reported, as it is not connected to the default
+        // SPI pins and so must still be detected
+        let psram = (self.package_version(connection)? == 6).then_some(Psram {
+            size: 2,
+            kind: PsramKind::Quad,
+        });
+
+        Ok(EmbeddedMemory { flash: None, psram })
+    }
+
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         let apb_ctl_date = connection.read_reg(0x3FF6_607C)?;
 
@@ -142,21 +162,16 @@ impl Target for Esp32 {
     fn crystal_freq(&self, connection: &mut Connection) -> Result<u32, Error> {
         let uart_div = connection.read_reg(UART_CLKDIV_REG)? & UART_CLKDIV_MASK;
         let est_xtal = (connection.get_baud()? * uart_div) / 1_000_000 / XTAL_CLK_DIVIDER;
-        let norm_xtal = if est_xtal > 33 { 40 } else { 26 };
 
-        Ok(norm_xtal)
+        Ok(normalize_xtal_freq(est_xtal))
     }
 
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -165,16 +180,28 @@ impl Target for Esp32 {
                 image,
                 Chip::Esp32,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32, flash_data)
+            }
             _ => Err(UnsupportedImageFormatError::new(image_format, Chip::Esp32, None).into()),
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     fn mac_address(&self, connection: &mut Connection) -> Result<String, Error> {
         let word1 = self.read_efuse(connection, 1)?;
         let word2 = self.read_efuse(connection, 2)?;
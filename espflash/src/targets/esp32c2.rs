@@ -1,14 +1,17 @@
-use std::{collections::HashMap, ops::Range};
+use std::collections::HashMap;
 
-use esp_idf_part::PartitionTable;
-
-use super::{bytes_to_mac_addr, Chip, Esp32Params, ReadEFuse, SpiRegisters, Target};
+use super::{
+    bytes_to_mac_addr, normalize_xtal_freq, Chip, Esp32Params, MemoryKind, MemoryRegion, ReadEFuse,
+    SpiRegisters, Target,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::{FlashData, FlashFrequency},
+    image_format::{
+        build_custom_image, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
@@ -16,9 +19,11 @@ const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
     0x7c41_a06f, // ECO1
 ];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x4200_0000..0x4240_0000, // IROM
-    0x3c00_0000..0x3c40_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x3c00_0000..0x3c40_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3fca_0000..0x3fce_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4037_c000..0x403c_0000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4200_0000..0x4240_0000),
 ];
 
 const PARAMS: Esp32Params = Esp32Params::new(
@@ -50,8 +55,8 @@ impl ReadEFuse for Esp32c2 {
 }
 
 impl Target for Esp32c2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
     fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
@@ -69,9 +74,8 @@ impl Target for Esp32c2 {
     fn crystal_freq(&self, connection: &mut Connection) -> Result<u32, Error> {
         let uart_div = connection.read_reg(UART_CLKDIV_REG)? & UART_CLKDIV_MASK;
         let est_xtal = (connection.get_baud()? * uart_div) / 1_000_000 / XTAL_CLK_DIVIDER;
-        let norm_xtal = if est_xtal > 33 { 40 } else { 26 };
 
-        Ok(norm_xtal)
+        Ok(normalize_xtal_freq(est_xtal))
     }
 
     fn flash_frequency_encodings(&self) -> HashMap<FlashFrequency, u8> {
@@ -85,13 +89,9 @@ impl Target for Esp32c2 {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -100,16 +100,28 @@ impl Target for Esp32c2 {
                 image,
                 Chip::Esp32c2,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
             ImageFormatKind::DirectBoot => Ok(Box::new(DirectBootFormat::new(image, 0)?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32c2, flash_data)
+            }
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32c2,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     /// What is the MAC address?
     fn mac_address(&self, connection: &mut Connection) -> Result<String, Error> {
         let word5 = self.read_efuse(connection, 16)?;
@@ -1,14 +1,15 @@
-use std::ops::Range;
-
-use esp_idf_part::PartitionTable;
-
-use super::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target};
+use super::{
+    Chip, EFuseController, EmbeddedMemory, Esp32Params, MemoryKind, MemoryRegion, ReadEFuse,
+    SpiRegisters, Target, WatchdogRegisters,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::{Error, UnsupportedImageFormatError},
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::FlashData,
+    image_format::{
+        build_custom_image, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
@@ -16,9 +17,12 @@ const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
     0x1b31_506f, // ECO3
 ];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x4200_0000..0x4280_0000, // IROM
-    0x3c00_0000..0x3c80_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x3c00_0000..0x3c80_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3fc8_0000..0x3fce_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4037_c000..0x403e_0000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4200_0000..0x4280_0000),
+    MemoryRegion::new("RTC_RAM", MemoryKind::RtcFast, 0x5000_0000..0x5000_2000),
 ];
 
 const PARAMS: Esp32Params = Esp32Params::new(
@@ -36,6 +40,10 @@ impl Esp32c3 {
     pub fn has_magic_value(value: u32) -> bool {
         CHIP_DETECT_MAGIC_VALUES.contains(&value)
     }
+
+    fn flash_cap(&self, connection: &mut Connection) -> Result<u32, Error> {
+        Ok(self.read_efuse(connection, 20)? >> 27 & 0x7)
+    }
 }
 
 impl ReadEFuse for Esp32c3 {
@@ -45,12 +53,34 @@ impl ReadEFuse for Esp32c3 {
 }
 
 impl Target for Esp32c3 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
+    }
+
+    fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
+        let mut features = vec!["WiFi", "BLE"];
+
+        match self.embedded_memory(connection)?.flash {
+            Some(8) => features.push("Embedded Flash 8MB"),
+            Some(4) => features.push("Embedded Flash 4MB"),
+            Some(2) => features.push("Embedded Flash 2MB"),
+            Some(1) => features.push("Embedded Flash 1MB"),
+            _ => {}
+        }
+
+        Ok(features)
     }
 
-    fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
-        Ok(vec!["WiFi", "BLE"])
+    fn embedded_memory(&self, connection: &mut Connection) -> Result<EmbeddedMemory, Error> {
+        let flash = match self.flash_cap(connection)? {
+            1 => Some(4),
+            2 => Some(2),
+            3 => Some(1),
+            4 => Some(8),
+            _ => None,
+        };
+
+        Ok(EmbeddedMemory { flash, psram: None })
     }
 
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
@@ -72,13 +102,9 @@ impl Target for Esp32c3 {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -87,15 +113,14 @@ impl Target for Esp32c3 {
                 image,
                 Chip::Esp32c3,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
             (ImageFormatKind::DirectBoot, None | Some((_, 3..))) => {
                 Ok(Box::new(DirectBootFormat::new(image, 0)?))
             }
+            (ImageFormatKind::Custom(name), _) => {
+                build_custom_image(name, image, Chip::Esp32c3, flash_data)
+            }
             _ => Err(
                 UnsupportedImageFormatError::new(image_format, Chip::Esp32c3, chip_revision)
                     .with_context(format!(
@@ -107,6 +132,19 @@ impl Target for Esp32c3 {
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32c3,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x6000_2000,
@@ -119,6 +157,31 @@ impl Target for Esp32c3 {
         }
     }
 
+    fn watchdog_registers(&self) -> Option<WatchdogRegisters> {
+        Some(WatchdogRegisters {
+            wdt_config0: 0x6000_8090,
+            wdt_wprotect: 0x6000_80a8,
+            swd_conf: 0x6000_80ac,
+            swd_wprotect: 0x6000_80b0,
+            swd_wkey: 0x8f1d_312a,
+            swd_auto_feed_en: 1 << 31,
+        })
+    }
+
+    fn efuse_controller(&self) -> Option<EFuseController> {
+        Some(EFuseController {
+            base: 0x6000_8800,
+            rd_blk0_offset: 0x2c,
+            conf_offset: 0x1cc,
+            cmd_offset: 0x1d4,
+            dac_conf_offset: 0x1e8,
+            wr_tim_conf1_offset: 0x1f4,
+            wr_tim_conf2_offset: 0x1f8,
+            secure_version_word: 5,
+            secure_version_len: 16,
+        })
+    }
+
     fn supported_image_formats(&self) -> &[ImageFormatKind] {
         &[ImageFormatKind::EspBootloader, ImageFormatKind::DirectBoot]
     }
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use super::{Chip, Esp32Params, MemoryKind, MemoryRegion, ReadEFuse, SpiRegisters, Target};
+use crate::{
+    connection::Connection,
+    elf::FirmwareImage,
+    error::Error,
+    flasher::{FlashData, FlashFrequency},
+    image_format::{
+        build_custom_image, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+    },
+};
+
+const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x7211_606F];
+
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4080_0000..0x4085_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x4080_0000..0x4085_0000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4200_0000..0x4400_0000),
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x4200_0000..0x4400_0000),
+];
+
+const PARAMS: Esp32Params = Esp32Params::new(
+    0x0,
+    0x1_0000,
+    0x3f_0000,
+    20,
+    include_bytes!("../../resources/bootloaders/esp32c61-bootloader.bin"),
+);
+
+/// ESP32-C61 Target
+pub struct Esp32c61;
+
+impl Esp32c61 {
+    pub fn has_magic_value(value: u32) -> bool {
+        CHIP_DETECT_MAGIC_VALUES.contains(&value)
+    }
+}
+
+impl ReadEFuse for Esp32c61 {
+    fn efuse_reg(&self) -> u32 {
+        0x600B_4800
+    }
+}
+
+impl Target for Esp32c61 {
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
+    }
+
+    fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
+        Ok(vec!["WiFi 6", "BT 5"])
+    }
+
+    fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+        Ok((self.read_efuse(connection, 19)? >> 4) & 0x3)
+    }
+
+    fn minor_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+        Ok(self.read_efuse(connection, 19)? & 0xF)
+    }
+
+    fn crystal_freq(&self, _connection: &mut Connection) -> Result<u32, Error> {
+        // The ESP32-C61's XTAL has a fixed frequency of 40MHz.
+        Ok(40)
+    }
+
+    fn flash_frequency_encodings(&self) -> HashMap<FlashFrequency, u8> {
+        use FlashFrequency::*;
+
+        let encodings = [(_20Mhz, 0x2), (_40Mhz, 0x0), (_80Mhz, 0xF)];
+
+        HashMap::from(encodings)
+    }
+
+    fn get_flash_image<'a>(
+        &self,
+        image: &'a dyn FirmwareImage<'a>,
+        flash_data: FlashData,
+        image_format: Option<ImageFormatKind>,
+        _chip_revision: Option<(u32, u32)>,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
+
+        match image_format {
+            ImageFormatKind::EspBootloader => Ok(Box::new(IdfBootloaderFormat::new(
+                image,
+                Chip::Esp32c61,
+                PARAMS,
+                flash_data,
+            )?)),
+            ImageFormatKind::DirectBoot => Ok(Box::new(DirectBootFormat::new(image, 0x0)?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32c61, flash_data)
+            }
+        }
+    }
+
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32c61,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
+    fn spi_registers(&self) -> SpiRegisters {
+        SpiRegisters {
+            base: 0x6000_3000,
+            usr_offset: 0x18,
+            usr1_offset: 0x1c,
+            usr2_offset: 0x20,
+            w0_offset: 0x58,
+            mosi_length_offset: Some(0x24),
+            miso_length_offset: Some(0x28),
+        }
+    }
+
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[ImageFormatKind::EspBootloader, ImageFormatKind::DirectBoot]
+    }
+
+    fn supported_build_targets(&self) -> &[&str] {
+        &["riscv32imac-esp-espidf", "riscv32imac-unknown-none-elf"]
+    }
+}
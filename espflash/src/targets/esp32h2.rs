@@ -1,22 +1,26 @@
 use std::collections::HashMap;
-use std::ops::Range;
 
-use esp_idf_part::PartitionTable;
-
-use super::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target};
+use super::{
+    Chip, Esp32Params, MemoryKind, MemoryRegion, ReadEFuse, SpiRegisters, Target, WatchdogRegisters,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::{FlashData, FlashFrequency},
+    image_format::{
+        build_custom_image, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0xD7B7_3E80];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x4200_0000..0x4280_0000, // IROM
-    0x4280_0000..0x4300_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4080_0000..0x4085_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x4080_0000..0x4085_0000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4200_0000..0x4280_0000),
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x4280_0000..0x4300_0000),
+    MemoryRegion::new("RTC_RAM", MemoryKind::RtcFast, 0x5000_0000..0x5000_1000),
 ];
 
 const PARAMS: Esp32Params = Esp32Params::new(
@@ -43,8 +47,8 @@ impl ReadEFuse for Esp32h2 {
 }
 
 impl Target for Esp32h2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
     fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
@@ -78,13 +82,9 @@ impl Target for Esp32h2 {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -93,16 +93,28 @@ impl Target for Esp32h2 {
                 image,
                 Chip::Esp32h2,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
             ImageFormatKind::DirectBoot => Ok(Box::new(DirectBootFormat::new(image, 0x0)?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32h2, flash_data)
+            }
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32h2,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x6000_3000,
@@ -115,6 +127,17 @@ impl Target for Esp32h2 {
         }
     }
 
+    fn watchdog_registers(&self) -> Option<WatchdogRegisters> {
+        Some(WatchdogRegisters {
+            wdt_config0: 0x600b_1c00,
+            wdt_wprotect: 0x600b_1c18,
+            swd_conf: 0x600b_1c1c,
+            swd_wprotect: 0x600b_1c20,
+            swd_wkey: 0x50d8_3aa1,
+            swd_auto_feed_en: 1 << 18,
+        })
+    }
+
     fn supported_image_formats(&self) -> &[ImageFormatKind] {
         &[ImageFormatKind::EspBootloader, ImageFormatKind::DirectBoot]
     }
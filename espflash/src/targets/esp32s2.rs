@@ -1,21 +1,30 @@
-use std::ops::Range;
-
-use esp_idf_part::PartitionTable;
-
-use super::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, MAX_RAM_BLOCK_SIZE};
+use super::{
+    Chip, EmbeddedMemory, Esp32Params, MemoryKind, MemoryRegion, Psram, PsramKind, ReadEFuse,
+    SpiRegisters, Target, MAX_RAM_BLOCK_SIZE,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::{Error, UnsupportedImageFormatError},
-    flasher::{FlashFrequency, FlashMode, FlashSize, FLASH_WRITE_SIZE},
-    image_format::{IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::{FlashData, FLASH_WRITE_SIZE},
+    image_format::{build_custom_image, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x0000_07c6];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x4008_0000..0x40b8_0000, // IROM
-    0x3f00_0000..0x3f3f_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x3f00_0000..0x3f3f_0000),
+    MemoryRegion::new(
+        "EXTRAM_DATA",
+        MemoryKind::ExternalRam,
+        0x3f50_0000..0x3ff8_0000,
+    ),
+    MemoryRegion::new("RTC_DRAM", MemoryKind::RtcFast, 0x3ff9_e000..0x3ffa_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3ffb_0000..0x4000_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4002_0000..0x4007_0000),
+    MemoryRegion::new("RTC_IRAM", MemoryKind::RtcFast, 0x4007_0000..0x4007_2000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4008_0000..0x40b8_0000),
+    MemoryRegion::new("RTC_DATA", MemoryKind::RtcSlow, 0x5000_0000..0x5000_2000),
 ];
 
 const MAX_USB_BLOCK_SIZE: usize = 0x800;
@@ -72,8 +81,8 @@ impl ReadEFuse for Esp32s2 {
 }
 
 impl Target for Esp32s2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
     fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
@@ -106,6 +115,25 @@ impl Target for Esp32s2 {
         Ok(features)
     }
 
+    fn embedded_memory(&self, connection: &mut Connection) -> Result<EmbeddedMemory, Error> {
+        let flash = match self.get_flash_version(connection)? {
+            1 => Some(2),
+            2 => Some(4),
+            _ => None,
+        };
+        let psram = match self.get_psram_version(connection)? {
+            1 => Some(2),
+            2 => Some(4),
+            _ => None,
+        }
+        .map(|size| Psram {
+            size,
+            kind: PsramKind::Quad,
+        });
+
+        Ok(EmbeddedMemory { flash, psram })
+    }
+
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 20)? >> 18 & 0x3)
     }
@@ -133,13 +161,9 @@ impl Target for Esp32s2 {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -148,16 +172,28 @@ impl Target for Esp32s2 {
                 image,
                 Chip::Esp32s2,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32s2, flash_data)
+            }
             _ => Err(UnsupportedImageFormatError::new(image_format, Chip::Esp32s2, None).into()),
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32s2,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     fn max_ram_block_size(&self, connection: &mut Connection) -> Result<usize, Error> {
         Ok(if self.connection_is_usb_otg(connection)? {
             MAX_USB_BLOCK_SIZE
@@ -1,21 +1,26 @@
-use std::ops::Range;
-
-use esp_idf_part::PartitionTable;
-
-use super::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target};
+use super::{
+    Chip, EFuseController, EmbeddedMemory, Esp32Params, MemoryKind, MemoryRegion, Psram, PsramKind,
+    ReadEFuse, SpiRegisters, Target, WatchdogRegisters,
+};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind},
+    flasher::{FlashData, FlashFrequency, FlashMode, FlashSize},
+    image_format::{
+        build_custom_image, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x9];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x4200_0000..0x4400_0000, // IROM
-    0x3c00_0000..0x3e00_0000, // DROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DROM", MemoryKind::Drom, 0x3c00_0000..0x3e00_0000),
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3fc8_8000..0x3fd0_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4037_0000..0x403e_0000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4200_0000..0x4400_0000),
+    MemoryRegion::new("RTC_DATA", MemoryKind::RtcSlow, 0x5000_0000..0x5000_2000),
+    MemoryRegion::new("RTC_RAM", MemoryKind::RtcFast, 0x600f_e000..0x6010_0000),
 ];
 
 const PARAMS: Esp32Params = Esp32Params::new(
@@ -41,6 +46,17 @@ impl Esp32s3 {
     fn blk_version_minor(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 20)? >> 24 & 0x7)
     }
+
+    fn flash_cap(&self, connection: &mut Connection) -> Result<u32, Error> {
+        Ok(self.read_efuse(connection, 20)? >> 27 & 0x7)
+    }
+
+    fn psram_cap(&self, connection: &mut Connection) -> Result<u32, Error> {
+        let lo = self.read_efuse(connection, 21)? >> 3 & 0x3;
+        let hi = self.read_efuse(connection, 22)? >> 19 & 0x1;
+
+        Ok((hi << 2) | lo)
+    }
 }
 
 impl ReadEFuse for Esp32s3 {
@@ -50,12 +66,47 @@ impl ReadEFuse for Esp32s3 {
 }
 
 impl Target for Esp32s3 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
-    fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
-        Ok(vec!["WiFi", "BLE"])
+    fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
+        let mut features = vec!["WiFi", "BLE"];
+
+        let memory = self.embedded_memory(connection)?;
+        match memory.flash {
+            Some(8) => features.push("Embedded Flash 8MB"),
+            Some(4) => features.push("Embedded Flash 4MB"),
+            _ => {}
+        }
+        match memory.psram.map(|psram| psram.size) {
+            Some(16) => features.push("Embedded PSRAM 16MB"),
+            Some(8) => features.push("Embedded PSRAM 8MB"),
+            Some(4) => features.push("Embedded PSRAM 4MB"),
+            Some(2) => features.push("Embedded PSRAM 2MB"),
+            _ => {}
+        }
+
+        Ok(features)
+    }
+
+    fn embedded_memory(&self, connection: &mut Connection) -> Result<EmbeddedMemory, Error> {
+        let flash = match self.flash_cap(connection)? {
+            1 => Some(8),
+            2 => Some(4),
+            _ => None,
+        };
+        // The 8MB and 16MB PSRAM are octal, the smaller sizes are quad
+        let psram = match self.psram_cap(connection)? {
+            1 => Some((8, PsramKind::Octal)),
+            2 => Some((2, PsramKind::Quad)),
+            3 => Some((16, PsramKind::Octal)),
+            4 => Some((4, PsramKind::Quad)),
+            _ => None,
+        }
+        .map(|(size, kind)| Psram { size, kind });
+
+        Ok(EmbeddedMemory { flash, psram })
     }
 
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
@@ -86,16 +137,37 @@ impl Target for Esp32s3 {
         Ok(40)
     }
 
+    fn flash_frequencies(&self) -> Vec<FlashFrequency> {
+        let mut frequencies = self
+            .flash_frequency_encodings()
+            .into_keys()
+            .collect::<Vec<_>>();
+        frequencies.push(FlashFrequency::_120Mhz);
+
+        frequencies
+    }
+
+    fn flash_modes(&self) -> &'static [FlashMode] {
+        &[
+            FlashMode::Qio,
+            FlashMode::Qout,
+            FlashMode::Dio,
+            FlashMode::Dout,
+            FlashMode::Opi,
+        ]
+    }
+
+    fn max_flash_size(&self) -> FlashSize {
+        // Flash beyond 16MB is accessed using 32-bit addresses
+        FlashSize::_128Mb
+    }
+
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
@@ -104,16 +176,28 @@ impl Target for Esp32s3 {
                 image,
                 Chip::Esp32s3,
                 PARAMS,
-                partition_table,
-                bootloader,
-                flash_mode,
-                flash_size,
-                flash_freq,
+                flash_data,
             )?)),
             ImageFormatKind::DirectBoot => Ok(Box::new(DirectBootFormat::new(image, 0x400)?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp32s3, flash_data)
+            }
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(IdfBootloaderFormat::from_bin(
+            app,
+            Chip::Esp32s3,
+            PARAMS,
+            flash_data,
+        )?))
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x6000_2000,
@@ -126,6 +210,31 @@ impl Target for Esp32s3 {
         }
     }
 
+    fn watchdog_registers(&self) -> Option<WatchdogRegisters> {
+        Some(WatchdogRegisters {
+            wdt_config0: 0x6000_8098,
+            wdt_wprotect: 0x6000_80b0,
+            swd_conf: 0x6000_80b4,
+            swd_wprotect: 0x6000_80b8,
+            swd_wkey: 0x8f1d_312a,
+            swd_auto_feed_en: 1 << 31,
+        })
+    }
+
+    fn efuse_controller(&self) -> Option<EFuseController> {
+        Some(EFuseController {
+            base: 0x6000_7000,
+            rd_blk0_offset: 0x2c,
+            conf_offset: 0x1cc,
+            cmd_offset: 0x1d4,
+            dac_conf_offset: 0x1e8,
+            wr_tim_conf1_offset: 0x1f4,
+            wr_tim_conf2_offset: 0x1f8,
+            secure_version_word: 5,
+            secure_version_len: 16,
+        })
+    }
+
     fn supported_image_formats(&self) -> &[ImageFormatKind] {
         &[ImageFormatKind::EspBootloader, ImageFormatKind::DirectBoot]
     }
@@ -1,20 +1,18 @@
-use std::ops::Range;
-
-use esp_idf_part::PartitionTable;
-
-use super::{bytes_to_mac_addr, Chip, ReadEFuse, SpiRegisters, Target};
+use super::{bytes_to_mac_addr, Chip, MemoryKind, MemoryRegion, ReadEFuse, SpiRegisters, Target};
 use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::{Error, UnsupportedImageFormatError},
-    flasher::{FlashFrequency, FlashMode, FlashSize},
-    image_format::{Esp8266Format, ImageFormat, ImageFormatKind},
+    flasher::FlashData,
+    image_format::{build_custom_image, Esp8266Format, ImageFormat, ImageFormatKind},
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0xfff0_c101];
 
-const FLASH_RANGES: &[Range<u32>] = &[
-    0x40200000..0x40300000, // IROM
+const MEMORY_REGIONS: &[MemoryRegion] = &[
+    MemoryRegion::new("DRAM", MemoryKind::Dram, 0x3ffe_8000..0x4000_0000),
+    MemoryRegion::new("IRAM", MemoryKind::Iram, 0x4010_0000..0x4010_8000),
+    MemoryRegion::new("IROM", MemoryKind::Irom, 0x4020_0000..0x4030_0000),
 ];
 
 const UART_CLKDIV_REG: u32 = 0x6000_0014;
@@ -38,8 +36,8 @@ impl ReadEFuse for Esp8266 {
 }
 
 impl Target for Esp8266 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn memory_regions(&self) -> &'static [MemoryRegion] {
+        MEMORY_REGIONS
     }
 
     fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
@@ -71,24 +69,29 @@ impl Target for Esp8266 {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        _bootloader: Option<Vec<u8>>,
-        _partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         _chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let image_format = image_format.unwrap_or(ImageFormatKind::EspBootloader);
 
         match image_format {
-            ImageFormatKind::EspBootloader => Ok(Box::new(Esp8266Format::new(
-                image, flash_mode, flash_size, flash_freq,
-            )?)),
+            ImageFormatKind::EspBootloader => Ok(Box::new(Esp8266Format::new(image, &flash_data)?)),
+            ImageFormatKind::Custom(name) => {
+                build_custom_image(name, image, Chip::Esp8266, flash_data)
+            }
             _ => Err(UnsupportedImageFormatError::new(image_format, Chip::Esp8266, None).into()),
         }
     }
 
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        _flash_data: FlashData,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(Esp8266Format::from_bin(app)?))
+    }
+
     fn mac_address(&self, connection: &mut Connection) -> Result<String, Error> {
         let word0 = self.read_efuse(connection, 0)?;
         let word1 = self.read_efuse(connection, 1)?;
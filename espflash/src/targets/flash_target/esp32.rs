@@ -1,9 +1,10 @@
-use std::io::Write;
+use std::{io::Write, time::Instant};
 
 use flate2::{
     write::{ZlibDecoder, ZlibEncoder},
     Compression,
 };
+use tracing::debug_span;
 
 use super::FlashTarget;
 use crate::{
@@ -11,15 +12,21 @@ use crate::{
     connection::{Connection, USB_SERIAL_JTAG_PID},
     elf::RomSegment,
     error::Error,
-    flasher::{ProgressCallbacks, SpiAttachParams, FLASH_SECTOR_SIZE},
+    flasher::{
+        FlashSummary, ProgressCallbacks, SegmentSummary, SpiAttachParams, FLASH_SECTOR_SIZE,
+    },
     targets::Chip,
 };
 
+/// Alignment of the address and size of encrypted writes
+const ENCRYPTED_WRITE_ALIGN: usize = 32;
+
 /// Applications running from an ESP32's (or variant's) flash
 pub struct Esp32Target {
     chip: Chip,
     spi_attach_params: SpiAttachParams,
     use_stub: bool,
+    summary: FlashSummary,
 }
 
 impl Esp32Target {
@@ -28,115 +35,139 @@ impl Esp32Target {
             chip,
             spi_attach_params,
             use_stub,
+            summary: FlashSummary::default(),
         }
     }
-}
 
-impl FlashTarget for Esp32Target {
-    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
-        connection.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
+    /// Write a segment uncompressed, to be encrypted by either the stub or
+    /// the ROM loader
+    fn write_encrypted_segment(
+        &mut self,
+        connection: &mut Connection,
+        segment: RomSegment,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+        if addr as usize % ENCRYPTED_WRITE_ALIGN != 0 {
+            return Err(Error::UnalignedEncryptedWrite(addr));
+        }
+        // The ESP32's ROM loader can't encrypt data
+        if self.chip == Chip::Esp32 && !self.use_stub {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "writing encrypted data without the flasher stub".into(),
+            });
+        }
+
+        let mut data = segment.data.into_owned();
+        let padded_len = (data.len() + ENCRYPTED_WRITE_ALIGN - 1) / ENCRYPTED_WRITE_ALIGN
+            * ENCRYPTED_WRITE_ALIGN;
+        data.resize(padded_len, 0xff);
+
+        let flash_write_size = self.chip.into_target().flash_write_size(connection)?;
+        let block_count = (data.len() + flash_write_size - 1) / flash_write_size;
+        let erase_size =
+            ((data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE) as u32;
+
+        let erase_span = debug_span!("erase", size = erase_size).entered();
+        let erase_start = Instant::now();
+        connection.with_timeout(
+            CommandType::FlashBegin.timeout_for_size(erase_size),
+            |connection| {
+                connection.command(Command::FlashBegin {
+                    size: data.len() as u32,
+                    blocks: block_count as u32,
+                    block_size: flash_write_size as u32,
+                    offset: addr,
+                    supports_encryption: self.chip != Chip::Esp32 && !self.use_stub,
+                    // The stub encrypts each block, rather than the whole write
+                    encrypted: !self.use_stub,
+                })?;
+                Ok(())
+            },
+        )?;
+        let erase_time = erase_start.elapsed();
+        erase_span.exit();
+
+        let write_start = Instant::now();
+        let chunks = data.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, num_chunks)
+        }
+
+        for (i, block) in chunks.enumerate() {
             let command = if self.use_stub {
-                Command::SpiAttachStub {
-                    spi_params: self.spi_attach_params,
+                Command::FlashEncryptData {
+                    sequence: i as u32,
+                    pad_to: flash_write_size,
+                    pad_byte: 0xff,
+                    data: block,
                 }
             } else {
-                Command::SpiAttach {
-                    spi_params: self.spi_attach_params,
+                Command::FlashData {
+                    sequence: i as u32,
+                    pad_to: flash_write_size,
+                    pad_byte: 0xff,
+                    data: block,
                 }
             };
+            connection.with_timeout(
+                CommandType::FlashData.timeout_for_size(block.len() as u32),
+                |connection| {
+                    connection.command(command)?;
+                    Ok(())
+                },
+            )?;
 
-            connection.command(command)
-        })?;
-
-        // The stub usually disables these watchdog timers, however if we're not using the stub
-        // we need to disable them before flashing begins
-        // TODO: the stub doesn't appear to disable the watchdog on ESP32-S3, so we explicitly
-        //       disable the watchdog here.
-        if connection.get_usb_pid()? == USB_SERIAL_JTAG_PID {
-            match self.chip {
-                Chip::Esp32c3 => {
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_80a8,
-                        value: 0x50D8_3AA1,
-                        mask: None,
-                    })?; // WP disable
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_8090,
-                        value: 0x0,
-                        mask: None,
-                    })?; // turn off RTC WDT
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_80a8,
-                        value: 0x0,
-                        mask: None,
-                    })?; // WP enable
-                }
-                Chip::Esp32s3 => {
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_80B0,
-                        value: 0x50D8_3AA1,
-                        mask: None,
-                    })?; // WP disable
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_8098,
-                        value: 0x0,
-                        mask: None,
-                    })?; // turn off RTC WDT
-                    connection.command(Command::WriteReg {
-                        address: 0x6000_80B0,
-                        value: 0x0,
-                        mask: None,
-                    })?; // WP enable
-                }
-                Chip::Esp32c6 => {
-                    connection.command(Command::WriteReg {
-                        address: 0x600B_1C18,
-                        value: 0x50D8_3AA1,
-                        mask: None,
-                    })?; // WP disable
-                    connection.command(Command::WriteReg {
-                        address: 0x600B_1C00,
-                        value: 0x0,
-                        mask: None,
-                    })?; // turn off RTC WDT
-                    connection.command(Command::WriteReg {
-                        address: 0x600B_1C18,
-                        value: 0x0,
-                        mask: None,
-                    })?; // WP enable
-                }
-                _ => {}
+            if let Some(cb) = progress.as_mut() {
+                cb.update(i + 1)
             }
         }
 
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
+        }
+
+        self.summary.segments.push(SegmentSummary {
+            addr,
+            size: data.len(),
+            compressed_size: None,
+            erase_time,
+            write_time: write_start.elapsed(),
+        });
+
         Ok(())
     }
 
-    fn write_segment(
+    /// Write `size` bytes of data, compressed as `compressed`, to the flash at
+    /// `addr`
+    fn write_compressed(
         &mut self,
         connection: &mut Connection,
-        segment: RomSegment,
+        addr: u32,
+        size: usize,
+        compressed: &[u8],
         progress: &mut Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        let addr = segment.addr;
-
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&segment.data)?;
-        let compressed = encoder.finish()?;
-
         let target = self.chip.into_target();
         let flash_write_size = target.flash_write_size(connection)?;
         let block_count = (compressed.len() + flash_write_size - 1) / flash_write_size;
-        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+        let erase_count = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
 
         // round up to sector size
         let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
 
+        // Beginning the write erases the region being written to, which can take
+        // a significant amount of time.
+        let erase_span = debug_span!("erase", size = erase_size).entered();
+        let erase_start = Instant::now();
         connection.with_timeout(
             CommandType::FlashDeflateBegin.timeout_for_size(erase_size),
             |connection| {
                 connection.command(Command::FlashDeflateBegin {
-                    size: segment.data.len() as u32,
+                    size: size as u32,
                     blocks: block_count as u32,
                     block_size: flash_write_size as u32,
                     offset: addr,
@@ -145,7 +176,10 @@ impl FlashTarget for Esp32Target {
                 Ok(())
             },
         )?;
+        let erase_time = erase_start.elapsed();
+        erase_span.exit();
 
+        let write_start = Instant::now();
         let chunks = compressed.chunks(flash_write_size);
         let num_chunks = chunks.len();
 
@@ -185,14 +219,113 @@ impl FlashTarget for Esp32Target {
             cb.finish()
         }
 
+        self.summary.segments.push(SegmentSummary {
+            addr,
+            size,
+            compressed_size: Some(compressed.len()),
+            erase_time,
+            write_time: write_start.elapsed(),
+        });
+
         Ok(())
     }
+}
 
-    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
-        connection.with_timeout(CommandType::FlashDeflateEnd.timeout(), |connection| {
-            connection.command(Command::FlashDeflateEnd { reboot: false })
+impl FlashTarget for Esp32Target {
+    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
+        connection.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
+            let command = if self.use_stub {
+                Command::SpiAttachStub {
+                    spi_params: self.spi_attach_params,
+                }
+            } else {
+                Command::SpiAttach {
+                    spi_params: self.spi_attach_params,
+                }
+            };
+
+            connection.command(command)
         })?;
 
+        // The stub usually disables the watchdog timers, however if we're not using the
+        // stub we need to disable them before flashing begins
+        // TODO: the stub doesn't appear to disable the watchdog on ESP32-S3, so we explicitly
+        //       disable the watchdog here.
+        if connection.get_usb_pid()? == USB_SERIAL_JTAG_PID {
+            if let Some(registers) = self.chip.into_target().watchdog_registers() {
+                registers.disable(connection)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_segment(
+        &mut self,
+        connection: &mut Connection,
+        segment: RomSegment,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+        let _span = debug_span!(
+            "write_segment",
+            addr = %format_args!("{:#010x}", addr),
+            size = segment.data.len()
+        )
+        .entered();
+
+        if segment.encrypted {
+            return self.write_encrypted_segment(connection, segment, progress);
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&segment.data)?;
+        let compressed = encoder.finish()?;
+
+        self.write_compressed(connection, addr, segment.data.len(), &compressed, progress)
+    }
+
+    fn write_generated_segment(
+        &mut self,
+        connection: &mut Connection,
+        addr: u32,
+        write: &dyn Fn(&mut dyn Write) -> Result<(), Error>,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let _span = debug_span!(
+            "write_generated_segment",
+            addr = %format_args!("{:#010x}", addr)
+        )
+        .entered();
+
+        // The data is compressed as it is generated, so that only the compressed
+        // data, whose size is needed to begin the write, is held in memory
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        write(&mut encoder)?;
+        let size = encoder.total_in() as usize;
+        let compressed = encoder.finish()?;
+
+        self.write_compressed(connection, addr, size, &compressed, progress)
+    }
+
+    fn summary(&self) -> FlashSummary {
+        self.summary.clone()
+    }
+
+    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
+        // Every segment may have been skipped or encrypted, in which case no
+        // compressed write was begun
+        let compressed = self
+            .summary
+            .segments
+            .iter()
+            .any(|segment| segment.compressed_size.is_some());
+        if compressed {
+            connection.with_timeout(CommandType::FlashDeflateEnd.timeout(), |connection| {
+                connection.command(Command::FlashDeflateEnd { reboot: false })
+            })?;
+        }
+
         if reboot {
             connection.reset()?;
         }
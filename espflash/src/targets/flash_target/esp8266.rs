@@ -1,19 +1,26 @@
+use std::time::Instant;
+
+use tracing::debug_span;
+
 use super::FlashTarget;
 use crate::{
     command::{Command, CommandType},
     connection::Connection,
     elf::RomSegment,
     error::Error,
-    flasher::{get_erase_size, ProgressCallbacks, FLASH_WRITE_SIZE},
+    flasher::{get_erase_size, FlashSummary, ProgressCallbacks, SegmentSummary, FLASH_WRITE_SIZE},
+    targets::Chip,
 };
 
 /// Applications running from an ESP8266's flash
 #[derive(Default)]
-pub struct Esp8266Target;
+pub struct Esp8266Target {
+    summary: FlashSummary,
+}
 
 impl Esp8266Target {
     pub fn new() -> Self {
-        Esp8266Target
+        Self::default()
     }
 }
 
@@ -25,6 +32,7 @@ impl FlashTarget for Esp8266Target {
             block_size: FLASH_WRITE_SIZE as u32,
             offset: 0,
             supports_encryption: false,
+            encrypted: false,
         })?;
 
         Ok(())
@@ -37,10 +45,26 @@ impl FlashTarget for Esp8266Target {
         progress: &mut Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
         let addr = segment.addr;
+        let _span = debug_span!(
+            "write_segment",
+            addr = %format_args!("{:#010x}", addr),
+            size = segment.data.len()
+        )
+        .entered();
+
+        if segment.encrypted {
+            return Err(Error::UnsupportedFeature {
+                chip: Chip::Esp8266,
+                feature: "flash encryption".into(),
+            });
+        }
+
         let block_count = (segment.data.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
 
         let erase_size = get_erase_size(addr as usize, segment.data.len()) as u32;
 
+        let erase_span = debug_span!("erase", size = erase_size).entered();
+        let erase_start = Instant::now();
         connection.with_timeout(
             CommandType::FlashBegin.timeout_for_size(erase_size),
             |connection| {
@@ -50,10 +74,14 @@ impl FlashTarget for Esp8266Target {
                     block_size: FLASH_WRITE_SIZE as u32,
                     offset: addr,
                     supports_encryption: false,
+                    encrypted: false,
                 })
             },
         )?;
+        let erase_time = erase_start.elapsed();
+        erase_span.exit();
 
+        let write_start = Instant::now();
         let chunks = segment.data.chunks(FLASH_WRITE_SIZE);
         let num_chunks = chunks.len();
 
@@ -78,9 +106,21 @@ impl FlashTarget for Esp8266Target {
             cb.finish()
         }
 
+        self.summary.segments.push(SegmentSummary {
+            addr,
+            size: segment.data.len(),
+            compressed_size: None,
+            erase_time,
+            write_time: write_start.elapsed(),
+        });
+
         Ok(())
     }
 
+    fn summary(&self) -> FlashSummary {
+        self.summary.clone()
+    }
+
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
         connection.with_timeout(CommandType::FlashEnd.timeout(), |connection| {
             connection.write_command(Command::FlashEnd { reboot: false })
@@ -1,8 +1,15 @@
+use std::{borrow::Cow, io::Write};
+
 use bytemuck::{Pod, Zeroable};
 
 pub(crate) use self::ram::MAX_RAM_BLOCK_SIZE;
 pub use self::{esp32::Esp32Target, esp8266::Esp8266Target, ram::RamTarget};
-use crate::{connection::Connection, elf::RomSegment, error::Error, flasher::ProgressCallbacks};
+use crate::{
+    connection::Connection,
+    elf::RomSegment,
+    error::Error,
+    flasher::{FlashSummary, ProgressCallbacks},
+};
 
 mod esp32;
 mod esp8266;
@@ -21,8 +28,35 @@ pub trait FlashTarget {
         progress: &mut Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error>;
 
+    /// Write a segment to the target device, whose data is generated by
+    /// `write` as it is written
+    ///
+    /// By default the data is generated in memory and written as a segment.
+    fn write_generated_segment(
+        &mut self,
+        connection: &mut Connection,
+        addr: u32,
+        write: &dyn Fn(&mut dyn Write) -> Result<(), Error>,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let mut data = Vec::new();
+        write(&mut data)?;
+
+        let segment = RomSegment {
+            addr,
+            data: Cow::Owned(data),
+            encrypted: false,
+        };
+        self.write_segment(connection, segment, progress)
+    }
+
     /// Complete the flashing operation
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error>;
+
+    /// Timings and sizes from the segments written so far
+    fn summary(&self) -> FlashSummary {
+        FlashSummary::default()
+    }
 }
 
 #[derive(Zeroable, Pod, Copy, Clone, Debug)]
@@ -1,4 +1,5 @@
 use bytemuck::{Pod, Zeroable};
+use tracing::debug_span;
 
 use super::FlashTarget;
 use crate::{
@@ -48,6 +49,12 @@ impl FlashTarget for RamTarget {
         progress: &mut Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
         let addr = segment.addr;
+        let _span = debug_span!(
+            "write_segment",
+            addr = %format_args!("{:#010x}", addr),
+            size = segment.data.len()
+        )
+        .entered();
 
         let padding = 4 - segment.data.len() % 4;
         let block_count = (segment.data.len() + padding + self.block_size - 1) / self.block_size;
@@ -6,10 +6,11 @@
 //! It's also possible to write an application to and boot from RAM, where a
 //! bootloader is obviously not required either.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range};
 
 use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
-use strum::{Display, EnumIter, EnumString, EnumVariantNames};
+use strum::{Display, EnumIter, EnumString, EnumVariantNames, IntoEnumIterator};
+use tracing::warn;
 
 use self::flash_target::MAX_RAM_BLOCK_SIZE;
 pub use self::{
@@ -17,6 +18,7 @@ pub use self::{
     esp32c2::Esp32c2,
     esp32c3::Esp32c3,
     esp32c6::Esp32c6,
+    esp32c61::Esp32c61,
     esp32h2::Esp32h2,
     esp32s2::Esp32s2,
     esp32s3::Esp32s3,
@@ -27,7 +29,7 @@ use crate::{
     connection::Connection,
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashFrequency, FlashMode, FlashSize, SpiAttachParams, FLASH_WRITE_SIZE},
+    flasher::{FlashData, FlashFrequency, FlashMode, FlashSize, SpiAttachParams, FLASH_WRITE_SIZE},
     image_format::{ImageFormat, ImageFormatKind},
 };
 
@@ -35,12 +37,20 @@ mod esp32;
 mod esp32c2;
 mod esp32c3;
 mod esp32c6;
+mod esp32c61;
 mod esp32h2;
 mod esp32s2;
 mod esp32s3;
 mod esp8266;
 mod flash_target;
 
+/// Offset in flash of the partition table
+const PARTITION_TABLE_ADDR: u32 = 0x8000;
+/// Size of the OTA data partition in the default OTA partition layouts
+const OTA_DATA_SIZE: u32 = 0x2000;
+/// Alignment required of app partitions
+const APP_PARTITION_ALIGN: u32 = 0x1_0000;
+
 /// Enumeration of all supported devices
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, EnumVariantNames)]
@@ -55,6 +65,8 @@ pub enum Chip {
     Esp32c3,
     /// ESP32-C6
     Esp32c6,
+    /// ESP32-C61
+    Esp32c61,
     /// ESP32-S2
     Esp32s2,
     /// ESP32-S3
@@ -75,6 +87,8 @@ impl Chip {
             Ok(Chip::Esp32c3)
         } else if Esp32c6::has_magic_value(magic) {
             Ok(Chip::Esp32c6)
+        } else if Esp32c61::has_magic_value(magic) {
+            Ok(Chip::Esp32c61)
         } else if Esp32s2::has_magic_value(magic) {
             Ok(Chip::Esp32s2)
         } else if Esp32s3::has_magic_value(magic) {
@@ -88,12 +102,101 @@ impl Chip {
         }
     }
 
+    /// Identify a chip by the chip ID used in images and reported in its
+    /// security info
+    pub fn from_chip_id(chip_id: u32) -> Option<Self> {
+        match chip_id {
+            0 => Some(Chip::Esp32),
+            2 => Some(Chip::Esp32s2),
+            5 => Some(Chip::Esp32c3),
+            9 => Some(Chip::Esp32s3),
+            12 => Some(Chip::Esp32c2),
+            13 => Some(Chip::Esp32c6),
+            16 => Some(Chip::Esp32h2),
+            20 => Some(Chip::Esp32c61),
+            _ => None,
+        }
+    }
+
+    /// Identify a chip by a Rust build target which only it supports, eg.
+    /// `xtensa-esp32s3-none-elf`
+    ///
+    /// Returns `None` for targets which are supported by several chips, as the
+    /// RISC-V targets are.
+    pub fn from_build_target(target: &str) -> Option<Self> {
+        let mut chips =
+            Chip::iter().filter(|chip| chip.into_target().supports_build_target(target));
+
+        match (chips.next(), chips.next()) {
+            (Some(chip), None) => Some(chip),
+            _ => None,
+        }
+    }
+
+    /// Check that the chip supports the flash mode
+    pub fn check_flash_mode(&self, flash_mode: FlashMode) -> Result<(), Error> {
+        if !self.into_target().flash_modes().contains(&flash_mode) {
+            return Err(Error::UnsupportedFlashMode {
+                chip: *self,
+                flash_mode,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check that the chip supports the flash frequency
+    pub fn check_flash_frequency(&self, frequency: FlashFrequency) -> Result<(), Error> {
+        if !self.into_target().flash_frequencies().contains(&frequency) {
+            return Err(Error::UnsupportedFlashFrequency {
+                chip: *self,
+                frequency,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check that the chip can address flash of the given size
+    pub fn check_flash_size(&self, flash_size: FlashSize) -> Result<(), Error> {
+        if flash_size.size() > self.into_target().max_flash_size().size() {
+            return Err(Error::UnsupportedFlashSize {
+                chip: *self,
+                flash_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Offset in flash from which the ROM loads the second-stage bootloader
+    pub fn boot_address(&self) -> u32 {
+        match self {
+            Chip::Esp32 | Chip::Esp32s2 => 0x1000,
+            _ => 0x0,
+        }
+    }
+
+    /// Offset in flash of the partition table, for chips which have one
+    pub fn partition_table_address(&self) -> Option<u32> {
+        match self {
+            Chip::Esp8266 => None,
+            _ => Some(PARTITION_TABLE_ADDR),
+        }
+    }
+
+    /// The named regions of the chip's memory map
+    pub fn memory_regions(&self) -> &'static [MemoryRegion] {
+        self.into_target().memory_regions()
+    }
+
     pub fn into_target(&self) -> Box<dyn Target> {
         match self {
             Chip::Esp32 => Box::new(Esp32),
             Chip::Esp32c2 => Box::new(Esp32c2),
             Chip::Esp32c3 => Box::new(Esp32c3),
             Chip::Esp32c6 => Box::new(Esp32c6),
+            Chip::Esp32c61 => Box::new(Esp32c61),
             Chip::Esp32s2 => Box::new(Esp32s2),
             Chip::Esp32s3 => Box::new(Esp32s3),
             Chip::Esp32h2 => Box::new(Esp32h2),
@@ -133,9 +236,35 @@ pub struct Esp32Params {
     pub app_addr: u32,
     pub app_size: u32,
     pub chip_id: u16,
+    /// Minimum chip revision written to application images, in
+    /// `major * 100 + minor` form
+    pub min_chip_rev_full: u16,
+    /// Maximum chip revision written to application images, in
+    /// `major * 100 + minor` form
+    pub max_chip_rev_full: u16,
+    /// Bootloader used unless a custom one is provided, built for DIO mode
     pub default_bootloader: &'static [u8],
+    /// Flash mode written to the default bootloader's header for each mode
+    /// which may be selected, as `(selected, written)`
+    pub bootloader_flash_modes: &'static [(FlashMode, FlashMode)],
 }
 
+/// Flash modes written to the headers of bootloaders built for DIO mode
+///
+/// A bootloader fails to boot if its header disagrees with how it configures
+/// the flash, and the code which switches the flash to the quad and octal modes
+/// is only included in bootloaders built for them. As with ESP-IDF, which
+/// writes DIO to the headers of its QIO, QOUT and OPI images, DIO is written
+/// instead. The flash frequency is configured from the header as the
+/// bootloader starts, so it is always written as selected.
+pub const DIO_BOOTLOADER_FLASH_MODES: &[(FlashMode, FlashMode)] = &[
+    (FlashMode::Qio, FlashMode::Dio),
+    (FlashMode::Qout, FlashMode::Dio),
+    (FlashMode::Dio, FlashMode::Dio),
+    (FlashMode::Dout, FlashMode::Dout),
+    (FlashMode::Opi, FlashMode::Dio),
+];
+
 impl Esp32Params {
     pub const fn new(
         boot_addr: u32,
@@ -146,7 +275,7 @@ impl Esp32Params {
     ) -> Self {
         Self {
             boot_addr,
-            partition_addr: 0x8000,
+            partition_addr: PARTITION_TABLE_ADDR,
             nvs_addr: 0x9000,
             nvs_size: 0x6000,
             phy_init_data_addr: 0xf000,
@@ -154,40 +283,291 @@ impl Esp32Params {
             app_addr,
             app_size,
             chip_id,
+            // The same defaults as `esptool.py elf2image`, which do not restrict the
+            // revisions the application can be booted on
+            min_chip_rev_full: 0,
+            max_chip_rev_full: u16::MAX,
             default_bootloader: bootloader,
+            // Each of the default bootloaders was built for DIO mode
+            bootloader_flash_modes: DIO_BOOTLOADER_FLASH_MODES,
         }
     }
 
+    /// The flash mode written to the default bootloader's header when
+    /// `flash_mode` is selected
+    pub fn bootloader_flash_mode(&self, flash_mode: FlashMode) -> FlashMode {
+        self.bootloader_flash_modes
+            .iter()
+            .find(|(selected, _)| *selected == flash_mode)
+            .map_or(flash_mode, |(_, written)| *written)
+    }
+
     /// Generates a default partition table.
     /// `flash_size` is used to scale app partition when present, otherwise the
     /// param defaults are used.
     pub fn default_partition_table(&self, flash_size: Option<u32>) -> PartitionTable {
-        PartitionTable::new(vec![
-            Partition::new(
-                String::from("nvs"),
-                Type::Data,
-                SubType::Data(DataType::Nvs),
-                self.nvs_addr,
-                self.nvs_size,
-                false,
-            ),
-            Partition::new(
-                String::from("phy_init"),
+        self.default_partition_table_with_layout(PartitionLayout::default(), flash_size)
+    }
+
+    /// Generates a default partition table with the given layout
+    ///
+    /// The app partitions share the flash following the data partitions
+    /// equally, so `flash_size` is used to scale them when present, otherwise
+    /// the param defaults are used.
+    pub fn default_partition_table_with_layout(
+        &self,
+        layout: PartitionLayout,
+        flash_size: Option<u32>,
+    ) -> PartitionTable {
+        let mut partitions = Vec::new();
+
+        // The OTA data partition takes the end of the space used by NVS
+        let nvs_size = match layout {
+            PartitionLayout::SingleFactory => self.nvs_size,
+            PartitionLayout::TwoOta | PartitionLayout::TwoOtaWithFactory => {
+                self.nvs_size - OTA_DATA_SIZE
+            }
+        };
+        partitions.push(Partition::new(
+            String::from("nvs"),
+            Type::Data,
+            SubType::Data(DataType::Nvs),
+            self.nvs_addr,
+            nvs_size,
+            false,
+        ));
+        if layout != PartitionLayout::SingleFactory {
+            partitions.push(Partition::new(
+                String::from("otadata"),
                 Type::Data,
-                SubType::Data(DataType::Phy),
-                self.phy_init_data_addr,
-                self.phy_init_data_size,
+                SubType::Data(DataType::Ota),
+                self.nvs_addr + nvs_size,
+                OTA_DATA_SIZE,
                 false,
-            ),
-            Partition::new(
-                String::from("factory"),
+            ));
+        }
+        partitions.push(Partition::new(
+            String::from("phy_init"),
+            Type::Data,
+            SubType::Data(DataType::Phy),
+            self.phy_init_data_addr,
+            self.phy_init_data_size,
+            false,
+        ));
+
+        let apps: &[(&str, AppType)] = match layout {
+            PartitionLayout::SingleFactory => &[("factory", AppType::Factory)],
+            PartitionLayout::TwoOta => &[("ota_0", AppType::Ota_0), ("ota_1", AppType::Ota_1)],
+            PartitionLayout::TwoOtaWithFactory => &[
+                ("factory", AppType::Factory),
+                ("ota_0", AppType::Ota_0),
+                ("ota_1", AppType::Ota_1),
+            ],
+        };
+        let apps_size = flash_size.map_or(self.app_size, |size| size - self.app_addr);
+        // App partitions must be aligned to 64KB
+        let app_size = match apps.len() {
+            1 => apps_size,
+            n => (apps_size / n as u32) & !(APP_PARTITION_ALIGN - 1),
+        };
+
+        for (i, (name, ty)) in apps.iter().enumerate() {
+            partitions.push(Partition::new(
+                String::from(*name),
                 Type::App,
-                SubType::App(AppType::Factory),
-                self.app_addr,
-                flash_size.map_or(self.app_size, |size| size - self.app_addr),
+                SubType::App(*ty),
+                self.app_addr + i as u32 * app_size,
+                app_size,
                 false,
-            ),
-        ])
+            ));
+        }
+
+        PartitionTable::new(partitions)
+    }
+}
+
+/// Layout of the partition table generated when a custom one is not provided
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PartitionLayout {
+    /// A single factory app partition
+    #[default]
+    SingleFactory,
+    /// Two OTA app partitions, which are updated alternately
+    TwoOta,
+    /// A factory app partition, followed by two OTA app partitions
+    TwoOtaWithFactory,
+}
+
+/// Key which disables the write protection of the RTC watchdog's registers
+const RTC_WDT_WKEY: u32 = 0x50d8_3aa1;
+
+/// RTC watchdog and super watchdog register addresses
+///
+/// When connected using USB-Serial-JTAG, these watchdogs are left running by
+/// the ROM bootloader, and reset the chip out of download mode during long
+/// operations such as erasing the flash unless they are disabled.
+pub struct WatchdogRegisters {
+    wdt_config0: u32,
+    wdt_wprotect: u32,
+    swd_conf: u32,
+    swd_wprotect: u32,
+    swd_wkey: u32,
+    swd_auto_feed_en: u32,
+}
+
+impl WatchdogRegisters {
+    /// Disable the RTC watchdog, and have the super watchdog, which can not be
+    /// disabled, fed automatically
+    pub fn disable(&self, connection: &mut Connection) -> Result<(), Error> {
+        connection.write_reg(self.wdt_wprotect, RTC_WDT_WKEY, None)?;
+        connection.write_reg(self.wdt_config0, 0, None)?;
+        connection.write_reg(self.wdt_wprotect, 0, None)?;
+
+        connection.write_reg(self.swd_wprotect, self.swd_wkey, None)?;
+        let swd_conf = connection.read_reg(self.swd_conf)?;
+        connection.write_reg(self.swd_conf, swd_conf | self.swd_auto_feed_en, None)?;
+        connection.write_reg(self.swd_wprotect, 0, None)?;
+
+        Ok(())
+    }
+}
+
+/// Opcode which must be written to the eFuse configuration register before
+/// programming the eFuses
+const EFUSE_WRITE_OP_CODE: u32 = 0x5a5a;
+/// Opcode which must be written to the eFuse configuration register before
+/// reloading the eFuses into their read registers
+const EFUSE_READ_OP_CODE: u32 = 0x5aa5;
+const EFUSE_READ_CMD: u32 = 1 << 0;
+const EFUSE_PGM_CMD: u32 = 1 << 1;
+/// Number of programming registers: 8 data words and 3 check words
+const EFUSE_PGM_REGS: u32 = 11;
+
+/// eFuse controller register addresses, and the location of the anti-rollback
+/// secure version eFuse within block 0
+///
+/// The secure version is stored as the number of bits which have been set,
+/// as eFuse bits can be set but never cleared.
+pub struct EFuseController {
+    /// Base address, at which the programming registers start
+    base: u32,
+    /// Offset of the first word of block 0's read registers
+    rd_blk0_offset: u32,
+    conf_offset: u32,
+    cmd_offset: u32,
+    dac_conf_offset: u32,
+    wr_tim_conf1_offset: u32,
+    wr_tim_conf2_offset: u32,
+    /// Word of block 0 containing the secure version
+    secure_version_word: u32,
+    /// Number of bits of the secure version, and so its maximum value
+    secure_version_len: u32,
+}
+
+impl EFuseController {
+    /// The highest secure version which can be burned into the eFuses
+    pub fn max_secure_version(&self) -> u32 {
+        self.secure_version_len
+    }
+
+    /// Read the secure version from the eFuses
+    pub fn read_secure_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+        let word =
+            connection.read_reg(self.base + self.rd_blk0_offset + self.secure_version_word * 4)?;
+        let mask = (1u32 << self.secure_version_len) - 1;
+
+        Ok((word & mask).count_ones())
+    }
+
+    /// Burn the eFuses so that the secure version is raised to `version`
+    ///
+    /// This is irreversible. The eFuses are read back afterwards to check that
+    /// they were burned correctly. The programming timings assume a 40MHz
+    /// crystal.
+    pub fn burn_secure_version(
+        &self,
+        connection: &mut Connection,
+        version: u32,
+    ) -> Result<(), Error> {
+        if version > self.secure_version_len {
+            return Err(Error::SecureVersionTooLarge(
+                version,
+                self.secure_version_len,
+            ));
+        }
+        let current = self.read_secure_version(connection)?;
+        if version < current {
+            return Err(Error::SecureVersionRollback {
+                current,
+                requested: version,
+            });
+        }
+        if version == current {
+            return Ok(());
+        }
+
+        self.update_reg(connection, self.dac_conf_offset, 0xff << 9, 0xff)?;
+        self.update_reg(connection, self.dac_conf_offset, 0xff, 0x28)?;
+        self.update_reg(connection, self.wr_tim_conf1_offset, 0xffff << 8, 0x3000)?;
+        self.update_reg(connection, self.wr_tim_conf2_offset, 0xffff, 0x190)?;
+
+        self.clear_pgm_regs(connection)?;
+        connection.write_reg(
+            self.base + (self.secure_version_word * 4),
+            (1 << version) - 1,
+            None,
+        )?;
+        // Block 0 is selected by the (zero) block number in the command
+        self.run_command(connection, EFUSE_WRITE_OP_CODE, EFUSE_PGM_CMD)?;
+        self.clear_pgm_regs(connection)?;
+        self.run_command(connection, EFUSE_READ_OP_CODE, EFUSE_READ_CMD)?;
+
+        match self.read_secure_version(connection)? {
+            actual if actual == version => Ok(()),
+            actual => Err(Error::EFuseBurnFailed(format!(
+                "the secure version read back as {actual}, rather than {version}"
+            ))),
+        }
+    }
+
+    /// Set the field selected by `mask` of the register at `offset`
+    fn update_reg(
+        &self,
+        connection: &mut Connection,
+        offset: u32,
+        mask: u32,
+        value: u32,
+    ) -> Result<(), Error> {
+        let addr = self.base + offset;
+        let reg = connection.read_reg(addr)?;
+        let value = (reg & !mask) | ((value << mask.trailing_zeros()) & mask);
+        connection.write_reg(addr, value, None)
+    }
+
+    fn clear_pgm_regs(&self, connection: &mut Connection) -> Result<(), Error> {
+        for i in 0..EFUSE_PGM_REGS {
+            connection.write_reg(self.base + i * 4, 0, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run a command, waiting for the controller to complete it
+    fn run_command(&self, connection: &mut Connection, opcode: u32, cmd: u32) -> Result<(), Error> {
+        connection.write_reg(self.base + self.conf_offset, opcode, None)?;
+        connection.write_reg(self.base + self.cmd_offset, cmd, None)?;
+
+        for _ in 0..100 {
+            if connection.read_reg(self.base + self.cmd_offset)? & cmd == 0 {
+                return Ok(());
+            }
+        }
+
+        Err(Error::EFuseBurnFailed(
+            "the eFuse controller did not complete the command".into(),
+        ))
     }
 }
 
@@ -232,6 +612,115 @@ impl SpiRegisters {
     }
 }
 
+/// The kind of memory which a region of a chip's address space maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum MemoryKind {
+    /// Internal SRAM, accessed using the instruction bus
+    Iram,
+    /// Internal SRAM, accessed using the data bus
+    Dram,
+    /// Flash, mapped onto the instruction bus
+    Irom,
+    /// Flash, mapped onto the data bus
+    Drom,
+    /// RTC (or low-power) fast memory
+    RtcFast,
+    /// RTC slow memory
+    RtcSlow,
+    /// External RAM, such as PSRAM
+    ExternalRam,
+}
+
+/// A named region of a chip's memory map
+///
+/// Regions may overlap, where the same memory is accessible using both the
+/// instruction and data buses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// Name of the region, eg. `IRAM` or `RTC_DRAM`
+    pub name: &'static str,
+    /// The kind of memory the region maps to
+    pub kind: MemoryKind,
+    /// Addresses spanned by the region
+    pub range: Range<u32>,
+}
+
+impl MemoryRegion {
+    const fn new(name: &'static str, kind: MemoryKind, range: Range<u32>) -> Self {
+        Self { name, kind, range }
+    }
+
+    /// Is `addr` within the region?
+    pub fn contains(&self, addr: u32) -> bool {
+        self.range.contains(&addr)
+    }
+
+    /// Does the region map the flash?
+    pub fn is_flash(&self) -> bool {
+        matches!(self.kind, MemoryKind::Irom | MemoryKind::Drom)
+    }
+
+    /// Is the region RTC memory, which is retained during deep sleep?
+    pub fn is_rtc(&self) -> bool {
+        matches!(self.kind, MemoryKind::RtcFast | MemoryKind::RtcSlow)
+    }
+
+    /// Size of the region, in bytes
+    pub fn size(&self) -> u32 {
+        self.range.end - self.range.start
+    }
+}
+
+/// Flash and PSRAM included in the chip's package, in megabytes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddedMemory {
+    /// Size of the embedded flash, if any
+    pub flash: Option<u32>,
+    /// The embedded PSRAM, if any
+    pub psram: Option<Psram>,
+}
+
+/// PSRAM included in the chip's package
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Psram {
+    /// Size of the PSRAM, in megabytes
+    pub size: u32,
+    /// Interface used to access the PSRAM
+    pub kind: PsramKind,
+}
+
+/// Interface used to access PSRAM
+///
+/// Applications must be built for the matching interface, eg. using the
+/// `CONFIG_SPIRAM_MODE_OCT` ESP-IDF option for octal PSRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum PsramKind {
+    /// Quad SPI
+    Quad,
+    /// Octal SPI
+    Octal,
+}
+
+impl EmbeddedMemory {
+    /// Does the package include any flash or PSRAM?
+    pub fn is_empty(&self) -> bool {
+        self.flash.is_none() && self.psram.is_none()
+    }
+}
+
+impl std::fmt::Display for EmbeddedMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flash = self.flash.map(|size| format!("{size}MB flash"));
+        let psram = self
+            .psram
+            .map(|psram| format!("{}MB {} PSRAM", psram.size, psram.kind));
+        let memory = flash.into_iter().chain(psram).collect::<Vec<_>>();
+
+        write!(f, "embedded {}", memory.join(" / "))
+    }
+}
+
 /// Enable the reading of eFuses for a target
 pub trait ReadEFuse {
     /// Returns the base address of the eFuse register
@@ -246,8 +735,22 @@ pub trait ReadEFuse {
 
 /// Operations for interacting with supported target devices
 pub trait Target: ReadEFuse {
+    /// The named regions of the chip's memory map
+    fn memory_regions(&self) -> &'static [MemoryRegion];
+
     /// Is the provided address `addr` in flash?
-    fn addr_is_flash(&self, addr: u32) -> bool;
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        self.memory_regions()
+            .iter()
+            .any(|region| region.is_flash() && region.contains(addr))
+    }
+
+    /// Is the provided address in RTC memory?
+    fn addr_is_rtc(&self, addr: u32) -> bool {
+        self.memory_regions()
+            .iter()
+            .any(|region| region.is_rtc() && region.contains(addr))
+    }
 
     /// Enumerate the chip's features, read from eFuse
     fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error>;
@@ -264,6 +767,23 @@ pub trait Target: ReadEFuse {
 
     fn minor_chip_version(&self, connection: &mut Connection) -> Result<u32, Error>;
 
+    /// Flash and PSRAM included in the chip's package, read from eFuse
+    fn embedded_memory(&self, _connection: &mut Connection) -> Result<EmbeddedMemory, Error> {
+        Ok(EmbeddedMemory::default())
+    }
+
+    /// SPI parameters to attach to the flash with, when they can be determined
+    /// from the chip's package rather than needing to be detected
+    fn spi_attach_params(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<Option<SpiAttachParams>, Error> {
+        // Embedded flash is connected to the default SPI pins on most chips
+        let embedded = self.embedded_memory(connection)?.flash.is_some();
+
+        Ok(embedded.then(SpiAttachParams::default))
+    }
+
     /// What is the crystal frequency?
     fn crystal_freq(&self, connection: &mut Connection) -> Result<u32, Error>;
 
@@ -276,6 +796,29 @@ pub trait Target: ReadEFuse {
         HashMap::from(encodings)
     }
 
+    /// Flash frequencies supported by a chip
+    ///
+    /// These are the frequencies which can be encoded in the image header,
+    /// unless the chip also supports faster ones.
+    fn flash_frequencies(&self) -> Vec<FlashFrequency> {
+        self.flash_frequency_encodings().into_keys().collect()
+    }
+
+    /// Flash modes supported by a chip
+    fn flash_modes(&self) -> &'static [FlashMode] {
+        &[
+            FlashMode::Qio,
+            FlashMode::Qout,
+            FlashMode::Dio,
+            FlashMode::Dout,
+        ]
+    }
+
+    /// Largest flash size which the chip can address
+    fn max_flash_size(&self) -> FlashSize {
+        FlashSize::_16Mb
+    }
+
     /// Write size for flashing operations
     fn flash_write_size(&self, _connection: &mut Connection) -> Result<usize, Error> {
         Ok(FLASH_WRITE_SIZE)
@@ -285,13 +828,16 @@ pub trait Target: ReadEFuse {
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
-        bootloader: Option<Vec<u8>>,
-        partition_table: Option<PartitionTable>,
+        flash_data: FlashData,
         image_format: Option<ImageFormatKind>,
         chip_revision: Option<(u32, u32)>,
-        flash_mode: Option<FlashMode>,
-        flash_size: Option<FlashSize>,
-        flash_freq: Option<FlashFrequency>,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error>;
+
+    /// Build an image for flashing from a pre-built application binary
+    fn get_flash_image_from_bin<'a>(
+        &self,
+        app: &'a [u8],
+        flash_data: FlashData,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error>;
 
     /// What is the MAC address?
@@ -314,6 +860,17 @@ pub trait Target: ReadEFuse {
     /// SPI register addresses for a chip
     fn spi_registers(&self) -> SpiRegisters;
 
+    /// Watchdog register addresses for a chip, if it supports USB-Serial-JTAG
+    fn watchdog_registers(&self) -> Option<WatchdogRegisters> {
+        None
+    }
+
+    /// eFuse controller register addresses for a chip, if burning its eFuses is
+    /// supported
+    fn efuse_controller(&self) -> Option<EFuseController> {
+        None
+    }
+
     /// Image formats supported by a chip
     fn supported_image_formats(&self) -> &[ImageFormatKind] {
         &[ImageFormatKind::EspBootloader]
@@ -335,3 +892,75 @@ fn bytes_to_mac_addr(bytes: &[u8]) -> String {
         .collect::<Vec<_>>()
         .join(":")
 }
+
+/// Round a crystal frequency estimated from the UART clock divider to the
+/// nearest supported frequency, in MHz
+fn normalize_xtal_freq(est_xtal: u32) -> u32 {
+    let norm_xtal = if est_xtal > 33 { 40 } else { 26 };
+
+    if est_xtal.abs_diff(norm_xtal) > 1 {
+        warn!(
+            "Detected crystal frequency of {}MHz is quite different from the normalized \
+             frequency of {}MHz, is an unsupported crystal in use?",
+            est_xtal, norm_xtal
+        );
+    }
+
+    norm_xtal
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+
+    use super::*;
+    use crate::{
+        command::CommandType,
+        interface::{Interface, MockTransport},
+    };
+
+    fn connection(mock: &MockTransport) -> Connection {
+        let port_info = UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+
+        Connection::new(Interface::from_transport(mock.clone()), port_info)
+    }
+
+    #[test]
+    fn test_normalize_xtal_freq() {
+        assert_eq!(normalize_xtal_freq(40), 40);
+        assert_eq!(normalize_xtal_freq(39), 40);
+        assert_eq!(normalize_xtal_freq(34), 40);
+        assert_eq!(normalize_xtal_freq(33), 26);
+        assert_eq!(normalize_xtal_freq(26), 26);
+        assert_eq!(normalize_xtal_freq(24), 26);
+    }
+
+    #[test]
+    fn test_crystal_freq() {
+        for (chip, reg) in [(Chip::Esp32, 0x3ff4_0014), (Chip::Esp32c2, 0x6000_0014)] {
+            // At 115200 baud, the ROM divides a 40MHz crystal by 347 and a 26MHz
+            // one by 225, and the bits above the divider are ignored
+            let mock = MockTransport::new();
+            mock.push_response(CommandType::ReadReg, 347, &[]);
+            mock.push_response(CommandType::ReadReg, 225, &[]);
+            mock.push_response(CommandType::ReadReg, 0xfff0_0000 | 225, &[]);
+
+            let mut connection = connection(&mock);
+            let target = chip.into_target();
+            assert_eq!(target.crystal_freq(&mut connection).unwrap(), 40);
+            assert_eq!(target.crystal_freq(&mut connection).unwrap(), 26);
+            assert_eq!(target.crystal_freq(&mut connection).unwrap(), 26);
+
+            for (opcode, data) in mock.written_commands() {
+                assert_eq!(opcode, CommandType::ReadReg as u8);
+                assert_eq!(data, u32::to_le_bytes(reg));
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Client for the serial updater protocol
+//!
+//! Some products disable the ROM bootloader's download mode, so their firmware
+//! can only be updated by the application which is already running. The serial
+//! updater protocol is a small framed protocol which such an application can
+//! provide on its console UART, and over which a new application image is
+//! written to the OTA partition which will be booted next.
+//!
+//! # Framing
+//!
+//! Frames are SLIP-encoded, as with the ROM bootloader's protocol, and integers
+//! are little-endian. A request consists of a one byte command followed by its
+//! payload. The response consists of the command with its high bit set, a one
+//! byte status, and the response's payload. Anything received which is not a
+//! response, such as the application's log output, is ignored.
+//!
+//! # Commands
+//!
+//! | Command       | Request payload                                   | Response payload                                                 |
+//! |---------------|---------------------------------------------------|------------------------------------------------------------------|
+//! | `0x01` HELLO  | the magic `ESPU`, protocol version: `u8` (`1`)    | protocol version: `u8`, chip ID: `u16`, max data size: `u32`, partition size: `u32` |
+//! | `0x02` BEGIN  | image size: `u32`, SHA-256 digest: `[u8; 32]`     | empty                                                            |
+//! | `0x03` DATA   | offset: `u32`, checksum: `u8`, data               | empty                                                            |
+//! | `0x04` END    | empty                                             | empty                                                            |
+//! | `0x05` REBOOT | empty                                             | none is sent                                                     |
+//!
+//! The chip ID is the one used in the header of application images, or
+//! `0xffff` for the ESP8266. The max data size is the largest amount of data
+//! accepted by a single DATA command, and the partition size is that of the
+//! OTA partition which will be written.
+//!
+//! BEGIN erases the partition. DATA writes the data at the offset within the
+//! partition; the checksum is the XOR of each byte of data, seeded with
+//! `0xef`, as for the ROM bootloader. END checks the SHA-256 digest of the
+//! written image, then selects the partition to boot from next.
+//!
+//! The statuses are:
+//!
+//! | Status | Meaning                        |
+//! |--------|--------------------------------|
+//! | `0`    | Success                        |
+//! | `1`    | Unknown command                |
+//! | `2`    | Invalid argument               |
+//! | `3`    | Checksum mismatch              |
+//! | `4`    | Flash error                    |
+//! | `5`    | SHA-256 digest mismatch        |
+//! | `6`    | No update is in progress       |
+
+use std::time::{Duration, Instant};
+
+use serialport::UsbPortInfo;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    connection::Connection,
+    error::{ConnectionError, Error},
+    flasher::{checksum, ProgressCallbacks, CHECKSUM_INIT},
+    interface::Interface,
+    targets::Chip,
+};
+
+const MAGIC: &[u8; 4] = b"ESPU";
+const PROTOCOL_VERSION: u8 = 1;
+/// Set on the command of each response
+const RESPONSE_FLAG: u8 = 0x80;
+/// The chip ID reported by updaters running on the ESP8266
+const ESP8266_CHIP_ID: u16 = 0xffff;
+
+const HELLO_ATTEMPTS: usize = 10;
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+const ERASE_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum UpdaterCommand {
+    Hello = 0x01,
+    Begin = 0x02,
+    Data = 0x03,
+    End = 0x04,
+    Reboot = 0x05,
+}
+
+impl UpdaterCommand {
+    fn name(self) -> &'static str {
+        match self {
+            UpdaterCommand::Hello => "HELLO",
+            UpdaterCommand::Begin => "BEGIN",
+            UpdaterCommand::Data => "DATA",
+            UpdaterCommand::End => "END",
+            UpdaterCommand::Reboot => "REBOOT",
+        }
+    }
+}
+
+fn status_reason(status: u8) -> &'static str {
+    match status {
+        1 => "unknown command",
+        2 => "invalid argument",
+        3 => "checksum mismatch",
+        4 => "flash error",
+        5 => "SHA-256 digest mismatch",
+        6 => "no update is in progress",
+        _ => "unknown error",
+    }
+}
+
+/// Information reported by the serial updater during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdaterInfo {
+    /// Chip the application is running on
+    pub chip: Chip,
+    /// Largest amount of data accepted by a single write
+    pub max_data_size: u32,
+    /// Size of the OTA partition which will be written
+    pub partition_size: u32,
+}
+
+/// A connection to the serial updater provided by the application running on
+/// a device
+pub struct Updater {
+    connection: Connection,
+    info: UpdaterInfo,
+}
+
+impl Updater {
+    /// Perform the handshake with the serial updater
+    ///
+    /// Unlike [Flasher::connect](crate::flasher::Flasher::connect), the device
+    /// is not reset, as the application must keep running.
+    pub fn connect(serial: Interface, port_info: UsbPortInfo) -> Result<Self, Error> {
+        let mut connection = Connection::new(serial, port_info);
+
+        let mut hello = MAGIC.to_vec();
+        hello.push(PROTOCOL_VERSION);
+        for _ in 0..HELLO_ATTEMPTS {
+            match command(
+                &mut connection,
+                UpdaterCommand::Hello,
+                &hello,
+                HELLO_TIMEOUT,
+            ) {
+                Ok(response) => {
+                    let info = parse_hello(&response)?;
+                    return Ok(Self { connection, info });
+                }
+                Err(Error::UpdaterTimeout(_) | Error::Connection(ConnectionError::Timeout(_))) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(Error::UpdaterNotFound)
+    }
+
+    /// Information reported by the serial updater during the handshake
+    pub fn info(&self) -> &UpdaterInfo {
+        &self.info
+    }
+
+    /// Write an application image to the OTA partition which will be booted
+    /// next
+    pub fn update(
+        &mut self,
+        image: &[u8],
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        if image.len() > self.info.partition_size as usize {
+            return Err(Error::UpdaterImageTooLarge {
+                size: image.len(),
+                partition_size: self.info.partition_size,
+            });
+        }
+
+        let mut begin = (image.len() as u32).to_le_bytes().to_vec();
+        begin.extend_from_slice(&Sha256::digest(image));
+        let erase_timeout = ERASE_TIMEOUT_PER_MB.mul_f64(image.len() as f64 / 1e6);
+        self.command(
+            UpdaterCommand::Begin,
+            &begin,
+            erase_timeout.max(COMMAND_TIMEOUT),
+        )?;
+
+        let max_data_size = self.info.max_data_size as usize;
+        let chunks = image.chunks(max_data_size);
+        if let Some(cb) = progress.as_mut() {
+            cb.init(0, chunks.len())
+        }
+
+        for (i, chunk) in chunks.enumerate() {
+            let mut data = ((i * max_data_size) as u32).to_le_bytes().to_vec();
+            data.push(checksum(chunk, CHECKSUM_INIT));
+            data.extend_from_slice(chunk);
+            self.command(UpdaterCommand::Data, &data, COMMAND_TIMEOUT)?;
+
+            if let Some(cb) = progress.as_mut() {
+                cb.update(i + 1)
+            }
+        }
+
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
+        }
+
+        self.command(UpdaterCommand::End, &[], COMMAND_TIMEOUT)?;
+
+        Ok(())
+    }
+
+    /// Reboot the device into the new application
+    pub fn reboot(&mut self) -> Result<(), Error> {
+        self.connection.write_frame(&[UpdaterCommand::Reboot as u8])
+    }
+
+    pub fn get_usb_pid(&self) -> Result<u16, Error> {
+        self.connection.get_usb_pid()
+    }
+
+    pub fn into_interface(self) -> Interface {
+        self.connection.into_interface()
+    }
+
+    fn command(
+        &mut self,
+        command_type: UpdaterCommand,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        command(&mut self.connection, command_type, payload, timeout)
+    }
+}
+
+/// Send a command and wait for its response, returning the response's payload
+fn command(
+    connection: &mut Connection,
+    command: UpdaterCommand,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    let mut frame = vec![command as u8];
+    frame.extend_from_slice(payload);
+
+    connection.with_timeout(timeout, |connection| {
+        connection.write_frame(&frame)?;
+
+        // The application may log output at any time, so other frames are
+        // skipped until the deadline
+        let deadline = Instant::now() + timeout;
+        loop {
+            let response = connection.read(2)?.unwrap_or_default();
+            match response.as_slice() {
+                [op, 0, payload @ ..] if *op == command as u8 | RESPONSE_FLAG => {
+                    return Ok(payload.to_vec())
+                }
+                [op, status, ..] if *op == command as u8 | RESPONSE_FLAG => {
+                    return Err(Error::UpdaterRejected {
+                        command: command.name(),
+                        reason: status_reason(*status),
+                    })
+                }
+                _ if Instant::now() < deadline => {}
+                _ => return Err(Error::UpdaterTimeout(command.name())),
+            }
+        }
+    })
+}
+
+fn parse_hello(response: &[u8]) -> Result<UpdaterInfo, Error> {
+    if response.len() != 11 {
+        return Err(Error::InvalidUpdaterResponse);
+    }
+
+    let version = response[0];
+    let chip_id = u16::from_le_bytes([response[1], response[2]]);
+    let max_data_size = u32::from_le_bytes(response[3..7].try_into().unwrap());
+    let partition_size = u32::from_le_bytes(response[7..11].try_into().unwrap());
+
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedUpdaterVersion(version));
+    }
+    if max_data_size == 0 {
+        return Err(Error::InvalidUpdaterResponse);
+    }
+
+    let chip = match chip_id {
+        ESP8266_CHIP_ID => Chip::Esp8266,
+        id => Chip::from_chip_id(id as u32).ok_or(Error::ChipIdDetectError(id as u32))?,
+    };
+
+    Ok(UpdaterInfo {
+        chip,
+        max_data_size,
+        partition_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::MockTransport;
+
+    const PORT_INFO: UsbPortInfo = UsbPortInfo {
+        vid: 0,
+        pid: 0,
+        serial_number: None,
+        manufacturer: None,
+        product: None,
+    };
+
+    fn hello_response(version: u8, chip_id: u16) -> Vec<u8> {
+        let mut response = vec![UpdaterCommand::Hello as u8 | RESPONSE_FLAG, 0, version];
+        response.extend(chip_id.to_le_bytes());
+        response.extend(4u32.to_le_bytes());
+        response.extend(0x1000u32.to_le_bytes());
+        response
+    }
+
+    #[test]
+    fn test_update() {
+        let mock = MockTransport::new();
+        mock.push_raw(b"I (1234) app: running\n");
+        mock.push_frame(&hello_response(1, 5));
+        for command in [
+            UpdaterCommand::Begin,
+            UpdaterCommand::Data,
+            UpdaterCommand::Data,
+        ] {
+            mock.push_frame(&[command as u8 | RESPONSE_FLAG, 0]);
+        }
+        mock.push_frame(&[UpdaterCommand::End as u8 | RESPONSE_FLAG, 5]);
+
+        let mut updater =
+            Updater::connect(Interface::from_transport(mock.clone()), PORT_INFO).unwrap();
+        assert_eq!(updater.info().chip, Chip::Esp32c3);
+        assert_eq!(updater.info().max_data_size, 4);
+
+        assert!(matches!(
+            updater.update(b"abcdef", None),
+            Err(Error::UpdaterRejected {
+                command: "END",
+                reason: "SHA-256 digest mismatch"
+            })
+        ));
+
+        let frames = mock.written_frames();
+        assert_eq!(frames[0], b"\x01ESPU\x01");
+        assert_eq!(&frames[1][..5], &[0x02, 6, 0, 0, 0]);
+        assert_eq!(&frames[1][5..], Sha256::digest(b"abcdef").as_slice());
+        assert_eq!(
+            frames[2],
+            [
+                &[0x03, 0, 0, 0, 0][..],
+                &[0xef ^ b'a' ^ b'b' ^ b'c' ^ b'd'],
+                b"abcd"
+            ]
+            .concat()
+        );
+        assert_eq!(&frames[3][..5], &[0x03, 4, 0, 0, 0]);
+        assert_eq!(frames[4], [0x04]);
+
+        let mock = MockTransport::new();
+        mock.push_frame(&hello_response(2, 5));
+        assert!(matches!(
+            Updater::connect(Interface::from_transport(mock), PORT_INFO),
+            Err(Error::UnsupportedUpdaterVersion(2))
+        ));
+    }
+}
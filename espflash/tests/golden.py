@@ -0,0 +1,886 @@
+#!/usr/bin/env python3
+"""Generate the golden images used by `src/image_format/golden.rs`
+
+The expected images are produced independently of espflash, following what
+`esptool.py elf2image`, `esptool.py merge_bin` and ESP-IDF's
+`gen_esp32part.py` do, so that the golden image tests compare espflash
+against the reference tools rather than against its own earlier output.
+
+By default the bundled port of those tools is used, which only needs a Python
+3 interpreter. With `--esptool`, `esptool.py` (v4.x) from the `PATH` is used
+for `elf2image` and `merge_bin` instead, and the two are cross-checked:
+
+    $ python3 tests/golden.py            # regenerate tests/resources/golden
+    $ python3 tests/golden.py --check    # verify the checked-in images
+    $ python3 tests/golden.py --esptool  # also compare against esptool.py
+
+Chips for which no reference application is checked in are covered by
+synthetic ELF files, written to `tests/resources/golden` alongside the images
+built from them. Their layouts follow those of ESP-IDF applications: flash
+sections begin 0x20 bytes into a 64KB MMU page, RAM sections are split
+across several (sometimes adjacent) sections, and some sections are of types
+other than PROGBITS.
+"""
+
+import argparse
+import hashlib
+import os
+import shutil
+import struct
+import subprocess
+import sys
+import tempfile
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+RESOURCES = os.path.join(HERE, "resources")
+GOLDEN = os.path.join(RESOURCES, "golden")
+BOOTLOADERS = os.path.join(HERE, "..", "resources", "bootloaders")
+
+ESP_IMAGE_MAGIC = 0xE9
+ESP_CHECKSUM_MAGIC = 0xEF
+IROM_ALIGN = 0x10000
+SEG_HEADER_LEN = 8
+WP_PIN_DISABLED = 0xEE
+APP_DESC_MAGIC = 0xABCD5432
+
+FLASH_MODES = {"qio": 0, "qout": 1, "dio": 2, "dout": 3}
+
+ESP32_FLASH_SIZES = {
+    "1MB": 0x00,
+    "2MB": 0x10,
+    "4MB": 0x20,
+    "8MB": 0x30,
+    "16MB": 0x40,
+    "32MB": 0x50,
+    "64MB": 0x60,
+    "128MB": 0x70,
+}
+ESP8266_FLASH_SIZES = {
+    "512KB": 0x00,
+    "256KB": 0x10,
+    "1MB": 0x20,
+    "2MB": 0x30,
+    "4MB": 0x40,
+    "2MB-c1": 0x50,
+    "4MB-c1": 0x60,
+    "8MB": 0x80,
+    "16MB": 0x90,
+}
+DEFAULT_FLASH_FREQUENCY = {"80m": 0xF, "40m": 0x0, "26m": 0x1, "20m": 0x2}
+
+
+class Chip:
+    """The parts of esptool's ROM class of a chip used to generate images"""
+
+    def __init__(self, name, chip_id, bootloader_addr, irom, drom, memory_map,
+                 flash_frequency=DEFAULT_FLASH_FREQUENCY,
+                 flash_sizes=ESP32_FLASH_SIZES):
+        self.name = name
+        self.chip_id = chip_id
+        self.bootloader_addr = bootloader_addr
+        self.irom = irom
+        self.drom = drom
+        self.memory_map = memory_map
+        self.flash_frequency = flash_frequency
+        self.flash_sizes = flash_sizes
+
+    def is_flash_addr(self, addr):
+        return any(start <= addr < end for start, end in (self.irom, self.drom))
+
+    def memory_type(self, addr):
+        return [name for start, end, name in self.memory_map if start <= addr < end]
+
+
+CHIPS = {
+    chip.name: chip
+    for chip in [
+        Chip(
+            "esp8266", None, 0x0,
+            (0x40200000, 0x40300000), (0, 0),
+            [
+                (0x3FF00000, 0x3FF00010, "DPORT"),
+                (0x3FFE8000, 0x40000000, "DRAM"),
+                (0x40100000, 0x40108000, "IRAM"),
+                (0x40201010, 0x402E1010, "IROM"),
+            ],
+            flash_sizes=ESP8266_FLASH_SIZES,
+        ),
+        Chip(
+            "esp32", 0, 0x1000,
+            (0x400D0000, 0x40400000), (0x3F400000, 0x3F800000),
+            [
+                (0x3F400000, 0x3F800000, "DROM"),
+                (0x3F800000, 0x3FC00000, "EXTRAM_DATA"),
+                (0x3FF80000, 0x3FF82000, "RTC_DRAM"),
+                (0x3FF90000, 0x40000000, "BYTE_ACCESSIBLE"),
+                (0x3FFAE000, 0x40000000, "DRAM"),
+                (0x3FFE0000, 0x3FFFFFFC, "DIRAM_DRAM"),
+                (0x40000000, 0x40070000, "IROM"),
+                (0x40070000, 0x40078000, "CACHE_PRO"),
+                (0x40078000, 0x40080000, "CACHE_APP"),
+                (0x40080000, 0x400A0000, "IRAM"),
+                (0x400A0000, 0x400BFFFC, "DIRAM_IRAM"),
+                (0x400C0000, 0x400C2000, "RTC_IRAM"),
+                (0x400D0000, 0x40400000, "IROM"),
+                (0x50000000, 0x50002000, "RTC_DATA"),
+            ],
+        ),
+        Chip(
+            "esp32s2", 2, 0x1000,
+            (0x40080000, 0x40B80000), (0x3F000000, 0x3F3F0000),
+            [
+                (0x3F000000, 0x3FF80000, "DROM"),
+                (0x3F500000, 0x3FF80000, "EXTRAM_DATA"),
+                (0x3FF9E000, 0x3FFA0000, "RTC_DRAM"),
+                (0x3FF9E000, 0x40000000, "BYTE_ACCESSIBLE"),
+                (0x3FF9E000, 0x40072000, "MEM_INTERNAL"),
+                (0x3FFB0000, 0x40000000, "DRAM"),
+                (0x40000000, 0x4001A100, "IROM_MASK"),
+                (0x40020000, 0x40070000, "IRAM"),
+                (0x40070000, 0x40072000, "RTC_IRAM"),
+                (0x40080000, 0x40800000, "IROM"),
+                (0x50000000, 0x50002000, "RTC_DATA"),
+            ],
+        ),
+        Chip(
+            "esp32s3", 9, 0x0,
+            (0x42000000, 0x44000000), (0x3C000000, 0x3E000000),
+            [
+                (0x3C000000, 0x3D000000, "DROM"),
+                (0x3D000000, 0x3E000000, "EXTRAM_DATA"),
+                (0x600FE000, 0x60100000, "RTC_DRAM"),
+                (0x3FC88000, 0x3FD00000, "BYTE_ACCESSIBLE"),
+                (0x3FC88000, 0x403E2000, "MEM_INTERNAL"),
+                (0x3FC88000, 0x3FD00000, "DRAM"),
+                (0x40000000, 0x4001A100, "IROM_MASK"),
+                (0x40370000, 0x403E0000, "IRAM"),
+                (0x600FE000, 0x60100000, "RTC_IRAM"),
+                (0x42000000, 0x42800000, "IROM"),
+                (0x50000000, 0x50002000, "RTC_DATA"),
+            ],
+        ),
+        Chip(
+            "esp32c2", 12, 0x0,
+            (0x42000000, 0x42400000), (0x3C000000, 0x3C400000),
+            [
+                (0x3C000000, 0x3C400000, "DROM"),
+                (0x3FCA0000, 0x3FCE0000, "DRAM"),
+                (0x3FC88000, 0x3FD00000, "BYTE_ACCESSIBLE"),
+                (0x3FF00000, 0x3FF50000, "DROM_MASK"),
+                (0x40000000, 0x40090000, "IROM_MASK"),
+                (0x42000000, 0x42400000, "IROM"),
+                (0x4037C000, 0x403C0000, "IRAM"),
+            ],
+            flash_frequency={"60m": 0xF, "30m": 0x0, "20m": 0x1, "15m": 0x2},
+        ),
+        Chip(
+            "esp32c3", 5, 0x0,
+            (0x42000000, 0x42800000), (0x3C000000, 0x3C800000),
+            [
+                (0x3C000000, 0x3C800000, "DROM"),
+                (0x3FC80000, 0x3FCE0000, "DRAM"),
+                (0x3FC88000, 0x3FD00000, "BYTE_ACCESSIBLE"),
+                (0x3FF00000, 0x3FF20000, "DROM_MASK"),
+                (0x40000000, 0x40060000, "IROM_MASK"),
+                (0x42000000, 0x42800000, "IROM"),
+                (0x4037C000, 0x403E0000, "IRAM"),
+                (0x50000000, 0x50002000, "RTC_IRAM"),
+                (0x50000000, 0x50002000, "RTC_DRAM"),
+                (0x600FE000, 0x60100000, "MEM_INTERNAL2"),
+            ],
+        ),
+        Chip(
+            "esp32c6", 13, 0x0,
+            (0x42000000, 0x42800000), (0x42800000, 0x43000000),
+            [
+                (0x42800000, 0x43000000, "DROM"),
+                (0x40800000, 0x40880000, "DRAM"),
+                (0x40800000, 0x40880000, "BYTE_ACCESSIBLE"),
+                (0x4004AC00, 0x40050000, "DROM_MASK"),
+                (0x40000000, 0x4004AC00, "IROM_MASK"),
+                (0x42000000, 0x42800000, "IROM"),
+                (0x40800000, 0x40880000, "IRAM"),
+                (0x50000000, 0x50004000, "RTC_IRAM"),
+                (0x50000000, 0x50004000, "RTC_DRAM"),
+                (0x600FE000, 0x60100000, "MEM_INTERNAL2"),
+            ],
+            flash_frequency={"80m": 0x0, "40m": 0x0, "20m": 0x2},
+        ),
+        Chip(
+            "esp32c61", 20, 0x0,
+            (0x42000000, 0x44000000), (0x42000000, 0x44000000),
+            [
+                (0x42000000, 0x44000000, "DROM"),
+                (0x40800000, 0x40850000, "DRAM"),
+                (0x40800000, 0x40850000, "BYTE_ACCESSIBLE"),
+                (0x42000000, 0x44000000, "IROM"),
+                (0x40800000, 0x40850000, "IRAM"),
+                (0x50000000, 0x50004000, "RTC_IRAM"),
+                (0x50000000, 0x50004000, "RTC_DRAM"),
+            ],
+            flash_frequency={"80m": 0xF, "40m": 0x0, "20m": 0x2},
+        ),
+        Chip(
+            "esp32h2", 16, 0x0,
+            (0x42000000, 0x42800000), (0x42800000, 0x43000000),
+            [
+                (0x42800000, 0x43000000, "DROM"),
+                (0x40800000, 0x40850000, "DRAM"),
+                (0x40800000, 0x40850000, "BYTE_ACCESSIBLE"),
+                (0x4001C400, 0x40020000, "DROM_MASK"),
+                (0x40000000, 0x4001C400, "IROM_MASK"),
+                (0x42000000, 0x42800000, "IROM"),
+                (0x40800000, 0x40850000, "IRAM"),
+                (0x50000000, 0x50001000, "RTC_IRAM"),
+                (0x50000000, 0x50001000, "RTC_DRAM"),
+                (0x600FE000, 0x60100000, "MEM_INTERNAL2"),
+            ],
+            flash_frequency={"48m": 0xF, "24m": 0x0, "16m": 0x1, "12m": 0x2},
+        ),
+    ]
+}
+
+
+# ELF files
+
+
+SHT_PROGBITS = 1
+SHT_STRTAB = 3
+SHT_NOBITS = 8
+SHT_INIT_ARRAY = 14
+SHT_FINI_ARRAY = 15
+SHT_PREINIT_ARRAY = 16
+PROG_SEC_TYPES = (SHT_PROGBITS, SHT_INIT_ARRAY, SHT_FINI_ARRAY, SHT_PREINIT_ARRAY)
+
+SHF_WRITE = 1
+SHF_ALLOC = 2
+SHF_EXECINSTR = 4
+
+
+class Segment:
+    def __init__(self, addr, data, name=None):
+        self.addr = addr
+        self.data = bytes(data)
+        self.name = name
+        # As esptool's `ImageSegment`, pad all segments loaded from the ELF file
+        if self.addr != 0 and len(self.data) % 4:
+            self.data += b"\x00" * (4 - len(self.data) % 4)
+
+    def split(self, length):
+        head = Segment(self.addr, self.data[:length], self.name)
+        self.data = self.data[length:]
+        self.addr += length
+        return head
+
+
+class Elf:
+    """The subset of esptool's `ELFFile` used by `elf2image`"""
+
+    def __init__(self, data):
+        if data[:4] != b"\x7fELF" or data[4] != 1 or data[5] != 1:
+            raise ValueError("not a 32-bit little-endian ELF file")
+
+        (self.entry,) = struct.unpack_from("<I", data, 0x18)
+        (phoff, shoff) = struct.unpack_from("<II", data, 0x1C)
+        (phentsize, phnum, shentsize, shnum, shstrndx) = struct.unpack_from(
+            "<HHHHH", data, 0x2A
+        )
+
+        headers = [
+            struct.unpack_from("<IIIIIIIIII", data, shoff + i * shentsize)
+            for i in range(shnum)
+        ]
+        strtab = headers[shstrndx][4]
+
+        def name(offset):
+            start = strtab + offset
+            return data[start:data.index(b"\x00", start)].decode()
+
+        self.sections = [
+            Segment(addr, data[offset:offset + size], name(name_offset))
+            for (name_offset, kind, _, addr, offset, size, *_) in headers
+            if kind in PROG_SEC_TYPES and addr != 0 and size > 0
+        ]
+
+        # Program headers are only used for direct boot images
+        self.load_segments = []
+        for i in range(phnum):
+            (kind, offset, _, paddr, filesz, _, _, _) = struct.unpack_from(
+                "<IIIIIIII", data, phoff + i * phentsize
+            )
+            if kind == 1 and filesz > 0 and offset > 0:
+                self.load_segments.append((paddr, data[offset:offset + filesz]))
+
+
+def write_elf(path, entry, machine, sections):
+    """Write a minimal executable containing `sections`
+
+    Each section is given as `(name, type, flags, addr, data)`, where `data`
+    is the size of the section for NOBITS sections.
+    """
+    shstrtab = b"\x00"
+    names = []
+    for name, *_ in sections:
+        names.append(len(shstrtab))
+        shstrtab += name.encode() + b"\x00"
+    names.append(len(shstrtab))
+    shstrtab += b".shstrtab\x00"
+
+    body = b""
+    headers = [struct.pack("<IIIIIIIIII", *[0] * 10)]
+    offset = 0x34
+    for (name, kind, flags, addr, data), name_offset in zip(sections, names):
+        if kind == SHT_NOBITS:
+            size = data
+        else:
+            size = len(data)
+            offset_pad = (-offset) % 4
+            body += b"\x00" * offset_pad
+            offset += offset_pad
+        headers.append(
+            struct.pack("<IIIIIIIIII", name_offset, kind, flags, addr, offset,
+                        size, 0, 0, 4, 0)
+        )
+        if kind != SHT_NOBITS:
+            body += data
+            offset += len(data)
+
+    headers.append(
+        struct.pack("<IIIIIIIIII", names[-1], SHT_STRTAB, 0, 0, offset,
+                    len(shstrtab), 0, 0, 1, 0)
+    )
+    body += shstrtab
+    offset += len(shstrtab)
+    body += b"\x00" * ((-offset) % 4)
+    offset += (-offset) % 4
+
+    header = b"\x7fELF" + bytes([1, 1, 1]) + b"\x00" * 9
+    header += struct.pack(
+        "<HHIIIIIHHHHHH", 2, machine, 1, entry, 0, offset, 0, 0x34, 0, 0, 40,
+        len(headers), len(headers) - 1,
+    )
+
+    with open(path, "wb") as f:
+        f.write(header + body + b"".join(headers))
+
+
+def filler(seed, length):
+    """Deterministic, incompressible section contents"""
+    data = b""
+    counter = 0
+    while len(data) < length:
+        data += hashlib.sha256(b"%s/%d" % (seed.encode(), counter)).digest()
+        counter += 1
+    return data[:length]
+
+
+def app_desc(seed):
+    """An `esp_app_desc_t`, whose location esptool treats specially"""
+    desc = struct.pack("<IIII", APP_DESC_MAGIC, 0, 0, 0)
+    desc += b"golden".ljust(32, b"\x00")  # version
+    desc += seed.encode().ljust(32, b"\x00")  # project_name
+    desc += b"12:00:00".ljust(16, b"\x00")  # time
+    desc += b"Jan  1 2024".ljust(16, b"\x00")  # date
+    desc += b"v5.3".ljust(32, b"\x00")  # idf_ver
+    desc += filler(seed + "/sha", 32)  # app_elf_sha256
+    return desc + b"\x00" * (256 - len(desc))
+
+
+def synthetic_elf(path, chip, seed, drom, irom, iram, dram, ram_sizes,
+                  appdesc=False, irom_size=0x1235, drom_size=0x2344):
+    """Write an ELF file with an ESP-IDF like layout for `chip`
+
+    The RAM sections are listed in the same order as in the ESP-IDF linker
+    scripts: the vectors and the rest of IRAM are adjacent but separated in
+    the ELF file by the flash sections, so esptool loads them as separate
+    segments.
+    """
+    vectors, iram_text, dram_data = ram_sizes
+    is_riscv = chip.name not in ("esp32", "esp32s2", "esp32s3", "esp8266")
+    machine = 243 if is_riscv else 94
+    ax, wa, awx = SHF_ALLOC | SHF_EXECINSTR, SHF_ALLOC | SHF_WRITE, SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR
+
+    rodata_addr = drom + 0x20
+    sections = [(".iram0.vectors", SHT_PROGBITS, awx, iram, filler(seed + "/vectors", vectors))]
+    rodata = []
+    if appdesc:
+        desc = app_desc(seed)
+        rodata.append((".flash.appdesc", SHT_PROGBITS, SHF_ALLOC, rodata_addr, desc))
+        rodata_addr += len(desc)
+    rodata += [
+        (".flash.rodata", SHT_PROGBITS, wa, rodata_addr, filler(seed + "/rodata", drom_size)),
+        (".init_array", SHT_INIT_ARRAY, wa, rodata_addr + drom_size, filler(seed + "/init", 8)),
+    ]
+    text = [(".flash.text", SHT_PROGBITS, ax, irom + 0x20, filler(seed + "/text", irom_size))]
+    # The sections are in the same order as the MMU pages they're mapped to
+    sections += text + rodata if irom < drom else rodata + text
+    sections += [
+        (".iram0.text", SHT_PROGBITS, awx, iram + vectors, filler(seed + "/iram", iram_text)),
+        (".dram0.data", SHT_PROGBITS, wa, dram, filler(seed + "/data", dram_data)),
+        (".dram0.bss", SHT_NOBITS, wa, dram + dram_data, 0x400),
+        (".comment", SHT_PROGBITS, 0, 0, b"golden\x00"),
+    ]
+
+    write_elf(path, irom + 0x20, machine, sections)
+
+
+# esptool.py elf2image
+
+
+def merge_adjacent_segments(chip, segments):
+    """Merge segments which follow each other in the ELF file, and are
+    contiguous in the same memory region
+
+    The segments are not sorted first, so segments only adjacent in memory are
+    kept separate.
+    """
+    merged = []
+    for segment in segments:
+        if merged and all((
+            chip.memory_type(merged[-1].addr) == chip.memory_type(segment.addr),
+            segment.addr == merged[-1].addr + len(merged[-1].data),
+        )):
+            merged[-1].data += segment.data
+        else:
+            merged.append(Segment(segment.addr, segment.data, segment.name))
+    return merged
+
+
+def checksum(data, state=ESP_CHECKSUM_MAGIC):
+    for b in data:
+        state ^= b
+    return state
+
+
+def flash_params(chip, mode, size, freq):
+    return bytes([FLASH_MODES[mode], chip.flash_sizes[size] + chip.flash_frequency[freq]])
+
+
+def elf2image_esp32(chip, elf, mode, size, freq, min_rev_full=0, max_rev_full=65535):
+    segments = merge_adjacent_segments(chip, elf.sections)
+
+    flash = sorted((s for s in segments if chip.is_flash_addr(s.addr)), key=lambda s: s.addr)
+    ram = sorted((s for s in segments if not chip.is_flash_addr(s.addr)), key=lambda s: s.addr)
+
+    # The application description must be in the first flash segment
+    for segment in flash:
+        if segment.name == ".flash.appdesc":
+            flash.remove(segment)
+            flash.insert(0, segment)
+            break
+
+    for prev, segment in zip(flash, flash[1:]):
+        if prev.addr // IROM_ALIGN == segment.addr // IROM_ALIGN:
+            raise ValueError("flash segments share a 64KB MMU page")
+
+    out = bytearray()
+    out += struct.pack("<BB", ESP_IMAGE_MAGIC, 0) + flash_params(chip, mode, size, freq)
+    out += struct.pack("<I", elf.entry)
+    out += struct.pack(
+        "<BBBBHBHH4xB", WP_PIN_DISABLED, 0, 0, 0, chip.chip_id, min_rev_full // 100,
+        min_rev_full, max_rev_full, 1,
+    )
+
+    state = ESP_CHECKSUM_MAGIC
+    count = 0
+
+    def save(segment):
+        nonlocal state, count
+        out.extend(struct.pack("<II", segment.addr, len(segment.data)))
+        out.extend(segment.data)
+        state = checksum(segment.data, state)
+        count += 1
+
+    def alignment_needed(segment):
+        align_past = (segment.addr % IROM_ALIGN) - SEG_HEADER_LEN
+        pad_len = (IROM_ALIGN - (len(out) % IROM_ALIGN)) + align_past
+        if pad_len == 0 or pad_len == IROM_ALIGN:
+            return 0
+        pad_len -= SEG_HEADER_LEN
+        if pad_len < 0:
+            pad_len += IROM_ALIGN
+        return pad_len
+
+    while flash:
+        segment = flash[0]
+        pad_len = alignment_needed(segment)
+        if pad_len > 0:
+            if ram and pad_len > SEG_HEADER_LEN:
+                pad = ram[0].split(pad_len)
+                if not ram[0].data:
+                    ram.pop(0)
+            else:
+                pad = Segment(0, b"\x00" * pad_len)
+            save(pad)
+        else:
+            assert (len(out) + SEG_HEADER_LEN) % IROM_ALIGN == segment.addr % IROM_ALIGN
+            # Work around the second stage bootloader not mapping the last MMU
+            # page if a segment is less than 0x24 bytes into it
+            remainder = (len(out) + len(segment.data) + SEG_HEADER_LEN) % IROM_ALIGN
+            if remainder < 0x24:
+                segment.data += b"\x00" * (0x24 - remainder)
+            save(segment)
+            flash.pop(0)
+
+    for segment in ram:
+        save(segment)
+
+    out += b"\x00" * (15 - len(out) % 16)
+    out.append(state)
+    out[1] = count
+    out += hashlib.sha256(out).digest()
+
+    return {0: bytes(out)}
+
+
+def elf2image_esp8266(chip, elf, mode, size, freq):
+    """Version 1 images, whose IROM section is written to a separate file"""
+    segments = merge_adjacent_segments(chip, elf.sections)
+    irom = [s for s in segments if chip.is_flash_addr(s.addr)]
+    ram = [s for s in segments if not chip.is_flash_addr(s.addr)]
+    if len(irom) > 1:
+        raise ValueError("more than one IROM segment")
+
+    out = bytearray()
+    out += struct.pack("<BB", ESP_IMAGE_MAGIC, len(ram)) + flash_params(chip, mode, size, freq)
+    out += struct.pack("<I", elf.entry)
+    state = ESP_CHECKSUM_MAGIC
+    for segment in ram:
+        out += struct.pack("<II", segment.addr, len(segment.data)) + segment.data
+        state = checksum(segment.data, state)
+    out += b"\x00" * (15 - len(out) % 16)
+    out.append(state)
+
+    images = {0: bytes(out)}
+    for segment in irom:
+        images[segment.addr - chip.irom[0]] = segment.data
+    return images
+
+
+def elf2image(chip, elf, mode, size, freq):
+    """Images built from `elf`, by their offset in flash"""
+    if chip.name == "esp8266":
+        return elf2image_esp8266(chip, elf, mode, size, freq)
+    return elf2image_esp32(chip, elf, mode, size, freq)
+
+
+def direct_boot(elf):
+    """The equivalent of `objcopy -O binary` for a direct boot application,
+    with each segment mapped to the first 4MB of flash"""
+    out = bytearray()
+    for addr, data in elf.load_segments:
+        offset = addr % 0x400000
+        out += b"\x00" * (offset - len(out))
+        out[offset:offset + len(data)] = data
+    out += b"\x00" * ((-len(out)) % 4)
+    return {0: bytes(out)}
+
+
+# esptool.py merge_bin
+
+
+def image_data_length(data):
+    """The length of an image up to and including its checksum"""
+    pos = 24
+    for _ in range(data[1]):
+        (_, length) = struct.unpack_from("<II", data, pos)
+        pos += 8 + length
+    return pos + 16 - pos % 16
+
+
+def update_flash_params(chip, data, mode, size, freq):
+    """Update the header of a bootloader, as `merge_bin` does"""
+    data = bytearray(data)
+    data[2:4] = flash_params(chip, mode, size, freq)
+    if chip.name != "esp8266" and data[8 + 15] == 1:
+        length = image_data_length(data)
+        data[length:length + 32] = hashlib.sha256(data[:length]).digest()
+    return bytes(data)
+
+
+def merge_bin(chip, files, mode, size, freq, target_offset):
+    out = bytearray()
+    for addr, data in sorted(files.items()):
+        out += b"\xFF" * (addr - target_offset - len(out))
+        if addr == chip.bootloader_addr and data[0] == ESP_IMAGE_MAGIC:
+            data = update_flash_params(chip, data, mode, size, freq)
+        out += data
+    return bytes(out)
+
+
+# gen_esp32part.py
+
+
+PARTITION_TYPES = {"app": 0x00, "data": 0x01}
+PARTITION_SUBTYPES = {
+    "factory": 0x00, "ota_0": 0x10, "ota_1": 0x11,
+    "ota": 0x00, "phy": 0x01, "nvs": 0x02, "coredump": 0x03, "nvs_keys": 0x04,
+    "efuse": 0x05, "fat": 0x81, "spiffs": 0x82, "littlefs": 0x83,
+}
+
+
+def parse_int(value):
+    for suffix, multiplier in (("K", 1024), ("M", 1024 * 1024)):
+        if value.upper().endswith(suffix):
+            return parse_int(value[:-1]) * multiplier
+    return int(value, 0)
+
+
+def gen_esp32part(csv):
+    table = b""
+    for line in csv.strip().splitlines():
+        line = line.strip()
+        if not line or line.startswith("#"):
+            continue
+        name, kind, subtype, offset, size, *flags = [f.strip() for f in line.split(",")]
+        encrypted = 1 if flags and "encrypted" in flags[0] else 0
+        table += struct.pack(
+            "<2sBBLL16sL", b"\xAA\x50", PARTITION_TYPES[kind], PARTITION_SUBTYPES[subtype],
+            parse_int(offset), parse_int(size), name.encode(), encrypted,
+        )
+    table += b"\xEB\xEB" + b"\xFF" * 14 + hashlib.md5(table).digest()
+    return table + b"\xFF" * (0xC00 - len(table))
+
+
+def default_partition_table(app_size):
+    """The partition table espflash uses when none is provided"""
+    return gen_esp32part(f"""
+        nvs,      data, nvs,     0x9000,  0x6000,
+        phy_init, data, phy,     0xf000,  0x1000,
+        factory,  app,  factory, 0x10000, {app_size:#x},
+    """)
+
+
+# Golden images
+
+
+class Case:
+    def __init__(self, chip, elf, expected, merged=False, flash=None, app_size=0x3F0000,
+                 direct_boot=False):
+        self.chip = CHIPS[chip]
+        self.elf = elf
+        self.expected = expected
+        self.merged = merged
+        # Without flash settings, those already in the bootloader's header are
+        # used
+        self.flash = flash
+        self.app_size = app_size
+        self.direct_boot = direct_boot
+
+
+def synthetic_elfs():
+    """The synthetic ELF files, and the arguments used to generate them"""
+    return {
+        "esp32s2_synthetic": dict(
+            chip="esp32s2", drom=0x3F000000, irom=0x40080000 + 0x10000,
+            iram=0x40020000, dram=0x3FFB0000, ram_sizes=(0x400, 0x2a6c, 0x731),
+        ),
+        "esp32s3_synthetic": dict(
+            chip="esp32s3", drom=0x3C010000, irom=0x42000000,
+            iram=0x40370000, dram=0x3FC88000, ram_sizes=(0x400, 0x3f12, 0x1a4),
+            irom_size=0x10000 - 0x20 + 0x10,
+        ),
+        "esp32c2_synthetic": dict(
+            chip="esp32c2", drom=0x3C010000, irom=0x42000000,
+            iram=0x4037C000, dram=0x3FCA0000, ram_sizes=(0x100, 0x1b0, 0x88),
+        ),
+        "esp32c3_synthetic": dict(
+            chip="esp32c3", drom=0x3C010000, irom=0x42000000,
+            iram=0x4037C000, dram=0x3FC80000, ram_sizes=(0x200, 0x8e3c, 0x5c3),
+            irom_size=0x12345,
+        ),
+        "esp32c6_synthetic": dict(
+            chip="esp32c6", drom=0x42800000 + 0x10000, irom=0x42000000,
+            iram=0x40800000, dram=0x40808000, ram_sizes=(0x100, 0x5a0a, 0xb28),
+            appdesc=True,
+        ),
+        "esp32h2_synthetic": dict(
+            chip="esp32h2", drom=0x42800000 + 0x10000, irom=0x42000000,
+            iram=0x40800000, dram=0x40806000, ram_sizes=(0x100, 0x4412, 0x2d0),
+            appdesc=True,
+        ),
+        "esp32c61_synthetic": dict(
+            chip="esp32c61", drom=0x42010000, irom=0x42000000,
+            iram=0x40800000, dram=0x40807000, ram_sizes=(0x100, 0x6180, 0x98),
+            appdesc=True,
+        ),
+    }
+
+
+CASES = [
+    Case("esp32", "esp32_hal_blinky", "esp32_hal_blinky.bin"),
+    Case("esp32", "esp32_hal_blinky", "esp32_hal_blinky_merged.bin", merged=True),
+    Case("esp32", "esp32_hal_blinky", "esp32_hal_blinky_dio_8mb_26m.bin", merged=True,
+         flash=("dio", "8MB", "26m"), app_size=0x7F0000),
+    Case("esp32c3", "esp32c3_hal_blinky_db", "esp32c3_hal_blinky_db.bin", direct_boot=True),
+    Case("esp8266", "esp8266_hal_blinky", "esp8266_hal_blinky.bin"),
+    Case("esp8266", "esp8266_hal_blinky", "esp8266_hal_blinky_merged.bin", merged=True),
+    Case("esp32s2", "golden/esp32s2_synthetic", "golden/esp32s2_synthetic.bin"),
+    Case("esp32s2", "golden/esp32s2_synthetic", "golden/esp32s2_synthetic_merged.bin",
+         merged=True, app_size=0x100000),
+    Case("esp32s3", "golden/esp32s3_synthetic", "golden/esp32s3_synthetic.bin"),
+    Case("esp32s3", "golden/esp32s3_synthetic", "golden/esp32s3_synthetic_dio_32mb_80m.bin",
+         merged=True, flash=("dio", "32MB", "80m"), app_size=0x1FF0000),
+    Case("esp32c2", "golden/esp32c2_synthetic", "golden/esp32c2_synthetic.bin"),
+    Case("esp32c2", "golden/esp32c2_synthetic", "golden/esp32c2_synthetic_dout_2mb_15m.bin",
+         merged=True, flash=("dout", "2MB", "15m"), app_size=0x1F0000),
+    Case("esp32c3", "golden/esp32c3_synthetic", "golden/esp32c3_synthetic.bin"),
+    # The frequency in the header of the ESP32-C6 bootloader has no esptool
+    # equivalent, so is always replaced
+    Case("esp32c6", "golden/esp32c6_synthetic", "golden/esp32c6_synthetic.bin",
+         flash=("dio", "2MB", "40m")),
+    Case("esp32c6", "golden/esp32c6_synthetic", "golden/esp32c6_synthetic_dio_16mb_80m.bin",
+         merged=True, flash=("dio", "16MB", "80m"), app_size=0xFF0000),
+    Case("esp32h2", "golden/esp32h2_synthetic", "golden/esp32h2_synthetic.bin"),
+    Case("esp32h2", "golden/esp32h2_synthetic", "golden/esp32h2_synthetic_dio_4mb_48m.bin",
+         merged=True, flash=("dio", "4MB", "48m"), app_size=0x3F0000),
+    Case("esp32c61", "golden/esp32c61_synthetic", "golden/esp32c61_synthetic.bin"),
+]
+
+
+def bootloader(chip):
+    with open(os.path.join(BOOTLOADERS, f"{chip.name}-bootloader.bin"), "rb") as f:
+        return f.read()
+
+
+def header_flash_settings(chip, header):
+    """The flash settings in an image header, as esptool arguments"""
+    mode = {v: k for k, v in FLASH_MODES.items()}[header[2]]
+    size = {v: k for k, v in chip.flash_sizes.items()}[header[3] & 0xF0]
+    # Frequencies sharing an encoding are equivalent
+    freq = {v: k for k, v in reversed(chip.flash_frequency.items())}[header[3] & 0x0F]
+    return mode, size, freq
+
+
+def default_flash_settings(chip):
+    if chip.name == "esp8266":
+        # espflash's defaults, as there's no bootloader to take them from
+        return ("dio", "4MB", "40m")
+    return header_flash_settings(chip, bootloader(chip))
+
+
+class Tools:
+    """The bundled port of esptool"""
+
+    def elf2image(self, chip, elf_path, mode, size, freq):
+        with open(elf_path, "rb") as f:
+            return elf2image(chip, Elf(f.read()), mode, size, freq)
+
+    def merge_bin(self, chip, files, mode, size, freq, target_offset):
+        return merge_bin(chip, files, mode, size, freq, target_offset)
+
+
+class Esptool(Tools):
+    """`esptool.py` from the `PATH`"""
+
+    def __init__(self):
+        self.esptool = shutil.which("esptool.py") or shutil.which("esptool")
+        if self.esptool is None:
+            sys.exit("esptool.py was not found")
+
+    def run(self, chip, *args):
+        subprocess.run([self.esptool, "--chip", chip.name, *args], check=True,
+                       stdout=subprocess.DEVNULL)
+
+    def elf2image(self, chip, elf_path, mode, size, freq):
+        with tempfile.TemporaryDirectory() as tmp:
+            out = os.path.join(tmp, "app")
+            args = ["elf2image", "--flash_mode", mode, "--flash_size", size,
+                    "--flash_freq", freq, "-o", out]
+            if chip.name == "esp8266":
+                args += ["--version", "1"]
+            else:
+                args[-2:-2] = ["--min-rev-full", "0", "--max-rev-full", "65535"]
+                out += ".bin"
+            self.run(chip, *args, elf_path)
+
+            if chip.name != "esp8266":
+                with open(out, "rb") as f:
+                    return {0: f.read()}
+
+            images = {}
+            for name in os.listdir(tmp):
+                with open(os.path.join(tmp, name), "rb") as f:
+                    images[int(name[len("app"):-len(".bin")], 16)] = f.read()
+            return images
+
+    def merge_bin(self, chip, files, mode, size, freq, target_offset):
+        with tempfile.TemporaryDirectory() as tmp:
+            args = ["merge_bin", "-o", os.path.join(tmp, "merged.bin"),
+                    "--flash_mode", mode, "--flash_size", size, "--flash_freq", freq,
+                    "--target-offset", hex(target_offset)]
+            for addr, data in files.items():
+                path = os.path.join(tmp, f"{addr:#x}.bin")
+                with open(path, "wb") as f:
+                    f.write(data)
+                args += [hex(addr), path]
+            self.run(chip, *args)
+
+            with open(os.path.join(tmp, "merged.bin"), "rb") as f:
+                return f.read()
+
+
+def build(case, tools):
+    chip = case.chip
+    elf_path = os.path.join(RESOURCES, case.elf)
+
+    if case.direct_boot:
+        with open(elf_path, "rb") as f:
+            return direct_boot(Elf(f.read()))[0]
+
+    mode, size, freq = case.flash or default_flash_settings(chip)
+    images = tools.elf2image(chip, elf_path, mode, size, freq)
+
+    if chip.name != "esp8266":
+        if not case.merged:
+            return images[0]
+        images = {
+            chip.bootloader_addr: bootloader(chip),
+            0x8000: default_partition_table(case.app_size),
+            0x10000: images[0],
+        }
+    elif not case.merged:
+        # As `elf2image` writes it to `<name>0x00000.bin`
+        return images[0]
+
+    return tools.merge_bin(chip, images, mode, size, freq, min(images))
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--check", action="store_true",
+                        help="check the images in tests/resources instead of writing them")
+    parser.add_argument("--esptool", action="store_true",
+                        help="use esptool.py, and check that the port agrees with it")
+    args = parser.parse_args()
+
+    if not args.check:
+        os.makedirs(GOLDEN, exist_ok=True)
+        for name, kwargs in synthetic_elfs().items():
+            kwargs = dict(kwargs)
+            chip = CHIPS[kwargs.pop("chip")]
+            synthetic_elf(os.path.join(GOLDEN, name), chip, name, **kwargs)
+
+    tools = Esptool() if args.esptool else Tools()
+    failed = False
+    for case in CASES:
+        image = build(case, tools)
+        if args.esptool and image != build(case, Tools()):
+            print(f"{case.expected}: the bundled port disagrees with esptool.py")
+            failed = True
+
+        path = os.path.join(RESOURCES, case.expected)
+        if args.check:
+            with open(path, "rb") as f:
+                if f.read() != image:
+                    print(f"{case.expected}: differs")
+                    failed = True
+        else:
+            with open(path, "wb") as f:
+                f.write(image)
+
+    sys.exit(1 if failed else 0)
+
+
+if __name__ == "__main__":
+    main()